@@ -16,7 +16,7 @@
 
 use boring::error::ErrorStack;
 use boring::pkey::{HasPrivate, PKeyRef};
-use boring::ssl::{Ssl, SslAcceptor, SslRef};
+use boring::ssl::{Ssl, SslAcceptor, SslContextBuilder, SslRef};
 use boring::x509::store::X509StoreRef;
 use boring::x509::verify::X509VerifyParamRef;
 use boring::x509::X509Ref;
@@ -137,6 +137,29 @@ pub fn ssl_use_second_key_share(ssl: &mut SslRef, enabled: bool) {
 #[cfg(not(feature = "pq_use_second_keyshare"))]
 pub fn ssl_use_second_key_share(_ssl: &mut SslRef, _enabled: bool) {}
 
+/// Accept TLS 1.3 early data (0-RTT) when `bytes` is not 0
+///
+/// BoringSSL doesn't allow to configure the size limit of the early data, which is fixed to
+/// 14336 bytes.
+pub fn ssl_ctx_set_max_early_data(
+    ctx: &mut SslContextBuilder,
+    bytes: u32,
+) -> Result<(), ErrorStack> {
+    unsafe { boring_sys::SSL_CTX_set_early_data_enabled(ctx.as_ptr(), (bytes > 0) as c_int) };
+    Ok(())
+}
+
+/// Whether the early data the client sent on this connection was accepted
+pub fn ssl_early_data_accepted(ssl: &SslRef) -> bool {
+    unsafe { boring_sys::SSL_early_data_accepted(ssl.as_ptr()) == 1 }
+}
+
+/// Whether the connection is still reading the early data of the client, i.e., the handshake is
+/// not confirmed yet
+pub fn ssl_in_early_data(ssl: &SslRef) -> bool {
+    unsafe { boring_sys::SSL_in_early_data(ssl.as_ptr()) == 1 }
+}
+
 /// Clear the error stack
 ///
 /// SSL calls should check and clear the BoringSSL error stack. But some calls fail to do so.
@@ -31,4 +31,5 @@ pub use ssl_lib::hash;
 pub use ssl_lib::nid;
 pub use ssl_lib::pkey;
 pub use ssl_lib::ssl;
+pub use ssl_lib::version;
 pub use ssl_lib::x509;
@@ -0,0 +1,1166 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! File based on disk cache storage
+//!
+//! Each asset is stored as two files under a shard directory named after the first two hex
+//! characters of its key hash: the body in `<hash>.<body id>`, and the metadata in the sidecar
+//! `<hash>.meta`, which names the body by its random id. Assets are written to a temporary
+//! directory first, then moved in place when complete, the sidecar last, so readers never see
+//! partially written assets nor the body of an asset with the metadata of another.
+//!
+//! The bodies can optionally be compressed at rest, see [DiskCompression].
+
+use super::*;
+use crate::key::CompactCacheKey;
+use crate::storage::{HandleHit, HandleMiss};
+use crate::trace::SpanHandle;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use log::warn;
+use lru::LruCache;
+use parking_lot::Mutex;
+use pingora_error::{Error, ErrorType::*, OrErr, Result};
+use std::any::Any;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{ErrorKind, Read, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+const META_SUFFIX: &str = ".meta";
+const TMP_DIR: &str = "tmp";
+// temporary files older than this are leftovers of crashed writers
+const STALE_TMP_AGE: Duration = Duration::from_secs(3600);
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+// the sidecar starts with: internal meta len (u32), header len (u32), body id (u64),
+// body len (u64), stored body len (u64), codec (u8)
+const SIDECAR_PREFIX_LEN: usize = 4 + 4 + 8 + 8 + 8 + 1;
+
+/// The codec to compress the bodies at rest with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskCodec {
+    Gzip,
+    Zstd,
+}
+
+impl DiskCodec {
+    fn to_u8(codec: Option<Self>) -> u8 {
+        match codec {
+            None => 0,
+            Some(DiskCodec::Gzip) => 1,
+            Some(DiskCodec::Zstd) => 2,
+        }
+    }
+
+    fn from_u8(v: u8) -> Option<Option<Self>> {
+        match v {
+            0 => Some(None),
+            1 => Some(Some(DiskCodec::Gzip)),
+            2 => Some(Some(DiskCodec::Zstd)),
+            _ => None,
+        }
+    }
+
+    // compress the file at `from` into the file at `to`, return the compressed size
+    fn compress_file(&self, level: u32, from: &Path, to: &Path) -> std::io::Result<u64> {
+        let mut input = std::fs::File::open(from)?;
+        let output = std::fs::File::create(to)?;
+        let output = match self {
+            DiskCodec::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(output, flate2::Compression::new(level));
+                std::io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?
+            }
+            DiskCodec::Zstd => {
+                let mut encoder = zstd::Encoder::new(output, level as i32)?;
+                std::io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?
+            }
+        };
+        output.metadata().map(|m| m.len())
+    }
+
+    // decompress no more than one byte over `len`, which is enough to tell a corrupted body
+    fn decompress(&self, data: &[u8], len: usize) -> std::io::Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(len);
+        let limit = len as u64 + 1;
+        match self {
+            DiskCodec::Gzip => flate2::read::GzDecoder::new(data)
+                .take(limit)
+                .read_to_end(&mut out)?,
+            DiskCodec::Zstd => zstd::Decoder::new(data)?
+                .take(limit)
+                .read_to_end(&mut out)?,
+        };
+        Ok(out)
+    }
+}
+
+/// The settings of the compression at rest of [DiskStorage]
+///
+/// The eligible bodies are compressed once fully written. They are decompressed in memory when
+/// read, so that range requests are served from the original bytes, which is why the bodies over
+/// `max_size` are not compressed. The bodies that don't shrink or fail to compress are stored as
+/// is.
+#[derive(Debug, Clone)]
+pub struct DiskCompression {
+    /// The codec to compress with
+    pub codec: DiskCodec,
+    /// The compression level of the codec
+    pub level: u32,
+    /// The bodies smaller than this are stored uncompressed
+    pub min_size: usize,
+    /// The bodies larger than this are stored uncompressed, which bounds the memory to serve
+    /// each hit of a compressed body
+    pub max_size: usize,
+    /// The prefixes of the eligible `Content-Type`s, e.g., `text/` or `application/json`
+    pub content_types: Vec<String>,
+}
+
+impl DiskCompression {
+    /// The settings to compress the common text types from 1KiB to 8MiB with the given codec
+    pub fn new(codec: DiskCodec) -> Self {
+        DiskCompression {
+            codec,
+            level: match codec {
+                DiskCodec::Gzip => 6,
+                DiskCodec::Zstd => 3,
+            },
+            min_size: 1024,
+            max_size: 8 * 1024 * 1024,
+            content_types: [
+                "text/",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        }
+    }
+
+    fn eligible(&self, header: &ResponseHeader) -> bool {
+        // already compressed by the origin
+        if header
+            .headers
+            .get(http::header::CONTENT_ENCODING)
+            .is_some_and(|v| v.as_bytes() != b"identity")
+        {
+            return false;
+        }
+        let Some(content_type) = header.headers.get(http::header::CONTENT_TYPE) else {
+            return false;
+        };
+        let content_type = content_type.as_bytes();
+        self.content_types.iter().any(|prefix| {
+            content_type.len() >= prefix.len()
+                && content_type[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+        })
+    }
+}
+
+// how the body of an asset is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StoredBody {
+    // the id of the body file, unique per write of the asset
+    id: u64,
+    // the length of the body as served
+    len: u64,
+    // the length of the body file
+    stored_len: u64,
+    codec: Option<DiskCodec>,
+}
+
+impl StoredBody {
+    fn uncompressed(id: u64, len: u64) -> Self {
+        StoredBody {
+            id,
+            len,
+            stored_len: len,
+            codec: None,
+        }
+    }
+}
+
+fn encode_sidecar(meta: &(Vec<u8>, Vec<u8>), body: StoredBody) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(SIDECAR_PREFIX_LEN + meta.0.len() + meta.1.len());
+    buf.extend_from_slice(&(meta.0.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&(meta.1.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&body.id.to_be_bytes());
+    buf.extend_from_slice(&body.len.to_be_bytes());
+    buf.extend_from_slice(&body.stored_len.to_be_bytes());
+    buf.push(DiskCodec::to_u8(body.codec));
+    buf.extend_from_slice(&meta.0);
+    buf.extend_from_slice(&meta.1);
+    buf
+}
+
+// decode the prefix of the sidecar, return the lengths of the internal meta and the header too
+fn decode_sidecar_prefix(buf: &[u8]) -> Option<(usize, usize, StoredBody)> {
+    if buf.len() < SIDECAR_PREFIX_LEN {
+        return None;
+    }
+    let u64_at = |i: usize| u64::from_be_bytes(buf[i..i + 8].try_into().unwrap());
+    let internal_len = u32::from_be_bytes(buf[0..4].try_into().unwrap()) as usize;
+    let header_len = u32::from_be_bytes(buf[4..8].try_into().unwrap()) as usize;
+    let body = StoredBody {
+        id: u64_at(8),
+        len: u64_at(16),
+        stored_len: u64_at(24),
+        codec: DiskCodec::from_u8(buf[32])?,
+    };
+    Some((internal_len, header_len, body))
+}
+
+fn decode_sidecar(buf: &[u8]) -> Result<(CacheMeta, StoredBody)> {
+    let corrupted = || Error::e_explain(InternalError, "corrupted cache meta sidecar");
+    let Some((internal_len, header_len, body)) = decode_sidecar_prefix(buf) else {
+        return corrupted();
+    };
+    let rest = &buf[SIDECAR_PREFIX_LEN..];
+    if rest.len() != internal_len + header_len {
+        return corrupted();
+    }
+    let (internal, header) = rest.split_at(internal_len);
+    Ok((CacheMeta::deserialize(internal, header)?, body))
+}
+
+struct Index {
+    // hash -> on disk size of the asset
+    lru: LruCache<String, usize>,
+    size: usize,
+}
+
+/// File based on disk cache storage
+///
+/// The total on disk size of the assets is capped: the least recently used assets are evicted
+/// when it is exceeded. The existing assets are indexed on startup so that the cache survives
+/// restarts.
+///
+/// The size accounting is per [DiskStorage] instance. Processes sharing the same directory,
+/// e.g. during a graceful upgrade, don't see each other's writes in their accounting.
+pub struct DiskStorage {
+    root: PathBuf,
+    max_size: usize,
+    index: Mutex<Index>,
+    tmp_counter: AtomicU64,
+    compression: Option<DiskCompression>,
+}
+
+impl DiskStorage {
+    /// Create a new [DiskStorage] under the given directory that holds up to `max_size` bytes.
+    ///
+    /// The directory is created if it doesn't exist. This function blocks on file system IO to
+    /// index the existing assets.
+    pub fn new(root: impl Into<PathBuf>, max_size: usize) -> Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(root.join(TMP_DIR)).or_err_with(FileCreateError, || {
+            format!("fail to create {}", root.display())
+        })?;
+        let storage = DiskStorage {
+            root,
+            max_size,
+            index: Mutex::new(Index {
+                lru: LruCache::unbounded(),
+                size: 0,
+            }),
+            tmp_counter: AtomicU64::new(0),
+            compression: None,
+        };
+        storage.load_index()?;
+        Ok(storage)
+    }
+
+    /// Compress the eligible bodies at rest
+    ///
+    /// The assets already stored are still served whether or not they are compressed.
+    pub fn with_compression(mut self, compression: DiskCompression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// The total on disk size of the assets in this storage
+    pub fn size(&self) -> usize {
+        self.index.lock().size
+    }
+
+    fn body_path(&self, hash: &str, id: u64) -> PathBuf {
+        self.root.join(&hash[..2]).join(format!("{hash}.{id:016x}"))
+    }
+
+    // a random id, so that the bodies don't collide with the ones of the other processes sharing
+    // the directory either
+    fn new_body_id(&self) -> u64 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(self.tmp_counter.fetch_add(1, Ordering::Relaxed));
+        hasher.finish()
+    }
+
+    fn meta_path(&self, hash: &str) -> PathBuf {
+        self.root
+            .join(&hash[..2])
+            .join(format!("{hash}{META_SUFFIX}"))
+    }
+
+    fn tmp_path(&self, hash: &str) -> PathBuf {
+        let n = self.tmp_counter.fetch_add(1, Ordering::Relaxed);
+        self.root
+            .join(TMP_DIR)
+            .join(format!("{hash}.{}.{n}", std::process::id()))
+    }
+
+    fn load_index(&self) -> Result<()> {
+        let read_dir = |dir: &Path| {
+            std::fs::read_dir(dir)
+                .or_err_with(FileReadError, || format!("fail to read {}", dir.display()))
+        };
+
+        let now = SystemTime::now();
+        let remove_stale = |file: &std::fs::DirEntry| {
+            let stale = file
+                .metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|t| now.duration_since(t).unwrap_or_default() > STALE_TMP_AGE);
+            if stale {
+                let _ = std::fs::remove_file(file.path());
+            }
+        };
+
+        // (last modified, hash, size)
+        let mut assets = vec![];
+        for shard in read_dir(&self.root)? {
+            let Ok(shard) = shard else { continue };
+            if shard.file_name() == TMP_DIR || !shard.path().is_dir() {
+                continue;
+            }
+            let mut bodies = vec![];
+            let mut referenced = std::collections::HashSet::new();
+            for file in read_dir(&shard.path())? {
+                let Ok(file) = file else { continue };
+                let name = file.file_name();
+                let Some(hash) = name.to_str().and_then(|n| n.strip_suffix(META_SUFFIX)) else {
+                    bodies.push(file);
+                    continue;
+                };
+                let Some(body_path) = std::fs::read(file.path())
+                    .ok()
+                    .and_then(|buf| decode_sidecar_prefix(&buf))
+                    .map(|(_, _, body)| self.body_path(hash, body.id))
+                else {
+                    // corrupted asset
+                    let _ = std::fs::remove_file(file.path());
+                    continue;
+                };
+                let (Ok(meta), Ok(body)) = (file.metadata(), std::fs::metadata(&body_path)) else {
+                    // incomplete asset
+                    let _ = std::fs::remove_file(file.path());
+                    continue;
+                };
+                referenced.insert(body_path);
+                let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                let size = (meta.len() + body.len()) as usize;
+                assets.push((modified, hash.to_string(), size));
+            }
+            // the bodies replaced while being read, or left by crashed writers
+            for body in bodies {
+                if !referenced.contains(&body.path()) {
+                    remove_stale(&body);
+                }
+            }
+        }
+
+        for tmp in read_dir(&self.root.join(TMP_DIR))?.flatten() {
+            remove_stale(&tmp);
+        }
+
+        // the least recently modified assets are the first to evict
+        assets.sort();
+        let evicted = {
+            let mut index = self.index.lock();
+            for (_, hash, size) in assets {
+                index.size += size;
+                index.lru.put(hash, size);
+            }
+            self.evict(&mut index)
+        };
+        for hash in evicted {
+            let meta_path = self.meta_path(&hash);
+            let body = std::fs::read(&meta_path)
+                .ok()
+                .and_then(|buf| decode_sidecar_prefix(&buf));
+            let _ = std::fs::remove_file(meta_path);
+            if let Some((_, _, body)) = body {
+                let _ = std::fs::remove_file(self.body_path(&hash, body.id));
+            }
+        }
+        Ok(())
+    }
+
+    // evict the least recently used assets until the size is under the limit, return their hashes
+    fn evict(&self, index: &mut Index) -> Vec<String> {
+        let mut evicted = vec![];
+        while index.size > self.max_size {
+            let Some((hash, size)) = index.lru.pop_lru() else {
+                break;
+            };
+            index.size -= size;
+            evicted.push(hash);
+        }
+        evicted
+    }
+
+    async fn remove_asset(&self, hash: &str) -> bool {
+        let meta_path = self.meta_path(hash);
+        let body = fs::read(&meta_path)
+            .await
+            .ok()
+            .and_then(|buf| decode_sidecar_prefix(&buf));
+        // remove the meta first so that the asset is no longer visible
+        let meta_removed = fs::remove_file(meta_path).await.is_ok();
+        let body_removed = match body {
+            Some((_, _, body)) => fs::remove_file(self.body_path(hash, body.id)).await.is_ok(),
+            None => false,
+        };
+        meta_removed || body_removed
+    }
+
+    async fn admit(&self, hash: String, size: usize) {
+        let evicted = {
+            let mut index = self.index.lock();
+            if let Some(old) = index.lru.put(hash, size) {
+                index.size -= old;
+            }
+            index.size += size;
+            self.evict(&mut index)
+        };
+        for hash in evicted {
+            self.remove_asset(&hash).await;
+        }
+    }
+
+    async fn read_sidecar(&self, hash: &str) -> Result<Option<(CacheMeta, StoredBody)>> {
+        match fs::read(self.meta_path(hash)).await {
+            Ok(buf) => decode_sidecar(&buf).map(Some),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).or_err(FileReadError, "while reading cache meta"),
+        }
+    }
+
+    // write the file via a temporary file so that readers never see a partial one
+    async fn write_atomic(&self, hash: &str, path: &Path, data: &[u8]) -> Result<()> {
+        let tmp = self.tmp_path(hash);
+        fs::write(&tmp, data)
+            .await
+            .or_err_with(FileWriteError, || {
+                format!("fail to write {}", tmp.display())
+            })?;
+        if let Err(e) = fs::rename(&tmp, path).await {
+            let _ = fs::remove_file(&tmp).await;
+            return Err(e).or_err_with(FileWriteError, || {
+                format!("fail to write {}", path.display())
+            });
+        }
+        Ok(())
+    }
+
+    // compress the finished body in place, keep it as is if it doesn't shrink or fails to compress
+    async fn compress(
+        &self,
+        hash: &str,
+        tmp_body: &Path,
+        id: u64,
+        len: u64,
+        compression: &DiskCompression,
+    ) -> Result<StoredBody> {
+        let from = tmp_body.to_path_buf();
+        let to = self.tmp_path(hash);
+        let (codec, level) = (compression.codec, compression.level);
+        let compressed = {
+            let to = to.clone();
+            tokio::task::spawn_blocking(move || codec.compress_file(level, &from, &to))
+                .await
+                .or_err(InternalError, "compression task failed")?
+        };
+        let stored_len = match compressed {
+            Ok(stored_len) if stored_len < len => stored_len,
+            Ok(_) => {
+                let _ = fs::remove_file(&to).await;
+                return Ok(StoredBody::uncompressed(id, len));
+            }
+            Err(e) => {
+                warn!(
+                    "fail to compress {}, store it uncompressed: {e}",
+                    tmp_body.display()
+                );
+                let _ = fs::remove_file(&to).await;
+                return Ok(StoredBody::uncompressed(id, len));
+            }
+        };
+        if let Err(e) = fs::rename(&to, tmp_body).await {
+            let _ = fs::remove_file(&to).await;
+            return Err(e).or_err_with(FileWriteError, || {
+                format!("fail to write {}", tmp_body.display())
+            });
+        }
+        Ok(StoredBody {
+            id,
+            len,
+            stored_len,
+            codec: Some(codec),
+        })
+    }
+
+    async fn decompress(codec: DiskCodec, data: Vec<u8>, len: u64) -> Result<Bytes> {
+        let body = tokio::task::spawn_blocking(move || codec.decompress(&data, len as usize))
+            .await
+            .or_err(InternalError, "decompression task failed")?
+            .or_err(FileReadError, "while decompressing cache body")?;
+        if body.len() as u64 != len {
+            return Error::e_explain(
+                FileReadError,
+                format!("corrupted cache body: {} != {len} bytes", body.len()),
+            );
+        }
+        Ok(body.into())
+    }
+}
+
+enum HitBody {
+    File(File),
+    // the decompressed body
+    Memory(Bytes),
+}
+
+/// The [HandleHit] of [DiskStorage]
+pub struct DiskHitHandler {
+    body: HitBody,
+    body_len: u64,
+    pos: u64,
+    end: u64,
+    pending_seek: Option<u64>,
+}
+
+#[async_trait]
+impl HandleHit for DiskHitHandler {
+    async fn read_body(&mut self) -> Result<Option<Bytes>> {
+        if let Some(start) = self.pending_seek.take() {
+            if let HitBody::File(file) = &mut self.body {
+                file.seek(SeekFrom::Start(start))
+                    .await
+                    .or_err(FileReadError, "while seeking cache body")?;
+            }
+            self.pos = start;
+        }
+        if self.pos >= self.end {
+            return Ok(None);
+        }
+        let len = std::cmp::min(READ_CHUNK_SIZE as u64, self.end - self.pos) as usize;
+        let data = match &mut self.body {
+            HitBody::File(file) => {
+                let mut buf = BytesMut::zeroed(len);
+                file.read_exact(&mut buf)
+                    .await
+                    .or_err(FileReadError, "while reading cache body")?;
+                buf.freeze()
+            }
+            HitBody::Memory(body) => {
+                let start = self.pos as usize;
+                body.slice(start..start + len)
+            }
+        };
+        self.pos += len as u64;
+        Ok(Some(data))
+    }
+
+    async fn finish(
+        self: Box<Self>, // because self is always used as a trait object
+        _storage: &'static (dyn storage::Storage + Sync),
+        _key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    fn seek(&mut self, start: usize, end: Option<usize>) -> Result<()> {
+        let start = start as u64;
+        if start >= self.body_len {
+            return Error::e_explain(
+                InternalError,
+                format!("seek start out of range {start} >= {}", self.body_len),
+            );
+        }
+        self.pending_seek = Some(start);
+        // end over the actual last byte is allowed, we just need to return the actual bytes
+        self.end = end.map_or(self.body_len, |e| std::cmp::min(self.body_len, e as u64));
+        Ok(())
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+/// The [HandleMiss] of [DiskStorage]
+pub struct DiskMissHandler {
+    storage: &'static DiskStorage,
+    hash: String,
+    meta: (Vec<u8>, Vec<u8>),
+    compress: bool,
+    tmp_body: PathBuf,
+    // None after finish()
+    file: Option<File>,
+    written: u64,
+}
+
+#[async_trait]
+impl HandleMiss for DiskMissHandler {
+    async fn write_body(&mut self, data: Bytes, _eof: bool) -> Result<()> {
+        let file = self.file.as_mut().expect("write after finish");
+        file.write_all(&data)
+            .await
+            .or_err(FileWriteError, "while writing cache body")?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<usize> {
+        let mut file = self.file.take().expect("finish twice");
+        file.flush()
+            .await
+            .or_err(FileWriteError, "while writing cache body")?;
+        drop(file);
+
+        let storage = self.storage;
+        let hash = &self.hash;
+        let id = storage.new_body_id();
+        let body = match &storage.compression {
+            Some(compression)
+                if self.compress
+                    && (compression.min_size as u64..=compression.max_size as u64)
+                        .contains(&self.written) =>
+            {
+                storage
+                    .compress(hash, &self.tmp_body, id, self.written, compression)
+                    .await?
+            }
+            _ => StoredBody::uncompressed(id, self.written),
+        };
+        // the new body is invisible until the new sidecar naming it replaces the old one, whose
+        // body is removed after. The readers that already opened the old body keep reading it.
+        let body_path = storage.body_path(hash, id);
+        let shard = body_path.parent().expect("shard dir");
+        fs::create_dir_all(shard)
+            .await
+            .or_err_with(FileCreateError, || {
+                format!("fail to create {}", shard.display())
+            })?;
+        fs::rename(&self.tmp_body, &body_path)
+            .await
+            .or_err_with(FileWriteError, || {
+                format!("fail to write {}", body_path.display())
+            })?;
+        let old_body = storage.read_sidecar(hash).await.ok().flatten();
+        let sidecar = encode_sidecar(&self.meta, body);
+        if let Err(e) = storage
+            .write_atomic(hash, &storage.meta_path(hash), &sidecar)
+            .await
+        {
+            let _ = fs::remove_file(&body_path).await;
+            return Err(e);
+        }
+        if let Some((_, old_body)) = old_body {
+            let _ = fs::remove_file(storage.body_path(hash, old_body.id)).await;
+        }
+
+        let size = body.stored_len as usize + sidecar.len();
+        storage.admit(hash.clone(), size).await;
+        Ok(size)
+    }
+}
+
+impl Drop for DiskMissHandler {
+    fn drop(&mut self) {
+        // the body is already moved in place if finish() succeeded
+        match std::fs::remove_file(&self.tmp_body) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                warn!("fail to remove {}: {e}", self.tmp_body.display())
+            }
+            _ => {}
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for DiskStorage {
+    async fn lookup(
+        &'static self,
+        key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> Result<Option<(CacheMeta, HitHandler)>> {
+        let hash = key.combined();
+        let Some((meta, body)) = self.read_sidecar(&hash).await? else {
+            return Ok(None);
+        };
+        let mut file = match File::open(self.body_path(&hash, body.id)).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).or_err(FileOpenError, "while opening cache body"),
+        };
+        let actual_len = file
+            .metadata()
+            .await
+            .or_err(FileReadError, "while reading cache body")?
+            .len();
+        if actual_len != body.stored_len {
+            return Error::e_explain(
+                FileReadError,
+                format!(
+                    "corrupted cache body: {actual_len} != {} bytes",
+                    body.stored_len
+                ),
+            );
+        }
+        let hit_body = match body.codec {
+            None => HitBody::File(file),
+            Some(codec) => {
+                let mut data = Vec::with_capacity(body.stored_len as usize);
+                file.read_to_end(&mut data)
+                    .await
+                    .or_err(FileReadError, "while reading cache body")?;
+                HitBody::Memory(Self::decompress(codec, data, body.len).await?)
+            }
+        };
+        self.index.lock().lru.promote(&hash);
+        let hit_handler = DiskHitHandler {
+            body: hit_body,
+            body_len: body.len,
+            pos: 0,
+            end: body.len,
+            pending_seek: None,
+        };
+        Ok(Some((meta, Box::new(hit_handler))))
+    }
+
+    async fn get_miss_handler(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<MissHandler> {
+        let hash = key.combined();
+        let compress = self
+            .compression
+            .as_ref()
+            .is_some_and(|c| c.eligible(meta.response_header()));
+        let meta = meta.serialize()?;
+        let tmp_body = self.tmp_path(&hash);
+        let file = File::create(&tmp_body)
+            .await
+            .or_err_with(FileCreateError, || {
+                format!("fail to create {}", tmp_body.display())
+            })?;
+        Ok(Box::new(DiskMissHandler {
+            storage: self,
+            hash,
+            meta,
+            compress,
+            tmp_body,
+            file: Some(file),
+            written: 0,
+        }))
+    }
+
+    async fn purge(&'static self, key: &CompactCacheKey, _trace: &SpanHandle) -> Result<bool> {
+        let hash = key.combined();
+        {
+            let mut index = self.index.lock();
+            if let Some(size) = index.lru.pop(&hash) {
+                index.size -= size;
+            }
+        }
+        Ok(self.remove_asset(&hash).await)
+    }
+
+    async fn update_meta(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<bool> {
+        let hash = key.combined();
+        let Some((_, body)) = self.read_sidecar(&hash).await? else {
+            return Ok(false);
+        };
+        let sidecar = encode_sidecar(&meta.serialize()?, body);
+        self.write_atomic(&hash, &self.meta_path(&hash), &sidecar)
+            .await?;
+        self.admit(hash, body.stored_len as usize + sidecar.len())
+            .await;
+        Ok(true)
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustracing::span::Span;
+
+    fn gen_meta() -> CacheMeta {
+        let mut header = ResponseHeader::build(200, None).unwrap();
+        header.append_header("foo1", "bar1").unwrap();
+        header.append_header("Server", "Pingora").unwrap();
+        let internal = crate::meta::InternalMeta::default();
+        CacheMeta(Box::new(crate::meta::CacheMetaInner {
+            internal,
+            header,
+            extensions: http::Extensions::new(),
+        }))
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("pingora-disk-cache-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn new_storage(dir: &Path, max_size: usize) -> &'static DiskStorage {
+        Box::leak(Box::new(DiskStorage::new(dir, max_size).unwrap()))
+    }
+
+    async fn write(storage: &'static DiskStorage, key: &CacheKey, body: &[&str]) -> usize {
+        write_meta(storage, key, &gen_meta(), body).await
+    }
+
+    async fn write_meta(
+        storage: &'static DiskStorage,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        body: &[&str],
+    ) -> usize {
+        let span = &Span::inactive().handle();
+        let mut miss_handler = storage.get_miss_handler(key, meta, span).await.unwrap();
+        for chunk in body {
+            miss_handler
+                .write_body(Bytes::copy_from_slice(chunk.as_bytes()), false)
+                .await
+                .unwrap();
+        }
+        miss_handler.finish().await.unwrap()
+    }
+
+    async fn read(storage: &'static DiskStorage, key: &CacheKey) -> Option<String> {
+        let span = &Span::inactive().handle();
+        let (_, mut hit_handler) = storage.lookup(key, span).await.unwrap()?;
+        let mut body = String::new();
+        while let Some(data) = hit_handler.read_body().await.unwrap() {
+            body.push_str(std::str::from_utf8(&data).unwrap());
+        }
+        Some(body)
+    }
+
+    fn body_path(storage: &DiskStorage, key: &CacheKey) -> PathBuf {
+        let hash = key.combined();
+        let sidecar = std::fs::read(storage.meta_path(&hash)).unwrap();
+        let (_, _, body) = decode_sidecar_prefix(&sidecar).unwrap();
+        storage.body_path(&hash, body.id)
+    }
+
+    // the number of the files of the asset in its shard
+    fn asset_files(storage: &DiskStorage, key: &CacheKey) -> usize {
+        let hash = key.combined();
+        match std::fs::read_dir(storage.root.join(&hash[..2])) {
+            Ok(dir) => dir
+                .flatten()
+                .filter(|f| f.file_name().to_string_lossy().starts_with(&hash))
+                .count(),
+            Err(_) => 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_then_read() {
+        let dir = test_dir("rw");
+        let storage = new_storage(&dir, 1 << 20);
+        let key1 = CacheKey::new("", "a", "1");
+        assert_eq!(read(storage, &key1).await, None);
+
+        let size = write(storage, &key1, &["test1", "test2"]).await;
+        assert_eq!(storage.size(), size);
+        assert_eq!(read(storage, &key1).await.unwrap(), "test1test2");
+
+        // survives restarts
+        let storage = new_storage(&dir, 1 << 20);
+        assert_eq!(storage.size(), size);
+        assert_eq!(read(storage, &key1).await.unwrap(), "test1test2");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_range() {
+        let dir = test_dir("range");
+        let storage = new_storage(&dir, 1 << 20);
+        let span = &Span::inactive().handle();
+        let key1 = CacheKey::new("", "a", "1");
+        write(storage, &key1, &["test1test2"]).await;
+
+        let (_, mut hit_handler) = storage.lookup(&key1, span).await.unwrap().unwrap();
+        assert!(hit_handler.can_seek());
+        // out of range
+        assert!(hit_handler.seek(10000, None).is_err());
+
+        assert!(hit_handler.seek(5, None).is_ok());
+        let data = hit_handler.read_body().await.unwrap().unwrap();
+        assert_eq!("test2", data);
+        assert!(hit_handler.read_body().await.unwrap().is_none());
+
+        assert!(hit_handler.seek(4, Some(5)).is_ok());
+        let data = hit_handler.read_body().await.unwrap().unwrap();
+        assert_eq!("1", data);
+        assert!(hit_handler.read_body().await.unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_large_body() {
+        let dir = test_dir("large");
+        let storage = new_storage(&dir, 1 << 24);
+        let key1 = CacheKey::new("", "a", "1");
+        let chunk = "x".repeat(100_000);
+        write(storage, &key1, &[&chunk, &chunk, &chunk]).await;
+        assert_eq!(read(storage, &key1).await.unwrap().len(), 300_000);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_eviction() {
+        let dir = test_dir("evict");
+        let key1 = CacheKey::new("", "a", "1");
+        let key2 = CacheKey::new("", "b", "1");
+        let key3 = CacheKey::new("", "c", "1");
+
+        let storage = new_storage(&dir, 1 << 20);
+        let size = write(storage, &key1, &["test1"]).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // room for 2 assets
+        let storage = new_storage(&dir, size * 2);
+        write(storage, &key1, &["test1"]).await;
+        write(storage, &key2, &["test2"]).await;
+        // key1 is now more recently used than key2
+        assert!(read(storage, &key1).await.is_some());
+        write(storage, &key3, &["test3"]).await;
+
+        assert_eq!(storage.size(), size * 2);
+        assert!(read(storage, &key1).await.is_some());
+        assert!(read(storage, &key2).await.is_none());
+        assert!(read(storage, &key3).await.is_some());
+        assert_eq!(asset_files(storage, &key2), 0);
+
+        // a smaller limit evicts on startup
+        let storage = new_storage(&dir, size);
+        assert_eq!(storage.size(), size);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_purge_and_update_meta() {
+        let dir = test_dir("purge");
+        let storage = new_storage(&dir, 1 << 20);
+        let span = &Span::inactive().handle();
+        let key1 = CacheKey::new("", "a", "1");
+
+        let mut meta = gen_meta();
+        assert!(!storage.update_meta(&key1, &meta, span).await.unwrap());
+
+        write(storage, &key1, &["test1"]).await;
+        meta.0.header.insert_header("foo2", "bar2").unwrap();
+        assert!(storage.update_meta(&key1, &meta, span).await.unwrap());
+        let (meta2, _) = storage.lookup(&key1, span).await.unwrap().unwrap();
+        assert_eq!(meta2.0.header.headers.get("foo2").unwrap(), "bar2");
+        assert_eq!(read(storage, &key1).await.unwrap(), "test1");
+
+        assert!(storage.purge(&key1.to_compact(), span).await.unwrap());
+        assert!(!storage.purge(&key1.to_compact(), span).await.unwrap());
+        assert!(read(storage, &key1).await.is_none());
+        assert_eq!(storage.size(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replace() {
+        let dir = test_dir("replace");
+        let storage = new_storage(&dir, 1 << 20);
+        let span = &Span::inactive().handle();
+        let key1 = CacheKey::new("", "a", "1");
+        write(storage, &key1, &["test1"]).await;
+
+        let (_, mut hit_handler) = storage.lookup(&key1, span).await.unwrap().unwrap();
+        // replaced with a body of the same length
+        write(storage, &key1, &["test2"]).await;
+        // the body already opened is still the one of its meta
+        let data = hit_handler.read_body().await.unwrap().unwrap();
+        assert_eq!("test1", data);
+        assert_eq!(read(storage, &key1).await.unwrap(), "test2");
+        // the old body is removed
+        assert_eq!(asset_files(storage, &key1), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_write() {
+        let dir = test_dir("abandon");
+        let storage = new_storage(&dir, 1 << 20);
+        let span = &Span::inactive().handle();
+        let key1 = CacheKey::new("", "a", "1");
+
+        let mut miss_handler = storage
+            .get_miss_handler(&key1, &gen_meta(), span)
+            .await
+            .unwrap();
+        miss_handler
+            .write_body(b"test1"[..].into(), false)
+            .await
+            .unwrap();
+        // not visible until finished
+        assert!(read(storage, &key1).await.is_none());
+        drop(miss_handler);
+
+        assert!(read(storage, &key1).await.is_none());
+        assert_eq!(std::fs::read_dir(dir.join(TMP_DIR)).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let span = &Span::inactive().handle();
+        let mut text = gen_meta();
+        text.0
+            .header
+            .insert_header("Content-Type", "text/html")
+            .unwrap();
+        let chunk = "<p>hello world</p>".repeat(1000);
+
+        for codec in [DiskCodec::Gzip, DiskCodec::Zstd] {
+            let dir = test_dir(&format!("compress-{codec:?}"));
+            let storage = Box::leak(Box::new(
+                DiskStorage::new(&dir, 1 << 24)
+                    .unwrap()
+                    .with_compression(DiskCompression::new(codec)),
+            ));
+            let key1 = CacheKey::new("", "a", "1");
+            let size = write_meta(storage, &key1, &text, &[&chunk, &chunk]).await;
+            assert!(size < chunk.len());
+            let body = read(storage, &key1).await.unwrap();
+            assert_eq!(body, chunk.repeat(2));
+
+            // range reads are served from the decompressed body
+            let (_, mut hit_handler) = storage.lookup(&key1, span).await.unwrap().unwrap();
+            assert!(hit_handler
+                .seek(chunk.len() + 3, Some(chunk.len() + 8))
+                .is_ok());
+            let data = hit_handler.read_body().await.unwrap().unwrap();
+            assert_eq!("hello", data);
+            assert!(hit_handler.read_body().await.unwrap().is_none());
+
+            // too small
+            let key2 = CacheKey::new("", "b", "1");
+            write_meta(storage, &key2, &text, &["<p>hello</p>"]).await;
+            assert_eq!(
+                std::fs::metadata(body_path(storage, &key2)).unwrap().len(),
+                12
+            );
+
+            // too large
+            let storage = Box::leak(Box::new(
+                DiskStorage::new(&dir, 1 << 24)
+                    .unwrap()
+                    .with_compression(DiskCompression {
+                        max_size: chunk.len(),
+                        ..DiskCompression::new(codec)
+                    }),
+            ));
+            let key4 = CacheKey::new("", "d", "1");
+            write_meta(storage, &key4, &text, &[&chunk, "<p>"]).await;
+            assert_eq!(
+                std::fs::metadata(body_path(storage, &key4)).unwrap().len(),
+                chunk.len() as u64 + 3
+            );
+
+            // not an eligible type
+            let key3 = CacheKey::new("", "c", "1");
+            write(storage, &key3, &[&chunk]).await;
+            assert_eq!(
+                std::fs::metadata(body_path(storage, &key3)).unwrap().len(),
+                chunk.len() as u64
+            );
+
+            // the compressed assets are still readable without compression configured
+            let storage = new_storage(&dir, 1 << 24);
+            assert_eq!(read(storage, &key1).await.unwrap(), chunk.repeat(2));
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compression_failure() {
+        let dir = test_dir("compress-failure");
+        let compression = DiskCompression::new(DiskCodec::Zstd);
+        let storage = DiskStorage::new(&dir, 1 << 24)
+            .unwrap()
+            .with_compression(compression.clone());
+        // the body is kept uncompressed instead of failing the write
+        let missing = dir.join("missing");
+        let body = storage
+            .compress("a", &missing, 1, 100, &compression)
+            .await
+            .unwrap();
+        assert!(body.codec.is_none());
+        assert_eq!(body.stored_len, 100);
+        assert_eq!(std::fs::read_dir(dir.join(TMP_DIR)).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compression_eligible() {
+        let compression = DiskCompression::new(DiskCodec::Zstd);
+        let mut header = ResponseHeader::build(200, None).unwrap();
+        assert!(!compression.eligible(&header));
+        header
+            .insert_header("Content-Type", "Application/JSON; charset=utf-8")
+            .unwrap();
+        assert!(compression.eligible(&header));
+        header.insert_header("Content-Encoding", "br").unwrap();
+        assert!(!compression.eligible(&header));
+        header.insert_header("Content-Type", "image/png").unwrap();
+        header.remove_header("Content-Encoding");
+        assert!(!compression.eligible(&header));
+    }
+}
@@ -18,7 +18,7 @@ use super::*;
 use crate::cache_control::{CacheControl, Cacheable, InterpretCacheControl};
 use crate::RespCacheable::*;
 
-use http::{header, HeaderValue};
+use http::{header, HeaderValue, StatusCode};
 use httpdate::HttpDate;
 use log::warn;
 use pingora_http::RequestHeader;
@@ -39,6 +39,9 @@ pub fn resp_cacheable(
     authorization_present: bool,
     defaults: &CacheMetaDefaults,
 ) -> RespCacheable {
+    if is_vary_wildcard(resp_header) {
+        return Uncacheable(NoCacheReason::OriginNotCache);
+    }
     let now = SystemTime::now();
     let expire_time = calculate_fresh_until(
         now,
@@ -66,6 +69,157 @@ pub fn resp_cacheable(
     Uncacheable(NoCacheReason::OriginNotCache)
 }
 
+/// The override of the TTL derived from the response headers, see [apply_ttl_override()]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtlOverride {
+    /// Cache the response for this long
+    Ttl(Duration),
+    /// Do not cache the response
+    NoCache,
+}
+
+/// Apply a [TtlOverride] to the result of a cacheability decision such as [resp_cacheable()].
+///
+/// The override takes precedence over the TTL derived from `Cache-Control` and `Expires`:
+/// - [TtlOverride::NoCache] makes the response uncacheable.
+/// - [TtlOverride::Ttl] replaces the TTL of a cacheable response. A response that is
+///   uncacheable only because the origin sent no caching headers
+///   ([NoCacheReason::OriginNotCache]) is made cacheable with this TTL.
+///
+/// The override never makes a response cacheable when the origin forbids it with
+/// `Cache-Control: no-store` or `private`, or when the request has `Authorization` and the
+/// origin doesn't allow caching it. Responses made uncacheable for other reasons are unchanged.
+pub fn apply_ttl_override(
+    cacheable: RespCacheable,
+    ttl_override: TtlOverride,
+    cache_control: Option<&CacheControl>,
+    resp_header: &ResponseHeader,
+    authorization_present: bool,
+) -> RespCacheable {
+    let ttl = match ttl_override {
+        TtlOverride::NoCache => {
+            return match cacheable {
+                Cacheable(_) => Uncacheable(NoCacheReason::Custom("ttl_override")),
+                uncacheable => uncacheable,
+            };
+        }
+        TtlOverride::Ttl(ttl) => ttl,
+    };
+
+    match cacheable {
+        Cacheable(mut meta) => {
+            if let Some(fresh_until) = meta.created().checked_add(ttl) {
+                meta.0.internal.fresh_until = fresh_until;
+            }
+            Cacheable(meta)
+        }
+        Uncacheable(NoCacheReason::OriginNotCache) => {
+            let forbidden = match cache_control {
+                Some(cc) => {
+                    cc.is_cacheable() == Cacheable::No
+                        || (authorization_present && !cc.allow_caching_authorized_req())
+                }
+                None => authorization_present,
+            };
+            let now = SystemTime::now();
+            let fresh_until = now.checked_add(ttl);
+            if forbidden || fresh_until.is_none() {
+                return Uncacheable(NoCacheReason::OriginNotCache);
+            }
+            let mut cloned_header = resp_header.clone();
+            if let Some(cc) = cache_control {
+                cc.strip_private_headers(&mut cloned_header);
+            }
+            Cacheable(CacheMeta::new(
+                fresh_until.unwrap(),
+                now,
+                0,
+                0,
+                cloned_header,
+            ))
+        }
+        uncacheable => uncacheable,
+    }
+}
+
+/// The settings of negative caching: caching error responses for a short time so that repeated
+/// requests to a failing URL don't all go to the origin.
+///
+/// The TTL of the error responses is separate from the TTL of the regular responses.
+#[derive(Debug, Clone)]
+pub struct NegativeCaching {
+    statuses: Vec<u16>,
+    ttl_sec: u32,
+}
+
+impl NegativeCaching {
+    /// Create a new [NegativeCaching] which caches the responses of the given status codes for
+    /// `ttl_sec` seconds.
+    pub fn new(statuses: &[u16], ttl_sec: u32) -> Self {
+        NegativeCaching {
+            statuses: statuses.to_vec(),
+            ttl_sec,
+        }
+    }
+
+    /// Whether the responses of the given status code are negatively cached
+    pub fn is_negative(&self, status: StatusCode) -> bool {
+        self.statuses.contains(&status.as_u16())
+    }
+
+    /// The TTL of the negatively cached responses
+    pub fn ttl_sec(&self) -> u32 {
+        self.ttl_sec
+    }
+}
+
+/// Decide if the response is cacheable, with negative caching.
+///
+/// Responses with one of the status codes of `negative` are cacheable for its TTL, capped by the
+/// TTL in `Cache-Control` if shorter, and are never served stale. `Cache-Control: no-store` or
+/// `private` still makes them uncacheable. Other responses are handled by [resp_cacheable()].
+pub fn resp_cacheable_with_negative(
+    cache_control: Option<&CacheControl>,
+    resp_header: &ResponseHeader,
+    authorization_present: bool,
+    defaults: &CacheMetaDefaults,
+    negative: &NegativeCaching,
+) -> RespCacheable {
+    if !negative.is_negative(resp_header.status) {
+        return resp_cacheable(cache_control, resp_header, authorization_present, defaults);
+    }
+
+    let uncacheable = match cache_control {
+        Some(cc) => {
+            cc.is_cacheable() == Cacheable::No
+                || (authorization_present && !cc.allow_caching_authorized_req())
+        }
+        None => authorization_present,
+    };
+    if uncacheable || is_vary_wildcard(resp_header) {
+        return Uncacheable(NoCacheReason::OriginNotCache);
+    }
+
+    let now = SystemTime::now();
+    let ttl = match cache_control.and_then(|cc| cc.fresh_sec()) {
+        Some(fresh_sec) => std::cmp::min(fresh_sec, negative.ttl_sec),
+        None => negative.ttl_sec,
+    };
+    if ttl == 0 {
+        return Uncacheable(NoCacheReason::OriginNotCache);
+    }
+    let Some(fresh_until) = now.checked_add(Duration::from_secs(ttl.into())) else {
+        return Uncacheable(NoCacheReason::OriginNotCache);
+    };
+
+    let mut cloned_header = resp_header.clone();
+    if let Some(cc) = cache_control {
+        cc.strip_private_headers(&mut cloned_header);
+    }
+    // don't serve stale errors
+    Cacheable(CacheMeta::new(fresh_until, now, 0, 0, cloned_header))
+}
+
 /// Calculate the [SystemTime] at which the asset expires
 ///
 /// Return None when not cacheable.
@@ -206,7 +360,10 @@ pub mod upstream {
 mod tests {
     use super::*;
     use crate::RespCacheable::Cacheable;
-    use http::header::{HeaderName, CACHE_CONTROL, EXPIRES, SET_COOKIE};
+    use http::header::{
+        HeaderName, CACHE_CONTROL, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+        RANGE, SET_COOKIE, VARY,
+    };
     use http::StatusCode;
     use httpdate::fmt_http_date;
 
@@ -253,6 +410,90 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_resp_ttl_override() {
+        let apply = |resp: &ResponseHeader, ttl_override, authorization_present| {
+            let cc = CacheControl::from_resp_headers(resp);
+            let cacheable = resp_cacheable(
+                cc.as_ref(),
+                resp,
+                authorization_present,
+                &BYPASS_CACHE_DEFAULTS,
+            );
+            match apply_ttl_override(
+                cacheable,
+                ttl_override,
+                cc.as_ref(),
+                resp,
+                authorization_present,
+            ) {
+                Cacheable(meta) => Some(meta),
+                _ => None,
+            }
+        };
+        let now = SystemTime::now();
+        let hour = TtlOverride::Ttl(Duration::from_secs(3600));
+
+        // cap the TTL
+        let resp = build_response(200, &[(CACHE_CONTROL, "max-age=86400")]);
+        let meta = apply(&resp, hour, false).unwrap();
+        assert!(meta.is_fresh(now + Duration::from_secs(3500)));
+        assert!(!meta.is_fresh(now + Duration::from_secs(3700)));
+
+        // cache without caching headers
+        let resp = build_response(200, &[]);
+        assert!(apply(&resp, TtlOverride::NoCache, false).is_none());
+        let meta = apply(&resp, hour, false).unwrap();
+        assert!(meta.is_fresh(now + Duration::from_secs(3500)));
+        assert!(apply(&resp, hour, true).is_none());
+
+        // no-store and private win
+        let resp = build_response(200, &[(CACHE_CONTROL, "no-store")]);
+        assert!(apply(&resp, hour, false).is_none());
+        let resp = build_response(200, &[(CACHE_CONTROL, "private, max-age=60")]);
+        assert!(apply(&resp, hour, false).is_none());
+
+        // do not cache
+        let resp = build_response(200, &[(CACHE_CONTROL, "max-age=60")]);
+        assert!(apply(&resp, TtlOverride::NoCache, false).is_none());
+    }
+
+    #[test]
+    fn test_resp_negative_caching() {
+        let negative = NegativeCaching::new(&[404, 502], 3);
+        let cacheable = |resp: &ResponseHeader| match resp_cacheable_with_negative(
+            CacheControl::from_resp_headers(resp).as_ref(),
+            resp,
+            false,
+            &BYPASS_CACHE_DEFAULTS,
+            &negative,
+        ) {
+            Cacheable(meta) => Some(meta),
+            _ => None,
+        };
+        let now = SystemTime::now();
+
+        let meta = cacheable(&build_response(502, &[])).unwrap();
+        assert!(meta.is_fresh(now));
+        assert!(!meta.is_fresh(now + Duration::from_secs(4)));
+        assert!(!meta.serve_stale_if_error(now + Duration::from_secs(4)));
+        assert!(!meta.serve_stale_while_revalidate(now + Duration::from_secs(4)));
+
+        // the negative TTL is separate from the origin TTL
+        let meta = cacheable(&build_response(404, &[(CACHE_CONTROL, "max-age=3600")])).unwrap();
+        assert!(!meta.is_fresh(now + Duration::from_secs(4)));
+        // but a shorter origin TTL is respected
+        let meta = cacheable(&build_response(404, &[(CACHE_CONTROL, "max-age=1")])).unwrap();
+        assert!(!meta.is_fresh(now + Duration::from_secs(2)));
+        assert!(cacheable(&build_response(404, &[(CACHE_CONTROL, "max-age=0")])).is_none());
+
+        assert!(cacheable(&build_response(502, &[(CACHE_CONTROL, "no-store")])).is_none());
+        assert!(cacheable(&build_response(502, &[(CACHE_CONTROL, "private")])).is_none());
+        // not in the negative set, follow the defaults which cache nothing
+        assert!(cacheable(&build_response(503, &[])).is_none());
+        assert!(cacheable(&build_response(200, &[])).is_none());
+    }
+
     #[test]
     fn test_resp_cacheable() {
         let meta = resp_cacheable_wrapper(
@@ -292,6 +533,26 @@ mod tests {
         assert!(meta.is_none());
     }
 
+    #[test]
+    fn test_resp_vary_wildcard() {
+        let meta = resp_cacheable_wrapper(
+            &build_response(200, &[(CACHE_CONTROL, "max-age=12345"), (VARY, "*")]),
+            &DEFAULTS,
+            false,
+        );
+        assert!(meta.is_none());
+
+        let meta = resp_cacheable_wrapper(
+            &build_response(
+                200,
+                &[(CACHE_CONTROL, "max-age=12345"), (VARY, "Accept-Encoding")],
+            ),
+            &DEFAULTS,
+            false,
+        );
+        assert!(meta.is_some());
+    }
+
     #[test]
     fn test_resp_cache_authorization() {
         let meta = resp_cacheable_wrapper(&build_response(200, &[]), &DEFAULTS, true);
@@ -670,4 +931,51 @@ mod tests {
         assert!(!meta.headers().contains_key(SET_COOKIE));
         assert!(!meta.headers().contains_key("meta1"));
     }
+
+    #[test]
+    fn test_upstream_request_filter() {
+        let mut req = RequestHeader::build("HEAD", b"/", None).unwrap();
+        req.insert_header(IF_NONE_MATCH, "\"downstream\"").unwrap();
+        req.insert_header(RANGE, "bytes=0-1").unwrap();
+        upstream::request_filter(&mut req, None).unwrap();
+        // fetch the entire response to cache
+        assert_eq!(req.method, Method::GET);
+        assert!(!req.headers.contains_key(IF_NONE_MATCH));
+        assert!(!req.headers.contains_key(RANGE));
+
+        // revalidate with the validators of the stale response
+        let meta = resp_cacheable_wrapper(
+            &build_response(
+                200,
+                &[
+                    (CACHE_CONTROL, "max-age=0"),
+                    (ETAG, "\"abc\""),
+                    (LAST_MODIFIED, "Wed, 09 Oct 2024 07:28:00 GMT"),
+                ],
+            ),
+            &DEFAULTS,
+            false,
+        )
+        .unwrap();
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header(IF_NONE_MATCH, "\"downstream\"").unwrap();
+        upstream::request_filter(&mut req, Some(&meta)).unwrap();
+        assert_eq!(req.headers[IF_NONE_MATCH], "\"abc\"");
+        assert_eq!(
+            req.headers[IF_MODIFIED_SINCE],
+            "Wed, 09 Oct 2024 07:28:00 GMT"
+        );
+
+        // no validator to send
+        let meta = resp_cacheable_wrapper(
+            &build_response(200, &[(CACHE_CONTROL, "max-age=0")]),
+            &DEFAULTS,
+            false,
+        )
+        .unwrap();
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        upstream::request_filter(&mut req, Some(&meta)).unwrap();
+        assert!(!req.headers.contains_key(IF_NONE_MATCH));
+        assert!(!req.headers.contains_key(IF_MODIFIED_SINCE));
+    }
 }
@@ -243,6 +243,172 @@ impl CacheHashKey for CacheKey {
     }
 }
 
+/// How the query parameters are used in the cache key built by [CacheKeyBuilder]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryParams {
+    /// Use all the query parameters
+    All,
+    /// Ignore the query string
+    None,
+    /// Only use the query parameters with the given names
+    Include(Vec<String>),
+    /// Use all but the query parameters with the given names, e.g., tracking parameters
+    Exclude(Vec<String>),
+}
+
+type HeaderNormalizer = Box<dyn Fn(Option<&str>) -> String + Send + Sync>;
+
+/// A builder to construct the [CacheKey] of each request from its method, host, path, query
+/// parameters and headers.
+///
+/// The primary key is a plain string of the selected components so that it is stable across
+/// restarts and machines. By default, the key is made of the host and the path with all the
+/// query parameters sorted by name.
+///
+/// The builder is meant to be created once and used in the `cache_key_callback()` of the proxy.
+pub struct CacheKeyBuilder {
+    namespace: String,
+    method: bool,
+    host: bool,
+    lowercase_path: bool,
+    query: QueryParams,
+    sort_query: bool,
+    headers: Vec<(String, HeaderNormalizer)>,
+}
+
+impl Default for CacheKeyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CacheKeyBuilder {
+    /// Create a new [CacheKeyBuilder] with the default settings
+    pub fn new() -> Self {
+        CacheKeyBuilder {
+            namespace: "".into(),
+            method: false,
+            host: true,
+            lowercase_path: false,
+            query: QueryParams::All,
+            sort_query: true,
+            headers: vec![],
+        }
+    }
+
+    /// Set the namespace of the keys
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = namespace.into();
+        self
+    }
+
+    /// Whether to include the request method, `false` by default so that `HEAD` and `GET` share
+    /// the same asset.
+    pub fn method(mut self, method: bool) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// Whether to include the host, `true` by default. The host is always lowercased.
+    pub fn host(mut self, host: bool) -> Self {
+        self.host = host;
+        self
+    }
+
+    /// Whether to lowercase the path, `false` by default
+    pub fn lowercase_path(mut self, lowercase: bool) -> Self {
+        self.lowercase_path = lowercase;
+        self
+    }
+
+    /// Select the query parameters to include, [QueryParams::All] by default
+    pub fn query(mut self, query: QueryParams) -> Self {
+        self.query = query;
+        self
+    }
+
+    /// Whether to sort the query parameters by name, `true` by default, so that the order of the
+    /// parameters doesn't matter.
+    pub fn sort_query(mut self, sort: bool) -> Self {
+        self.sort_query = sort;
+        self
+    }
+
+    /// Include the value of the given request header, trimmed and lowercased
+    pub fn header(self, name: &str) -> Self {
+        self.header_with(name, |v| {
+            v.map(|v| v.trim().to_ascii_lowercase()).unwrap_or_default()
+        })
+    }
+
+    /// Include a value derived from the given request header, e.g., to bucket the values of
+    /// `Accept-Encoding`. The function receives `None` when the header is absent.
+    pub fn header_with<F>(mut self, name: &str, normalize: F) -> Self
+    where
+        F: Fn(Option<&str>) -> String + Send + Sync + 'static,
+    {
+        self.headers
+            .push((name.to_ascii_lowercase(), Box::new(normalize)));
+        self
+    }
+
+    fn query_string(&self, query: &str) -> String {
+        let mut params: Vec<&str> = query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .filter(|p| {
+                let name = p.split_once('=').map_or(*p, |(n, _)| n);
+                match &self.query {
+                    QueryParams::All => true,
+                    QueryParams::None => false,
+                    QueryParams::Include(names) => names.iter().any(|n| n == name),
+                    QueryParams::Exclude(names) => !names.iter().any(|n| n == name),
+                }
+            })
+            .collect();
+        if self.sort_query {
+            // stable sort: keep the order of the repeated parameters
+            params.sort_by_key(|p| p.split_once('=').map_or(*p, |(n, _)| n));
+        }
+        params.join("&")
+    }
+
+    /// Build the [CacheKey] of the given request
+    pub fn build(&self, req: &ReqHeader) -> CacheKey {
+        use std::fmt::Write;
+        let mut primary = String::new();
+        if self.method {
+            write!(primary, "{} ", req.method).unwrap();
+        }
+        if self.host {
+            let host = req
+                .headers
+                .get(http::header::HOST)
+                .and_then(|h| h.to_str().ok())
+                .or_else(|| req.uri.host())
+                .unwrap_or_default();
+            primary.push_str(&host.to_ascii_lowercase());
+        }
+        let path = req.uri.path();
+        if self.lowercase_path {
+            primary.push_str(&path.to_lowercase());
+        } else {
+            primary.push_str(path);
+        }
+        let query = req.uri.query().map(|q| self.query_string(q));
+        if let Some(query) = query.filter(|q| !q.is_empty()) {
+            primary.push('?');
+            primary.push_str(&query);
+        }
+        for (name, normalize) in self.headers.iter() {
+            let value = req.headers.get(name.as_str()).and_then(|v| v.to_str().ok());
+            // use a separator that cannot be in a URI
+            write!(primary, "\n{name}:{}", normalize(value)).unwrap();
+        }
+        CacheKey::new(self.namespace.clone(), primary, "")
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -286,6 +452,77 @@ mod tests {
         assert_eq!(compact.combined(), "004174d3e75a811a5b44c46b3856f3ee");
     }
 
+    fn request(uri: &str, headers: &[(&str, &str)]) -> ReqHeader {
+        let mut req = http::Request::builder().uri(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn test_cache_key_builder() {
+        let builder = CacheKeyBuilder::new();
+        let req = request("/a/B?z=1&a=2&a=1", &[("Host", "Example.com")]);
+        let key = builder.build(&req);
+        assert_eq!(key.primary_key(), "example.com/a/B?a=2&a=1&z=1");
+        // the order of the parameters doesn't matter
+        let req2 = request("/a/B?a=2&z=1&a=1", &[("Host", "example.com")]);
+        assert_eq!(builder.build(&req2).primary(), key.primary());
+        // stable across restarts
+        assert_eq!(
+            builder.build(&req).primary(),
+            CacheKey::new("", "example.com/a/B?a=2&a=1&z=1", "").primary()
+        );
+
+        let builder = CacheKeyBuilder::new()
+            .host(false)
+            .method(true)
+            .lowercase_path(true)
+            .query(QueryParams::Exclude(vec!["utm_source".into()]));
+        let req = request("http://example.com/A?utm_source=x&b=1", &[]);
+        assert_eq!(builder.build(&req).primary_key(), "GET /a?b=1");
+        let req = request("/A?utm_source=x", &[]);
+        assert_eq!(builder.build(&req).primary_key(), "GET /a");
+
+        let builder = CacheKeyBuilder::new()
+            .host(false)
+            .query(QueryParams::Include(vec!["id".into()]));
+        let req = request("/a?x=1&id=2", &[]);
+        assert_eq!(builder.build(&req).primary_key(), "/a?id=2");
+        let builder = builder.query(QueryParams::None);
+        assert_eq!(builder.build(&req).primary_key(), "/a");
+    }
+
+    #[test]
+    fn test_cache_key_builder_headers() {
+        let builder = CacheKeyBuilder::new()
+            .host(false)
+            .namespace("ns")
+            .header("X-Version")
+            .header_with("Accept-Encoding", |v| {
+                if v.is_some_and(|v| v.contains("br")) {
+                    "br".into()
+                } else if v.is_some_and(|v| v.contains("gzip")) {
+                    "gzip".into()
+                } else {
+                    "".into()
+                }
+            });
+        let req = request(
+            "/a",
+            &[("X-Version", " V1 "), ("Accept-Encoding", "gzip, deflate")],
+        );
+        let key = builder.build(&req);
+        assert_eq!(key.namespace(), "ns");
+        assert_eq!(key.primary_key(), "/a\nx-version:v1\naccept-encoding:gzip");
+        let req = request("/a", &[("Accept-Encoding", "br")]);
+        assert_eq!(
+            builder.build(&req).primary_key(),
+            "/a\nx-version:\naccept-encoding:br"
+        );
+    }
+
     #[test]
     fn test_hex_str() {
         let mut key = [0; KEY_SIZE];
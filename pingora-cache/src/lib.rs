@@ -16,6 +16,7 @@
 
 #![allow(clippy::new_without_default)]
 
+use http::HeaderMap;
 use http::{method::Method, request::Parts as ReqHeader, response::Parts as RespHeader};
 use key::{CacheHashKey, HashBinary};
 use lock::WritePermit;
@@ -25,6 +26,7 @@ use std::time::{Duration, SystemTime};
 use trace::CacheTraceCTX;
 
 pub mod cache_control;
+pub mod disk;
 pub mod eviction;
 pub mod filters;
 pub mod hashtable;
@@ -34,18 +36,23 @@ pub mod max_file_size;
 mod memory;
 pub mod meta;
 pub mod predictor;
+pub mod purge;
 pub mod put;
+pub mod stats;
 pub mod storage;
 pub mod trace;
 mod variance;
 
 use crate::max_file_size::MaxFileSizeMissHandler;
-pub use key::CacheKey;
+pub use disk::{DiskCodec, DiskCompression, DiskStorage};
+pub use key::{CacheKey, CacheKeyBuilder};
 use lock::{CacheLock, LockStatus, Locked};
 pub use memory::MemCache;
 pub use meta::{CacheMeta, CacheMetaDefaults};
+pub use purge::{CacheTags, Purger};
+pub use stats::{CacheCounters, CacheStats};
 pub use storage::{HitHandler, MissHandler, Storage};
-pub use variance::VarianceBuilder;
+pub use variance::{is_vary_wildcard, vary_variance, VarianceBuilder, VariantLimit};
 
 pub mod prelude {}
 
@@ -57,6 +64,8 @@ pub struct HttpCache {
     phase: CachePhase,
     // Box the rest so that a disabled HttpCache struct is small
     inner: Option<Box<HttpCacheInner>>,
+    // kept outside of inner so that the numbers survive disable()
+    stats: stats::RequestStats,
 }
 
 /// This reflects the phase of HttpCache during the lifetime of a request
@@ -127,6 +136,9 @@ pub enum NoCacheReason {
     /// This request waited too long for the writer of the cache lock to finish, so this request will
     /// fetch from the origin without caching
     CacheLockTimeout,
+    /// The primary key of the asset already has as many variants cached as allowed, see
+    /// [VariantLimit]
+    TooManyVariants,
     /// Other custom defined reasons
     Custom(&'static str),
 }
@@ -144,6 +156,7 @@ impl NoCacheReason {
             Deferred => "Deferred",
             CacheLockGiveUp => "CacheLockGiveUp",
             CacheLockTimeout => "CacheLockTimeout",
+            TooManyVariants => "TooManyVariants",
             Custom(s) => s,
         }
     }
@@ -219,6 +232,8 @@ struct HttpCacheInner {
     pub storage: &'static (dyn storage::Storage + Sync), // static for now
     pub eviction: Option<&'static (dyn eviction::EvictionManager + Sync)>,
     pub predictor: Option<&'static (dyn predictor::CacheablePredictor + Sync)>,
+    pub tags: Option<&'static CacheTags>,
+    pub variant_limit: Option<&'static VariantLimit>,
     pub lock: Option<Locked>, // TODO: these 3 fields should come in 1 sub struct
     pub cache_lock: Option<&'static CacheLock>,
     pub lock_duration: Option<Duration>,
@@ -233,6 +248,7 @@ impl HttpCache {
         HttpCache {
             phase: CachePhase::Disabled(NoCacheReason::NeverEnabled),
             inner: None,
+            stats: Default::default(),
         }
     }
 
@@ -251,6 +267,17 @@ impl HttpCache {
         self.phase
     }
 
+    /// Record the body bytes served from the cache, for [CacheCounters]
+    pub fn record_cache_bytes(&mut self, bytes: usize) {
+        self.stats.cache_bytes += bytes;
+    }
+
+    /// Record the body bytes received from the origin to be admitted to the cache, for
+    /// [CacheCounters]
+    pub fn record_origin_bytes(&mut self, bytes: usize) {
+        self.stats.origin_bytes += bytes;
+    }
+
     /// Whether anything was fetched from the upstream
     ///
     /// This essentially checks all possible [CachePhase] who need to contact the upstream server
@@ -288,7 +315,9 @@ impl HttpCache {
                             // let the next request try to fetch it
                             InternalError | StorageError | Deferred => LockStatus::TransientError,
                             // no need for the lock anymore
-                            OriginNotCache | ResponseTooLarge => LockStatus::GiveUp,
+                            OriginNotCache | ResponseTooLarge | TooManyVariants => {
+                                LockStatus::GiveUp
+                            }
                             // not sure which LockStatus make sense, we treat it as GiveUp for now
                             Custom(_) => LockStatus::GiveUp,
                             // should never happen, NeverEnabled shouldn't hold a lock
@@ -366,6 +395,8 @@ impl HttpCache {
                     storage,
                     eviction,
                     predictor,
+                    tags: None,
+                    variant_limit: None,
                     lock: None,
                     cache_lock,
                     lock_duration: None,
@@ -442,6 +473,29 @@ impl HttpCache {
         }
     }
 
+    /// Set the [CacheTags] index to tag the assets admitted to the cache so that they can be
+    /// purged by tag via [Purger::purge_by_tag()].
+    pub fn set_cache_tags(&mut self, tags: &'static CacheTags) {
+        match self.phase {
+            CachePhase::Disabled(_) => panic!("wrong phase {:?}", self.phase),
+            _ => {
+                self.inner_mut().tags = Some(tags);
+            }
+        }
+    }
+
+    /// Set the [VariantLimit] to cap the number of the variants cached under the same primary key.
+    ///
+    /// The variants beyond the limit are not cached, see [Self::update_variance()].
+    pub fn set_variant_limit(&mut self, limit: &'static VariantLimit) {
+        match self.phase {
+            CachePhase::Disabled(_) => panic!("wrong phase {:?}", self.phase),
+            _ => {
+                self.inner_mut().variant_limit = Some(limit);
+            }
+        }
+    }
+
     /// Set that cache is found in cache storage.
     ///
     /// This function is called after [Self::cache_lookup()] which returns the [CacheMeta] and
@@ -639,13 +693,25 @@ impl HttpCache {
                 }
                 let miss_handler = inner.miss_handler.take().unwrap();
                 let size = miss_handler.finish().await?;
-                let lock = inner.lock.take();
                 let key = inner.key.as_ref().unwrap();
+                let meta = inner.meta.as_ref().unwrap();
+                if meta.0.internal.trailers.is_some() {
+                    // the trailers came after the meta was written along with the miss handler
+                    let mut span = inner.traces.child("update_meta");
+                    let result = inner.storage.update_meta(key, meta, &span.handle()).await;
+                    span.set_tag(|| trace::Tag::new("updated", result.is_ok()));
+                    result?;
+                }
+                let lock = inner.lock.take();
                 if let Some(Locked::Write(_r)) = lock {
                     // no need to call r.unlock() because release() will call it
                     // r is a guard to make sure the lock is unlocked when this request is dropped
                     inner.cache_lock.unwrap().release(key, LockStatus::Done);
                 }
+                if let Some(tags) = inner.tags {
+                    let meta = inner.meta.as_ref().unwrap();
+                    tags.insert(key.to_compact(), tags.tags_of(&meta.0.header));
+                }
                 if let Some(eviction) = inner.eviction {
                     let cache_key = key.to_compact();
                     let meta = inner.meta.as_ref().unwrap();
@@ -654,6 +720,9 @@ impl HttpCache {
                     let span = inner.traces.child("eviction");
                     let handle = span.handle();
                     for item in evicted {
+                        if let Some(tags) = inner.tags {
+                            tags.remove(&item);
+                        }
                         // TODO: warn/log the error
                         let _ = inner.storage.purge(&item, &handle).await;
                     }
@@ -665,6 +734,34 @@ impl HttpCache {
         }
     }
 
+    /// Store the response trailers of the asset being admitted in its [CacheMeta]
+    ///
+    /// The trailers come after the body, so the meta in the storage is updated when the admission
+    /// finishes, or right away if it already finished.
+    pub async fn set_miss_trailers(&mut self, trailers: &HeaderMap) -> Result<()> {
+        match self.phase {
+            CachePhase::Miss | CachePhase::Expired => {
+                let inner = self.inner_mut();
+                inner.meta.as_mut().unwrap().set_trailers(trailers);
+                if inner.miss_handler.is_some() {
+                    return Ok(());
+                }
+                let mut span = inner.traces.child("update_meta");
+                let result = inner
+                    .storage
+                    .update_meta(
+                        inner.key.as_ref().unwrap(),
+                        inner.meta.as_ref().unwrap(),
+                        &span.handle(),
+                    )
+                    .await;
+                span.set_tag(|| trace::Tag::new("updated", result.is_ok()));
+                result.map(|_| ())
+            }
+            _ => panic!("wrong phase {:?}", self.phase),
+        }
+    }
+
     /// Set the [CacheMeta] of the cache
     pub fn set_cache_meta(&mut self, meta: CacheMeta) {
         match self.phase {
@@ -699,6 +796,12 @@ impl HttpCache {
                 // no need to set `updated` here
 
                 inner.meta.replace(meta);
+                if let Some(tags) = inner.tags {
+                    // the tags might be updated by the revalidation
+                    let meta = inner.meta.as_ref().unwrap();
+                    let key = inner.key.as_ref().unwrap().to_compact();
+                    tags.insert(key, tags.tags_of(&meta.0.header));
+                }
 
                 let lock = inner.lock.take();
                 if let Some(Locked::Write(_r)) = lock {
@@ -784,6 +887,9 @@ impl HttpCache {
     /// Note that this process may change the lookup `key`, and eventually (when the asset is
     /// written to storage) invalidate other cached variants under the same primary key as the
     /// current asset.
+    ///
+    /// If a [VariantLimit] is set and this variant is beyond it, the cache is disabled with
+    /// [NoCacheReason::TooManyVariants], so check [Self::enabled()] before admitting the asset.
     pub fn update_variance(&mut self, variance: Option<HashBinary>) {
         // If this is a cache miss, we will simply update the variance in the meta.
         //
@@ -837,6 +943,12 @@ impl HttpCache {
                 inner.key.as_mut().unwrap().remove_variance_key();
             }
         }
+
+        if let (Some(limit), Some(variance)) = (inner.variant_limit, variance) {
+            if !limit.admit(inner.key.as_ref().unwrap(), variance) {
+                self.disable(NoCacheReason::TooManyVariants);
+            }
+        }
     }
 
     /// Return the [CacheMeta] of this asset
@@ -1007,6 +1119,16 @@ impl HttpCache {
                 .serve_stale_while_revalidate(SystemTime::now())
     }
 
+    /// Whether this asset is staled and the cache lock allows serving it instead of waiting for
+    /// the revalidation, see [CacheLock::with_serve_stale()].
+    pub fn can_serve_stale_while_locked(&self) -> bool {
+        self.has_staled_asset()
+            && self
+                .inner()
+                .cache_lock
+                .is_some_and(|lock| lock.serve_stale())
+    }
+
     /// Wait for the cache read lock to be unlocked
     /// # Panic
     /// Check [Self::is_cache_locked()], panic if this request doesn't have a read lock.
@@ -1016,7 +1138,7 @@ impl HttpCache {
         let lock = inner.lock.take(); // remove the lock from self
         if let Some(Locked::Read(r)) = lock {
             let now = std::time::Instant::now();
-            r.wait().await;
+            let lock_status = r.wait().await;
             let lock_duration = now.elapsed();
             // it's possible for a request to be locked more than once
             inner.lock_duration = Some(
@@ -1024,7 +1146,11 @@ impl HttpCache {
                     .lock_duration
                     .map_or(lock_duration, |d| d + lock_duration),
             );
-            r.lock_status() // TODO: tag the span with lock status
+            self.stats.lock_waits += 1;
+            if lock_status == LockStatus::Timeout {
+                self.stats.lock_timeouts += 1;
+            }
+            lock_status // TODO: tag the span with lock status
         } else {
             // should always call is_cache_locked() before this function
             panic!("cache_lock_wait on wrong type of lock")
@@ -1046,8 +1172,8 @@ impl HttpCache {
                 let inner = self.inner_mut();
                 let mut span = inner.traces.child("purge");
                 let key = inner.key.as_ref().unwrap().to_compact();
-                let result = inner.storage.purge(&key, &span.handle()).await;
-                // FIXME: also need to remove from eviction manager
+                let purger = Purger::new(inner.storage, inner.eviction, inner.tags);
+                let result = purger.purge(&key).await;
                 span.set_tag(|| trace::Tag::new("purged", matches!(result, Ok(true))));
                 result
             }
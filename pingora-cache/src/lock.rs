@@ -23,9 +23,15 @@ use std::sync::Arc;
 const N_SHARDS: usize = 16;
 
 /// The global cache locking manager
+///
+/// The first request of an asset that misses or needs revalidation gets the write lock and
+/// fetches from the origin while the other concurrent requests of the same asset wait for it to
+/// populate the cache, so that only one request goes to the origin.
 pub struct CacheLock {
     lock_table: ConcurrentHashTable<LockStub, N_SHARDS>,
     timeout: Duration, // fixed timeout value for now
+    wait_timeout: Duration,
+    serve_stale: bool,
 }
 
 /// A struct representing locked cache access
@@ -52,9 +58,35 @@ impl CacheLock {
         CacheLock {
             lock_table: ConcurrentHashTable::new(),
             timeout,
+            wait_timeout: timeout,
+            serve_stale: false,
         }
     }
 
+    /// Bound how long each reader waits for the writer, the lock timeout by default.
+    ///
+    /// A reader that waits longer than this gives up with [LockStatus::Timeout] even if the
+    /// writer is still within its lock timeout. The wait is never longer than the lock timeout.
+    pub fn with_wait_timeout(mut self, wait_timeout: Duration) -> Self {
+        self.wait_timeout = std::cmp::min(wait_timeout, self.timeout);
+        self
+    }
+
+    /// Whether the readers serve the stale asset, when there is one, instead of waiting for the
+    /// writer to revalidate it. `false` by default.
+    ///
+    /// Unlike stale-while-revalidate, this doesn't depend on the cache control directives of the
+    /// asset. Force expired assets are never served stale.
+    pub fn with_serve_stale(mut self, serve_stale: bool) -> Self {
+        self.serve_stale = serve_stale;
+        self
+    }
+
+    /// Whether the readers serve the stale asset during the lock, see [Self::with_serve_stale()]
+    pub fn serve_stale(&self) -> bool {
+        self.serve_stale
+    }
+
     /// Try to lock a cache fetch
     ///
     /// Users should call after a cache miss before fetching the asset.
@@ -72,7 +104,7 @@ impl CacheLock {
             // compete for the write lock again.
         }
 
-        let (permit, stub) = WritePermit::new(self.timeout);
+        let (permit, stub) = WritePermit::new(self.timeout, self.wait_timeout);
         let mut table = table.write();
         // check again in case another request already added it
         if let Some(lock) = table.get(&key) {
@@ -153,16 +185,18 @@ impl From<u8> for LockStatus {
 struct LockCore {
     pub lock_start: Instant,
     pub timeout: Duration,
+    pub wait_timeout: Duration,
     pub(super) lock: Semaphore,
     // use u8 for Atomic enum
     lock_status: AtomicU8,
 }
 
 impl LockCore {
-    pub fn new_arc(timeout: Duration) -> Arc<Self> {
+    pub fn new_arc(timeout: Duration, wait_timeout: Duration) -> Arc<Self> {
         Arc::new(LockCore {
             lock: Semaphore::new(0),
             timeout,
+            wait_timeout,
             lock_start: Instant::now(),
             lock_status: AtomicU8::new(LockStatus::Waiting.into()),
         })
@@ -192,16 +226,24 @@ pub struct ReadLock(Arc<LockCore>);
 
 impl ReadLock {
     /// Wait for the writer to release the lock
-    pub async fn wait(&self) {
-        if !self.locked() || self.expired() {
-            return;
+    ///
+    /// Return [LockStatus::Timeout] if the lock expired or this reader waited for longer than
+    /// the wait timeout, otherwise the status set by the writer.
+    pub async fn wait(&self) -> LockStatus {
+        if self.locked() && !self.expired() {
+            // don't wait beyond start + timeout
+            let remaining = self.0.timeout.saturating_sub(self.0.lock_start.elapsed());
+            let wait_timeout = std::cmp::min(remaining, self.0.wait_timeout);
+            // TODO: need to be careful not to wake everyone up at the same time
+            // (maybe not an issue because regular cache lock release behaves that way)
+            let _ = timeout(wait_timeout, self.0.lock.acquire()).await;
+            // permit is returned to Semaphore right away
+        }
+        match self.lock_status() {
+            // the writer is still working on it but this reader gives up
+            LockStatus::Waiting => LockStatus::Timeout,
+            status => status,
         }
-
-        // TODO: should subtract now - start so that the lock don't wait beyond start + timeout
-        // Also need to be careful not to wake everyone up at the same time
-        // (maybe not an issue because regular cache lock release behaves that way)
-        let _ = timeout(self.0.timeout, self.0.lock.acquire()).await;
-        // permit is returned to Semaphore right away
     }
 
     /// Test if it is still locked
@@ -232,8 +274,8 @@ impl ReadLock {
 pub struct WritePermit(Arc<LockCore>);
 
 impl WritePermit {
-    fn new(timeout: Duration) -> (WritePermit, LockStub) {
-        let lock = LockCore::new_arc(timeout);
+    fn new(timeout: Duration, wait_timeout: Duration) -> (WritePermit, LockStub) {
+        let lock = LockCore::new_arc(timeout, wait_timeout);
         let stub = LockStub(lock.clone());
         (WritePermit(lock), stub)
     }
@@ -333,4 +375,42 @@ mod test {
         permit.unlock(LockStatus::Done);
         handle.await.unwrap();
     }
+
+    #[tokio::test]
+    async fn test_lock_wait_timeout() {
+        let cache_lock =
+            CacheLock::new(Duration::from_secs(1000)).with_wait_timeout(Duration::from_millis(100));
+        let key1 = CacheKey::new("", "a", "1");
+        let permit = match cache_lock.lock(&key1) {
+            Locked::Write(w) => w,
+            _ => panic!(),
+        };
+        let lock = match cache_lock.lock(&key1) {
+            Locked::Read(r) => r,
+            _ => panic!(),
+        };
+
+        let start = Instant::now();
+        // the reader gives up while the writer is still within its lock timeout
+        assert_eq!(lock.wait().await, LockStatus::Timeout);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!lock.expired());
+        assert_eq!(lock.lock_status(), LockStatus::Waiting);
+
+        permit.unlock(LockStatus::Done);
+        assert_eq!(lock.wait().await, LockStatus::Done);
+    }
+
+    #[test]
+    fn test_lock_options() {
+        let cache_lock = CacheLock::new(Duration::from_secs(1));
+        assert_eq!(cache_lock.wait_timeout, Duration::from_secs(1));
+        assert!(!cache_lock.serve_stale());
+        // the wait timeout is capped by the lock timeout
+        let cache_lock = cache_lock
+            .with_wait_timeout(Duration::from_secs(10))
+            .with_serve_stale(true);
+        assert_eq!(cache_lock.wait_timeout, Duration::from_secs(1));
+        assert!(cache_lock.serve_stale());
+    }
 }
@@ -14,6 +14,7 @@
 
 //! Metadata for caching
 
+use http::header::{HeaderMap, HeaderName, HeaderValue};
 use http::Extensions;
 use pingora_error::{Error, ErrorType::*, OrErr, Result};
 use pingora_http::{HMap, ResponseHeader};
@@ -89,8 +90,11 @@ mod internal_meta {
         //    schema to decode it
         // After full releases, remove `skip_serializing_if` so that we can add the next extended field.
         #[serde(default)]
-        #[serde(skip_serializing_if = "Option::is_none")]
         pub(crate) variance: Option<HashBinary>,
+        // the response trailers as (name, value) pairs
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub(crate) trailers: Option<Vec<(String, Vec<u8>)>>,
     }
 
     impl Default for InternalMetaV2 {
@@ -104,6 +108,7 @@ mod internal_meta {
                 stale_while_revalidate_sec: 0,
                 stale_if_error_sec: 0,
                 variance: None,
+                trailers: None,
             }
         }
     }
@@ -257,7 +262,7 @@ mod internal_meta {
         #[test]
         fn test_internal_meta_serde_v2_extend_fields() {
             // make sure that v2 format is backward compatible
-            // this is the base version of v2 without any extended fields
+            // this is the base version of v2 with only the released extended fields
             #[derive(Deserialize, Serialize)]
             pub(crate) struct InternalMetaV2Base {
                 pub(crate) version: u8,
@@ -266,6 +271,7 @@ mod internal_meta {
                 pub(crate) updated: SystemTime,
                 pub(crate) stale_while_revalidate_sec: u32,
                 pub(crate) stale_if_error_sec: u32,
+                pub(crate) variance: Option<HashBinary>,
             }
 
             impl InternalMetaV2Base {
@@ -299,6 +305,7 @@ mod internal_meta {
                 updated: now,
                 stale_while_revalidate_sec: 0,
                 stale_if_error_sec: 0,
+                variance: None,
             };
             let binary = meta.serialize().unwrap();
             let meta2 = InternalMetaV2::deserialize(&binary).unwrap();
@@ -306,6 +313,16 @@ mod internal_meta {
             assert_eq!(meta.fresh_until, meta2.fresh_until);
             assert_eq!(meta.created, meta2.created);
             assert_eq!(meta.updated, meta2.updated);
+            assert!(meta2.trailers.is_none());
+
+            // the extended field round trips
+            let meta = InternalMetaV2 {
+                trailers: Some(vec![("grpc-status".to_string(), b"0".to_vec())]),
+                ..Default::default()
+            };
+            let binary = meta.serialize().unwrap();
+            let meta2 = InternalMetaV2::deserialize(&binary).unwrap();
+            assert_eq!(meta2.trailers, meta.trailers);
         }
     }
 }
@@ -444,6 +461,32 @@ impl CacheMeta {
         self.0.internal.variance = None
     }
 
+    /// Get the response trailers of this asset, if any
+    pub fn trailers(&self) -> Option<HeaderMap> {
+        let trailers = self.0.internal.trailers.as_ref()?;
+        let mut map = HeaderMap::with_capacity(trailers.len());
+        for (name, value) in trailers {
+            // they are valid when they are set
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_bytes(value),
+            ) {
+                map.append(name, value);
+            }
+        }
+        Some(map)
+    }
+
+    /// Set the response trailers of this asset
+    pub fn set_trailers(&mut self, trailers: &HeaderMap) {
+        self.0.internal.trailers = Some(
+            trailers
+                .iter()
+                .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+                .collect(),
+        );
+    }
+
     /// Get the response header in this asset
     pub fn response_header(&self) -> &ResponseHeader {
         &self.0.header
@@ -119,8 +119,9 @@ where
         match reason {
             // CacheLockGiveUp: the writer will set OriginNotCache (if applicable)
             // readers don't need to do it
+            // TooManyVariants: the other variants are still cacheable
             NeverEnabled | StorageError | InternalError | Deferred | CacheLockGiveUp
-            | CacheLockTimeout => {
+            | CacheLockTimeout | TooManyVariants => {
                 return None;
             }
             // Skip certain NoCacheReason::Custom according to user
@@ -0,0 +1,319 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cache purge by exact key and by tag
+
+use crate::eviction::EvictionManager;
+use crate::key::CompactCacheKey;
+use crate::storage::Storage;
+use crate::trace::Span;
+
+use parking_lot::RwLock;
+use pingora_error::Result;
+use pingora_http::ResponseHeader;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Default)]
+struct TagIndex {
+    tags: HashMap<String, HashSet<CompactCacheKey>>,
+    keys: HashMap<CompactCacheKey, Vec<String>>,
+}
+
+impl TagIndex {
+    fn remove(&mut self, key: &CompactCacheKey) {
+        let Some(tags) = self.keys.remove(key) else {
+            return;
+        };
+        for tag in tags {
+            if let Some(keys) = self.tags.get_mut(&tag) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.tags.remove(&tag);
+                }
+            }
+        }
+    }
+}
+
+/// The index of the cache tags of the cached assets
+///
+/// The tags of an asset are read from its response headers when it is admitted to the cache.
+/// By default, the `Surrogate-Key` and `Cache-Tag` headers are used, both whitespace and comma
+/// separated lists are accepted.
+///
+/// The index is in memory only. Assets that the storage removes on its own, without going
+/// through the [EvictionManager] or [Purger], stay in the index until they are purged.
+pub struct CacheTags {
+    headers: Vec<String>,
+    index: RwLock<TagIndex>,
+}
+
+impl Default for CacheTags {
+    fn default() -> Self {
+        Self::new(&["Surrogate-Key", "Cache-Tag"])
+    }
+}
+
+impl CacheTags {
+    /// Create a new [CacheTags] which reads the tags from the given response headers
+    pub fn new(headers: &[&str]) -> Self {
+        CacheTags {
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            index: RwLock::new(TagIndex::default()),
+        }
+    }
+
+    /// The tags of the given response
+    pub fn tags_of(&self, resp: &ResponseHeader) -> Vec<String> {
+        let mut tags = vec![];
+        for name in self.headers.iter() {
+            for value in resp.headers.get_all(name.as_str()) {
+                let Ok(value) = value.to_str() else {
+                    continue;
+                };
+                for tag in value.split(|c: char| c == ',' || c.is_ascii_whitespace()) {
+                    if !tag.is_empty() && !tags.iter().any(|t| t == tag) {
+                        tags.push(tag.to_string());
+                    }
+                }
+            }
+        }
+        tags
+    }
+
+    /// Tag the given asset, replacing its previous tags
+    pub fn insert(&self, key: CompactCacheKey, tags: Vec<String>) {
+        let mut index = self.index.write();
+        index.remove(&key);
+        if tags.is_empty() {
+            return;
+        }
+        for tag in tags.iter() {
+            index
+                .tags
+                .entry(tag.clone())
+                .or_default()
+                .insert(key.clone());
+        }
+        index.keys.insert(key, tags);
+    }
+
+    /// Remove the given asset from the index
+    pub fn remove(&self, key: &CompactCacheKey) {
+        self.index.write().remove(key)
+    }
+
+    /// The assets with the given tag
+    pub fn keys(&self, tag: &str) -> Vec<CompactCacheKey> {
+        self.index
+            .read()
+            .tags
+            .get(tag)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The number of tagged assets
+    pub fn len(&self) -> usize {
+        self.index.read().keys.len()
+    }
+
+    /// Whether no asset is tagged
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The handle to purge the assets of a cache, e.g., from an admin endpoint
+///
+/// Purged assets are removed from the storage, the eviction manager and the tag index.
+pub struct Purger {
+    storage: &'static (dyn Storage + Sync),
+    eviction: Option<&'static (dyn EvictionManager + Sync)>,
+    tags: Option<&'static CacheTags>,
+}
+
+impl Purger {
+    /// Create a new [Purger]
+    ///
+    /// The arguments should be the same as the ones given to [crate::HttpCache::enable()] and
+    /// [crate::HttpCache::set_cache_tags()].
+    pub fn new(
+        storage: &'static (dyn Storage + Sync),
+        eviction: Option<&'static (dyn EvictionManager + Sync)>,
+        tags: Option<&'static CacheTags>,
+    ) -> Self {
+        Purger {
+            storage,
+            eviction,
+            tags,
+        }
+    }
+
+    /// Purge the asset of the given key
+    ///
+    /// Return whether the asset was found in the storage.
+    pub async fn purge(&self, key: &CompactCacheKey) -> Result<bool> {
+        if let Some(eviction) = self.eviction {
+            eviction.remove(key);
+        }
+        if let Some(tags) = self.tags {
+            tags.remove(key);
+        }
+        let span = Span::inactive();
+        self.storage.purge(key, &span.handle()).await
+    }
+
+    /// Purge all the assets with the given tag
+    ///
+    /// Return the number of assets found in the storage. Nothing is purged if there is no
+    /// [CacheTags] index.
+    pub async fn purge_by_tag(&self, tag: &str) -> Result<usize> {
+        let Some(tags) = self.tags else {
+            return Ok(0);
+        };
+        let mut purged = 0;
+        for key in tags.keys(tag) {
+            if self.purge(&key).await? {
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::eviction::simple_lru::Manager;
+    use crate::{CacheKey, CacheMeta, MemCache};
+    use once_cell::sync::Lazy;
+    use std::time::SystemTime;
+
+    fn response(tags: &[(&str, &str)]) -> ResponseHeader {
+        let mut header = ResponseHeader::build(200, None).unwrap();
+        for (name, value) in tags {
+            header.append_header(name.to_string(), *value).unwrap();
+        }
+        header
+    }
+
+    #[test]
+    fn test_tags_of() {
+        let tags = CacheTags::default();
+        let resp = response(&[
+            ("Surrogate-Key", "a b  c"),
+            ("Cache-Tag", "c,d, e"),
+            ("X-Other", "f"),
+        ]);
+        assert_eq!(tags.tags_of(&resp), vec!["a", "b", "c", "d", "e"]);
+        assert!(tags.tags_of(&response(&[])).is_empty());
+
+        let tags = CacheTags::new(&["X-Other"]);
+        assert_eq!(tags.tags_of(&resp), vec!["f"]);
+    }
+
+    #[test]
+    fn test_tag_index() {
+        let tags = CacheTags::default();
+        let key1 = CacheKey::new("", "a", "1").to_compact();
+        let key2 = CacheKey::new("", "b", "1").to_compact();
+        tags.insert(key1.clone(), vec!["x".into(), "y".into()]);
+        tags.insert(key2.clone(), vec!["y".into()]);
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags.keys("x"), vec![key1.clone()]);
+        assert_eq!(tags.keys("y").len(), 2);
+
+        // re-tagging replaces the old tags
+        tags.insert(key1.clone(), vec!["z".into()]);
+        assert!(tags.keys("x").is_empty());
+        assert_eq!(tags.keys("y"), vec![key2.clone()]);
+        assert_eq!(tags.keys("z"), vec![key1.clone()]);
+
+        tags.remove(&key2);
+        assert!(tags.keys("y").is_empty());
+        tags.insert(key1, vec![]);
+        assert!(tags.is_empty());
+    }
+
+    async fn admit(
+        storage: &'static MemCache,
+        eviction: &'static Manager,
+        tags: &'static CacheTags,
+        key: &CacheKey,
+        resp: ResponseHeader,
+    ) {
+        let span = Span::inactive();
+        let now = SystemTime::now();
+        let meta = CacheMeta::new(now, now, 0, 0, resp);
+        let mut miss_handler = storage
+            .get_miss_handler(key, &meta, &span.handle())
+            .await
+            .unwrap();
+        miss_handler
+            .write_body(b"test"[..].into(), true)
+            .await
+            .unwrap();
+        let size = miss_handler.finish().await.unwrap();
+        eviction.admit(key.to_compact(), size, now);
+        tags.insert(key.to_compact(), tags.tags_of(&meta.0.header));
+    }
+
+    #[tokio::test]
+    async fn test_purge() {
+        static STORAGE: Lazy<MemCache> = Lazy::new(MemCache::new);
+        static EVICTION: Lazy<Manager> = Lazy::new(|| Manager::new(1000));
+        static TAGS: Lazy<CacheTags> = Lazy::new(CacheTags::default);
+        let purger = Purger::new(&*STORAGE, Some(&*EVICTION), Some(&*TAGS));
+
+        let key1 = CacheKey::new("", "a", "1");
+        let key2 = CacheKey::new("", "b", "1");
+        let key3 = CacheKey::new("", "c", "1");
+        admit(
+            &STORAGE,
+            &EVICTION,
+            &TAGS,
+            &key1,
+            response(&[("Cache-Tag", "x,y")]),
+        )
+        .await;
+        admit(
+            &STORAGE,
+            &EVICTION,
+            &TAGS,
+            &key2,
+            response(&[("Cache-Tag", "y")]),
+        )
+        .await;
+        admit(&STORAGE, &EVICTION, &TAGS, &key3, response(&[])).await;
+        assert_eq!(EVICTION.total_items(), 3);
+
+        // exact key
+        assert!(purger.purge(&key3.to_compact()).await.unwrap());
+        assert!(!purger.purge(&key3.to_compact()).await.unwrap());
+        assert_eq!(EVICTION.total_items(), 2);
+
+        // by tag
+        assert_eq!(purger.purge_by_tag("y").await.unwrap(), 2);
+        assert_eq!(purger.purge_by_tag("x").await.unwrap(), 0);
+        assert_eq!(EVICTION.total_items(), 0);
+        assert!(TAGS.is_empty());
+        let span = Span::inactive();
+        assert!(STORAGE
+            .lookup(&key1, &span.handle())
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,398 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cache statistics
+
+use crate::{CachePhase, HttpCache};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// The per request numbers which are not part of the cache phase
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct RequestStats {
+    pub cache_bytes: usize,
+    pub origin_bytes: usize,
+    pub lock_waits: usize,
+    pub lock_timeouts: usize,
+}
+
+/// A snapshot of [CacheCounters]
+///
+/// All the numbers are accumulated since the counters are created, which plays well with the
+/// Prometheus counter metric type.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Requests served from a fresh asset
+    pub hit: u64,
+    /// Requests with no asset found
+    pub miss: u64,
+    /// Requests served from a stale asset, e.g., stale-while-revalidate or stale-if-error
+    pub stale: u64,
+    /// Requests with a stale asset that was replaced by a fresh one from the origin
+    pub expired: u64,
+    /// Requests with a stale asset that was revalidated to be fresh
+    pub revalidated: u64,
+    /// Requests with a stale asset that was revalidated but deemed uncacheable
+    pub revalidated_no_cache: u64,
+    /// Requests that bypassed the cache
+    pub bypass: u64,
+    /// Requests that disabled the cache after it was enabled, e.g., uncacheable responses
+    pub disabled: u64,
+    /// Times requests waited behind a cache lock
+    pub lock_waits: u64,
+    /// Times requests gave up waiting behind a cache lock
+    pub lock_timeouts: u64,
+    /// Body bytes served from the cache
+    pub cache_bytes: u64,
+    /// Body bytes received from the origin to be admitted to the cache
+    pub origin_bytes: u64,
+}
+
+impl CacheStats {
+    /// The ratio of the requests served from the cache, fresh or stale, among the requests that
+    /// looked up the cache. Return 0 when no request looked up the cache.
+    pub fn hit_ratio(&self) -> f64 {
+        let served = self.hit + self.stale + self.revalidated + self.revalidated_no_cache;
+        let total = served + self.miss + self.expired;
+        if total == 0 {
+            0.0
+        } else {
+            served as f64 / total as f64
+        }
+    }
+
+    /// The names and values of the request counters by cache phase, e.g., for an exporter to
+    /// label them.
+    pub fn by_phase(&self) -> [(&'static str, u64); 8] {
+        [
+            (CachePhase::Hit.as_str(), self.hit),
+            (CachePhase::Miss.as_str(), self.miss),
+            (CachePhase::Stale.as_str(), self.stale),
+            (CachePhase::Expired.as_str(), self.expired),
+            (CachePhase::Revalidated.as_str(), self.revalidated),
+            ("revalidated-nocache", self.revalidated_no_cache),
+            (CachePhase::Bypass.as_str(), self.bypass),
+            ("disabled", self.disabled),
+        ]
+    }
+}
+
+/// Atomic counters of the cache effectiveness
+///
+/// Call [Self::record()] once per request when it is done, e.g., in the logging phase of the
+/// proxy, then read the numbers via [Self::snapshot()].
+#[derive(Debug, Default)]
+pub struct CacheCounters {
+    hit: AtomicU64,
+    miss: AtomicU64,
+    stale: AtomicU64,
+    expired: AtomicU64,
+    revalidated: AtomicU64,
+    revalidated_no_cache: AtomicU64,
+    bypass: AtomicU64,
+    disabled: AtomicU64,
+    lock_waits: AtomicU64,
+    lock_timeouts: AtomicU64,
+    cache_bytes: AtomicU64,
+    origin_bytes: AtomicU64,
+}
+
+fn add(counter: &AtomicU64, value: usize) {
+    if value > 0 {
+        counter.fetch_add(value as u64, Ordering::Relaxed);
+    }
+}
+
+impl CacheCounters {
+    /// Create a new [CacheCounters]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the outcome of the given request
+    pub fn record(&self, cache: &HttpCache) {
+        let phase = match cache.phase() {
+            CachePhase::Hit => Some(&self.hit),
+            CachePhase::Miss => Some(&self.miss),
+            CachePhase::Stale => Some(&self.stale),
+            CachePhase::Expired => Some(&self.expired),
+            CachePhase::Revalidated => Some(&self.revalidated),
+            CachePhase::RevalidatedNoCache(_) => Some(&self.revalidated_no_cache),
+            CachePhase::Bypass => Some(&self.bypass),
+            CachePhase::Disabled(reason) if reason != crate::NoCacheReason::NeverEnabled => {
+                Some(&self.disabled)
+            }
+            // the cache is never used by this request
+            CachePhase::Disabled(_) | CachePhase::Uninit | CachePhase::CacheKey => None,
+        };
+        if let Some(counter) = phase {
+            add(counter, 1);
+        }
+        let stats = cache.stats;
+        add(&self.lock_waits, stats.lock_waits);
+        add(&self.lock_timeouts, stats.lock_timeouts);
+        add(&self.cache_bytes, stats.cache_bytes);
+        add(&self.origin_bytes, stats.origin_bytes);
+    }
+
+    /// Read the current numbers
+    pub fn snapshot(&self) -> CacheStats {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        CacheStats {
+            hit: get(&self.hit),
+            miss: get(&self.miss),
+            stale: get(&self.stale),
+            expired: get(&self.expired),
+            revalidated: get(&self.revalidated),
+            revalidated_no_cache: get(&self.revalidated_no_cache),
+            bypass: get(&self.bypass),
+            disabled: get(&self.disabled),
+            lock_waits: get(&self.lock_waits),
+            lock_timeouts: get(&self.lock_timeouts),
+            cache_bytes: get(&self.cache_bytes),
+            origin_bytes: get(&self.origin_bytes),
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+mod metrics {
+    use super::{CacheCounters, CacheStats};
+    use pingora_error::{ErrorType, OrErr, Result};
+    use prometheus::core::{Collector, Desc};
+    use prometheus::proto::MetricFamily;
+    use prometheus::{IntCounter, IntCounterVec, Opts};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Weak};
+
+    const REQUESTS: (&str, &str) = (
+        "pingora_cache_requests_total",
+        "Number of the requests by cache phase",
+    );
+    const BODY_BYTES: (&str, &str) = (
+        "pingora_cache_body_bytes_total",
+        "Body bytes served from the cache or received from the origin to be admitted",
+    );
+    // the name, the help and the value of a metric
+    type Metric = (&'static str, &'static str, fn(&CacheStats) -> u64);
+
+    const LOCKS: [Metric; 2] = [
+        (
+            "pingora_cache_lock_waits_total",
+            "Number of the times requests waited behind a cache lock",
+            |s| s.lock_waits,
+        ),
+        (
+            "pingora_cache_lock_timeouts_total",
+            "Number of the times requests gave up waiting behind a cache lock",
+            |s| s.lock_timeouts,
+        ),
+    ];
+
+    struct CacheCollector {
+        counters: Weak<CacheCounters>,
+        const_labels: HashMap<String, String>,
+        descs: Vec<Desc>,
+    }
+
+    impl CacheCollector {
+        fn opts(&self, (name, help): (&str, &str)) -> Opts {
+            Opts::new(name, help).const_labels(self.const_labels.clone())
+        }
+    }
+
+    impl Collector for CacheCollector {
+        fn desc(&self) -> Vec<&Desc> {
+            self.descs.iter().collect()
+        }
+
+        fn collect(&self) -> Vec<MetricFamily> {
+            let Some(counters) = self.counters.upgrade() else {
+                return vec![];
+            };
+            let stats = counters.snapshot();
+
+            let mut families = vec![];
+            let requests = IntCounterVec::new(self.opts(REQUESTS), &["phase"]).unwrap();
+            for (phase, value) in stats.by_phase() {
+                requests.with_label_values(&[phase]).inc_by(value);
+            }
+            families.extend(requests.collect());
+            let body_bytes = IntCounterVec::new(self.opts(BODY_BYTES), &["source"]).unwrap();
+            body_bytes
+                .with_label_values(&["cache"])
+                .inc_by(stats.cache_bytes);
+            body_bytes
+                .with_label_values(&["origin"])
+                .inc_by(stats.origin_bytes);
+            families.extend(body_bytes.collect());
+            for (name, help, get) in LOCKS {
+                let counter = IntCounter::with_opts(self.opts((name, help))).unwrap();
+                counter.inc_by(get(&stats));
+                families.extend(counter.collect());
+            }
+            families
+        }
+    }
+
+    impl CacheCounters {
+        /// Report these counters as Prometheus metrics in the default registry for as long as
+        /// they are alive, with the `cache` label set to `name` to tell the caches apart.
+        ///
+        /// The metrics are `pingora_cache_requests_total` labeled by `phase`, see
+        /// [CacheStats::by_phase()], `pingora_cache_body_bytes_total` labeled by `source`,
+        /// `cache` or `origin`, `pingora_cache_lock_waits_total` and
+        /// `pingora_cache_lock_timeouts_total`.
+        ///
+        /// Registering counters with a `name` that is already registered fails, even after the
+        /// counters registered first are dropped.
+        pub fn register_metrics(self: &Arc<Self>, name: &str) -> Result<()> {
+            let const_labels = HashMap::from([("cache".to_string(), name.to_string())]);
+            let variable_labels = |labels: &[&str]| labels.iter().map(|l| l.to_string()).collect();
+            let mut descs = vec![
+                (REQUESTS, variable_labels(&["phase"])),
+                (BODY_BYTES, variable_labels(&["source"])),
+            ];
+            descs.extend(LOCKS.iter().map(|(name, help, _)| ((*name, *help), vec![])));
+            let descs = descs
+                .into_iter()
+                .map(|((name, help), labels)| {
+                    Desc::new(name.into(), help.into(), labels, const_labels.clone())
+                })
+                .collect::<prometheus::Result<_>>()
+                .or_err(ErrorType::InternalError, "invalid cache metrics")?;
+            let collector = CacheCollector {
+                counters: Arc::downgrade(self),
+                const_labels,
+                descs,
+            };
+            prometheus::register(Box::new(collector)).or_err(
+                ErrorType::InternalError,
+                "fail to register the cache metrics",
+            )
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::{CacheKey, HttpCache, MemCache};
+        use once_cell::sync::Lazy;
+
+        static STORAGE: Lazy<MemCache> = Lazy::new(MemCache::new);
+
+        #[test]
+        fn test_register_metrics() {
+            let counters = Arc::new(CacheCounters::new());
+            counters.register_metrics("test_register_metrics").unwrap();
+            assert!(counters.register_metrics("test_register_metrics").is_err());
+
+            let mut cache = HttpCache::new();
+            cache.enable(&*STORAGE, None, None, None);
+            cache.set_cache_key(CacheKey::new("", "a", "1"));
+            cache.cache_miss();
+            counters.record(&cache);
+
+            let requests = prometheus::gather()
+                .into_iter()
+                .find(|f| f.get_name() == "pingora_cache_requests_total")
+                .unwrap();
+            let miss = requests
+                .get_metric()
+                .iter()
+                .find(|m| {
+                    let labels = m.get_label();
+                    labels.iter().any(|l| {
+                        l.get_name() == "cache" && l.get_value() == "test_register_metrics"
+                    }) && labels
+                        .iter()
+                        .any(|l| l.get_name() == "phase" && l.get_value() == "miss")
+                })
+                .unwrap();
+            assert_eq!(miss.get_counter().get_value(), 1.0);
+
+            // no longer reported once the counters are gone
+            drop(counters);
+            let reported = prometheus::gather().into_iter().any(|f| {
+                f.get_metric().iter().any(|m| {
+                    m.get_label()
+                        .iter()
+                        .any(|l| l.get_value() == "test_register_metrics")
+                })
+            });
+            assert!(!reported);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CacheKey, MemCache, NoCacheReason};
+    use once_cell::sync::Lazy;
+
+    static STORAGE: Lazy<MemCache> = Lazy::new(MemCache::new);
+
+    fn enabled_cache() -> HttpCache {
+        let mut cache = HttpCache::new();
+        cache.enable(&*STORAGE, None, None, None);
+        cache.set_cache_key(CacheKey::new("", "a", "1"));
+        cache
+    }
+
+    #[test]
+    fn test_record() {
+        let counters = CacheCounters::new();
+
+        // never enabled: not counted
+        counters.record(&HttpCache::new());
+        assert_eq!(counters.snapshot(), CacheStats::default());
+
+        let mut cache = enabled_cache();
+        cache.cache_miss();
+        cache.record_origin_bytes(10);
+        counters.record(&cache);
+
+        let mut cache = enabled_cache();
+        cache.disable(NoCacheReason::OriginNotCache);
+        counters.record(&cache);
+
+        let mut cache = enabled_cache();
+        cache.bypass();
+        counters.record(&cache);
+
+        let stats = counters.snapshot();
+        assert_eq!(stats.miss, 1);
+        assert_eq!(stats.disabled, 1);
+        assert_eq!(stats.bypass, 1);
+        assert_eq!(stats.origin_bytes, 10);
+        assert_eq!(stats.cache_bytes, 0);
+        assert_eq!(stats.hit_ratio(), 0.0);
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = CacheStats {
+            hit: 6,
+            stale: 1,
+            miss: 2,
+            expired: 1,
+            ..Default::default()
+        };
+        assert_eq!(stats.hit_ratio(), 0.7);
+        let by_phase = stats.by_phase();
+        assert_eq!(by_phase[0], ("hit", 6));
+        assert_eq!(by_phase[1], ("miss", 2));
+        assert_eq!(CacheStats::default().hit_ratio(), 0.0);
+    }
+}
@@ -1,8 +1,12 @@
 use std::{borrow::Cow, collections::BTreeMap};
 
 use blake2::Digest;
+use http::header::VARY;
+use pingora_http::{RequestHeader, ResponseHeader};
 
-use crate::key::{Blake2b128, HashBinary};
+use crate::hashtable::ConcurrentLruCache;
+use crate::key::{Blake2b128, CacheHashKey, CacheKey, HashBinary};
+use crate::CacheMeta;
 
 /// A builder for variance keys, used for distinguishing multiple cached assets
 /// at the same URL. This is intended to be easily passed to helper functions,
@@ -58,6 +62,110 @@ impl<'a> VarianceBuilder<'a> {
     }
 }
 
+// the lowercase header names listed in the Vary headers of the response, in order
+fn vary_names(resp: &ResponseHeader) -> Vec<String> {
+    let mut names: Vec<String> = resp
+        .headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Whether the response has `Vary: *`, which makes it uncacheable because no request can be
+/// known to match it
+pub fn is_vary_wildcard(resp: &ResponseHeader) -> bool {
+    vary_names(resp).iter().any(|name| name == "*")
+}
+
+/// The variance of the request according to the `Vary` header of the cached response
+///
+/// The variance records the values of the request headers named in the `Vary` header, so that
+/// a cached variant only matches the requests with the same values. A header absent from the
+/// request is different from any value of it. Return `None` if the response has no `Vary`, or
+/// `Vary: *`, which is never cached.
+pub fn vary_variance(meta: &CacheMeta, req: &RequestHeader) -> Option<HashBinary> {
+    let names = vary_names(meta.response_header());
+    if names.is_empty() || names.iter().any(|name| name == "*") {
+        return None;
+    }
+    let mut variance = VarianceBuilder::new();
+    // the names themselves so that an absent header still varies
+    variance.add_owned_value("vary", names.join(",").into_bytes());
+    for name in names.iter() {
+        let mut values = req.headers.get_all(name.as_str()).iter();
+        if let Some(first) = values.next() {
+            let mut value = first.as_bytes().to_vec();
+            for v in values {
+                value.extend_from_slice(b", ");
+                value.extend_from_slice(v.as_bytes());
+            }
+            variance.add_owned_value(name, value);
+        }
+    }
+    variance.finalize()
+}
+
+const VARIANT_SHARDS: usize = 16;
+
+/// Caps the number of the variants cached under the same primary key
+///
+/// Set it via [HttpCache::set_variant_limit()](crate::HttpCache::set_variant_limit). The
+/// variants beyond the limit are not cached, so that a `Vary` on a header of many values doesn't
+/// flood the cache. The variants are remembered per primary key in an LRU so the limit is best
+/// effort: the variants of the keys pushed out of the LRU are forgotten.
+pub struct VariantLimit {
+    max_variants: usize,
+    variants: ConcurrentLruCache<Vec<HashBinary>, VARIANT_SHARDS>,
+}
+
+impl VariantLimit {
+    /// Create a new [VariantLimit] which allows up to `max_variants` variants per primary key and
+    /// remembers the variants of about `capacity` primary keys.
+    // usize::div_ceil() is newer than the MSRV
+    #[allow(clippy::manual_div_ceil)]
+    pub fn new(max_variants: usize, capacity: usize) -> Self {
+        VariantLimit {
+            max_variants,
+            variants: ConcurrentLruCache::new(
+                ((capacity + VARIANT_SHARDS - 1) / VARIANT_SHARDS).max(1),
+            ),
+        }
+    }
+
+    /// Whether the variant of the key can be cached, and if so remember it
+    ///
+    /// An asset admitted to the primary slot of the key replaces all the other variants of it.
+    /// `variance` is the variance of the asset, which is also in the key unless it's for the
+    /// primary slot.
+    pub fn admit(&self, key: &CacheKey, variance: HashBinary) -> bool {
+        let primary = u128::from_be_bytes(key.primary_bin());
+        let mut variants = self.variants.write(primary);
+        if key.variance_bin().is_none() {
+            variants.put(primary, vec![variance]);
+            return true;
+        }
+        match variants.get_mut(&primary) {
+            Some(known) if known.contains(&variance) => true,
+            Some(known) if known.len() >= self.max_variants => false,
+            Some(known) => {
+                known.push(variance);
+                true
+            }
+            None => {
+                variants.put(primary, vec![variance]);
+                true
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -117,4 +225,83 @@ mod test {
 
         assert_eq!(key_a, key_b);
     }
+
+    fn meta_with_vary(vary: &[&str]) -> CacheMeta {
+        use std::time::SystemTime;
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        for v in vary {
+            resp.append_header("Vary", *v).unwrap();
+        }
+        CacheMeta::new(SystemTime::now(), SystemTime::now(), 0, 0, resp)
+    }
+
+    fn req_with(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            req.append_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_vary_variance() {
+        let meta = meta_with_vary(&[]);
+        assert_eq!(vary_variance(&meta, &req_with(&[])), None);
+        assert_eq!(vary_variance(&meta_with_vary(&["*"]), &req_with(&[])), None);
+        assert!(is_vary_wildcard(
+            meta_with_vary(&["Origin, *"]).response_header()
+        ));
+        assert!(!is_vary_wildcard(meta.response_header()));
+
+        let meta = meta_with_vary(&["Accept-Encoding"]);
+        let gzip = vary_variance(&meta, &req_with(&[("Accept-Encoding", "gzip")]));
+        let br = vary_variance(&meta, &req_with(&[("accept-encoding", "br")]));
+        let none = vary_variance(&meta, &req_with(&[]));
+        let empty = vary_variance(&meta, &req_with(&[("Accept-Encoding", "")]));
+        // a request without the header still varies
+        assert!(gzip.is_some() && none.is_some());
+        assert_ne!(gzip, br);
+        assert_ne!(gzip, none);
+        assert_ne!(none, empty);
+        // headers not in Vary don't matter
+        assert_eq!(
+            gzip,
+            vary_variance(
+                &meta,
+                &req_with(&[("Accept-Encoding", "gzip"), ("User-Agent", "curl")])
+            )
+        );
+
+        // names are case insensitive, in any order and across multiple headers
+        let meta_a = meta_with_vary(&["accept-encoding, Origin"]);
+        let meta_b = meta_with_vary(&["Origin", "Accept-Encoding"]);
+        let req = req_with(&[("Accept-Encoding", "gzip"), ("Origin", "a.com")]);
+        assert_eq!(vary_variance(&meta_a, &req), vary_variance(&meta_b, &req));
+        assert_ne!(vary_variance(&meta_a, &req), gzip);
+    }
+
+    #[test]
+    fn test_variant_limit() {
+        let limit = VariantLimit::new(2, 100);
+        let primary = CacheKey::new("", "a", "1");
+        let variant = |v: u8| {
+            let mut key = primary.clone();
+            key.set_variance_key([v; 16]);
+            key
+        };
+        assert!(limit.admit(&primary, [0; 16]));
+        assert!(limit.admit(&variant(1), [1; 16]));
+        // the primary one counts too
+        assert!(!limit.admit(&variant(2), [2; 16]));
+        // the known ones are fine
+        assert!(limit.admit(&variant(1), [1; 16]));
+        // the primary slot replaces all
+        assert!(limit.admit(&primary, [3; 16]));
+        assert!(limit.admit(&variant(2), [2; 16]));
+        assert!(!limit.admit(&variant(4), [4; 16]));
+        // other keys are not affected
+        let mut other = CacheKey::new("", "b", "1");
+        other.set_variance_key([4; 16]);
+        assert!(limit.admit(&other, [4; 16]));
+    }
 }
@@ -15,6 +15,7 @@
 //! The abstraction and implementation interface for service application logic
 
 pub mod http_app;
+#[cfg(feature = "prometheus")]
 pub mod prometheus_http_app;
 
 use crate::server::ShutdownWatch;
@@ -77,6 +78,18 @@ pub trait HttpServerApp {
         None
     }
 
+    /// The maximum number of requests to serve on each downstream connection.
+    ///
+    /// Once an HTTP/2 connection has accepted this many streams, a GOAWAY is sent so that the
+    /// client opens a new connection for its further requests. HTTP/1.x applications should stop
+    /// keeping the connection alive after this many requests themselves, see
+    /// [`crate::protocols::SocketDigest::requests()`].
+    ///
+    /// `None` (default) means no limit.
+    fn max_requests_per_connection(&self) -> Option<usize> {
+        None
+    }
+
     fn http_cleanup(&self) {}
 }
 
@@ -111,6 +124,12 @@ where
                     Ok(c) => c,
                 };
 
+                let max_requests = self.max_requests_per_connection();
+                let listener_stats = digest
+                    .socket_digest
+                    .as_ref()
+                    .and_then(|d| d.listener_stats().cloned());
+                let mut requests = 0;
                 loop {
                     // this loop ends when the client decides to close the h2 conn
                     // TODO: add a timeout?
@@ -125,6 +144,15 @@ where
                         }
                         Ok(s) => s?, // None means the connection is ready to be closed
                     };
+                    requests += 1;
+                    if let Some(stats) = listener_stats.as_ref() {
+                        stats.record_h2_request();
+                    }
+                    if max_requests.is_some_and(|max| requests == max) {
+                        debug!("H2 connection served {requests} requests, sending GOAWAY");
+                        // the ongoing streams continue, the loop ends once they finish
+                        h2_conn.graceful_shutdown();
+                    }
                     let app = self.clone();
                     let shutdown = shutdown.clone();
                     pingora_runtime::current_handle().spawn(async move {
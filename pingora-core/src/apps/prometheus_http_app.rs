@@ -26,12 +26,40 @@ use crate::protocols::http::ServerSession;
 /// An HTTP application that reports Prometheus metrics.
 ///
 /// This application will report all the [static metrics](https://docs.rs/prometheus/latest/prometheus/index.html#static-metrics)
-/// collected via the [Prometheus](https://docs.rs/prometheus/) crate;
-pub struct PrometheusHttpApp;
+/// collected via the [Prometheus](https://docs.rs/prometheus/) crate, including the process
+/// metrics on Linux.
+#[derive(Default)]
+pub struct PrometheusHttpApp {
+    path: Option<String>,
+}
+
+impl PrometheusHttpApp {
+    /// Create a new [PrometheusHttpApp] that reports the metrics on any request path
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new [PrometheusHttpApp] that only reports the metrics on the given request path,
+    /// e.g., `/metrics`. Other paths are answered with 404.
+    pub fn with_path(path: &str) -> Self {
+        PrometheusHttpApp {
+            path: Some(path.to_string()),
+        }
+    }
+}
 
 #[cfg_attr(not(doc_async_trait), async_trait)]
 impl ServeHttp for PrometheusHttpApp {
-    async fn response(&self, _http_session: &mut ServerSession) -> Response<Vec<u8>> {
+    async fn response(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        if let Some(path) = self.path.as_ref() {
+            if http_session.req_header().uri.path() != path {
+                return Response::builder()
+                    .status(404)
+                    .header(http::header::CONTENT_LENGTH, 0)
+                    .body(vec![])
+                    .unwrap();
+            }
+        }
         let encoder = TextEncoder::new();
         let metric_families = prometheus::gather();
         let mut buffer = vec![];
@@ -52,7 +80,16 @@ pub type PrometheusServer = HttpServer<PrometheusHttpApp>;
 
 impl PrometheusServer {
     pub fn new() -> Self {
-        let mut server = Self::new_app(PrometheusHttpApp);
+        Self::with_app(PrometheusHttpApp::new())
+    }
+
+    /// Create a new [PrometheusServer] that only reports the metrics on the given request path
+    pub fn with_path(path: &str) -> Self {
+        Self::with_app(PrometheusHttpApp::with_path(path))
+    }
+
+    fn with_app(app: PrometheusHttpApp) -> Self {
+        let mut server = Self::new_app(app);
         // enable gzip level 7 compression
         server.add_module(ResponseCompressionBuilder::enable(7));
         server
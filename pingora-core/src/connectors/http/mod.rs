@@ -26,22 +26,45 @@ pub mod v2;
 pub struct Connector {
     h1: v1::Connector,
     h2: v2::Connector,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
 }
 
 impl Connector {
     pub fn new(options: Option<ConnectorOptions>) -> Self {
+        let read_timeout = options.as_ref().and_then(|o| o.read_timeout);
+        let write_timeout = options.as_ref().and_then(|o| o.write_timeout);
         Connector {
             h1: v1::Connector::new(options.clone()),
             h2: v2::Connector::new(options),
+            read_timeout,
+            write_timeout,
         }
     }
 
     /// Get an [HttpSession] to the given server.
     ///
     /// The second return value indicates whether the session is connected via a reused stream.
+    ///
+    /// The read and write timeouts of the session are set to the ones of the peer, or the
+    /// defaults of the [ConnectorOptions] if the peer doesn't set them.
     pub async fn get_http_session<P: Peer + Send + Sync + 'static>(
         &self,
         peer: &P,
+    ) -> Result<(HttpSession, bool)> {
+        let (mut session, reused) = self.do_get_http_session(peer).await?;
+        if let Some(timeout) = peer.read_timeout().or(self.read_timeout) {
+            session.set_read_timeout(timeout);
+        }
+        if let Some(timeout) = peer.write_timeout().or(self.write_timeout) {
+            session.set_write_timeout(timeout);
+        }
+        Ok((session, reused))
+    }
+
+    async fn do_get_http_session<P: Peer + Send + Sync + 'static>(
+        &self,
+        peer: &P,
     ) -> Result<(HttpSession, bool)> {
         // NOTE: maybe TODO: we do not yet enforce that only TLS traffic can use h2, which is the
         // de facto requirement for h2, because non TLS traffic lack the negotiation mechanism.
@@ -88,6 +111,38 @@ impl Connector {
         }
     }
 
+    /// Establish connections to the given server ahead of time and pool them, so that the first
+    /// requests to it don't pay for the connection setup.
+    ///
+    /// The connections are made the same way [Self::get_http_session()] does. If the server
+    /// doesn't speak h2, h1 connections are pooled instead. See [TransportConnector::warm_up()]
+    /// for the limits and the error handling.
+    ///
+    /// Return the number of the new connections pooled.
+    ///
+    /// [TransportConnector::warm_up()]: crate::connectors::TransportConnector::warm_up
+    pub async fn warm_up<P: Peer + Send + Sync + 'static>(
+        &self,
+        peer: &P,
+        count: usize,
+    ) -> Result<usize> {
+        let h1_only = peer
+            .get_peer_options()
+            .map_or(true, |o| o.alpn.get_max_http_version() == 1);
+        if h1_only {
+            return self.h1.warm_up(peer, count).await;
+        }
+        let count = self.h2.warm_up_count(peer, count);
+        for n in 0..count {
+            match self.h2.new_http_session(peer).await? {
+                HttpSession::H2(h2) => self.h2.release_http_session(h2, peer, peer.idle_timeout()),
+                // the server doesn't speak h2
+                HttpSession::H1(_) => return Ok(n + self.h1.warm_up(peer, count - n).await?),
+            }
+        }
+        Ok(count)
+    }
+
     /// Tell the connector to always send h1 for ALPN for the given peer in the future.
     pub fn prefer_h1(&self, peer: &impl Peer) {
         self.h2.prefer_h1(peer);
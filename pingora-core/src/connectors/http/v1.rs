@@ -14,6 +14,7 @@
 
 use crate::connectors::{ConnectorOptions, TransportConnector};
 use crate::protocols::http::v1::client::HttpSession;
+use crate::protocols::Stream;
 use crate::upstreams::peer::Peer;
 
 use pingora_error::Result;
@@ -21,22 +22,30 @@ use std::time::Duration;
 
 pub struct Connector {
     transport: TransportConnector,
+    max_requests: Option<usize>,
 }
 
 impl Connector {
     pub fn new(options: Option<ConnectorOptions>) -> Self {
+        let max_requests = options.as_ref().and_then(|o| o.max_requests_per_connection);
         Connector {
             transport: TransportConnector::new(options),
+            max_requests,
         }
     }
 
+    fn new_session(&self, stream: Stream) -> HttpSession {
+        let mut http = HttpSession::new(stream);
+        http.set_max_requests(self.max_requests);
+        http
+    }
+
     pub async fn get_http_session<P: Peer + Send + Sync + 'static>(
         &self,
         peer: &P,
     ) -> Result<(HttpSession, bool)> {
         let (stream, reused) = self.transport.get_stream(peer).await?;
-        let http = HttpSession::new(stream);
-        Ok((http, reused))
+        Ok((self.new_session(stream), reused))
     }
 
     pub async fn reused_http_session<P: Peer + Send + Sync + 'static>(
@@ -46,7 +55,16 @@ impl Connector {
         self.transport
             .reused_stream(peer)
             .await
-            .map(HttpSession::new)
+            .map(|stream| self.new_session(stream))
+    }
+
+    /// Pool connections to the given peer ahead of time, see [TransportConnector::warm_up()]
+    pub async fn warm_up<P: Peer + Send + Sync + 'static>(
+        &self,
+        peer: &P,
+        count: usize,
+    ) -> Result<usize> {
+        self.transport.warm_up(peer, count).await
     }
 
     pub async fn release_http_session<P: Peer + Send + Sync + 'static>(
@@ -16,6 +16,7 @@ use super::HttpSession;
 use crate::connectors::{ConnectorOptions, TransportConnector};
 use crate::protocols::http::v1::client::HttpSession as Http1Session;
 use crate::protocols::http::v2::client::{drive_connection, Http2Session};
+use crate::protocols::http::v2::settings::H2Settings;
 use crate::protocols::{Digest, Stream};
 use crate::upstreams::peer::{Peer, ALPN};
 
@@ -52,6 +53,10 @@ pub(crate) struct ConnectionRefInner {
     max_streams: usize,
     // how many concurrent streams already active
     current_streams: AtomicUsize,
+    // max total streams this connection is allowed to create over its lifetime
+    max_requests: Option<usize>,
+    // how many streams were created in total
+    requests: AtomicUsize,
     // because `SendRequest` doesn't actually have access to the underlying Stream,
     // we log info about timing and tcp info here.
     pub(crate) digest: Digest,
@@ -67,6 +72,7 @@ impl ConnectionRef {
         ping_timeout_occurred: Arc<AtomicBool>,
         id: i32,
         max_streams: usize,
+        max_requests: Option<usize>,
         digest: Digest,
     ) -> Self {
         ConnectionRef(Arc::new(ConnectionRefInner {
@@ -76,11 +82,21 @@ impl ConnectionRef {
             id,
             max_streams,
             current_streams: AtomicUsize::new(0),
+            max_requests,
+            requests: AtomicUsize::new(0),
             digest,
         }))
     }
     pub fn more_streams_allowed(&self) -> bool {
-        self.0.max_streams > self.0.current_streams.load(Ordering::Relaxed)
+        self.more_requests_allowed()
+            && self.0.max_streams > self.0.current_streams.load(Ordering::Relaxed)
+    }
+
+    // whether the connection has not carried its max number of streams yet
+    fn more_requests_allowed(&self) -> bool {
+        self.0
+            .max_requests
+            .is_none_or(|max| max > self.0.requests.load(Ordering::Relaxed))
     }
 
     pub fn is_idle(&self) -> bool {
@@ -117,6 +133,13 @@ impl ConnectionRef {
             self.0.current_streams.fetch_sub(1, Ordering::SeqCst);
             return Ok(None);
         }
+        let requests = self.0.requests.fetch_add(1, Ordering::SeqCst);
+        if self.0.max_requests.is_some_and(|max| requests >= max) {
+            // the connection has carried enough streams
+            self.0.requests.fetch_sub(1, Ordering::SeqCst);
+            self.0.current_streams.fetch_sub(1, Ordering::SeqCst);
+            return Ok(None);
+        }
         let send_req = self.0.connection_stub.new_stream().await.map_err(|e| {
             // fail to create the stream, reset the counter
             self.0.current_streams.fetch_sub(1, Ordering::SeqCst);
@@ -174,8 +197,6 @@ impl InUsePool {
     }
 }
 
-const DEFAULT_POOL_SIZE: usize = 128;
-
 /// Http2 connector
 pub struct Connector {
     // just for creating connections, the Stream of h2 should be reused
@@ -184,19 +205,27 @@ pub struct Connector {
     idle_pool: Arc<ConnectionPool<ConnectionRef>>,
     // the pool of h2 connections that have ongoing streams
     in_use_pool: InUsePool,
+    // the max number of streams of each connection
+    max_requests: Option<usize>,
+    h2_settings: H2Settings,
 }
 
 impl Connector {
     /// Create a new [Connector] from the given [ConnectorOptions]
     pub fn new(options: Option<ConnectorOptions>) -> Self {
-        let pool_size = options
+        let idle_pool = ConnectorOptions::new_pool(options.as_ref());
+        let max_requests = options.as_ref().and_then(|o| o.max_requests_per_connection);
+        let h2_settings = options
             .as_ref()
-            .map_or(DEFAULT_POOL_SIZE, |o| o.keepalive_pool_size);
+            .and_then(|o| o.h2_settings.clone())
+            .unwrap_or_default();
         // connection offload is handled by the [TransportConnector]
         Connector {
             transport: TransportConnector::new(options),
-            idle_pool: Arc::new(ConnectionPool::new(pool_size)),
+            idle_pool: Arc::new(idle_pool),
             in_use_pool: InUsePool::new(),
+            max_requests,
+            h2_settings,
         }
     }
 
@@ -209,29 +238,20 @@ impl Connector {
     ) -> Result<HttpSession> {
         let stream = self.transport.new_stream(peer).await?;
 
-        // check alpn
-        match stream.selected_alpn_proto() {
-            Some(ALPN::H2) => { /* continue */ }
-            Some(_) => {
-                // H2 not supported
-                return Ok(HttpSession::H1(Http1Session::new(stream)));
-            }
-            None => {
-                // if tls but no ALPN, default to h1
-                // else if plaintext and min http version is 1, this is most likely h1
-                if peer.tls()
-                    || peer
-                        .get_peer_options()
-                        .map_or(true, |o| o.alpn.get_min_http_version() == 1)
-                {
-                    return Ok(HttpSession::H1(Http1Session::new(stream)));
-                }
-                // else: min http version=H2 over plaintext, there is no ALPN anyways, we trust
-                // the caller that the server speaks h2c
-            }
+        if !speak_h2(peer, stream.selected_alpn_proto())? {
+            let mut h1 = Http1Session::new(stream);
+            h1.set_max_requests(self.max_requests);
+            return Ok(HttpSession::H1(h1));
         }
         let max_h2_stream = peer.get_peer_options().map_or(1, |o| o.max_h2_streams);
-        let conn = handshake(stream, max_h2_stream, peer.h2_ping_interval()).await?;
+        let conn = handshake(
+            stream,
+            self.h2_settings.max_client_streams(max_h2_stream),
+            self.max_requests,
+            &self.h2_settings,
+            peer.h2_ping_interval(),
+        )
+        .await?;
         let h2_stream = conn
             .spawn_stream()
             .await?
@@ -307,6 +327,10 @@ impl Connector {
             // Already dead h2 connection
             return;
         }
+        if !conn.more_requests_allowed() {
+            // no more streams for this connection, it closes once the other streams finish
+            return;
+        }
         if conn.is_idle() {
             let meta = ConnectionMeta {
                 key: reuse_hash,
@@ -327,6 +351,13 @@ impl Connector {
         }
     }
 
+    // how many new connections to make to have `count` idle h2 connections to the peer
+    pub(crate) fn warm_up_count(&self, peer: &impl Peer, count: usize) -> usize {
+        let idle = self.idle_pool.idle_count(&peer.reuse_hash());
+        self.transport
+            .warm_up_count(peer, &self.idle_pool, idle, count)
+    }
+
     /// Tell the connector to always send h1 for ALPN for the given peer in the future.
     pub fn prefer_h1(&self, peer: &impl Peer) {
         self.transport.prefer_h1(peer);
@@ -340,17 +371,38 @@ impl Connector {
     }
 }
 
-// The h2 library we use has unbounded internal buffering, which will cause excessive memory
-// consumption when the downstream is slower than upstream. This window size caps the buffering by
-// limiting how much data can be inflight. However, setting this value will also cap the max
-// download speed by limiting the bandwidth-delay product of a link.
-// Long term, we should advertising large window but shrink it when a small buffer is full.
-// 8 Mbytes = 80 Mbytes X 100ms, which should be enough for most links.
-const H2_WINDOW_SIZE: u32 = 1 << 23;
+// Decide whether to speak h2 on a new connection according to the negotiated ALPN. Error if the
+// peer requires h2 but the server doesn't select it.
+fn speak_h2<P: Peer>(peer: &P, selected: Option<ALPN>) -> Result<bool> {
+    let h2_only = peer
+        .get_peer_options()
+        .is_some_and(|o| o.alpn.get_min_http_version() == 2);
+    match selected {
+        Some(ALPN::H2) => Ok(true),
+        // H2 not supported
+        Some(other) if h2_only => Error::e_explain(
+            H2Downgrade,
+            format!("h2 required but {other} is selected via ALPN by {peer}"),
+        ),
+        Some(_) => Ok(false),
+        // if tls but no ALPN, default to h1
+        // else if plaintext and min http version is 1, this is most likely h1
+        None if !h2_only => Ok(false),
+        None if peer.tls() => Error::e_explain(
+            H2Downgrade,
+            format!("h2 required but no ALPN is selected by {peer}"),
+        ),
+        // min http version=H2 over plaintext, there is no ALPN anyways, we trust the caller that
+        // the server speaks h2c
+        None => Ok(true),
+    }
+}
 
 async fn handshake(
     stream: Stream,
     max_streams: usize,
+    max_requests: Option<usize>,
+    h2_settings: &H2Settings,
     h2_ping_interval: Option<Duration>,
 ) -> Result<ConnectionRef> {
     use h2::client::Builder;
@@ -371,16 +423,15 @@ async fn handshake(
         proxy_digest: stream.get_proxy_digest(),
         socket_digest: stream.get_socket_digest(),
     };
-    // TODO: make these configurable
-    let (send_req, connection) = Builder::new()
+    let mut builder = Builder::new();
+    builder
         .enable_push(false)
         .initial_max_send_streams(max_streams)
         // The limit for the server. Server push is not allowed, so this value doesn't matter
-        .max_concurrent_streams(1)
-        .max_frame_size(64 * 1024) // advise server to send larger frames
-        .initial_window_size(H2_WINDOW_SIZE)
-        // should this be max_streams * H2_WINDOW_SIZE?
-        .initial_connection_window_size(H2_WINDOW_SIZE)
+        .max_concurrent_streams(1);
+    // the window sizes and the frame sizes, see H2Settings for the defaults
+    h2_settings.apply_client(&mut builder);
+    let (send_req, connection) = builder
         .handshake(stream)
         .await
         .or_err(HandshakeError, "during H2 handshake")?;
@@ -413,6 +464,7 @@ async fn handshake(
         ping_timeout_occurred,
         id,
         max_allowed_streams,
+        max_requests,
         digest,
     ))
 }
@@ -459,6 +511,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_speak_h2() {
+        let mut tls = HttpPeer::new(("1.1.1.1", 443), true, "one.one.one.one".into());
+        let mut plaintext = HttpPeer::new(("1.1.1.1", 80), false, "".into());
+        for peer in [&mut tls, &mut plaintext] {
+            peer.set_http_protocol(ALPN::H2H1, 10);
+        }
+        assert!(speak_h2(&tls, Some(ALPN::H2)).unwrap());
+        assert!(!speak_h2(&tls, Some(ALPN::H1)).unwrap());
+        assert!(!speak_h2(&tls, None).unwrap());
+        assert!(!speak_h2(&plaintext, None).unwrap());
+
+        for peer in [&mut tls, &mut plaintext] {
+            peer.set_http_protocol(ALPN::H2, 10);
+        }
+        assert!(speak_h2(&tls, Some(ALPN::H2)).unwrap());
+        let e = speak_h2(&tls, Some(ALPN::H1)).unwrap_err();
+        assert_eq!(e.etype(), &H2Downgrade);
+        let e = speak_h2(&tls, None).unwrap_err();
+        assert_eq!(e.etype(), &H2Downgrade);
+        // h2c
+        assert!(speak_h2(&plaintext, None).unwrap());
+    }
+
     #[tokio::test]
     async fn test_h2_single_stream() {
         let connector = Connector::new(None);
@@ -12,20 +12,80 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use futures::stream::{FuturesUnordered, StreamExt};
 use log::debug;
 use pingora_error::{Context, Error, ErrorType::*, OrErr, Result};
 use rand::seq::SliceRandom;
 use std::net::SocketAddr as InetSocketAddr;
 use std::os::unix::io::AsRawFd;
+use tokio::net::TcpStream;
 
-use crate::protocols::l4::ext::{connect as tcp_connect, connect_uds, set_tcp_keepalive};
+use crate::protocols::l4::ext::{
+    connect_transparent, connect_uds, connect_with_device, set_tcp_keepalive,
+};
 use crate::protocols::l4::socket::SocketAddr;
 use crate::protocols::l4::stream::Stream;
 use crate::protocols::{GetSocketDigest, SocketDigest};
-use crate::upstreams::peer::Peer;
+use crate::upstreams::peer::{HappyEyeballs, Peer};
 
-/// Establish a connection (l4) to the given peer using its settings and an optional bind address.
-pub async fn connect<P>(peer: &P, bind_to: Option<InetSocketAddr>) -> Result<Stream>
+/// The source addresses and network interface to bind upstream connections to
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BindTo {
+    pub v4: Vec<InetSocketAddr>,
+    pub v6: Vec<InetSocketAddr>,
+    pub device: Option<String>,
+}
+
+impl BindTo {
+    // Choose the source address to connect to `addr` of the given peer.
+    // The bind address of the peer itself takes precedence over the lists.
+    fn select<P: Peer>(&self, peer: &P, addr: &InetSocketAddr) -> Result<Option<InetSocketAddr>> {
+        if let Some(bind_to) = peer.get_peer_options().and_then(|o| o.bind_to) {
+            if bind_to.is_ipv4() != addr.is_ipv4() {
+                return Error::e_explain(
+                    BindError,
+                    format!("bind address {bind_to} and peer {addr} are of different IP families"),
+                );
+            }
+            return Ok(Some(bind_to));
+        }
+
+        let ips = if addr.is_ipv4() { &self.v4 } else { &self.v6 };
+        Ok(match ips.len() {
+            0 => None,
+            1 => Some(ips[0]),
+            _ => {
+                // pick a random bind ip
+                ips.choose(&mut rand::thread_rng()).copied()
+            }
+        })
+    }
+
+    fn device<'a, P: Peer>(&'a self, peer: &'a P) -> Option<&'a str> {
+        peer.bind_to_device().or(self.device.as_deref())
+    }
+}
+
+// connect() to one address of the peer with the source address and device to bind to
+async fn tcp_connect<P: Peer>(peer: &P, addr: &InetSocketAddr, bind: &BindTo) -> Result<TcpStream> {
+    let bind_to = bind.select(peer, addr)?;
+    match bind_to {
+        Some(bind_to) if peer.transparent() => {
+            connect_transparent(addr, &bind_to, bind.device(peer)).await
+        }
+        _ => connect_with_device(addr, bind_to.as_ref(), bind.device(peer)).await,
+    }
+}
+
+/// Establish a connection (l4) to the given peer using its settings and the given bind settings.
+///
+/// `resolved` are the addresses of the peer's hostname if it has one, which take precedence over
+/// the addresses of the peer itself.
+pub(crate) async fn connect<P>(
+    peer: &P,
+    bind: &BindTo,
+    resolved: Option<&[InetSocketAddr]>,
+) -> Result<Stream>
 where
     P: Peer + Send + Sync,
 {
@@ -37,9 +97,28 @@ where
     let peer_addr = peer.address();
     let mut stream: Stream = match peer_addr {
         SocketAddr::Inet(addr) => {
-            let connect_future = tcp_connect(addr, bind_to.as_ref());
+            let connect_future = async {
+                let addrs = match resolved {
+                    Some(addrs) => addrs.to_vec(),
+                    None => {
+                        let mut addrs = vec![*addr];
+                        addrs.extend_from_slice(peer.alternative_addresses());
+                        addrs
+                    }
+                };
+                match peer.happy_eyeballs() {
+                    Some(he) if addrs.len() > 1 => {
+                        happy_eyeballs_connect(peer, &he.order_addresses(&addrs), bind, he).await
+                    }
+                    _ => match addrs.first() {
+                        Some(addr) => tcp_connect(peer, addr, bind).await,
+                        None => Error::e_explain(ConnectError, "no address to connect to"),
+                    },
+                }
+            };
             let conn_res = match peer.connection_timeout() {
                 Some(t) => pingora_timeout::timeout(t, connect_future)
+                    .label("connect")
                     .await
                     .explain_err(ConnectTimedout, |_| {
                         format!("timeout {t:?} connecting to server {peer}")
@@ -71,6 +150,7 @@ where
             );
             let conn_res = match peer.connection_timeout() {
                 Some(t) => pingora_timeout::timeout(t, connect_future)
+                    .label("connect")
                     .await
                     .explain_err(ConnectTimedout, |_| {
                         format!("timeout {t:?} connecting to server {peer}")
@@ -102,42 +182,60 @@ where
     stream.set_nodelay()?;
 
     let digest = SocketDigest::from_raw_fd(stream.as_raw_fd());
-    digest
-        .peer_addr
-        .set(Some(peer_addr.clone()))
-        .expect("newly created OnceCell must be empty");
+    // with multiple addresses, leave the peer address to be looked up from the fd
+    if resolved.is_none() && peer.alternative_addresses().is_empty() {
+        digest
+            .peer_addr
+            .set(Some(peer_addr.clone()))
+            .expect("newly created OnceCell must be empty");
+    }
     stream.set_socket_digest(digest);
 
     Ok(stream)
 }
 
-pub(crate) fn bind_to_random<P: Peer>(
+// Race the connection attempts to the given addresses, see [HappyEyeballs].
+async fn happy_eyeballs_connect<P: Peer>(
     peer: &P,
-    v4_list: &[InetSocketAddr],
-    v6_list: &[InetSocketAddr],
-) -> Option<InetSocketAddr> {
-    let selected = peer.get_peer_options().and_then(|o| o.bind_to);
-    if selected.is_some() {
-        return selected;
-    }
+    addrs: &[InetSocketAddr],
+    bind: &BindTo,
+    settings: &HappyEyeballs,
+) -> Result<TcpStream> {
+    let attempt = |addr: InetSocketAddr| async move { tcp_connect(peer, &addr, bind).await };
 
-    fn bind_to_ips(ips: &[InetSocketAddr]) -> Option<InetSocketAddr> {
-        match ips.len() {
-            0 => None,
-            1 => Some(ips[0]),
-            _ => {
-                // pick a random bind ip
-                ips.choose(&mut rand::thread_rng()).copied()
+    let mut pending = addrs.iter().copied();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(attempt(addr)),
+                None => {
+                    return Err(last_error.unwrap_or_else(|| {
+                        Error::explain(ConnectError, "no address to connect to")
+                    }))
+                }
+            }
+        }
+        tokio::select! {
+            Some(res) = attempts.next() => match res {
+                // returning drops the other attempts in flight
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("happy eyeballs attempt failed: {e}");
+                    last_error = Some(e);
+                    // start the next attempt right away, if any left
+                    if let Some(addr) = pending.next() {
+                        attempts.push(attempt(addr));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(settings.attempt_delay), if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(attempt(addr));
+                }
             }
         }
-    }
-
-    match peer.address() {
-        SocketAddr::Inet(sockaddr) => match sockaddr {
-            InetSocketAddr::V4(_) => bind_to_ips(v4_list),
-            InetSocketAddr::V6(_) => bind_to_ips(v6_list),
-        },
-        SocketAddr::Unix(_) => None,
     }
 }
 
@@ -195,7 +293,7 @@ mod tests {
     #[tokio::test]
     async fn test_conn_error_refused() {
         let peer = BasicPeer::new("127.0.0.1:79"); // hopefully port 79 is not used
-        let new_session = connect(&peer, None).await;
+        let new_session = connect(&peer, &BindTo::default(), None).await;
         assert_eq!(new_session.unwrap_err().etype(), &ConnectRefused)
     }
 
@@ -204,14 +302,21 @@ mod tests {
     #[tokio::test]
     async fn test_conn_error_no_route() {
         let peer = BasicPeer::new("[::3]:79"); // no route
-        let new_session = connect(&peer, None).await;
+        let new_session = connect(&peer, &BindTo::default(), None).await;
         assert_eq!(new_session.unwrap_err().etype(), &ConnectNoRoute)
     }
 
+    fn bind_v4(addr: &str) -> BindTo {
+        BindTo {
+            v4: vec![addr.parse().unwrap()],
+            ..Default::default()
+        }
+    }
+
     #[tokio::test]
     async fn test_conn_error_addr_not_avail() {
         let peer = HttpPeer::new("127.0.0.1:121".to_string(), false, "".to_string());
-        let new_session = connect(&peer, Some("192.0.2.2:0".parse().unwrap())).await;
+        let new_session = connect(&peer, &bind_v4("192.0.2.2:0"), None).await;
         assert_eq!(new_session.unwrap_err().etype(), &ConnectRefused)
     }
 
@@ -220,21 +325,110 @@ mod tests {
         let peer = HttpPeer::new("240.0.0.1:80".to_string(), false, "".to_string()); // non localhost
 
         // create an error: cannot send from src addr: localhost to dst addr: a public IP
-        let new_session = connect(&peer, Some("127.0.0.1:0".parse().unwrap())).await;
+        let new_session = connect(&peer, &bind_v4("127.0.0.1:0"), None).await;
         let error = new_session.unwrap_err();
         // XXX: some system will allow the socket to bind and connect without error, only to timeout
         assert!(error.etype() == &ConnectError || error.etype() == &ConnectTimedout)
     }
 
+    #[tokio::test]
+    async fn test_conn_bind_family_mismatch() {
+        let mut peer = BasicPeer::new("127.0.0.1:79");
+        peer.options.bind_to = Some("[::1]:0".parse().unwrap());
+        let new_session = connect(&peer, &BindTo::default(), None).await;
+        let error = new_session.unwrap_err();
+        assert_eq!(error.etype(), &InternalError);
+        assert!(error.to_string().contains("different IP families"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_conn_bind_to_device() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _ = listener.accept().await;
+        });
+        let peer = BasicPeer::new(&addr.to_string());
+        let bind = BindTo {
+            device: Some("lo".to_string()),
+            ..Default::default()
+        };
+        // binding to a device may require CAP_NET_RAW on older kernels
+        match connect(&peer, &bind, None).await {
+            Ok(_) => {}
+            Err(e) => assert_eq!(e.etype(), &InternalError),
+        }
+    }
+
     #[tokio::test]
     async fn test_conn_timeout() {
         // 192.0.2.1 is effectively a blackhole
         let mut peer = BasicPeer::new("192.0.2.1:79");
         peer.options.connection_timeout = Some(std::time::Duration::from_millis(1)); //1ms
-        let new_session = connect(&peer, None).await;
+        let new_session = connect(&peer, &BindTo::default(), None).await;
         assert_eq!(new_session.unwrap_err().etype(), &ConnectTimedout)
     }
 
+    #[test]
+    fn test_happy_eyeballs_order() {
+        let addrs: Vec<InetSocketAddr> = ["1.0.0.1:80", "1.0.0.2:80", "[::1]:80", "1.0.0.3:80"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let mut he = HappyEyeballs::default();
+        let ordered: Vec<String> = he
+            .order_addresses(&addrs)
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            ordered,
+            ["[::1]:80", "1.0.0.1:80", "1.0.0.2:80", "1.0.0.3:80"]
+        );
+        he.prefer_ipv6 = false;
+        let ordered: Vec<String> = he
+            .order_addresses(&addrs)
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            ordered,
+            ["1.0.0.1:80", "[::1]:80", "1.0.0.2:80", "1.0.0.3:80"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_fallback() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let good_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _ = listener.accept().await;
+        });
+
+        // 192.0.2.1 is effectively a blackhole
+        let mut peer = BasicPeer::new("192.0.2.1:79");
+        peer.options.connection_timeout = Some(std::time::Duration::from_secs(1));
+        peer.options.alternative_addresses = vec![good_addr];
+        peer.options.happy_eyeballs = Some(HappyEyeballs {
+            attempt_delay: std::time::Duration::from_millis(10),
+            prefer_ipv6: true,
+        });
+        let stream = connect(&peer, &BindTo::default(), None).await.unwrap();
+        let digest = stream.get_socket_digest().unwrap();
+        assert_eq!(digest.peer_addr(), Some(&SocketAddr::Inet(good_addr)));
+        assert!(peer.matches_fd(stream.as_raw_fd()));
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_all_fail() {
+        let mut peer = BasicPeer::new("127.0.0.1:79"); // hopefully port 79 is not used
+        peer.options.alternative_addresses = vec!["127.0.0.1:78".parse().unwrap()];
+        peer.options.happy_eyeballs = Some(HappyEyeballs::default());
+        let new_session = connect(&peer, &BindTo::default(), None).await;
+        assert_eq!(new_session.unwrap_err().etype(), &ConnectRefused)
+    }
+
     #[tokio::test]
     async fn test_connect_proxy_fail() {
         let mut peer = HttpPeer::new("1.1.1.1:80".to_string(), false, "".to_string());
@@ -246,7 +440,7 @@ mod tests {
             port: 80,
             headers: BTreeMap::new(),
         });
-        let new_session = connect(&peer, None).await;
+        let new_session = connect(&peer, &BindTo::default(), None).await;
         let e = new_session.unwrap_err();
         assert_eq!(e.etype(), &ConnectError);
         assert!(!e.retry());
@@ -282,7 +476,7 @@ mod tests {
             port: 80,
             headers: BTreeMap::new(),
         });
-        let new_session = connect(&peer, None).await;
+        let new_session = connect(&peer, &BindTo::default(), None).await;
         assert!(new_session.is_ok());
     }
 
@@ -316,7 +510,7 @@ mod tests {
             port: 80,
             headers: BTreeMap::new(),
         });
-        let new_session = connect(&peer, None).await;
+        let new_session = connect(&peer, &BindTo::default(), None).await;
         let err = new_session.unwrap_err();
         assert_eq!(err.etype(), &ConnectionClosed);
         assert!(!err.retry());
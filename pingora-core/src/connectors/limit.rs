@@ -0,0 +1,281 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limit and keep track of the connections to the same peer
+
+use crate::protocols::raw_connect::ProxyDigest;
+use crate::protocols::ssl::SslDigest;
+use crate::protocols::{
+    GetProxyDigest, GetSocketDigest, GetTimingDigest, Shutdown, SocketDigest, Ssl, Stream,
+    TimingDigest, UniqueID, ALPN,
+};
+use crate::tls::ssl::SslRef;
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use pingora_error::{Error, ErrorType::ConnectLimited, Result};
+use pingora_pool::OpenConnection;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// The per peer semaphores that cap the connections, idle or in use, to each peer
+pub(crate) struct ConnectionLimit {
+    max: usize,
+    wait: Option<Duration>,
+    peers: Mutex<HashMap<u64, Arc<Semaphore>>>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize, wait: Option<Duration>) -> Self {
+        ConnectionLimit {
+            max,
+            wait,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn semaphore(&self, key: u64) -> Arc<Semaphore> {
+        let mut peers = self.peers.lock();
+        if let Some(s) = peers.get(&key) {
+            return s.clone();
+        }
+        // forget the peers without any connection, only when a new peer shows up
+        // (each connection holds a reference of its semaphore)
+        peers.retain(|_, s| Arc::strong_count(s) > 1);
+        let s = Arc::new(Semaphore::new(self.max));
+        peers.insert(key, s.clone());
+        s
+    }
+
+    /// Take a connection slot of the peer of the given reuse hash.
+    ///
+    /// Wait for at most the configured duration if all the slots are taken, or fail right away
+    /// if no wait is configured.
+    pub async fn acquire(
+        &self,
+        key: u64,
+        peer: &(impl Display + ?Sized),
+    ) -> Result<OwnedSemaphorePermit> {
+        let semaphore = self.semaphore(key);
+        let permit = match self.wait {
+            Some(wait) => pingora_timeout::timeout(wait, semaphore.acquire_owned())
+                .await
+                .ok()
+                .and_then(|p| p.ok()),
+            None => semaphore.try_acquire_owned().ok(),
+        };
+        permit.map_or_else(
+            || {
+                Error::e_explain(
+                    ConnectLimited,
+                    format!("{} connections to {peer} already", self.max),
+                )
+            },
+            Ok,
+        )
+    }
+
+    /// The number of connections, idle or in use, to the peer of the given reuse hash
+    pub fn connections(&self, key: u64) -> usize {
+        self.peers
+            .lock()
+            .get(&key)
+            .map_or(0, |s| self.max.saturating_sub(s.available_permits()))
+    }
+
+    /// The number of connections that can still be made to the peer of the given reuse hash
+    pub fn available(&self, key: u64) -> usize {
+        self.max.saturating_sub(self.connections(key))
+    }
+}
+
+// A connection that stays counted as open, and holds its slot if limited, until it is closed
+#[derive(Debug)]
+pub(crate) struct TrackedStream {
+    stream: Stream,
+    _permit: Option<OwnedSemaphorePermit>,
+    _open: OpenConnection,
+}
+
+impl TrackedStream {
+    pub fn new(stream: Stream, permit: Option<OwnedSemaphorePermit>, open: OpenConnection) -> Self {
+        TrackedStream {
+            stream,
+            _permit: permit,
+            _open: open,
+        }
+    }
+}
+
+impl AsyncRead for TrackedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TrackedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
+
+#[async_trait]
+impl Shutdown for TrackedStream {
+    async fn shutdown(&mut self) {
+        self.stream.shutdown().await
+    }
+}
+
+impl UniqueID for TrackedStream {
+    fn id(&self) -> i32 {
+        self.stream.id()
+    }
+}
+
+impl Ssl for TrackedStream {
+    fn get_ssl(&self) -> Option<&SslRef> {
+        self.stream.get_ssl()
+    }
+
+    fn get_ssl_digest(&self) -> Option<Arc<SslDigest>> {
+        self.stream.get_ssl_digest()
+    }
+
+    fn selected_alpn_proto(&self) -> Option<ALPN> {
+        self.stream.selected_alpn_proto()
+    }
+
+    fn negotiated_alpn(&self) -> Option<&[u8]> {
+        self.stream.negotiated_alpn()
+    }
+}
+
+impl GetTimingDigest for TrackedStream {
+    fn get_timing_digest(&self) -> Vec<Option<TimingDigest>> {
+        self.stream.get_timing_digest()
+    }
+}
+
+impl GetProxyDigest for TrackedStream {
+    fn get_proxy_digest(&self) -> Option<Arc<ProxyDigest>> {
+        self.stream.get_proxy_digest()
+    }
+
+    fn set_proxy_digest(&mut self, digest: ProxyDigest) {
+        self.stream.set_proxy_digest(digest)
+    }
+}
+
+impl GetSocketDigest for TrackedStream {
+    fn get_socket_digest(&self) -> Option<Arc<SocketDigest>> {
+        self.stream.get_socket_digest()
+    }
+
+    fn set_socket_digest(&mut self, socket_digest: SocketDigest) {
+        self.stream.set_socket_digest(socket_digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_pool::ConnectionPool;
+    use tokio_test::io::Builder;
+
+    #[tokio::test]
+    async fn test_fail_fast() {
+        let limit = ConnectionLimit::new(2, None);
+        let p1 = limit.acquire(1, "peer1").await.unwrap();
+        let _p2 = limit.acquire(1, "peer1").await.unwrap();
+        assert_eq!(limit.connections(1), 2);
+        let e = limit.acquire(1, "peer1").await.unwrap_err();
+        assert_eq!(e.etype(), &ConnectLimited);
+        // other peers are not affected
+        let _p3 = limit.acquire(2, "peer2").await.unwrap();
+
+        drop(p1);
+        assert_eq!(limit.connections(1), 1);
+        let _p1 = limit.acquire(1, "peer1").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bounded_wait() {
+        let limit = Arc::new(ConnectionLimit::new(1, Some(Duration::from_millis(100))));
+        let p1 = limit.acquire(1, "peer1").await.unwrap();
+        let e = limit.acquire(1, "peer1").await.unwrap_err();
+        assert_eq!(e.etype(), &ConnectLimited);
+
+        let limit2 = limit.clone();
+        let waiter = tokio::spawn(async move { limit2.acquire(1, "peer1").await.is_ok() });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(p1);
+        assert!(waiter.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_stream_releases_slot() {
+        let limit = ConnectionLimit::new(1, None);
+        let pool: ConnectionPool<()> = ConnectionPool::new(1);
+        let permit = limit.acquire(1, "peer1").await.unwrap();
+        let stream = TrackedStream::new(
+            Box::new(Builder::new().build()),
+            Some(permit),
+            pool.connection_created(1),
+        );
+        assert_eq!(limit.connections(1), 1);
+        assert_eq!(pool.stats().active, 1);
+        drop(stream);
+        assert_eq!(limit.connections(1), 0);
+        assert_eq!(pool.stats().active, 0);
+
+        // idle peers are forgotten when a new one shows up
+        let _permit = limit.acquire(2, "peer2").await.unwrap();
+        assert_eq!(limit.peers.lock().len(), 1);
+    }
+}
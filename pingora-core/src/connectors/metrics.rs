@@ -0,0 +1,153 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The Prometheus metrics of the connection pools of the [super::TransportConnector]s
+//!
+//! The numbers of all the live connectors are summed up when the metrics are scraped.
+
+use once_cell::sync::Lazy;
+use pingora_pool::{ConnectionPool, PoolStats};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntCounter, IntGauge};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+type Pool = ConnectionPool<Arc<tokio::sync::Mutex<crate::protocols::Stream>>>;
+
+const COUNTERS: [(&str, &str, fn(&PoolStats) -> u64); 4] = [
+    (
+        "pingora_upstream_connections_created_total",
+        "Number of the upstream connections created",
+        |s| s.created,
+    ),
+    (
+        "pingora_upstream_connections_reused_total",
+        "Number of the upstream connections reused from the keepalive pool",
+        |s| s.reused,
+    ),
+    (
+        "pingora_upstream_connections_evicted_total",
+        "Number of the idle upstream connections closed for staying in the pool too long",
+        |s| s.evicted,
+    ),
+    (
+        "pingora_upstream_connections_closed_full_total",
+        "Number of the upstream connections closed because the keepalive pool was full",
+        |s| s.closed_full,
+    ),
+];
+
+const GAUGES: [(&str, &str, fn(&PoolStats) -> usize); 2] = [
+    (
+        "pingora_upstream_connections_idle",
+        "Number of the idle upstream connections in the keepalive pool",
+        |s| s.idle,
+    ),
+    (
+        "pingora_upstream_connections_active",
+        "Number of the open upstream connections in use",
+        |s| s.active,
+    ),
+];
+
+static POOLS: Lazy<Mutex<Vec<Weak<Pool>>>> = Lazy::new(|| {
+    if let Err(e) = prometheus::register(Box::new(PoolCollector::new())) {
+        log::warn!("fail to register the connection pool metrics, {e}");
+    }
+    Mutex::new(vec![])
+});
+
+// Report the stats of the given pool until it is dropped
+pub(super) fn register(pool: &Arc<Pool>) {
+    let mut pools = POOLS.lock().unwrap();
+    pools.retain(|p| p.strong_count() > 0);
+    pools.push(Arc::downgrade(pool));
+}
+
+struct PoolCollector {
+    descs: Vec<Desc>,
+}
+
+impl PoolCollector {
+    fn new() -> Self {
+        let names = COUNTERS
+            .iter()
+            .map(|(name, help, _)| (name, help))
+            .chain(GAUGES.iter().map(|(name, help, _)| (name, help)));
+        let descs = names
+            .map(|(name, help)| {
+                Desc::new(name.to_string(), help.to_string(), vec![], HashMap::new()).unwrap()
+            })
+            .collect();
+        PoolCollector { descs }
+    }
+}
+
+impl Collector for PoolCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let pools: Vec<_> = POOLS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let stats: Vec<_> = pools.iter().map(|p| p.stats()).collect();
+
+        let mut families = Vec::with_capacity(self.descs.len());
+        for (name, help, get) in COUNTERS {
+            let counter = IntCounter::new(name, help).unwrap();
+            counter.inc_by(stats.iter().map(get).sum());
+            families.extend(counter.collect());
+        }
+        for (name, help, get) in GAUGES {
+            let gauge = IntGauge::new(name, help).unwrap();
+            gauge.set(stats.iter().map(get).sum::<usize>() as i64);
+            families.extend(gauge.collect());
+        }
+        families
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connectors::TransportConnector;
+
+    fn gathered(name: &str) -> Option<f64> {
+        let family = prometheus::gather()
+            .into_iter()
+            .find(|f| f.get_name() == name)?;
+        let metric = &family.get_metric()[0];
+        if metric.has_counter() {
+            Some(metric.get_counter().get_value())
+        } else {
+            Some(metric.get_gauge().get_value())
+        }
+    }
+
+    #[test]
+    fn test_pool_metrics() {
+        let connector = TransportConnector::new(None);
+        let pool = &connector.connection_pool;
+        let open = pool.connection_created(1);
+        assert!(gathered("pingora_upstream_connections_created_total").unwrap() >= 1.0);
+        assert!(gathered("pingora_upstream_connections_active").unwrap() >= 1.0);
+        assert!(gathered("pingora_upstream_connections_idle").is_some());
+        drop(open);
+    }
+}
@@ -16,23 +16,33 @@
 
 pub mod http;
 mod l4;
+mod limit;
+#[cfg(feature = "prometheus")]
+mod metrics;
 mod offload;
+pub mod resolver;
 mod tls;
 
+pub use pingora_pool::CheckoutOrder;
+
+use crate::protocols::http::v2::settings::H2Settings;
 use crate::protocols::Stream;
 use crate::server::configuration::ServerConf;
 use crate::tls::ssl::SslConnector;
 use crate::upstreams::peer::{Peer, ALPN};
 
-use l4::connect as l4_connect;
+use l4::{connect as l4_connect, BindTo};
+use limit::{ConnectionLimit, TrackedStream};
 use log::{debug, error, warn};
 use offload::OffloadRuntime;
 use parking_lot::RwLock;
-use pingora_error::{Error, ErrorType::*, OrErr, Result};
-use pingora_pool::{ConnectionMeta, ConnectionPool};
+use pingora_error::{Context, Error, ErrorType::*, OrErr, Result};
+use pingora_pool::{ConnectionMeta, ConnectionPool, PoolStats};
+use resolver::{CachingResolver, Resolver, SystemResolver};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
 /// The options to configure a [TransportConnector]
@@ -49,6 +59,54 @@ pub struct ConnectorOptions {
     pub cert_key_file: Option<(String, String)>,
     /// How many connections to keepalive
     pub keepalive_pool_size: usize,
+    /// The maximum number of idle connections to keepalive to the same peer
+    ///
+    /// Connections released over this limit are closed instead of pooled. `None` means no limit
+    /// other than `keepalive_pool_size`.
+    pub max_idle_per_host: Option<usize>,
+    /// The maximum number of idle connections to keepalive in total
+    ///
+    /// Unlike `keepalive_pool_size`, which evicts the least recently used connections, connections
+    /// released over this limit are closed instead of pooled.
+    pub max_idle: Option<usize>,
+    /// Close the connections that stay idle in the keepalive pool for longer than this duration
+    ///
+    /// Peers can set a shorter [Peer::idle_timeout()] of their own. This protects against reusing
+    /// connections that the servers or middleboxes may have silently closed.
+    pub idle_timeout: Option<Duration>,
+    /// The order in which the idle connections to the same peer are reused
+    ///
+    /// [CheckoutOrder::Lifo] together with `idle_timeout` lets the pool shrink back after a spike
+    /// of traffic, see [CheckoutOrder].
+    pub checkout_order: CheckoutOrder,
+    /// The maximum number of connections, idle or in use, to the same peer
+    ///
+    /// When the limit is reached, new connections to the peer wait for a slot for up to
+    /// `connection_limit_wait`, then fail with the [ConnectLimited] error.
+    pub max_connections_per_host: Option<usize>,
+    /// How long to wait for a slot when `max_connections_per_host` is reached
+    ///
+    /// `None` means to fail right away.
+    pub connection_limit_wait: Option<Duration>,
+    /// The maximum number of requests to send on each connection
+    ///
+    /// An HTTP/1.x connection sends its last request with `Connection: close` and is not reused
+    /// after it. An HTTP/2 connection takes no more streams and is closed once its streams finish.
+    /// `None` means no limit.
+    pub max_requests_per_connection: Option<usize>,
+    /// The HTTP/2 settings of the connections, see [H2Settings] for the defaults
+    pub h2_settings: Option<H2Settings>,
+    /// The connect timeout of the peers that don't set their own [Peer::connection_timeout()]
+    pub connection_timeout: Option<Duration>,
+    /// The total connection establishment timeout of the peers that don't set their own
+    /// [Peer::total_connection_timeout()]
+    pub total_connection_timeout: Option<Duration>,
+    /// The read timeout of the HTTP sessions to the peers that don't set their own
+    /// [Peer::read_timeout()]
+    pub read_timeout: Option<Duration>,
+    /// The write timeout of the HTTP sessions to the peers that don't set their own
+    /// [Peer::write_timeout()]
+    pub write_timeout: Option<Duration>,
     /// Optionally offload the connection establishment to dedicated thread pools
     ///
     /// TCP and TLS connection establishment can be CPU intensive. Sometimes such tasks can slow
@@ -62,6 +120,14 @@ pub struct ConnectorOptions {
     pub bind_to_v4: Vec<SocketAddr>,
     /// Bind to any of the given source IPv4 addresses
     pub bind_to_v6: Vec<SocketAddr>,
+    /// Bind to the given network interface (`SO_BINDTODEVICE`), Linux only
+    ///
+    /// Each individual peer can use their own interface to override this.
+    pub bind_to_device: Option<String>,
+    /// The resolver for the peers that have a hostname
+    ///
+    /// If `None`, a [CachingResolver] on top of the system resolver will be used.
+    pub resolver: Option<Arc<dyn Resolver>>,
 }
 
 impl ConnectorOptions {
@@ -96,9 +162,23 @@ impl ConnectorOptions {
             ca_file: server_conf.ca_file.clone(),
             cert_key_file: None, // TODO: use it
             keepalive_pool_size: server_conf.upstream_keepalive_pool_size,
+            max_idle_per_host: None,
+            max_idle: None,
+            idle_timeout: None,
+            checkout_order: CheckoutOrder::Fifo,
+            max_connections_per_host: None,
+            connection_limit_wait: None,
+            max_requests_per_connection: server_conf.upstream_max_requests_per_connection,
+            h2_settings: server_conf.upstream_h2_settings.clone(),
+            connection_timeout: server_conf.upstream_connect_timeout,
+            total_connection_timeout: server_conf.upstream_total_connect_timeout,
+            read_timeout: server_conf.upstream_read_timeout,
+            write_timeout: server_conf.upstream_write_timeout,
             offload_threadpool,
             bind_to_v4,
             bind_to_v6,
+            bind_to_device: None,
+            resolver: None,
         }
     }
 
@@ -108,10 +188,42 @@ impl ConnectorOptions {
             ca_file: None,
             cert_key_file: None,
             keepalive_pool_size,
+            max_idle_per_host: None,
+            max_idle: None,
+            idle_timeout: None,
+            checkout_order: CheckoutOrder::Fifo,
+            max_connections_per_host: None,
+            connection_limit_wait: None,
+            max_requests_per_connection: None,
+            h2_settings: None,
+            connection_timeout: None,
+            total_connection_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
             offload_threadpool: None,
             bind_to_v4: vec![],
             bind_to_v6: vec![],
+            bind_to_device: None,
+            resolver: None,
+        }
+    }
+
+    // create the keepalive pool of the connections according to these options
+    pub(crate) fn new_pool<S>(options: Option<&Self>) -> ConnectionPool<S> {
+        let Some(options) = options else {
+            return ConnectionPool::new(DEFAULT_POOL_SIZE);
+        };
+        let mut pool = ConnectionPool::new(options.keepalive_pool_size);
+        if let Some(max) = options.max_idle_per_host {
+            pool = pool.with_max_idle_per_host(max);
+        }
+        if let Some(max) = options.max_idle {
+            pool = pool.with_max_idle(max);
         }
+        if let Some(timeout) = options.idle_timeout {
+            pool = pool.with_idle_timeout(timeout);
+        }
+        pool.with_checkout_order(options.checkout_order)
     }
 }
 
@@ -120,9 +232,11 @@ pub struct TransportConnector {
     tls_ctx: tls::Connector,
     connection_pool: Arc<ConnectionPool<Arc<Mutex<Stream>>>>,
     offload: Option<OffloadRuntime>,
-    bind_to_v4: Vec<SocketAddr>,
-    bind_to_v6: Vec<SocketAddr>,
+    bind_to: Arc<BindTo>,
     preferred_http_version: PreferredHttpVersion,
+    resolver: Arc<dyn Resolver>,
+    limit: Option<ConnectionLimit>,
+    timeouts: ConnectTimeouts,
 }
 
 const DEFAULT_POOL_SIZE: usize = 128;
@@ -130,49 +244,111 @@ const DEFAULT_POOL_SIZE: usize = 128;
 impl TransportConnector {
     /// Create a new [TransportConnector] with the given [ConnectorOptions]
     pub fn new(mut options: Option<ConnectorOptions>) -> Self {
-        let pool_size = options
-            .as_ref()
-            .map_or(DEFAULT_POOL_SIZE, |c| c.keepalive_pool_size);
+        let pool = ConnectorOptions::new_pool(options.as_ref());
         // Take the offloading setting there because this layer has implement offloading,
         // so no need for stacks at lower layer to offload again.
         let offload = options.as_mut().and_then(|o| o.offload_threadpool.take());
-        let bind_to_v4 = options
-            .as_ref()
-            .map_or_else(Vec::new, |o| o.bind_to_v4.clone());
-        let bind_to_v6 = options
+        let bind_to = options.as_ref().map_or_else(BindTo::default, |o| BindTo {
+            v4: o.bind_to_v4.clone(),
+            v6: o.bind_to_v6.clone(),
+            device: o.bind_to_device.clone(),
+        });
+        let limit = options.as_ref().and_then(|o| {
+            o.max_connections_per_host
+                .map(|max| ConnectionLimit::new(max, o.connection_limit_wait))
+        });
+        let resolver = options
             .as_ref()
-            .map_or_else(Vec::new, |o| o.bind_to_v6.clone());
+            .and_then(|o| o.resolver.clone())
+            .unwrap_or_else(|| Arc::new(CachingResolver::new(SystemResolver)));
+        let timeouts = ConnectTimeouts::new(options.as_ref());
+        let connection_pool = Arc::new(pool);
+        #[cfg(feature = "prometheus")]
+        metrics::register(&connection_pool);
         TransportConnector {
             tls_ctx: tls::Connector::new(options),
-            connection_pool: Arc::new(ConnectionPool::new(pool_size)),
+            connection_pool,
             offload: offload.map(|v| OffloadRuntime::new(v.0, v.1)),
-            bind_to_v4,
-            bind_to_v6,
+            bind_to: Arc::new(bind_to),
             preferred_http_version: PreferredHttpVersion::new(),
+            resolver,
+            limit,
+            timeouts,
         }
     }
 
+    /// The number of idle connections in the keepalive pool of each peer, keyed by the
+    /// [Peer::reuse_hash()] of the peer
+    pub fn idle_connections(&self) -> Vec<(u64, usize)> {
+        self.connection_pool.idle_counts()
+    }
+
+    /// The number of connections, idle or in use, to the given [Peer]
+    ///
+    /// Connections are only counted when [ConnectorOptions::max_connections_per_host] is set.
+    pub fn connections(&self, peer: &impl Peer) -> usize {
+        self.limit
+            .as_ref()
+            .map_or(0, |l| l.connections(peer.reuse_hash()))
+    }
+
+    /// The statistics of the connections made by this connector and its keepalive pool
+    ///
+    /// With the `prometheus` feature, the sums of these numbers over all the connectors are also
+    /// reported as the `pingora_upstream_connections_*` metrics.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.connection_pool.stats()
+    }
+
     /// Connect to the given server [Peer]
     ///
     /// No connection is reused.
+    ///
+    /// If [ConnectorOptions::max_connections_per_host] is reached, this function waits for a
+    /// connection to the peer to close, then fails with the [ConnectLimited] error.
     pub async fn new_stream<P: Peer + Send + Sync + 'static>(&self, peer: &P) -> Result<Stream> {
+        let permit = match self.limit.as_ref() {
+            Some(limit) => Some(limit.acquire(peer.reuse_hash(), peer).await?),
+            None => None,
+        };
         let rt = self
             .offload
             .as_ref()
             .map(|o| o.get_runtime(peer.reuse_hash()));
-        let bind_to = l4::bind_to_random(peer, &self.bind_to_v4, &self.bind_to_v6);
         let alpn_override = self.preferred_http_version.get(peer);
         let stream = if let Some(rt) = rt {
             let peer = peer.clone();
             let tls_ctx = self.tls_ctx.clone();
-            rt.spawn(async move { do_connect(&peer, bind_to, alpn_override, &tls_ctx.ctx).await })
+            let bind_to = self.bind_to.clone();
+            let resolver = self.resolver.clone();
+            let timeouts = self.timeouts;
+            rt.spawn(async move {
+                do_connect(
+                    &peer,
+                    &bind_to,
+                    alpn_override,
+                    &tls_ctx.ctx,
+                    &*resolver,
+                    timeouts,
+                )
                 .await
-                .or_err(InternalError, "offload runtime failure")??
+            })
+            .await
+            .or_err(InternalError, "offload runtime failure")??
         } else {
-            do_connect(peer, bind_to, alpn_override, &self.tls_ctx.ctx).await?
+            do_connect(
+                peer,
+                &self.bind_to,
+                alpn_override,
+                &self.tls_ctx.ctx,
+                &*self.resolver,
+                self.timeouts,
+            )
+            .await?
         };
 
-        Ok(stream)
+        let open = self.connection_pool.connection_created(peer.reuse_hash());
+        Ok(Box::new(TrackedStream::new(stream, permit, open)))
     }
 
     /// Try to find a reusable connection to the given server [Peer]
@@ -260,22 +436,125 @@ impl TransportConnector {
     pub fn prefer_h1(&self, peer: &impl Peer) {
         self.preferred_http_version.add(peer, 1);
     }
+
+    // how many new connections to make to have `count` idle ones to the peer, within the limits
+    pub(crate) fn warm_up_count<S>(
+        &self,
+        peer: &impl Peer,
+        pool: &ConnectionPool<S>,
+        idle: usize,
+        count: usize,
+    ) -> usize {
+        let count = pool.max_idle_per_host().map_or(count, |max| count.min(max));
+        let new = count.saturating_sub(idle);
+        self.limit
+            .as_ref()
+            .map_or(new, |l| new.min(l.available(peer.reuse_hash())))
+    }
+
+    /// Establish connections to the given [Peer] ahead of time and put them in the keepalive pool,
+    /// so that the first requests to the peer don't pay for the connection setup, e.g., after a
+    /// restart.
+    ///
+    /// Connections are made until there are `count` idle ones to the peer, or fewer if
+    /// [ConnectorOptions::max_idle_per_host] or [ConnectorOptions::max_connections_per_host] is
+    /// lower. They are made one at a time and the first failure is returned, the connections made
+    /// before it stay pooled. Connections that negotiate h2 are not pooled here, see
+    /// [http::Connector::warm_up()] instead.
+    ///
+    /// Return the number of the new connections pooled.
+    pub async fn warm_up<P: Peer + Send + Sync + 'static>(
+        &self,
+        peer: &P,
+        count: usize,
+    ) -> Result<usize> {
+        let key = peer.reuse_hash();
+        let idle = self.connection_pool.idle_count(&key);
+        let count = self.warm_up_count(peer, &self.connection_pool, idle, count);
+        for n in 0..count {
+            let stream = self.new_stream(peer).await?;
+            if matches!(stream.selected_alpn_proto(), Some(ALPN::H2)) {
+                return Ok(n);
+            }
+            self.release_stream(stream, key, peer.idle_timeout());
+        }
+        Ok(count)
+    }
+}
+
+// The connect timeouts of a connector for the peers that don't set their own
+#[derive(Debug, Clone, Copy, Default)]
+struct ConnectTimeouts {
+    connection: Option<Duration>,
+    total: Option<Duration>,
+}
+
+impl ConnectTimeouts {
+    fn new(options: Option<&ConnectorOptions>) -> Self {
+        options.map_or_else(Self::default, |o| ConnectTimeouts {
+            connection: o.connection_timeout,
+            total: o.total_connection_timeout,
+        })
+    }
+
+    // the default timeout of each connect step, only if the peer doesn't set its own, which the
+    // steps apply by themselves
+    fn default_connection<P: Peer>(&self, peer: &P) -> Option<Duration> {
+        match peer.connection_timeout() {
+            Some(_) => None,
+            None => self.connection,
+        }
+    }
+
+    fn total<P: Peer>(&self, peer: &P) -> Option<Duration> {
+        peer.total_connection_timeout().or(self.total)
+    }
+}
+
+// Run a connect step with the default connect timeout of the connector, if any
+async fn with_default_timeout<P: Peer, T>(
+    peer: &P,
+    timeout: Option<Duration>,
+    label: &'static str,
+    step: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(t) => pingora_timeout::timeout(t, step)
+            .label(label)
+            .await
+            .explain_err(ConnectTimedout, |_| {
+                format!("timeout {t:?} connecting to server {peer}")
+            })?,
+        None => step.await,
+    }
 }
 
 // Perform the actual L4 and tls connection steps while respecting the peer's
-// connection timeout if there is one
+// connection timeout if there is one, or the connector's otherwise
 async fn do_connect<P: Peer + Send + Sync>(
     peer: &P,
-    bind_to: Option<SocketAddr>,
+    bind_to: &BindTo,
     alpn_override: Option<ALPN>,
     tls_ctx: &SslConnector,
+    resolver: &dyn Resolver,
+    timeouts: ConnectTimeouts,
 ) -> Result<Stream> {
     // Create the future that does the connections, but don't evaluate it until
     // we decide if we need a timeout or not
-    let connect_future = do_connect_inner(peer, bind_to, alpn_override, tls_ctx);
-
-    match peer.total_connection_timeout() {
-        Some(t) => match pingora_timeout::timeout(t, connect_future).await {
+    let connect_future = do_connect_inner(
+        peer,
+        bind_to,
+        alpn_override,
+        tls_ctx,
+        resolver,
+        timeouts.default_connection(peer),
+    );
+
+    match timeouts.total(peer) {
+        Some(t) => match pingora_timeout::timeout(t, connect_future)
+            .label("total_connect")
+            .await
+        {
             Ok(res) => res,
             Err(_) => Error::e_explain(
                 ConnectTimedout,
@@ -286,22 +565,53 @@ async fn do_connect<P: Peer + Send + Sync>(
     }
 }
 
-// Perform the actual L4 and tls connection steps with no timeout
+// Perform the actual L4 and tls connection steps with no total timeout
 async fn do_connect_inner<P: Peer + Send + Sync>(
     peer: &P,
-    bind_to: Option<SocketAddr>,
+    bind_to: &BindTo,
     alpn_override: Option<ALPN>,
     tls_ctx: &SslConnector,
+    resolver: &dyn Resolver,
+    default_timeout: Option<Duration>,
 ) -> Result<Stream> {
-    let stream = l4_connect(peer, bind_to).await?;
+    let resolved = resolve_peer(peer, resolver).await?;
+    let l4_future = l4_connect(peer, bind_to, resolved.as_deref());
+    let stream = with_default_timeout(peer, default_timeout, "connect", l4_future).await?;
     if peer.tls() {
-        let tls_stream = tls::connect(stream, peer, alpn_override, tls_ctx).await?;
+        let tls_future = tls::connect(stream, peer, alpn_override, tls_ctx);
+        let tls_stream =
+            with_default_timeout(peer, default_timeout, "tls_handshake", tls_future).await?;
         Ok(Box::new(tls_stream))
     } else {
         Ok(Box::new(stream))
     }
 }
 
+// Resolve the hostname of the peer to the addresses to connect to, if it has one
+async fn resolve_peer<P: Peer>(
+    peer: &P,
+    resolver: &dyn Resolver,
+) -> Result<Option<Vec<SocketAddr>>> {
+    let Some(host) = peer.hostname() else {
+        return Ok(None);
+    };
+    let port = peer.address().as_inet().map_or(0, |a| a.port());
+    let resolved = resolver
+        .resolve(host)
+        .await
+        .err_context(|| format!("Fail to resolve peer {peer}"))?;
+    if resolved.addrs.is_empty() {
+        return Error::e_explain(ConnectError, format!("{host} resolved to no address"));
+    }
+    Ok(Some(
+        resolved
+            .addrs
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect(),
+    ))
+}
+
 struct PreferredHttpVersion {
     // TODO: shard to avoid the global lock
     versions: RwLock<HashMap<u64, u8>>, // <hash of peer, version>
@@ -363,7 +673,7 @@ mod tests {
 
     use super::*;
     use crate::tls::ssl::SslMethod;
-    use crate::upstreams::peer::BasicPeer;
+    use crate::upstreams::peer::{BasicPeer, HttpPeer};
 
     // 192.0.2.1 is effectively a black hole
     const BLACK_HOLE: &str = "192.0.2.1:79";
@@ -431,12 +741,78 @@ mod tests {
         assert!(error.etype() == &ConnectError || error.etype() == &ConnectTimedout)
     }
 
+    #[tokio::test]
+    async fn test_connect_hostname() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let _ = listener.accept().await;
+        });
+
+        let mut static_resolver = resolver::StaticResolver::new();
+        static_resolver.insert("backend.internal", vec!["127.0.0.1".parse().unwrap()]);
+        let mut conf = ConnectorOptions::new(1);
+        conf.resolver = Some(Arc::new(static_resolver));
+        let connector = TransportConnector::new(Some(conf));
+
+        let peer = HttpPeer::new_hostname("backend.internal", port, false, "".to_string());
+        let stream = connector.new_stream(&peer).await.unwrap();
+        assert!(peer.matches_fd(stream.id()));
+
+        let peer = HttpPeer::new_hostname("unknown.internal", port, false, "".to_string());
+        let e = connector.new_stream(&peer).await.unwrap_err();
+        assert_eq!(e.etype(), &ConnectError);
+    }
+
+    #[tokio::test]
+    async fn test_warm_up() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut conns = vec![];
+            while let Ok((conn, _)) = listener.accept().await {
+                conns.push(conn);
+            }
+        });
+        let peer = BasicPeer::new(&addr);
+
+        let mut conf = ConnectorOptions::new(8);
+        conf.max_idle_per_host = Some(3);
+        let connector = TransportConnector::new(Some(conf));
+        assert_eq!(connector.warm_up(&peer, 5).await.unwrap(), 3);
+        assert_eq!(connector.idle_connections(), vec![(peer.reuse_hash(), 3)]);
+        // already warm
+        assert_eq!(connector.warm_up(&peer, 5).await.unwrap(), 0);
+        let (_, reused) = connector.get_stream(&peer).await.unwrap();
+        assert!(reused);
+
+        let mut conf = ConnectorOptions::new(8);
+        conf.max_connections_per_host = Some(2);
+        let connector = TransportConnector::new(Some(conf));
+        assert_eq!(connector.warm_up(&peer, 5).await.unwrap(), 2);
+
+        // nothing listening
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = BasicPeer::new(&closed.local_addr().unwrap().to_string());
+        drop(closed);
+        assert!(connector.warm_up(&peer, 5).await.is_err());
+    }
+
     /// Helper function for testing error handling in the `do_connect` function.
     /// This assumes that the connection will fail to on the peer and returns
     /// the decomposed error type and message
     async fn get_do_connect_failure_with_peer(peer: &BasicPeer) -> (ErrorType, String) {
+        get_do_connect_failure(peer, ConnectTimeouts::default()).await
+    }
+
+    async fn get_do_connect_failure(
+        peer: &BasicPeer,
+        timeouts: ConnectTimeouts,
+    ) -> (ErrorType, String) {
         let ssl_connector = SslConnector::builder(SslMethod::tls()).unwrap().build();
-        let stream = do_connect(peer, None, None, &ssl_connector).await;
+        let resolver = resolver::StaticResolver::new();
+        let bind_to = BindTo::default();
+        let stream = do_connect(peer, &bind_to, None, &ssl_connector, &resolver, timeouts).await;
         match stream {
             Ok(_) => panic!("should throw an error"),
             Err(e) => (
@@ -468,6 +844,40 @@ mod tests {
         assert!(!context.contains("total-connection timeout"));
     }
 
+    #[tokio::test]
+    async fn test_do_connect_with_default_total_timeout() {
+        let mut options = ConnectorOptions::new(1);
+        options.total_connection_timeout = Some(std::time::Duration::from_millis(1));
+        let timeouts = ConnectTimeouts::new(Some(&options));
+        let peer = BasicPeer::new(BLACK_HOLE);
+        let (etype, context) = get_do_connect_failure(&peer, timeouts).await;
+        assert_eq!(etype, ConnectTimedout);
+        assert!(context.contains("total-connection timeout 1ms"));
+
+        // the peer's own timeout takes precedence
+        let mut peer = BasicPeer::new(BLACK_HOLE);
+        peer.options.total_connection_timeout = Some(std::time::Duration::from_millis(2));
+        let (etype, context) = get_do_connect_failure(&peer, timeouts).await;
+        assert_eq!(etype, ConnectTimedout);
+        assert!(context.contains("total-connection timeout 2ms"));
+    }
+
+    #[test]
+    fn test_default_connection_timeout() {
+        let mut options = ConnectorOptions::new(1);
+        options.connection_timeout = Some(std::time::Duration::from_secs(1));
+        let timeouts = ConnectTimeouts::new(Some(&options));
+        let mut peer = BasicPeer::new("127.0.0.1:80");
+        assert_eq!(
+            timeouts.default_connection(&peer),
+            Some(std::time::Duration::from_secs(1))
+        );
+        // the peer applies its own timeout
+        peer.options.connection_timeout = Some(std::time::Duration::from_secs(2));
+        assert_eq!(timeouts.default_connection(&peer), None);
+        assert_eq!(ConnectTimeouts::new(None).default_connection(&peer), None);
+    }
+
     #[tokio::test]
     async fn test_do_connect_without_total_timeout() {
         let peer = BasicPeer::new(BLACK_HOLE);
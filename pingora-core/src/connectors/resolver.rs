@@ -0,0 +1,252 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hostname resolution for upstream peers
+
+use async_trait::async_trait;
+use log::debug;
+use parking_lot::RwLock;
+use pingora_error::{Error, ErrorType::*, OrErr, Result};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// The addresses a hostname resolves to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolved {
+    /// The A and AAAA records of the hostname
+    pub addrs: Vec<IpAddr>,
+    /// How long the answer can be cached, e.g. the DNS TTL.
+    ///
+    /// `None` if unknown.
+    pub ttl: Option<Duration>,
+}
+
+/// The interface to resolve the hostnames of upstream peers
+///
+/// See [`crate::upstreams::peer::Peer::hostname()`].
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// Resolve the given hostname to its IP addresses
+    async fn resolve(&self, host: &str) -> Result<Resolved>;
+}
+
+/// A [`Resolver`] that uses the system resolver (`getaddrinfo()`)
+///
+/// The system resolver doesn't report TTLs.
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, host: &str) -> Result<Resolved> {
+        let addrs = tokio::net::lookup_host((host, 0))
+            .await
+            .or_err_with(ConnectError, || format!("fail to resolve {host}"))?
+            .map(|a| a.ip())
+            .collect();
+        Ok(Resolved { addrs, ttl: None })
+    }
+}
+
+/// A [`Resolver`] that answers from a fixed table, mostly for testing
+#[derive(Default)]
+pub struct StaticResolver {
+    table: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticResolver {
+    /// Create an empty [`StaticResolver`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the addresses the given hostname resolves to
+    pub fn insert(&mut self, host: &str, addrs: Vec<IpAddr>) {
+        self.table.insert(host.to_string(), addrs);
+    }
+}
+
+#[async_trait]
+impl Resolver for StaticResolver {
+    async fn resolve(&self, host: &str) -> Result<Resolved> {
+        match self.table.get(host) {
+            Some(addrs) => Ok(Resolved {
+                addrs: addrs.clone(),
+                ttl: None,
+            }),
+            None => Error::e_explain(ConnectError, format!("fail to resolve {host}")),
+        }
+    }
+}
+
+struct CacheEntry {
+    // None: negative cache
+    addrs: Option<Vec<IpAddr>>,
+    expire: Instant,
+}
+
+/// A [`Resolver`] that caches the answers of another [`Resolver`]
+///
+/// Answers are cached for their TTL, clamped to `max_ttl`. Answers without a TTL are cached for
+/// `default_ttl`. Failures and empty answers are cached for `negative_ttl`.
+pub struct CachingResolver<R> {
+    inner: R,
+    cache: RwLock<HashMap<String, CacheEntry>>,
+    /// How long to cache answers without TTL
+    pub default_ttl: Duration,
+    /// The upper bound of how long to cache any answer
+    pub max_ttl: Duration,
+    /// How long to cache failed lookups
+    pub negative_ttl: Duration,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    /// Create a new [`CachingResolver`] on top of the given [`Resolver`]
+    pub fn new(inner: R) -> Self {
+        CachingResolver {
+            inner,
+            cache: RwLock::new(HashMap::new()),
+            default_ttl: Duration::from_secs(30),
+            max_ttl: Duration::from_secs(3600),
+            negative_ttl: Duration::from_secs(5),
+        }
+    }
+
+    fn lookup_cache(&self, host: &str, now: Instant) -> Option<Result<Resolved>> {
+        let cache = self.cache.read();
+        let entry = cache.get(host)?;
+        if entry.expire <= now {
+            return None;
+        }
+        let ttl = Some(entry.expire - now);
+        Some(match &entry.addrs {
+            Some(addrs) => Ok(Resolved {
+                addrs: addrs.clone(),
+                ttl,
+            }),
+            None => Error::e_explain(
+                ConnectError,
+                format!("fail to resolve {host} (negative cache)"),
+            ),
+        })
+    }
+}
+
+#[async_trait]
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    async fn resolve(&self, host: &str) -> Result<Resolved> {
+        let now = Instant::now();
+        if let Some(cached) = self.lookup_cache(host, now) {
+            return cached;
+        }
+
+        let res = self.inner.resolve(host).await;
+        let (addrs, ttl) = match &res {
+            Ok(r) if !r.addrs.is_empty() => (
+                Some(r.addrs.clone()),
+                r.ttl.unwrap_or(self.default_ttl).min(self.max_ttl),
+            ),
+            _ => (None, self.negative_ttl),
+        };
+        debug!("caching resolution of {host} for {ttl:?}: {addrs:?}");
+        let mut cache = self.cache.write();
+        // evict expired entries so that the cache doesn't grow with stale hostnames
+        cache.retain(|_, e| e.expire > now);
+        cache.insert(
+            host.to_string(),
+            CacheEntry {
+                addrs,
+                expire: now + ttl,
+            },
+        );
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingResolver {
+        count: AtomicUsize,
+        ttl: Option<Duration>,
+    }
+
+    #[async_trait]
+    impl Resolver for CountingResolver {
+        async fn resolve(&self, host: &str) -> Result<Resolved> {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            if host == "bad.example" {
+                return Error::e_explain(ConnectError, "NXDOMAIN");
+            }
+            Ok(Resolved {
+                addrs: vec!["127.0.0.1".parse().unwrap()],
+                ttl: self.ttl,
+            })
+        }
+    }
+
+    fn counting(ttl: Option<Duration>) -> CachingResolver<CountingResolver> {
+        CachingResolver::new(CountingResolver {
+            count: AtomicUsize::new(0),
+            ttl,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_static_resolver() {
+        let mut resolver = StaticResolver::new();
+        resolver.insert("a.example", vec!["::1".parse().unwrap()]);
+        let r = resolver.resolve("a.example").await.unwrap();
+        assert_eq!(r.addrs, vec!["::1".parse::<IpAddr>().unwrap()]);
+        assert!(resolver.resolve("b.example").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cache_respects_ttl() {
+        let resolver = counting(Some(Duration::from_millis(50)));
+        resolver.resolve("a.example").await.unwrap();
+        let r = resolver.resolve("a.example").await.unwrap();
+        assert!(r.ttl.unwrap() <= Duration::from_millis(50));
+        assert_eq!(resolver.inner.count.load(Ordering::Relaxed), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        resolver.resolve("a.example").await.unwrap();
+        assert_eq!(resolver.inner.count.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_max_ttl() {
+        let mut resolver = counting(Some(Duration::from_secs(3600)));
+        resolver.max_ttl = Duration::from_millis(10);
+        resolver.resolve("a.example").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        resolver.resolve("a.example").await.unwrap();
+        assert_eq!(resolver.inner.count.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_negative_cache() {
+        let mut resolver = counting(None);
+        resolver.negative_ttl = Duration::from_millis(50);
+        assert!(resolver.resolve("bad.example").await.is_err());
+        assert!(resolver.resolve("bad.example").await.is_err());
+        assert_eq!(resolver.inner.count.load(Ordering::Relaxed), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(resolver.resolve("bad.example").await.is_err());
+        assert_eq!(resolver.inner.count.load(Ordering::Relaxed), 2);
+    }
+}
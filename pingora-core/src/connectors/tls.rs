@@ -259,7 +259,10 @@ where
     let connect_future = handshake(ssl_conf, peer.sni(), stream);
 
     match peer.connection_timeout() {
-        Some(t) => match pingora_timeout::timeout(t, connect_future).await {
+        Some(t) => match pingora_timeout::timeout(t, connect_future)
+            .label("tls_handshake")
+            .await
+        {
             Ok(res) => res,
             Err(_) => Error::e_explain(
                 ConnectTimedout,
@@ -38,6 +38,12 @@
 //!
 //! # Optional features
 //! `boringssl`: Switch the internal TLS library from OpenSSL to BoringSSL.
+//!
+//! `transparent_proxy`: Support transparent proxying on Linux, see
+//! [TcpSocketOptions::transparent](crate::listeners::TcpSocketOptions::transparent).
+//!
+//! `test-utils`: Expose the test harnesses, e.g., [server::upgrade_test] for zero downtime
+//! upgrades.
 
 pub mod apps;
 pub mod connectors;
@@ -0,0 +1,190 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! IP allow/deny lists evaluated right after a connection is accepted
+
+use pingora_error::{Error, ErrorType, OrErr, Result};
+use std::net::IpAddr;
+
+pub const ACL_CONF_ERR: ErrorType = ErrorType::Custom("ACLConfigError");
+
+/// An IPv4 or IPv6 network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    /// Parse a CIDR string. A bare address is treated as a single host network.
+    pub fn parse(cidr: &str) -> Result<Self> {
+        let (addr, prefix_len) = match cidr.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (cidr, None),
+        };
+        let addr: IpAddr = addr
+            .trim()
+            .parse()
+            .or_err_with(ACL_CONF_ERR, || format!("invalid address in {cidr}"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .trim()
+                .parse::<u8>()
+                .or_err_with(ACL_CONF_ERR, || format!("invalid prefix length in {cidr}"))?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Error::e_explain(ACL_CONF_ERR, format!("prefix length too long in {cidr}"));
+        }
+        Ok(IpCidr { addr, prefix_len })
+    }
+
+    /// Whether the given address is part of this network
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A list of allowed and denied networks enforced on a listening endpoint.
+///
+/// The peer address of every accepted connection is evaluated in this order:
+/// 1. If it matches any denied network, the connection is closed.
+/// 2. Otherwise, if it matches any allowed network, the connection is accepted.
+/// 3. Otherwise, the default policy of the list decides.
+///
+/// IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) are matched as their IPv4 form.
+/// Connections without an IP peer address (e.g. Unix domain sockets) are always accepted.
+#[derive(Clone, Debug)]
+pub struct IpAccessList {
+    allow: Vec<IpCidr>,
+    deny: Vec<IpCidr>,
+    default_allow: bool,
+}
+
+impl IpAccessList {
+    /// Create an empty list that accepts addresses not matching any rule.
+    pub fn allow_by_default() -> Self {
+        IpAccessList {
+            allow: vec![],
+            deny: vec![],
+            default_allow: true,
+        }
+    }
+
+    /// Create an empty list that rejects addresses not matching any rule.
+    pub fn deny_by_default() -> Self {
+        IpAccessList {
+            default_allow: false,
+            ..Self::allow_by_default()
+        }
+    }
+
+    /// Add a network to the allow list.
+    pub fn allow(&mut self, cidr: &str) -> Result<()> {
+        self.allow.push(IpCidr::parse(cidr)?);
+        Ok(())
+    }
+
+    /// Add a network to the deny list.
+    pub fn deny(&mut self, cidr: &str) -> Result<()> {
+        self.deny.push(IpCidr::parse(cidr)?);
+        Ok(())
+    }
+
+    /// Whether a connection from the given address should be accepted.
+    pub fn is_allowed(&self, addr: &IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(addr)) {
+            return false;
+        }
+        if self.allow.iter().any(|net| net.contains(addr)) {
+            return true;
+        }
+        self.default_allow
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_cidr() {
+        assert!(IpCidr::parse("10.0.0.0/8").is_ok());
+        assert!(IpCidr::parse("fd00::/8").is_ok());
+        assert_eq!(
+            IpCidr::parse("1.2.3.4").unwrap(),
+            IpCidr::parse("1.2.3.4/32").unwrap()
+        );
+        assert!(IpCidr::parse("10.0.0.0/33").is_err());
+        assert!(IpCidr::parse("::/129").is_err());
+        assert!(IpCidr::parse("10.0.0/8").is_err());
+        assert!(IpCidr::parse("10.0.0.0/x").is_err());
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let net = IpCidr::parse("192.168.0.0/16").unwrap();
+        assert!(net.contains(&ip("192.168.3.4")));
+        assert!(net.contains(&ip("::ffff:192.168.3.4")));
+        assert!(!net.contains(&ip("192.169.0.1")));
+        assert!(!net.contains(&ip("fd00::1")));
+
+        let any = IpCidr::parse("0.0.0.0/0").unwrap();
+        assert!(any.contains(&ip("8.8.8.8")));
+
+        let net = IpCidr::parse("fd00::/8").unwrap();
+        assert!(net.contains(&ip("fd12::1")));
+        assert!(!net.contains(&ip("fe80::1")));
+    }
+
+    #[test]
+    fn test_deny_over_allow() {
+        let mut acl = IpAccessList::allow_by_default();
+        acl.allow("10.0.0.0/8").unwrap();
+        acl.deny("10.1.0.0/16").unwrap();
+        assert!(acl.is_allowed(&ip("10.2.0.1")));
+        assert!(!acl.is_allowed(&ip("10.1.0.1")));
+        assert!(acl.is_allowed(&ip("1.1.1.1")));
+    }
+
+    #[test]
+    fn test_default_deny() {
+        let mut acl = IpAccessList::deny_by_default();
+        acl.allow("127.0.0.1").unwrap();
+        acl.allow("::1").unwrap();
+        assert!(acl.is_allowed(&ip("127.0.0.1")));
+        assert!(acl.is_allowed(&ip("::1")));
+        assert!(!acl.is_allowed(&ip("127.0.0.2")));
+        assert!(!acl.is_allowed(&ip("2001:db8::1")));
+    }
+}
@@ -12,8 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use log::warn;
+use log::{error, warn};
 use pingora_error::{
+    Error,
     ErrorType::{AcceptError, BindError},
     OrErr, Result,
 };
@@ -22,9 +23,11 @@ use std::io::ErrorKind;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::Path;
 use std::time::Duration;
 use tokio::net::TcpSocket;
 
+use crate::protocols::l4::ext::set_ip_transparent;
 use crate::protocols::l4::listener::Listener;
 pub use crate::protocols::l4::stream::Stream;
 use crate::server::ListenFds;
@@ -51,12 +54,34 @@ impl AsRef<str> for ServerAddress {
 }
 
 /// TCP socket configuration options.
-#[derive(Clone, Debug)]
+///
+/// On graceful upgrade, the listening sockets passed by the old process were created with the
+/// options of the old process. The options that can change on a bound socket, i.e.,
+/// `transparent`, and the listen backlog, are applied again with the values of the new process.
+/// The others, `ipv6_only` and `reuseport`, only apply when the socket is bound: a change of them
+/// is logged and takes effect after a restart instead of an upgrade.
+#[derive(Clone, Debug, Default)]
 pub struct TcpSocketOptions {
     /// IPV6_V6ONLY flag (if true, limit socket to IPv6 communication only).
     /// This is mostly useful when binding to `[::]`, which on most Unix distributions
     /// will bind to both IPv4 and IPv6 addresses by default.
     pub ipv6_only: bool,
+    /// SO_REUSEPORT flag (if true, each endpoint of this address binds its own socket and the
+    /// kernel spreads the new connections across them).
+    ///
+    /// On graceful upgrade, the endpoints of the new process adopt these sockets one each instead
+    /// of binding more of them, so the old process doesn't keep taking a share of the
+    /// connections after it stops accepting.
+    pub reuseport: bool,
+    /// IP_TRANSPARENT flag (if true, accept the connections intercepted by an iptables `TPROXY`
+    /// rule, whose destinations are not local addresses).
+    ///
+    /// An upgrade sets or clears it on the adopted socket as configured.
+    ///
+    /// The original destination of a connection is available via
+    /// [SocketDigest::original_dst()](crate::protocols::SocketDigest::original_dst). Linux only,
+    /// requires the `transparent_proxy` feature and `CAP_NET_ADMIN`.
+    pub transparent: bool,
     // TODO: allow configuring reuseaddr, backlog, etc. from here?
 }
 
@@ -116,17 +141,71 @@ mod uds {
     }
 }
 
-// currently, these options can only apply on sockets prior to calling bind()
-fn apply_tcp_socket_options(sock: &TcpSocket, opt: Option<&TcpSocketOptions>) -> Result<()> {
+// applied on sockets prior to calling bind(), see reapply_tcp_socket_options() for the sockets
+// already bound
+fn apply_tcp_socket_options(
+    sock: &TcpSocket,
+    ipv6: bool,
+    opt: Option<&TcpSocketOptions>,
+) -> Result<()> {
     let Some(opt) = opt else {
         return Ok(());
     };
+    if opt.reuseport {
+        sock.set_reuseport(true)
+            .or_err(BindError, "failed to set SO_REUSEPORT")?;
+    }
+    if opt.transparent {
+        set_ip_transparent(sock.as_raw_fd(), ipv6, true)
+            .or_err(BindError, "failed to set IP_TRANSPARENT")?;
+    }
+    if !ipv6 {
+        // IPV6_V6ONLY is not available on IPv4 sockets
+        return Ok(());
+    }
     let socket_ref = socket2::SockRef::from(sock);
     socket_ref
         .set_only_v6(opt.ipv6_only)
         .or_err(BindError, "failed to set IPV6_V6ONLY")
 }
 
+// apply the options that can still change on the bound socket passed by the old process, and
+// warn about the ones that cannot
+fn reapply_tcp_socket_options(
+    sock: &TcpSocket,
+    addr: &str,
+    opt: Option<&TcpSocketOptions>,
+) -> Result<()> {
+    let default = TcpSocketOptions::default();
+    let opt = opt.unwrap_or(&default);
+    let ipv6 = sock.local_addr().is_ok_and(|a| a.is_ipv6());
+    // the old process may have set it or not
+    set_ip_transparent(sock.as_raw_fd(), ipv6, opt.transparent)
+        .or_err(BindError, "failed to set IP_TRANSPARENT")?;
+    for name in fixed_option_mismatches(sock, ipv6, opt) {
+        warn!("{name} of {addr} cannot change on the upgraded socket, restart to change it");
+    }
+    Ok(())
+}
+
+// the options that differ between the bound socket and the configuration but only apply before
+// bind()
+fn fixed_option_mismatches(
+    sock: &TcpSocket,
+    ipv6: bool,
+    opt: &TcpSocketOptions,
+) -> Vec<&'static str> {
+    let socket_ref = socket2::SockRef::from(sock);
+    let mut mismatches = vec![];
+    if socket_ref.reuse_port().is_ok_and(|v| v != opt.reuseport) {
+        mismatches.push("SO_REUSEPORT");
+    }
+    if ipv6 && socket_ref.only_v6().is_ok_and(|v| v != opt.ipv6_only) {
+        mismatches.push("IPV6_V6ONLY");
+    }
+    mismatches
+}
+
 fn from_raw_fd(address: &ServerAddress, fd: i32) -> Result<Listener> {
     match address {
         ServerAddress::Uds(addr, perm) => {
@@ -135,9 +214,10 @@ fn from_raw_fd(address: &ServerAddress, fd: i32) -> Result<Listener> {
             uds::set_perms(addr, perm.clone())?;
             Ok(uds::set_backlog(std_listener, LISTENER_BACKLOG)?.into())
         }
-        ServerAddress::Tcp(_, _) => {
+        ServerAddress::Tcp(addr, opt) => {
             let std_listener_socket = unsafe { std::net::TcpStream::from_raw_fd(fd) };
             let listener_socket = TcpSocket::from_std_stream(std_listener_socket);
+            reapply_tcp_socket_options(&listener_socket, addr, opt.as_ref())?;
             // Note that we call listen on an already listening socket
             // POSIX undefined but on Linux it will update the backlog size
             Ok(listener_socket
@@ -148,6 +228,42 @@ fn from_raw_fd(address: &ServerAddress, fd: i32) -> Result<Listener> {
     }
 }
 
+// make sure the listener passed from the old process is bound to the configured address
+fn check_upgraded_listener(address: &ServerAddress, listener: &Listener) -> Result<()> {
+    let (matched, actual) = match (address, listener) {
+        (ServerAddress::Tcp(addr, _), Listener::Tcp(l)) => match l.local_addr() {
+            // hostnames are not resolved again, only literal addresses are compared
+            Ok(actual) => (
+                !matches!(addr.parse::<SocketAddr>(), Ok(a) if a != actual),
+                format!("{actual}"),
+            ),
+            Err(e) => (false, format!("{e}")),
+        },
+        (ServerAddress::Uds(path, _), Listener::Unix(l)) => match l.local_addr() {
+            Ok(actual) => (
+                actual.as_pathname() == Some(Path::new(path)),
+                format!("{actual:?}"),
+            ),
+            Err(e) => (false, format!("{e}")),
+        },
+        _ => (false, "a different type of socket".to_string()),
+    };
+    if matched {
+        return Ok(());
+    }
+    error!(
+        "The fd of {} passed by the old process is {actual}, rejecting it",
+        address.as_ref()
+    );
+    Error::e_explain(
+        BindError,
+        format!(
+            "the fd passed for {} is bound to {actual}",
+            address.as_ref()
+        ),
+    )
+}
+
 async fn bind_tcp(addr: &str, opt: Option<TcpSocketOptions>) -> Result<Listener> {
     let mut try_count = 0;
     loop {
@@ -170,7 +286,7 @@ async fn bind_tcp(addr: &str, opt: Option<TcpSocketOptions>) -> Result<Listener>
             .set_reuseaddr(true)
             .or_err(BindError, "fail to set_reuseaddr(true)")?;
 
-        apply_tcp_socket_options(&listener_socket, opt.as_ref())?;
+        apply_tcp_socket_options(&listener_socket, sock_addr.is_ipv6(), opt.as_ref())?;
 
         match listener_socket.bind(sock_addr) {
             Ok(()) => {
@@ -220,6 +336,10 @@ impl ListenerEndpoint {
         self.listen_addr.as_ref()
     }
 
+    fn reuseport(&self) -> bool {
+        matches!(&self.listen_addr, ServerAddress::Tcp(_, Some(opt)) if opt.reuseport)
+    }
+
     pub async fn listen(&mut self, fds: Option<ListenFds>) -> Result<()> {
         if self.listener.is_some() {
             return Ok(());
@@ -229,8 +349,21 @@ impl ListenerEndpoint {
             let addr = self.listen_addr.as_ref();
             // consider make this mutex std::sync::Mutex or OnceCell
             let mut table = fds_table.lock().await;
-            if let Some(fd) = table.get(addr.as_ref()) {
-                from_raw_fd(&self.listen_addr, *fd)?
+            if self.reuseport() {
+                // each endpoint needs a socket of its own
+                if let Some(fd) = table.take_reuseport(addr) {
+                    let listener = from_raw_fd(&self.listen_addr, fd)?;
+                    check_upgraded_listener(&self.listen_addr, &listener)?;
+                    listener
+                } else {
+                    let listener = bind(&self.listen_addr).await?;
+                    table.add_reuseport(addr.to_string(), listener.as_raw_fd());
+                    listener
+                }
+            } else if let Some(fd) = table.get(addr.as_ref()) {
+                let listener = from_raw_fd(&self.listen_addr, *fd)?;
+                check_upgraded_listener(&self.listen_addr, &listener)?;
+                listener
             } else {
                 // not found
                 let listener = bind(&self.listen_addr).await?;
@@ -262,6 +395,7 @@ impl ListenerEndpoint {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::protocols::GetSocketDigest;
 
     #[tokio::test]
     async fn test_listen_tcp() {
@@ -277,9 +411,142 @@ mod test {
             .expect("can connect to TCP listener");
     }
 
+    #[tokio::test]
+    async fn test_listen_upgraded_fd() {
+        use crate::server::transfer_fd::Fds;
+        use std::os::unix::io::IntoRawFd;
+        use std::sync::Arc;
+        use tokio::sync::Mutex;
+
+        let fd = std::net::TcpListener::bind("127.0.0.1:7105")
+            .unwrap()
+            .into_raw_fd();
+        let mut fds = Fds::new();
+        fds.add("127.0.0.1:7105".to_string(), fd);
+        let fds = Arc::new(Mutex::new(fds));
+        let mut listener = ListenerEndpoint::new(ServerAddress::Tcp("127.0.0.1:7105".into(), None));
+        listener.listen(Some(fds)).await.unwrap();
+
+        // the fd passed is not bound to the configured address
+        let fd = std::net::TcpListener::bind("127.0.0.1:7106")
+            .unwrap()
+            .into_raw_fd();
+        let mut fds = Fds::new();
+        fds.add("127.0.0.1:7107".to_string(), fd);
+        let fds = Arc::new(Mutex::new(fds));
+        let mut listener = ListenerEndpoint::new(ServerAddress::Tcp("127.0.0.1:7107".into(), None));
+        assert!(listener.listen(Some(fds)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_listen_upgraded_fd_options() {
+        use crate::server::transfer_fd::Fds;
+        use std::os::unix::io::IntoRawFd;
+        use std::sync::Arc;
+        use tokio::sync::Mutex;
+
+        // the old process binds with IPV6_V6ONLY
+        let sock = TcpSocket::new_v6().unwrap();
+        socket2::SockRef::from(&sock).set_only_v6(true).unwrap();
+        if sock.bind("[::1]:0".parse().unwrap()).is_err() {
+            // no IPv6 loopback in this environment
+            return;
+        }
+        let addr = &sock.local_addr().unwrap().to_string();
+        let sock_opt = TcpSocketOptions::default();
+        assert_eq!(
+            fixed_option_mismatches(&sock, true, &sock_opt),
+            ["IPV6_V6ONLY"]
+        );
+        let same_opt = TcpSocketOptions {
+            ipv6_only: true,
+            ..Default::default()
+        };
+        assert!(fixed_option_mismatches(&sock, true, &same_opt).is_empty());
+        let fd = sock
+            .listen(LISTENER_BACKLOG)
+            .unwrap()
+            .into_std()
+            .unwrap()
+            .into_raw_fd();
+
+        // the new process still adopts the socket, the option takes effect after a restart
+        let mut fds = Fds::new();
+        fds.add(addr.to_string(), fd);
+        let fds = Arc::new(Mutex::new(fds));
+        let mut listener = ListenerEndpoint::new(ServerAddress::Tcp(addr.into(), Some(sock_opt)));
+        listener.listen(Some(fds)).await.unwrap();
+        assert_eq!(listener.listener.as_ref().unwrap().as_raw_fd(), fd);
+    }
+
+    #[tokio::test]
+    async fn test_listen_reuseport_upgraded_fd() {
+        use crate::server::transfer_fd::Fds;
+        use std::os::unix::io::IntoRawFd;
+        use std::sync::Arc;
+        use tokio::sync::Mutex;
+
+        let addr = "127.0.0.1:7108";
+        // the old process listens on the address with two reuseport sockets
+        let bind_reuseport = || {
+            let sock = TcpSocket::new_v4().unwrap();
+            sock.set_reuseport(true).unwrap();
+            sock.bind(addr.parse().unwrap()).unwrap();
+            sock.listen(LISTENER_BACKLOG)
+                .unwrap()
+                .into_std()
+                .unwrap()
+                .into_raw_fd()
+        };
+        let old_fds = [bind_reuseport(), bind_reuseport()];
+        let mut fds = Fds::new();
+        for fd in old_fds {
+            fds.add_reuseport(addr.to_string(), fd);
+        }
+        let (binds, raw_fds) = fds.serialize();
+        let mut fds = Fds::new();
+        fds.deserialize(binds, raw_fds);
+        let fds = Arc::new(Mutex::new(fds));
+
+        // the new process adopts both of them instead of binding competing ones
+        let sock_opt = TcpSocketOptions {
+            reuseport: true,
+            ..Default::default()
+        };
+        let mut listeners = vec![];
+        for _ in 0..3 {
+            let mut listener =
+                ListenerEndpoint::new(ServerAddress::Tcp(addr.into(), Some(sock_opt.clone())));
+            listener.listen(Some(fds.clone())).await.unwrap();
+            listeners.push(listener);
+        }
+        let new_fds: Vec<_> = listeners
+            .iter()
+            .map(|l| l.listener.as_ref().unwrap().as_raw_fd())
+            .collect();
+        assert_eq!(new_fds[..2], old_fds);
+        // the extra endpoint binds a socket of its own
+        assert!(!old_fds.contains(&new_fds[2]));
+        assert!(fds.lock().await.unadopted_reuseport().is_empty());
+        // all of them are handed off on the next upgrade
+        assert_eq!(fds.lock().await.serialize().1, new_fds);
+
+        for mut listener in listeners {
+            tokio::spawn(async move {
+                listener.accept().await.unwrap();
+            });
+        }
+        tokio::net::TcpStream::connect(addr)
+            .await
+            .expect("can connect to TCP listener");
+    }
+
     #[tokio::test]
     async fn test_listen_tcp_ipv6_only() {
-        let sock_opt = Some(TcpSocketOptions { ipv6_only: true });
+        let sock_opt = Some(TcpSocketOptions {
+            ipv6_only: true,
+            ..Default::default()
+        });
         let mut listener = ListenerEndpoint::new(ServerAddress::Tcp("[::]:7101".into(), sock_opt));
         listener.listen(None).await.unwrap();
         tokio::spawn(async move {
@@ -295,6 +562,22 @@ mod test {
             .expect("can connect to v6 addr");
     }
 
+    #[tokio::test]
+    async fn test_listen_tcp_original_dst() {
+        let addr = "127.0.0.1:7109";
+        let mut listener = ListenerEndpoint::new(ServerAddress::Tcp(addr.into(), None));
+        listener.listen(None).await.unwrap();
+        let _client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let stream = listener.accept().await.unwrap();
+        let digest = stream.get_socket_digest().unwrap();
+        if cfg!(all(feature = "transparent_proxy", target_os = "linux")) {
+            // not redirected
+            assert_eq!(digest.original_dst(), digest.local_addr());
+        } else {
+            assert!(digest.original_dst().is_none());
+        }
+    }
+
     #[tokio::test]
     async fn test_listen_uds() {
         let addr = "/tmp/test_listen_uds";
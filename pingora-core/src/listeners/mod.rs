@@ -14,12 +14,15 @@
 
 //! The listening endpoints (TCP and TLS) and their configurations.
 
+mod acl;
 mod l4;
+mod stats;
 mod tls;
 
-use crate::protocols::Stream;
+use crate::protocols::{GetSocketDigest, Stream};
 use crate::server::ListenFds;
 
+use log::debug;
 use pingora_error::Result;
 use std::{fs::Permissions, sync::Arc};
 
@@ -27,12 +30,17 @@ use l4::{ListenerEndpoint, Stream as L4Stream};
 use tls::Acceptor;
 
 pub use crate::protocols::ssl::server::TlsAccept;
+pub use acl::{IpAccessList, IpCidr};
 pub use l4::{ServerAddress, TcpSocketOptions};
+pub use stats::{ListenerStats, ListenerStatsSnapshot};
 pub use tls::{TlsSettings, ALPN};
 
 struct TransportStackBuilder {
     l4: ServerAddress,
     tls: Option<TlsSettings>,
+    acl: Option<Arc<IpAccessList>>,
+    // shared by the stacks built from this across restarts of the service
+    stats: Arc<ListenerStats>,
 }
 
 impl TransportStackBuilder {
@@ -40,6 +48,8 @@ impl TransportStackBuilder {
         TransportStack {
             l4: ListenerEndpoint::new(self.l4.clone()),
             tls: self.tls.take().map(|tls| Arc::new(tls.build())),
+            acl: self.acl.clone(),
+            stats: self.stats.clone(),
             upgrade_listeners,
         }
     }
@@ -48,6 +58,8 @@ impl TransportStackBuilder {
 pub(crate) struct TransportStack {
     l4: ListenerEndpoint,
     tls: Option<Arc<Acceptor>>,
+    acl: Option<Arc<IpAccessList>>,
+    stats: Arc<ListenerStats>,
     // listeners sent from the old process for graceful upgrade
     upgrade_listeners: Option<ListenFds>,
 }
@@ -57,16 +69,50 @@ impl TransportStack {
         self.l4.as_str()
     }
 
+    pub fn stats(&self) -> &Arc<ListenerStats> {
+        &self.stats
+    }
+
     pub async fn listen(&mut self) -> Result<()> {
         self.l4.listen(self.upgrade_listeners.take()).await
     }
 
     pub async fn accept(&mut self) -> Result<UninitializedStream> {
-        let stream = self.l4.accept().await?;
-        Ok(UninitializedStream {
-            l4: stream,
-            tls: self.tls.clone(),
-        })
+        loop {
+            let stream = self.l4.accept().await?;
+            if !self.is_allowed(&stream) {
+                self.stats.record_rejected();
+                // dropping the stream closes the connection
+                continue;
+            }
+            if let Some(digest) = stream.get_socket_digest() {
+                // for the HTTP sessions to count their requests
+                digest.set_listener_stats(self.stats.clone());
+            }
+            return Ok(UninitializedStream {
+                l4: stream,
+                tls: self.tls.clone(),
+                stats: self.stats.clone(),
+            });
+        }
+    }
+
+    fn is_allowed(&self, stream: &L4Stream) -> bool {
+        let Some(acl) = self.acl.as_ref() else {
+            return true;
+        };
+        let Some(digest) = stream.get_socket_digest() else {
+            return true;
+        };
+        // non-IP peers such as UDS clients are not subject to the IP access list
+        let Some(peer) = digest.peer_addr().and_then(|a| a.as_inet()) else {
+            return true;
+        };
+        let allowed = acl.is_allowed(&peer.ip());
+        if !allowed {
+            debug!("Connection from {peer} denied on {}", self.as_str());
+        }
+        allowed
     }
 
     pub fn cleanup(&mut self) {
@@ -77,14 +123,23 @@ impl TransportStack {
 pub(crate) struct UninitializedStream {
     l4: L4Stream,
     tls: Option<Arc<Acceptor>>,
+    stats: Arc<ListenerStats>,
 }
 
 impl UninitializedStream {
     pub async fn handshake(self) -> Result<Stream> {
         if let Some(tls) = self.tls {
-            let tls_stream = tls.tls_handshake(self.l4).await?;
+            let tls_stream = match tls.tls_handshake(self.l4).await {
+                Ok(s) => s,
+                Err(e) => {
+                    self.stats.record_rejected();
+                    return Err(e);
+                }
+            };
+            self.stats.record_accepted();
             Ok(Box::new(tls_stream))
         } else {
+            self.stats.record_accepted();
             Ok(Box::new(self.l4))
         }
     }
@@ -165,7 +220,38 @@ impl Listeners {
 
     /// Add the given [`ServerAddress`] to `self` with the given [`TlsSettings`] if provided
     pub fn add_endpoint(&mut self, l4: ServerAddress, tls: Option<TlsSettings>) {
-        self.stacks.push(TransportStackBuilder { l4, tls })
+        self.stacks.push(TransportStackBuilder {
+            l4,
+            tls,
+            acl: None,
+            stats: Arc::default(),
+        })
+    }
+
+    /// Add the given [`ServerAddress`] to `self` with the given [`TlsSettings`] if provided.
+    ///
+    /// Connections whose peer address is rejected by the [`IpAccessList`] are closed right after
+    /// `accept()`, before any TLS handshake or request parsing happens.
+    pub fn add_endpoint_with_acl(
+        &mut self,
+        l4: ServerAddress,
+        tls: Option<TlsSettings>,
+        acl: IpAccessList,
+    ) {
+        self.stacks.push(TransportStackBuilder {
+            l4,
+            tls,
+            acl: Some(Arc::new(acl)),
+            stats: Arc::default(),
+        })
+    }
+
+    /// The [ListenerStats] of each endpoint, along with its address
+    pub fn listener_stats(&self) -> Vec<(String, Arc<ListenerStats>)> {
+        self.stacks
+            .iter()
+            .map(|b| (b.l4.as_ref().to_string(), b.stats.clone()))
+            .collect()
     }
 
     pub(crate) fn build(&mut self, upgrade_listeners: Option<ListenFds>) -> Vec<TransportStack> {
@@ -212,6 +298,54 @@ mod test {
         TcpStream::connect(addr2).await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_listen_tcp_acl() {
+        use tokio::io::AsyncReadExt;
+
+        let addr = "127.0.0.1:7104";
+        let mut acl = IpAccessList::deny_by_default();
+        acl.allow("127.0.0.0/8").unwrap();
+        acl.deny("127.0.0.2").unwrap();
+        let mut listeners = Listeners::new();
+        listeners.add_endpoint_with_acl(ServerAddress::Tcp(addr.into(), None), None, acl);
+        let (endpoint, stats) = listeners.listener_stats().pop().unwrap();
+        assert_eq!(endpoint, addr);
+        let mut listener = listeners.build(None).pop().unwrap();
+
+        tokio::spawn(async move {
+            listener.listen().await.unwrap();
+            loop {
+                let stream = listener.accept().await.unwrap();
+                let mut stream = stream.handshake().await.unwrap();
+                let digest = stream.get_socket_digest().unwrap();
+                assert!(Arc::ptr_eq(
+                    digest.listener_stats().unwrap(),
+                    listener.stats()
+                ));
+                stream.write_all(b"hi").await.unwrap();
+                stream.flush().await.unwrap();
+            }
+        });
+        // make sure the above starts before the lines below
+        sleep(Duration::from_millis(10)).await;
+
+        let mut buf = [0; 2];
+        let mut allowed = TcpStream::connect(addr).await.unwrap();
+        allowed.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+
+        // denied connections are closed without any data
+        let sock = tokio::net::TcpSocket::new_v4().unwrap();
+        sock.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let mut denied = sock.connect(addr.parse().unwrap()).await.unwrap();
+        assert_eq!(denied.read(&mut buf).await.unwrap(), 0);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.accepted, 1);
+        assert_eq!(snapshot.rejected, 1);
+        assert_eq!(snapshot.requests(), 0);
+    }
+
     #[tokio::test]
     async fn test_listen_tls() {
         use tokio::io::AsyncReadExt;
@@ -245,4 +379,34 @@ mod test {
         let res = client.get(format!("https://{addr}")).send().await.unwrap();
         assert_eq!(res.status(), reqwest::StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn test_listen_tls_handshake_failure() {
+        let addr = "127.0.0.1:7110";
+        let cert_path = format!("{}/tests/keys/server.crt", env!("CARGO_MANIFEST_DIR"));
+        let key_path = format!("{}/tests/keys/key.pem", env!("CARGO_MANIFEST_DIR"));
+        let mut listeners = Listeners::tls(addr, &cert_path, &key_path).unwrap();
+        let (_, stats) = listeners.listener_stats().pop().unwrap();
+        let mut listener = listeners.build(None).pop().unwrap();
+
+        let server = tokio::spawn(async move {
+            listener.listen().await.unwrap();
+            let stream = listener.accept().await.unwrap();
+            assert!(stream.handshake().await.is_err());
+        });
+        // make sure the above starts before the lines below
+        sleep(Duration::from_millis(10)).await;
+
+        // not a TLS client hello
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: pingora.org\r\n\r\n")
+            .await
+            .unwrap();
+        server.await.unwrap();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.accepted, 0);
+        assert_eq!(snapshot.rejected, 1);
+    }
 }
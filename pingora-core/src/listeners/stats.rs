@@ -0,0 +1,107 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The counters of the listening endpoints
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// The counters of a listening endpoint
+///
+/// The counters are maintained by the listening service accepting on the endpoint and by the
+/// HTTP sessions of the connections it accepts, so that a metrics exporter can read them via
+/// [Self::snapshot()]. Get them from
+/// [Service::listener_stats()](crate::services::listening::Service::listener_stats).
+#[derive(Debug, Default)]
+pub struct ListenerStats {
+    accepted: AtomicU64,
+    active: AtomicUsize,
+    rejected: AtomicU64,
+    overload_pauses: AtomicU64,
+    h1_requests: AtomicU64,
+    h2_requests: AtomicU64,
+}
+
+/// The values of [ListenerStats] at a point in time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ListenerStatsSnapshot {
+    /// The number of the connections established, including their TLS handshake if any, not
+    /// including the rejected ones
+    pub accepted: u64,
+    /// The number of the connections that are still open, including the ones still handshaking
+    pub active: usize,
+    /// The number of the connections closed before being established, because the
+    /// [IpAccessList](super::IpAccessList) of the endpoint denied them or because their TLS
+    /// handshake failed
+    pub rejected: u64,
+    /// The number of times this endpoint paused accepting because its service was overloaded,
+    /// see [AcceptBackpressure](crate::services::listening::AcceptBackpressure). The connections
+    /// waiting in the listen backlog meanwhile are only counted once they are accepted.
+    pub overload_pauses: u64,
+    /// The number of the HTTP/1.x requests read from the accepted connections
+    pub h1_requests: u64,
+    /// The number of the HTTP/2 streams accepted on the accepted connections
+    pub h2_requests: u64,
+}
+
+impl ListenerStatsSnapshot {
+    /// The number of the requests of all the HTTP versions
+    pub fn requests(&self) -> u64 {
+        self.h1_requests + self.h2_requests
+    }
+}
+
+impl ListenerStats {
+    /// Read all the counters
+    ///
+    /// The counters are read one by one, so they may be slightly inconsistent with each other
+    /// while the endpoint is busy.
+    pub fn snapshot(&self) -> ListenerStatsSnapshot {
+        ListenerStatsSnapshot {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            overload_pauses: self.overload_pauses.load(Ordering::Relaxed),
+            h1_requests: self.h1_requests.load(Ordering::Relaxed),
+            h2_requests: self.h2_requests.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn record_accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_overload_pause(&self) {
+        self.overload_pauses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_h1_request(&self) {
+        self.h1_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_h2_request(&self) {
+        self.h2_requests.fetch_add(1, Ordering::Relaxed);
+    }
+}
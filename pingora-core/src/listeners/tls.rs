@@ -13,14 +13,15 @@
 // limitations under the License.
 
 use log::debug;
-use pingora_error::{ErrorType, OrErr, Result};
+use pingora_error::{Error, ErrorType, OrErr, Result};
 use std::ops::{Deref, DerefMut};
 
 use crate::protocols::ssl::{
-    server::{handshake, handshake_with_callback, TlsAcceptCallbacks},
+    server::{handshake, handshake_with_callback, handshake_with_early_data, TlsAcceptCallbacks},
     SslStream,
 };
 use crate::protocols::IO;
+use crate::tls::ext;
 use crate::tls::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
 
 pub use crate::protocols::ssl::ALPN;
@@ -30,12 +31,14 @@ pub const TLS_CONF_ERR: ErrorType = ErrorType::Custom("TLSConfigError");
 pub(crate) struct Acceptor {
     ssl_acceptor: SslAcceptor,
     callbacks: Option<TlsAcceptCallbacks>,
+    early_data: bool,
 }
 
 /// The TLS settings of a listening endpoint
 pub struct TlsSettings {
     accept_builder: SslAcceptorBuilder,
     callbacks: Option<TlsAcceptCallbacks>,
+    early_data: bool,
 }
 
 impl Deref for TlsSettings {
@@ -70,6 +73,7 @@ impl TlsSettings {
         Ok(TlsSettings {
             accept_builder,
             callbacks: None,
+            early_data: false,
         })
     }
 
@@ -83,6 +87,7 @@ impl TlsSettings {
         Ok(TlsSettings {
             accept_builder,
             callbacks: Some(callbacks),
+            early_data: false,
         })
     }
 
@@ -103,10 +108,51 @@ impl TlsSettings {
         }
     }
 
+    /// Set an ordered list of ALPN protocols this endpoint accepts, most preferred first.
+    ///
+    /// Unlike [`Self::set_alpn()`], the protocols are not limited to HTTP. The first protocol in
+    /// `protocols` that the client also offers is selected. If the client offers ALPN but none of
+    /// its protocols is in the list, the handshake fails with a `no_application_protocol` alert.
+    ///
+    /// The negotiated protocol is available via [`crate::protocols::Ssl::negotiated_alpn()`] on
+    /// the accepted stream.
+    pub fn set_alpn_protocols(&mut self, protocols: &[&str]) -> Result<()> {
+        let wire = alpn::encode_protocols(protocols)?;
+        self.accept_builder
+            .set_alpn_select_callback(move |_ssl, alpn_in| alpn::select_custom(&wire, alpn_in));
+        Ok(())
+    }
+
+    /// Accept TLS 1.3 early data (0-RTT) of up to `bytes` bytes per connection, which is default
+    /// off. 0 turns it off again.
+    ///
+    /// Early data lets a resuming client send its first request along with the handshake, saving
+    /// a round trip. But unlike the rest of the connection, early data has no protection against
+    /// replay: an attacker who captured it can send it again, and the request is then handled
+    /// more than once. The TLS library only rejects the replays it can detect within this
+    /// process, so the application must only act on the requests that are safe to replay, e.g.,
+    /// idempotent ones, when [`crate::protocols::http::ServerSession::is_early_data()`] is true.
+    ///
+    /// Not supported with [`TlsAcceptCallbacks`]. With BoringSSL, the size limit is fixed to 14336
+    /// bytes whenever `bytes` is not 0.
+    pub fn set_max_early_data(&mut self, bytes: u32) -> Result<()> {
+        if self.callbacks.is_some() && bytes > 0 {
+            return Error::e_explain(
+                TLS_CONF_ERR,
+                "early data is not supported with TlsAcceptCallbacks",
+            );
+        }
+        ext::ssl_ctx_set_max_early_data(&mut self.accept_builder, bytes)
+            .or_err(TLS_CONF_ERR, "fail to set max early data")?;
+        self.early_data = bytes > 0;
+        Ok(())
+    }
+
     pub(crate) fn build(self) -> Acceptor {
         Acceptor {
             ssl_acceptor: self.accept_builder.build(),
             callbacks: self.callbacks,
+            early_data: self.early_data,
         }
     }
 }
@@ -117,6 +163,8 @@ impl Acceptor {
         // TODO: be able to offload this handshake in a thread pool
         if let Some(cb) = self.callbacks.as_ref() {
             handshake_with_callback(&self.ssl_acceptor, stream, cb).await
+        } else if self.early_data {
+            handshake_with_early_data(&self.ssl_acceptor, stream).await
         } else {
             handshake(&self.ssl_acceptor, stream).await
         }
@@ -126,6 +174,30 @@ impl Acceptor {
 mod alpn {
     use super::*;
     use crate::tls::ssl::{select_next_proto, AlpnError, SslRef};
+    use pingora_error::Error;
+
+    // encode to the "vector of nonempty, 8-bit length-prefixed, byte strings" wire format
+    pub fn encode_protocols(protocols: &[&str]) -> Result<Vec<u8>> {
+        if protocols.is_empty() {
+            return Error::e_explain(TLS_CONF_ERR, "empty ALPN protocol list");
+        }
+        let mut wire = Vec::with_capacity(protocols.iter().map(|p| p.len() + 1).sum());
+        for proto in protocols {
+            if proto.is_empty() || proto.len() > u8::MAX as usize {
+                return Error::e_explain(TLS_CONF_ERR, format!("invalid ALPN protocol: {proto:?}"));
+            }
+            wire.push(proto.len() as u8);
+            wire.extend_from_slice(proto.as_bytes());
+        }
+        Ok(wire)
+    }
+
+    pub fn select_custom<'a>(preference: &[u8], alpn_in: &'a [u8]) -> Result<&'a [u8], AlpnError> {
+        match select_next_proto(preference, alpn_in) {
+            Some(p) => Ok(p),
+            _ => Err(AlpnError::ALERT_FATAL), // cannot agree
+        }
+    }
 
     // A standard implementation provided by the SSL lib is used below
 
@@ -150,3 +222,131 @@ mod alpn {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocols::Ssl;
+    use crate::tls::ssl;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_encode_alpn_protocols() {
+        assert_eq!(
+            alpn::encode_protocols(&["h2", "http/1.1"]).unwrap(),
+            b"\x02h2\x08http/1.1"
+        );
+        assert!(alpn::encode_protocols(&[]).is_err());
+        assert!(alpn::encode_protocols(&["h2", ""]).is_err());
+        let too_long = "a".repeat(256);
+        assert!(alpn::encode_protocols(&[too_long.as_str()]).is_err());
+    }
+
+    async fn negotiate(server_protos: &[&str], client_protos: &'static [u8]) -> Result<Vec<u8>> {
+        let cert_path = format!("{}/tests/keys/server.crt", env!("CARGO_MANIFEST_DIR"));
+        let key_path = format!("{}/tests/keys/key.pem", env!("CARGO_MANIFEST_DIR"));
+        let mut settings = TlsSettings::intermediate(&cert_path, &key_path).unwrap();
+        settings.set_alpn_protocols(server_protos).unwrap();
+        let acceptor = settings.build();
+
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut ctx = ssl::SslContext::builder(ssl::SslMethod::tls()).unwrap();
+            ctx.set_alpn_protos(client_protos).unwrap();
+            let mut ssl = ssl::Ssl::new(&ctx.build()).unwrap();
+            ssl.set_verify(ssl::SslVerifyMode::NONE);
+            let mut stream = SslStream::new(ssl, client).unwrap();
+            if stream.connect().await.is_ok() {
+                let mut buf = [0; 1];
+                let _ = stream.read(&mut buf).await;
+            }
+        });
+
+        let stream = acceptor.tls_handshake(server).await?;
+        Ok(stream.negotiated_alpn().unwrap_or_default().to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_custom_alpn_server_preference() {
+        let selected = negotiate(&["custom/1", "http/1.1"], b"\x08http/1.1\x08custom/1")
+            .await
+            .unwrap();
+        assert_eq!(selected, b"custom/1");
+    }
+
+    #[tokio::test]
+    async fn test_custom_alpn_no_overlap() {
+        assert!(negotiate(&["custom/1"], b"\x02h2\x08http/1.1")
+            .await
+            .is_err());
+    }
+
+    #[cfg(not(feature = "boringssl"))]
+    #[tokio::test]
+    async fn test_early_data() {
+        use crate::tls::tokio_ssl::SslStream as ClientStream;
+        use std::pin::Pin;
+        use tokio::io::AsyncWriteExt;
+
+        let cert_path = format!("{}/tests/keys/server.crt", env!("CARGO_MANIFEST_DIR"));
+        let key_path = format!("{}/tests/keys/key.pem", env!("CARGO_MANIFEST_DIR"));
+        let mut settings = TlsSettings::intermediate(&cert_path, &key_path).unwrap();
+        settings.set_max_early_data(16384).unwrap();
+        let acceptor = settings.build();
+
+        let mut ctx = ssl::SslContext::builder(ssl::SslMethod::tls()).unwrap();
+        ctx.set_verify(ssl::SslVerifyMode::NONE);
+        let ctx = ctx.build();
+
+        // the first connection gets the session ticket to resume
+        let (client, server) = tokio::io::duplex(16384);
+        let client_ctx = ctx.clone();
+        let client_task = tokio::spawn(async move {
+            let ssl = ssl::Ssl::new(&client_ctx).unwrap();
+            let mut stream = ClientStream::new(ssl, client).unwrap();
+            Pin::new(&mut stream).connect().await.unwrap();
+            let mut buf = [0; 1];
+            stream.read_exact(&mut buf).await.unwrap();
+            // the session is not resumable unless the connection is shut down cleanly
+            stream.shutdown().await.unwrap();
+            stream.ssl().session().unwrap().to_owned()
+        });
+        let mut stream = acceptor.tls_handshake(server).await.unwrap();
+        assert!(!stream.ssl_digest().unwrap().early_data);
+        stream.write_all(b"a").await.unwrap();
+        let session = client_task.await.unwrap();
+        assert_eq!(session.max_early_data(), 16384);
+
+        // the second one sends the request along with the handshake
+        let request = b"GET / HTTP/1.1\r\n\r\n";
+        let (client, server) = tokio::io::duplex(16384);
+        tokio::spawn(async move {
+            let mut ssl = ssl::Ssl::new(&ctx).unwrap();
+            unsafe { ssl.set_session(&session).unwrap() };
+            ssl.set_connect_state();
+            let mut stream = ClientStream::new(ssl, client).unwrap();
+            Pin::new(&mut stream)
+                .write_early_data(request)
+                .await
+                .unwrap();
+            Pin::new(&mut stream).connect().await.unwrap();
+            let mut buf = [0; 1];
+            let _ = stream.read(&mut buf).await;
+        });
+        let mut stream = acceptor.tls_handshake(server).await.unwrap();
+        assert!(stream.ssl_digest().unwrap().early_data);
+        let mut buf = [0; 18];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, request);
+    }
+
+    #[test]
+    fn test_early_data_with_callbacks() {
+        struct Callbacks;
+        #[async_trait::async_trait]
+        impl crate::protocols::ssl::server::TlsAccept for Callbacks {}
+        let mut settings = TlsSettings::with_callbacks(Box::new(Callbacks)).unwrap();
+        assert!(settings.set_max_early_data(16384).is_err());
+        assert!(settings.set_max_early_data(0).is_ok());
+    }
+}
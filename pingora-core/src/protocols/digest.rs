@@ -14,14 +14,17 @@
 
 //! Extra information about the connection
 
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use once_cell::sync::OnceCell;
 
+use super::l4::ext::{get_original_dst, get_tcp_info, TCP_INFO};
 use super::l4::socket::SocketAddr;
 use super::raw_connect::ProxyDigest;
 use super::ssl::digest::SslDigest;
+use crate::listeners::ListenerStats;
 
 /// The information can be extracted from a connection
 #[derive(Clone, Debug, Default)]
@@ -66,6 +69,12 @@ pub struct SocketDigest {
     pub peer_addr: OnceCell<Option<SocketAddr>>,
     /// Local socket address
     pub local_addr: OnceCell<Option<SocketAddr>>,
+    /// Original destination address of an intercepted connection
+    pub original_dst: OnceCell<Option<SocketAddr>>,
+    // the counters of the listening endpoint that accepted this connection
+    listener_stats: OnceCell<Arc<ListenerStats>>,
+    // the number of HTTP requests carried by this connection so far
+    requests: AtomicUsize,
 }
 
 impl SocketDigest {
@@ -74,6 +83,9 @@ impl SocketDigest {
             raw_fd,
             peer_addr: OnceCell::new(),
             local_addr: OnceCell::new(),
+            original_dst: OnceCell::new(),
+            listener_stats: OnceCell::new(),
+            requests: AtomicUsize::new(0),
         }
     }
 
@@ -88,6 +100,59 @@ impl SocketDigest {
             .get_or_init(|| SocketAddr::from_raw_fd(self.raw_fd, false))
             .as_ref()
     }
+
+    /// Return the address the client originally connected to before the connection was
+    /// intercepted by an iptables `REDIRECT` or `TPROXY` rule, which is the local address if the
+    /// connection was not redirected
+    ///
+    /// `None` if the socket is not TCP or the `transparent_proxy` feature is not enabled on Linux.
+    pub fn original_dst(&self) -> Option<&SocketAddr> {
+        self.original_dst
+            .get_or_init(|| {
+                // not a TCP socket
+                self.peer_addr()?.as_inet()?;
+                get_original_dst(self.raw_fd).ok().map(SocketAddr::Inet)
+            })
+            .as_ref()
+    }
+
+    /// Return the current kernel `TCP_INFO` of this socket
+    ///
+    /// `None` if the socket is not TCP or the info is not available on this platform.
+    pub fn tcp_info(&self) -> Option<TCP_INFO> {
+        // not a TCP socket
+        self.peer_addr()?.as_inet()?;
+        get_tcp_info(self.raw_fd).ok()
+    }
+
+    /// Count one more HTTP request carried by this connection, return the number of the requests
+    /// so far including this one
+    pub fn count_request(&self) -> usize {
+        self.requests.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// The [ListenerStats] of the listening endpoint this connection was accepted on
+    ///
+    /// `None` for the upstream connections and the ones not accepted by a listening service.
+    pub fn listener_stats(&self) -> Option<&Arc<ListenerStats>> {
+        self.listener_stats.get()
+    }
+
+    pub(crate) fn set_listener_stats(&self, stats: Arc<ListenerStats>) {
+        let _ = self.listener_stats.set(stats);
+    }
+
+    /// The number of the HTTP requests carried by this connection so far
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Return the current smoothed round trip time of this TCP connection measured by the kernel
+    pub fn rtt(&self) -> Option<Duration> {
+        self.tcp_info()
+            .filter(|info| info.tcpi_rtt > 0) // zeroed out when not available
+            .map(|info| Duration::from_micros(info.tcpi_rtt as u64))
+    }
 }
 
 /// The interface to return timing information
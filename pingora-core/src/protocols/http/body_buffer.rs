@@ -36,7 +36,8 @@ impl FixedBuffer {
         if !self.truncated && (self.buffer.len() + data.len() <= self.capacity) {
             self.buffer.extend_from_slice(data);
         } else {
-            // TODO: clear data because the data held here is useless anyway?
+            // the data held here is useless once truncated, release it
+            self.buffer = BytesMut::new();
             self.truncated = true;
         }
     }
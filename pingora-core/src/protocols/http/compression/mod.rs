@@ -14,7 +14,7 @@
 
 //! HTTP response (de)compression libraries
 //!
-//! Brotli and Gzip and partially supported.
+//! Brotli and Gzip and partially supported. Brotli requires the `brotli` feature.
 
 use super::HttpTask;
 
@@ -22,8 +22,10 @@ use bytes::Bytes;
 use log::warn;
 use pingora_error::{ErrorType, Result};
 use pingora_http::{RequestHeader, ResponseHeader};
+use std::sync::Arc;
 use std::time::Duration;
 
+#[cfg(feature = "brotli")]
 mod brotli;
 mod gzip;
 mod zstd;
@@ -43,6 +45,79 @@ pub trait Encode {
     fn stat(&self) -> (&'static str, usize, usize, Duration);
 }
 
+/// The default minimum size of the response body to compress
+///
+/// Too short bodies have little redundancy to compress while paying the overhead of the header
+/// and footer of the encoding. The latency is the same anyway as long as the data fits in a TCP
+/// congestion window.
+pub const DEFAULT_MIN_COMPRESS_LEN: usize = 20;
+
+/// The configuration of [`ResponseCompressionCtx`], usually shared by all the sessions of a
+/// service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// The compression level, `0` disables compression
+    pub level: u32,
+    /// Whether to decompress the responses in the encodings that the client doesn't accept
+    pub decompress: bool,
+    /// The responses whose `Content-Length` is smaller than this are not compressed. The
+    /// responses without `Content-Length` are always eligible.
+    pub min_size: usize,
+    /// The prefixes of the compressible `Content-Type`s, e.g., `text/` or `application/json`.
+    /// `None` to use the built-in list.
+    pub content_types: Option<Vec<String>>,
+}
+
+static DEFAULT_CONFIG: CompressionConfig = CompressionConfig {
+    level: 0,
+    decompress: false,
+    min_size: DEFAULT_MIN_COMPRESS_LEN,
+    content_types: None,
+};
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        DEFAULT_CONFIG.clone()
+    }
+}
+
+impl CompressionConfig {
+    /// Create a new [`CompressionConfig`] that compresses eligible responses with the given level
+    pub fn new(level: u32) -> Self {
+        CompressionConfig {
+            level,
+            ..Default::default()
+        }
+    }
+
+    // check if the response is eligible for compression by its size and mime type
+    fn compressible(&self, resp: &ResponseHeader) -> bool {
+        // check if response is too small to compress
+        if let Some(cl) = resp.headers.get(http::header::CONTENT_LENGTH) {
+            if let Some(cl_num) = std::str::from_utf8(cl.as_bytes())
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+            {
+                if cl_num < self.min_size {
+                    return false;
+                }
+            }
+        }
+        let Some(types) = self.content_types.as_ref() else {
+            return compressible_type(resp);
+        };
+        resp.headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|ct| std::str::from_utf8(ct.as_bytes()).ok())
+            .is_some_and(|ct| {
+                types.iter().any(|t| {
+                    ct.len() >= t.len()
+                        && ct.as_bytes()[..t.len()].eq_ignore_ascii_case(t.as_bytes())
+                })
+            })
+    }
+}
+
 /// The response compression object. Currently support gzip compression and brotli decompression.
 ///
 /// To use it, the caller should create a [`ResponseCompressionCtx`] per HTTP session.
@@ -67,6 +142,8 @@ enum CtxInner {
         decompress_enable: bool,
         // Store the preferred list to compare with content-encoding
         accept_encoding: Vec<Algorithm>,
+        // the eligibility of the responses to compress, the default if None
+        config: Option<Arc<CompressionConfig>>,
     },
     BodyPhase(Option<Box<dyn Encode + Send + Sync>>),
 }
@@ -80,6 +157,20 @@ impl ResponseCompressionCtx {
             compression_level,
             decompress_enable,
             accept_encoding: Vec::new(),
+            config: None,
+        })
+    }
+
+    /// Create a new [`ResponseCompressionCtx`] from the given config.
+    ///
+    /// The level and the decompression flag can still be adjusted per session via
+    /// [`Self::adjust_level()`] and [`Self::adjust_decompression()`].
+    pub fn with_config(config: Arc<CompressionConfig>) -> Self {
+        Self(CtxInner::HeaderPhase {
+            compression_level: config.level,
+            decompress_enable: config.decompress,
+            accept_encoding: Vec::new(),
+            config: Some(config),
         })
     }
 
@@ -91,6 +182,7 @@ impl ResponseCompressionCtx {
                 compression_level,
                 decompress_enable,
                 accept_encoding: _,
+                config: _,
             } => *compression_level != 0 || *decompress_enable,
             CtxInner::BodyPhase(c) => c.is_some(),
         }
@@ -104,6 +196,7 @@ impl ResponseCompressionCtx {
                 compression_level: _,
                 decompress_enable: _,
                 accept_encoding: _,
+                config: _,
             } => None,
             CtxInner::BodyPhase(c) => c.as_ref().map(|c| c.stat()),
         }
@@ -118,6 +211,7 @@ impl ResponseCompressionCtx {
                 compression_level,
                 decompress_enable: _,
                 accept_encoding: _,
+                config: _,
             } => {
                 *compression_level = new_level;
             }
@@ -134,6 +228,7 @@ impl ResponseCompressionCtx {
                 compression_level: _,
                 decompress_enable,
                 accept_encoding: _,
+                config: _,
             } => {
                 *decompress_enable = enabled;
             }
@@ -151,6 +246,7 @@ impl ResponseCompressionCtx {
                 compression_level: _,
                 decompress_enable: _,
                 accept_encoding,
+                config: _,
             } => parse_accept_encoding(
                 req.headers.get(http::header::ACCEPT_ENCODING),
                 accept_encoding,
@@ -165,6 +261,7 @@ impl ResponseCompressionCtx {
                 compression_level,
                 decompress_enable,
                 accept_encoding,
+                config,
             } => {
                 if resp.status.is_informational() {
                     if resp.status == http::status::StatusCode::SWITCHING_PROTOCOLS {
@@ -180,7 +277,8 @@ impl ResponseCompressionCtx {
                     return;
                 }
 
-                let action = decide_action(resp, accept_encoding);
+                let config = config.as_deref().unwrap_or(&DEFAULT_CONFIG);
+                let action = decide_action(resp, accept_encoding, config);
                 let encoder = match action {
                     Action::Noop => None,
                     Action::Compress(algorithm) => algorithm.compressor(*compression_level),
@@ -201,6 +299,7 @@ impl ResponseCompressionCtx {
                 compression_level: _,
                 decompress_enable: _,
                 accept_encoding: _,
+                config: _,
             } => panic!("Wrong phase: HeaderPhase"),
             CtxInner::BodyPhase(compressor) => {
                 let result = compressor
@@ -277,6 +376,7 @@ impl Algorithm {
         } else {
             match self {
                 Self::Gzip => Some(Box::new(gzip::Compressor::new(level))),
+                #[cfg(feature = "brotli")]
                 Self::Brotli => Some(Box::new(brotli::Compressor::new(level))),
                 Self::Zstd => Some(Box::new(zstd::Compressor::new(level))),
                 _ => None, // not implemented
@@ -284,11 +384,18 @@ impl Algorithm {
         }
     }
 
+    // whether this algorithm is available to compress
+    fn can_compress(&self) -> bool {
+        matches!(self, Self::Gzip | Self::Zstd)
+            || (cfg!(feature = "brotli") && *self == Self::Brotli)
+    }
+
     pub fn decompressor(&self, enabled: bool) -> Option<Box<dyn Encode + Send + Sync>> {
         if !enabled {
             None
         } else {
             match self {
+                #[cfg(feature = "brotli")]
                 Self::Brotli => Some(Box::new(brotli::Decompressor::new())),
                 _ => None, // not implemented
             }
@@ -388,7 +495,11 @@ fn test_accept_encoding_req_header() {
 }
 
 // filter response header to see if (de)compression is needed
-fn decide_action(resp: &ResponseHeader, accept_encoding: &[Algorithm]) -> Action {
+fn decide_action(
+    resp: &ResponseHeader,
+    accept_encoding: &[Algorithm],
+    config: &CompressionConfig,
+) -> Action {
     use http::header::CONTENT_ENCODING;
 
     let content_encoding = if let Some(ce) = resp.headers.get(CONTENT_ENCODING) {
@@ -417,14 +528,17 @@ fn decide_action(resp: &ResponseHeader, accept_encoding: &[Algorithm]) -> Action
             Action::Decompress(ce)
         }
     } else if accept_encoding.is_empty() // both CE and AE are empty
-        || !compressible(resp) // the type is not compressible
+        || !config.compressible(resp) // the type or size is not compressible
         || accept_encoding[0] == Algorithm::Any
     {
         Action::Noop
     } else {
-        // try to compress with the first AC
+        // try to compress with the first AC that is available
         // TODO: support to configure preferred encoding
-        Action::Compress(accept_encoding[0])
+        accept_encoding
+            .iter()
+            .find(|a| a.can_compress())
+            .map_or(Action::Noop, |a| Action::Compress(*a))
     }
 }
 
@@ -433,34 +547,35 @@ fn test_decide_action() {
     use Action::*;
     use Algorithm::*;
 
+    let config = CompressionConfig::default();
     let header = ResponseHeader::build(200, None).unwrap();
     // no compression asked, no compression needed
-    assert_eq!(decide_action(&header, &[]), Noop);
+    assert_eq!(decide_action(&header, &[], &config), Noop);
 
     // already gzip, no compression needed
     let mut header = ResponseHeader::build(200, None).unwrap();
     header.insert_header("content-type", "text/html").unwrap();
     header.insert_header("content-encoding", "gzip").unwrap();
-    assert_eq!(decide_action(&header, &[Gzip]), Noop);
+    assert_eq!(decide_action(&header, &[Gzip], &config), Noop);
 
     // already gzip, no compression needed, upper case
     let mut header = ResponseHeader::build(200, None).unwrap();
     header.insert_header("content-encoding", "GzIp").unwrap();
     header.insert_header("content-type", "text/html").unwrap();
-    assert_eq!(decide_action(&header, &[Gzip]), Noop);
+    assert_eq!(decide_action(&header, &[Gzip], &config), Noop);
 
     // no encoding, compression needed, accepted content-type, large enough
     // Will compress
     let mut header = ResponseHeader::build(200, None).unwrap();
     header.insert_header("content-length", "20").unwrap();
     header.insert_header("content-type", "text/html").unwrap();
-    assert_eq!(decide_action(&header, &[Gzip]), Compress(Gzip));
+    assert_eq!(decide_action(&header, &[Gzip], &config), Compress(Gzip));
 
     // too small
     let mut header = ResponseHeader::build(200, None).unwrap();
     header.insert_header("content-length", "19").unwrap();
     header.insert_header("content-type", "text/html").unwrap();
-    assert_eq!(decide_action(&header, &[Gzip]), Noop);
+    assert_eq!(decide_action(&header, &[Gzip], &config), Noop);
 
     // already compressed MIME
     let mut header = ResponseHeader::build(200, None).unwrap();
@@ -468,28 +583,82 @@ fn test_decide_action() {
     header
         .insert_header("content-type", "text/html+zip")
         .unwrap();
-    assert_eq!(decide_action(&header, &[Gzip]), Noop);
+    assert_eq!(decide_action(&header, &[Gzip], &config), Noop);
 
     // unsupported MIME
     let mut header = ResponseHeader::build(200, None).unwrap();
     header.insert_header("content-length", "20").unwrap();
     header.insert_header("content-type", "image/jpg").unwrap();
-    assert_eq!(decide_action(&header, &[Gzip]), Noop);
+    assert_eq!(decide_action(&header, &[Gzip], &config), Noop);
 
     // compressed, need decompress
     let mut header = ResponseHeader::build(200, None).unwrap();
     header.insert_header("content-encoding", "gzip").unwrap();
-    assert_eq!(decide_action(&header, &[]), Decompress(Gzip));
+    assert_eq!(decide_action(&header, &[], &config), Decompress(Gzip));
 
     // accept-encoding different, need decompress
     let mut header = ResponseHeader::build(200, None).unwrap();
     header.insert_header("content-encoding", "gzip").unwrap();
-    assert_eq!(decide_action(&header, &[Brotli]), Decompress(Gzip));
+    assert_eq!(decide_action(&header, &[Brotli], &config), Decompress(Gzip));
 
     // less preferred but no need to decompress
     let mut header = ResponseHeader::build(200, None).unwrap();
     header.insert_header("content-encoding", "gzip").unwrap();
-    assert_eq!(decide_action(&header, &[Brotli, Gzip]), Noop);
+    assert_eq!(decide_action(&header, &[Brotli, Gzip], &config), Noop);
+
+    // skip the unavailable algorithms
+    let mut header = ResponseHeader::build(200, None).unwrap();
+    header.insert_header("content-type", "text/html").unwrap();
+    let preferred = if cfg!(feature = "brotli") {
+        Brotli
+    } else {
+        Gzip
+    };
+    assert_eq!(
+        decide_action(&header, &[Brotli, Gzip], &config),
+        Compress(preferred)
+    );
+}
+
+#[test]
+fn test_compression_config() {
+    use Action::*;
+    use Algorithm::*;
+
+    let config = CompressionConfig {
+        min_size: 1000,
+        content_types: Some(vec!["text/".into(), "application/json".into()]),
+        ..CompressionConfig::new(6)
+    };
+
+    let mut header = ResponseHeader::build(200, None).unwrap();
+    header.insert_header("content-length", "1000").unwrap();
+    header.insert_header("content-type", "Text/HTML").unwrap();
+    assert_eq!(decide_action(&header, &[Gzip], &config), Compress(Gzip));
+
+    // too small
+    header.insert_header("content-length", "999").unwrap();
+    assert_eq!(decide_action(&header, &[Gzip], &config), Noop);
+
+    // no content-length is always eligible
+    header.remove_header("content-length");
+    header
+        .insert_header("content-type", "application/json; charset=utf-8")
+        .unwrap();
+    assert_eq!(decide_action(&header, &[Gzip], &config), Compress(Gzip));
+
+    // not in the list even though the default list has it
+    header.insert_header("content-type", "font/woff").unwrap();
+    assert_eq!(decide_action(&header, &[Gzip], &config), Noop);
+    assert_eq!(
+        decide_action(&header, &[Gzip], &CompressionConfig::default()),
+        Compress(Gzip)
+    );
+
+    // already encoded
+    header.insert_header("content-type", "text/html").unwrap();
+    header.insert_header("content-encoding", "gzip").unwrap();
+    assert_eq!(decide_action(&header, &[Gzip], &config), Noop);
 }
 
 use once_cell::sync::Lazy;
@@ -502,26 +671,8 @@ static MIME_CHECK: Lazy<Regex> = Lazy::new(|| {
         .unwrap()
 });
 
-// check if the response mime type is compressible
-fn compressible(resp: &ResponseHeader) -> bool {
-    // arbitrary size limit, things to consider
-    // 1. too short body may have little redundancy to compress
-    // 2. gzip header and footer overhead
-    // 3. latency is the same as long as data fits in a TCP congestion window regardless of size
-    const MIN_COMPRESS_LEN: usize = 20;
-
-    // check if response is too small to compress
-    if let Some(cl) = resp.headers.get(http::header::CONTENT_LENGTH) {
-        if let Some(cl_num) = std::str::from_utf8(cl.as_bytes())
-            .ok()
-            .and_then(|v| v.parse::<usize>().ok())
-        {
-            if cl_num < MIN_COMPRESS_LEN {
-                return false;
-            }
-        }
-    }
-    // no Content-Length or large enough, check content-type next
+// check if the response mime type is compressible with the built-in list
+fn compressible_type(resp: &ResponseHeader) -> bool {
     if let Some(ct) = resp.headers.get(http::header::CONTENT_TYPE) {
         if let Ok(ct_str) = std::str::from_utf8(ct.as_bytes()) {
             if ct_str.contains("zip") {
@@ -540,7 +691,7 @@ fn compressible(resp: &ResponseHeader) -> bool {
 }
 
 fn adjust_response_header(resp: &mut ResponseHeader, action: &Action) {
-    use http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING};
+    use http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING, VARY};
 
     fn set_stream_headers(resp: &mut ResponseHeader) {
         // because the transcoding is streamed, content length is not known ahead
@@ -548,6 +699,18 @@ fn adjust_response_header(resp: &mut ResponseHeader, action: &Action) {
         // we stream body now TODO: chunked is for h1 only
         resp.insert_header(&TRANSFER_ENCODING, HeaderValue::from_static("chunked"))
             .unwrap();
+        // the encoding now depends on the accept-encoding of the request
+        let varied = resp.headers.get_all(&VARY).iter().any(|v| {
+            v.to_str().is_ok_and(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .any(|f| f == "*" || f.eq_ignore_ascii_case("accept-encoding"))
+            })
+        });
+        if !varied {
+            resp.append_header(&VARY, HeaderValue::from_static("Accept-Encoding"))
+                .unwrap();
+        }
     }
 
     match action {
@@ -609,4 +772,23 @@ fn test_adjust_response_header() {
         header.headers.get("transfer-encoding").unwrap().as_bytes(),
         b"chunked"
     );
+    assert_eq!(
+        header.headers.get("vary").unwrap().as_bytes(),
+        b"Accept-Encoding"
+    );
+
+    // vary is kept
+    let mut header = ResponseHeader::build(200, None).unwrap();
+    header.insert_header("vary", "Origin").unwrap();
+    adjust_response_header(&mut header, &Compress(Gzip));
+    let vary: Vec<_> = header.headers.get_all("vary").iter().collect();
+    assert_eq!(vary, ["Origin", "Accept-Encoding"]);
+
+    // already varies on accept-encoding
+    let mut header = ResponseHeader::build(200, None).unwrap();
+    header
+        .insert_header("vary", "origin, accept-encoding")
+        .unwrap();
+    adjust_response_header(&mut header, &Compress(Gzip));
+    assert_eq!(header.headers.get_all("vary").iter().count(), 1);
 }
@@ -18,10 +18,11 @@ use super::error_resp;
 use super::v1::server::HttpSession as SessionV1;
 use super::v2::server::HttpSession as SessionV2;
 use super::HttpTask;
-use crate::protocols::{SocketAddr, Stream};
+use crate::protocols::ssl::SslDigest;
+use crate::protocols::{Digest, SocketAddr, Stream};
 use bytes::Bytes;
 use http::header::AsHeaderName;
-use http::HeaderValue;
+use http::{HeaderMap, HeaderValue};
 use log::error;
 use pingora_error::Result;
 use pingora_http::{RequestHeader, ResponseHeader};
@@ -106,6 +107,16 @@ impl Session {
         }
     }
 
+    /// Read the request trailers once the request body is done. Ok(None) if there are no trailers.
+    ///
+    /// For H1, only chunked request bodies can have trailers.
+    pub async fn read_request_trailers(&mut self) -> Result<Option<Box<HeaderMap>>> {
+        match self {
+            Self::H1(s) => Ok(s.take_request_trailers()),
+            Self::H2(s) => Ok(s.read_trailers().await?.map(Box::new)),
+        }
+    }
+
     /// Write the response header to client
     /// Informational headers (status code 100-199, excluding 101) can be written multiple times the final
     /// response header (status code 200+ or 101) is written.
@@ -274,6 +285,25 @@ impl Session {
         }
     }
 
+    /// Set the maximum size of the request body to keep in the retry buffer, 64KiB by default.
+    /// The retry buffer is truncated once the body grows beyond it.
+    ///
+    /// This should be called before [`Self::enable_retry_buffering()`] to take effect.
+    pub fn set_retry_buffer_limit(&mut self, limit: usize) {
+        match self {
+            Self::H1(s) => s.set_retry_buffer_limit(limit),
+            Self::H2(s) => s.set_retry_buffer_limit(limit),
+        }
+    }
+
+    /// The maximum size of the request body to keep in the retry buffer
+    pub fn retry_buffer_limit(&self) -> usize {
+        match self {
+            Self::H1(s) => s.retry_buffer_limit(),
+            Self::H2(s) => s.retry_buffer_limit(),
+        }
+    }
+
     pub fn get_retry_buffer(&self) -> Option<Bytes> {
         match self {
             Self::H1(s) => s.get_retry_buffer(),
@@ -290,6 +320,16 @@ impl Session {
         }
     }
 
+    /// Take over the underlying stream of an HTTP/1.x session, see [SessionV1::take_over()].
+    ///
+    /// Return `None` for HTTP/2 whose streams share the connection.
+    pub fn take_over(&mut self) -> Option<(&mut Stream, Bytes)> {
+        match self {
+            Self::H1(s) => Some(s.take_over()),
+            Self::H2(_) => None,
+        }
+    }
+
     pub fn as_http1(&self) -> Option<&SessionV1> {
         match self {
             Self::H1(s) => Some(s),
@@ -323,6 +363,15 @@ impl Session {
         }
     }
 
+    /// Whether the client accepts trailers in the response. HTTP/1.1 clients have to ask for them
+    /// with `TE: trailers` while HTTP/2 clients always accept them.
+    pub fn accepts_trailers(&self) -> bool {
+        match self {
+            Self::H1(s) => s.accepts_trailers(),
+            Self::H2(_) => true,
+        }
+    }
+
     /// How many response body bytes already sent
     pub fn body_bytes_sent(&self) -> usize {
         match self {
@@ -331,6 +380,47 @@ impl Session {
         }
     }
 
+    /// How many request body bytes already read
+    pub fn body_bytes_read(&self) -> usize {
+        match self {
+            Self::H1(s) => s.body_bytes_read(),
+            Self::H2(s) => s.body_bytes_read(),
+        }
+    }
+
+    /// Return the [Digest] of the connection, which includes its socket and TLS information
+    pub fn digest(&self) -> Option<&Digest> {
+        match self {
+            Self::H1(s) => Some(s.digest()),
+            Self::H2(s) => s.digest(),
+        }
+    }
+
+    /// Return the TLS information of the connection, including its negotiated version, cipher, SNI
+    /// and ALPN, or `None` if the connection is not TLS.
+    pub fn tls_info(&self) -> Option<&SslDigest> {
+        self.digest()?.ssl_digest.as_deref()
+    }
+
+    /// Whether this request may have arrived as TLS 1.3 early data (0-RTT), which can be replayed
+    ///
+    /// This is true for the HTTP/1.x requests read, at least partially, from the early data and
+    /// all the HTTP/2 requests of a connection whose early data was accepted, see
+    /// [`crate::listeners::TlsSettings::set_max_early_data()`]. Such requests should be rejected,
+    /// e.g., with `425 Too Early`, unless they are safe to replay.
+    pub fn is_early_data(&self) -> bool {
+        let Some(digest) = self.digest() else {
+            return false;
+        };
+        if !digest.ssl_digest.as_ref().is_some_and(|d| d.early_data) {
+            return false;
+        }
+        match self {
+            Self::H1(s) => s.is_early_data(),
+            Self::H2(_) => true,
+        }
+    }
+
     /// Return the client (peer) address of the connnection.
     pub fn client_addr(&self) -> Option<&SocketAddr> {
         match self {
@@ -346,4 +436,153 @@ impl Session {
             Self::H2(s) => s.server_addr(),
         }
     }
+
+    /// Return the address the client originally connected to if the connection was intercepted
+    /// by a transparent proxy setup, see
+    /// [SocketDigest::original_dst()](crate::protocols::SocketDigest::original_dst).
+    pub fn original_dst(&self) -> Option<&SocketAddr> {
+        match self {
+            Self::H1(s) => s.original_dst(),
+            Self::H2(s) => s.original_dst(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocols::{
+        GetProxyDigest, GetSocketDigest, GetTimingDigest, Shutdown, SocketDigest, Ssl, UniqueID,
+    };
+    use async_trait::async_trait;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio_test::io::{Builder, Mock};
+
+    // a TLS connection whose first `early_data` bytes read are the early data
+    #[derive(Debug)]
+    struct EarlyDataStream {
+        inner: Mock,
+        early_data: usize,
+        ssl_digest: Arc<SslDigest>,
+        socket_digest: Arc<SocketDigest>,
+    }
+
+    impl EarlyDataStream {
+        fn new(inner: Mock, early_data: usize) -> Self {
+            EarlyDataStream {
+                inner,
+                early_data,
+                ssl_digest: Arc::new(SslDigest {
+                    cipher: "",
+                    version: "TLSv1.3",
+                    sni: None,
+                    alpn: None,
+                    early_data: true,
+                    organization: None,
+                    serial_number: None,
+                    cert_digest: vec![],
+                }),
+                socket_digest: Arc::new(SocketDigest::from_raw_fd(0)),
+            }
+        }
+    }
+
+    impl AsyncRead for EarlyDataStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let filled = buf.filled().len();
+            let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+            let n = buf.filled().len() - filled;
+            self.early_data = self.early_data.saturating_sub(n);
+            res
+        }
+    }
+
+    // the responses are not checked
+    impl AsyncWrite for EarlyDataStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[async_trait]
+    impl Shutdown for EarlyDataStream {
+        async fn shutdown(&mut self) {}
+    }
+    impl UniqueID for EarlyDataStream {
+        fn id(&self) -> i32 {
+            0
+        }
+    }
+    impl Ssl for EarlyDataStream {
+        fn get_ssl_digest(&self) -> Option<Arc<SslDigest>> {
+            Some(self.ssl_digest.clone())
+        }
+
+        fn early_data_pending(&self) -> bool {
+            self.early_data > 0
+        }
+    }
+    impl GetTimingDigest for EarlyDataStream {
+        fn get_timing_digest(&self) -> Vec<Option<crate::protocols::TimingDigest>> {
+            vec![]
+        }
+    }
+    impl GetProxyDigest for EarlyDataStream {
+        fn get_proxy_digest(&self) -> Option<Arc<crate::protocols::raw_connect::ProxyDigest>> {
+            None
+        }
+    }
+    impl GetSocketDigest for EarlyDataStream {
+        fn get_socket_digest(&self) -> Option<Arc<SocketDigest>> {
+            Some(self.socket_digest.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_early_data_pipelined_h1() {
+        let request1 = b"GET /1 HTTP/1.1\r\nHost: pingora.org\r\n\r\n";
+        let request2 = b"GET /2 HTTP/1.1\r\nHost: pingora.org\r\n\r\n";
+        let request3 = b"GET /3 HTTP/1.1\r\nHost: pingora.org\r\n\r\n";
+        // the first two requests are pipelined in the early data
+        let mock_io = Builder::new()
+            .read(&request1[..])
+            .read(&request2[..])
+            .read(&request3[..])
+            .build();
+        let mut stream: Stream = Box::new(EarlyDataStream::new(
+            mock_io,
+            request1.len() + request2.len(),
+        ));
+
+        for (path, early_data) in [("/1", true), ("/2", true), ("/3", false)] {
+            let mut session = Session::new_http1(stream);
+            assert!(session.read_request().await.unwrap());
+            assert_eq!(session.req_header().uri.path(), path);
+            assert_eq!(session.is_early_data(), early_data);
+
+            let mut resp = ResponseHeader::build(200, None).unwrap();
+            resp.insert_header("Content-Length", "0").unwrap();
+            session.write_response_header(Box::new(resp)).await.unwrap();
+            stream = session.finish().await.unwrap().unwrap();
+        }
+    }
 }
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::header::{HeaderMap, HeaderName, HeaderValue};
 use log::{debug, trace, warn};
 use pingora_error::{
     Error,
@@ -31,6 +32,10 @@ const BODY_BUFFER_SIZE: usize = 1024 * 64;
 const PARTIAL_CHUNK_HEAD_LIMIT: usize = 1024 * 8;
 
 const LAST_CHUNK: &[u8; 5] = b"0\r\n\r\n";
+// the max number of trailer fields to parse
+const MAX_TRAILERS: usize = 64;
+// the max size of the trailer section
+const TRAILERS_SIZE_LIMIT: usize = 1024 * 8;
 
 pub const INVALID_CHUNK: ErrorType = ErrorType::new("InvalidChunk");
 pub const PREMATURE_BODY_END: ErrorType = ErrorType::new("PrematureBodyEnd");
@@ -103,6 +108,10 @@ pub struct BodyReader {
     pub body_buf: Option<BytesMut>,
     pub body_buf_size: usize,
     rewind_buf_len: usize,
+    // the trailers after the terminating chunk, if any
+    trailers: Option<Box<HeaderMap>>,
+    // the incomplete trailer section to read the rest of
+    trailer_buf: Option<BytesMut>,
 }
 
 impl BodyReader {
@@ -112,6 +121,8 @@ impl BodyReader {
             body_buf: None,
             body_buf_size: BODY_BUFFER_SIZE,
             rewind_buf_len: 0,
+            trailers: None,
+            trailer_buf: None,
         }
     }
 
@@ -121,6 +132,8 @@ impl BodyReader {
 
     pub fn reinit(&mut self) {
         self.body_state = PS::ToStart;
+        self.trailers = None;
+        self.trailer_buf = None;
     }
 
     fn prepare_buf(&mut self, buf_to_rewind: &[u8]) {
@@ -172,6 +185,11 @@ impl BodyReader {
         self.body_state == PS::Complete(0)
     }
 
+    /// Take the trailers of a chunked body, available once the body is done
+    pub fn take_trailers(&mut self) -> Option<Box<HeaderMap>> {
+        self.trailers.take()
+    }
+
     pub async fn read_body<S>(&mut self, stream: &mut S) -> Result<Option<BufRef>>
     where
         S: AsyncRead + Unpin + Send,
@@ -348,13 +366,55 @@ impl BodyReader {
                             self.body_state.multi_chunk(payload_size, expecting_from_io);
                         return Ok(Some(BufRef::new(0, payload_size)));
                     }
-                    self.parse_chunked_buf(existing_buf_start, existing_buf_end)
+                    let res = self.parse_chunked_buf(existing_buf_start, existing_buf_end);
+                    if matches!(res, Ok(None)) {
+                        self.read_trailers(stream).await?;
+                    }
+                    res
                 }
             }
             _ => panic!("wrong body state: {:?}", self.body_state),
         }
     }
 
+    // read the rest of the trailer section after the terminating chunk, if it is incomplete
+    async fn read_trailers<S>(&mut self, stream: &mut S) -> Result<()>
+    where
+        S: AsyncRead + Unpin + Send,
+    {
+        let Some(mut buf) = self.trailer_buf.take() else {
+            return Ok(());
+        };
+        let res = loop {
+            if buf.len() > TRAILERS_SIZE_LIMIT {
+                break Error::e_explain(INVALID_CHUNK, "Trailers over limit");
+            }
+            buf.reserve(1024);
+            match stream.read_buf(&mut buf).await {
+                // tolerate a peer that closes right after the last chunk without the final CRLF
+                Ok(0) if buf.is_empty() => break Ok(()),
+                Ok(0) => {
+                    break Error::e_explain(
+                        ConnectionClosed,
+                        "Connection prematurely closed in the trailers",
+                    )
+                }
+                Ok(_) => {}
+                Err(e) => break Error::e_because(ReadError, "when reading trailers", e),
+            }
+            if let httparse::Status::Complete(trailers) = parse_trailers(&buf) {
+                self.trailers = trailers.map(Box::new);
+                break Ok(());
+            }
+        };
+        if res.is_err() {
+            if let PS::Complete(read) = self.body_state {
+                self.body_state = PS::Done(read);
+            }
+        }
+        res
+    }
+
     fn parse_chunked_buf(
         &mut self,
         buf_index_start: usize,
@@ -373,7 +433,17 @@ impl BodyReader {
                         );
                         let chunk_size = chunk_size as usize;
                         if chunk_size == 0 {
-                            /* terminating chunk. TODO: trailer */
+                            /* terminating chunk, followed by the trailer section */
+                            let rest = &buf[payload_index..];
+                            match parse_trailers(rest) {
+                                httparse::Status::Complete(trailers) => {
+                                    self.trailers = trailers.map(Box::new)
+                                }
+                                // the caller reads the rest of it
+                                httparse::Status::Partial => {
+                                    self.trailer_buf = Some(BytesMut::from(rest))
+                                }
+                            }
                             self.body_state = self.body_state.finish(0);
                             return Ok(None);
                         }
@@ -430,6 +500,32 @@ impl BodyReader {
     }
 }
 
+// Parse the trailer section after the terminating chunk, `Partial` if it is incomplete. Invalid
+// trailers are ignored.
+fn parse_trailers(buf: &[u8]) -> httparse::Status<Option<HeaderMap>> {
+    let mut fields = [httparse::EMPTY_HEADER; MAX_TRAILERS];
+    let fields = match httparse::parse_headers(buf, &mut fields) {
+        Ok(httparse::Status::Complete((_, fields))) => fields,
+        Ok(httparse::Status::Partial) => return httparse::Status::Partial,
+        Err(e) => {
+            debug!("Ignoring invalid trailers: {e:?}");
+            return httparse::Status::Complete(None);
+        }
+    };
+    let mut trailers = HeaderMap::with_capacity(fields.len());
+    for field in fields.iter() {
+        let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(field.name.as_bytes()),
+            HeaderValue::from_bytes(field.value),
+        ) else {
+            debug!("Ignoring invalid trailer {:?}", field.name);
+            continue;
+        };
+        trailers.append(name, value);
+    }
+    httparse::Status::Complete((!trailers.is_empty()).then_some(trailers))
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum BodyMode {
     ToSelect,
@@ -597,6 +693,41 @@ impl BodyWriter {
         }
     }
 
+    /// Finish the body with the given trailers
+    ///
+    /// The trailers are only sent with chunked encoding. Otherwise they are dropped as the framing
+    /// cannot carry them, and this is the same as [Self::finish()].
+    pub async fn finish_with_trailers<S>(
+        &mut self,
+        stream: &mut S,
+        trailers: &HeaderMap,
+    ) -> Result<Option<usize>>
+    where
+        S: AsyncWrite + Unpin + Send,
+    {
+        let BM::ChunkedEncoding(written) = self.body_mode else {
+            if !trailers.is_empty() {
+                debug!("Dropping trailers of non-chunked body");
+            }
+            return self.finish(stream).await;
+        };
+        let mut buf = BytesMut::with_capacity(64);
+        buf.put_slice(b"0\r\n");
+        for (name, value) in trailers.iter() {
+            buf.put_slice(name.as_str().as_bytes());
+            buf.put_slice(b": ");
+            buf.put_slice(value.as_bytes());
+            buf.put_slice(b"\r\n");
+        }
+        buf.put_slice(b"\r\n");
+        let res = stream.write_all(&buf).await;
+        self.body_mode = BM::Complete(written);
+        match res {
+            Ok(()) => Ok(Some(written)),
+            Err(e) => Error::e_because(WriteError, "while writing trailers", e),
+        }
+    }
+
     fn do_finish_body<S>(&mut self, _stream: S) -> Result<Option<usize>> {
         match self.body_mode {
             BM::ContentLength(total, written) => {
@@ -882,6 +1013,77 @@ mod tests {
         assert_eq!(body_reader.body_state, ParseState::Complete(3));
     }
 
+    #[tokio::test]
+    async fn read_with_body_trailers() {
+        init_log();
+        let input1 = b"1\r\na\r\n";
+        let input2 = b"0\r\ngrpc-status: 0\r\ngrpc-message: ok\r\n\r\n";
+        let mut mock_io = Builder::new().read(&input1[..]).read(&input2[..]).build();
+        let mut body_reader = BodyReader::new();
+        body_reader.init_chunked(b"");
+        let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
+        assert_eq!(&input1[3..4], body_reader.get_body(&res));
+        let res = body_reader.read_body(&mut mock_io).await.unwrap();
+        assert_eq!(res, None);
+        assert_eq!(body_reader.body_state, ParseState::Complete(1));
+        let trailers = body_reader.take_trailers().unwrap();
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+        assert_eq!(trailers.get("grpc-message").unwrap(), "ok");
+        assert!(body_reader.take_trailers().is_none());
+    }
+
+    #[tokio::test]
+    async fn read_with_body_split_trailers() {
+        init_log();
+        let input1 = b"1\r\na\r\n0\r\ngrpc-status: 0\r\n";
+        let input2 = b"grpc-message: o";
+        let input3 = b"k\r\n\r\n";
+        let mut mock_io = Builder::new()
+            .read(&input1[..])
+            .read(&input2[..])
+            .read(&input3[..])
+            .build();
+        let mut body_reader = BodyReader::new();
+        body_reader.init_chunked(b"");
+        let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
+        assert_eq!(&input1[3..4], body_reader.get_body(&res));
+        let res = body_reader.read_body(&mut mock_io).await.unwrap();
+        assert_eq!(res, None);
+        assert_eq!(body_reader.body_state, ParseState::Complete(1));
+        let trailers = body_reader.take_trailers().unwrap();
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+        assert_eq!(trailers.get("grpc-message").unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn read_with_body_split_last_crlf() {
+        init_log();
+        let input1 = b"1\r\na\r\n0\r\n";
+        let input2 = b"\r\n";
+        let mut mock_io = Builder::new().read(&input1[..]).read(&input2[..]).build();
+        let mut body_reader = BodyReader::new();
+        body_reader.init_chunked(b"");
+        let res = body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
+        assert_eq!(&input1[3..4], body_reader.get_body(&res));
+        let res = body_reader.read_body(&mut mock_io).await.unwrap();
+        assert_eq!(res, None);
+        assert_eq!(body_reader.body_state, ParseState::Complete(1));
+        assert!(body_reader.take_trailers().is_none());
+    }
+
+    #[tokio::test]
+    async fn read_with_body_trailers_closed() {
+        init_log();
+        let input1 = b"1\r\na\r\n0\r\ngrpc-status: 0\r\n";
+        let mut mock_io = Builder::new().read(&input1[..]).build();
+        let mut body_reader = BodyReader::new();
+        body_reader.init_chunked(b"");
+        body_reader.read_body(&mut mock_io).await.unwrap().unwrap();
+        let res = body_reader.read_body(&mut mock_io).await;
+        assert_eq!(res.unwrap_err().etype(), &ConnectionClosed);
+        assert_eq!(body_reader.body_state, ParseState::Done(1));
+    }
+
     #[tokio::test]
     async fn read_with_body_partial_chunk() {
         init_log();
@@ -985,6 +1187,46 @@ mod tests {
         assert_eq!(body_writer.body_mode, BodyMode::Complete(data.len() * 2));
     }
 
+    #[tokio::test]
+    async fn write_body_chunked_trailers() {
+        init_log();
+        let mut mock_io = Builder::new()
+            .write(b"1\r\na\r\n")
+            .write(b"0\r\ngrpc-status: 0\r\ngrpc-message: ok\r\n\r\n")
+            .build();
+        let mut body_writer = BodyWriter::new();
+        body_writer.init_chunked();
+        body_writer.write_body(&mut mock_io, b"a").await.unwrap();
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        trailers.insert("grpc-message", HeaderValue::from_static("ok"));
+        let res = body_writer
+            .finish_with_trailers(&mut mock_io, &trailers)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res, 1);
+        assert_eq!(body_writer.body_mode, BodyMode::Complete(1));
+    }
+
+    #[tokio::test]
+    async fn write_body_cl_trailers() {
+        init_log();
+        let mut mock_io = Builder::new().write(b"a").build();
+        let mut body_writer = BodyWriter::new();
+        body_writer.init_content_length(1);
+        body_writer.write_body(&mut mock_io, b"a").await.unwrap();
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        // dropped
+        let res = body_writer
+            .finish_with_trailers(&mut mock_io, &trailers)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res, 1);
+    }
+
     #[tokio::test]
     async fn write_body_http10() {
         init_log();
@@ -15,7 +15,7 @@
 //! HTTP/1.x client session
 
 use bytes::{BufMut, Bytes, BytesMut};
-use http::{header, header::AsHeaderName, HeaderValue, StatusCode, Version};
+use http::{header, header::AsHeaderName, HeaderMap, HeaderValue, StatusCode, Version};
 use log::{debug, trace};
 use pingora_error::{Error, ErrorType::*, OrErr, Result, RetryType};
 use pingora_http::{HMap, IntoCaseHeaderName, RequestHeader, ResponseHeader};
@@ -54,6 +54,9 @@ pub struct HttpSession {
     request_written: Option<Box<RequestHeader>>,
     bytes_sent: usize,
     upgraded: bool,
+    // the max number of requests on the underlying connection, and whether this is the last one
+    max_requests: Option<usize>,
+    last_request: bool,
 }
 
 /// HTTP 1.x client session
@@ -82,13 +85,34 @@ impl HttpSession {
             digest,
             bytes_sent: 0,
             upgraded: false,
+            max_requests: None,
+            last_request: false,
         }
     }
+
+    /// Set the maximum number of requests to send on the underlying connection
+    ///
+    /// The request that reaches the limit is sent with `Connection: close` and the connection
+    /// will not be reused after it. The requests are counted on the connection across sessions.
+    pub fn set_max_requests(&mut self, max: Option<usize>) {
+        self.max_requests = max;
+    }
     /// Write the request header to the server
     /// After the request header is sent. The caller can either start reading the response or
     /// sending request body if any.
-    pub async fn write_request_header(&mut self, req: Box<RequestHeader>) -> Result<usize> {
+    pub async fn write_request_header(&mut self, mut req: Box<RequestHeader>) -> Result<usize> {
         // TODO: make sure this can only be called once
+        let requests = self
+            .digest
+            .socket_digest
+            .as_ref()
+            .map(|d| d.count_request());
+        if let Some((max, n)) = self.max_requests.zip(requests) {
+            if n >= max {
+                self.last_request = true;
+                req.insert_header(header::CONNECTION, "close")?;
+            }
+        }
         // init body writer
         self.init_req_body_writer(&req);
 
@@ -97,7 +121,7 @@ impl HttpSession {
 
         let write_fut = self.underlying_stream.write_all(to_wire.as_ref());
         match self.write_timeout {
-            Some(t) => match timeout(t, write_fut).await {
+            Some(t) => match timeout(t, write_fut).label("upstream_write").await {
                 Ok(res) => res,
                 Err(_) => Err(std::io::Error::from(ErrorKind::TimedOut)),
             },
@@ -138,7 +162,10 @@ impl HttpSession {
     pub async fn write_body(&mut self, buf: &[u8]) -> Result<Option<usize>> {
         // TODO: verify that request header is sent already
         match self.write_timeout {
-            Some(t) => match timeout(t, self.do_write_body(buf)).await {
+            Some(t) => match timeout(t, self.do_write_body(buf))
+                .label("upstream_write")
+                .await
+            {
                 Ok(res) => res,
                 Err(_) => Error::e_explain(WriteTimedout, format!("writing body, timeout: {t:?}")),
             },
@@ -166,6 +193,24 @@ impl HttpSession {
         Ok(res)
     }
 
+    /// Finish the request body with the given trailers.
+    ///
+    /// The trailers are only sent if the request uses chunked encoding. Otherwise this is the same
+    /// as [Self::finish_body()].
+    pub async fn write_trailers(&mut self, trailers: &HeaderMap) -> Result<Option<usize>> {
+        let res = self
+            .body_writer
+            .finish_with_trailers(&mut self.underlying_stream, trailers)
+            .await?;
+        self.underlying_stream
+            .flush()
+            .await
+            .or_err(WriteError, "flushing trailers")?;
+
+        self.maybe_force_close_body_reader();
+        Ok(res)
+    }
+
     /// Read the response header from the server
     /// This function can be called multiple times, if the headers received are just informational
     /// headers.
@@ -187,6 +232,7 @@ impl HttpSession {
             let read_fut = self.underlying_stream.read_buf(&mut buf);
             let read_result = match self.read_timeout {
                 Some(t) => timeout(t, read_fut)
+                    .label("upstream_read")
                     .await
                     .map_err(|_| Error::explain(ReadTimedout, "while reading response headers"))?,
                 None => read_fut.await,
@@ -343,7 +389,7 @@ impl HttpSession {
     /// Return `Ok(None)` if there is no more body to read.
     pub async fn read_body_ref(&mut self) -> Result<Option<&[u8]>> {
         let result = match self.read_timeout {
-            Some(t) => match timeout(t, self.do_read_body()).await {
+            Some(t) => match timeout(t, self.do_read_body()).label("upstream_read").await {
                 Ok(res) => res,
                 Err(_) => Error::e_explain(ReadTimedout, format!("reading body, timeout: {t:?}")),
             },
@@ -365,6 +411,11 @@ impl HttpSession {
         self.body_reader.body_done()
     }
 
+    /// Take the trailers of the chunked response body, available once the body is done
+    pub fn take_response_trailers(&mut self) -> Option<Box<HeaderMap>> {
+        self.body_reader.take_trailers()
+    }
+
     pub(super) fn get_headers_raw(&self) -> &[u8] {
         // TODO: these get_*() could panic. handle them better
         self.raw_header.as_ref().unwrap().get(&self.buf[..])
@@ -399,6 +450,11 @@ impl HttpSession {
             self.set_keepalive(None);
             return;
         }
+        if self.last_request {
+            // the connection has carried enough requests
+            self.set_keepalive(None);
+            return;
+        }
         if let Some(keepalive) = self.is_connection_keepalive() {
             if keepalive {
                 let (timeout, _max_use) = self.get_keepalive_values();
@@ -605,8 +661,7 @@ impl HttpSession {
             );
             Ok(HttpTask::Header(resp_header, end_of_body))
         } else if self.is_body_done() {
-            debug!("Response is done");
-            Ok(HttpTask::Done)
+            Ok(self.response_done_task())
         } else {
             /* need to read body */
             let data = self.read_body_bytes().await?;
@@ -616,11 +671,20 @@ impl HttpSession {
                 trace!("Response body: {:?}", body);
                 Ok(HttpTask::Body(Some(body), end_of_body))
             } else {
-                debug!("Response is done");
-                Ok(HttpTask::Done)
+                Ok(self.response_done_task())
             }
         }
-        // TODO: support h1 trailer
+    }
+
+    // the trailers if there are any, then Done
+    fn response_done_task(&mut self) -> HttpTask {
+        if let Some(trailers) = self.take_response_trailers() {
+            debug!("Response trailers: {:?}", trailers);
+            HttpTask::Trailer(Some(trailers))
+        } else {
+            debug!("Response is done");
+            HttpTask::Done
+        }
     }
 
     pub fn digest(&self) -> &Digest {
@@ -829,6 +893,31 @@ mod tests_stream {
         assert_eq!(wire.len(), n);
     }
 
+    #[tokio::test]
+    async fn write_max_requests() {
+        use crate::protocols::SocketDigest;
+        use std::sync::Arc;
+
+        let wire = b"GET /test HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let input = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+        let mock_io = Builder::new().write(wire).read(&input[..]).build();
+        let mut http_stream = HttpSession::new(Box::new(mock_io));
+        // one request is already sent on this connection
+        let digest = Arc::new(SocketDigest::from_raw_fd(0));
+        digest.count_request();
+        http_stream.digest.socket_digest = Some(digest.clone());
+        http_stream.set_max_requests(Some(2));
+        let new_request = RequestHeader::build("GET", b"/test", None).unwrap();
+        http_stream
+            .write_request_header(Box::new(new_request))
+            .await
+            .unwrap();
+        http_stream.read_response().await.unwrap();
+        http_stream.respect_keepalive();
+        assert!(!http_stream.will_keepalive());
+        assert_eq!(digest.requests(), 2);
+    }
+
     #[tokio::test]
     #[should_panic(expected = "There is still data left to write.")]
     async fn write_timeout() {
@@ -976,6 +1065,67 @@ mod tests_stream {
         }
     }
 
+    #[tokio::test]
+    async fn read_response_trailers() {
+        init_log();
+        let input1 = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let input2 = b"1\r\na\r\n";
+        let input3 = b"0\r\ngrpc-status: 0\r\n\r\n";
+        let mock_io = Builder::new()
+            .read(&input1[..])
+            .read(&input2[..])
+            .read(&input3[..])
+            .build();
+        let mut http_stream = HttpSession::new(Box::new(mock_io));
+
+        let task = http_stream.read_response_task().await.unwrap();
+        assert!(matches!(task, HttpTask::Header(_, false)));
+        let task = http_stream.read_response_task().await.unwrap();
+        match task {
+            HttpTask::Body(b, eob) => {
+                assert_eq!(b.unwrap(), &b"a"[..]);
+                assert!(!eob);
+            }
+            _ => panic!("task should be body"),
+        }
+        let task = http_stream.read_response_task().await.unwrap();
+        match task {
+            HttpTask::Trailer(Some(trailers)) => {
+                assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+            }
+            _ => panic!("task should be trailer"),
+        }
+        let task = http_stream.read_response_task().await.unwrap();
+        assert!(matches!(task, HttpTask::Done));
+    }
+
+    #[tokio::test]
+    async fn write_request_trailers() {
+        use crate::protocols::http::v1::body::BodyMode;
+
+        init_log();
+        let wire = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let mock_io = Builder::new()
+            .write(&wire[..])
+            .write(b"1\r\na\r\n")
+            .write(b"0\r\nx-checksum: 1\r\n\r\n")
+            .build();
+        let mut http_stream = HttpSession::new(Box::new(mock_io));
+        let mut new_request = RequestHeader::build("POST", b"/", None).unwrap();
+        new_request
+            .insert_header("Transfer-Encoding", "chunked")
+            .unwrap();
+        http_stream
+            .write_request_header(Box::new(new_request))
+            .await
+            .unwrap();
+        http_stream.write_body(b"a").await.unwrap();
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", HeaderValue::from_static("1"));
+        http_stream.write_trailers(&trailers).await.unwrap();
+        assert_eq!(http_stream.body_writer.body_mode, BodyMode::Complete(1));
+    }
+
     // Note: in debug mode, due to from_maybe_shared_unchecked() still tries to validate headers
     // values, so the code has to replace CRLF with whitespaces. In release mode, the CRLF is
     // reserved
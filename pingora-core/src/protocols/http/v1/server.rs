@@ -16,8 +16,8 @@
 
 use bytes::Bytes;
 use bytes::{BufMut, BytesMut};
-use http::HeaderValue;
 use http::{header, header::AsHeaderName, Method, Version};
+use http::{HeaderMap, HeaderValue};
 use log::{debug, error, warn};
 use once_cell::sync::Lazy;
 use percent_encoding::{percent_encode, AsciiSet, CONTROLS};
@@ -53,6 +53,8 @@ pub struct HttpSession {
     body_write_buf: BytesMut,
     /// Track how many application (not on the wire) body bytes already sent
     body_bytes_sent: usize,
+    /// Track how many application (not on the wire) body bytes already read
+    body_bytes_read: usize,
     /// Whether to update headers like connection, Date
     update_resp_headers: bool,
     /// timeouts:
@@ -65,9 +67,13 @@ pub struct HttpSession {
     request_header: Option<Box<RequestHeader>>,
     /// An internal buffer that holds a copy of the request body up to a certain size
     retry_buffer: Option<FixedBuffer>,
+    /// The size limit of the retry buffer
+    retry_buffer_limit: usize,
     /// Whether this session is an upgraded session. This flag is calculated when sending the
     /// response header to the client.
     upgraded: bool,
+    /// Whether any of the request header was read from the TLS 1.3 early data (0-RTT)
+    early_data: bool,
     /// Digest to track underlying connection metrics
     digest: Box<Digest>,
 }
@@ -100,8 +106,11 @@ impl HttpSession {
             read_timeout: None,
             write_timeout: None,
             body_bytes_sent: 0,
+            body_bytes_read: 0,
             retry_buffer: None,
+            retry_buffer_limit: BODY_BUF_LIMIT,
             upgraded: false,
+            early_data: false,
             digest,
         }
     }
@@ -115,6 +124,7 @@ impl HttpSession {
         self.buf.clear();
         let mut buf = BytesMut::with_capacity(INIT_HEADER_BUF_SIZE);
         let mut already_read: usize = 0;
+        let mut early_data = false;
         loop {
             if already_read > MAX_HEADER_SIZE {
                 /* NOTE: this check only blocks second read. The first large read is allowed
@@ -126,6 +136,8 @@ impl HttpSession {
                 );
             }
 
+            // pipelined requests can be sent in the early data too, so check every read
+            early_data |= self.underlying_stream.early_data_pending();
             let read_result = {
                 let read_event = self.underlying_stream.read_buf(&mut buf);
                 match self.keepalive_timeout {
@@ -223,7 +235,14 @@ impl HttpSession {
 
                         self.body_reader.reinit();
                         self.response_written = None;
+                        self.early_data = early_data;
                         self.respect_keepalive();
+                        if let Some(socket_digest) = self.digest.socket_digest.as_ref() {
+                            socket_digest.count_request();
+                            if let Some(stats) = socket_digest.listener_stats() {
+                                stats.record_h1_request();
+                            }
+                        }
 
                         return Ok(Some(s));
                     }
@@ -328,6 +347,13 @@ impl HttpSession {
         }
     }
 
+    /// Whether the client accepts trailers in the response, i.e., the request has `TE: trailers`
+    pub fn accepts_trailers(&self) -> bool {
+        self.request_header
+            .as_deref()
+            .is_some_and(is_trailers_accepted)
+    }
+
     /// Get the request header as raw bytes, `b""` when the header doesn't exist
     pub fn get_header_bytes(&self, name: impl AsHeaderName) -> &[u8] {
         self.get_header(name).map_or(b"", |v| v.as_bytes())
@@ -338,6 +364,7 @@ impl HttpSession {
         let read = self.read_body().await?;
         Ok(read.map(|b| {
             let bytes = Bytes::copy_from_slice(self.get_body(&b));
+            self.body_bytes_read += bytes.len();
             if let Some(buffer) = self.retry_buffer.as_mut() {
                 buffer.write_to_buffer(&bytes);
             }
@@ -369,6 +396,11 @@ impl HttpSession {
         self.body_reader.body_done()
     }
 
+    /// Take the trailers of the chunked request body, available once the body is done
+    pub fn take_request_trailers(&mut self) -> Option<Box<HeaderMap>> {
+        self.body_reader.take_trailers()
+    }
+
     /// Whether the request has an empty body
     /// Because HTTP 1.1 clients have to send either `Content-Length` or `Transfer-Encoding` in order
     /// to signal the server that it will send the body, this function returns accurate results even
@@ -388,6 +420,19 @@ impl HttpSession {
             }
         }
 
+        // HTTP/1.0 clients don't understand interim responses
+        // https://www.rfc-editor.org/rfc/rfc9110#section-15.2
+        if header.status.is_informational()
+            && header.status != 101
+            && self
+                .request_header
+                .as_ref()
+                .is_some_and(|r| r.version == http::Version::HTTP_10)
+        {
+            debug!("skip 1xx response to HTTP/1.0 client");
+            return Ok(());
+        }
+
         // no need to add these headers to 1xx responses
         if !header.status.is_informational() && self.update_resp_headers {
             /* update headers */
@@ -627,11 +672,40 @@ impl HttpSession {
         Ok(res)
     }
 
+    /// Finish the response body with the given trailers.
+    ///
+    /// The trailers are only sent if the response uses chunked encoding and the client accepts
+    /// them, see [Self::accepts_trailers()]. Otherwise this is the same as [Self::finish_body()].
+    pub async fn write_trailers(&mut self, trailers: &HeaderMap) -> Result<Option<usize>> {
+        if !self.accepts_trailers() {
+            if !trailers.is_empty() {
+                debug!("Dropping trailers not accepted by the client");
+            }
+            return self.finish_body().await;
+        }
+        let res = self
+            .body_writer
+            .finish_with_trailers(&mut self.underlying_stream, trailers)
+            .await?;
+        self.underlying_stream
+            .flush()
+            .await
+            .or_err(WriteError, "flushing trailers")?;
+
+        self.maybe_force_close_body_reader();
+        Ok(res)
+    }
+
     /// Return how many (application, not wire) body bytes that have been written
     pub fn body_bytes_sent(&self) -> usize {
         self.body_bytes_sent
     }
 
+    /// Return how many (application, not wire) request body bytes that have been read
+    pub fn body_bytes_read(&self) -> usize {
+        self.body_bytes_read
+    }
+
     fn is_chunked_encoding(&self) -> bool {
         is_header_value_chunked_encoding(self.get_header(header::TRANSFER_ENCODING))
     }
@@ -691,10 +765,23 @@ impl HttpSession {
 
     pub fn enable_retry_buffering(&mut self) {
         if self.retry_buffer.is_none() {
-            self.retry_buffer = Some(FixedBuffer::new(BODY_BUF_LIMIT))
+            self.retry_buffer = Some(FixedBuffer::new(self.retry_buffer_limit))
         }
     }
 
+    /// Set the maximum size of the request body to keep in the retry buffer, 64KiB by default.
+    /// The retry buffer is truncated once the body grows beyond it.
+    ///
+    /// This should be called before [`Self::enable_retry_buffering()`] to take effect.
+    pub fn set_retry_buffer_limit(&mut self, limit: usize) {
+        self.retry_buffer_limit = limit;
+    }
+
+    /// The maximum size of the request body to keep in the retry buffer
+    pub fn retry_buffer_limit(&self) -> usize {
+        self.retry_buffer_limit
+    }
+
     pub fn get_retry_buffer(&self) -> Option<Bytes> {
         self.retry_buffer.as_ref().and_then(|b| {
             if b.is_truncated() {
@@ -769,6 +856,12 @@ impl HttpSession {
         }
     }
 
+    /// Whether the request header was read, at least partially, from the TLS 1.3 early data
+    /// (0-RTT) of the client, which can be replayed
+    pub fn is_early_data(&self) -> bool {
+        self.early_data
+    }
+
     /// Return the [Digest] of the connection.
     pub fn digest(&self) -> &Digest {
         &self.digest
@@ -790,6 +883,30 @@ impl HttpSession {
             .map(|d| d.local_addr())?
     }
 
+    /// Return the original destination address of the underlying connection if it was
+    /// intercepted, see [SocketDigest::original_dst()](crate::protocols::SocketDigest::original_dst).
+    pub fn original_dst(&self) -> Option<&SocketAddr> {
+        self.digest()
+            .socket_digest
+            .as_ref()
+            .map(|d| d.original_dst())?
+    }
+
+    /// Take over the underlying stream, e.g., to tunnel a `CONNECT` request after answering it.
+    ///
+    /// Return the stream along with the bytes the client already sent after the request header.
+    /// The session cannot be used for HTTP afterwards so the connection will not be reused.
+    pub fn take_over(&mut self) -> (&mut Stream, Bytes) {
+        self.set_keepalive(None);
+        let preread = self
+            .preread_body
+            .as_ref()
+            .map_or_else(Bytes::new, |p| p.get_bytes(&self.buf));
+        // nothing is left to read as the request body
+        self.body_reader.init_content_length(0, b"");
+        (&mut self.underlying_stream, preread)
+    }
+
     /// Consume `self`, if the connection can be reused, the underlying stream will be returned
     /// to be fed to the next [`Self::new()`]. The next session can just call [`Self::read_request()`].
     /// If the connection cannot be reused, the underlying stream will be closed and `None` will be
@@ -844,6 +961,14 @@ impl HttpSession {
         Ok(())
     }
 
+    async fn write_response_trailers(&mut self, trailers: Option<Box<HeaderMap>>) -> Result<()> {
+        match trailers {
+            Some(trailers) => self.write_trailers(&trailers).await?,
+            None => self.finish_body().await?,
+        };
+        Ok(())
+    }
+
     async fn response_duplex(&mut self, task: HttpTask) -> Result<bool> {
         match task {
             HttpTask::Header(header, end_stream) => {
@@ -861,7 +986,12 @@ impl HttpSession {
                 }
                 None => Ok(end_stream),
             },
-            HttpTask::Trailer(_) => Ok(true), // h1 trailer is not supported yet
+            HttpTask::Trailer(trailers) => {
+                self.write_response_trailers(trailers)
+                    .await
+                    .map_err(|e| e.into_down())?;
+                Ok(true)
+            }
             HttpTask::Done => {
                 self.finish_body().await.map_err(|e| e.into_down())?;
                 Ok(true)
@@ -895,7 +1025,14 @@ impl HttpSession {
                     }
                     None => end_stream,
                 },
-                HttpTask::Trailer(_) => true, // h1 trailer is not supported yet
+                HttpTask::Trailer(trailers) => {
+                    // flush body first
+                    self.write_body_buf().await.map_err(|e| e.into_down())?;
+                    self.write_response_trailers(trailers)
+                        .await
+                        .map_err(|e| e.into_down())?;
+                    return Ok(true);
+                }
                 HttpTask::Done => {
                     // flush body first
                     self.write_body_buf().await.map_err(|e| e.into_down())?;
@@ -1094,6 +1231,63 @@ mod tests_stream {
         assert_eq!(input3, http_stream.get_body(&res));
     }
 
+    #[tokio::test]
+    async fn read_with_body_retry_buffer_limit() {
+        init_log();
+        let input1 = b"POST / HTTP/1.1\r\nHost: pingora.org\r\nContent-Length: 6\r\n\r\n";
+        let input2 = b"abc";
+        let input3 = b"def";
+        let mock_io = Builder::new()
+            .read(&input1[..])
+            .read(&input2[..])
+            .read(&input3[..])
+            .build();
+        let mut http_stream = HttpSession::new(Box::new(mock_io));
+        http_stream.read_request().await.unwrap();
+        http_stream.set_retry_buffer_limit(4);
+        http_stream.enable_retry_buffering();
+        http_stream.read_body_bytes().await.unwrap().unwrap();
+        assert!(!http_stream.retry_buffer_truncated());
+        assert_eq!(http_stream.get_retry_buffer().unwrap(), &input2[..]);
+        http_stream.read_body_bytes().await.unwrap().unwrap();
+        assert!(http_stream.retry_buffer_truncated());
+        assert!(http_stream.get_retry_buffer().is_none());
+    }
+
+    #[tokio::test]
+    async fn read_request_count() {
+        use crate::protocols::SocketDigest;
+        use std::sync::Arc;
+
+        init_log();
+        let input = b"GET / HTTP/1.1\r\nHost: pingora.org\r\n\r\n";
+        let mock_io = Builder::new().read(&input[..]).build();
+        let mut http_stream = HttpSession::new(Box::new(mock_io));
+        // the requests are counted on the connection
+        let digest = Arc::new(SocketDigest::from_raw_fd(0));
+        digest.count_request();
+        http_stream.digest.socket_digest = Some(digest.clone());
+        http_stream.read_request().await.unwrap();
+        assert_eq!(digest.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn take_over_connect() {
+        init_log();
+        let input = b"CONNECT pingora.org:443 HTTP/1.1\r\nHost: pingora.org:443\r\n\r\nhello";
+        let mock_io = Builder::new().read(&input[..]).read(b" world").build();
+        let mut http_stream = HttpSession::new(Box::new(mock_io));
+        http_stream.read_request().await.unwrap();
+        assert_eq!(Some(&Method::CONNECT), http_stream.get_method());
+        let (stream, preread) = http_stream.take_over();
+        assert_eq!(preread.as_ref(), b"hello");
+        let mut rest = vec![0; 6];
+        stream.read_exact(&mut rest).await.unwrap();
+        assert_eq!(rest, b" world");
+        assert!(http_stream.is_body_done());
+        assert!(!http_stream.will_keepalive());
+    }
+
     #[tokio::test]
     #[should_panic(expected = "There is still data left to read.")]
     async fn read_with_body_timeout() {
@@ -1312,6 +1506,58 @@ mod tests_stream {
         assert!(!http_stream.is_body_done());
     }
 
+    #[tokio::test]
+    async fn write_early_hints() {
+        let input = b"GET / HTTP/1.1\r\nHost: pingora.org\r\n\r\n";
+        let mock_io = Builder::new()
+            .read(&input[..])
+            .write(b"HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n")
+            .write(b"HTTP/1.1 200 OK\r\nFoo: Bar\r\n\r\n")
+            .build();
+        let mut http_stream = HttpSession::new(Box::new(mock_io));
+        http_stream.read_request().await.unwrap();
+        let mut response = ResponseHeader::build(103, None).unwrap();
+        response
+            .append_header("Link", "</style.css>; rel=preload")
+            .unwrap();
+        http_stream
+            .write_response_header(Box::new(response))
+            .await
+            .unwrap();
+        assert!(http_stream
+            .response_written()
+            .unwrap()
+            .status
+            .is_informational());
+        // the final response still follows
+        let mut response = ResponseHeader::build(StatusCode::OK, None).unwrap();
+        response.append_header("Foo", "Bar").unwrap();
+        http_stream.update_resp_headers = false;
+        http_stream
+            .write_response_header(Box::new(response))
+            .await
+            .unwrap();
+        assert_eq!(
+            http_stream.response_written().unwrap().status,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_1xx_for_http10() {
+        let input = b"GET / HTTP/1.0\r\nHost: pingora.org\r\n\r\n";
+        // nothing is written
+        let mock_io = Builder::new().read(&input[..]).build();
+        let mut http_stream = HttpSession::new(Box::new(mock_io));
+        http_stream.read_request().await.unwrap();
+        let response = ResponseHeader::build(103, None).unwrap();
+        http_stream
+            .write_response_header(Box::new(response))
+            .await
+            .unwrap();
+        assert!(http_stream.response_written().is_none());
+    }
+
     #[tokio::test]
     async fn set_server_keepalive() {
         // close
@@ -1476,6 +1722,50 @@ mod tests_stream {
         assert_eq!(b"a".len(), n);
     }
 
+    #[tokio::test]
+    async fn write_body_chunk_trailers() {
+        let wire_header = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let wire_body = b"1\r\na\r\n";
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", HeaderValue::from_static("1"));
+
+        for (te, wire_end) in [
+            (
+                &b"TE: deflate, trailers\r\n"[..],
+                &b"0\r\nx-checksum: 1\r\n\r\n"[..],
+            ),
+            // the client doesn't accept trailers
+            (&b""[..], &b"0\r\n\r\n"[..]),
+        ] {
+            let input = [b"GET / HTTP/1.1\r\n", te, b"\r\n"].concat();
+            let mock_io = Builder::new()
+                .read(&input)
+                .write(wire_header)
+                .write(wire_body)
+                .write(wire_end)
+                .build();
+            let mut http_stream = HttpSession::new(Box::new(mock_io));
+            http_stream.read_request().await.unwrap();
+            assert_eq!(http_stream.accepts_trailers(), !te.is_empty());
+            let mut new_response = ResponseHeader::build(StatusCode::OK, None).unwrap();
+            new_response
+                .append_header("Transfer-Encoding", "chunked")
+                .unwrap();
+            http_stream.update_resp_headers = false;
+            http_stream
+                .write_response_header_ref(&new_response)
+                .await
+                .unwrap();
+            http_stream.write_body(b"a").await.unwrap();
+            let n = http_stream
+                .write_trailers(&trailers)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(b"a".len(), n);
+        }
+    }
+
     #[tokio::test]
     async fn read_with_illegal() {
         init_log();
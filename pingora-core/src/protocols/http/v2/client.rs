@@ -172,6 +172,7 @@ impl Http2Session {
 
         let res = match self.read_timeout {
             Some(t) => timeout(t, resp_fut)
+                .label("upstream_read")
                 .await
                 .map_err(|_| Error::explain(ReadTimedout, "while reading h2 response header"))
                 .map_err(|e| self.handle_err(e))?,
@@ -201,6 +202,7 @@ impl Http2Session {
         let fut = body_reader.data();
         let res = match self.read_timeout {
             Some(t) => timeout(t, fut)
+                .label("upstream_read")
                 .await
                 .map_err(|_| Error::explain(ReadTimedout, "while reading h2 response body"))?,
             None => fut.await,
@@ -245,6 +247,7 @@ impl Http2Session {
 
         let res = match self.read_timeout {
             Some(t) => timeout(t, fut)
+                .label("upstream_read")
                 .await
                 .map_err(|_| Error::explain(ReadTimedout, "while reading h2 trailer"))
                 .map_err(|e| self.handle_err(e))?,
@@ -16,3 +16,4 @@
 
 pub mod client;
 pub mod server;
+pub mod settings;
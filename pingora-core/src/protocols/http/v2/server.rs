@@ -20,7 +20,7 @@ use h2::server;
 use h2::server::SendResponse;
 use h2::{RecvStream, SendStream};
 use http::header::HeaderName;
-use http::{header, Response};
+use http::{header, HeaderMap, Response};
 use log::{debug, warn};
 use pingora_http::{RequestHeader, ResponseHeader};
 use std::sync::Arc;
@@ -96,6 +96,8 @@ pub struct HttpSession {
     body_sent: usize,
     // buffered request body for retry logic
     retry_buffer: Option<FixedBuffer>,
+    // the size limit of the retry buffer
+    retry_buffer_limit: usize,
     // digest to record underlying connection info
     digest: Arc<Digest>,
 }
@@ -136,6 +138,7 @@ impl HttpSession {
                 body_read: 0,
                 body_sent: 0,
                 retry_buffer: None,
+                retry_buffer_limit: BODY_BUF_LIMIT,
                 digest,
             }
         }))
@@ -177,6 +180,15 @@ impl HttpSession {
         Ok(data)
     }
 
+    /// Read the request trailers after the body. `None` when there are no trailers.
+    pub async fn read_trailers(&mut self) -> Result<Option<HeaderMap>> {
+        // TODO: timeout
+        self.request_body_reader.trailers().await.or_err(
+            ErrorType::ReadError,
+            "while reading downstream request trailers",
+        )
+    }
+
     // the write_* don't have timeouts because the actual writing happens on the connection
     // not here.
 
@@ -194,8 +206,13 @@ impl HttpSession {
             return Ok(());
         }
 
-        // FIXME: we should ignore 1xx header because send_response() can only be called once
+        // The h2 lib can't send 1xx headers because send_response() can only be called once.
+        // Skip them as they are just interim responses.
         // https://github.com/hyperium/h2/issues/167
+        if header.status.is_informational() {
+            debug!("skip 1xx response to h2 client");
+            return Ok(());
+        }
 
         if let Some(resp) = self.response_written.as_ref() {
             if !resp.status.is_informational() {
@@ -261,7 +278,25 @@ impl HttpSession {
         self.write_response_header(Box::new(header.clone()), end)
     }
 
-    // TODO: trailer
+    /// Write the response trailers to the client, which ends the response.
+    pub fn write_trailers(&mut self, trailers: HeaderMap) -> Result<()> {
+        if self.ended {
+            warn!("Try to write trailers after end of stream, dropping them");
+            return Ok(());
+        }
+        let Some(writer) = self.send_response_body.as_mut() else {
+            return Err(Error::explain(
+                ErrorType::H2Error,
+                "try to send trailers before header is sent",
+            ));
+        };
+        writer.send_trailers(trailers).or_err(
+            ErrorType::WriteError,
+            "while writing h2 response trailers to downstream",
+        )?;
+        self.ended = true;
+        Ok(())
+    }
 
     /// Mark the session end. If no `end` flag is already set before this call, this call will
     /// signal the client. Otherwise this call does nothing.
@@ -305,7 +340,14 @@ impl HttpSession {
                     }
                     None => end,
                 },
-                HttpTask::Trailer(_) => true, // trailer is not supported yet
+                HttpTask::Trailer(Some(trailers)) => {
+                    self.write_trailers(*trailers).map_err(|e| e.into_down())?;
+                    true
+                }
+                HttpTask::Trailer(None) => {
+                    self.finish().map_err(|e| e.into_down())?;
+                    true
+                }
                 HttpTask::Done => {
                     self.finish().map_err(|e| e.into_down())?;
                     return Ok(true);
@@ -377,10 +419,23 @@ impl HttpSession {
 
     pub fn enable_retry_buffering(&mut self) {
         if self.retry_buffer.is_none() {
-            self.retry_buffer = Some(FixedBuffer::new(BODY_BUF_LIMIT))
+            self.retry_buffer = Some(FixedBuffer::new(self.retry_buffer_limit))
         }
     }
 
+    /// Set the maximum size of the request body to keep in the retry buffer, 64KiB by default.
+    /// The retry buffer is truncated once the body grows beyond it.
+    ///
+    /// This should be called before [`Self::enable_retry_buffering()`] to take effect.
+    pub fn set_retry_buffer_limit(&mut self, limit: usize) {
+        self.retry_buffer_limit = limit;
+    }
+
+    /// The maximum size of the request body to keep in the retry buffer
+    pub fn retry_buffer_limit(&self) -> usize {
+        self.retry_buffer_limit
+    }
+
     pub fn get_retry_buffer(&self) -> Option<Bytes> {
         self.retry_buffer.as_ref().and_then(|b| {
             if b.is_truncated() {
@@ -418,6 +473,11 @@ impl HttpSession {
         self.body_sent
     }
 
+    /// How many request body bytes read from the client
+    pub fn body_bytes_read(&self) -> usize {
+        self.body_read
+    }
+
     /// Return the [Digest] of the connection.
     pub fn digest(&self) -> Option<&Digest> {
         Some(&self.digest)
@@ -428,6 +488,15 @@ impl HttpSession {
         self.digest.socket_digest.as_ref().map(|d| d.local_addr())?
     }
 
+    /// Return the original destination address recorded in the connection digest, see
+    /// [SocketDigest::original_dst()](crate::protocols::SocketDigest::original_dst).
+    pub fn original_dst(&self) -> Option<&SocketAddr> {
+        self.digest
+            .socket_digest
+            .as_ref()
+            .map(|d| d.original_dst())?
+    }
+
     /// Return the client (peer) address recorded in the connection digest.
     pub fn client_addr(&self) -> Option<&SocketAddr> {
         self.digest.socket_digest.as_ref().map(|d| d.peer_addr())?
@@ -516,4 +585,63 @@ mod test {
             });
         }
     }
+
+    #[tokio::test]
+    async fn test_grpc_trailers() {
+        let (client, server) = duplex(65536);
+
+        tokio::spawn(async move {
+            let mut connection = handshake(Box::new(server), None).await.unwrap();
+            let digest = Arc::new(Digest::default());
+            while let Some(mut http) = HttpSession::from_h2_conn(&mut connection, digest.clone())
+                .await
+                .unwrap()
+            {
+                tokio::spawn(async move {
+                    let body = http.read_body_bytes().await.unwrap().unwrap();
+                    assert_eq!(body, "request");
+                    assert!(http.read_body_bytes().await.unwrap().is_none());
+                    let trailers = http.read_trailers().await.unwrap().unwrap();
+                    assert_eq!(trailers.get("x-request-trailer").unwrap(), "1");
+                    assert!(http.is_body_done());
+
+                    let mut resp = ResponseHeader::build(200, None).unwrap();
+                    resp.insert_header("content-type", "application/grpc")
+                        .unwrap();
+                    let mut trailers = HeaderMap::new();
+                    trailers.insert("grpc-status", "0".parse().unwrap());
+                    let tasks = vec![
+                        HttpTask::Header(Box::new(resp), false),
+                        HttpTask::Body(Some("response".into()), false),
+                        HttpTask::Trailer(Some(Box::new(trailers))),
+                    ];
+                    assert!(http.response_duplex_vec(tasks).unwrap());
+                });
+            }
+        });
+
+        let (h2, connection) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(async move {
+            connection.await.unwrap();
+        });
+        let mut h2 = h2.ready().await.unwrap();
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("https://www.example.com/")
+            .body(())
+            .unwrap();
+        let (response, mut req_body) = h2.send_request(request, false).unwrap();
+        req_body.send_data("request".into(), false).unwrap();
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-request-trailer", "1".parse().unwrap());
+        req_body.send_trailers(trailers).unwrap();
+
+        let (head, mut body) = response.await.unwrap().into_parts();
+        assert_eq!(head.status, 200);
+        let data = body.data().await.unwrap().unwrap();
+        assert_eq!(data, "response");
+        assert!(body.data().await.is_none());
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+    }
 }
@@ -0,0 +1,293 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP/2 settings of the listeners and the upstream connectors
+
+use pingora_error::{Error, ErrorType::H2Error, Result};
+use serde::{Deserialize, Serialize};
+
+use super::server::H2Options;
+
+// The legal ranges, RFC 9113 6.5.2
+const MIN_FRAME_SIZE: u32 = 16_384;
+const MAX_FRAME_SIZE: u32 = 16_777_215;
+const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+// The defaults of the h2 library, which the listeners use
+const H2_DEFAULT_WINDOW_SIZE: u32 = 65_535;
+const H2_DEFAULT_MAX_HEADER_LIST_SIZE: u32 = 16 << 20;
+
+/// The default window sizes of the upstream connections
+///
+/// The h2 library we use has unbounded internal buffering, which will cause excessive memory
+/// consumption when the downstream is slower than upstream. This window size caps the buffering by
+/// limiting how much data can be inflight. However, setting this value will also cap the max
+/// download speed by limiting the bandwidth-delay product of a link.
+/// 8 Mbytes = 80 Mbytes X 100ms, which should be enough for most links.
+pub const CLIENT_DEFAULT_WINDOW_SIZE: u32 = 1 << 23;
+/// The default max frame size the upstream connections advertise to the servers
+pub const CLIENT_DEFAULT_MAX_FRAME_SIZE: u32 = 64 * 1024;
+
+/// The HTTP/2 settings to tune the flow control and the concurrency
+///
+/// Each setting left `None` uses its default:
+///
+/// | setting | listener | upstream connector |
+/// | ------- | -------- | ------------------ |
+/// | `max_concurrent_streams` | unlimited | the `max_h2_streams` of the peer |
+/// | `initial_stream_window_size` | 65,535 | 8 MiB |
+/// | `initial_connection_window_size` | 65,535 | 8 MiB |
+/// | `max_frame_size` | 16,384 | 64 KiB |
+/// | `max_header_list_size` | 16 MiB | 16 MiB |
+///
+/// Use [Self::effective_server()] and [Self::effective_client()] to see the settings in effect.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct H2Settings {
+    /// The max number of concurrent streams of each connection
+    ///
+    /// For the listeners, this is how many streams each client can open at once. For the upstream
+    /// connectors, this caps how many streams are opened to each server at once on top of the
+    /// `max_h2_streams` of the peer.
+    pub max_concurrent_streams: Option<u32>,
+    /// The initial flow control window size of each stream, at most 2^31-1
+    pub initial_stream_window_size: Option<u32>,
+    /// The initial flow control window size of each connection, at most 2^31-1
+    pub initial_connection_window_size: Option<u32>,
+    /// The max size of the frames to receive, between 16,384 and 16,777,215
+    pub max_frame_size: Option<u32>,
+    /// The max size of the header list to receive
+    pub max_header_list_size: Option<u32>,
+}
+
+impl H2Settings {
+    /// Check that the settings are within their legal ranges
+    pub fn validate(&self) -> Result<()> {
+        if self.max_concurrent_streams == Some(0) {
+            return Error::e_explain(H2Error, "max_concurrent_streams must be positive");
+        }
+        for (name, size) in [
+            (
+                "initial_stream_window_size",
+                self.initial_stream_window_size,
+            ),
+            (
+                "initial_connection_window_size",
+                self.initial_connection_window_size,
+            ),
+        ] {
+            if size.is_some_and(|s| s > MAX_WINDOW_SIZE) {
+                return Error::e_explain(
+                    H2Error,
+                    format!("{name} must be at most {MAX_WINDOW_SIZE}"),
+                );
+            }
+        }
+        if self
+            .max_frame_size
+            .is_some_and(|s| !(MIN_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&s))
+        {
+            return Error::e_explain(
+                H2Error,
+                format!("max_frame_size must be between {MIN_FRAME_SIZE} and {MAX_FRAME_SIZE}"),
+            );
+        }
+        Ok(())
+    }
+
+    // clamp the settings into their legal ranges so that h2 won't panic
+    fn clamped(&self) -> Self {
+        H2Settings {
+            max_concurrent_streams: self.max_concurrent_streams.map(|s| s.max(1)),
+            initial_stream_window_size: self
+                .initial_stream_window_size
+                .map(|s| s.min(MAX_WINDOW_SIZE)),
+            initial_connection_window_size: self
+                .initial_connection_window_size
+                .map(|s| s.min(MAX_WINDOW_SIZE)),
+            max_frame_size: self
+                .max_frame_size
+                .map(|s| s.clamp(MIN_FRAME_SIZE, MAX_FRAME_SIZE)),
+            max_header_list_size: self.max_header_list_size,
+        }
+    }
+
+    /// The settings in effect for the listeners, with the defaults filled in
+    ///
+    /// `max_concurrent_streams` stays `None` when it is unlimited.
+    pub fn effective_server(&self) -> Self {
+        let s = self.clamped();
+        H2Settings {
+            max_concurrent_streams: s.max_concurrent_streams,
+            initial_stream_window_size: Some(
+                s.initial_stream_window_size
+                    .unwrap_or(H2_DEFAULT_WINDOW_SIZE),
+            ),
+            initial_connection_window_size: Some(
+                s.initial_connection_window_size
+                    .unwrap_or(H2_DEFAULT_WINDOW_SIZE),
+            ),
+            max_frame_size: Some(s.max_frame_size.unwrap_or(MIN_FRAME_SIZE)),
+            max_header_list_size: Some(
+                s.max_header_list_size
+                    .unwrap_or(H2_DEFAULT_MAX_HEADER_LIST_SIZE),
+            ),
+        }
+    }
+
+    /// The settings in effect for the upstream connectors, with the defaults filled in
+    ///
+    /// `max_concurrent_streams` stays `None` when only the `max_h2_streams` of the peers apply.
+    pub fn effective_client(&self) -> Self {
+        let s = self.clamped();
+        H2Settings {
+            max_concurrent_streams: s.max_concurrent_streams,
+            initial_stream_window_size: Some(
+                s.initial_stream_window_size
+                    .unwrap_or(CLIENT_DEFAULT_WINDOW_SIZE),
+            ),
+            initial_connection_window_size: Some(
+                s.initial_connection_window_size
+                    .unwrap_or(CLIENT_DEFAULT_WINDOW_SIZE),
+            ),
+            max_frame_size: Some(s.max_frame_size.unwrap_or(CLIENT_DEFAULT_MAX_FRAME_SIZE)),
+            max_header_list_size: Some(
+                s.max_header_list_size
+                    .unwrap_or(H2_DEFAULT_MAX_HEADER_LIST_SIZE),
+            ),
+        }
+    }
+
+    /// Build the [H2Options] of a listener from these settings
+    ///
+    /// The settings out of their legal ranges are clamped into them.
+    pub fn server_options(&self) -> H2Options {
+        let s = self.effective_server();
+        let mut options = H2Options::new();
+        if let Some(max) = s.max_concurrent_streams {
+            options.max_concurrent_streams(max);
+        }
+        // all filled in by effective_server()
+        options
+            .initial_window_size(s.initial_stream_window_size.unwrap())
+            .initial_connection_window_size(s.initial_connection_window_size.unwrap())
+            .max_frame_size(s.max_frame_size.unwrap())
+            .max_header_list_size(s.max_header_list_size.unwrap());
+        options
+    }
+
+    /// Apply these settings to the builder of an upstream connection
+    ///
+    /// `max_concurrent_streams` is not applied here as it limits the streams the server opens,
+    /// see [Self::max_client_streams()].
+    pub fn apply_client(&self, builder: &mut h2::client::Builder) {
+        let s = self.effective_client();
+        // all filled in by effective_client()
+        builder
+            .initial_window_size(s.initial_stream_window_size.unwrap())
+            .initial_connection_window_size(s.initial_connection_window_size.unwrap())
+            .max_frame_size(s.max_frame_size.unwrap())
+            .max_header_list_size(s.max_header_list_size.unwrap());
+    }
+
+    /// The max number of concurrent streams to open on an upstream connection given the
+    /// `max_h2_streams` of the peer
+    pub fn max_client_streams(&self, peer_max_streams: usize) -> usize {
+        match self.clamped().max_concurrent_streams {
+            Some(max) => peer_max_streams.min(max as usize),
+            None => peer_max_streams,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(H2Settings::default().validate().is_ok());
+        let settings = H2Settings {
+            max_concurrent_streams: Some(100),
+            initial_stream_window_size: Some(MAX_WINDOW_SIZE),
+            initial_connection_window_size: Some(1 << 20),
+            max_frame_size: Some(MAX_FRAME_SIZE),
+            max_header_list_size: Some(64 * 1024),
+        };
+        assert!(settings.validate().is_ok());
+
+        let invalid = [
+            H2Settings {
+                max_concurrent_streams: Some(0),
+                ..Default::default()
+            },
+            H2Settings {
+                initial_stream_window_size: Some(MAX_WINDOW_SIZE + 1),
+                ..Default::default()
+            },
+            H2Settings {
+                initial_connection_window_size: Some(u32::MAX),
+                ..Default::default()
+            },
+            H2Settings {
+                max_frame_size: Some(MIN_FRAME_SIZE - 1),
+                ..Default::default()
+            },
+            H2Settings {
+                max_frame_size: Some(MAX_FRAME_SIZE + 1),
+                ..Default::default()
+            },
+        ];
+        for settings in invalid {
+            assert!(settings.validate().is_err(), "{settings:?}");
+        }
+    }
+
+    #[test]
+    fn test_effective() {
+        let settings = H2Settings {
+            initial_stream_window_size: Some(u32::MAX),
+            max_frame_size: Some(1),
+            ..Default::default()
+        };
+        let server = settings.effective_server();
+        assert_eq!(server.max_concurrent_streams, None);
+        assert_eq!(server.initial_stream_window_size, Some(MAX_WINDOW_SIZE));
+        assert_eq!(server.initial_connection_window_size, Some(65_535));
+        assert_eq!(server.max_frame_size, Some(MIN_FRAME_SIZE));
+        assert_eq!(server.max_header_list_size, Some(16 << 20));
+
+        let client = H2Settings::default().effective_client();
+        assert_eq!(client.initial_stream_window_size, Some(1 << 23));
+        assert_eq!(client.initial_connection_window_size, Some(1 << 23));
+        assert_eq!(client.max_frame_size, Some(64 * 1024));
+
+        let settings = H2Settings {
+            max_concurrent_streams: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(settings.max_client_streams(100), 10);
+        assert_eq!(settings.max_client_streams(1), 1);
+        assert_eq!(H2Settings::default().max_client_streams(100), 100);
+    }
+
+    #[test]
+    fn test_serde() {
+        let settings: H2Settings =
+            serde_yaml::from_str("max_concurrent_streams: 100\nmax_frame_size: 32768").unwrap();
+        assert_eq!(settings.max_concurrent_streams, Some(100));
+        assert_eq!(settings.max_frame_size, Some(32768));
+        assert_eq!(settings.initial_stream_window_size, None);
+    }
+}
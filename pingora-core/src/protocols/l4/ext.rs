@@ -142,6 +142,130 @@ fn ip_bind_addr_no_port(_fd: RawFd, _val: bool) -> io::Result<()> {
     Ok(())
 }
 
+#[cfg(target_os = "linux")]
+fn set_bind_to_device(fd: RawFd, device: &str) -> io::Result<()> {
+    // the payload is the interface name, not a fixed size value
+    unsafe {
+        cvt_linux_error(libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr() as *const c_void,
+            device.len() as socklen_t,
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_bind_to_device(_fd: RawFd, _device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "SO_BINDTODEVICE is only supported on Linux",
+    ))
+}
+
+/// Set or clear `IP_TRANSPARENT` (or `IPV6_TRANSPARENT`) on the given socket. When set, the
+/// socket can accept the connections intercepted by `TPROXY` or bind to a non-local address.
+///
+/// `CAP_NET_ADMIN` is required to set it.
+#[cfg(all(feature = "transparent_proxy", target_os = "linux"))]
+pub fn set_ip_transparent(fd: RawFd, ipv6: bool, enable: bool) -> io::Result<()> {
+    let value = enable as c_int;
+    if ipv6 {
+        set_opt(fd, libc::SOL_IPV6, libc::IPV6_TRANSPARENT, value)
+    } else {
+        set_opt(fd, libc::SOL_IP, libc::IP_TRANSPARENT, value)
+    }
+}
+
+/// Set or clear `IP_TRANSPARENT` (or `IPV6_TRANSPARENT`) on the given socket. When set, the
+/// socket can accept the connections intercepted by `TPROXY` or bind to a non-local address.
+///
+/// Setting it is only supported on Linux with the `transparent_proxy` feature. Clearing it is a
+/// noop otherwise, since it can't be set.
+#[cfg(not(all(feature = "transparent_proxy", target_os = "linux")))]
+pub fn set_ip_transparent(_fd: RawFd, _ipv6: bool, enable: bool) -> io::Result<()> {
+    if enable {
+        Err(transparent_proxy_unsupported())
+    } else {
+        Ok(())
+    }
+}
+
+/// Get the destination address the client originally connected to before the connection was
+/// intercepted by an iptables `REDIRECT` or `TPROXY` rule
+///
+/// `SO_ORIGINAL_DST` is used for the redirected (NAT'd) connections. Other connections, including
+/// the ones intercepted by `TPROXY`, are not NAT'd so their local address is returned.
+#[cfg(all(feature = "transparent_proxy", target_os = "linux"))]
+pub fn get_original_dst(fd: RawFd) -> io::Result<SocketAddr> {
+    const IP6T_SO_ORIGINAL_DST: c_int = 80;
+
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+    cvt_linux_error(unsafe {
+        libc::getsockname(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len)
+    })?;
+    let local = sockaddr_to_std(&storage)?;
+
+    let (level, opt) = if local.is_ipv6() {
+        (libc::SOL_IPV6, IP6T_SO_ORIGINAL_DST)
+    } else {
+        (libc::SOL_IP, libc::SO_ORIGINAL_DST)
+    };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+    match get_opt(fd, level, opt, &mut storage, &mut len) {
+        Ok(()) => sockaddr_to_std(&storage),
+        // not redirected, or conntrack is not loaded at all
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOENT | libc::ENOPROTOOPT)) => Ok(local),
+        Err(e) => Err(e),
+    }
+}
+
+/// Get the destination address the client originally connected to before the connection was
+/// intercepted by an iptables `REDIRECT` or `TPROXY` rule
+///
+/// Only supported on Linux with the `transparent_proxy` feature.
+#[cfg(not(all(feature = "transparent_proxy", target_os = "linux")))]
+pub fn get_original_dst(_fd: RawFd) -> io::Result<SocketAddr> {
+    Err(transparent_proxy_unsupported())
+}
+
+#[cfg(not(all(feature = "transparent_proxy", target_os = "linux")))]
+fn transparent_proxy_unsupported() -> io::Error {
+    io::Error::new(
+        ErrorKind::Unsupported,
+        "transparent proxying requires the transparent_proxy feature on Linux",
+    )
+}
+
+#[cfg(all(feature = "transparent_proxy", target_os = "linux"))]
+fn sockaddr_to_std(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+
+    match storage.ss_family as c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::from((ip, u16::from_be(addr.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        family => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("not an IP socket, address family {family}"),
+        )),
+    }
+}
+
 #[cfg(target_os = "linux")]
 fn set_so_keepalive(fd: RawFd, val: bool) -> io::Result<()> {
     set_opt(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, val as c_int)
@@ -221,6 +345,39 @@ pub fn get_tcp_info(_fd: RawFd) -> io::Result<TCP_INFO> {
 ///
 /// `IP_BIND_ADDRESS_NO_PORT` is used.
 pub async fn connect(addr: &SocketAddr, bind_to: Option<&SocketAddr>) -> Result<TcpStream> {
+    connect_with_device(addr, bind_to, None).await
+}
+
+/// connect() to the given address while optionally bind to the specific source address and
+/// network interface (`SO_BINDTODEVICE`)
+///
+/// `IP_BIND_ADDRESS_NO_PORT` is used.
+pub async fn connect_with_device(
+    addr: &SocketAddr,
+    bind_to: Option<&SocketAddr>,
+    device: Option<&str>,
+) -> Result<TcpStream> {
+    connect_socket(addr, bind_to, device, false).await
+}
+
+/// connect() to the given address from the given source address which doesn't need to be local,
+/// e.g., the address of the downstream client for a transparent proxy to spoof it
+///
+/// `IP_TRANSPARENT` is set before binding, see [set_ip_transparent()].
+pub async fn connect_transparent(
+    addr: &SocketAddr,
+    bind_to: &SocketAddr,
+    device: Option<&str>,
+) -> Result<TcpStream> {
+    connect_socket(addr, Some(bind_to), device, true).await
+}
+
+async fn connect_socket(
+    addr: &SocketAddr,
+    bind_to: Option<&SocketAddr>,
+    device: Option<&str>,
+    transparent: bool,
+) -> Result<TcpStream> {
     let socket = if addr.is_ipv4() {
         TcpSocket::new_v4()
     } else {
@@ -228,6 +385,16 @@ pub async fn connect(addr: &SocketAddr, bind_to: Option<&SocketAddr>) -> Result<
     }
     .or_err(SocketError, "failed to create socket")?;
 
+    if let Some(device) = device {
+        set_bind_to_device(socket.as_raw_fd(), device)
+            .or_err_with(BindError, || format!("failed to bind to device {device}"))?;
+    }
+
+    if transparent {
+        set_ip_transparent(socket.as_raw_fd(), addr.is_ipv6(), true)
+            .or_err(BindError, "failed to set IP_TRANSPARENT")?;
+    }
+
     if cfg!(target_os = "linux") {
         ip_bind_addr_no_port(socket.as_raw_fd(), true)
             .or_err(SocketError, "failed to set socket opts")?;
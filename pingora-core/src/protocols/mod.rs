@@ -60,6 +60,19 @@ pub trait Ssl {
         let ssl = self.get_ssl()?;
         ALPN::from_wire_selected(ssl.selected_alpn_protocol()?)
     }
+
+    /// Return the raw ALPN protocol negotiated during the TLS handshake if any
+    ///
+    /// Unlike [`Self::selected_alpn_proto()`], this also returns protocols that are not HTTP.
+    fn negotiated_alpn(&self) -> Option<&[u8]> {
+        self.get_ssl()?.selected_alpn_protocol()
+    }
+
+    /// Whether the TLS 1.3 early data (0-RTT) of the client is not fully read yet, so the next
+    /// read may return data that can be replayed
+    fn early_data_pending(&self) -> bool {
+        false
+    }
 }
 
 use std::any::Any;
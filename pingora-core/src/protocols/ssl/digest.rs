@@ -14,7 +14,11 @@
 
 //! TLS information from the TLS connection
 
-use crate::tls::{hash::MessageDigest, ssl::SslRef};
+use crate::tls::{
+    ext,
+    hash::MessageDigest,
+    ssl::{NameType, SslRef},
+};
 use crate::utils;
 
 /// The TLS connection information
@@ -24,6 +28,12 @@ pub struct SslDigest {
     pub cipher: &'static str,
     /// The TLS version of this connection
     pub version: &'static str,
+    /// The SNI (server name indication) sent by the client, if any
+    pub sni: Option<String>,
+    /// The protocol negotiated via ALPN, e.g., `h2`, if any
+    pub alpn: Option<String>,
+    /// Whether the TLS 1.3 early data (0-RTT) of the client was accepted, which can be replayed
+    pub early_data: bool,
     /// The organization of the peer's certificate
     pub organization: Option<String>,
     /// The serial number of the peer's certificate
@@ -57,9 +67,20 @@ impl SslDigest {
         SslDigest {
             cipher,
             version: ssl.version_str(),
+            sni: ssl.servername(NameType::HOST_NAME).map(|s| s.to_string()),
+            alpn: ssl
+                .selected_alpn_protocol()
+                .map(|p| String::from_utf8_lossy(p).into_owned()),
+            early_data: ext::ssl_early_data_accepted(ssl),
             organization: org,
             serial_number: sn,
             cert_digest,
         }
     }
+
+    /// Whether the TLS version of this connection is older than TLS 1.2, which is deprecated by
+    /// RFC 8996, e.g., to reject such connections
+    pub fn is_legacy_version(&self) -> bool {
+        matches!(self.version, "SSLv2" | "SSLv3" | "TLSv1" | "TLSv1.1")
+    }
 }
@@ -16,11 +16,13 @@
 
 pub mod client;
 pub mod digest;
+pub mod provider;
 pub mod server;
 
 use crate::protocols::digest::TimingDigest;
 use crate::protocols::{Ssl, UniqueID};
 use crate::tls::{self, ssl, tokio_ssl::SslStream as InnerSsl};
+use bytes::Bytes;
 use log::warn;
 use pingora_error::{ErrorType::*, OrErr, Result};
 use std::pin::Pin;
@@ -30,6 +32,7 @@ use std::time::SystemTime;
 use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
 
 pub use digest::SslDigest;
+pub use provider::TlsProvider;
 
 /// The TLS connection
 #[derive(Debug)]
@@ -37,6 +40,8 @@ pub struct SslStream<T> {
     ssl: InnerSsl<T>,
     digest: Option<Arc<SslDigest>>,
     timing: TimingDigest,
+    // the early data read during the handshake, returned before anything else is read
+    early_data: Bytes,
 }
 
 impl<T> SslStream<T>
@@ -55,6 +60,7 @@ where
             ssl,
             digest: None,
             timing: Default::default(),
+            early_data: Bytes::new(),
         })
     }
 
@@ -76,6 +82,31 @@ where
         Ok(())
     }
 
+    /// Finish the TLS handshake from client as a server, accepting the TLS 1.3 early data (0-RTT)
+    /// the client sends along with it, if enabled by the configuration of this connection.
+    ///
+    /// The early data is returned by the reads that follow as if it was sent after the
+    /// handshake. Whether there was any is told by [`SslDigest::early_data`].
+    pub async fn accept_with_early_data(&mut self) -> Result<(), ssl::Error> {
+        // OpenSSL only accepts the early data read before finishing the handshake, while
+        // BoringSSL finishes the handshake early and returns the early data via normal reads.
+        #[cfg(not(feature = "boringssl"))]
+        {
+            Self::clear_error();
+            let mut early_data = bytes::BytesMut::new();
+            let mut buf = vec![0; 4096];
+            loop {
+                let n = Pin::new(&mut self.ssl).read_early_data(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                early_data.extend_from_slice(&buf[..n]);
+            }
+            self.early_data = early_data.freeze();
+        }
+        self.accept().await
+    }
+
     #[inline]
     fn clear_error() {
         let errs = tls::error::ErrorStack::get();
@@ -116,6 +147,11 @@ where
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
+        if !self.early_data.is_empty() && buf.remaining() > 0 {
+            let n = buf.remaining().min(self.early_data.len());
+            buf.put_slice(&self.early_data.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
         Self::clear_error();
         Pin::new(&mut self.ssl).poll_read(cx, buf)
     }
@@ -175,6 +211,18 @@ impl<T> Ssl for SslStream<T> {
     fn get_ssl_digest(&self) -> Option<Arc<SslDigest>> {
         self.ssl_digest()
     }
+
+    fn early_data_pending(&self) -> bool {
+        // BoringSSL returns the early data via normal reads until the handshake is confirmed
+        #[cfg(feature = "boringssl")]
+        {
+            tls::ext::ssl_in_early_data(self.ssl())
+        }
+        #[cfg(not(feature = "boringssl"))]
+        {
+            !self.early_data.is_empty()
+        }
+    }
 }
 
 /// The protocol for Application-Layer Protocol Negotiation
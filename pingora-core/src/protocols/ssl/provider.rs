@@ -0,0 +1,118 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The TLS library this build uses
+//!
+//! The TLS provider is selected at build time by the cargo features: `openssl` (the default) or
+//! `boringssl`, which takes precedence when both are enabled. This module only tells which one is
+//! in use, so that a deployment can require a particular provider.
+
+use pingora_error::{Error, ErrorType::InternalError, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The TLS libraries pingora can be built with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsProvider {
+    /// OpenSSL, the `openssl` feature
+    OpenSsl,
+    /// BoringSSL, the `boringssl` feature
+    BoringSsl,
+}
+
+impl TlsProvider {
+    /// The provider of this build
+    pub const fn current() -> Self {
+        if cfg!(feature = "boringssl") {
+            TlsProvider::BoringSsl
+        } else {
+            TlsProvider::OpenSsl
+        }
+    }
+
+    /// The name of the provider, the same as its cargo feature
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TlsProvider::OpenSsl => "openssl",
+            TlsProvider::BoringSsl => "boringssl",
+        }
+    }
+
+    /// The version of the library of the [Self::current()] provider this process runs with, e.g.,
+    /// `OpenSSL 3.0.2 15 Mar 2022`
+    pub fn library_version() -> &'static str {
+        crate::tls::version::version()
+    }
+
+    /// Fail if this build doesn't use the `expected` provider
+    ///
+    /// This is how a deployment that requires a particular provider, e.g., for FIPS compliance,
+    /// refuses to run a binary built with another one, see `tls_provider` of the server
+    /// configuration.
+    pub fn check(expected: TlsProvider) -> Result<()> {
+        let current = Self::current();
+        if current == expected {
+            return Ok(());
+        }
+        Error::e_explain(
+            InternalError,
+            format!("the TLS provider {expected} is required but this build uses {current}"),
+        )
+    }
+}
+
+impl fmt::Display for TlsProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current_provider() {
+        #[cfg(feature = "boringssl")]
+        {
+            assert_eq!(TlsProvider::current(), TlsProvider::BoringSsl);
+            assert!(TlsProvider::library_version().contains("BoringSSL"));
+        }
+        #[cfg(not(feature = "boringssl"))]
+        {
+            assert_eq!(TlsProvider::current(), TlsProvider::OpenSsl);
+            assert!(!TlsProvider::library_version().is_empty());
+        }
+
+        assert!(TlsProvider::check(TlsProvider::current()).is_ok());
+        let other = match TlsProvider::current() {
+            TlsProvider::OpenSsl => TlsProvider::BoringSsl,
+            TlsProvider::BoringSsl => TlsProvider::OpenSsl,
+        };
+        let e = TlsProvider::check(other).unwrap_err();
+        assert_eq!(e.etype(), &pingora_error::ErrorType::InternalError);
+        assert!(!e.retry());
+    }
+
+    #[test]
+    fn test_provider_serde() {
+        let provider: TlsProvider = serde_yaml::from_str("boringssl").unwrap();
+        assert_eq!(provider, TlsProvider::BoringSsl);
+        assert_eq!(provider.to_string(), "boringssl");
+        let provider: TlsProvider = serde_yaml::from_str("openssl").unwrap();
+        assert_eq!(provider, TlsProvider::OpenSsl);
+        assert!(serde_yaml::from_str::<TlsProvider>("OpenSSL").is_err());
+    }
+}
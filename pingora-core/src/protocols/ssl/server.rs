@@ -44,6 +44,20 @@ pub async fn handshake<S: IO>(ssl_acceptor: &SslAcceptor, io: S) -> Result<SslSt
     Ok(stream)
 }
 
+/// Perform TLS handshake like [handshake()], accepting the TLS 1.3 early data (0-RTT) of the
+/// client if the `ssl_acceptor` is configured to, see [`crate::tls::ext::ssl_ctx_set_max_early_data()`]
+pub async fn handshake_with_early_data<S: IO>(
+    ssl_acceptor: &SslAcceptor,
+    io: S,
+) -> Result<SslStream<S>> {
+    let mut stream = prepare_tls_stream(ssl_acceptor, io)?;
+    stream
+        .accept_with_early_data()
+        .await
+        .explain_err(TLSHandshakeFailure, |e| format!("TLS accept() failed: {e}"))?;
+    Ok(stream)
+}
+
 /// Perform TLS handshake for the given connection with the given configuration and callbacks
 pub async fn handshake_with_callback<S: IO>(
     ssl_acceptor: &SslAcceptor,
@@ -191,3 +205,43 @@ async fn test_async_cert() {
         .await
         .unwrap();
 }
+
+#[tokio::test]
+async fn test_tls_info() {
+    use tokio::io::AsyncReadExt;
+    let cert = format!("{}/tests/keys/server.crt", env!("CARGO_MANIFEST_DIR"));
+    let key = format!("{}/tests/keys/key.pem", env!("CARGO_MANIFEST_DIR"));
+    let mut acceptor = ssl::SslAcceptor::mozilla_intermediate_v5(ssl::SslMethod::tls()).unwrap();
+    acceptor
+        .set_private_key_file(key, ssl::SslFiletype::PEM)
+        .unwrap();
+    acceptor.set_certificate_chain_file(cert).unwrap();
+    acceptor.set_alpn_select_callback(|_, alpn| {
+        ssl::select_next_proto(b"\x02h2", alpn).ok_or(ssl::AlpnError::NOACK)
+    });
+    let acceptor = acceptor.build();
+
+    let (client, server) = tokio::io::duplex(1024);
+
+    tokio::spawn(async move {
+        let ssl_context = ssl::SslContext::builder(ssl::SslMethod::tls())
+            .unwrap()
+            .build();
+        let mut ssl = ssl::Ssl::new(&ssl_context).unwrap();
+        ssl.set_hostname("pingora.org").unwrap();
+        ssl.set_alpn_protos(b"\x02h2").unwrap();
+        ssl.set_verify(ssl::SslVerifyMode::NONE); // we don have a valid cert
+        let mut stream = SslStream::new(ssl, client).unwrap();
+        Pin::new(&mut stream).connect().await.unwrap();
+        let mut buf = [0; 1];
+        let _ = stream.read(&mut buf).await;
+    });
+
+    let stream = handshake(&acceptor, server).await.unwrap();
+    let digest = stream.ssl_digest().unwrap();
+    assert_eq!(digest.sni.as_deref(), Some("pingora.org"));
+    assert_eq!(digest.alpn.as_deref(), Some("h2"));
+    assert!(!digest.version.is_empty());
+    assert!(!digest.cipher.is_empty());
+    assert!(!digest.is_legacy_version());
+}
@@ -0,0 +1,241 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Durations with units in the configuration
+//!
+//! A duration is written as a sequence of numbers with units, e.g., `500ms`, `5m` or `1h 30m`.
+//! The units are `ns`, `us`, `ms`, `s`, `m`, `h` and `d`. A bare integer is in seconds so that the
+//! configurations written before the units were supported keep working.
+//!
+//! Use `#[serde(with = "pingora_core::server::configuration::duration::option")]` on an
+//! `Option<Duration>` field of a user defined configuration to parse it the same way.
+
+use pingora_error::{Error, ErrorType::ReadError, Result};
+use serde::de::{self, Deserializer, Visitor};
+use serde::Serializer;
+use std::fmt;
+use std::time::Duration;
+
+/// Parse a duration with units, or a bare integer of seconds
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    let invalid = || Error::e_explain(ReadError, format!("invalid duration {s:?}"));
+    if s.is_empty() {
+        return invalid();
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let Ok(value) = rest[..digits].parse::<u64>() else {
+            return invalid();
+        };
+        rest = &rest[digits..];
+        let unit_len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let duration = match &rest[..unit_len] {
+            "ns" => Some(Duration::from_nanos(value)),
+            "us" => Some(Duration::from_micros(value)),
+            "ms" => Some(Duration::from_millis(value)),
+            "s" => Some(Duration::from_secs(value)),
+            "m" => value.checked_mul(60).map(Duration::from_secs),
+            "h" => value.checked_mul(3600).map(Duration::from_secs),
+            "d" => value.checked_mul(86400).map(Duration::from_secs),
+            _ => return invalid(),
+        };
+        let Some(sum) = duration.and_then(|d| total.checked_add(d)) else {
+            return invalid();
+        };
+        total = sum;
+        rest = rest[unit_len..].trim_start();
+    }
+    Ok(total)
+}
+
+/// Format a duration in the largest unit that represents it exactly, e.g., `500ms` or `5m`
+pub fn format_duration(d: Duration) -> String {
+    let nanos = d.as_nanos();
+    if nanos == 0 {
+        return "0s".to_string();
+    }
+    const UNITS: [(&str, u128); 7] = [
+        ("d", 86_400_000_000_000),
+        ("h", 3_600_000_000_000),
+        ("m", 60_000_000_000),
+        ("s", 1_000_000_000),
+        ("ms", 1_000_000),
+        ("us", 1_000),
+        ("ns", 1),
+    ];
+    // "ns" always divides
+    let (unit, size) = UNITS
+        .iter()
+        .find(|(_, size)| nanos.checked_rem(*size) == Some(0))
+        .unwrap();
+    format!("{}{unit}", nanos / size)
+}
+
+struct DurationVisitor;
+
+impl<'de> Visitor<'de> for DurationVisitor {
+    type Value = Duration;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a duration like 500ms or 5m, or an integer of seconds")
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Duration, E> {
+        Ok(Duration::from_secs(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Duration, E> {
+        u64::try_from(v)
+            .map(Duration::from_secs)
+            .map_err(|_| E::custom(format!("negative duration {v}")))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Duration, E> {
+        parse_duration(v).map_err(|e| E::custom(e.to_string()))
+    }
+}
+
+/// Serialize the duration with units, see [format_duration()]
+pub fn serialize<S: Serializer>(d: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_duration(*d))
+}
+
+/// Deserialize a duration with units, or a bare integer of seconds
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    deserializer.deserialize_any(DurationVisitor)
+}
+
+/// Serde helpers for `Option<Duration>` fields
+pub mod option {
+    use super::*;
+
+    struct OptionVisitor;
+
+    impl<'de> Visitor<'de> for OptionVisitor {
+        type Value = Option<Duration>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            DurationVisitor.expecting(f)
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Self::Value, D::Error> {
+            super::deserialize(d).map(Some)
+        }
+    }
+
+    /// Serialize the duration with units, see [format_duration()]
+    pub fn serialize<S: Serializer>(
+        d: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match d {
+            Some(d) => super::serialize(d, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    /// Deserialize a duration with units, or a bare integer of seconds
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        deserializer.deserialize_option(OptionVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("5").unwrap(), Duration::from_secs(5));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1h 30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(
+            parse_duration("1s500ms").unwrap(),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(parse_duration("2d").unwrap(), Duration::from_secs(172_800));
+        assert_eq!(parse_duration("10us").unwrap(), Duration::from_micros(10));
+        for invalid in ["", "ms", "5x", "-5s", "1.5s", "5 s 3"] {
+            assert!(parse_duration(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::ZERO), "0s");
+        assert_eq!(format_duration(Duration::from_millis(500)), "500ms");
+        assert_eq!(format_duration(Duration::from_secs(300)), "5m");
+        assert_eq!(format_duration(Duration::from_secs(90)), "90s");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1500ms");
+    }
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(default)]
+    struct Conf {
+        #[serde(with = "super")]
+        timeout: Duration,
+        #[serde(with = "option")]
+        grace: Option<Duration>,
+    }
+
+    #[test]
+    fn test_serde() {
+        let conf: Conf = serde_yaml::from_str("timeout: 500ms\ngrace: 5m").unwrap();
+        assert_eq!(conf.timeout, Duration::from_millis(500));
+        assert_eq!(conf.grace, Some(Duration::from_secs(300)));
+
+        // bare integers are seconds
+        let conf: Conf = serde_yaml::from_str("timeout: 3\ngrace: 60").unwrap();
+        assert_eq!(conf.timeout, Duration::from_secs(3));
+        assert_eq!(conf.grace, Some(Duration::from_secs(60)));
+
+        let conf: Conf = serde_yaml::from_str("grace: ~").unwrap();
+        assert_eq!(conf.grace, None);
+        let conf: Conf = serde_yaml::from_str("timeout: 1").unwrap();
+        assert_eq!(conf.grace, None);
+
+        assert!(serde_yaml::from_str::<Conf>("timeout: soon").is_err());
+        assert!(serde_yaml::from_str::<Conf>("timeout: -1").is_err());
+
+        let conf = Conf {
+            timeout: Duration::from_millis(1500),
+            grace: Some(Duration::from_secs(300)),
+        };
+        let yaml = serde_yaml::to_string(&conf).unwrap();
+        assert_eq!(serde_yaml::from_str::<Conf>(&yaml).unwrap(), conf);
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Split a configuration across multiple files
+//!
+//! A configuration file can list other files to merge under the `include` key:
+//! ```yaml
+//! ---
+//! version: 1
+//! include:
+//!     - base.yaml
+//!     - prod.yaml
+//! threads: 8
+//! ```
+//! The included files are merged in the order listed, and then the keys of the including file
+//! itself are merged on top. So later files override the earlier ones. Maps are merged key by key,
+//! scalars are replaced and lists are replaced or appended according to `include_list_merge` of
+//! the top level file, see [ListMerge]. Relative paths are relative to the directory of the file
+//! that includes them. Included files can include other files, but not in a cycle.
+//!
+//! User defined configurations can call [load_yaml_file()] to get the same merged YAML to parse.
+
+use log::debug;
+use pingora_error::{Error, ErrorType::ReadError, OrErr, Result};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const INCLUDE_KEY: &str = "include";
+const LIST_MERGE_KEY: &str = "include_list_merge";
+
+/// How the lists of the included files are merged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListMerge {
+    /// The list of the later file replaces the earlier one, the default
+    #[default]
+    Replace,
+    /// The list of the later file is appended to the earlier one
+    Append,
+}
+
+/// Deep merge `overlay` into `base`
+pub fn merge_yaml(base: &mut Value, overlay: Value, lists: ListMerge) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_yaml(existing, value, lists),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Sequence(base), Value::Sequence(overlay)) if lists == ListMerge::Append => {
+            base.extend(overlay);
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Load the YAML file at `path` with all its includes merged
+pub fn load_yaml_file<P: AsRef<Path>>(path: P) -> Result<Value> {
+    let path = path.as_ref();
+    let doc = read_yaml(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let chain = vec![canonical(path)?];
+    resolve_root(doc, dir, chain)
+}
+
+/// Merge the includes of the YAML document into it, the relative paths are relative to `dir`
+pub fn resolve_includes(doc: Value, dir: &Path) -> Result<Value> {
+    resolve_root(doc, dir, vec![])
+}
+
+fn resolve_root(doc: Value, dir: &Path, mut chain: Vec<PathBuf>) -> Result<Value> {
+    let lists = match doc.get(LIST_MERGE_KEY) {
+        Some(v) => serde_yaml::from_value(v.clone()).or_err(
+            ReadError,
+            "invalid include_list_merge, expect replace or append",
+        )?,
+        None => ListMerge::default(),
+    };
+    let include = doc.get(INCLUDE_KEY).cloned();
+    let mut merged = resolve(doc, dir, &mut chain, lists)?;
+    // keep the includes of the top level file for the record
+    if let (Value::Mapping(merged), Some(include)) = (&mut merged, include) {
+        merged.insert(INCLUDE_KEY.into(), include);
+    }
+    Ok(merged)
+}
+
+fn resolve(
+    mut doc: Value,
+    dir: &Path,
+    chain: &mut Vec<PathBuf>,
+    lists: ListMerge,
+) -> Result<Value> {
+    let include = match &mut doc {
+        Value::Mapping(m) => m.remove(&Value::from(INCLUDE_KEY)),
+        _ => None,
+    };
+    let paths: Vec<String> = match include {
+        Some(include) => serde_yaml::from_value(include)
+            .or_err(ReadError, "invalid include, expect a list of paths")?,
+        None => return Ok(doc),
+    };
+
+    let mut merged = Value::Mapping(Default::default());
+    for path in paths {
+        let path = dir.join(path);
+        let canonical_path = canonical(&path)?;
+        if let Some(start) = chain.iter().position(|p| *p == canonical_path) {
+            let cycle: Vec<_> = chain[start..]
+                .iter()
+                .chain(std::iter::once(&canonical_path))
+                .map(|p| p.display().to_string())
+                .collect();
+            return Error::e_explain(ReadError, format!("include cycle: {}", cycle.join(" -> ")));
+        }
+        debug!("Including conf file {}", path.display());
+        let included = read_yaml(&path)?;
+        let included_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        chain.push(canonical_path);
+        let included = resolve(included, included_dir, chain, lists)?;
+        chain.pop();
+        merge_yaml(&mut merged, included, lists);
+    }
+    merge_yaml(&mut merged, doc, lists);
+    Ok(merged)
+}
+
+fn canonical(path: &Path) -> Result<PathBuf> {
+    path.canonicalize().or_err_with(ReadError, || {
+        format!("Unable to find conf file {}", path.display())
+    })
+}
+
+fn read_yaml(path: &Path) -> Result<Value> {
+    let conf_str = fs::read_to_string(path).or_err_with(ReadError, || {
+        format!("Unable to read conf file from {}", path.display())
+    })?;
+    serde_yaml::from_str(&conf_str).or_err_with(ReadError, || {
+        format!("Unable to parse yaml conf file {}", path.display())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    // a fresh directory for the conf files of each test
+    fn conf_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "pingora-conf-include-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_merge_yaml() {
+        let base = yaml("a: 1\nb:\n  c: 2\n  d: [1, 2]\ne: [x]");
+        let overlay = yaml("a: 3\nb:\n  d: [3]\n  f: 4");
+
+        let mut replaced = base.clone();
+        merge_yaml(&mut replaced, overlay.clone(), ListMerge::Replace);
+        assert_eq!(replaced, yaml("a: 3\nb:\n  c: 2\n  d: [3]\n  f: 4\ne: [x]"));
+
+        let mut appended = base;
+        merge_yaml(&mut appended, overlay, ListMerge::Append);
+        assert_eq!(
+            appended,
+            yaml("a: 3\nb:\n  c: 2\n  d: [1, 2, 3]\n  f: 4\ne: [x]")
+        );
+    }
+
+    #[test]
+    fn test_load_yaml_file() {
+        let dir = conf_dir("load");
+        fs::create_dir(dir.join("env")).unwrap();
+        fs::write(
+            dir.join("base.yaml"),
+            "threads: 1\nlist: [a]\npid_file: /base.pid",
+        )
+        .unwrap();
+        // relative to the directory of env/prod.yaml
+        fs::write(
+            dir.join("env/prod.yaml"),
+            "include: [../base.yaml]\nthreads: 4\nlist: [b]",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("main.yaml"),
+            "version: 1\ninclude: [env/prod.yaml]\nlist: [c]",
+        )
+        .unwrap();
+
+        let conf = load_yaml_file(dir.join("main.yaml")).unwrap();
+        assert_eq!(conf["threads"], yaml("4"));
+        assert_eq!(conf["pid_file"], yaml("/base.pid"));
+        assert_eq!(conf["list"], yaml("[c]"));
+        assert_eq!(conf["include"], yaml("[env/prod.yaml]"));
+
+        fs::write(
+            dir.join("main.yaml"),
+            "version: 1\ninclude: [env/prod.yaml]\ninclude_list_merge: append\nlist: [c]",
+        )
+        .unwrap();
+        let conf = load_yaml_file(dir.join("main.yaml")).unwrap();
+        assert_eq!(conf["list"], yaml("[a, b, c]"));
+
+        assert!(resolve_includes(yaml("include: [missing.yaml]"), &dir).is_err());
+        assert!(resolve_includes(yaml("include: base.yaml"), &dir).is_err());
+        let conf = resolve_includes(yaml("include: [base.yaml]\nthreads: 2"), &dir).unwrap();
+        assert_eq!(conf["threads"], yaml("2"));
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = conf_dir("cycle");
+        fs::write(dir.join("a.yaml"), "include: [b.yaml]").unwrap();
+        fs::write(dir.join("b.yaml"), "include: [c.yaml]").unwrap();
+        fs::write(dir.join("c.yaml"), "include: [b.yaml]").unwrap();
+        fs::write(dir.join("shared.yaml"), "threads: 2").unwrap();
+        // including the same file twice is not a cycle
+        fs::write(dir.join("d.yaml"), "include: [shared.yaml, e.yaml]").unwrap();
+        fs::write(dir.join("e.yaml"), "include: [shared.yaml]").unwrap();
+
+        let e = load_yaml_file(dir.join("a.yaml")).unwrap_err();
+        let msg = e.to_string();
+        assert!(msg.contains("include cycle"), "{msg}");
+        assert!(msg.contains("b.yaml -> "), "{msg}");
+        assert!(msg.contains("c.yaml -> "), "{msg}");
+        assert!(!msg.contains("a.yaml"), "{msg}");
+
+        let conf = load_yaml_file(dir.join("d.yaml")).unwrap();
+        assert_eq!(conf["threads"], yaml("2"));
+    }
+}
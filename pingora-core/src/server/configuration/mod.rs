@@ -19,10 +19,19 @@
 //! * Number of threads per service
 //! * Error log file path
 
+pub mod duration;
+pub mod include;
+
+use crate::protocols::http::v2::settings::H2Settings;
+use crate::protocols::ssl::TlsProvider;
+use duration::parse_duration;
+use include::ListMerge;
 use log::{debug, trace};
 use pingora_error::{Error, ErrorType::*, OrErr, Result};
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
 use structopt::StructOpt;
 
 /// The configuration file
@@ -33,15 +42,33 @@ use structopt::StructOpt;
 /// # Extension
 /// New keys can be added to the configuration files which this configuration object will ignore.
 /// Then, users can parse these key-values to pass to their code to use.
+///
+/// # Include
+/// Other configuration files listed under `include` are merged into this one, see [include].
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(default)]
 pub struct ServerConf {
     /// Version
     pub version: usize,
+    /// The configuration files to merge before the rest of this file, see [include]
+    pub include: Vec<String>,
+    /// Whether the lists of the included files are replaced or appended. Default replace.
+    pub include_list_merge: ListMerge,
     /// Whether to run this process in the background.
     pub daemon: bool,
     /// When configured, error log will be written to the given file. Otherwise StdErr will be used.
     pub error_log: Option<String>,
+    /// When daemonized, redirect the stdout of the process to the given file, in append mode.
+    /// Otherwise the output of e.g. `println!()` is discarded.
+    pub stdout_file: Option<String>,
+    /// When daemonized, redirect the stderr of the process, including the panic messages, to the
+    /// given file, in append mode. Take precedence over `error_log`.
+    pub stderr_file: Option<String>,
+    /// The working directory of the process after daemonization. Default `/`.
+    pub daemon_work_dir: String,
+    /// The umask of the process after daemonization. Default `0o007`, which allows the same group
+    /// to access the files created but not everyone else.
+    pub daemon_umask: u32,
     /// The pid (process ID) file of this server
     pub pid_file: String,
     /// the path to the upgrade socket
@@ -49,6 +76,18 @@ pub struct ServerConf {
     /// In order to perform zero downtime restart, both the new and old process need to agree on the
     /// path to this sock in order to coordinate the upgrade.
     pub upgrade_sock: String,
+    /// How long the new process waits for the old one to send the listening sockets during an
+    /// upgrade, e.g., `10s`. About 6 seconds if not set.
+    ///
+    /// `upgrade_timeout_seconds` is accepted as an alias. See [duration] for the format.
+    #[serde(with = "duration::option", alias = "upgrade_timeout_seconds")]
+    pub upgrade_timeout: Option<Duration>,
+    /// How long the old process keeps accepting after sending the listening sockets during an
+    /// upgrade, before it starts the graceful shutdown, e.g., `10s`. This is the window for the
+    /// new process to get ready, and for a second SIGQUIT to cancel the upgrade. 5 seconds if not
+    /// set. See [duration] for the format.
+    #[serde(with = "duration::option")]
+    pub upgrade_close_timeout: Option<Duration>,
     /// If configured, after daemonization, this process will switch to the given user before
     /// starting to serve traffic.
     pub user: Option<String>,
@@ -58,13 +97,47 @@ pub struct ServerConf {
     pub threads: usize,
     /// Allow work stealing between threads of the same service. Default `true`.
     pub work_stealing: bool,
+    /// The maximum number of threads for the blocking operations, e.g., disk cache IO or
+    /// blocking DNS resolution, of **each** service. With `work_stealing: false`, each thread of
+    /// the service gets this many. The server's own runtime, which sends the listening sockets
+    /// during graceful upgrades, uses it too. Must be at least 1. `None` to use tokio's default
+    /// (512). Services can override it via [`Service::max_blocking_threads()`].
+    ///
+    /// [`Service::max_blocking_threads()`]: crate::services::Service::max_blocking_threads
+    pub max_blocking_threads: Option<usize>,
+    /// How many threads the server's own runtime gets. The runtime runs the main loop, which
+    /// handles the signals, the configuration reloads and the graceful upgrades, so more threads
+    /// keep these control tasks from starving each other when the main loop also runs admin
+    /// tasks. Work stealing is always enabled on it. Default 1, must be at least 1.
+    pub main_loop_threads: usize,
     /// The path to CA file the SSL library should use. If empty, the default trust store location
     /// defined by the SSL library will be used.
     pub ca_file: Option<String>,
-    /// Grace period in seconds before starting the final step of the graceful shutdown after signaling shutdown.
-    pub grace_period_seconds: Option<u64>,
-    /// Timeout in seconds of the final step for the graceful shutdown.
-    pub graceful_shutdown_timeout_seconds: Option<u64>,
+    /// The TLS provider this server requires. The server refuses to start if it is built with
+    /// another one. Any provider is accepted if not set. See [TlsProvider].
+    pub tls_provider: Option<TlsProvider>,
+    /// Grace period before starting the final step of the graceful shutdown after signaling
+    /// shutdown, e.g., `5m`. 5 minutes if not set.
+    ///
+    /// `grace_period_seconds` is accepted as an alias. See [duration] for the format.
+    #[serde(with = "duration::option", alias = "grace_period_seconds")]
+    pub grace_period: Option<Duration>,
+    /// Timeout of the final step for the graceful shutdown, e.g., `5s`. 5 seconds if not set.
+    ///
+    /// `graceful_shutdown_timeout_seconds` is accepted as an alias. See [duration] for the format.
+    #[serde(with = "duration::option", alias = "graceful_shutdown_timeout_seconds")]
+    pub graceful_shutdown_timeout: Option<Duration>,
+    /// Close each downstream connection after serving this many requests on it so that the client
+    /// reconnects, which helps to redistribute the load. `None` (default) means no limit.
+    pub max_requests_per_connection: Option<usize>,
+    /// The HTTP/2 settings of the downstream connections. See [`H2Settings`] for the defaults.
+    pub h2_settings: Option<H2Settings>,
+    /// The maximum size of the request body that the proxy keeps a copy of in order to retry or
+    /// mirror the request. 64KiB if not set.
+    pub max_buffered_request_body: Option<usize>,
+    /// What to do with the requests whose bodies exceed `max_buffered_request_body`. Default
+    /// `stream`. See [OversizedRequestBody].
+    pub oversized_request_body: OversizedRequestBody,
     // These options don't belong here as they are specific to certain services
     /// IPv4 addresses for a client connector to bind to. See [`ConnectorOptions`].
     /// Note: this is an _unstable_ field that may be renamed or removed in the future.
@@ -83,43 +156,108 @@ pub struct ServerConf {
     /// See [`ConnectorOptions`].
     /// Note: this is an _unstable_ field that may be renamed or removed in the future.
     pub upstream_connect_offload_thread_per_pool: Option<usize>,
+    /// Close each upstream connection after sending this many requests on it.
+    /// See [`ConnectorOptions`].
+    pub upstream_max_requests_per_connection: Option<usize>,
+    /// The HTTP/2 settings of the upstream connections. See [`H2Settings`] for the defaults.
+    pub upstream_h2_settings: Option<H2Settings>,
+    /// The default timeout of connecting to the upstreams and of the TLS handshakes with them,
+    /// e.g., `1s`. The peers can set their own instead, see [`ConnectorOptions`]. No timeout if
+    /// not set. See [duration] for the format.
+    #[serde(with = "duration::option")]
+    pub upstream_connect_timeout: Option<Duration>,
+    /// The default timeout of the whole connection establishment to the upstreams. See
+    /// `upstream_connect_timeout`.
+    #[serde(with = "duration::option")]
+    pub upstream_total_connect_timeout: Option<Duration>,
+    /// The default timeout of each read from the upstreams. See `upstream_connect_timeout`.
+    #[serde(with = "duration::option")]
+    pub upstream_read_timeout: Option<Duration>,
+    /// The default timeout of each write to the upstreams. See `upstream_connect_timeout`.
+    #[serde(with = "duration::option")]
+    pub upstream_write_timeout: Option<Duration>,
 }
 
 impl Default for ServerConf {
     fn default() -> Self {
         ServerConf {
             version: 0,
+            include: vec![],
+            include_list_merge: ListMerge::Replace,
             client_bind_to_ipv4: vec![],
             client_bind_to_ipv6: vec![],
             ca_file: None,
+            tls_provider: None,
             daemon: false,
             error_log: None,
+            stdout_file: None,
+            stderr_file: None,
+            daemon_work_dir: "/".to_string(),
+            daemon_umask: 0o007,
             pid_file: "/tmp/pingora.pid".to_string(),
             upgrade_sock: "/tmp/pingora_upgrade.sock".to_string(),
+            upgrade_timeout: None,
+            upgrade_close_timeout: None,
             user: None,
             group: None,
             threads: 1,
             work_stealing: true,
+            max_blocking_threads: None,
+            main_loop_threads: 1,
             upstream_keepalive_pool_size: 128,
             upstream_connect_offload_threadpools: None,
             upstream_connect_offload_thread_per_pool: None,
-            grace_period_seconds: None,
-            graceful_shutdown_timeout_seconds: None,
+            upstream_max_requests_per_connection: None,
+            upstream_h2_settings: None,
+            upstream_connect_timeout: None,
+            upstream_total_connect_timeout: None,
+            upstream_read_timeout: None,
+            upstream_write_timeout: None,
+            grace_period: None,
+            graceful_shutdown_timeout: None,
+            max_requests_per_connection: None,
+            h2_settings: None,
+            max_buffered_request_body: None,
+            oversized_request_body: OversizedRequestBody::Stream,
         }
     }
 }
 
+/// How the proxy handles a request body larger than `max_buffered_request_body`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizedRequestBody {
+    /// Stop buffering and keep streaming the body to the upstream, the default. The request can
+    /// no longer be retried or mirrored.
+    #[default]
+    Stream,
+    /// Fail the request with `413 Payload Too Large`: right away, before connecting to the
+    /// upstream, if its `Content-Length` is larger, otherwise as soon as its body outgrows it.
+    Reject,
+}
+
+// the conf path to read the configuration from the stdin instead
+pub(crate) const STDIN_PATH: &str = "-";
+
 /// Command-line options
 ///
 /// Call `Opt::from_args()` to build this object from the process's command line arguments.
+///
+/// `-V` or `--version` prints the version of this crate and exits.
 #[derive(StructOpt, Debug)]
-#[structopt(name = "basic")]
+#[structopt(name = "basic", version = env!("CARGO_PKG_VERSION"))]
 pub struct Opt {
     /// Whether this server should try to upgrade from a running old server
     ///
     /// `-u` or `--upgrade` can be used
     #[structopt(short, long)]
     pub upgrade: bool,
+    /// How long to wait for the old server to send the listening sockets when upgrading,
+    /// overriding `upgrade_timeout` of the configuration file
+    ///
+    /// `--upgrade-timeout` can be used, with units like `10s` or bare seconds
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub upgrade_timeout: Option<Duration>,
     /// Whether should run this server in the background
     ///
     /// `-d` or `--daemon` can be used
@@ -139,7 +277,7 @@ pub struct Opt {
     /// `-t` or `--test` can be used
     #[structopt(short, long)]
     pub test: bool,
-    /// The path to the configuration file.
+    /// The path to the configuration file, `-` to read it from the stdin.
     ///
     /// See [`ServerConf`] for more details of the configuration file.
     ///
@@ -158,21 +296,65 @@ impl Default for Opt {
 }
 
 impl ServerConf {
+    /// The [Self::grace_period] in whole seconds
+    #[deprecated(note = "use the `grace_period` field instead")]
+    pub fn grace_period_seconds(&self) -> Option<u64> {
+        self.grace_period.map(|d| d.as_secs())
+    }
+
+    /// Set the [Self::grace_period] in seconds
+    #[deprecated(note = "use the `grace_period` field instead")]
+    pub fn set_grace_period_seconds(&mut self, seconds: Option<u64>) {
+        self.grace_period = seconds.map(Duration::from_secs);
+    }
+
+    /// The [Self::graceful_shutdown_timeout] in whole seconds
+    #[deprecated(note = "use the `graceful_shutdown_timeout` field instead")]
+    pub fn graceful_shutdown_timeout_seconds(&self) -> Option<u64> {
+        self.graceful_shutdown_timeout.map(|d| d.as_secs())
+    }
+
+    /// Set the [Self::graceful_shutdown_timeout] in seconds
+    #[deprecated(note = "use the `graceful_shutdown_timeout` field instead")]
+    pub fn set_graceful_shutdown_timeout_seconds(&mut self, seconds: Option<u64>) {
+        self.graceful_shutdown_timeout = seconds.map(Duration::from_secs);
+    }
+
     // Does not has to be async until we want runtime reload
+    /// Load the YAML configuration file, with the files it includes merged
     pub fn load_from_yaml<P>(path: P) -> Result<Self>
     where
         P: AsRef<std::path::Path> + std::fmt::Display,
     {
-        let conf_str = fs::read_to_string(&path).or_err_with(ReadError, || {
-            format!("Unable to read conf file from {path}")
-        })?;
+        let conf = include::load_yaml_file(&path)?;
         debug!("Conf file read from {path}");
+        Self::from_yaml_value(conf)
+    }
+
+    /// Load the YAML configuration from the stdin
+    pub fn load_from_stdin() -> Result<Self> {
+        Self::load_from_reader(std::io::stdin().lock())
+    }
+
+    fn load_from_reader<R: Read>(mut reader: R) -> Result<Self> {
+        let mut conf_str = String::new();
+        reader
+            .read_to_string(&mut conf_str)
+            .or_err(ReadError, "Unable to read conf from stdin")?;
+        debug!("Conf read from stdin");
         Self::from_yaml(&conf_str)
     }
 
+    /// Load the configuration file given by `opt.conf` and then apply the other options
+    ///
+    /// The configuration is read from the stdin if the path is `-`.
     pub fn load_yaml_with_opt_override(opt: &Opt) -> Result<Self> {
         if let Some(path) = &opt.conf {
-            let mut conf = Self::load_from_yaml(path)?;
+            let mut conf = if path == STDIN_PATH {
+                Self::load_from_stdin()?
+            } else {
+                Self::load_from_yaml(path)?
+            };
             conf.merge_with_opt(opt);
             Ok(conf)
         } else {
@@ -195,11 +377,22 @@ impl ServerConf {
         }
     }
 
+    /// Parse the YAML configuration
+    ///
+    /// The relative paths it includes are relative to the current directory.
     pub fn from_yaml(conf_str: &str) -> Result<Self> {
         trace!("Read conf file: {conf_str}");
-        let conf: ServerConf = serde_yaml::from_str(conf_str).or_err_with(ReadError, || {
-            format!("Unable to parse yaml conf {conf_str}")
-        })?;
+        let conf: serde_yaml::Value = serde_yaml::from_str(conf_str)
+            .or_err_with(ReadError, || {
+                format!("Unable to parse yaml conf {conf_str}")
+            })?;
+        let conf = include::resolve_includes(conf, Path::new(""))?;
+        Self::from_yaml_value(conf)
+    }
+
+    fn from_yaml_value(conf: serde_yaml::Value) -> Result<Self> {
+        let conf: ServerConf =
+            serde_yaml::from_value(conf).or_err(ReadError, "Unable to parse yaml conf")?;
 
         trace!("Loaded conf: {conf:?}");
         conf.validate()
@@ -210,7 +403,28 @@ impl ServerConf {
     }
 
     pub fn validate(self) -> Result<Self> {
-        // TODO: do the validation
+        // TODO: do more validation
+        if self.max_blocking_threads == Some(0) {
+            return Error::e_explain(ReadError, "max_blocking_threads must be at least 1");
+        }
+        if self.main_loop_threads == 0 {
+            return Error::e_explain(ReadError, "main_loop_threads must be at least 1");
+        }
+        if let Some(provider) = self.tls_provider {
+            TlsProvider::check(provider)?;
+        }
+        if self.daemon_umask > 0o777 {
+            return Error::e_explain(
+                ReadError,
+                format!("invalid daemon_umask {:o}", self.daemon_umask),
+            );
+        }
+        for settings in [&self.h2_settings, &self.upstream_h2_settings]
+            .into_iter()
+            .flatten()
+        {
+            settings.validate()?;
+        }
         Ok(self)
     }
 
@@ -218,6 +432,9 @@ impl ServerConf {
         if opt.daemon {
             self.daemon = true;
         }
+        if let Some(timeout) = opt.upgrade_timeout {
+            self.upgrade_timeout = Some(timeout);
+        }
     }
 }
 
@@ -234,27 +451,194 @@ mod tests {
         init_log();
         let conf = ServerConf {
             version: 1,
+            include: vec![],
+            include_list_merge: ListMerge::Replace,
             client_bind_to_ipv4: vec!["1.2.3.4".to_string(), "5.6.7.8".to_string()],
             client_bind_to_ipv6: vec![],
             ca_file: None,
+            tls_provider: None,
             daemon: false,
             error_log: None,
+            stdout_file: None,
+            stderr_file: None,
+            daemon_work_dir: "/".to_string(),
+            daemon_umask: 0o007,
             pid_file: "".to_string(),
             upgrade_sock: "".to_string(),
+            upgrade_timeout: None,
+            upgrade_close_timeout: None,
             user: None,
             group: None,
             threads: 1,
             work_stealing: true,
+            max_blocking_threads: None,
+            main_loop_threads: 1,
             upstream_keepalive_pool_size: 4,
             upstream_connect_offload_threadpools: None,
             upstream_connect_offload_thread_per_pool: None,
-            grace_period_seconds: None,
-            graceful_shutdown_timeout_seconds: None,
+            upstream_max_requests_per_connection: None,
+            upstream_h2_settings: None,
+            upstream_connect_timeout: None,
+            upstream_total_connect_timeout: None,
+            upstream_read_timeout: None,
+            upstream_write_timeout: None,
+            grace_period: None,
+            graceful_shutdown_timeout: None,
+            max_requests_per_connection: None,
+            h2_settings: None,
+            max_buffered_request_body: None,
+            oversized_request_body: OversizedRequestBody::Stream,
         };
         // cargo test -- --nocapture not_a_test_i_cannot_write_yaml_by_hand
         println!("{}", conf.to_yaml());
     }
 
+    #[test]
+    fn test_daemon_settings() {
+        init_log();
+        let conf = ServerConf::from_yaml("---\nversion: 1").unwrap();
+        assert_eq!(conf.daemon_work_dir, "/");
+        assert_eq!(conf.daemon_umask, 0o007);
+
+        let conf =
+            ServerConf::from_yaml("---\nversion: 1\ndaemon_work_dir: /tmp\ndaemon_umask: 0o027")
+                .unwrap();
+        assert_eq!(conf.daemon_work_dir, "/tmp");
+        assert_eq!(conf.daemon_umask, 0o027);
+
+        assert!(ServerConf::from_yaml("---\nversion: 1\ndaemon_umask: 0o1000").is_err());
+    }
+
+    #[test]
+    fn test_max_blocking_threads() {
+        init_log();
+        let conf = ServerConf::from_yaml("---\nversion: 1").unwrap();
+        assert_eq!(conf.max_blocking_threads, None);
+        let conf = ServerConf::from_yaml("---\nversion: 1\nmax_blocking_threads: 8").unwrap();
+        assert_eq!(conf.max_blocking_threads, Some(8));
+        assert!(ServerConf::from_yaml("---\nversion: 1\nmax_blocking_threads: 0").is_err());
+    }
+
+    #[test]
+    fn test_main_loop_threads() {
+        init_log();
+        let conf = ServerConf::from_yaml("---\nversion: 1").unwrap();
+        assert_eq!(conf.main_loop_threads, 1);
+        let conf = ServerConf::from_yaml("---\nversion: 1\nmain_loop_threads: 4").unwrap();
+        assert_eq!(conf.main_loop_threads, 4);
+        assert!(ServerConf::from_yaml("---\nversion: 1\nmain_loop_threads: 0").is_err());
+    }
+
+    #[test]
+    fn test_tls_provider() {
+        init_log();
+        let conf = ServerConf::from_yaml("---\nversion: 1").unwrap();
+        assert_eq!(conf.tls_provider, None);
+        let current = TlsProvider::current();
+        let conf =
+            ServerConf::from_yaml(&format!("---\nversion: 1\ntls_provider: {current}")).unwrap();
+        assert_eq!(conf.tls_provider, Some(current));
+        let other = match current {
+            TlsProvider::OpenSsl => "boringssl",
+            TlsProvider::BoringSsl => "openssl",
+        };
+        assert!(ServerConf::from_yaml(&format!("---\nversion: 1\ntls_provider: {other}")).is_err());
+        assert!(ServerConf::from_yaml("---\nversion: 1\ntls_provider: rustls").is_err());
+    }
+
+    #[test]
+    fn test_opt() {
+        init_log();
+        let opt = Opt::from_iter(["pingora", "-u", "--upgrade-timeout", "30", "-c", "-"]);
+        assert!(opt.upgrade);
+        assert_eq!(opt.upgrade_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(opt.conf.as_deref(), Some("-"));
+        let conf = ServerConf::new_with_opt_override(&opt).unwrap();
+        assert_eq!(conf.upgrade_timeout, Some(Duration::from_secs(30)));
+
+        let opt = Opt::from_iter(["pingora", "--upgrade-timeout", "1500ms"]);
+        assert_eq!(opt.upgrade_timeout, Some(Duration::from_millis(1500)));
+        assert!(Opt::from_iter_safe(["pingora", "--upgrade-timeout", "soon"]).is_err());
+
+        let e = Opt::from_iter_safe(["pingora", "--version"]).unwrap_err();
+        assert_eq!(e.kind, structopt::clap::ErrorKind::VersionDisplayed);
+    }
+
+    #[test]
+    fn test_durations() {
+        init_log();
+        let conf = ServerConf::from_yaml(
+            "---\nversion: 1\ngrace_period: 1m 30s\ngraceful_shutdown_timeout: 500ms\nupgrade_timeout: 10s\nupgrade_close_timeout: 30s",
+        )
+        .unwrap();
+        assert_eq!(conf.grace_period, Some(Duration::from_secs(90)));
+        assert_eq!(
+            conf.graceful_shutdown_timeout,
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(conf.upgrade_timeout, Some(Duration::from_secs(10)));
+        assert_eq!(conf.upgrade_close_timeout, Some(Duration::from_secs(30)));
+
+        // the old keys in seconds still work
+        let conf = ServerConf::from_yaml(
+            "---\nversion: 1\ngrace_period_seconds: 60\ngraceful_shutdown_timeout_seconds: 5\nupgrade_timeout_seconds: 3",
+        )
+        .unwrap();
+        assert_eq!(conf.grace_period, Some(Duration::from_secs(60)));
+        assert_eq!(conf.graceful_shutdown_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(conf.upgrade_timeout, Some(Duration::from_secs(3)));
+
+        assert!(ServerConf::from_yaml("---\nversion: 1\ngrace_period: soon").is_err());
+
+        // the old fields are still accessible
+        #[allow(deprecated)]
+        {
+            let mut conf = ServerConf::default();
+            conf.set_grace_period_seconds(Some(10));
+            conf.set_graceful_shutdown_timeout_seconds(Some(2));
+            assert_eq!(conf.grace_period, Some(Duration::from_secs(10)));
+            assert_eq!(conf.grace_period_seconds(), Some(10));
+            assert_eq!(conf.graceful_shutdown_timeout_seconds(), Some(2));
+        }
+    }
+
+    #[test]
+    fn test_include() {
+        init_log();
+        let dir = std::env::temp_dir().join(format!("pingora-conf-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("base.yaml"),
+            "---\nversion: 1\nthreads: 2\nclient_bind_to_ipv4: [1.2.3.4]",
+        )
+        .unwrap();
+        let path = dir.join("prod.yaml");
+        std::fs::write(
+            &path,
+            "---\ninclude: [base.yaml]\ninclude_list_merge: append\nclient_bind_to_ipv4: [5.6.7.8]",
+        )
+        .unwrap();
+
+        let opt = Opt::from_iter(["pingora", "-d", "-c", path.to_str().unwrap()]);
+        let conf = ServerConf::load_yaml_with_opt_override(&opt).unwrap();
+        assert_eq!(conf.version, 1);
+        assert_eq!(conf.threads, 2);
+        assert_eq!(conf.client_bind_to_ipv4, ["1.2.3.4", "5.6.7.8"]);
+        assert_eq!(conf.include, ["base.yaml"]);
+        assert_eq!(conf.include_list_merge, ListMerge::Append);
+        assert!(conf.daemon);
+    }
+
+    #[test]
+    fn test_load_from_reader() {
+        init_log();
+        let conf_str = "---\nversion: 1\nthreads: 4\n";
+        let conf = ServerConf::load_from_reader(conf_str.as_bytes()).unwrap();
+        assert_eq!(1, conf.version);
+        assert_eq!(4, conf.threads);
+        assert!(ServerConf::load_from_reader("threads: many".as_bytes()).is_err());
+    }
+
     #[test]
     fn test_load_file() {
         init_log();
@@ -273,6 +657,74 @@ client_bind_to_ipv6: []
         assert_eq!(1, conf.version);
     }
 
+    #[test]
+    fn test_h2_settings() {
+        init_log();
+        let conf_str = r#"
+---
+version: 1
+h2_settings:
+    max_concurrent_streams: 100
+    initial_stream_window_size: 1048576
+upstream_h2_settings:
+    max_frame_size: 65536
+        "#
+        .to_string();
+        let conf = ServerConf::from_yaml(&conf_str).unwrap();
+        let h2_settings = conf.h2_settings.unwrap();
+        assert_eq!(h2_settings.max_concurrent_streams, Some(100));
+        assert_eq!(h2_settings.initial_stream_window_size, Some(1048576));
+        assert_eq!(
+            conf.upstream_h2_settings.unwrap().max_frame_size,
+            Some(65536)
+        );
+
+        let conf_str = r#"
+---
+version: 1
+h2_settings:
+    max_frame_size: 1024
+        "#
+        .to_string();
+        assert!(ServerConf::from_yaml(&conf_str).is_err());
+    }
+
+    #[test]
+    fn test_upstream_timeouts() {
+        init_log();
+        let conf_str = r#"
+---
+version: 1
+upstream_connect_timeout: 1s
+upstream_read_timeout: 500ms
+        "#
+        .to_string();
+        let conf = ServerConf::from_yaml(&conf_str).unwrap();
+        assert_eq!(conf.upstream_connect_timeout, Some(Duration::from_secs(1)));
+        assert_eq!(conf.upstream_total_connect_timeout, None);
+        assert_eq!(conf.upstream_read_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(conf.upstream_write_timeout, None);
+    }
+
+    #[test]
+    fn test_request_body_buffering() {
+        init_log();
+        let conf = ServerConf::from_yaml("---\nversion: 1").unwrap();
+        assert_eq!(conf.max_buffered_request_body, None);
+        assert_eq!(conf.oversized_request_body, OversizedRequestBody::Stream);
+
+        let conf_str = r#"
+---
+version: 1
+max_buffered_request_body: 1048576
+oversized_request_body: reject
+        "#
+        .to_string();
+        let conf = ServerConf::from_yaml(&conf_str).unwrap();
+        assert_eq!(conf.max_buffered_request_body, Some(1048576));
+        assert_eq!(conf.oversized_request_body, OversizedRequestBody::Reject);
+    }
+
     #[test]
     fn test_default() {
         init_log();
@@ -14,8 +14,10 @@
 
 use daemonize::Daemonize;
 use log::{debug, error};
+use nix::unistd::{access, AccessFlags};
+use pingora_error::{Error, ErrorType::ReadError, OrErr, Result};
 use std::ffi::CString;
-use std::fs::{self, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::os::unix::prelude::OpenOptionsExt;
 use std::path::Path;
 
@@ -53,28 +55,51 @@ unsafe fn gid_for_username(name: &CString) -> Option<libc::gid_t> {
     None
 }
 
+fn open_output_file(path: &str) -> File {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        // open read() in case there are no readers
+        // available otherwise we will panic with
+        // an ENXIO since O_NONBLOCK is set
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .unwrap()
+}
+
+/// Check that the working directory of the daemon exists and can be entered
+pub fn check_work_dir(conf: &ServerConf) -> Result<()> {
+    let dir = conf.daemon_work_dir.as_str();
+    let metadata = fs::metadata(dir).or_err_with(ReadError, || {
+        format!("daemon_work_dir {dir} is not accessible")
+    })?;
+    if !metadata.is_dir() {
+        return Error::e_explain(
+            ReadError,
+            format!("daemon_work_dir {dir} is not a directory"),
+        );
+    }
+    access(dir, AccessFlags::X_OK).or_err_with(ReadError, || {
+        format!("daemon_work_dir {dir} cannot be entered")
+    })
+}
+
 /// Start a server instance as a daemon.
 pub fn daemonize(conf: &ServerConf) {
-    // TODO: customize working dir
-
     let daemonize = Daemonize::new()
-        .umask(0o007) // allow same group to access files but not everyone else
+        .working_directory(&conf.daemon_work_dir)
+        .umask(conf.daemon_umask)
         .pid_file(&conf.pid_file);
 
-    let daemonize = if let Some(error_log) = conf.error_log.as_ref() {
-        let err = OpenOptions::new()
-            .append(true)
-            .create(true)
-            // open read() in case there are no readers
-            // available otherwise we will panic with
-            // an ENXIO since O_NONBLOCK is set
-            .read(true)
-            .custom_flags(libc::O_NONBLOCK)
-            .open(error_log)
-            .unwrap();
-        daemonize.stderr(err)
-    } else {
-        daemonize
+    let daemonize = match conf.stdout_file.as_ref() {
+        Some(stdout_file) => daemonize.stdout(open_output_file(stdout_file)),
+        None => daemonize,
+    };
+
+    let daemonize = match conf.stderr_file.as_ref().or(conf.error_log.as_ref()) {
+        Some(stderr_file) => daemonize.stderr(open_output_file(stderr_file)),
+        None => daemonize,
     };
 
     let daemonize = match conf.user.as_ref() {
@@ -0,0 +1,161 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Drain a single service while the rest of the server keeps serving
+//!
+//! Each service has its own drain watch in addition to the [ShutdownWatch] shared by the whole
+//! server. A drained listening service stops accepting, lets the in-flight connections finish and
+//! resumes accepting once un-drained. The services that don't handle the drain watch themselves
+//! see it as their shutdown watch turning true, see [Service::start_service_with_drain()].
+//!
+//! [Service::start_service_with_drain()]: crate::services::Service::start_service_with_drain
+
+use log::info;
+use pingora_error::{Error, ErrorType, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+use super::ShutdownWatch;
+
+/// The receiver of the drain signal of a service. The value is true while the service is drained.
+pub type DrainWatch = watch::Receiver<bool>;
+
+type Drains = Arc<Mutex<HashMap<String, watch::Sender<bool>>>>;
+
+/// The handle to drain and un-drain the services of a running server
+///
+/// Obtained via [Server::shutdown_handle()](super::Server::shutdown_handle). The services are
+/// identified by their [name](crate::services::Service::name), the services sharing the same name
+/// are drained together.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    drains: Drains,
+}
+
+impl ShutdownHandle {
+    // the drain watch of the service, created on its first registration
+    pub(crate) fn register(&self, name: &str) -> DrainWatch {
+        self.drains
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| watch::channel(false).0)
+            .subscribe()
+    }
+
+    fn set(&self, name: &str, drain: bool) -> Result<()> {
+        let drains = self.drains.lock().unwrap();
+        let Some(sender) = drains.get(name) else {
+            return Error::e_explain(ErrorType::InternalError, format!("no service named {name}"));
+        };
+        sender.send_replace(drain);
+        Ok(())
+    }
+
+    /// Stop the service from accepting new connections and let the in-flight ones finish
+    pub fn drain_service(&self, name: &str) -> Result<()> {
+        self.set(name, true)?;
+        info!("Draining service {name}");
+        Ok(())
+    }
+
+    /// Let a drained service accept new connections again
+    ///
+    /// Only the services that handle the drain watch, like the listening services, resume. The
+    /// others exit when drained.
+    pub fn undrain_service(&self, name: &str) -> Result<()> {
+        self.set(name, false)?;
+        info!("Un-draining service {name}");
+        Ok(())
+    }
+
+    /// Whether the service is drained, `None` if there is no such service
+    pub fn is_service_drained(&self, name: &str) -> Option<bool> {
+        self.drains
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|sender| *sender.borrow())
+    }
+}
+
+/// Keep `tx` true whenever either `shutdown` or `drain` is true
+///
+/// This never returns, drop it along with the service it serves.
+pub(crate) async fn combine_watches(
+    mut shutdown: ShutdownWatch,
+    mut drain: DrainWatch,
+    tx: &watch::Sender<bool>,
+) {
+    let mut shutdown_open = true;
+    let mut drain_open = true;
+    while shutdown_open || drain_open {
+        tx.send_if_modified(|v| {
+            let combined = *shutdown.borrow_and_update() || *drain.borrow_and_update();
+            let modified = *v != combined;
+            *v = combined;
+            modified
+        });
+        tokio::select! {
+            r = shutdown.changed(), if shutdown_open => shutdown_open = r.is_ok(),
+            r = drain.changed(), if drain_open => drain_open = r.is_ok(),
+        }
+    }
+    // keep `tx` open for the service
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let handle = ShutdownHandle::default();
+        let mut drain = handle.register("svc");
+        assert_eq!(handle.is_service_drained("svc"), Some(false));
+        assert_eq!(handle.is_service_drained("other"), None);
+        assert!(handle.drain_service("other").is_err());
+
+        handle.drain_service("svc").unwrap();
+        assert!(drain.has_changed().unwrap());
+        assert!(*drain.borrow_and_update());
+        // the services of the same name share the watch
+        assert!(*handle.register("svc").borrow());
+
+        handle.undrain_service("svc").unwrap();
+        assert!(!*drain.borrow_and_update());
+        assert_eq!(handle.is_service_drained("svc"), Some(false));
+    }
+
+    #[tokio::test]
+    async fn test_combine_watches() {
+        let handle = ShutdownHandle::default();
+        let drain = handle.register("svc");
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let (tx, mut combined) = watch::channel(false);
+        tokio::spawn(async move { combine_watches(shutdown, drain, &tx).await });
+
+        handle.drain_service("svc").unwrap();
+        combined.changed().await.unwrap();
+        assert!(*combined.borrow_and_update());
+        handle.undrain_service("svc").unwrap();
+        combined.changed().await.unwrap();
+        assert!(!*combined.borrow_and_update());
+        shutdown_tx.send(true).unwrap();
+        combined.changed().await.unwrap();
+        assert!(*combined.borrow_and_update());
+    }
+}
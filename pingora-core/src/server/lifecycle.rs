@@ -0,0 +1,118 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Callbacks at the lifecycle transitions of the server
+//!
+//! An external supervisor can follow the server through its lifecycle by setting a
+//! [LifecycleObserver] via [Server::with_lifecycle_observer()] instead of scraping the logs. The
+//! transitions happen in this order:
+//! 1. [LifecycleObserver::bootstrapped()]
+//! 2. [LifecycleObserver::services_started()]
+//! 3. [LifecycleObserver::ready()]
+//! 4. [LifecycleObserver::draining()], only for graceful shutdowns
+//! 5. [LifecycleObserver::shutdown_complete()]
+//!
+//! [Server::with_lifecycle_observer()]: super::Server::with_lifecycle_observer
+
+use super::{ShutdownEvent, ShutdownSignal};
+
+/// The callbacks at the lifecycle transitions of the server
+///
+/// All the methods do nothing by default. They are called from the thread driving the server, so
+/// they should return quickly. A tuple of two observers calls both of them in order.
+pub trait LifecycleObserver: Send + Sync {
+    /// The server is bootstrapped: the listening sockets of the old process, if upgrading, are
+    /// taken over
+    fn bootstrapped(&self) {}
+
+    /// The runtimes of the services are created and the services are started
+    fn services_started(&self, _services: usize) {}
+
+    /// The server is serving and waiting for the signals. This is called again when a graceful
+    /// upgrade is cancelled.
+    fn ready(&self) {}
+
+    /// The graceful shutdown started: the services are told to stop accepting and the grace period
+    /// starts
+    fn draining(&self, _signal: ShutdownSignal) {}
+
+    /// All the runtimes exited and the process is about to exit
+    fn shutdown_complete(&self, _event: &ShutdownEvent) {}
+}
+
+// no observer
+impl LifecycleObserver for () {}
+
+// both observers, in order
+impl<A: LifecycleObserver, B: LifecycleObserver> LifecycleObserver for (A, B) {
+    fn bootstrapped(&self) {
+        self.0.bootstrapped();
+        self.1.bootstrapped();
+    }
+
+    fn services_started(&self, services: usize) {
+        self.0.services_started(services);
+        self.1.services_started(services);
+    }
+
+    fn ready(&self) {
+        self.0.ready();
+        self.1.ready();
+    }
+
+    fn draining(&self, signal: ShutdownSignal) {
+        self.0.draining(signal);
+        self.1.draining(signal);
+    }
+
+    fn shutdown_complete(&self, event: &ShutdownEvent) {
+        self.0.shutdown_complete(event);
+        self.1.shutdown_complete(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl LifecycleObserver for std::sync::Arc<Recorder> {
+        fn bootstrapped(&self) {
+            self.0.lock().unwrap().push("bootstrapped".into());
+        }
+
+        fn services_started(&self, services: usize) {
+            self.0.lock().unwrap().push(format!("started {services}"));
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let recorder = std::sync::Arc::new(Recorder::default());
+        let mut server = Server::new(None)
+            .unwrap()
+            .with_lifecycle_observer((recorder.clone(), recorder.clone()));
+        server.bootstrap();
+        let runtimes = server.run_services();
+        assert!(runtimes.is_empty());
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec!["bootstrapped", "bootstrapped", "started 0", "started 0"]
+        );
+    }
+}
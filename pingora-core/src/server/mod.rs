@@ -14,16 +14,19 @@
 
 //! Server process and configuration management
 
+use std::future::Future;
 use std::sync::Arc;
 use std::thread;
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use tokio::runtime::Handle;
 use tokio::signal::unix;
-use tokio::sync::{Mutex, watch};
-use tokio::time::{Duration, sleep};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
 
-use configuration::{Opt, ServerConf};
-use daemon::daemonize;
+use configuration::{Opt, ServerConf, STDIN_PATH};
+use daemon::{check_work_dir, daemonize};
 use pingora_error::{Error, ErrorType, Result};
 use pingora_runtime::Runtime;
 use pingora_timeout::fast_timeout;
@@ -33,18 +36,46 @@ use crate::services::Service;
 
 pub mod configuration;
 mod daemon;
+mod drain;
+mod lifecycle;
+mod reload;
+mod shutdown;
 pub(crate) mod transfer_fd;
+mod upgrade;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod upgrade_test;
+
+pub(crate) use drain::combine_watches;
+pub use drain::{DrainWatch, ShutdownHandle};
+pub use lifecycle::LifecycleObserver;
+pub use reload::{ConfReload, ReloadWatch};
+pub use shutdown::{ShutdownEvent, ShutdownEventWatch, ShutdownSignal};
+pub use upgrade::{fd_transfer_stats, FdTransferStats, UpgradeResult};
 
 /* time to wait before exiting the program
-this is the graceful period for all existing session to finish */
+this is the graceful period for all existing session to finish
+unless the grace_period is configured */
 const EXIT_TIMEOUT: u64 = 60 * 5;
+/* time to wait for the runtimes to exit after the grace period
+unless the graceful_shutdown_timeout is configured */
+const SHUTDOWN_TIMEOUT: u64 = 5;
 /* time to wait before shutting down listening sockets
-this is the graceful period for the new service to get ready */
+this is the graceful period for the new service to get ready
+unless the upgrade_close_timeout is configured */
 const CLOSE_TIMEOUT: u64 = 5;
+/* time to wait for the endpoints to adopt the SO_REUSEPORT sockets passed from the old process
+before closing the ones left over, well within the default CLOSE_TIMEOUT of the old process */
+const REUSEPORT_ADOPT_TIMEOUT: Duration = Duration::from_secs(1);
 
-enum ShutdownType {
-    Graceful,
-    Quick,
+// how the graceful upgrade started by SIGQUIT ended
+#[derive(Debug)]
+enum UpgradeOutcome {
+    // SIGINT, shut down right away
+    Interrupted,
+    // another SIGQUIT, keep serving
+    Cancelled,
+    // the upgrade result and whether any service is shutting down gracefully
+    Done(UpgradeResult, bool),
 }
 
 /// The receiver for server's shutdown event. The value will turn to true once the server starts
@@ -58,12 +89,22 @@ pub(crate) type ListenFds = Arc<Mutex<Fds>>;
 /// services (see [crate::services]). The server object handles signals, reading configuration,
 /// zero downtime upgrade and error reporting.
 pub struct Server {
-    services: Vec<Box<dyn Service>>,
+    // the services and the external runtimes to run them on, if any
+    services: Vec<(Box<dyn Service>, Option<Handle>)>,
+    // the services running on the external runtimes
+    external_services: Vec<JoinHandle<()>>,
     listen_fds: Option<ListenFds>,
     shutdown_watch: watch::Sender<bool>,
     // TODO: we many want to drop this copy to let sender call closed()
     shutdown_recv: ShutdownWatch,
+    reload_watch: watch::Sender<ConfReload>,
+    shutdown_event: watch::Sender<Option<ShutdownEvent>>,
+    shutdown_handle: ShutdownHandle,
+    lifecycle: Box<dyn LifecycleObserver>,
     /// the parsed server configuration
+    ///
+    /// This is the configuration the server started with. See [Self::reload_watch()] for the
+    /// reloaded ones.
     pub configuration: Arc<ServerConf>,
     /// the parser command line options
     pub options: Option<Opt>,
@@ -76,90 +117,235 @@ pub struct Server {
 // TODO: delete the pid when exit
 
 impl Server {
-    async fn main_loop(&self) -> ShutdownType {
-        // waiting for exit signal
-        let shutdown_signal = wait_for_shutdown_signal().await;
-        match shutdown_signal {
-            ShutdownSignal::Fast => {
-                info!("SIGINT received, exiting");
-                ShutdownType::Quick
-            }
-            ShutdownSignal::GracefulTerminate => {
-                // we receive a graceful terminate, all instances are instructed to stop
-                info!("SIGTERM received, gracefully exiting");
-                // graceful shutdown if there are listening sockets
-                info!("Broadcasting graceful shutdown");
-                match self.shutdown_watch.send(true) {
-                    Ok(_) => {
-                        info!("Graceful shutdown started!");
+    // the returned event is yet to be filled with the outcome of draining
+    async fn main_loop(&self) -> ShutdownEvent {
+        // waiting for exit signal, reloading the configuration on the way
+        let mut sig_hup =
+            unix::signal(unix::SignalKind::hangup()).expect("Failed to create SIGHUP listener.");
+        loop {
+            self.lifecycle.ready();
+            let shutdown_signal = loop {
+                tokio::select! {
+                    signal = wait_for_shutdown_signal() => break signal,
+                    _ = sig_hup.recv() => {
+                        info!("SIGHUP received, reloading the configuration");
+                        let _ = self.reload();
                     }
-                    Err(e) => {
-                        error!("Graceful shutdown broadcast failed: {e}");
+                }
+            };
+            let mut event = ShutdownEvent {
+                signal: shutdown_signal,
+                graceful: true,
+                upgrade: None,
+                drain_time: Duration::ZERO,
+                remaining_connections: 0,
+            };
+            match shutdown_signal {
+                ShutdownSignal::Fast => {
+                    info!("SIGINT received, exiting");
+                    event.graceful = false;
+                }
+                ShutdownSignal::GracefulTerminate => {
+                    // we receive a graceful terminate, all instances are instructed to stop
+                    info!("SIGTERM received, gracefully exiting");
+                    // no connection to wait for if all the services already exited
+                    event.graceful = self.broadcast_shutdown();
+                }
+                ShutdownSignal::GracefulUpgrade => {
+                    let mut wait_for_sig_int = unix::signal(unix::SignalKind::interrupt())
+                        .expect("Failed to create SIGINT listener.");
+                    // a second SIGQUIT cancels the upgrade before the shutdown starts
+                    let mut wait_for_sig_quit = unix::signal(unix::SignalKind::quit())
+                        .expect("Failed to create SIGQUIT listener.");
+                    let outcome = self
+                        .upgrade_unless_cancelled(
+                            async {
+                                wait_for_sig_int.recv().await;
+                            },
+                            async {
+                                wait_for_sig_quit.recv().await;
+                            },
+                        )
+                        .await;
+                    match outcome {
+                        UpgradeOutcome::Interrupted => {}
+                        UpgradeOutcome::Cancelled => {
+                            info!("SIGQUIT received again, graceful upgrade cancelled, resume serving");
+                            continue;
+                        }
+                        UpgradeOutcome::Done(result, graceful) => {
+                            info!("Graceful upgrade: {result:?}");
+                            event.upgrade = Some(result);
+                            event.graceful = graceful;
+                        }
                     }
                 }
-                info!("Broadcast graceful shutdown complete");
-                ShutdownType::Graceful
             }
-            ShutdownSignal::GracefulUpgrade => {
-                let mut wait_for_sig_int = unix::signal(unix::SignalKind::interrupt())
-                    .expect("Failed to create SIGINT listener.");
-                tokio::select! {
-                    _ = wait_for_sig_int.recv() => {}
-                    _ = self.graceful_upgrade() => {}
-                }
-                ShutdownType::Graceful
+            return event;
+        }
+    }
+
+    /// Reload the configuration file and notify the subscribers of [Self::reload_watch()]
+    ///
+    /// Return the new configuration generation. On failure, the current configuration and its
+    /// generation stay in effect. The configuration can't be reloaded if it was read from the
+    /// stdin or there was no configuration file at all.
+    pub fn reload(&self) -> Result<u64> {
+        let generation = self.config_generation();
+        let result = match self.options.as_ref() {
+            Some(opt) if opt.conf.as_deref().is_some_and(|c| c != STDIN_PATH) => {
+                ServerConf::load_yaml_with_opt_override(opt)
+            }
+            _ => Error::e_explain(ErrorType::ReadError, "no configuration file to reload"),
+        };
+        match result {
+            Ok(conf) => {
+                let generation = generation + 1;
+                self.reload_watch.send_replace(ConfReload {
+                    generation,
+                    conf: Arc::new(conf),
+                });
+                reload::record_generation(generation);
+                info!("Configuration reloaded, generation {generation}");
+                Ok(generation)
+            }
+            Err(e) => {
+                error!("Configuration reload failed: {e}, staying at generation {generation}");
+                Err(e)
             }
         }
     }
 
-    async fn graceful_upgrade(&self) {
+    /// The generation of the configuration in effect, 0 until the first successful reload
+    pub fn config_generation(&self) -> u64 {
+        self.reload_watch.borrow().generation
+    }
+
+    /// Subscribe to the configuration reloads
+    ///
+    /// The receiver holds the latest configuration and its generation, and gets notified on each
+    /// successful [Self::reload()] so that the service can log "applied config gen N".
+    pub fn reload_watch(&self) -> ReloadWatch {
+        self.reload_watch.subscribe()
+    }
+
+    /// Subscribe to the [ShutdownEvent]
+    ///
+    /// The event is sent once the grace period is over, before the runtimes of the services are
+    /// shut down.
+    pub fn shutdown_event_watch(&self) -> ShutdownEventWatch {
+        self.shutdown_event.subscribe()
+    }
+
+    /// Send the listening sockets to the new process and then start shutting down gracefully
+    ///
+    /// The result tells whether the sockets were actually handed off. It is also recorded in
+    /// [fd_transfer_stats()].
+    pub async fn graceful_upgrade(&self) -> UpgradeResult {
+        self.upgrade().await.0
+    }
+
+    // the graceful upgrade, unless `interrupt` (SIGINT) or `cancel` (another SIGQUIT) happens
+    // before the services are told to shut down
+    async fn upgrade_unless_cancelled(
+        &self,
+        interrupt: impl Future<Output = ()>,
+        cancel: impl Future<Output = ()>,
+    ) -> UpgradeOutcome {
+        tokio::select! {
+            _ = interrupt => UpgradeOutcome::Interrupted,
+            _ = cancel => UpgradeOutcome::Cancelled,
+            (result, graceful) = self.upgrade() => UpgradeOutcome::Done(result, graceful),
+        }
+    }
+
+    // the graceful upgrade, which also returns whether there is any service to shut down
+    // gracefully
+    async fn upgrade(&self) -> (UpgradeResult, bool) {
         // aka: move below to another task and only kick it off here
         info!("SIGQUIT received, sending socks and gracefully exiting");
         if let Some(result) = self.send_fds().await {
             info!("Trying to send socks");
             // XXX: this is blocking IO
-            match result {
-                Ok(_) => {
+            let upgrade_result = match result {
+                Ok(None) => {
+                    info!("No listener sockets to send");
+                    UpgradeResult::NoListeners
+                }
+                Ok(Some(sent)) => {
                     info!("listener sockets sent");
+                    UpgradeResult::Sent(sent)
                 }
                 Err(e) => {
                     error!("Unable to send listener sockets to new process: {e}");
                     // sentry log error on fd send failure
                     #[cfg(not(debug_assertions))]
                     sentry::capture_error(&e);
+                    UpgradeResult::Failed(e)
                 }
-            }
-            sleep(Duration::from_secs(CLOSE_TIMEOUT)).await;
-            info!("Broadcasting graceful shutdown");
+            };
+            upgrade::record_upgrade(upgrade_result);
+            let close_timeout = self
+                .configuration
+                .upgrade_close_timeout
+                .unwrap_or(Duration::from_secs(CLOSE_TIMEOUT));
+            sleep(close_timeout).await;
             // gracefully exiting
-            match self.shutdown_watch.send(true) {
-                Ok(_) => {
-                    info!("Graceful shutdown started!");
-                }
-                Err(e) => {
-                    error!("Graceful shutdown broadcast failed: {e}");
-                    // switch to fast shutdown
-                }
-            }
-            info!("Broadcast graceful shutdown complete");
+            let graceful = self.broadcast_shutdown();
+            (upgrade_result, graceful)
         } else {
             info!("No socks to send, shutting down.");
+            upgrade::record_upgrade(UpgradeResult::NoListeners);
+            (UpgradeResult::NoListeners, true)
+        }
+    }
+
+    // Whether any service still holds the shutdown watch to be told to shut down
+    fn services_running(&self) -> bool {
+        // the server holds one receiver itself to hand out to the services
+        self.shutdown_watch.receiver_count() > 1
+    }
+
+    // Tell the services to shut down gracefully.
+    //
+    // Return false if there is no service left to tell, e.g., all of them already exited, so that
+    // the grace period is skipped because there is no connection to wait for.
+    fn broadcast_shutdown(&self) -> bool {
+        if !self.services_running() {
+            info!("No service is running, skipping the grace period");
+            return false;
+        }
+        info!("Broadcasting graceful shutdown");
+        match self.shutdown_watch.send(true) {
+            Ok(_) => {
+                info!("Graceful shutdown started!");
+            }
+            Err(e) => {
+                error!("Graceful shutdown broadcast failed: {e}");
+                // switch to fast shutdown
+                return false;
+            }
         }
+        info!("Broadcast graceful shutdown complete");
+        true
     }
 
     fn run_service(
         mut service: Box<dyn Service>,
         fds: Option<ListenFds>,
         shutdown: ShutdownWatch,
+        drain: DrainWatch,
         threads: usize,
         work_stealing: bool,
+        max_blocking_threads: Option<usize>,
     ) -> Runtime
 // NOTE: we need to keep the runtime outside async since
     // otherwise the runtime will be dropped.
     {
-        let service_runtime = Server::create_runtime(service.name(), threads, work_stealing);
+        let service_runtime =
+            Server::create_runtime(service.name(), threads, work_stealing, max_blocking_threads);
         service_runtime.get_handle().spawn(async move {
-            service.start_service(fds, shutdown).await;
+            service.start_service_with_drain(fds, shutdown, drain).await;
             info!("service exited.")
         });
         service_runtime
@@ -169,11 +355,18 @@ impl Server {
     ///
     /// When trying to zero downtime upgrade as a new server from older which is already
     /// running, this function will try to send all its listening sockets to the new one.
-    pub async fn send_fds(&self) -> Option<Result<usize, nix::Error>> {
+    ///
+    /// Return `None` if the server has no listening socket table, `Some(Ok(None))` if the table
+    /// is empty, or the number of bytes sent along with the sockets. An empty table is still sent
+    /// so that the new process doesn't wait for the sockets until it times out.
+    pub async fn send_fds(&self) -> Option<Result<Option<usize>, nix::Error>> {
         if let Some(fds) = &self.listen_fds {
             let fds = fds.lock().await;
+            let empty = fds.is_empty();
             info!("Trying to send socks");
-            return Some(fds.send_to_sock(self.configuration.as_ref().upgrade_sock.as_str()));
+            let result = fds.send_to_sock(self.configuration.as_ref().upgrade_sock.as_str());
+            upgrade::record_transfer(&result);
+            return Some(result.map(|sent| (!empty).then_some(sent)));
         }
         None
     }
@@ -182,7 +375,12 @@ impl Server {
         let mut fds = Fds::new();
         if upgrade {
             debug!("Trying to receive socks");
-            fds.get_from_sock(self.configuration.as_ref().upgrade_sock.as_str())?
+            let result = fds.get_from_sock_with_timeout(
+                self.configuration.as_ref().upgrade_sock.as_str(),
+                self.configuration.upgrade_timeout,
+            );
+            upgrade::record_transfer(&result);
+            result?
         }
         self.listen_fds = Some(Arc::new(Mutex::new(fds)));
         Ok(())
@@ -217,27 +415,69 @@ impl Server {
                 .ok_or_else(|| Error::explain(ErrorType::ReadError, "Conf generation failed"))
         }?;
 
+        let conf = Arc::new(conf);
+        let (reload_watch, _) = watch::channel(ConfReload {
+            generation: 0,
+            conf: conf.clone(),
+        });
+
         Ok(Server {
             services: vec![],
+            external_services: vec![],
             listen_fds: None,
             shutdown_watch: tx,
             shutdown_recv: rx,
-            configuration: Arc::new(conf),
+            reload_watch,
+            shutdown_event: watch::channel(None).0,
+            shutdown_handle: ShutdownHandle::default(),
+            lifecycle: Box::new(()),
+            configuration: conf,
             options: opt,
             sentry: None,
         })
     }
 
+    /// Set the [LifecycleObserver] to be called at the lifecycle transitions of this server
+    pub fn with_lifecycle_observer(mut self, observer: impl LifecycleObserver + 'static) -> Self {
+        self.lifecycle = Box::new(observer);
+        self
+    }
+
     /// Add a service to this server.
     ///
     /// A service is anything that implements [`Service`].
     pub fn add_service(&mut self, service: impl Service + 'static) {
-        self.services.push(Box::new(service));
+        self.shutdown_handle.register(service.name());
+        self.services.push((Box::new(service), None));
+    }
+
+    /// Similar to [`Self::add_service()`], but run the service on the given runtime instead of
+    /// one created for it
+    ///
+    /// This is for embedding the server in an application that already has a runtime, or to share
+    /// a runtime across services. The [`Service::threads()`] of the service is ignored. The server
+    /// doesn't own the runtime, so it never shuts it down: on exit the server only waits for the
+    /// service to finish, up to the `graceful_shutdown_timeout`.
+    pub fn add_service_on(&mut self, service: impl Service + 'static, runtime: Handle) {
+        self.shutdown_handle.register(service.name());
+        self.services.push((Box::new(service), Some(runtime)));
     }
 
     /// Similar to [`Self::add_service()`], but take a list of services
     pub fn add_services(&mut self, services: Vec<Box<dyn Service>>) {
-        self.services.extend(services);
+        for service in services.iter() {
+            self.shutdown_handle.register(service.name());
+        }
+        self.services
+            .extend(services.into_iter().map(|s| (s, None)));
+    }
+
+    /// The handle to drain and un-drain the services of this server by their names
+    ///
+    /// The handle can be cloned and kept around, e.g., by an admin service, to be used while the
+    /// server runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown_handle.clone()
     }
 
     /// Prepare the server to start
@@ -250,11 +490,18 @@ impl Server {
 
         /* only init sentry in release builds */
         #[cfg(not(debug_assertions))]
-            let _guard = match self.sentry.as_ref() {
+        let _guard = match self.sentry.as_ref() {
             Some(uri) => Some(sentry::init(uri.as_str())),
             None => None,
         };
 
+        if self.configuration.daemon {
+            if let Err(e) = check_work_dir(&self.configuration) {
+                error!("Bootstrap failed on error: {e}, exiting.");
+                std::process::exit(1);
+            }
+        }
+
         if self.options.as_ref().map_or(false, |o| o.test) {
             info!("Server Test passed, exiting");
             std::process::exit(0);
@@ -264,6 +511,7 @@ impl Server {
         match self.load_fds(self.options.as_ref().map_or(false, |o| o.upgrade)) {
             Ok(_) => {
                 info!("Bootstrap done");
+                self.lifecycle.bootstrapped();
             }
             Err(e) => {
                 // sentry log error on fd load failure
@@ -278,22 +526,49 @@ impl Server {
 
     /// Run all services of server
     ///
-    /// This function will run all services of server.
+    /// This function will run all services of server. Only the runtimes created for the services
+    /// are returned, the services added via [`Self::add_service_on()`] are spawned on their
+    /// runtimes.
     pub fn run_services(&mut self) -> Vec<Runtime> {
         let conf = self.configuration.as_ref();
         let mut runtimes: Vec<Runtime> = Vec::new();
+        let services = self.services.len();
 
-        while let Some(service) = self.services.pop() {
+        while let Some((service, external)) = self.services.pop() {
+            let drain = self.shutdown_handle.register(service.name());
+            if let Some(handle) = external {
+                let fds = self.listen_fds.clone();
+                let shutdown = self.shutdown_recv.clone();
+                self.external_services.push(handle.spawn(async move {
+                    let mut service = service;
+                    service.start_service_with_drain(fds, shutdown, drain).await;
+                    info!("service exited.")
+                }));
+                continue;
+            }
             let threads = service.threads().unwrap_or(conf.threads);
+            let max_blocking_threads = match service.max_blocking_threads() {
+                Some(0) => {
+                    error!(
+                        "{}: max_blocking_threads must be at least 1, ignored",
+                        service.name()
+                    );
+                    conf.max_blocking_threads
+                }
+                max => max.or(conf.max_blocking_threads),
+            };
             let runtime = Server::run_service(
                 service,
                 self.listen_fds.clone(),
                 self.shutdown_recv.clone(),
+                drain,
                 threads,
                 conf.work_stealing,
+                max_blocking_threads,
             );
             runtimes.push(runtime);
         }
+        self.lifecycle.services_started(services);
         runtimes
     }
 
@@ -318,7 +593,7 @@ impl Server {
 
         /* only init sentry in release builds */
         #[cfg(not(debug_assertions))]
-            let _guard = match self.sentry.as_ref() {
+        let _guard = match self.sentry.as_ref() {
             Some(uri) => Some(sentry::init(uri.as_str())),
             None => None,
         };
@@ -327,19 +602,30 @@ impl Server {
 
         // blocked on main loop so that it runs forever
         // Only work steal runtime can use block_on()
-        let server_runtime = Server::create_runtime("Server", 1, true);
-        let shutdown_type = server_runtime.get_handle().block_on(self.main_loop());
-
-        if matches!(shutdown_type, ShutdownType::Graceful) {
-            info!("Graceful shutdown: grace period {}s starts", EXIT_TIMEOUT);
-            thread::sleep(Duration::from_secs(EXIT_TIMEOUT));
-            info!("Graceful shutdown: grace period ends");
+        let server_runtime = Server::create_runtime(
+            "Server",
+            self.configuration.main_loop_threads,
+            true,
+            self.configuration.max_blocking_threads,
+        );
+        if let Some(fds) = self.listen_fds.clone() {
+            server_runtime.get_handle().spawn(async move {
+                sleep(REUSEPORT_ADOPT_TIMEOUT).await;
+                close_unadopted_reuseport(&mut *fds.lock().await);
+            });
         }
+        let mut event = server_runtime.get_handle().block_on(self.main_loop());
+        self.drain(&mut event);
+        info!("Shutdown: {event}");
+        self.shutdown_event.send_replace(Some(event.clone()));
 
         // Give tokio runtimes time to exit
-        let shutdown_timeout = match shutdown_type {
-            ShutdownType::Quick => Duration::from_secs(0),
-            ShutdownType::Graceful => Duration::from_secs(5),
+        let shutdown_timeout = if event.graceful {
+            self.configuration
+                .graceful_shutdown_timeout
+                .unwrap_or(Duration::from_secs(SHUTDOWN_TIMEOUT))
+        } else {
+            Duration::from_secs(0)
         };
         let shutdowns: Vec<_> = runtimes
             .into_iter()
@@ -351,28 +637,58 @@ impl Server {
                 })
             })
             .collect();
+        // the external runtimes are not ours to shut down, just wait for the services on them
+        let external_services = std::mem::take(&mut self.external_services);
+        if !external_services.is_empty() && !shutdown_timeout.is_zero() {
+            info!("Waiting for services on external runtimes to exit!");
+            let wait = fast_timeout::fast_timeout(
+                shutdown_timeout,
+                futures::future::join_all(external_services),
+            );
+            if server_runtime.get_handle().block_on(wait).is_err() {
+                error!("Services on external runtimes didn't exit in {shutdown_timeout:?}");
+            }
+        }
         for shutdown in shutdowns {
             if let Err(e) = shutdown.join() {
                 error!("Failed to shutdown runtime: {:?}", e);
             }
         }
         info!("All runtimes exited, exiting now");
+        self.lifecycle.shutdown_complete(&event);
         std::process::exit(0)
     }
 
-    fn create_runtime(name: &str, threads: usize, work_steal: bool) -> Runtime {
-        if work_steal {
-            Runtime::new_steal(threads, name)
+    // Wait for the connections to finish if the shutdown is graceful, and fill in the event with
+    // the outcome
+    fn drain(&self, event: &mut ShutdownEvent) {
+        if event.graceful {
+            self.lifecycle.draining(event.signal);
+            let grace_period = self
+                .configuration
+                .grace_period
+                .unwrap_or(Duration::from_secs(EXIT_TIMEOUT));
+            info!("Graceful shutdown: grace period {grace_period:?} starts");
+            (event.drain_time, event.remaining_connections) =
+                shutdown::wait_grace_period(grace_period);
+            info!("Graceful shutdown: grace period ends");
         } else {
-            Runtime::new_no_steal(threads, name)
+            event.remaining_connections = crate::services::listening::active_connections();
         }
     }
-}
 
-enum ShutdownSignal {
-    Fast,
-    GracefulTerminate,
-    GracefulUpgrade,
+    fn create_runtime(
+        name: &str,
+        threads: usize,
+        work_steal: bool,
+        max_blocking_threads: Option<usize>,
+    ) -> Runtime {
+        if work_steal {
+            Runtime::new_steal_with_max_blocking(threads, name, max_blocking_threads)
+        } else {
+            Runtime::new_no_steal_with_max_blocking(threads, name, max_blocking_threads)
+        }
+    }
 }
 
 async fn wait_for_shutdown_signal() -> ShutdownSignal {
@@ -383,7 +699,7 @@ async fn wait_for_shutdown_signal() -> ShutdownSignal {
     };
 
     #[cfg(unix)]
-        let sig_term = async {
+    let sig_term = async {
         unix::signal(unix::SignalKind::terminate())
             .expect("Failed to create SIGTERM listener.")
             .recv()
@@ -391,7 +707,7 @@ async fn wait_for_shutdown_signal() -> ShutdownSignal {
     };
 
     #[cfg(unix)]
-        let sig_quit = async {
+    let sig_quit = async {
         unix::signal(unix::SignalKind::quit())
             .expect("Failed to create SIGQUIT listener.")
             .recv()
@@ -399,14 +715,353 @@ async fn wait_for_shutdown_signal() -> ShutdownSignal {
     };
 
     #[cfg(not(unix))]
-        let sig_term = std::future::pending::<()>();
+    let sig_term = std::future::pending::<()>();
 
     #[cfg(not(unix))]
-        let sig_quit = std::future::pending::<()>();
+    let sig_quit = std::future::pending::<()>();
 
     tokio::select! {
         _ = sig_int => ShutdownSignal::Fast,
         _ = sig_term => ShutdownSignal::GracefulTerminate,
         _ = sig_quit => ShutdownSignal::GracefulUpgrade,
     }
-}
\ No newline at end of file
+}
+
+// Close the SO_REUSEPORT sockets passed from the old process that no endpoint adopted, otherwise
+// they keep receiving their share of the new connections that nobody accepts once the old
+// process closes its copies.
+fn close_unadopted_reuseport(fds: &mut Fds) {
+    for (bind, fd) in fds.take_unadopted_reuseport() {
+        warn!("Closing the SO_REUSEPORT socket {fd} of {bind} that no endpoint adopted");
+        if let Err(e) = nix::unistd::close(fd) {
+            error!("Failed to close the unadopted socket {fd} of {bind}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("pingora-reload-{}.yaml", std::process::id()));
+        std::fs::write(&path, "---\nversion: 1\nthreads: 1").unwrap();
+        let opt = Opt::from_iter(["pingora", "-c", path.to_str().unwrap()]);
+        let server = Server::new(opt).unwrap();
+        let mut reload_watch = server.reload_watch();
+        assert_eq!(server.config_generation(), 0);
+        assert_eq!(reload_watch.borrow().conf.threads, 1);
+
+        std::fs::write(&path, "---\nversion: 1\nthreads: 2").unwrap();
+        assert_eq!(server.reload().unwrap(), 1);
+        assert!(reload_watch.has_changed().unwrap());
+        let reloaded = reload_watch.borrow_and_update().clone();
+        assert_eq!(reloaded.generation, 1);
+        assert_eq!(reloaded.conf.threads, 2);
+        // the services started with the original one
+        assert_eq!(server.configuration.threads, 1);
+
+        // a broken conf keeps the current one
+        std::fs::write(&path, "---\nversion: 1\nthreads: many").unwrap();
+        assert!(server.reload().is_err());
+        assert_eq!(server.config_generation(), 1);
+        assert!(!reload_watch.has_changed().unwrap());
+
+        let server = Server::new(None).unwrap();
+        assert!(server.reload().is_err());
+        assert_eq!(server.config_generation(), 0);
+    }
+
+    #[test]
+    fn test_drain_service() {
+        use crate::services::background::{background_service, BackgroundService};
+        use async_trait::async_trait;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        struct Bg(Arc<AtomicBool>);
+
+        #[async_trait]
+        impl BackgroundService for Bg {
+            async fn start(&self, mut shutdown: ShutdownWatch) {
+                while !*shutdown.borrow_and_update() {
+                    shutdown.changed().await.unwrap();
+                }
+                self.0.store(true, Ordering::Relaxed);
+            }
+        }
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut server = Server::new(None).unwrap();
+        server.add_service(background_service("bg", Bg(stopped.clone())));
+        server.add_service(background_service("other", Bg(Arc::new(false.into()))));
+        let handle = server.shutdown_handle();
+        assert_eq!(handle.is_service_drained("BG bg"), Some(false));
+        assert!(handle.drain_service("missing").is_err());
+
+        let _runtimes = server.run_services();
+        handle.drain_service("BG bg").unwrap();
+        assert_eq!(handle.is_service_drained("BG bg"), Some(true));
+        assert_eq!(handle.is_service_drained("BG other"), Some(false));
+        // the background service doesn't handle the drain, so it shuts down
+        for _ in 0..100 {
+            if stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(stopped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_shutdown_without_running_services() {
+        use crate::services::background::{background_service, BackgroundService};
+        use async_trait::async_trait;
+        use std::time::Instant;
+
+        struct Bg;
+
+        #[async_trait]
+        impl BackgroundService for Bg {
+            // exit right away without waiting for the shutdown
+            async fn start(&self, _shutdown: ShutdownWatch) {}
+        }
+
+        let mut server = Server::new(None).unwrap();
+        server.add_service(background_service("bg", Bg));
+        let _runtimes = server.run_services();
+        for _ in 0..100 {
+            if !server.services_running() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // what the main loop does on SIGTERM
+        let mut event = ShutdownEvent {
+            signal: ShutdownSignal::GracefulTerminate,
+            graceful: true,
+            upgrade: None,
+            drain_time: Duration::ZERO,
+            remaining_connections: 0,
+        };
+        event.graceful = server.broadcast_shutdown();
+        assert!(!event.graceful);
+        // no EXIT_TIMEOUT to sleep through
+        let start = Instant::now();
+        server.drain(&mut event);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_broadcast_shutdown() {
+        use crate::services::background::{background_service, BackgroundService};
+        use async_trait::async_trait;
+
+        struct Bg;
+
+        #[async_trait]
+        impl BackgroundService for Bg {
+            async fn start(&self, mut shutdown: ShutdownWatch) {
+                let _ = shutdown.changed().await;
+            }
+        }
+
+        let mut server = Server::new(None).unwrap();
+        // nothing to tell before the services run
+        assert!(!server.broadcast_shutdown());
+        server.add_service(background_service("bg", Bg));
+        let _runtimes = server.run_services();
+        assert!(server.broadcast_shutdown());
+    }
+
+    #[test]
+    fn test_close_unadopted_reuseport() {
+        use std::os::unix::io::IntoRawFd;
+
+        let raw_fds: Vec<_> = (0..2)
+            .map(|_| {
+                std::net::TcpListener::bind("127.0.0.1:0")
+                    .unwrap()
+                    .into_raw_fd()
+            })
+            .collect();
+        let mut fds = Fds::new();
+        fds.deserialize(
+            vec!["127.0.0.1:7000#reuseport".to_string(); 2],
+            raw_fds.clone(),
+        );
+        assert_eq!(fds.take_reuseport("127.0.0.1:7000"), Some(raw_fds[0]));
+        close_unadopted_reuseport(&mut fds);
+        assert!(fds.unadopted_reuseport().is_empty());
+
+        let is_open = |fd| nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFD).is_ok();
+        assert!(is_open(raw_fds[0]));
+        assert!(!is_open(raw_fds[1]));
+        nix::unistd::close(raw_fds[0]).unwrap();
+    }
+
+    #[test]
+    fn test_send_empty_fds() {
+        let sock = format!("/tmp/pingora_empty_fds_{}.sock", std::process::id());
+        let mut server = Server::new(None).unwrap();
+        let mut conf = ServerConf::new().unwrap();
+        conf.upgrade_sock = sock.clone();
+        server.configuration = Arc::new(conf);
+        server.listen_fds = Some(Arc::new(Mutex::new(Fds::new())));
+
+        // the new process, which only has background services
+        let child = thread::spawn(move || {
+            let mut fds = Fds::new();
+            fds.get_from_sock_with_timeout(sock.as_str(), Some(Duration::from_secs(5)))
+                .unwrap();
+            assert!(fds.is_empty());
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let result = runtime.block_on(server.send_fds()).unwrap();
+        assert_eq!(result, Ok(None));
+        child.join().unwrap();
+    }
+
+    // a server whose upgrade hands its (empty) socket table to a new process right away
+    fn upgrading_server(name: &str, close_timeout: Duration) -> (Server, thread::JoinHandle<()>) {
+        let sock = format!("/tmp/pingora_{name}_{}.sock", std::process::id());
+        let mut server = Server::new(None).unwrap();
+        let mut conf = ServerConf::new().unwrap();
+        conf.upgrade_sock = sock.clone();
+        conf.upgrade_close_timeout = Some(close_timeout);
+        server.configuration = Arc::new(conf);
+        server.listen_fds = Some(Arc::new(Mutex::new(Fds::new())));
+        let new_process = thread::spawn(move || {
+            let mut fds = Fds::new();
+            fds.get_from_sock_with_timeout(sock.as_str(), Some(Duration::from_secs(5)))
+                .unwrap();
+        });
+        (server, new_process)
+    }
+
+    #[test]
+    fn test_upgrade_cancelled() {
+        use crate::services::background::{background_service, BackgroundService};
+        use async_trait::async_trait;
+        use std::time::Instant;
+
+        struct Bg;
+
+        #[async_trait]
+        impl BackgroundService for Bg {
+            async fn start(&self, mut shutdown: ShutdownWatch) {
+                let _ = shutdown.changed().await;
+            }
+        }
+
+        let (mut server, new_process) =
+            upgrading_server("upgrade_cancelled", Duration::from_secs(10));
+        server.add_service(background_service("bg", Bg));
+        let _runtimes = server.run_services();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        // the second SIGQUIT comes during the close timeout
+        let outcome = runtime.block_on(
+            server.upgrade_unless_cancelled(std::future::pending(), async {
+                sleep(Duration::from_millis(100)).await
+            }),
+        );
+        assert!(matches!(outcome, UpgradeOutcome::Cancelled), "{outcome:?}");
+        assert!(start.elapsed() < Duration::from_secs(5));
+        new_process.join().unwrap();
+        // the services are not told to shut down
+        assert!(!*server.shutdown_watch.borrow());
+        assert!(server.services_running());
+
+        // SIGINT interrupts the upgrade the same way, but for a fast shutdown
+        let (server, new_process) =
+            upgrading_server("upgrade_interrupted", Duration::from_secs(10));
+        let outcome = runtime.block_on(server.upgrade_unless_cancelled(
+            async { sleep(Duration::from_millis(100)).await },
+            std::future::pending(),
+        ));
+        assert!(
+            matches!(outcome, UpgradeOutcome::Interrupted),
+            "{outcome:?}"
+        );
+        new_process.join().unwrap();
+    }
+
+    #[test]
+    fn test_upgrade_close_timeout() {
+        use std::time::Instant;
+
+        let close_timeout = Duration::from_millis(300);
+        let (server, new_process) = upgrading_server("upgrade_close_timeout", close_timeout);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let outcome = runtime.block_on(
+            server.upgrade_unless_cancelled(std::future::pending(), std::future::pending()),
+        );
+        let elapsed = start.elapsed();
+        new_process.join().unwrap();
+        // no listener to hand off and no service to shut down
+        assert!(
+            matches!(
+                outcome,
+                UpgradeOutcome::Done(UpgradeResult::NoListeners, false)
+            ),
+            "{outcome:?}"
+        );
+        // the configured timeout replaces the default one
+        assert!(elapsed >= close_timeout, "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(CLOSE_TIMEOUT), "{elapsed:?}");
+    }
+
+    #[test]
+    fn test_add_service_on() {
+        use crate::services::background::{background_service, BackgroundService};
+        use async_trait::async_trait;
+
+        struct Bg(watch::Sender<bool>);
+
+        #[async_trait]
+        impl BackgroundService for Bg {
+            async fn start(&self, mut shutdown: ShutdownWatch) {
+                self.0.send_replace(true);
+                let _ = shutdown.changed().await;
+            }
+        }
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let (tx, mut started) = watch::channel(false);
+        let mut server = Server::new(None).unwrap();
+        server.add_service_on(background_service("bg", Bg(tx)), runtime.handle().clone());
+        assert!(server
+            .shutdown_handle()
+            .is_service_drained("BG bg")
+            .is_some());
+
+        // no runtime is created for it
+        assert!(server.run_services().is_empty());
+        assert_eq!(server.external_services.len(), 1);
+        runtime
+            .block_on(started.wait_for(|started| *started))
+            .unwrap();
+
+        server.shutdown_watch.send(true).unwrap();
+        let service = server.external_services.pop().unwrap();
+        runtime.block_on(service).unwrap();
+    }
+}
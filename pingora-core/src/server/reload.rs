@@ -0,0 +1,57 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reload the configuration on SIGHUP
+//!
+//! Each successful reload bumps the configuration generation, which starts at 0 for the
+//! configuration the server started with. Services subscribe to the reloads via
+//! [Server::reload_watch()](super::Server::reload_watch) to get the new configuration along with
+//! its generation. With the `prometheus` feature the generation is also reported as the
+//! `pingora_config_generation` gauge.
+
+use std::sync::Arc;
+use tokio::sync::watch;
+
+use super::configuration::ServerConf;
+
+/// A configuration the server loaded and its generation
+#[derive(Debug, Clone)]
+pub struct ConfReload {
+    /// 0 for the configuration the server started with, and then bumped on each reload
+    pub generation: u64,
+    /// The configuration of this generation
+    pub conf: Arc<ServerConf>,
+}
+
+/// The receiver of the configuration reloads. It holds the latest configuration loaded.
+pub type ReloadWatch = watch::Receiver<ConfReload>;
+
+#[cfg(feature = "prometheus")]
+mod metrics {
+    use once_cell::sync::Lazy;
+    use prometheus::{register_int_gauge, IntGauge};
+
+    pub(super) static GENERATION: Lazy<IntGauge> = Lazy::new(|| {
+        register_int_gauge!(
+            "pingora_config_generation",
+            "Generation of the configuration in effect, bumped on each reload"
+        )
+        .unwrap()
+    });
+}
+
+pub(crate) fn record_generation(_generation: u64) {
+    #[cfg(feature = "prometheus")]
+    metrics::GENERATION.set(_generation as i64);
+}
@@ -0,0 +1,164 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The record of why and how the server shut down
+//!
+//! Once the grace period is over, the server logs a [ShutdownEvent] as a single line of
+//! `key=value` pairs and sends it to the subscribers of
+//! [Server::shutdown_event_watch()](super::Server::shutdown_event_watch), right before the
+//! runtimes are shut down.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use super::UpgradeResult;
+use crate::services::listening::active_connections;
+
+/// The signal that started the shutdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownSignal {
+    /// SIGINT: exit right away
+    Fast,
+    /// SIGTERM: stop accepting and exit after the grace period
+    GracefulTerminate,
+    /// SIGQUIT: hand the listening sockets to the new process and then shut down gracefully
+    GracefulUpgrade,
+}
+
+impl ShutdownSignal {
+    /// The name of the signal, e.g., `SIGTERM`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShutdownSignal::Fast => "SIGINT",
+            ShutdownSignal::GracefulTerminate => "SIGTERM",
+            ShutdownSignal::GracefulUpgrade => "SIGQUIT",
+        }
+    }
+}
+
+/// The summary of a server shutdown
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownEvent {
+    /// The signal that started the shutdown
+    pub signal: ShutdownSignal,
+    /// Whether the connections were given the grace period to finish
+    ///
+    /// The grace period is skipped on SIGINT, and when all the services already exited before
+    /// they could be told to shut down.
+    pub graceful: bool,
+    /// The result of handing off the listening sockets, if it was a graceful upgrade that
+    /// completed
+    pub upgrade: Option<UpgradeResult>,
+    /// How long it took for all the connections to finish after the shutdown started, or the
+    /// whole grace period if some were still open at the deadline
+    pub drain_time: Duration,
+    /// The number of the downstream connections still open at the deadline
+    pub remaining_connections: usize,
+}
+
+impl fmt::Display for ShutdownEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "signal={} graceful={} drain_time={:?} remaining_connections={}",
+            self.signal.as_str(),
+            self.graceful,
+            self.drain_time,
+            self.remaining_connections
+        )?;
+        match self.upgrade {
+            Some(UpgradeResult::Sent(_)) => write!(f, " upgrade=sent"),
+            Some(UpgradeResult::NoListeners) => write!(f, " upgrade=no_listeners"),
+            Some(UpgradeResult::Failed(e)) => write!(f, " upgrade=failed({e})"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The receiver of the [ShutdownEvent], `None` until the server shuts down
+pub type ShutdownEventWatch = watch::Receiver<Option<ShutdownEvent>>;
+
+// how often to check whether the connections are drained during the grace period
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Block for the grace period, and return how long it took for the connections to drain and how
+/// many of them were left at the end of it.
+///
+/// The full grace period is always waited for, so that the new process of an upgrade has the
+/// time to take over before the listening sockets are closed.
+pub(crate) fn wait_grace_period(grace_period: Duration) -> (Duration, usize) {
+    let start = Instant::now();
+    let deadline = start + grace_period;
+    let mut drained_at = None;
+    loop {
+        let now = Instant::now();
+        if drained_at.is_none() && active_connections() == 0 {
+            drained_at = Some(now);
+        }
+        if now >= deadline {
+            break;
+        }
+        std::thread::sleep(DRAIN_CHECK_INTERVAL.min(deadline - now));
+    }
+    let remaining = active_connections();
+    let drained_at = match drained_at {
+        Some(drained_at) if remaining == 0 => drained_at,
+        _ => deadline,
+    };
+    (drained_at - start, remaining)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::errno::Errno;
+
+    #[test]
+    fn test_display() {
+        let event = ShutdownEvent {
+            signal: ShutdownSignal::GracefulUpgrade,
+            graceful: true,
+            upgrade: Some(UpgradeResult::Failed(Errno::ECONNREFUSED)),
+            drain_time: Duration::from_millis(1500),
+            remaining_connections: 3,
+        };
+        assert_eq!(
+            event.to_string(),
+            "signal=SIGQUIT graceful=true drain_time=1.5s remaining_connections=3 \
+             upgrade=failed(ECONNREFUSED: Connection refused)"
+        );
+
+        let event = ShutdownEvent {
+            signal: ShutdownSignal::Fast,
+            graceful: false,
+            upgrade: None,
+            drain_time: Duration::ZERO,
+            remaining_connections: 0,
+        };
+        assert_eq!(
+            event.to_string(),
+            "signal=SIGINT graceful=false drain_time=0ns remaining_connections=0"
+        );
+    }
+
+    #[test]
+    fn test_wait_grace_period() {
+        // no connection is open in this test
+        let (drain_time, remaining) = wait_grace_period(Duration::from_millis(50));
+        if remaining == 0 {
+            assert!(drain_time < Duration::from_millis(50));
+        }
+    }
+}
@@ -12,8 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use log::error;
 #[cfg(target_os = "linux")]
-use log::{debug, error, warn};
+use log::{debug, warn};
 use nix::errno::Errno;
 #[cfg(target_os = "linux")]
 use nix::sys::socket::{self, AddressFamily, RecvMsg, SockFlag, SockType, UnixAddr};
@@ -21,7 +22,6 @@ use nix::sys::socket::{self, AddressFamily, RecvMsg, SockFlag, SockType, UnixAdd
 use nix::sys::stat;
 use nix::{Error, NixPath};
 use std::collections::HashMap;
-use std::io::Write;
 #[cfg(target_os = "linux")]
 use std::io::{IoSlice, IoSliceMut};
 use std::os::unix::io::RawFd;
@@ -30,15 +30,49 @@ use std::{thread, time};
 
 // Utilities to transfer file descriptors between sockets, e.g. during graceful upgrades.
 
+// The fds are sent in chunks, one message each, as one message can only carry a limited number
+// of fds. The payload of each message starts with a header: the magic, the version of the format,
+// the total number of the fds and the number of the fds in this message, all big endian. The
+// listen address of each fd in this message follows, in the same order as the fds, each prefixed
+// with its u16 length. Bump the version whenever the format changes so that a mismatched binary
+// rejects the fds instead of misinterpreting them. The unversioned format of the older servers,
+// a single message of the space separated addresses, is still accepted so that they can be
+// upgraded from.
+const FDS_MAGIC: &[u8; 4] = b"PGFD";
+const FDS_VERSION: u16 = 2;
+const FDS_HEADER_LEN: usize = 10;
+const MAX_PAYLOAD_LEN: usize = 8192;
+// well below the SCM_RIGHTS limit of a message, 253 on Linux
+const MAX_FDS_PER_MSG: usize = 32;
+// The SO_REUSEPORT sockets of an address are sent in order, each under the address with this
+// suffix, so that the receiver can tell them apart from the only socket of a plain address.
+const REUSEPORT_SUFFIX: &str = "#reuseport";
+
+/// The fds and the payload of a message received
+pub type FdMessage = (Vec<RawFd>, Vec<u8>);
+
 /// Container for open file descriptors and their associated bind addresses.
+///
+/// An address bound with SO_REUSEPORT can have several sockets, one per endpoint listening on it.
+/// These are tracked apart from the plain ones so that each endpoint of the new process adopts
+/// its own socket from the old process instead of binding another one that competes with them.
 pub struct Fds {
     map: HashMap<String, RawFd>,
+    reuseport: HashMap<String, ReuseportFds>,
+}
+
+#[derive(Default)]
+struct ReuseportFds {
+    fds: Vec<RawFd>,
+    // how many of the fds are adopted by the endpoints of this process
+    adopted: usize,
 }
 
 impl Fds {
     pub fn new() -> Self {
         Fds {
             map: HashMap::new(),
+            reuseport: HashMap::new(),
         }
     }
 
@@ -50,11 +84,72 @@ impl Fds {
         self.map.get(bind)
     }
 
+    /// Add a SO_REUSEPORT socket newly bound to `bind` by an endpoint of this process.
+    pub fn add_reuseport(&mut self, bind: String, fd: RawFd) {
+        let entry = self.reuseport.entry(bind).or_default();
+        entry.fds.push(fd);
+        entry.adopted = entry.fds.len();
+    }
+
+    /// Take the next SO_REUSEPORT socket of `bind` that no endpoint of this process adopted yet.
+    ///
+    /// Return `None` when all of them are taken, in which case the endpoint should bind a new one
+    /// and [Self::add_reuseport()] it.
+    pub fn take_reuseport(&mut self, bind: &str) -> Option<RawFd> {
+        let entry = self.reuseport.get_mut(bind)?;
+        let fd = entry.fds.get(entry.adopted).copied()?;
+        entry.adopted += 1;
+        Some(fd)
+    }
+
+    /// The SO_REUSEPORT sockets passed from the old process that no endpoint adopted.
+    ///
+    /// These happen when the new process listens on fewer endpoints of an address than the old
+    /// one. They still take their share of the new connections from the kernel, which are never
+    /// accepted unless the sockets are closed, see [Self::take_unadopted_reuseport()].
+    pub fn unadopted_reuseport(&self) -> Vec<(&str, RawFd)> {
+        self.reuseport
+            .iter()
+            .flat_map(|(bind, entry)| {
+                entry.fds[entry.adopted..]
+                    .iter()
+                    .map(move |fd| (bind.as_str(), *fd))
+            })
+            .collect()
+    }
+
+    /// Remove the SO_REUSEPORT sockets that no endpoint adopted from the table and return them
+    ///
+    /// The caller is responsible for closing them. Endpoints of these addresses that listen
+    /// afterwards bind new sockets.
+    pub fn take_unadopted_reuseport(&mut self) -> Vec<(String, RawFd)> {
+        self.reuseport
+            .iter_mut()
+            .flat_map(|(bind, entry)| {
+                entry
+                    .fds
+                    .split_off(entry.adopted)
+                    .into_iter()
+                    .map(move |fd| (bind.clone(), fd))
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty() && self.reuseport.is_empty()
+    }
+
     pub fn serialize(&self) -> (Vec<String>, Vec<RawFd>) {
         let serialized: Vec<(String, RawFd)> = self
             .map
             .iter()
             .map(|(key, value)| (key.clone(), *value))
+            .chain(self.reuseport.iter().flat_map(|(key, entry)| {
+                // only the adopted ones are in use, the next process shouldn't adopt the others
+                entry.fds[..entry.adopted]
+                    .iter()
+                    .map(move |fd| (format!("{key}{REUSEPORT_SUFFIX}"), *fd))
+            }))
             .collect();
 
         (
@@ -68,7 +163,13 @@ impl Fds {
         assert!(binds.len() == fds.len());
         // TODO: use zip()
         for i in 0..binds.len() {
-            self.map.insert(binds[i].clone(), fds[i]);
+            if let Some(bind) = binds[i].strip_suffix(REUSEPORT_SUFFIX) {
+                // nothing received is adopted yet
+                let entry = self.reuseport.entry(bind.to_string()).or_default();
+                entry.fds.push(fds[i]);
+            } else {
+                self.map.insert(binds[i].clone(), fds[i]);
+            }
         }
     }
 
@@ -77,47 +178,183 @@ impl Fds {
         P: ?Sized + NixPath + std::fmt::Display,
     {
         let (vec_key, vec_fds) = self.serialize();
-        let mut ser_buf: [u8; 2048] = [0; 2048];
-        let ser_key_size = serialize_vec_string(&vec_key, &mut ser_buf);
-        send_fds_to(vec_fds, &ser_buf[..ser_key_size], path)
+        let total = vec_key.len();
+        let payloads = if vec_key.is_empty() {
+            // still tell the receiver that there is nothing to expect
+            vec![encode_payload(0, &[])?]
+        } else {
+            vec_key
+                .chunks(MAX_FDS_PER_MSG)
+                .map(|keys| encode_payload(total, keys))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let messages: Vec<(&[RawFd], &[u8])> = payloads
+            .iter()
+            .enumerate()
+            .map(|(i, payload)| {
+                let start = (i * MAX_FDS_PER_MSG).min(total);
+                let end = (start + MAX_FDS_PER_MSG).min(total);
+                (&vec_fds[start..end], payload.as_slice())
+            })
+            .collect();
+        send_fds_batch_to(&messages, path)
     }
 
     pub fn get_from_sock<P>(&mut self, path: &P) -> Result<(), Error>
     where
         P: ?Sized + NixPath + std::fmt::Display,
     {
-        let mut de_buf: [u8; 2048] = [0; 2048];
-        let (fds, bytes) = get_fds_from(path, &mut de_buf)?;
-        let keys = deserialize_vec_string(&de_buf[..bytes]);
+        self.get_from_sock_with_timeout(path, None)
+    }
+
+    /// Same as [Self::get_from_sock()] but give up if the old process doesn't connect within
+    /// `timeout`, instead of the default of about 6 seconds
+    pub fn get_from_sock_with_timeout<P>(
+        &mut self,
+        path: &P,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<(), Error>
+    where
+        P: ?Sized + NixPath + std::fmt::Display,
+    {
+        let messages = get_fds_batch_from(path, timeout)?;
+        let keys = decode_messages(&messages);
+        let fds: Vec<RawFd> = messages.into_iter().flat_map(|(fds, _)| fds).collect();
+        let keys = match keys {
+            Ok(keys) => keys,
+            Err(e) => {
+                error!("Rejecting the fds received from {path}");
+                // don't adopt the fds that can't be trusted
+                for fd in fds {
+                    let _ = nix::unistd::close(fd);
+                }
+                return Err(e);
+            }
+        };
         self.deserialize(keys, fds);
         Ok(())
     }
 }
 
-fn serialize_vec_string(vec_string: &[String], mut buf: &mut [u8]) -> usize {
-    // There are many ways to do this. Serde is probably the way to go
-    // But let's start with something simple: space separated strings
-    let joined = vec_string.join(" ");
-    // TODO: check the buf is large enough
-    buf.write(joined.as_bytes()).unwrap()
+fn encode_payload(total: usize, binds: &[String]) -> Result<Vec<u8>, Error> {
+    let mut payload = Vec::with_capacity(MAX_PAYLOAD_LEN);
+    payload.extend_from_slice(FDS_MAGIC);
+    payload.extend_from_slice(&FDS_VERSION.to_be_bytes());
+    for count in [total, binds.len()] {
+        let count = u16::try_from(count).map_err(|_| Errno::E2BIG)?;
+        payload.extend_from_slice(&count.to_be_bytes());
+    }
+    for bind in binds {
+        let len = u16::try_from(bind.len()).map_err(|_| Errno::E2BIG)?;
+        payload.extend_from_slice(&len.to_be_bytes());
+        payload.extend_from_slice(bind.as_bytes());
+    }
+    if payload.len() > MAX_PAYLOAD_LEN {
+        error!(
+            "The addresses of the fds take {} bytes, more than {MAX_PAYLOAD_LEN}",
+            payload.len()
+        );
+        return Err(Errno::E2BIG);
+    }
+    Ok(payload)
+}
+
+// check that all the fds announced are received along with their addresses
+fn decode_messages(messages: &[FdMessage]) -> Result<Vec<String>, Error> {
+    if let [(fds, payload)] = messages {
+        if !payload.starts_with(FDS_MAGIC) {
+            return decode_legacy_payload(payload, fds.len());
+        }
+    }
+    let mut binds = Vec::new();
+    let mut expected = None;
+    for (fds, payload) in messages {
+        let (total, mut keys) = decode_payload(payload)?;
+        if *expected.get_or_insert(total) != total {
+            error!("The fds received disagree on the total number of them");
+            return Err(Errno::EPROTO);
+        }
+        if keys.len() != fds.len() {
+            error!(
+                "Received {} fds but {} addresses in one message",
+                fds.len(),
+                keys.len()
+            );
+            return Err(Errno::EPROTO);
+        }
+        binds.append(&mut keys);
+    }
+    let expected = expected.unwrap_or_default();
+    if binds.len() != expected {
+        error!("Received {} fds, expecting {expected}", binds.len());
+        return Err(Errno::EPROTO);
+    }
+    Ok(binds)
+}
+
+// the space separated addresses of the fds sent by the servers before the format was versioned
+fn decode_legacy_payload(buf: &[u8], fds: usize) -> Result<Vec<String>, Error> {
+    let Ok(joined) = std::str::from_utf8(buf) else {
+        error!("The fds received are not sent by a compatible server, rejecting them");
+        return Err(Errno::EPROTO);
+    };
+    let binds: Vec<String> = joined.split_ascii_whitespace().map(String::from).collect();
+    if binds.len() != fds {
+        error!("Received {fds} fds but {} addresses", binds.len());
+        return Err(Errno::EPROTO);
+    }
+    Ok(binds)
 }
 
-fn deserialize_vec_string(buf: &[u8]) -> Vec<String> {
-    let joined = std::str::from_utf8(buf).unwrap(); // TODO: handle error
-    let mut results: Vec<String> = Vec::new();
-    for iter in joined.split_ascii_whitespace() {
-        results.push(String::from(iter));
+fn decode_payload(buf: &[u8]) -> Result<(usize, Vec<String>), Error> {
+    if buf.len() < FDS_HEADER_LEN || &buf[..4] != FDS_MAGIC {
+        error!("The fds received are not sent by a compatible server, rejecting them");
+        return Err(Errno::EPROTO);
+    }
+    let version = u16::from_be_bytes([buf[4], buf[5]]);
+    if version != FDS_VERSION {
+        error!(
+            "The fds received are of version {version}, expecting {FDS_VERSION}, rejecting them"
+        );
+        return Err(Errno::EPROTO);
     }
-    results
+    let total = u16::from_be_bytes([buf[6], buf[7]]) as usize;
+    let count = u16::from_be_bytes([buf[8], buf[9]]) as usize;
+    let mut binds = Vec::with_capacity(count);
+    let mut rest = &buf[FDS_HEADER_LEN..];
+    for _ in 0..count {
+        let len = match rest {
+            [a, b, ..] => u16::from_be_bytes([*a, *b]) as usize,
+            _ => usize::MAX,
+        };
+        let Some(bind) = rest
+            .get(2..len.saturating_add(2))
+            .and_then(|b| std::str::from_utf8(b).ok())
+        else {
+            error!("The addresses of the fds received are malformed, rejecting them");
+            return Err(Errno::EPROTO);
+        };
+        binds.push(bind.to_string());
+        rest = &rest[2 + len..];
+    }
+    Ok((total, binds))
 }
 
 #[cfg(target_os = "linux")]
-pub fn get_fds_from<P>(path: &P, payload: &mut [u8]) -> Result<(Vec<RawFd>, usize), Error>
+fn cleanup_listen_sock<P>(listen_fd: RawFd, path: &P)
 where
     P: ?Sized + NixPath + std::fmt::Display,
 {
-    const MAX_FDS: usize = 32;
+    if nix::unistd::close(listen_fd).is_ok() {
+        nix::unistd::unlink(path).unwrap();
+    }
+}
 
+#[cfg(target_os = "linux")]
+fn listen_and_accept<P>(path: &P, timeout: Option<time::Duration>) -> Result<(RawFd, RawFd), Error>
+where
+    P: ?Sized + NixPath + std::fmt::Display,
+{
     let listen_fd = socket::socket(
         AddressFamily::Unix,
         SockType::Stream,
@@ -150,47 +387,164 @@ where
 
     socket::listen(listen_fd, 8).unwrap();
 
-    let fd = match accept_with_retry(listen_fd) {
-        Ok(fd) => fd,
+    // keep sleeping RETRY_INTERVAL until the timeout
+    let max_retry = timeout.map_or(MAX_RETRY + 1, |t| {
+        (t.as_secs_f64() / RETRY_INTERVAL.as_secs_f64()).ceil() as usize
+    });
+    match accept_with_retry(listen_fd, max_retry) {
+        Ok(fd) => Ok((listen_fd, fd)),
         Err(e) => {
             error!("Giving up reading socket from: {path}, error: {e:?}");
-            //cleanup
-            if nix::unistd::close(listen_fd).is_ok() {
-                nix::unistd::unlink(path).unwrap();
+            cleanup_listen_sock(listen_fd, path);
+            Err(e)
+        }
+    }
+}
+
+// Read into `buf` once, collecting the fds that come along. Return the number of bytes read, 0
+// if the peer closed the connection.
+#[cfg(target_os = "linux")]
+fn recv_some(fd: RawFd, buf: &mut [u8], fds: &mut Vec<RawFd>) -> Result<usize, Error> {
+    loop {
+        let mut io_vec = [IoSliceMut::new(buf); 1];
+        let mut cmsg_buf = nix::cmsg_space!([RawFd; MAX_FDS_PER_MSG]);
+        let msg: RecvMsg<UnixAddr> = match socket::recvmsg(
+            fd,
+            &mut io_vec,
+            Some(&mut cmsg_buf),
+            socket::MsgFlags::empty(),
+        ) {
+            Ok(msg) => msg,
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e),
+        };
+        for cmsg in msg.cmsgs() {
+            if let socket::ControlMessageOwned::ScmRights(mut vec_fds) = cmsg {
+                fds.append(&mut vec_fds)
+            } else {
+                warn!("Unexpected control messages: {cmsg:?}")
             }
-            return Err(e);
         }
-    };
+        if msg.flags.contains(socket::MsgFlags::MSG_CTRUNC) {
+            error!("More than {MAX_FDS_PER_MSG} fds in one message, some of them are lost");
+            return Err(Errno::EPROTO);
+        }
+        return Ok(msg.bytes);
+    }
+}
 
-    let mut io_vec = [IoSliceMut::new(payload); 1];
-    let mut cmsg_buf = nix::cmsg_space!([RawFd; MAX_FDS]);
-    let msg: RecvMsg<UnixAddr> = socket::recvmsg(
-        fd,
-        &mut io_vec,
-        Some(&mut cmsg_buf),
-        socket::MsgFlags::empty(),
-    )
-    .unwrap();
+// Fill `buf` from the socket, collecting the fds that come along. Return false if the peer closes
+// the connection before anything is read.
+#[cfg(target_os = "linux")]
+fn recv_exact(fd: RawFd, buf: &mut [u8], fds: &mut Vec<RawFd>) -> Result<bool, Error> {
+    let mut read = 0;
+    while read < buf.len() {
+        let n = recv_some(fd, &mut buf[read..], fds)?;
+        if n == 0 {
+            if read == 0 {
+                return Ok(false);
+            }
+            error!("Connection closed in the middle of a message");
+            return Err(Errno::EPROTO);
+        }
+        read += n;
+    }
+    Ok(true)
+}
 
-    let mut fds: Vec<RawFd> = Vec::new();
-    for cmsg in msg.cmsgs() {
-        if let socket::ControlMessageOwned::ScmRights(mut vec_fds) = cmsg {
-            fds.append(&mut vec_fds)
-        } else {
-            warn!("Unexpected control messages: {cmsg:?}")
+// Receive the length prefixed messages until the peer closes the connection
+//
+// The servers before the messages were framed send a single message of the space separated
+// addresses, which can't start with a 0 byte unlike the length of a framed one. It is received
+// as is till the connection closes.
+#[cfg(target_os = "linux")]
+fn recv_batch(fd: RawFd, messages: &mut Vec<FdMessage>) -> Result<(), Error> {
+    loop {
+        let mut fds = Vec::new();
+        let mut len_buf = [0; 4];
+        let more = recv_exact(fd, &mut len_buf[..1], &mut fds);
+        // keep track of the fds so that they are closed if anything goes wrong
+        messages.push((fds, Vec::new()));
+        if !more? {
+            messages.pop();
+            return Ok(());
+        }
+        let first = messages.len() == 1;
+        let (fds, payload) = messages.last_mut().unwrap();
+        if first && len_buf[0] != 0 {
+            payload.push(len_buf[0]);
+            return recv_legacy(fd, payload, fds);
+        }
+        if !recv_exact(fd, &mut len_buf[1..], fds)? {
+            error!("Connection closed in the middle of a message");
+            return Err(Errno::EPROTO);
+        }
+        let len = u32::from_be_bytes(len_buf) as usize;
+        if len > MAX_PAYLOAD_LEN {
+            error!("The fds received are not sent by a compatible server, rejecting them");
+            return Err(Errno::EPROTO);
+        }
+        payload.resize(len, 0);
+        if !recv_exact(fd, payload, fds)? && len > 0 {
+            error!("Connection closed in the middle of a message");
+            return Err(Errno::EPROTO);
         }
     }
+}
 
-    //cleanup
-    if nix::unistd::close(listen_fd).is_ok() {
-        nix::unistd::unlink(path).unwrap();
+// Receive the rest of the unframed message of a legacy sender
+#[cfg(target_os = "linux")]
+fn recv_legacy(fd: RawFd, payload: &mut Vec<u8>, fds: &mut Vec<RawFd>) -> Result<(), Error> {
+    let mut buf = [0; 1024];
+    loop {
+        let n = recv_some(fd, &mut buf, fds)?;
+        if n == 0 {
+            return Ok(());
+        }
+        payload.extend_from_slice(&buf[..n]);
+        if payload.len() > MAX_PAYLOAD_LEN {
+            error!("The fds received are not sent by a compatible server, rejecting them");
+            return Err(Errno::EPROTO);
+        }
     }
+}
 
-    Ok((fds, msg.bytes))
+/// Receive the fds and the payloads sent by [send_fds_batch_to()], one entry per message
+///
+/// Give up if no sender connects within `timeout`. On error, the fds received so far are closed.
+#[cfg(target_os = "linux")]
+pub fn get_fds_batch_from<P>(
+    path: &P,
+    timeout: Option<time::Duration>,
+) -> Result<Vec<FdMessage>, Error>
+where
+    P: ?Sized + NixPath + std::fmt::Display,
+{
+    let (listen_fd, fd) = listen_and_accept(path, timeout)?;
+    let mut messages = Vec::new();
+    let result = recv_batch(fd, &mut messages);
+    let _ = nix::unistd::close(fd);
+    cleanup_listen_sock(listen_fd, path);
+    match result {
+        Ok(()) => Ok(messages),
+        Err(e) => {
+            error!("Error receiving sockets from: {path}, error: {e:?}");
+            messages
+                .into_iter()
+                .flat_map(|(fds, _)| fds)
+                .for_each(|fd| {
+                    let _ = nix::unistd::close(fd);
+                });
+            Err(e)
+        }
+    }
 }
 
 #[cfg(not(target_os = "linux"))]
-pub fn get_fds_from<P>(_path: &P, _payload: &mut [u8]) -> Result<(Vec<RawFd>, usize), Error>
+pub fn get_fds_batch_from<P>(
+    _path: &P,
+    _timeout: Option<std::time::Duration>,
+) -> Result<Vec<FdMessage>, Error>
 where
     P: ?Sized + NixPath + std::fmt::Display,
 {
@@ -201,15 +555,19 @@ where
 const MAX_RETRY: usize = 5;
 #[cfg(target_os = "linux")]
 const RETRY_INTERVAL: time::Duration = time::Duration::from_secs(1);
+#[cfg(target_os = "linux")]
+const MAX_NONBLOCKING_POLLS: usize = 20;
+#[cfg(target_os = "linux")]
+const NONBLOCKING_POLL_INTERVAL: time::Duration = time::Duration::from_millis(500);
 
 #[cfg(target_os = "linux")]
-fn accept_with_retry(listen_fd: i32) -> Result<i32, Error> {
+fn accept_with_retry(listen_fd: i32, max_retry: usize) -> Result<i32, Error> {
     let mut retried = 0;
     loop {
         match socket::accept(listen_fd) {
             Ok(fd) => return Ok(fd),
             Err(e) => {
-                if retried > MAX_RETRY {
+                if retried >= max_retry {
                     return Err(e);
                 }
                 match e {
@@ -231,26 +589,20 @@ fn accept_with_retry(listen_fd: i32) -> Result<i32, Error> {
 }
 
 #[cfg(target_os = "linux")]
-pub fn send_fds_to<P>(fds: Vec<RawFd>, payload: &[u8], path: &P) -> Result<usize, Error>
+fn connect_with_retry<P>(
+    send_fd: RawFd,
+    path: &P,
+    nonblocking_polls: &mut usize,
+) -> Result<(), Error>
 where
     P: ?Sized + NixPath + std::fmt::Display,
 {
-    const MAX_NONBLOCKING_POLLS: usize = 20;
-    const NONBLOCKING_POLL_INTERVAL: time::Duration = time::Duration::from_millis(500);
-
-    let send_fd = socket::socket(
-        AddressFamily::Unix,
-        SockType::Stream,
-        SockFlag::SOCK_NONBLOCK,
-        None,
-    )?;
     let unix_addr = UnixAddr::new(path)?;
     let mut retried = 0;
-    let mut nonblocking_polls = 0;
 
-    let conn_result: Result<usize, Error> = loop {
+    loop {
         match socket::connect(send_fd, &unix_addr) {
-            Ok(_) => break Ok(0),
+            Ok(_) => break Ok(()),
             Err(e) => match e {
                 /* If the new process hasn't created the upgrade sock we'll get an ENOENT.
                 ECONNREFUSED may happen if the sock wasn't cleaned up
@@ -271,8 +623,8 @@ where
                 }
                 /* handle nonblocking IO */
                 Errno::EINPROGRESS => {
-                    nonblocking_polls += 1;
-                    if nonblocking_polls >= MAX_NONBLOCKING_POLLS {
+                    *nonblocking_polls += 1;
+                    if *nonblocking_polls >= MAX_NONBLOCKING_POLLS {
                         error!("Connect() not ready after retries when sending socket to: {path}",);
                         break Err(e);
                     }
@@ -285,53 +637,98 @@ where
                 }
             },
         }
-    };
+    }
+}
 
-    let result = match conn_result {
-        Ok(_) => {
-            let io_vec = [IoSlice::new(payload); 1];
-            let scm = socket::ControlMessage::ScmRights(fds.as_slice());
-            let cmsg = [scm; 1];
-            loop {
-                match socket::sendmsg(
-                    send_fd,
-                    &io_vec,
-                    &cmsg,
-                    socket::MsgFlags::empty(),
-                    None::<&UnixAddr>,
-                ) {
-                    Ok(result) => break Ok(result),
-                    Err(e) => match e {
-                        /* handle nonblocking IO */
-                        Errno::EAGAIN => {
-                            nonblocking_polls += 1;
-                            if nonblocking_polls >= MAX_NONBLOCKING_POLLS {
-                                error!(
-                                    "Sendmsg() not ready after retries when sending socket to: {}",
-                                    path
-                                );
-                                break Err(e);
-                            }
-                            warn!(
-                                "Sendmsg() not ready, will try again in {:?}",
-                                NONBLOCKING_POLL_INTERVAL
-                            );
-                            thread::sleep(NONBLOCKING_POLL_INTERVAL);
-                        }
-                        _ => break Err(e),
-                    },
+// Send the whole payload, the fds go along with its first byte
+#[cfg(target_os = "linux")]
+fn send_msg<P>(
+    send_fd: RawFd,
+    fds: &[RawFd],
+    payload: &[u8],
+    path: &P,
+    nonblocking_polls: &mut usize,
+) -> Result<usize, Error>
+where
+    P: ?Sized + NixPath + std::fmt::Display,
+{
+    let mut sent = 0;
+    while sent < payload.len() {
+        let io_vec = [IoSlice::new(&payload[sent..]); 1];
+        let scm = [socket::ControlMessage::ScmRights(fds); 1];
+        // the fds are sent already if any byte is sent
+        let cmsg: &[socket::ControlMessage] = if sent == 0 { &scm } else { &[] };
+        match socket::sendmsg(
+            send_fd,
+            &io_vec,
+            cmsg,
+            socket::MsgFlags::empty(),
+            None::<&UnixAddr>,
+        ) {
+            Ok(n) => sent += n,
+            Err(e) => match e {
+                /* handle nonblocking IO */
+                Errno::EAGAIN => {
+                    *nonblocking_polls += 1;
+                    if *nonblocking_polls >= MAX_NONBLOCKING_POLLS {
+                        error!(
+                            "Sendmsg() not ready after retries when sending socket to: {}",
+                            path
+                        );
+                        return Err(e);
+                    }
+                    warn!(
+                        "Sendmsg() not ready, will try again in {:?}",
+                        NONBLOCKING_POLL_INTERVAL
+                    );
+                    thread::sleep(NONBLOCKING_POLL_INTERVAL);
                 }
+                Errno::EINTR => {}
+                _ => return Err(e),
+            },
+        }
+    }
+    Ok(sent)
+}
+
+/// Send the fds with their payloads over a single connection, one message each
+///
+/// Each payload is prefixed with its u32 length. The fds of each message should be no more than
+/// what [get_fds_batch_from()] can receive at once.
+#[cfg(target_os = "linux")]
+pub fn send_fds_batch_to<P>(messages: &[(&[RawFd], &[u8])], path: &P) -> Result<usize, Error>
+where
+    P: ?Sized + NixPath + std::fmt::Display,
+{
+    let send_fd = socket::socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_NONBLOCK,
+        None,
+    )?;
+    let mut nonblocking_polls = 0;
+
+    let result = connect_with_retry(send_fd, path, &mut nonblocking_polls).and_then(|_| {
+        let mut sent = 0;
+        for (fds, payload) in messages {
+            if fds.len() > MAX_FDS_PER_MSG || payload.len() > MAX_PAYLOAD_LEN {
+                error!("Too many fds or too large payload to send in one message to: {path}");
+                return Err(Errno::E2BIG);
             }
+            let mut framed = Vec::with_capacity(4 + payload.len());
+            framed.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            framed.extend_from_slice(payload);
+            sent += send_msg(send_fd, fds, &framed, path, &mut nonblocking_polls)?;
         }
-        Err(_) => conn_result,
-    };
+        Ok(sent)
+    });
 
     nix::unistd::close(send_fd).unwrap();
     result
 }
 
 #[cfg(not(target_os = "linux"))]
-pub fn send_fds_to<P>(_fds: Vec<RawFd>, _payload: &[u8], _path: &P) -> Result<usize, Error>
+pub fn send_fds_batch_to<P>(_messages: &[(&[RawFd], &[u8])], _path: &P) -> Result<usize, Error>
 where
     P: ?Sized + NixPath + std::fmt::Display,
 {
@@ -375,15 +772,117 @@ mod tests {
     }
 
     #[test]
-    fn test_vec_string_serde() {
+    fn test_reuseport_serde() {
+        init_log();
+        let mut fds = Fds::new();
+        let key = "1.1.1.1:80".to_string();
+        fds.add(key.clone(), 128);
+        fds.add_reuseport(key.clone(), 129);
+        fds.add_reuseport(key.clone(), 130);
+        // all added ones are in use by this process
+        assert_eq!(fds.take_reuseport(&key), None);
+        assert!(fds.unadopted_reuseport().is_empty());
+
+        let (k, v) = fds.serialize();
+        assert_eq!(k.len(), 3);
+        let mut fds2 = Fds::new();
+        fds2.deserialize(k, v);
+
+        assert_eq!(128, *fds2.get(&key).unwrap());
+        assert_eq!(
+            fds2.unadopted_reuseport(),
+            vec![("1.1.1.1:80", 129), ("1.1.1.1:80", 130)]
+        );
+        assert_eq!(fds2.take_reuseport(&key), Some(129));
+        assert_eq!(fds2.unadopted_reuseport(), vec![("1.1.1.1:80", 130)]);
+        // only the adopted ones are handed off again
+        let (k, v) = fds2.serialize();
+        assert_eq!(k.len(), 2);
+        assert!(v.contains(&129) && !v.contains(&130));
+        assert_eq!(fds2.take_reuseport(&key), Some(130));
+        assert_eq!(fds2.take_reuseport(&key), None);
+        fds2.add_reuseport(key.clone(), 131);
+        assert_eq!(fds2.take_reuseport(&key), None);
+        assert_eq!(fds2.serialize().0.len(), 4);
+    }
+
+    #[test]
+    fn test_take_unadopted_reuseport() {
         init_log();
-        let vec_str: Vec<String> = vec!["aaaa".to_string(), "bbb".to_string()];
-        let mut ser_buf: [u8; 1024] = [0; 1024];
-        let size = serialize_vec_string(&vec_str, &mut ser_buf);
-        let de_vec_string = deserialize_vec_string(&ser_buf[..size]);
-        assert_eq!(de_vec_string.len(), 2);
-        assert_eq!(de_vec_string[0], "aaaa");
-        assert_eq!(de_vec_string[1], "bbb");
+        let key = "1.1.1.1:80".to_string();
+        let mut fds = Fds::new();
+        fds.deserialize(
+            vec![format!("{key}{REUSEPORT_SUFFIX}"); 3],
+            vec![129, 130, 131],
+        );
+        assert_eq!(fds.take_reuseport(&key), Some(129));
+        assert_eq!(
+            fds.take_unadopted_reuseport(),
+            vec![(key.clone(), 130), (key.clone(), 131)]
+        );
+        assert!(fds.unadopted_reuseport().is_empty());
+        assert!(fds.take_unadopted_reuseport().is_empty());
+        // the taken ones are neither adopted nor handed off any more
+        assert_eq!(fds.take_reuseport(&key), None);
+        assert_eq!(fds.serialize().1, vec![129]);
+    }
+
+    #[test]
+    fn test_payload_serde() {
+        init_log();
+        let binds: Vec<String> = vec![
+            "aaaa".to_string(),
+            "bbb".to_string(),
+            "/tmp/with space.sock".to_string(),
+        ];
+        let payload = encode_payload(5, &binds).unwrap();
+        assert_eq!(&payload[..4], FDS_MAGIC);
+        let (total, decoded) = decode_payload(&payload).unwrap();
+        assert_eq!(total, 5);
+        assert_eq!(decoded, binds);
+
+        let (total, decoded) = decode_payload(&encode_payload(0, &[]).unwrap()).unwrap();
+        assert_eq!(total, 0);
+        assert!(decoded.is_empty());
+        let too_long = vec!["a".repeat(MAX_PAYLOAD_LEN)];
+        assert_eq!(encode_payload(1, &too_long).unwrap_err(), Errno::E2BIG);
+    }
+
+    #[test]
+    fn test_payload_mismatch() {
+        init_log();
+        let payload = encode_payload(1, &["1.1.1.1:80".to_string()]).unwrap();
+
+        // the space separated addresses of the old format are only accepted alone
+        assert_eq!(
+            decode_payload(b"1.1.1.1:80 1.1.1.1:443").unwrap_err(),
+            Errno::EPROTO
+        );
+        let legacy = b"1.1.1.1:80 1.1.1.1:443".to_vec();
+        assert_eq!(
+            decode_messages(&[(vec![3, 4], legacy.clone())]).unwrap(),
+            vec!["1.1.1.1:80", "1.1.1.1:443"]
+        );
+        assert_eq!(
+            decode_messages(&[(vec![3], legacy)]).unwrap_err(),
+            Errno::EPROTO
+        );
+        let mut other_version = payload.clone();
+        other_version[5] += 1;
+        assert_eq!(decode_payload(&other_version).unwrap_err(), Errno::EPROTO);
+        // truncated
+        assert_eq!(
+            decode_payload(&payload[..payload.len() - 1]).unwrap_err(),
+            Errno::EPROTO
+        );
+
+        // missing fds
+        let messages = vec![(vec![], payload.clone())];
+        assert_eq!(decode_messages(&messages).unwrap_err(), Errno::EPROTO);
+        // missing messages
+        let payload = encode_payload(2, &["1.1.1.1:80".to_string()]).unwrap();
+        let messages = vec![(vec![3], payload)];
+        assert_eq!(decode_messages(&messages).unwrap_err(), Errno::EPROTO);
     }
 
     #[test]
@@ -399,18 +898,23 @@ mod tests {
 
         // receiver need to start in another thread since it is blocking
         let child = thread::spawn(move || {
-            let mut buf: [u8; 32] = [0; 32];
-            let (fds, bytes) = get_fds_from("/tmp/pingora_fds_receive.sock", &mut buf).unwrap();
-            debug!("{:?}", fds);
+            let messages = get_fds_batch_from("/tmp/pingora_fds_receive.sock", None).unwrap();
+            debug!("{:?}", messages);
+            assert_eq!(2, messages.len());
+            let (fds, payload) = &messages[0];
             assert_eq!(1, fds.len());
-            assert_eq!(32, bytes);
-            assert_eq!(1, buf[0]);
-            assert_eq!(1, buf[31]);
+            assert_eq!(128, payload.len());
+            assert_eq!(1, payload[0]);
+            assert_eq!(1, payload[127]);
+            let (fds, payload) = &messages[1];
+            assert!(fds.is_empty());
+            assert_eq!(b"done", payload.as_slice());
         });
 
         let fds = vec![dumb_fd];
         let buf: [u8; 128] = [1; 128];
-        match send_fds_to(fds, &buf, "/tmp/pingora_fds_receive.sock") {
+        let messages: [(&[RawFd], &[u8]); 2] = [(&fds, &buf), (&[], b"done")];
+        match send_fds_batch_to(&messages, "/tmp/pingora_fds_receive.sock") {
             Ok(sent) => {
                 assert!(sent > 0);
             }
@@ -423,6 +927,54 @@ mod tests {
         child.join().unwrap();
     }
 
+    #[test]
+    fn test_receive_timeout() {
+        init_log();
+        let start = std::time::Instant::now();
+        let mut fds = Fds::new();
+        let e = fds
+            .get_from_sock_with_timeout(
+                "/tmp/pingora_fds_receive4.sock",
+                Some(time::Duration::from_secs(1)),
+            )
+            .unwrap_err();
+        assert_eq!(e, Errno::EAGAIN);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= time::Duration::from_secs(1) && elapsed < time::Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_send_receive_many_fds() {
+        init_log();
+        // more than what one message can carry
+        const COUNT: usize = 300;
+        let mut fds = Fds::new();
+        for i in 0..COUNT {
+            let fd = socket::socket(
+                AddressFamily::Unix,
+                SockType::Stream,
+                SockFlag::empty(),
+                None,
+            )
+            .unwrap();
+            fds.add(format!("127.0.0.1:{}", 10000 + i), fd);
+        }
+
+        let child = thread::spawn(move || {
+            let mut fds2 = Fds::new();
+            fds2.get_from_sock("/tmp/pingora_fds_receive3.sock")
+                .unwrap();
+            for i in 0..COUNT {
+                assert!(*fds2.get(&format!("127.0.0.1:{}", 10000 + i)).unwrap() > 0);
+            }
+            let (keys, _) = fds2.serialize();
+            assert_eq!(keys.len(), COUNT);
+        });
+
+        fds.send_to_sock("/tmp/pingora_fds_receive3.sock").unwrap();
+        child.join().unwrap();
+    }
+
     #[test]
     fn test_serde_via_socket() {
         init_log();
@@ -457,4 +1009,42 @@ mod tests {
         fds.send_to_sock("/tmp/pingora_fds_receive2.sock").unwrap();
         child.join().unwrap();
     }
+
+    #[test]
+    fn test_receive_legacy_fds() {
+        init_log();
+        let path = "/tmp/pingora_fds_receive_legacy.sock";
+        let fds: Vec<RawFd> = (0..2)
+            .map(|_| {
+                socket::socket(
+                    AddressFamily::Unix,
+                    SockType::Stream,
+                    SockFlag::empty(),
+                    None,
+                )
+                .unwrap()
+            })
+            .collect();
+
+        let child = thread::spawn(move || {
+            let mut fds2 = Fds::new();
+            fds2.get_from_sock(path).unwrap();
+            assert!(*fds2.get("1.1.1.1:80").unwrap() > 0);
+            assert!(*fds2.get("1.1.1.1:443").unwrap() > 0);
+        });
+
+        // a single unframed message like the servers before the format was versioned
+        let send_fd = socket::socket(
+            AddressFamily::Unix,
+            SockType::Stream,
+            SockFlag::empty(),
+            None,
+        )
+        .unwrap();
+        let mut polls = 0;
+        connect_with_retry(send_fd, path, &mut polls).unwrap();
+        send_msg(send_fd, &fds, b"1.1.1.1:80 1.1.1.1:443", path, &mut polls).unwrap();
+        nix::unistd::close(send_fd).unwrap();
+        child.join().unwrap();
+    }
 }
@@ -0,0 +1,167 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The outcome of handing off the listening sockets during graceful upgrades
+//!
+//! The counters are process wide. With the `prometheus` feature they are also reported as
+//! `pingora_fd_transfer_attempts_total`, `pingora_fd_transfer_successes_total`,
+//! `pingora_fd_transfer_failures_total` and the `pingora_fd_transfer_last_result` gauge, see
+//! [UpgradeResult::metric_value()].
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The result of sending the listening sockets to the new process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpgradeResult {
+    /// The listening sockets were sent, with the number of bytes sent along with them
+    Sent(usize),
+    /// There was no listening socket to send
+    NoListeners,
+    /// Sending the listening sockets failed
+    Failed(nix::Error),
+}
+
+impl UpgradeResult {
+    /// Whether the listening sockets were handed off to the new process
+    pub fn is_sent(&self) -> bool {
+        matches!(self, UpgradeResult::Sent(_))
+    }
+
+    /// The value of the `pingora_fd_transfer_last_result` gauge: 1 if sent, 0 if there was no
+    /// listener and -1 if failed
+    pub fn metric_value(&self) -> i64 {
+        match self {
+            UpgradeResult::Sent(_) => 1,
+            UpgradeResult::NoListeners => 0,
+            UpgradeResult::Failed(_) => -1,
+        }
+    }
+}
+
+/// The counters of the listening socket transfers of this process
+///
+/// Both sending the sockets to the new process and receiving them from the old one count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FdTransferStats {
+    /// The number of the transfers attempted
+    pub attempts: u64,
+    /// The number of the transfers succeeded
+    pub successes: u64,
+    /// The number of the transfers failed
+    pub failures: u64,
+    /// The result of the last attempt to send the sockets during a graceful upgrade, if any
+    pub last_upgrade: Option<UpgradeResult>,
+}
+
+static ATTEMPTS: AtomicU64 = AtomicU64::new(0);
+static SUCCESSES: AtomicU64 = AtomicU64::new(0);
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+static LAST_UPGRADE: Mutex<Option<UpgradeResult>> = Mutex::new(None);
+
+#[cfg(feature = "prometheus")]
+mod metrics {
+    use once_cell::sync::Lazy;
+    use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+
+    pub(super) static ATTEMPTS: Lazy<IntCounter> = Lazy::new(|| {
+        register_int_counter!(
+            "pingora_fd_transfer_attempts_total",
+            "Number of the listening socket transfers attempted"
+        )
+        .unwrap()
+    });
+    pub(super) static SUCCESSES: Lazy<IntCounter> = Lazy::new(|| {
+        register_int_counter!(
+            "pingora_fd_transfer_successes_total",
+            "Number of the listening socket transfers succeeded"
+        )
+        .unwrap()
+    });
+    pub(super) static FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+        register_int_counter!(
+            "pingora_fd_transfer_failures_total",
+            "Number of the listening socket transfers failed"
+        )
+        .unwrap()
+    });
+    pub(super) static LAST_RESULT: Lazy<IntGauge> = Lazy::new(|| {
+        register_int_gauge!(
+            "pingora_fd_transfer_last_result",
+            "Result of the last graceful upgrade: 1 sent, 0 no listener, -1 failed"
+        )
+        .unwrap()
+    });
+}
+
+// count the outcome of a transfer, in either direction
+pub(crate) fn record_transfer<T, E>(result: &Result<T, E>) {
+    ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+    let counter = if result.is_ok() {
+        &SUCCESSES
+    } else {
+        &FAILURES
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+
+    #[cfg(feature = "prometheus")]
+    {
+        metrics::ATTEMPTS.inc();
+        if result.is_ok() {
+            metrics::SUCCESSES.inc();
+        } else {
+            metrics::FAILURES.inc();
+        }
+    }
+}
+
+pub(crate) fn record_upgrade(result: UpgradeResult) {
+    *LAST_UPGRADE.lock().unwrap() = Some(result);
+    #[cfg(feature = "prometheus")]
+    metrics::LAST_RESULT.set(result.metric_value());
+}
+
+/// The counters of the listening socket transfers of this process so far
+pub fn fd_transfer_stats() -> FdTransferStats {
+    FdTransferStats {
+        attempts: ATTEMPTS.load(Ordering::Relaxed),
+        successes: SUCCESSES.load(Ordering::Relaxed),
+        failures: FAILURES.load(Ordering::Relaxed),
+        last_upgrade: *LAST_UPGRADE.lock().unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::errno::Errno;
+
+    #[test]
+    fn test_record() {
+        // other tests in this process may transfer too
+        let before = fd_transfer_stats();
+        record_transfer::<_, Errno>(&Ok(10));
+        record_transfer::<usize, _>(&Err(Errno::ECONNREFUSED));
+        record_upgrade(UpgradeResult::Failed(Errno::ECONNREFUSED));
+        let after = fd_transfer_stats();
+        assert!(after.attempts >= before.attempts + 2);
+        assert!(after.successes > before.successes);
+        assert!(after.failures > before.failures);
+        assert!(after.last_upgrade.is_some());
+
+        assert!(UpgradeResult::Sent(10).is_sent());
+        assert!(!UpgradeResult::NoListeners.is_sent());
+        assert_eq!(UpgradeResult::Failed(Errno::EPROTO).metric_value(), -1);
+    }
+}
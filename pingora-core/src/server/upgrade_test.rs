@@ -0,0 +1,229 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A harness to test zero downtime upgrades
+//!
+//! [UpgradeTest] performs a graceful upgrade between two servers in the current process, the same
+//! way as between an old and a new process, and checks the contract of the upgrade:
+//! - the listening sockets are sent from the old server to the new one
+//! - the connections established before the upgrade keep being served by the old server
+//! - the new connections are served by the new server
+//!
+//! Both servers are threads of the current process: no new process is executed, so this does not
+//! cover what only a real upgrade exercises, such as the binary and configuration of the new
+//! process, its daemonization or the fds being inherited across `exec()`.
+//!
+//! This module is available with the `test-utils` feature.
+
+use async_trait::async_trait;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::configuration::{Opt, ServerConf};
+use super::{Server, ShutdownWatch, UpgradeResult};
+use crate::apps::ServerApp;
+use crate::protocols::Stream;
+use crate::services::listening::Service;
+
+/// Run a graceful upgrade between two servers listening on the same Unix socket
+///
+/// The servers run a line based echo service which tells which server answers. The files of the
+/// test live in a new directory under the system temp directory.
+///
+/// ```no_run
+/// # use pingora_core::server::upgrade_test::UpgradeTest;
+/// // panics if the upgrade breaks its contract
+/// UpgradeTest::new().run();
+/// ```
+pub struct UpgradeTest {
+    dir: PathBuf,
+}
+
+impl Default for UpgradeTest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UpgradeTest {
+    /// Create a new [UpgradeTest] with its own upgrade and listening sockets
+    pub fn new() -> Self {
+        static TESTS: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "pingora_upgrade_test_{}_{}",
+            std::process::id(),
+            TESTS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).expect("create the test directory");
+        UpgradeTest { dir }
+    }
+
+    /// The path of the Unix socket both servers listen on
+    pub fn listen_path(&self) -> String {
+        self.dir.join("listen.sock").to_string_lossy().into_owned()
+    }
+
+    /// The path of the socket the listening sockets are sent over
+    pub fn upgrade_sock(&self) -> String {
+        self.dir.join("upgrade.sock").to_string_lossy().into_owned()
+    }
+
+    // the server the way the binary would create it from the command line
+    fn server(&self, name: &'static str, upgrade: bool) -> Server {
+        let args: &[&str] = if upgrade {
+            &["pingora", "--upgrade"]
+        } else {
+            &["pingora"]
+        };
+        let mut server = Server::new(Opt::from_iter(args)).expect("create the server");
+        let mut conf = ServerConf::new().expect("create the configuration");
+        conf.upgrade_sock = self.upgrade_sock();
+        conf.upgrade_timeout = Some(Duration::from_secs(10));
+        // no need to wait long for the new server, it is ready when it has the sockets
+        conf.upgrade_close_timeout = Some(Duration::from_millis(100));
+        server.configuration = Arc::new(conf);
+
+        let mut service = Service::new(format!("Echo {name}"), Arc::new(Echo(name)));
+        service.add_uds(&self.listen_path(), None);
+        server.add_service(service);
+        server
+    }
+
+    /// Run the upgrade, panic if any step of it fails
+    ///
+    /// Return the result of sending the listening sockets.
+    pub fn run(self) -> UpgradeResult {
+        let mut old = self.server("old", false);
+        old.load_fds(false).expect("load the listening sockets");
+        let old_runtimes = old.run_services();
+
+        let mut long_lived = connect(&self.listen_path());
+        assert_eq!(echo(&mut long_lived, "hello"), "old hello");
+
+        // the new server waits for the sockets like a new process started with --upgrade
+        let new = {
+            let mut new = self.server("new", true);
+            thread::spawn(move || new.load_fds(true).map(|_| new))
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let result = runtime.block_on(old.graceful_upgrade());
+        assert!(
+            matches!(result, UpgradeResult::Sent(_)),
+            "the listening sockets are not sent: {result:?}"
+        );
+        let mut new = new
+            .join()
+            .unwrap()
+            .expect("the new server receives the listening sockets");
+        let new_runtimes = new.run_services();
+
+        // the old server stops accepting once it broadcasts the shutdown
+        for _ in 0..500 {
+            if !old.services_running() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!old.services_running(), "the old server keeps accepting");
+
+        assert_eq!(
+            echo(&mut long_lived, "still there"),
+            "old still there",
+            "the connection established before the upgrade is dropped"
+        );
+        let mut fresh = connect(&self.listen_path());
+        assert_eq!(
+            echo(&mut fresh, "hello"),
+            "new hello",
+            "the new connection is not served by the new server"
+        );
+
+        for runtime in old_runtimes.into_iter().chain(new_runtimes) {
+            runtime.shutdown_timeout(Duration::from_secs(1));
+        }
+        result
+    }
+}
+
+impl Drop for UpgradeTest {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// answer each line with the name of the server in front of it
+struct Echo(&'static str);
+
+#[async_trait]
+impl ServerApp for Echo {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut io: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let mut buf = [0; 1024];
+        loop {
+            let n = io.read(&mut buf).await.ok()?;
+            if n == 0 {
+                return None;
+            }
+            let reply = [format!("{} ", self.0).as_bytes(), &buf[..n]].concat();
+            io.write_all(&reply).await.ok()?;
+            io.flush().await.ok()?;
+        }
+    }
+}
+
+fn connect(path: &str) -> UnixStream {
+    // the service may not be listening yet
+    for _ in 0..100 {
+        if let Ok(stream) = UnixStream::connect(path) {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            return stream;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("failed to connect to {path}");
+}
+
+fn echo(stream: &mut UnixStream, line: &str) -> String {
+    stream.write_all(format!("{line}\n").as_bytes()).unwrap();
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).unwrap();
+    reply.trim_end().to_string()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade() {
+        let result = UpgradeTest::new().run();
+        assert!(matches!(result, UpgradeResult::Sent(bytes) if bytes > 0));
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The health check service
+//!
+//! A [HealthCheckService] answers the liveness and readiness probes of an orchestrator such as
+//! Kubernetes over HTTP:
+//! - the liveness path, `/healthz` by default, answers `200` as long as the server runs
+//! - the readiness path, `/readyz` by default, answers `200` once the server is ready to serve
+//!   and until the graceful shutdown of the server is broadcast, and `503` otherwise so that the
+//!   orchestrator only sends traffic once all the services are started and stops during the grace
+//!   period
+//!
+//! The server tells the service when it is ready via the [ReadinessObserver] of
+//! [HealthCheckService::readiness_observer()], which has to be set as (part of) the
+//! [LifecycleObserver] of the server. Until then the readiness probe answers `503`.
+//!
+//! Unlike the listening services, this service keeps answering during the grace period, until its
+//! runtime is shut down.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{header, StatusCode};
+use log::{debug, error};
+use pingora_http::ResponseHeader;
+use pingora_runtime::current_handle;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::Service;
+use crate::listeners::{Listeners, TransportStack};
+use crate::protocols::http::ServerSession;
+use crate::protocols::Stream;
+use crate::server::{LifecycleObserver, ListenFds, ShutdownSignal, ShutdownWatch};
+
+/// The service that serves the liveness and readiness probes
+///
+/// ```no_run
+/// # use pingora_core::server::Server;
+/// # use pingora_core::services::health::HealthCheckService;
+/// let health = HealthCheckService::new("0.0.0.0:8081");
+/// let mut server = Server::new(None)
+///     .unwrap()
+///     .with_lifecycle_observer(health.readiness_observer());
+/// server.add_service(health);
+/// ```
+pub struct HealthCheckService {
+    name: String,
+    listeners: Listeners,
+    liveness_path: String,
+    readiness_path: String,
+    ready: Arc<AtomicBool>,
+}
+
+struct Probes {
+    liveness_path: String,
+    readiness_path: String,
+    ready: Arc<AtomicBool>,
+}
+
+/// The [LifecycleObserver] that tells a [HealthCheckService] when the server is ready to serve
+///
+/// Combine it with another observer as a tuple, e.g., `(observer, health.readiness_observer())`.
+pub struct ReadinessObserver(Arc<AtomicBool>);
+
+impl LifecycleObserver for ReadinessObserver {
+    fn ready(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn draining(&self, _signal: ShutdownSignal) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+impl HealthCheckService {
+    /// Create a new [HealthCheckService] that listens on the given TCP address, with the default
+    /// `/healthz` and `/readyz` paths
+    pub fn new(addr: &str) -> Self {
+        HealthCheckService {
+            name: "Health check".to_string(),
+            listeners: Listeners::tcp(addr),
+            liveness_path: "/healthz".to_string(),
+            readiness_path: "/readyz".to_string(),
+            ready: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Serve the liveness probe on the given path instead of `/healthz`
+    pub fn with_liveness_path(mut self, path: &str) -> Self {
+        self.liveness_path = path.to_string();
+        self
+    }
+
+    /// Serve the readiness probe on the given path instead of `/readyz`
+    pub fn with_readiness_path(mut self, path: &str) -> Self {
+        self.readiness_path = path.to_string();
+        self
+    }
+
+    /// The [ReadinessObserver] to set on the server, without which the readiness probe never
+    /// answers `200`, see [crate::server::Server::with_lifecycle_observer()].
+    pub fn readiness_observer(&self) -> ReadinessObserver {
+        ReadinessObserver(self.ready.clone())
+    }
+
+    async fn run_endpoint(mut stack: TransportStack, probes: Arc<Probes>, shutdown: ShutdownWatch) {
+        if let Err(e) = stack.listen().await {
+            error!("Listen() failed: {e}");
+            return;
+        }
+        // keep accepting after the shutdown is broadcast, to tell the probes about it
+        loop {
+            let io = match stack.accept().await {
+                Ok(io) => io,
+                Err(e) => {
+                    error!("Accept() failed {e}");
+                    // don't spin when accept() keeps failing, e.g., too many open files
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let probes = probes.clone();
+            let shutdown = shutdown.clone();
+            current_handle().spawn(async move {
+                match io.handshake().await {
+                    Ok(stream) => probes.serve(stream, &shutdown).await,
+                    Err(e) => debug!("Health check handshake error {e}"),
+                }
+            });
+        }
+    }
+}
+
+impl Probes {
+    fn status(&self, path: &str, ready: bool) -> StatusCode {
+        if path == self.liveness_path {
+            StatusCode::OK
+        } else if path == self.readiness_path {
+            if ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+        } else {
+            StatusCode::NOT_FOUND
+        }
+    }
+
+    async fn serve(&self, stream: Stream, shutdown: &ShutdownWatch) {
+        let mut stream = Some(stream);
+        while let Some(s) = stream.take() {
+            let mut session = ServerSession::new_http1(s);
+            match session.read_request().await {
+                Ok(true) => {}
+                Ok(false) => return,
+                Err(e) => {
+                    debug!("Health check fails to read the request: {e}");
+                    return;
+                }
+            }
+            let ready = self.ready.load(Ordering::Relaxed) && !*shutdown.borrow();
+            let status = self.status(session.req_header().uri.path(), ready);
+            let body = Bytes::from_static(status.canonical_reason().unwrap_or("").as_bytes());
+            let mut resp = ResponseHeader::build(status, Some(2)).unwrap();
+            resp.insert_header(header::CONTENT_TYPE, "text/plain")
+                .unwrap();
+            resp.insert_header(header::CONTENT_LENGTH, body.len())
+                .unwrap();
+            session.set_keepalive(if ready { Some(60) } else { None });
+            if let Err(e) = session.write_response_header(Box::new(resp)).await {
+                debug!("Health check fails to write the response: {e}");
+                return;
+            }
+            if let Err(e) = session.write_response_body(body).await {
+                debug!("Health check fails to write the response: {e}");
+                return;
+            }
+            stream = session.finish().await.ok().flatten();
+        }
+    }
+}
+
+#[async_trait]
+impl Service for HealthCheckService {
+    async fn start_service(&mut self, fds: Option<ListenFds>, shutdown: ShutdownWatch) {
+        let probes = Arc::new(Probes {
+            liveness_path: self.liveness_path.clone(),
+            readiness_path: self.readiness_path.clone(),
+            ready: self.ready.clone(),
+        });
+        let handles: Vec<_> = self
+            .listeners
+            .build(fds)
+            .into_iter()
+            .map(|stack| {
+                current_handle().spawn(Self::run_endpoint(stack, probes.clone(), shutdown.clone()))
+            })
+            .collect();
+        futures::future::join_all(handles).await;
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::sync::watch;
+
+    async fn probe(addr: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!("GET {path} HTTP/1.1\r\nHost: health\r\nConnection: close\r\n\r\n");
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        resp
+    }
+
+    #[tokio::test]
+    async fn test_health_check_service() {
+        let addr = "127.0.0.1:7140";
+        let mut service = HealthCheckService::new(addr).with_readiness_path("/ready");
+        let observer = service.readiness_observer();
+        let (tx, shutdown) = watch::channel(false);
+        tokio::spawn(async move { service.start_service(None, shutdown).await });
+        // wait for the service to listen
+        for _ in 0..100 {
+            if TcpStream::connect(addr).await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // the services are starting
+        assert!(probe(addr, "/healthz").await.starts_with("HTTP/1.1 200"));
+        assert!(probe(addr, "/ready").await.starts_with("HTTP/1.1 503"));
+
+        observer.ready();
+        assert!(probe(addr, "/ready").await.starts_with("HTTP/1.1 200"));
+        assert!(probe(addr, "/readyz").await.starts_with("HTTP/1.1 404"));
+
+        // draining: not ready but still alive
+        tx.send(true).unwrap();
+        assert!(probe(addr, "/ready").await.starts_with("HTTP/1.1 503"));
+        assert!(probe(addr, "/healthz").await.starts_with("HTTP/1.1 200"));
+    }
+}
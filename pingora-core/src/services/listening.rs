@@ -19,9 +19,11 @@
 //! more endpoints to listen to.
 
 use crate::apps::ServerApp;
-use crate::listeners::{Listeners, ServerAddress, TcpSocketOptions, TlsSettings, TransportStack};
+use crate::listeners::{
+    ListenerStats, Listeners, ServerAddress, TcpSocketOptions, TlsSettings, TransportStack,
+};
 use crate::protocols::Stream;
-use crate::server::{ListenFds, ShutdownWatch};
+use crate::server::{combine_watches, DrainWatch, ListenFds, ShutdownWatch};
 use crate::services::Service as ServiceTrait;
 
 use async_trait::async_trait;
@@ -29,7 +31,150 @@ use log::{debug, error, info};
 use pingora_error::Result;
 use pingora_runtime::current_handle;
 use std::fs::Permissions;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+static ACTIVE_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of the downstream connections the listening services of this process are handling
+pub fn active_connections() -> usize {
+    ACTIVE_CONNECTIONS.load(Ordering::Relaxed)
+}
+
+// counts a connection as active, process wide, for its service and optionally for its listening
+// endpoint, while alive
+struct ActiveConnection(Arc<AtomicUsize>, Option<Arc<ListenerStats>>);
+
+impl ActiveConnection {
+    fn new(service_connections: Arc<AtomicUsize>) -> Self {
+        ACTIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        service_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(service_connections, None)
+    }
+
+    fn with_listener(mut self, stats: Arc<ListenerStats>) -> Self {
+        stats.connection_opened();
+        self.1 = Some(stats);
+        self
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+        self.0.fetch_sub(1, Ordering::Relaxed);
+        if let Some(stats) = self.1.as_ref() {
+            stats.connection_closed();
+        }
+    }
+}
+
+/// Stop accepting new connections while a [Service] is overloaded.
+///
+/// When any of the configured thresholds is reached, the accept loops of the service stop calling
+/// `accept()` until the load drops below all of them again. The new connections wait in the
+/// listen backlog of the kernel meanwhile, and once the backlog is full the peers see their
+/// connection attempts stall, so that they can back off or go elsewhere instead of adding more
+/// work to a process that is already behind.
+#[derive(Debug, Clone)]
+pub struct AcceptBackpressure {
+    /// Pause when the connections this service is handling reach this number.
+    pub max_connections: Option<usize>,
+    /// Pause when the number of tasks waiting in the global queue of the runtime the service
+    /// runs on reaches this number.
+    pub max_queue_depth: Option<usize>,
+    /// How often to check the load again while paused.
+    pub check_interval: Duration,
+}
+
+impl Default for AcceptBackpressure {
+    fn default() -> Self {
+        AcceptBackpressure {
+            max_connections: None,
+            max_queue_depth: None,
+            check_interval: Duration::from_millis(10),
+        }
+    }
+}
+
+impl AcceptBackpressure {
+    fn overloaded(&self, connections: usize, queue_depth: impl FnOnce() -> usize) -> bool {
+        self.max_connections.is_some_and(|max| connections >= max)
+            || self.max_queue_depth.is_some_and(|max| queue_depth() >= max)
+    }
+}
+
+/// How often and for how long the accept loops of a [Service] paused because of
+/// [AcceptBackpressure].
+///
+/// Each listening endpoint pauses on its own, so a single overload of a service with several
+/// endpoints is counted once per endpoint.
+#[derive(Debug, Default)]
+pub struct AcceptPauses {
+    count: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl AcceptPauses {
+    /// The number of times accepting was paused.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The total time accepting was paused, including the ongoing pauses only once they end.
+    pub fn duration(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+
+    fn record(&self, paused: Duration) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.nanos
+            .fetch_add(paused.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+// what the accept loop of an endpoint needs to apply backpressure
+#[derive(Clone)]
+struct AcceptLoad {
+    backpressure: Option<AcceptBackpressure>,
+    connections: Arc<AtomicUsize>,
+    pauses: Arc<AcceptPauses>,
+}
+
+impl AcceptLoad {
+    fn overloaded(&self) -> bool {
+        let Some(backpressure) = self.backpressure.as_ref() else {
+            return false;
+        };
+        backpressure.overloaded(self.connections.load(Ordering::Relaxed), || {
+            current_handle().metrics().global_queue_depth()
+        })
+    }
+
+    // wait until no longer overloaded, return false if the system is shutting down instead
+    async fn wait_unloaded(&self, shutdown: &mut ShutdownWatch) -> bool {
+        let interval = self
+            .backpressure
+            .as_ref()
+            .map_or(Duration::from_millis(10), |b| b.check_interval);
+        let start = Instant::now();
+        let resumed = loop {
+            if *shutdown.borrow_and_update() {
+                break false;
+            }
+            if !self.overloaded() {
+                break true;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                r = shutdown.changed() => if r.is_err() { break false },
+            }
+        };
+        self.pauses.record(start.elapsed());
+        resumed
+    }
+}
 
 /// The type of service that is associated with a list of listening endpoints and a particular application
 pub struct Service<A> {
@@ -38,6 +183,9 @@ pub struct Service<A> {
     app_logic: Arc<A>,
     /// The number of preferred threads. `None` to follow global setting.
     pub threads: Option<usize>,
+    backpressure: Option<AcceptBackpressure>,
+    connections: Arc<AtomicUsize>,
+    accept_pauses: Arc<AcceptPauses>,
 }
 
 impl<A> Service<A> {
@@ -48,6 +196,9 @@ impl<A> Service<A> {
             listeners: Listeners::new(),
             app_logic,
             threads: None,
+            backpressure: None,
+            connections: Arc::new(AtomicUsize::new(0)),
+            accept_pauses: Arc::new(AcceptPauses::default()),
         }
     }
 
@@ -59,9 +210,40 @@ impl<A> Service<A> {
             listeners,
             app_logic,
             threads: None,
+            backpressure: None,
+            connections: Arc::new(AtomicUsize::new(0)),
+            accept_pauses: Arc::new(AcceptPauses::default()),
         }
     }
 
+    /// Get the application of this [`Service`], e.g., to share it with a background service.
+    pub fn app_logic(&self) -> &Arc<A> {
+        &self.app_logic
+    }
+
+    /// Pause accepting new connections while this [`Service`] is overloaded, see
+    /// [`AcceptBackpressure`]. `None` (the default) always accepts.
+    pub fn set_accept_backpressure(&mut self, backpressure: Option<AcceptBackpressure>) {
+        self.backpressure = backpressure;
+    }
+
+    /// The number of the downstream connections this [`Service`] is handling.
+    pub fn active_connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Get the [`AcceptPauses`] of this [`Service`], e.g., to report them from a background
+    /// service after this service is added to the server.
+    pub fn accept_pauses(&self) -> Arc<AcceptPauses> {
+        self.accept_pauses.clone()
+    }
+
+    /// The [`ListenerStats`] of each endpoint of this [`Service`], along with its address, e.g., to
+    /// export them from a background service after this service is added to the server.
+    pub fn listener_stats(&self) -> Vec<(String, Arc<ListenerStats>)> {
+        self.listeners.listener_stats()
+    }
+
     /// Get the [`Listeners`], mostly to add more endpoints.
     pub fn endpoints(&mut self) -> &mut Listeners {
         &mut self.listeners
@@ -125,6 +307,10 @@ impl<A: ServerApp + Send + Sync + 'static> Service<A> {
         app_logic: Arc<A>,
         mut stack: TransportStack,
         mut shutdown: ShutdownWatch,
+        mut drain: DrainWatch,
+        // what the connections see: true when either shutting down or drained
+        conn_shutdown: ShutdownWatch,
+        load: AcceptLoad,
     ) {
         if let Err(e) = stack.listen().await {
             error!("Listen() failed: {e}");
@@ -132,9 +318,35 @@ impl<A: ServerApp + Send + Sync + 'static> Service<A> {
         }
 
         // the accept loop, until the system is shutting down
+        let mut drain_open = true;
         loop {
+            if *drain.borrow_and_update() {
+                // keep the listening socket but stop accepting until un-drained
+                info!("Draining {}", stack.as_str());
+                if !Self::wait_undrain(&mut shutdown, &mut drain).await {
+                    info!("Shutting down {}", stack.as_str());
+                    break;
+                }
+                info!("Resuming {}", stack.as_str());
+            }
+            if load.overloaded() {
+                // leave the new connections in the backlog until the load drops
+                info!("Overloaded, pausing {}", stack.as_str());
+                stack.stats().record_overload_pause();
+                if !load.wait_unloaded(&mut shutdown).await {
+                    info!("Shutting down {}", stack.as_str());
+                    break;
+                }
+                info!("Resuming {}", stack.as_str());
+                // the service may have been drained meanwhile
+                continue;
+            }
             let new_io = tokio::select! { // TODO: consider biased for perf reason?
                 new_io = stack.accept() => new_io,
+                drain_signal = drain.changed(), if drain_open => {
+                    drain_open = drain_signal.is_ok();
+                    continue;
+                }
                 shutdown_signal = shutdown.changed() => {
                     match shutdown_signal {
                         Ok(()) => {
@@ -155,8 +367,11 @@ impl<A: ServerApp + Send + Sync + 'static> Service<A> {
             match new_io {
                 Ok(io) => {
                     let app = app_logic.clone();
-                    let shutdown = shutdown.clone();
+                    let shutdown = conn_shutdown.clone();
+                    let active = ActiveConnection::new(load.connections.clone())
+                        .with_listener(stack.stats().clone());
                     current_handle().spawn(async move {
+                        let _active = active;
                         match io.handshake().await {
                             Ok(io) => Self::handle_event(io, app, shutdown).await,
                             Err(e) => {
@@ -187,23 +402,64 @@ impl<A: ServerApp + Send + Sync + 'static> Service<A> {
 
         stack.cleanup();
     }
+
+    // wait until un-drained, return false if the system is shutting down instead
+    async fn wait_undrain(shutdown: &mut ShutdownWatch, drain: &mut DrainWatch) -> bool {
+        loop {
+            if *shutdown.borrow_and_update() {
+                return false;
+            }
+            if !*drain.borrow_and_update() {
+                return true;
+            }
+            tokio::select! {
+                r = shutdown.changed() => if r.is_err() { return false },
+                r = drain.changed() => if r.is_err() { return true },
+            }
+        }
+    }
 }
 
 #[async_trait]
 impl<A: ServerApp + Send + Sync + 'static> ServiceTrait for Service<A> {
     async fn start_service(&mut self, fds: Option<ListenFds>, shutdown: ShutdownWatch) {
+        // never drained
+        let (_drain_tx, drain) = tokio::sync::watch::channel(false);
+        self.start_service_with_drain(fds, shutdown, drain).await;
+    }
+
+    async fn start_service_with_drain(
+        &mut self,
+        fds: Option<ListenFds>,
+        shutdown: ShutdownWatch,
+        drain: DrainWatch,
+    ) {
         let runtime = current_handle();
         let endpoints = self.listeners.build(fds);
 
+        let (conn_shutdown_tx, conn_shutdown) = tokio::sync::watch::channel(*shutdown.borrow());
+        let load = AcceptLoad {
+            backpressure: self.backpressure.clone(),
+            connections: self.connections.clone(),
+            pauses: self.accept_pauses.clone(),
+        };
         let handlers = endpoints.into_iter().map(|endpoint| {
             let app_logic = self.app_logic.clone();
             let shutdown = shutdown.clone();
+            let drain = drain.clone();
+            let conn_shutdown = conn_shutdown.clone();
+            let load = load.clone();
             runtime.spawn(async move {
-                Self::run_endpoint(app_logic, endpoint, shutdown).await;
+                Self::run_endpoint(app_logic, endpoint, shutdown, drain, conn_shutdown, load).await;
             })
         });
 
-        futures::future::join_all(handlers).await;
+        tokio::select! {
+            _ = futures::future::join_all(handlers) => {}
+            _ = combine_watches(shutdown.clone(), drain.clone(), &conn_shutdown_tx) => {}
+        }
+        // the endpoints may exit before the connections are told
+        conn_shutdown_tx.send_replace(*shutdown.borrow() || *drain.borrow());
         self.listeners.cleanup();
         self.app_logic.cleanup();
     }
@@ -217,8 +473,15 @@ impl<A: ServerApp + Send + Sync + 'static> ServiceTrait for Service<A> {
     }
 }
 
+#[cfg(feature = "prometheus")]
 use crate::apps::prometheus_http_app::PrometheusServer;
 
+/// The listening [Service] that reports Prometheus metrics over HTTP, see
+/// [Service::prometheus_http_service()]
+#[cfg(feature = "prometheus")]
+pub type PrometheusHttpService = Service<PrometheusServer>;
+
+#[cfg(feature = "prometheus")]
 impl Service<PrometheusServer> {
     /// The Prometheus HTTP server
     ///
@@ -230,3 +493,97 @@ impl Service<PrometheusServer> {
         )
     }
 }
+
+/// Create the Prometheus HTTP service that listens on the given TCP address and reports the
+/// metrics on the given request path, e.g., `/metrics`
+///
+/// ```no_run
+/// # use pingora_core::server::Server;
+/// # use pingora_core::services::listening::prometheus_service;
+/// # let mut server = Server::new(None).unwrap();
+/// server.add_service(prometheus_service("127.0.0.1:6192", "/metrics"));
+/// ```
+#[cfg(feature = "prometheus")]
+pub fn prometheus_service(addr: &str, path: &str) -> PrometheusHttpService {
+    let mut service = Service::new(
+        "Prometheus metric HTTP".to_string(),
+        Arc::new(PrometheusServer::with_path(path)),
+    );
+    service.add_tcp(addr);
+    service
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backpressure_thresholds() {
+        let backpressure = AcceptBackpressure::default();
+        assert!(!backpressure.overloaded(usize::MAX, || usize::MAX));
+
+        let backpressure = AcceptBackpressure {
+            max_connections: Some(10),
+            max_queue_depth: Some(100),
+            ..Default::default()
+        };
+        assert!(!backpressure.overloaded(9, || 99));
+        assert!(backpressure.overloaded(10, || 0));
+        assert!(backpressure.overloaded(0, || 100));
+    }
+
+    #[test]
+    fn test_active_connections() {
+        let service_connections = Arc::new(AtomicUsize::new(0));
+        let stats = Arc::new(ListenerStats::default());
+
+        let first = ActiveConnection::new(service_connections.clone()).with_listener(stats.clone());
+        let second =
+            ActiveConnection::new(service_connections.clone()).with_listener(stats.clone());
+        assert_eq!(stats.snapshot().active, 2);
+        assert_eq!(service_connections.load(Ordering::Relaxed), 2);
+
+        drop(first);
+        assert_eq!(stats.snapshot().active, 1);
+        drop(second);
+        assert_eq!(stats.snapshot().active, 0);
+        assert_eq!(service_connections.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_accept_pauses() {
+        let load = AcceptLoad {
+            backpressure: Some(AcceptBackpressure {
+                max_connections: Some(1),
+                check_interval: Duration::from_millis(1),
+                ..Default::default()
+            }),
+            connections: Arc::new(AtomicUsize::new(0)),
+            pauses: Arc::new(AcceptPauses::default()),
+        };
+        let (_shutdown_tx, mut shutdown) = tokio::sync::watch::channel(false);
+        assert!(!load.overloaded());
+
+        let active = ActiveConnection::new(load.connections.clone());
+        assert!(load.overloaded());
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(active);
+        });
+        assert!(load.wait_unloaded(&mut shutdown).await);
+        release.await.unwrap();
+        assert_eq!(load.connections.load(Ordering::Relaxed), 0);
+        assert_eq!(load.pauses.count(), 1);
+        assert!(load.pauses.duration() >= Duration::from_millis(20));
+
+        // shutting down while paused
+        let _active = ActiveConnection::new(load.connections.clone());
+        let (shutdown_tx, mut shutdown) = tokio::sync::watch::channel(false);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            shutdown_tx.send(true).unwrap();
+        });
+        assert!(!load.wait_unloaded(&mut shutdown).await);
+        assert_eq!(load.pauses.count(), 2);
+    }
+}
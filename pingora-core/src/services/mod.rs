@@ -20,13 +20,18 @@
 //! Two types of services are particularly useful
 //! - services that are listening to some (TCP) endpoints
 //! - services that are just running in the background.
+//!
+//! [scheduled] builds on the latter to run recurring tasks. [health] serves the liveness and
+//! readiness probes of the server.
 
 use async_trait::async_trait;
 
-use crate::server::{ListenFds, ShutdownWatch};
+use crate::server::{DrainWatch, ListenFds, ShutdownWatch};
 
 pub mod background;
+pub mod health;
 pub mod listening;
+pub mod scheduled;
 
 /// The service interface
 #[async_trait]
@@ -41,6 +46,28 @@ pub trait Service: Sync + Send {
     /// - `shutdown`: the shutdown signal this server would receive.
     async fn start_service(&mut self, fds: Option<ListenFds>, mut shutdown: ShutdownWatch);
 
+    /// Similar to [Self::start_service()], with the drain signal of this service
+    ///
+    /// This is what the server calls. `drain` turns true when this service alone is asked to
+    /// stop accepting and finish what is in flight, and false again when it is un-drained, see
+    /// [crate::server::ShutdownHandle].
+    ///
+    /// By default, the drain signal is folded into the `shutdown` given to
+    /// [Self::start_service()], so the service shuts down when drained. Override this to
+    /// resume the service when un-drained.
+    async fn start_service_with_drain(
+        &mut self,
+        fds: Option<ListenFds>,
+        shutdown: ShutdownWatch,
+        drain: DrainWatch,
+    ) {
+        let (tx, combined) = tokio::sync::watch::channel(*shutdown.borrow());
+        tokio::select! {
+            _ = self.start_service(fds, combined) => {}
+            _ = crate::server::combine_watches(shutdown, drain, &tx) => {}
+        }
+    }
+
     /// The name of the service, just for logging and naming the threads assigned to this service
     ///
     /// Note that due to the limit of the underlying system, only the first 16 chars will be used
@@ -52,4 +79,12 @@ pub trait Service: Sync + Send {
     fn threads(&self) -> Option<usize> {
         None
     }
+
+    /// The maximum number of threads for the blocking operations of this service, e.g.,
+    /// `spawn_blocking()`
+    ///
+    /// If `None`, the global setting will be used
+    fn max_blocking_threads(&self) -> Option<usize> {
+        None
+    }
 }
@@ -0,0 +1,546 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recurring background tasks
+//!
+//! A [ScheduledService] runs a [ScheduledTask] on a fixed interval or at the times matching a
+//! cron expression, e.g., to clean up caches, flush metrics or refresh configs, until the server
+//! shuts down.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Datelike, Timelike};
+use log::{debug, warn};
+use pingora_error::{Error, ErrorType, Result};
+use std::future::Future;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::background::{BackgroundService, GenBackgroundService};
+use crate::server::ShutdownWatch;
+
+const CRON_ERROR: ErrorType = ErrorType::new("CronError");
+const INTERVAL_ERROR: ErrorType = ErrorType::new("IntervalError");
+
+/// The shortest period of [Schedule::Interval], so that a tiny period doesn't spin the task
+pub const MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A recurring task run by a [ScheduledService]
+#[cfg_attr(not(doc_async_trait), async_trait)]
+pub trait ScheduledTask {
+    /// Run the task once
+    ///
+    /// `shutdown` turns `true` when the server is shutting down so that a long run can stop
+    /// early. No new run starts after that.
+    async fn run(&self, shutdown: &ShutdownWatch);
+}
+
+#[async_trait]
+impl<F, Fut> ScheduledTask for F
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = ()> + Send,
+{
+    async fn run(&self, _shutdown: &ShutdownWatch) {
+        self().await
+    }
+}
+
+/// When to run a [ScheduledTask]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Every given period, starting right after the service starts
+    ///
+    /// A period shorter than [MIN_INTERVAL] is raised to it. Use [Schedule::interval()] to
+    /// reject it instead.
+    Interval(Duration),
+    /// At the times matching the cron expression
+    Cron(CronSchedule),
+}
+
+impl Schedule {
+    /// Run on the given cron expression, see [CronSchedule] for the syntax
+    pub fn cron(expr: &str) -> Result<Self> {
+        expr.parse().map(Schedule::Cron)
+    }
+
+    /// Run every given period, which should be at least [MIN_INTERVAL]
+    pub fn interval(period: Duration) -> Result<Self> {
+        if period < MIN_INTERVAL {
+            return Error::e_explain(
+                INTERVAL_ERROR,
+                format!("interval {period:?} is shorter than {MIN_INTERVAL:?}"),
+            );
+        }
+        Ok(Schedule::Interval(period))
+    }
+
+    // the time of the first run of a service started at `now`
+    fn first(&self, now: SystemTime) -> Option<SystemTime> {
+        match self {
+            Schedule::Interval(_) => Some(now),
+            Schedule::Cron(cron) => cron.next_after(now),
+        }
+    }
+
+    // the time of the run after the one at `last`
+    fn next(&self, last: SystemTime) -> Option<SystemTime> {
+        match self {
+            Schedule::Interval(period) => last.checked_add(interval_period(*period)),
+            Schedule::Cron(cron) => cron.next_after(last),
+        }
+    }
+}
+
+fn interval_period(period: Duration) -> Duration {
+    period.max(MIN_INTERVAL)
+}
+
+/// What to do with the runs that are due while the previous run is still going on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overlap {
+    /// Drop them and wait for the next one on the schedule
+    Skip,
+    /// Start a run right after the overrunning one finishes
+    ///
+    /// All the runs missed are merged into that one so that the task never runs back to back
+    /// more than once.
+    Queue,
+}
+
+// the fields of a cron expression: (name, min, max)
+const FIELDS: [(&str, u32, u32); 5] = [
+    ("minute", 0, 59),
+    ("hour", 0, 23),
+    ("day of month", 1, 31),
+    ("month", 1, 12),
+    ("day of week", 0, 7),
+];
+
+/// A parsed cron expression
+///
+/// The expression has the 5 standard fields: minute (0-59), hour (0-23), day of month (1-31),
+/// month (1-12) and day of week (0-7, both 0 and 7 are Sunday). Each field is `*`, a value, a
+/// range `a-b` or a comma separated list of them, each optionally followed by a step `/n`, e.g.,
+/// `*/15 9-17 * * 1-5`. Names like `MON` are not supported.
+///
+/// Like the classic cron, a time matches when the day matches either the day of month or the
+/// day of week if both of them are restricted. The times are in UTC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    // bit n is set if value n matches
+    fields: [u64; 5],
+    // whether the day of month and the day of week fields are `*`
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+fn parse_value(s: &str, name: &str, min: u32, max: u32) -> Result<u32> {
+    match s.parse::<u32>() {
+        Ok(v) if (min..=max).contains(&v) => Ok(v),
+        _ => Error::e_explain(
+            CRON_ERROR,
+            format!("invalid {name} {s:?}, should be between {min} and {max}"),
+        ),
+    }
+}
+
+fn parse_field(field: &str, name: &str, min: u32, max: u32) -> Result<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Error::e_explain(CRON_ERROR, format!("invalid step {step:?}")),
+            },
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            let start = parse_value(start, name, min, max)?;
+            let end = parse_value(end, name, min, max)?;
+            if start > end {
+                return Error::e_explain(CRON_ERROR, format!("invalid {name} range {range:?}"));
+            }
+            (start, end)
+        } else {
+            let start = parse_value(range, name, min, max)?;
+            // `a/n` means from a to the max
+            (start, if part.contains('/') { max } else { start })
+        };
+        for v in (start..=end).step_by(step as usize) {
+            bits |= 1 << v;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for CronSchedule {
+    type Err = Box<Error>;
+
+    fn from_str(expr: &str) -> Result<Self> {
+        let parts: Vec<&str> = expr.split_whitespace().collect();
+        if parts.len() != FIELDS.len() {
+            return Error::e_explain(
+                CRON_ERROR,
+                format!("cron expression {expr:?} should have 5 fields"),
+            );
+        }
+        let mut fields = [0; 5];
+        for (i, (name, min, max)) in FIELDS.iter().enumerate() {
+            fields[i] = parse_field(parts[i], name, *min, *max)?;
+        }
+        // 7 is also Sunday
+        if fields[4] & (1 << 7) != 0 {
+            fields[4] = (fields[4] | 1) & !(1 << 7);
+        }
+        Ok(CronSchedule {
+            fields,
+            any_day_of_month: parts[2] == "*",
+            any_day_of_week: parts[4] == "*",
+        })
+    }
+}
+
+impl CronSchedule {
+    /// Parse the given cron expression
+    pub fn parse(expr: &str) -> Result<Self> {
+        expr.parse()
+    }
+
+    fn matches(&self, field: usize, value: u32) -> bool {
+        self.fields[field] & (1 << value) != 0
+    }
+
+    fn day_matches(&self, day_of_month: u32, day_of_week: u32) -> bool {
+        let dom = self.matches(2, day_of_month);
+        let dow = self.matches(4, day_of_week);
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => dom || dow,
+            _ => dom && dow,
+        }
+    }
+
+    /// The first time after `time` that matches the expression
+    ///
+    /// Return `None` if nothing matches in the next few years, e.g., `0 0 30 2 *`.
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        const MINUTE: i64 = 60;
+        const HOUR: i64 = 60 * MINUTE;
+        const DAY: i64 = 24 * HOUR;
+        // every day of a leap cycle is covered by then
+        const MAX_SEARCH: i64 = 8 * 366 * DAY;
+
+        let start = time.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+        // the start of the next minute
+        let mut t = start - start % MINUTE + MINUTE;
+        while t - start < MAX_SEARCH {
+            let dt = DateTime::from_timestamp(t, 0)?;
+            // skip to the next day, hour or minute that can match
+            if !self.matches(3, dt.month())
+                || !self.day_matches(dt.day(), dt.weekday().num_days_from_sunday())
+            {
+                t = t - t % DAY + DAY;
+            } else if !self.matches(1, dt.hour()) {
+                t = t - t % HOUR + HOUR;
+            } else if !self.matches(0, dt.minute()) {
+                t += MINUTE;
+            } else {
+                return Some(UNIX_EPOCH + Duration::from_secs(t as u64));
+            }
+        }
+        None
+    }
+}
+
+/// A [BackgroundService] that runs a [ScheduledTask] on a [Schedule]
+///
+/// The runs never overlap: the [Overlap] policy decides what to do with the runs that are due
+/// while the previous one is still going on. Once the server starts shutting down, no new run
+/// starts and the service returns after the ongoing run, if any, finishes.
+pub struct ScheduledService<T> {
+    task: T,
+    schedule: Schedule,
+    overlap: Overlap,
+}
+
+impl<T> ScheduledService<T> {
+    /// Create a new [ScheduledService] which skips the runs due during an overrunning run
+    pub fn new(task: T, schedule: Schedule) -> Self {
+        ScheduledService {
+            task,
+            schedule,
+            overlap: Overlap::Skip,
+        }
+    }
+
+    /// Set what to do with the runs due during an overrunning run
+    pub fn set_overlap(&mut self, overlap: Overlap) {
+        self.overlap = overlap;
+    }
+
+    /// The task being run
+    pub fn task(&self) -> &T {
+        &self.task
+    }
+}
+
+#[cfg_attr(not(doc_async_trait), async_trait)]
+impl<T> BackgroundService for ScheduledService<T>
+where
+    T: ScheduledTask + Send + Sync,
+{
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut next = self.schedule.first(SystemTime::now());
+        while let Some(run_at) = next {
+            let wait = run_at.duration_since(SystemTime::now()).unwrap_or_default();
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                // an error means the sender is gone, keep following the schedule
+                Ok(()) = shutdown.changed() => {}
+            }
+            if *shutdown.borrow() {
+                return;
+            }
+            self.task.run(&shutdown).await;
+            if *shutdown.borrow() {
+                return;
+            }
+
+            next = self.schedule.next(run_at);
+            let now = SystemTime::now();
+            if next.is_some_and(|t| t < now) {
+                debug!("scheduled task overran, {:?}", self.overlap);
+                next = match self.overlap {
+                    Overlap::Skip => match &self.schedule {
+                        // keep the same phase
+                        Schedule::Interval(period) => next.map(|mut t| {
+                            while t < now {
+                                t += interval_period(*period);
+                            }
+                            t
+                        }),
+                        Schedule::Cron(cron) => cron.next_after(now),
+                    },
+                    Overlap::Queue => Some(now),
+                };
+            }
+        }
+        warn!("nothing left on the schedule, scheduled task stops");
+        // don't return until shutdown so that the service is not considered finished early
+        while shutdown.changed().await.is_ok() {
+            if *shutdown.borrow() {
+                return;
+            }
+        }
+    }
+}
+
+/// Create a [ScheduledService] with a human readable name that runs `task` on `schedule`
+///
+/// Use [GenBackgroundService::task()] to share the task with other logic or
+/// [ScheduledService::set_overlap()] before adding the service to the server.
+pub fn scheduled_service<T>(
+    name: &str,
+    schedule: Schedule,
+    task: T,
+) -> GenBackgroundService<ScheduledService<T>> {
+    super::background::background_service(name, ScheduledService::new(task, schedule))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::watch;
+
+    // 2024-01-01T00:00:00Z, a Monday
+    const JAN_1_2024: u64 = 1_704_067_200;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_parse_cron() {
+        let cron = CronSchedule::parse("*/15 9-17 * * 1-5").unwrap();
+        assert!(cron.matches(0, 0) && cron.matches(0, 45) && !cron.matches(0, 10));
+        assert!(cron.matches(1, 9) && cron.matches(1, 17) && !cron.matches(1, 18));
+        assert!(cron.matches(4, 1) && !cron.matches(4, 0));
+
+        let cron = CronSchedule::parse("0,30 0 1 1/6 7").unwrap();
+        assert!(cron.matches(3, 1) && cron.matches(3, 7) && !cron.matches(3, 2));
+        assert!(cron.matches(4, 0) && !cron.matches(4, 7));
+
+        for invalid in [
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "* * * JAN *",
+        ] {
+            assert!(CronSchedule::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_cron_next() {
+        let cron = CronSchedule::parse("*/15 * * * *").unwrap();
+        assert_eq!(cron.next_after(at(JAN_1_2024)), Some(at(JAN_1_2024 + 900)));
+        assert_eq!(
+            cron.next_after(at(JAN_1_2024 + 901)),
+            Some(at(JAN_1_2024 + 1800))
+        );
+
+        // 9:30 on weekdays: Saturday the 6th goes to Monday the 8th
+        let cron = CronSchedule::parse("30 9 * * 1-5").unwrap();
+        let saturday = JAN_1_2024 + 5 * 86400;
+        assert_eq!(
+            cron.next_after(at(saturday)),
+            Some(at(JAN_1_2024 + 7 * 86400 + 9 * 3600 + 1800))
+        );
+
+        // either the 15th or a Sunday
+        let cron = CronSchedule::parse("0 0 15 * 0").unwrap();
+        assert_eq!(
+            cron.next_after(at(JAN_1_2024)),
+            Some(at(JAN_1_2024 + 6 * 86400))
+        );
+        assert_eq!(
+            cron.next_after(at(JAN_1_2024 + 13 * 86400)),
+            Some(at(JAN_1_2024 + 14 * 86400))
+        );
+
+        // Feb 29 of the next leap year
+        let cron = CronSchedule::parse("0 0 29 2 *").unwrap();
+        let next = cron.next_after(at(JAN_1_2024 + 60 * 86400)).unwrap();
+        let next =
+            DateTime::from_timestamp(next.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64, 0)
+                .unwrap();
+        assert_eq!((next.year(), next.month(), next.day()), (2028, 2, 29));
+
+        assert!(CronSchedule::parse("0 0 30 2 *")
+            .unwrap()
+            .next_after(at(JAN_1_2024))
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_interval() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let service = ScheduledService::new(
+            move || {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+            Schedule::Interval(Duration::from_millis(20)),
+        );
+        let (tx, rx) = watch::channel(false);
+        let handle = tokio::spawn(async move { service.start(rx).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        tx.send(true).unwrap();
+        handle.await.unwrap();
+        // at 0, 20 and 40 ms
+        let runs = runs.load(Ordering::Relaxed);
+        assert!((2..=4).contains(&runs), "{runs}");
+    }
+
+    #[tokio::test]
+    async fn test_zero_interval() {
+        assert!(Schedule::interval(Duration::ZERO).is_err());
+        assert!(Schedule::interval(MIN_INTERVAL).is_ok());
+
+        // raised to the minimum instead of spinning
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let service = ScheduledService::new(
+            move || {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+            Schedule::Interval(Duration::ZERO),
+        );
+        let (tx, rx) = watch::channel(false);
+        let handle = tokio::spawn(async move { service.start(rx).await });
+        tokio::time::sleep(MIN_INTERVAL * 5).await;
+        tx.send(true).unwrap();
+        handle.await.unwrap();
+        let runs = runs.load(Ordering::Relaxed);
+        assert!((1..=7).contains(&runs), "{runs}");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_sender_dropped() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = runs.clone();
+        let service = ScheduledService::new(
+            move || {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+            Schedule::Interval(Duration::from_millis(20)),
+        );
+        let (tx, rx) = watch::channel(false);
+        drop(tx);
+        let handle = tokio::spawn(async move { service.start(rx).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        handle.abort();
+        // still on the schedule instead of running back to back
+        let runs = runs.load(Ordering::Relaxed);
+        assert!((2..=4).contains(&runs), "{runs}");
+    }
+
+    struct Slow(AtomicUsize);
+
+    #[async_trait]
+    impl ScheduledTask for Slow {
+        async fn run(&self, _shutdown: &ShutdownWatch) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    async fn run_slow(overlap: Overlap) -> usize {
+        let mut service = ScheduledService::new(
+            Slow(AtomicUsize::new(0)),
+            Schedule::Interval(Duration::from_millis(40)),
+        );
+        service.set_overlap(overlap);
+        let service = Arc::new(service);
+        let (tx, rx) = watch::channel(false);
+        let s = service.clone();
+        let handle = tokio::spawn(async move { s.start(rx).await });
+        tokio::time::sleep(Duration::from_millis(130)).await;
+        tx.send(true).unwrap();
+        handle.await.unwrap();
+        service.task().0.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn test_overlap() {
+        // skip: runs at 0 and 80 ms
+        assert_eq!(run_slow(Overlap::Skip).await, 2);
+        // queue: runs at 0, 50 and 100 ms
+        assert_eq!(run_slow(Overlap::Queue).await, 3);
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The client certificates to present to the upstream servers that require mTLS
+//!
+//! A [ClientCertStore] holds named client identities, e.g., one per tenant. The [HttpPeer] of each
+//! request picks its identity from the store:
+//!
+//! ```
+//! # use pingora_core::upstreams::client_cert::ClientCertStore;
+//! # use pingora_core::upstreams::peer::HttpPeer;
+//! # let store = ClientCertStore::new();
+//! let mut peer = HttpPeer::new("1.1.1.1:443", true, "tenant-a.example.com".into());
+//! peer.client_cert_key = store.get("tenant-a");
+//! ```
+//!
+//! The identities can be replaced at any time, e.g., when the certificates are renewed, without
+//! restarting the server: the peers created afterwards present the new ones. As the serial number
+//! of the client certificate is part of the reuse hash of a peer, these peers don't reuse the
+//! pooled connections that were made with the old certificates, as long as the new certificates
+//! have new serial numbers.
+//!
+//! [HttpPeer]: crate::upstreams::peer::HttpPeer
+
+use crate::utils::CertKey;
+use log::info;
+use parking_lot::RwLock;
+use pingora_error::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+struct StoredCert {
+    cert_key: Arc<CertKey>,
+    // the cert and key files to reload from, if loaded from files
+    files: Option<(String, String)>,
+}
+
+/// A store of named client certificates and keys, see the [module level docs](self)
+pub struct ClientCertStore {
+    certs: RwLock<HashMap<String, StoredCert>>,
+}
+
+impl ClientCertStore {
+    /// Create an empty store
+    pub fn new() -> Self {
+        ClientCertStore {
+            certs: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Add the identity `name`, replacing the existing one of the same name if any
+    pub fn insert(&self, name: impl Into<String>, cert_key: CertKey) {
+        let stored = StoredCert {
+            cert_key: Arc::new(cert_key),
+            files: None,
+        };
+        self.certs.write().insert(name.into(), stored);
+    }
+
+    /// Load the identity `name` from the PEM files of its certificate chain and private key, see
+    /// [CertKey::from_pem_files()]
+    ///
+    /// The existing identity of the same name, if any, is replaced only if the files are valid.
+    /// The files are read again by [Self::reload()].
+    pub fn load_pem_files(
+        &self,
+        name: impl Into<String>,
+        cert_path: &str,
+        key_path: &str,
+    ) -> Result<()> {
+        let cert_key = CertKey::from_pem_files(cert_path, key_path)?;
+        let stored = StoredCert {
+            cert_key: Arc::new(cert_key),
+            files: Some((cert_path.to_string(), key_path.to_string())),
+        };
+        self.certs.write().insert(name.into(), stored);
+        Ok(())
+    }
+
+    /// Remove the identity `name`
+    pub fn remove(&self, name: &str) -> Option<Arc<CertKey>> {
+        self.certs.write().remove(name).map(|c| c.cert_key)
+    }
+
+    /// Get the identity `name`, to set as the `client_cert_key` of a peer
+    pub fn get(&self, name: &str) -> Option<Arc<CertKey>> {
+        self.certs.read().get(name).map(|c| c.cert_key.clone())
+    }
+
+    /// The number of identities in this store
+    pub fn len(&self) -> usize {
+        self.certs.read().len()
+    }
+
+    /// Whether this store has no identity
+    pub fn is_empty(&self) -> bool {
+        self.certs.read().is_empty()
+    }
+
+    /// Read the files of all the identities loaded by [Self::load_pem_files()] again
+    ///
+    /// All the files are read and validated before any identity is replaced, so that the store
+    /// is left unchanged if any of them fails. Return the number of identities reloaded.
+    pub fn reload(&self) -> Result<usize> {
+        let files: Vec<(String, (String, String))> = self
+            .certs
+            .read()
+            .iter()
+            .filter_map(|(name, c)| c.files.clone().map(|f| (name.clone(), f)))
+            .collect();
+        let mut loaded = Vec::with_capacity(files.len());
+        for (name, (cert_path, key_path)) in files {
+            let cert_key = CertKey::from_pem_files(&cert_path, &key_path)
+                .map_err(|e| e.more_context(format!("client cert {name}")))?;
+            loaded.push((name, (cert_path, key_path), cert_key));
+        }
+
+        let mut certs = self.certs.write();
+        let mut reloaded = 0;
+        for (name, files, cert_key) in loaded {
+            // skip the ones removed or replaced meanwhile
+            let Some(stored) = certs.get_mut(&name) else {
+                continue;
+            };
+            if stored.files.as_ref() != Some(&files) {
+                continue;
+            }
+            stored.cert_key = Arc::new(cert_key);
+            reloaded += 1;
+        }
+        info!("{reloaded} client certs reloaded");
+        Ok(reloaded)
+    }
+}
+
+impl Default for ClientCertStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_path(name: &str) -> String {
+        format!("{}/tests/keys/{name}", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    #[test]
+    fn test_load_validates_pair() {
+        let store = ClientCertStore::new();
+        store
+            .load_pem_files("a", &key_path("server.crt"), &key_path("key.pem"))
+            .unwrap();
+        assert_eq!(store.len(), 1);
+        assert!(store.get("a").is_some());
+        assert!(store.get("b").is_none());
+
+        // a key that doesn't belong to the cert
+        let e = store
+            .load_pem_files("a", &key_path("server.crt"), &key_path("other_key.pem"))
+            .unwrap_err();
+        assert!(e.to_string().contains("doesn't match"));
+        // the valid one is kept
+        assert!(store.get("a").is_some());
+        assert!(store
+            .load_pem_files("b", &key_path("server.crt"), &key_path("missing.pem"))
+            .is_err());
+        assert!(store.get("b").is_none());
+
+        assert!(store.remove("a").is_some());
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("pingora_client_cert_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem").to_str().unwrap().to_string();
+        let key = dir.join("key.pem").to_str().unwrap().to_string();
+        std::fs::copy(key_path("server.crt"), &cert).unwrap();
+        std::fs::copy(key_path("key.pem"), &key).unwrap();
+
+        let store = ClientCertStore::new();
+        store.load_pem_files("a", &cert, &key).unwrap();
+        let cert_key = CertKey::from_pem_files(&key_path("server.crt"), &key_path("key.pem"));
+        store.insert("b", cert_key.unwrap());
+        let before = store.get("a").unwrap();
+        // only the ones from files are reloaded, as new objects
+        assert_eq!(store.reload().unwrap(), 1);
+        let after = store.get("a").unwrap();
+        assert!(!Arc::ptr_eq(&before, &after));
+
+        // a broken file leaves the store unchanged
+        std::fs::copy(key_path("other_key.pem"), &key).unwrap();
+        assert!(store.reload().is_err());
+        assert!(Arc::ptr_eq(&after, &store.get("a").unwrap()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
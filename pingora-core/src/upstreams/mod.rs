@@ -14,4 +14,5 @@
 
 //! The interface to connect to a remote server
 
+pub mod client_cert;
 pub mod peer;
@@ -18,7 +18,9 @@ use ahash::AHasher;
 use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::hash::{Hash, Hasher};
-use std::net::{IpAddr, SocketAddr as InetSocketAddr, ToSocketAddrs as ToInetSocketAddrs};
+use std::net::{
+    IpAddr, Ipv4Addr, SocketAddr as InetSocketAddr, ToSocketAddrs as ToInetSocketAddrs,
+};
 use std::os::unix::net::SocketAddr as UnixSocketAddr;
 use std::os::unix::prelude::AsRawFd;
 use std::path::{Path, PathBuf};
@@ -30,6 +32,7 @@ use crate::protocols::l4::socket::SocketAddr;
 use crate::protocols::ConnFdReusable;
 use crate::tls::x509::X509;
 use crate::utils::{get_organization_unit, CertKey};
+use pingora_error::{Error, ErrorType::InternalError, OrErr, Result};
 
 pub use crate::protocols::ssl::ALPN;
 
@@ -112,7 +115,25 @@ pub trait Peer: Display + Clone {
             None => None,
         }
     }
+    /// Which network interface this connection should be bound to (`SO_BINDTODEVICE`).
+    fn bind_to_device(&self) -> Option<&str> {
+        self.get_peer_options()
+            .and_then(|o| o.bind_to_device.as_deref())
+    }
+    /// Whether [Self::bind_to()] can be a non-local address, e.g., the address of the downstream
+    /// client so that a transparent proxy connects from the original source (`IP_TRANSPARENT`).
+    ///
+    /// Linux only, requires the `transparent_proxy` feature and `CAP_NET_ADMIN`.
+    fn transparent(&self) -> bool {
+        self.get_peer_options().is_some_and(|o| o.transparent)
+    }
     /// How long connect() call should be wait before it returns a timeout error.
+    ///
+    /// The timeouts of the peer take precedence over the defaults of the connector, e.g.,
+    /// [ConnectorOptions::connection_timeout], which default to the `upstream_*_timeout` settings
+    /// of the server conf when the connector is created from it.
+    ///
+    /// [ConnectorOptions::connection_timeout]: crate::connectors::ConnectorOptions::connection_timeout
     fn connection_timeout(&self) -> Option<Duration> {
         match self.get_peer_options() {
             Some(opt) => opt.connection_timeout,
@@ -126,6 +147,16 @@ pub trait Peer: Display + Clone {
             None => None,
         }
     }
+    /// How long each read of the HTTP session to the peer should wait before it returns a timeout
+    /// error.
+    fn read_timeout(&self) -> Option<Duration> {
+        self.get_peer_options().and_then(|o| o.read_timeout)
+    }
+    /// How long each write of the HTTP session to the peer should wait before it returns a timeout
+    /// error.
+    fn write_timeout(&self) -> Option<Duration> {
+        self.get_peer_options().and_then(|o| o.write_timeout)
+    }
     /// If the connection can be reused, how long the connection should wait to be reused before it
     /// shuts down.
     fn idle_timeout(&self) -> Option<Duration> {
@@ -163,8 +194,36 @@ pub trait Peer: Display + Clone {
         self.get_peer_options().and_then(|o| o.h2_ping_interval)
     }
 
+    /// The hostname to resolve to find the address(es) of this peer at connection time
+    ///
+    /// When set, the IP of [`Self::address()`] is ignored and only its port is used. The hostname
+    /// is resolved by the [`crate::connectors::resolver::Resolver`] of the connector.
+    fn hostname(&self) -> Option<&str> {
+        self.get_peer_options().and_then(|o| o.hostname.as_deref())
+    }
+
+    /// Other addresses of the same server, e.g. the remaining A/AAAA records of its hostname.
+    ///
+    /// They are only used when [`Self::happy_eyeballs()`] is set.
+    fn alternative_addresses(&self) -> &[InetSocketAddr] {
+        match self.get_peer_options() {
+            Some(opt) => &opt.alternative_addresses,
+            None => &[],
+        }
+    }
+
+    /// The Happy Eyeballs settings to race connections to [`Self::address()`] and
+    /// [`Self::alternative_addresses()`]
+    fn happy_eyeballs(&self) -> Option<&HappyEyeballs> {
+        self.get_peer_options()
+            .and_then(|o| o.happy_eyeballs.as_ref())
+    }
+
     fn matches_fd<V: AsRawFd>(&self, fd: V) -> bool {
-        self.address().check_fd_match(fd)
+        if self.hostname().is_some() {
+            return matches_resolved(fd);
+        }
+        matches_any_address(self.address(), self.alternative_addresses(), fd)
     }
 
     fn get_tracer(&self) -> Option<Tracer> {
@@ -172,6 +231,72 @@ pub trait Peer: Display + Clone {
     }
 }
 
+// The connection may be established to any of the addresses of the peer
+fn matches_any_address<V: AsRawFd>(
+    address: &SocketAddr,
+    alternatives: &[InetSocketAddr],
+    fd: V,
+) -> bool {
+    if alternatives.is_empty() {
+        return address.check_fd_match(fd);
+    }
+    match SocketAddr::from_raw_fd(fd.as_raw_fd(), true) {
+        Some(peer) if &peer == address => true,
+        Some(SocketAddr::Inet(peer)) => alternatives.contains(&peer),
+        _ => false,
+    }
+}
+
+// The address of a hostname may change over time, so only check that the connection is alive
+fn matches_resolved<V: AsRawFd>(fd: V) -> bool {
+    SocketAddr::from_raw_fd(fd.as_raw_fd(), true).is_some()
+}
+
+/// The settings to establish connections with Happy Eyeballs ([RFC 8305](https://datatracker.ietf.org/doc/html/rfc8305))
+///
+/// Connection attempts to all the addresses of a peer are started one after another, alternating
+/// between address families. A new attempt starts when the previous one fails or after
+/// `attempt_delay`, whichever comes first. The first established connection is used and the
+/// other attempts are cancelled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HappyEyeballs {
+    /// How long to wait for an attempt before starting the next one in parallel
+    pub attempt_delay: Duration,
+    /// Whether to try IPv6 addresses before IPv4 ones
+    pub prefer_ipv6: bool,
+}
+
+impl Default for HappyEyeballs {
+    fn default() -> Self {
+        // the recommended Connection Attempt Delay from the RFC
+        HappyEyeballs {
+            attempt_delay: Duration::from_millis(250),
+            prefer_ipv6: true,
+        }
+    }
+}
+
+impl HappyEyeballs {
+    /// Order the addresses for connection attempts: starting with the preferred family and
+    /// then alternating between families while keeping the relative order within each family.
+    pub fn order_addresses(&self, addrs: &[InetSocketAddr]) -> Vec<InetSocketAddr> {
+        let (preferred, other): (Vec<_>, Vec<_>) = addrs
+            .iter()
+            .copied()
+            .partition(|a| a.is_ipv6() == self.prefer_ipv6);
+        let mut ordered = Vec::with_capacity(addrs.len());
+        let mut preferred = preferred.into_iter();
+        let mut other = other.into_iter();
+        loop {
+            match (preferred.next(), other.next()) {
+                (None, None) => break,
+                (a, b) => ordered.extend(a.into_iter().chain(b)),
+            }
+        }
+        ordered
+    }
+}
+
 /// A simple TCP or TLS peer without many complicated settings.
 #[derive(Debug, Clone)]
 pub struct BasicPeer {
@@ -259,11 +384,15 @@ impl Scheme {
 #[derive(Clone, Debug)]
 pub struct PeerOptions {
     pub bind_to: Option<InetSocketAddr>,
+    pub bind_to_device: Option<String>,
+    pub transparent: bool,
     pub connection_timeout: Option<Duration>,
     pub total_connection_timeout: Option<Duration>,
     pub read_timeout: Option<Duration>,
     pub idle_timeout: Option<Duration>,
     pub write_timeout: Option<Duration>,
+    // close an upgraded (e.g. WebSocket) connection when neither side sends anything for this long
+    pub upgrade_idle_timeout: Option<Duration>,
     pub verify_cert: bool,
     pub verify_hostname: bool,
     /* accept the cert if it's CN matches the SNI or this name */
@@ -283,6 +412,9 @@ pub struct PeerOptions {
     pub second_keyshare: bool,
     // use Arc because Clone is required but not allowed in trait object
     pub tracer: Option<Tracer>,
+    pub alternative_addresses: Vec<InetSocketAddr>,
+    pub happy_eyeballs: Option<HappyEyeballs>,
+    pub hostname: Option<String>,
 }
 
 impl PeerOptions {
@@ -290,11 +422,14 @@ impl PeerOptions {
     pub fn new() -> Self {
         PeerOptions {
             bind_to: None,
+            bind_to_device: None,
+            transparent: false,
             connection_timeout: None,
             total_connection_timeout: None,
             read_timeout: None,
             idle_timeout: None,
             write_timeout: None,
+            upgrade_idle_timeout: None,
             verify_cert: true,
             verify_hostname: true,
             alternative_cn: None,
@@ -308,6 +443,9 @@ impl PeerOptions {
             curves: None,
             second_keyshare: true, // default true and noop when not using PQ curves
             tracer: None,
+            alternative_addresses: vec![],
+            happy_eyeballs: None,
+            hostname: None,
         }
     }
 
@@ -322,6 +460,12 @@ impl Display for PeerOptions {
         if let Some(b) = self.bind_to {
             write!(f, "bind_to: {:?},", b)?;
         }
+        if let Some(d) = &self.bind_to_device {
+            write!(f, "bind_to_device: {d},")?;
+        }
+        if self.transparent {
+            write!(f, "transparent,")?;
+        }
         if let Some(t) = self.connection_timeout {
             write!(f, "conn_timeout: {:?},", t)?;
         }
@@ -357,6 +501,9 @@ impl Display for PeerOptions {
         if let Some(h2_ping_interval) = self.h2_ping_interval {
             write!(f, "h2_ping_interval: {:?},", h2_ping_interval)?;
         }
+        if let Some(he) = &self.happy_eyeballs {
+            write!(f, "happy_eyeballs: {:?},", he)?;
+        }
         Ok(())
     }
 }
@@ -399,6 +546,36 @@ impl HttpPeer {
         Self::new_from_sockaddr(SocketAddr::Inet(addr), tls, sni)
     }
 
+    /// Create a new [`HttpPeer`] that connects to all the resolved addresses of `address` with
+    /// the default [`HappyEyeballs`] settings.
+    ///
+    /// Fail when `address` can't be resolved or resolves to no address.
+    pub fn new_happy_eyeballs<A: ToInetSocketAddrs>(
+        address: A,
+        tls: bool,
+        sni: String,
+    ) -> Result<Self> {
+        let mut addrs_iter = address
+            .to_socket_addrs()
+            .or_err(InternalError, "fail to resolve the peer address")?;
+        let Some(addr) = addrs_iter.next() else {
+            return Error::e_explain(InternalError, "the peer address resolves to no address");
+        };
+        let mut peer = Self::new_from_sockaddr(SocketAddr::Inet(addr), tls, sni);
+        peer.options.alternative_addresses = addrs_iter.collect();
+        peer.options.happy_eyeballs = Some(HappyEyeballs::default());
+        Ok(peer)
+    }
+
+    /// Create a new [`HttpPeer`] whose `host` is resolved by the connector every time a new
+    /// connection is established.
+    pub fn new_hostname(host: &str, port: u16, tls: bool, sni: String) -> Self {
+        let addr = InetSocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+        let mut peer = Self::new_from_sockaddr(SocketAddr::Inet(addr), tls, sni);
+        peer.options.hostname = Some(host.to_string());
+        peer
+    }
+
     /// Create a new [`HttpPeer`] with the given path to Unix domain socket and TLS settings.
     pub fn new_uds(path: &str, tls: bool, sni: String) -> Self {
         let addr = SocketAddr::Unix(UnixSocketAddr::from_pathname(Path::new(path)).unwrap()); //TODO: handle error
@@ -430,6 +607,21 @@ impl HttpPeer {
         }
     }
 
+    /// Set the HTTP version to speak to this peer.
+    ///
+    /// - [ALPN::H1]: HTTP/1.1 only, the default.
+    /// - [ALPN::H2]: HTTP/2 only. Over TLS, connecting fails with `H2Downgrade` if the server
+    ///   doesn't select h2 via ALPN. Over plaintext, h2 is spoken with prior knowledge (h2c).
+    /// - [ALPN::H2H1]: HTTP/2 if the server selects it via ALPN, otherwise HTTP/1.1. Plaintext
+    ///   connections always use HTTP/1.1.
+    ///
+    /// Concurrent requests to this peer share the same HTTP/2 connection, up to `max_h2_streams`
+    /// streams at a time.
+    pub fn set_http_protocol(&mut self, alpn: ALPN, max_h2_streams: usize) {
+        self.options.alpn = alpn;
+        self.options.max_h2_streams = max_h2_streams.max(1);
+    }
+
     fn peer_hash(&self) -> u64 {
         let mut hasher = AHasher::default();
         self.hash(&mut hasher);
@@ -440,6 +632,7 @@ impl HttpPeer {
 impl Hash for HttpPeer {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self._address.hash(state);
+        self.hostname().hash(state);
         self.scheme.hash(state);
         self.proxy.hash(state);
         self.sni.hash(state);
@@ -449,12 +642,19 @@ impl Hash for HttpPeer {
         self.verify_cert().hash(state);
         self.verify_hostname().hash(state);
         self.alternative_cn().hash(state);
+        // a connection from a spoofed source only serves the client of that source
+        if self.transparent() {
+            self.bind_to().hash(state);
+        }
     }
 }
 
 impl Display for HttpPeer {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         write!(f, "addr: {}, scheme: {},", self._address, self.scheme)?;
+        if let Some(host) = self.hostname() {
+            write!(f, "host: {host},")?;
+        }
         if !self.sni.is_empty() {
             write!(f, "sni: {},", self.sni)?;
         }
@@ -501,8 +701,10 @@ impl Peer for HttpPeer {
     fn matches_fd<V: AsRawFd>(&self, fd: V) -> bool {
         if let Some(proxy) = self.get_proxy() {
             proxy.next_hop.check_fd_match(fd)
+        } else if self.hostname().is_some() {
+            matches_resolved(fd)
         } else {
-            self.address().check_fd_match(fd)
+            matches_any_address(self.address(), self.alternative_addresses(), fd)
         }
     }
 
@@ -535,3 +737,22 @@ impl Display for Proxy {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_happy_eyeballs() {
+        let addrs: [InetSocketAddr; 2] =
+            ["[::1]:80".parse().unwrap(), "127.0.0.1:80".parse().unwrap()];
+        let peer = HttpPeer::new_happy_eyeballs(&addrs[..], false, "".into()).unwrap();
+        assert_eq!(peer.address(), &SocketAddr::Inet(addrs[0]));
+        assert_eq!(peer.options.alternative_addresses, vec![addrs[1]]);
+        assert!(peer.options.happy_eyeballs.is_some());
+
+        let no_addrs: [InetSocketAddr; 0] = [];
+        assert!(HttpPeer::new_happy_eyeballs(&no_addrs[..], false, "".into()).is_err());
+        assert!(HttpPeer::new_happy_eyeballs("not an address", false, "".into()).is_err());
+    }
+}
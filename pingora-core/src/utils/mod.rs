@@ -19,7 +19,7 @@
 use crate::tls::{nid::Nid, pkey::PKey, pkey::Private, x509::X509};
 use crate::Result;
 use bytes::Bytes;
-use pingora_error::{ErrorType::*, OrErr};
+use pingora_error::{Error, ErrorType::*, OrErr};
 use std::hash::{Hash, Hasher};
 
 /// A `BufRef` is a reference to a buffer of bytes. It removes the need for self-referential data
@@ -167,6 +167,43 @@ impl CertKey {
         CertKey { certificates, key }
     }
 
+    /// Create a new `CertKey` like [`CertKey::new()`], but fail instead of panicking if the list of
+    /// certificates is empty or if the key doesn't match the leaf certificate.
+    pub fn try_new(certificates: Vec<X509>, key: PKey<Private>) -> Result<CertKey> {
+        let Some(leaf) = certificates.first() else {
+            return Error::e_explain(InvalidCert, "no certificate");
+        };
+        let public_key = leaf
+            .public_key()
+            .or_err(InvalidCert, "invalid public key of the certificate")?;
+        if !public_key.public_eq(&key) {
+            return Error::e_explain(InvalidCert, "the key doesn't match the certificate");
+        }
+        Ok(CertKey { certificates, key })
+    }
+
+    /// Load a `CertKey` from the PEM encoded certificate chain, leaf first, and private key.
+    ///
+    /// Fail if the key doesn't match the leaf certificate.
+    pub fn from_pem(certificates: &[u8], key: &[u8]) -> Result<CertKey> {
+        let certificates =
+            X509::stack_from_pem(certificates).or_err(InvalidCert, "invalid certificate PEM")?;
+        let key = PKey::private_key_from_pem(key).or_err(InvalidCert, "invalid key PEM")?;
+        Self::try_new(certificates, key)
+    }
+
+    /// Load a `CertKey` from the files of the PEM encoded certificate chain and private key, see
+    /// [`CertKey::from_pem()`].
+    pub fn from_pem_files(cert_path: &str, key_path: &str) -> Result<CertKey> {
+        let certificates = std::fs::read(cert_path).or_err_with(InvalidCert, || {
+            format!("fail to read cert file {cert_path}")
+        })?;
+        let key = std::fs::read(key_path)
+            .or_err_with(InvalidCert, || format!("fail to read key file {key_path}"))?;
+        Self::from_pem(&certificates, &key)
+            .map_err(|e| e.more_context(format!("cert {cert_path}, key {key_path}")))
+    }
+
     /// Peek at the leaf certificate.
     pub fn leaf(&self) -> &X509 {
         // This is safe due to the assertion above.
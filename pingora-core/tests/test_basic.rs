@@ -16,7 +16,7 @@ mod utils;
 
 use hyper::Client;
 use hyperlocal::{UnixClientExt, Uri};
-use utils::init;
+use utils::{init, STATS_LISTENER};
 
 #[tokio::test]
 async fn test_http() {
@@ -58,3 +58,49 @@ async fn test_uds() {
     let res = client.get(url).await.unwrap();
     assert_eq!(res.status(), reqwest::StatusCode::OK);
 }
+
+#[tokio::test]
+async fn test_listener_stats() {
+    init();
+    let stats = STATS_LISTENER.get().unwrap();
+
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    for _ in 0..2 {
+        let res = client.get("https://127.0.0.1:6144").send().await.unwrap();
+        assert_eq!(res.version(), reqwest::Version::HTTP_2);
+    }
+
+    let h1_client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .http1_only()
+        .build()
+        .unwrap();
+    let res = h1_client
+        .get("https://127.0.0.1:6144")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.version(), reqwest::Version::HTTP_11);
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.accepted, 2);
+    assert_eq!(snapshot.active, 2);
+    assert_eq!(snapshot.h2_requests, 2);
+    assert_eq!(snapshot.h1_requests, 1);
+    assert_eq!(snapshot.requests(), 3);
+
+    // the connections are closed along with their clients
+    drop(client);
+    drop(h1_client);
+    for _ in 0..100 {
+        if stats.snapshot().active == 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(stats.snapshot().active, 0);
+    assert_eq!(stats.snapshot().accepted, 2);
+}
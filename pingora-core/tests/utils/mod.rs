@@ -12,10 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use std::{thread, time};
 
-use pingora_core::listeners::Listeners;
+use pingora_core::listeners::{ListenerStats, Listeners};
 use pingora_core::server::configuration::Opt;
 use pingora_core::server::Server;
 use pingora_core::services::listening::Service;
@@ -67,6 +67,9 @@ pub fn new_http_echo_app() -> Arc<EchoApp> {
     Arc::new(EchoApp {})
 }
 
+// the counters of the endpoint that only test_listener_stats() sends requests to
+pub static STATS_LISTENER: OnceCell<Arc<ListenerStats>> = OnceCell::new();
+
 pub struct MyServer {
     pub handle: thread::JoinHandle<()>,
 }
@@ -94,7 +97,21 @@ fn entry_point(opt: Option<Opt>) {
         new_http_echo_app(),
     );
 
+    let mut stats_listeners = Listeners::new();
+    let mut tls_settings =
+        pingora_core::listeners::TlsSettings::intermediate(&cert_path, &key_path).unwrap();
+    tls_settings.enable_h2();
+    stats_listeners.add_tls_with_settings("0.0.0.0:6144", None, tls_settings);
+    let (_, stats) = stats_listeners.listener_stats().pop().unwrap();
+    let _ = STATS_LISTENER.set(stats);
+    let echo_service_stats = Service::with_listeners(
+        "Echo Service Stats".to_string(),
+        stats_listeners,
+        new_http_echo_app(),
+    );
+
     my_server.add_service(echo_service_http);
+    my_server.add_service(echo_service_stats);
     my_server.run_forever();
 }
 
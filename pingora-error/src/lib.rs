@@ -23,6 +23,12 @@ use std::result::Result as StdResult;
 mod immut_str;
 pub use immut_str::ImmutStr;
 
+mod retry;
+pub use retry::RequestProgress;
+
+mod status;
+pub use status::{reason_phrase, StatusMap};
+
 /// The boxed [Error], the desired way to pass [Error]
 pub type BError = Box<Error>;
 /// Syntax sugar for `std::Result<T, BError>`
@@ -41,6 +47,8 @@ pub struct Error {
     pub cause: Option<Box<(dyn ErrorTrait + Send + Sync)>>,
     /// an arbitrary string that explains the context when the error happens
     pub context: Option<ImmutStr>,
+    // whether the retry is decided via set_retry(), which overrides Self::is_retriable()
+    retry_decided: bool,
 }
 
 /// The source of the error
@@ -114,6 +122,8 @@ pub enum ErrorType {
     AcceptError,
     SocketError,
     ConnectProxyFailure,
+    ConnectLimited, // too many connections to the peer already
+    CircuitOpen,    // the circuit breaker of the peer is open
     // protocol errors
     InvalidHTTPHeader,
     H1Error,     // catch all
@@ -164,6 +174,8 @@ impl ErrorType {
             ErrorType::ConnectRefused => "ConnectRefused",
             ErrorType::ConnectNoRoute => "ConnectNoRoute",
             ErrorType::ConnectProxyFailure => "ConnectProxyFailure",
+            ErrorType::ConnectLimited => "ConnectLimited",
+            ErrorType::CircuitOpen => "CircuitOpen",
             ErrorType::TLSHandshakeFailure => "TLSHandshakeFailure",
             ErrorType::TLSHandshakeTimedout => "TLSHandshakeTimedout",
             ErrorType::InvalidCert => "InvalidCert",
@@ -204,14 +216,14 @@ impl Error {
         context: Option<ImmutStr>,
         cause: Option<Box<dyn ErrorTrait + Send + Sync>>,
     ) -> BError {
-        let retry = if let Some(c) = cause.as_ref() {
+        let (retry, retry_decided) = if let Some(c) = cause.as_ref() {
             if let Some(e) = c.downcast_ref::<BError>() {
-                e.retry
+                (e.retry, e.retry_decided)
             } else {
-                false.into()
+                (false.into(), false)
             }
         } else {
-            false.into()
+            (false.into(), false)
         };
         Box::new(Error {
             etype,
@@ -219,6 +231,7 @@ impl Error {
             retry,
             cause,
             context,
+            retry_decided,
         })
     }
 
@@ -344,6 +357,7 @@ impl Error {
 
     pub fn set_retry(&mut self, retry: bool) {
         self.retry = retry.into();
+        self.retry_decided = true;
     }
 
     pub fn reason_str(&self) -> &str {
@@ -415,9 +429,11 @@ impl Error {
     pub fn more_context<T: Into<ImmutStr>>(self: BError, context: T) -> BError {
         let esource = self.esource.clone();
         let retry = self.retry;
+        let retry_decided = self.retry_decided;
         let mut e = Self::because(self.etype.clone(), context, self);
         e.esource = esource;
         e.retry = retry;
+        e.retry_decided = retry_decided;
         e
     }
 
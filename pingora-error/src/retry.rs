@@ -0,0 +1,187 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Classify errors by whether it is safe to retry the request that failed
+
+use super::{Error, ErrorType, RetryType};
+
+impl ErrorType {
+    /// Whether the request never reaches the server when this type of error happens, so that it
+    /// is always safe to retry the request, e.g., on another server.
+    ///
+    /// These are the failures to establish the connection to the server.
+    pub fn is_retriable(&self) -> bool {
+        use ErrorType::*;
+        matches!(
+            self,
+            ConnectTimedout
+                | ConnectRefused
+                | ConnectNoRoute
+                | TLSHandshakeFailure
+                | TLSHandshakeTimedout
+                | HandshakeError
+                | ConnectError
+                | ConnectProxyFailure
+                | ConnectLimited
+                | CircuitOpen
+        )
+    }
+
+    // the errors that can happen after the request is sent but before any of the response is
+    // received
+    fn is_before_response(&self) -> bool {
+        use ErrorType::*;
+        matches!(
+            self,
+            ReadError
+                | WriteError
+                | ReadTimedout
+                | WriteTimedout
+                | ConnectionClosed
+                | H1Error
+                | H2Error
+                | InvalidHTTPHeader
+        )
+    }
+}
+
+/// How far a request got when it failed, see [Error::is_retriable_at()]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestProgress {
+    /// Whether any of the response is received from the server
+    pub response_started: bool,
+    /// Whether the request is safe to be processed by the server more than once, e.g., a `GET`,
+    /// or a `POST` known to be idempotent by the application
+    pub idempotent: bool,
+}
+
+impl Error {
+    /// Whether it is safe to retry the request that failed with this error, without knowing how
+    /// far the request got.
+    ///
+    /// When the retry of this error is decided via [Error::set_retry()] to override the
+    /// classification of a specific request, the decision is final either way. Otherwise this is
+    /// true if the request never reached the server, see [ErrorType::is_retriable()], or if the
+    /// retry is decided to be true, e.g., by the protocol when a reused connection turns out to
+    /// be closed by the server.
+    pub fn is_retriable(&self) -> bool {
+        match self.retry {
+            RetryType::Decided(retry) if self.retry_decided => retry,
+            RetryType::Decided(true) => true,
+            _ => self.etype.is_retriable(),
+        }
+    }
+
+    /// Whether it is safe to retry the request that failed with this error given its progress
+    ///
+    /// Besides [Self::is_retriable()], the errors that happen before the response starts are
+    /// retriable if the request is idempotent, unless the retry of the error is decided to be
+    /// false via [Error::set_retry()]. Nothing is retriable once the response started, because part of it might have been
+    /// forwarded to the client already.
+    pub fn is_retriable_at(&self, progress: RequestProgress) -> bool {
+        if progress.response_started {
+            return false;
+        }
+        match self.retry {
+            RetryType::Decided(retry) if self.retry_decided => retry,
+            _ => self.is_retriable() || (progress.idempotent && self.etype.is_before_response()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ErrorType::*;
+
+    #[test]
+    fn test_error_type_retriable() {
+        assert!(ConnectRefused.is_retriable());
+        assert!(TLSHandshakeTimedout.is_retriable());
+        assert!(ConnectLimited.is_retriable());
+        assert!(CircuitOpen.is_retriable());
+        assert!(!ReadTimedout.is_retriable());
+        assert!(!HTTPStatus(502).is_retriable());
+        assert!(!InternalError.is_retriable());
+    }
+
+    #[test]
+    fn test_error_retriable() {
+        assert!(Error::new_up(ConnectTimedout).is_retriable());
+        // the context doesn't change the classification
+        let e = Error::new_up(ConnectRefused).more_context("peer: 1.1.1.1:80");
+        assert!(e.is_retriable());
+
+        let mut e = Error::new_up(ReadError);
+        assert!(!e.is_retriable());
+        e.set_retry(true);
+        assert!(e.is_retriable());
+
+        // decided not to retry, regardless of the classification
+        let mut e = Error::new_up(ConnectRefused);
+        e.set_retry(false);
+        assert!(!e.is_retriable());
+
+        // not reused: left to the classification
+        let mut e = Error::new_up(ConnectRefused);
+        e.retry = RetryType::ReusedOnly;
+        e.retry.decide_reuse(false);
+        assert_eq!(e.retry, RetryType::Decided(false));
+        assert!(e.is_retriable());
+
+        // the decision survives the context
+        let mut e = Error::new_up(ConnectRefused);
+        e.set_retry(false);
+        assert!(!e.more_context("peer: 1.1.1.1:80").is_retriable());
+        assert_eq!(Error::new_up(ConnectRefused).retry, RetryType::Decided(false));
+
+        // undecided
+        let mut e = Error::new_up(ReadError);
+        e.retry = RetryType::ReusedOnly;
+        assert!(!e.is_retriable());
+    }
+
+    #[test]
+    fn test_retriable_at() {
+        let before_response = RequestProgress::default();
+        let idempotent = RequestProgress {
+            idempotent: true,
+            ..Default::default()
+        };
+        let started = RequestProgress {
+            response_started: true,
+            idempotent: true,
+        };
+
+        let e = Error::new_up(ConnectRefused);
+        assert!(e.is_retriable_at(before_response));
+        assert!(e.is_retriable_at(idempotent));
+
+        let e = Error::new_up(ReadTimedout);
+        assert!(!e.is_retriable_at(before_response));
+        assert!(e.is_retriable_at(idempotent));
+        assert!(!e.is_retriable_at(started));
+
+        let mut e = Error::new_up(ReadError);
+        e.set_retry(true);
+        assert!(e.is_retriable_at(before_response));
+        assert!(!e.is_retriable_at(started));
+
+        let mut e = Error::new_up(ReadTimedout);
+        e.set_retry(false);
+        assert!(!e.is_retriable_at(idempotent));
+
+        assert!(!Error::new_up(InternalError).is_retriable_at(idempotent));
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Map errors to the HTTP status codes to respond to the clients with
+
+use super::{Error, ErrorSource, ErrorType};
+
+impl ErrorType {
+    /// The default HTTP status code of the response to the client for this type of error from
+    /// the given source
+    ///
+    /// 0 means that no response should be sent, because the client connection is already broken.
+    pub fn default_status(&self, source: &ErrorSource) -> u16 {
+        use ErrorType::*;
+        if let HTTPStatus(code) = self {
+            return *code;
+        }
+        match source {
+            ErrorSource::Upstream => match self {
+                ConnectTimedout | TLSHandshakeTimedout | ReadTimedout | WriteTimedout => 504,
+                // the upstream is at capacity or is being given time to recover
+                ConnectLimited | CircuitOpen => 503,
+                _ => 502,
+            },
+            ErrorSource::Downstream => match self {
+                WriteError | ReadError | ConnectionClosed => 0,
+                _ => 400,
+            },
+            ErrorSource::Internal | ErrorSource::Unset => 500,
+        }
+    }
+}
+
+/// The mapping from errors to the HTTP status codes to respond to the clients with
+///
+/// Errors of the [ErrorType]s that are not overridden in this map use
+/// [ErrorType::default_status()].
+#[derive(Debug, Clone, Default)]
+pub struct StatusMap {
+    overrides: Vec<(ErrorType, u16)>,
+}
+
+impl StatusMap {
+    /// Create a new [StatusMap] with only the default mapping
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Respond with the given status code to the errors of the given type regardless of their
+    /// source
+    pub fn with(mut self, etype: ErrorType, status: u16) -> Self {
+        self.set(etype, status);
+        self
+    }
+
+    /// Same as [Self::with()] but in place
+    pub fn set(&mut self, etype: ErrorType, status: u16) {
+        match self.overrides.iter_mut().find(|(t, _)| *t == etype) {
+            Some(entry) => entry.1 = status,
+            None => self.overrides.push((etype, status)),
+        }
+    }
+
+    /// The status code to respond to the client with for the given error
+    ///
+    /// 0 means that no response should be sent.
+    pub fn status(&self, e: &Error) -> u16 {
+        self.overrides
+            .iter()
+            .find(|(t, _)| t == e.etype())
+            .map_or_else(|| e.etype().default_status(e.esource()), |(_, s)| *s)
+    }
+
+    /// The status code and a body that is safe to show to the client for the given error
+    ///
+    /// The body is the reason phrase of the status code so that the details of the error,
+    /// which may be sensitive, are never exposed.
+    pub fn response(&self, e: &Error) -> (u16, &'static str) {
+        let status = self.status(e);
+        (status, reason_phrase(status))
+    }
+}
+
+/// The canonical reason phrase of the given HTTP status code, or `"Error"` if it is unknown
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ErrorType::*;
+
+    #[test]
+    fn test_default_status() {
+        let up = ErrorSource::Upstream;
+        assert_eq!(ConnectRefused.default_status(&up), 502);
+        assert_eq!(ConnectTimedout.default_status(&up), 504);
+        assert_eq!(ReadTimedout.default_status(&up), 504);
+        assert_eq!(ReadError.default_status(&up), 502);
+        assert_eq!(ConnectLimited.default_status(&up), 503);
+        assert_eq!(CircuitOpen.default_status(&up), 503);
+        assert_eq!(HTTPStatus(429).default_status(&up), 429);
+
+        let down = ErrorSource::Downstream;
+        assert_eq!(ReadError.default_status(&down), 0);
+        assert_eq!(InvalidHTTPHeader.default_status(&down), 400);
+        assert_eq!(HTTPStatus(403).default_status(&down), 403);
+
+        assert_eq!(InternalError.default_status(&ErrorSource::Internal), 500);
+        assert_eq!(ConnectError.default_status(&ErrorSource::Unset), 500);
+    }
+
+    #[test]
+    fn test_status_map() {
+        let map = StatusMap::new()
+            .with(ConnectTimedout, 502)
+            .with(Custom("quota"), 429);
+        assert_eq!(map.status(&Error::new_up(ConnectTimedout)), 502);
+        assert_eq!(map.status(&Error::new_in(Custom("quota"))), 429);
+        // not overridden
+        assert_eq!(map.status(&Error::new_up(ReadTimedout)), 504);
+        assert_eq!(
+            map.response(&Error::new_up(ReadTimedout)),
+            (504, "Gateway Timeout")
+        );
+
+        let mut map = map;
+        map.set(ConnectTimedout, 504);
+        assert_eq!(map.status(&Error::new_up(ConnectTimedout)), 504);
+        assert_eq!(
+            map.response(&Error::new_in(Custom("quota"))).1,
+            "Too Many Requests"
+        );
+    }
+}
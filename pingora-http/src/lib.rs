@@ -125,7 +125,15 @@ impl RequestHeader {
         req.base.method = method
             .try_into()
             .explain_err(InvalidHTTPHeader, |_| "invalid method")?;
-        if let Ok(p) = std::str::from_utf8(path) {
+        if req.base.method == Method::CONNECT && !path.starts_with(b"/") {
+            // the authority-form of CONNECT, RFC 9110 9.3.6
+            let p = String::from_utf8_lossy(path);
+            req.base.uri = p
+                .parse()
+                .explain_err(InvalidHTTPHeader, |_| format!("invalid uri {}", p))?;
+            // the uri has no path to read the raw one from
+            req.raw_path_fallback = path.to_vec();
+        } else if let Ok(p) = std::str::from_utf8(path) {
             let uri = Uri::builder()
                 .path_and_query(p)
                 .build()
@@ -660,6 +668,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_connect_authority() {
+        let req = RequestHeader::build("CONNECT", b"pingora.org:443", None).unwrap();
+        assert_eq!(req.uri.authority().unwrap(), "pingora.org:443");
+        assert_eq!(req.raw_path(), b"pingora.org:443");
+        assert!(RequestHeader::build("CONNECT", b"pingora org", None).is_err());
+    }
+
     #[cfg(feature = "patched_http1")]
     #[test]
     fn test_invalid_path() {
@@ -129,12 +129,29 @@ pub struct Continuum {
     addrs: Box<[SocketAddr]>,
 }
 
+/// The number of points per weight unit of nginx, which [Continuum::new()] uses
+pub const DEFAULT_POINTS_PER_WEIGHT: u32 = 160;
+
 impl Continuum {
     /// Create a new [Continuum] with the given list of buckets.
+    ///
+    /// Each bucket gets [DEFAULT_POINTS_PER_WEIGHT] points on the ring per unit of its weight. For
+    /// example, a weight of 2 will create 320 points on the ring.
     pub fn new(buckets: &[Bucket]) -> Self {
-        // This constant is copied from nginx. It will create 160 points per weight unit. For
-        // example, a weight of 2 will create 320 points on the ring.
-        const POINT_MULTIPLE: u32 = 160;
+        Self::with_points_per_weight(buckets, DEFAULT_POINTS_PER_WEIGHT)
+    }
+
+    /// Create a new [Continuum] that gives each bucket `points` points on the ring per unit of its
+    /// weight.
+    ///
+    /// More points spread the keys more evenly across the buckets at the cost of a larger ring.
+    /// Only a ring built with [DEFAULT_POINTS_PER_WEIGHT] is compatible with nginx.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if `points` is zero.
+    pub fn with_points_per_weight(buckets: &[Bucket], points: u32) -> Self {
+        assert!(points != 0, "points must be at least one");
 
         if buckets.is_empty() {
             return Continuum {
@@ -145,7 +162,7 @@ impl Continuum {
 
         // The total weight is multiplied by the factor of points to create many points per node.
         let total_weight: u32 = buckets.iter().fold(0, |sum, b| sum + b.weight);
-        let mut ring = Vec::with_capacity((total_weight * POINT_MULTIPLE) as usize);
+        let mut ring = Vec::with_capacity((total_weight * points) as usize);
         let mut addrs = Vec::with_capacity(buckets.len());
 
         for bucket in buckets {
@@ -166,7 +183,7 @@ impl Continuum {
             hasher.update(hash_bytes.as_ref());
 
             // A higher weight will add more points for this node.
-            let num_points = bucket.weight * POINT_MULTIPLE;
+            let num_points = bucket.weight * points;
 
             // This is appended to the crc32 hash for each point.
             let mut prev_hash: u32 = 0;
@@ -424,6 +441,18 @@ mod tests {
         assert!(iter.next().is_none());
     }
 
+    #[test]
+    fn test_points_per_weight() {
+        let buckets = [
+            Bucket::new(get_sockaddr("127.0.0.1:7777"), 1),
+            Bucket::new(get_sockaddr("127.0.0.1:7778"), 2),
+        ];
+        let c = Continuum::new(&buckets);
+        assert_eq!(c.ring.len(), 3 * 160);
+        let c = Continuum::with_points_per_weight(&buckets, 10);
+        assert_eq!(c.ring.len(), 3 * 10);
+    }
+
     #[test]
     fn test_ipv6_ring() {
         let upstream_hosts = ["[::1]:7777", "[::1]:7778", "[::1]:7779"];
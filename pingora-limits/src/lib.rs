@@ -22,6 +22,7 @@
 pub mod estimator;
 pub mod inflight;
 pub mod rate;
+pub mod token_bucket;
 
 use ahash::RandomState;
 use std::hash::Hash;
@@ -0,0 +1,189 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The token_bucket module defines the [TokenBucket] type which limits the rate of events per key.
+
+use ahash::RandomState;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// the number of independently locked shards of the buckets
+const SHARDS: usize = 64;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket rate limiter for each key, e.g., the client IP
+///
+/// Each key gets a bucket of `burst` tokens which refills at `rate` tokens per second. An event
+/// takes a token from the bucket of its key and is limited when the bucket is empty. The buckets
+/// are sharded and locked independently so that the limiter can be shared by all the threads.
+///
+/// A bucket that is full again is the same as a missing one, so such buckets can be dropped to
+/// bound the memory used by the idle keys. [Self::cleanup()] should be called periodically for
+/// that, e.g., from a background task, so that it doesn't add to the latency of the events.
+pub struct TokenBucket<K> {
+    rate: f64,
+    burst: f64,
+    shards: Box<[Mutex<HashMap<K, Bucket, RandomState>>]>,
+    hasher: RandomState,
+    allowed: AtomicU64,
+    limited: AtomicU64,
+}
+
+impl<K: Hash + Eq> TokenBucket<K> {
+    /// Create a new [TokenBucket] that allows `rate` events per second per key on average and up
+    /// to `burst` events at once.
+    pub fn new(rate: f64, burst: usize) -> Self {
+        assert!(rate > 0.0, "rate must be positive");
+        TokenBucket {
+            rate,
+            burst: burst.max(1) as f64,
+            shards: (0..SHARDS)
+                .map(|_| Mutex::new(HashMap::with_hasher(RandomState::new())))
+                .collect(),
+            hasher: RandomState::new(),
+            allowed: AtomicU64::new(0),
+            limited: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<HashMap<K, Bucket, RandomState>> {
+        let index = crate::hash(key, &self.hasher) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    /// Take a token for an event of the given key
+    ///
+    /// Return `Err` with the time until the next token is available if the event is limited.
+    pub fn acquire(&self, key: K) -> Result<(), Duration> {
+        self.acquire_at(key, Instant::now())
+    }
+
+    fn acquire_at(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let result = {
+            let mut shard = self.shard(&key).lock().unwrap();
+            let bucket = shard.entry(key).or_insert(Bucket {
+                tokens: self.burst,
+                updated: now,
+            });
+            self.refill(bucket, now);
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                Ok(())
+            } else {
+                Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+            }
+        };
+        match result {
+            Ok(_) => self.allowed.fetch_add(1, Ordering::Relaxed),
+            Err(_) => self.limited.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+    }
+
+    fn cleanup_at(&self, now: Instant) -> usize {
+        let mut removed = 0;
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let before = shard.len();
+            shard.retain(|_, bucket| {
+                self.refill(bucket, now);
+                bucket.tokens < self.burst
+            });
+            removed += before - shard.len();
+        }
+        removed
+    }
+
+    /// Drop the buckets of the keys that have been idle long enough for their buckets to be full
+    ///
+    /// Return the number of the buckets dropped.
+    pub fn cleanup(&self) -> usize {
+        self.cleanup_at(Instant::now())
+    }
+
+    /// The number of the keys being tracked
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    /// Whether no key is being tracked
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The total number of the events allowed so far
+    pub fn allowed(&self) -> u64 {
+        self.allowed.load(Ordering::Relaxed)
+    }
+
+    /// The total number of the events limited so far
+    pub fn limited(&self) -> u64 {
+        self.limited.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_and_refill() {
+        let limiter = TokenBucket::new(10.0, 3);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.acquire_at("a", now).is_ok());
+        }
+        let retry_after = limiter.acquire_at("a", now).unwrap_err();
+        assert!(
+            retry_after > Duration::from_millis(99) && retry_after <= Duration::from_millis(100)
+        );
+        // other keys have their own buckets
+        assert!(limiter.acquire_at("b", now).is_ok());
+
+        // one token per 100ms
+        let later = now + Duration::from_millis(150);
+        assert!(limiter.acquire_at("a", later).is_ok());
+        assert!(limiter.acquire_at("a", later).is_err());
+        assert_eq!(limiter.allowed(), 5);
+        assert_eq!(limiter.limited(), 2);
+    }
+
+    #[test]
+    fn test_cleanup() {
+        let limiter = TokenBucket::new(1.0, 2);
+        let now = Instant::now();
+        limiter.acquire_at(1, now).unwrap();
+        limiter.acquire_at(2, now).unwrap();
+        limiter.acquire_at(2, now).unwrap();
+        assert_eq!(limiter.len(), 2);
+        // key 1 is full again after 1 second, key 2 is not yet
+        assert_eq!(limiter.cleanup_at(now + Duration::from_millis(1500)), 1);
+        assert_eq!(limiter.len(), 1);
+        assert_eq!(limiter.cleanup_at(now + Duration::from_secs(2)), 1);
+        assert!(limiter.is_empty());
+    }
+}
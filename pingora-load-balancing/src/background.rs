@@ -38,15 +38,14 @@ where
             }
 
             if next_update <= now {
-                // TODO: log err
-                let _ = self.update().await;
+                if let Err(e) = self.update().await {
+                    log::warn!("fail to update the backends, {e}");
+                }
                 next_update = now + self.update_frequency.unwrap_or(NEVER);
             }
 
             if next_health_check <= now {
-                self.backends
-                    .run_health_check(self.parallel_health_check)
-                    .await;
+                self.run_health_check().await;
                 next_health_check = now + self.health_check_frequency.unwrap_or(NEVER);
             }
 
@@ -16,13 +16,15 @@
 
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
+use log::debug;
 use pingora_core::protocols::l4::socket::SocketAddr;
-use pingora_error::Result;
+use pingora_error::{ErrorType, OrErr, Result};
 use std::io::Result as IoResult;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr as InetSocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
 use std::{
     collections::{BTreeSet, HashMap},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
 use crate::Backend;
@@ -36,8 +38,6 @@ pub trait ServiceDiscovery {
     async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)>;
 }
 
-// TODO: add DNS base discovery
-
 /// A static collection of [Backend]s for service discovery.
 #[derive(Default)]
 pub struct Static {
@@ -62,6 +62,7 @@ impl Static {
             let addrs = addrs.to_socket_addrs()?.map(|addr| Backend {
                 addr: SocketAddr::Inet(addr),
                 weight: 1,
+                metadata: Default::default(),
             });
             upstreams.extend(addrs);
         }
@@ -105,3 +106,253 @@ impl ServiceDiscovery for Static {
         Ok((self.get(), health))
     }
 }
+
+/// A DNS SRV record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SrvRecord {
+    /// Lower values are preferred
+    pub priority: u16,
+    /// The relative weight among the records of the same priority
+    pub weight: u16,
+    /// The port of the service on the target
+    pub port: u16,
+    /// The hostname of the target
+    pub target: String,
+}
+
+/// The interface to look up DNS SRV records, so that any DNS client can back [Srv].
+///
+/// This crate doesn't come with a DNS client, so [Self::lookup_srv()] has to be implemented with
+/// the DNS client of the application, e.g.
+/// ```
+/// use async_trait::async_trait;
+/// use pingora_error::Result;
+/// use pingora_load_balancing::discovery::{SrvRecord, SrvResolver};
+/// use std::time::Duration;
+///
+/// struct MyResolver;
+///
+/// #[async_trait]
+/// impl SrvResolver for MyResolver {
+///     async fn lookup_srv(&self, name: &str) -> Result<(Vec<SrvRecord>, Option<Duration>)> {
+///         // query the SRV records of `name` with the DNS client here
+///         let record = SrvRecord {
+///             priority: 10,
+///             weight: 1,
+///             port: 8080,
+///             target: "localhost.".into(),
+///         };
+///         Ok((vec![record], Some(Duration::from_secs(60))))
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait SrvResolver {
+    /// Look up the SRV records of `name` and the TTL of the answer if known.
+    async fn lookup_srv(&self, name: &str) -> Result<(Vec<SrvRecord>, Option<Duration>)>;
+
+    /// Resolve the target of a SRV record to its socket addresses.
+    ///
+    /// The system resolver is used by default.
+    async fn lookup_target(&self, target: &str, port: u16) -> Result<Vec<InetSocketAddr>> {
+        let addrs = tokio::net::lookup_host((target.trim_end_matches('.'), port))
+            .await
+            .or_err_with(ErrorType::ConnectError, || {
+                format!("fail to resolve {target}")
+            })?;
+        Ok(addrs.collect())
+    }
+}
+
+struct SrvCache {
+    backends: BTreeSet<Backend>,
+    expire: Instant,
+}
+
+/// A [ServiceDiscovery] that discovers [Backend]s from the DNS SRV records of a name.
+///
+/// Only the records with the lowest priority value are used, as the most preferred targets. The
+/// weight of each record becomes the weight of its [Backend]s. A target resolving to multiple
+/// addresses yields one [Backend] per address.
+///
+/// The failover to the records of the next priority value is left to the DNS: it happens at the
+/// next lookup after the records of the lowest priority value are removed from the answer. The
+/// health of the [Backend]s doesn't trigger it, so when all of them are unhealthy the selection
+/// fails until the answer changes.
+///
+/// The SRV answer is cached for its TTL, so the lookup only happens when the answer expires
+/// even if [Backends::update()](crate::Backends::update) is called more often.
+pub struct Srv {
+    name: String,
+    resolver: Box<dyn SrvResolver + Send + Sync>,
+    cache: Mutex<Option<SrvCache>>,
+    /// How long to cache an answer without TTL. Default 30 seconds.
+    pub default_ttl: Duration,
+}
+
+impl Srv {
+    /// Create a new boxed [Srv] service discovery for the given SRV name, e.g.
+    /// `_http._tcp.example.com`
+    pub fn new(name: &str, resolver: Box<dyn SrvResolver + Send + Sync>) -> Box<Self> {
+        Box::new(Srv {
+            name: name.to_string(),
+            resolver,
+            cache: Mutex::new(None),
+            default_ttl: Duration::from_secs(30),
+        })
+    }
+
+    fn cached(&self, now: Instant) -> Option<BTreeSet<Backend>> {
+        let cache = self.cache.lock().unwrap();
+        cache
+            .as_ref()
+            .filter(|c| c.expire > now)
+            .map(|c| c.backends.clone())
+    }
+
+    async fn lookup(&self) -> Result<(BTreeSet<Backend>, Duration)> {
+        let (records, ttl) = self.resolver.lookup_srv(&self.name).await?;
+        let ttl = ttl.unwrap_or(self.default_ttl);
+
+        let mut backends = BTreeSet::new();
+        let Some(priority) = records.iter().map(|r| r.priority).min() else {
+            return Ok((backends, ttl));
+        };
+        for record in records.iter().filter(|r| r.priority == priority) {
+            let addrs = self
+                .resolver
+                .lookup_target(&record.target, record.port)
+                .await?;
+            backends.extend(addrs.into_iter().map(|addr| Backend {
+                addr: SocketAddr::Inet(addr),
+                // weight 0 records should still be selected occasionally
+                weight: std::cmp::max(record.weight as usize, 1),
+                metadata: Default::default(),
+            }));
+        }
+        Ok((backends, ttl))
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for Srv {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let now = Instant::now();
+        if let Some(backends) = self.cached(now) {
+            return Ok((backends, HashMap::new()));
+        }
+        let (backends, ttl) = self.lookup().await?;
+        debug!("SRV {} discovered {} backends", self.name, backends.len());
+        *self.cache.lock().unwrap() = Some(SrvCache {
+            backends: backends.clone(),
+            expire: now + ttl,
+        });
+        Ok((backends, HashMap::new()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct MockSrv {
+        lookups: Arc<AtomicUsize>,
+        ttl: Option<Duration>,
+        // drop the records of the lowest priority value from the answer
+        primary_removed: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl SrvResolver for MockSrv {
+        async fn lookup_srv(&self, _name: &str) -> Result<(Vec<SrvRecord>, Option<Duration>)> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            let record = |priority, weight, target: &str| SrvRecord {
+                priority,
+                weight,
+                port: 8080,
+                target: target.to_string(),
+            };
+            let mut records = vec![
+                record(10, 5, "a.example."),
+                record(10, 0, "b.example."),
+                record(20, 1, "backup.example."),
+            ];
+            if self.primary_removed.load(Ordering::Relaxed) {
+                records.retain(|r| r.priority != 10);
+            }
+            Ok((records, self.ttl))
+        }
+
+        async fn lookup_target(&self, target: &str, port: u16) -> Result<Vec<InetSocketAddr>> {
+            let ip = match target {
+                "a.example." => "10.0.0.1",
+                "b.example." => "10.0.0.2",
+                _ => "10.0.0.3",
+            };
+            Ok(vec![InetSocketAddr::new(ip.parse().unwrap(), port)])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_srv_priority_and_weight() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let srv = Srv::new(
+            "_http._tcp.example",
+            Box::new(MockSrv {
+                lookups: lookups.clone(),
+                ..Default::default()
+            }),
+        );
+        let (backends, _) = srv.discover().await.unwrap();
+        let mut a = Backend::new("10.0.0.1:8080").unwrap();
+        a.weight = 5;
+        let b = Backend::new("10.0.0.2:8080").unwrap();
+        assert_eq!(backends, BTreeSet::from([a, b]));
+    }
+
+    #[tokio::test]
+    async fn test_srv_ttl() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let srv = Srv::new(
+            "_http._tcp.example",
+            Box::new(MockSrv {
+                lookups: lookups.clone(),
+                ttl: Some(Duration::from_millis(50)),
+                ..Default::default()
+            }),
+        );
+        srv.discover().await.unwrap();
+        srv.discover().await.unwrap();
+        assert_eq!(lookups.load(Ordering::Relaxed), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        srv.discover().await.unwrap();
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_srv_failover() {
+        let primary_removed = Arc::new(AtomicBool::new(false));
+        let srv = Srv::new(
+            "_http._tcp.example",
+            Box::new(MockSrv {
+                ttl: Some(Duration::from_millis(50)),
+                primary_removed: primary_removed.clone(),
+                ..Default::default()
+            }),
+        );
+        let (backends, _) = srv.discover().await.unwrap();
+        assert_eq!(backends.len(), 2);
+
+        // the next priority value is used once the answer drops the lowest one
+        primary_removed.store(true, Ordering::Relaxed);
+        let (backends, _) = srv.discover().await.unwrap();
+        assert_eq!(backends.len(), 2);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let (backends, _) = srv.discover().await.unwrap();
+        let backup = Backend::new("10.0.0.3:8080").unwrap();
+        assert_eq!(backends, BTreeSet::from([backup]));
+    }
+}
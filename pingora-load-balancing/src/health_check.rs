@@ -17,12 +17,17 @@
 use crate::Backend;
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
+use bytes::Bytes;
 use pingora_core::connectors::{http::Connector as HttpConnector, TransportConnector};
-use pingora_core::upstreams::peer::{BasicPeer, HttpPeer, Peer};
-use pingora_error::{Error, ErrorType::CustomCode, Result};
+use pingora_core::protocols::http::client::HttpSession;
+use pingora_core::upstreams::peer::{BasicPeer, HttpPeer, Peer, ALPN};
+use pingora_error::{
+    Error, ErrorType, ErrorType::CustomCode, ErrorType::InternalError, OrErr, Result,
+};
 use pingora_http::{RequestHeader, ResponseHeader};
+use std::ops::RangeInclusive;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 /// [HealthCheck] is the interface to implement health check for backends
 #[async_trait]
@@ -114,6 +119,33 @@ impl HealthCheck for TcpHealthCheck {
 
 type Validator = Box<dyn Fn(&ResponseHeader) -> Result<()> + Send + Sync>;
 
+/// How to match the response body of a [HttpHealthCheck]
+pub enum BodyMatcher {
+    /// The body contains the given bytes
+    Contains(Vec<u8>),
+    /// The body matches the given regular expression
+    Regex(regex::bytes::Regex),
+}
+
+impl BodyMatcher {
+    /// Create a [BodyMatcher::Regex] from the given pattern
+    pub fn regex(pattern: &str) -> Result<Self> {
+        regex::bytes::Regex::new(pattern)
+            .or_err_with(InternalError, || format!("invalid regex {pattern}"))
+            .map(BodyMatcher::Regex)
+    }
+
+    /// Whether the given body matches
+    pub fn is_match(&self, body: &[u8]) -> bool {
+        match self {
+            BodyMatcher::Contains(needle) => {
+                needle.is_empty() || body.windows(needle.len()).any(|w| w == needle.as_slice())
+            }
+            BodyMatcher::Regex(re) => re.is_match(body),
+        }
+    }
+}
+
 /// HTTP health check
 ///
 /// This health check checks if it can receive the expected HTTP(s) response from the given backend.
@@ -142,8 +174,20 @@ pub struct HttpHealthCheck {
     connector: HttpConnector,
     /// Optional field to define how to validate the response from the server.
     ///
-    /// If not set, any response with a `200 OK` is considered a successful check.
+    /// If not set, any response with a status in `accepted_status` is considered a successful
+    /// check.
     pub validator: Option<Validator>,
+    /// The response status codes that pass the check, when `validator` is not set.
+    ///
+    /// Redirects are not followed: a 3xx response fails the check unless it is accepted here.
+    pub accepted_status: Vec<RangeInclusive<u16>>,
+    /// Optional matcher that the response body must satisfy for the check to pass.
+    pub body_matcher: Option<BodyMatcher>,
+    /// The maximum number of bytes of the response body to read for `body_matcher`.
+    ///
+    /// Only this prefix of the body is matched. The rest of a longer body is not read, and the
+    /// connection is not reused.
+    pub max_body_bytes: usize,
     /// Sometimes the health check endpoint lives one a different port than the actual backend.
     /// Setting this option allows the health check to perform on the given port of the backend IP.
     pub port_override: Option<u16>,
@@ -157,7 +201,10 @@ impl HttpHealthCheck {
     /// * consecutive_success: 1
     /// * consecutive_failure: 1
     /// * reuse_connection: false
-    /// * validator: `None`, any 200 response is considered successful
+    /// * validator: `None`, any response with an accepted status is considered successful
+    /// * accepted_status: 200
+    /// * body_matcher: `None`, the response body is not checked
+    /// * max_body_bytes: 4096
     pub fn new(host: &str, tls: bool) -> Self {
         let mut req = RequestHeader::build("GET", b"/", None).unwrap();
         req.append_header("Host", host).unwrap();
@@ -173,6 +220,9 @@ impl HttpHealthCheck {
             reuse_connection: false,
             req,
             validator: None,
+            accepted_status: vec![200..=200],
+            body_matcher: None,
+            max_body_bytes: 4096,
             port_override: None,
         }
     }
@@ -215,21 +265,194 @@ impl HealthCheck for HttpHealthCheck {
 
         if let Some(validator) = self.validator.as_ref() {
             validator(resp)?;
-        } else if resp.status != 200 {
+        } else {
+            let status = resp.status.as_u16();
+            if !self.accepted_status.iter().any(|r| r.contains(&status)) {
+                return Error::e_explain(
+                    CustomCode("unexpected status code", status),
+                    "during http healthcheck",
+                );
+            }
+        };
+
+        let mut body = Vec::new();
+        let mut truncated = false;
+        while let Some(chunk) = session.read_response_body().await? {
+            if self.body_matcher.is_none() {
+                // drain the body if any
+                continue;
+            }
+            let room = self.max_body_bytes - body.len();
+            body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+            if body.len() == self.max_body_bytes {
+                truncated = true;
+                break;
+            }
+        }
+
+        if let Some(matcher) = self.body_matcher.as_ref() {
+            if !matcher.is_match(&body) {
+                return Error::e_explain(
+                    ErrorType::Custom("unexpected body"),
+                    "during http healthcheck",
+                );
+            }
+        }
+
+        if self.reuse_connection && !truncated {
+            let idle_timeout = peer.idle_timeout();
+            self.connector
+                .release_http_session(session, &peer, idle_timeout)
+                .await;
+        }
+
+        Ok(())
+    }
+}
+
+/// The error type of failed gRPC health checks
+pub const GRPC_HC_ERR: ErrorType = ErrorType::Custom("GrpcHealthCheckError");
+
+// the `SERVING` value of `grpc.health.v1.HealthCheckResponse.ServingStatus`
+const GRPC_SERVING: u64 = 1;
+// a health check response is a few bytes, anything much larger is not one
+const GRPC_MAX_RESPONSE_SIZE: usize = 1024;
+
+/// gRPC health check
+///
+/// This health check calls the `grpc.health.v1.Health/Check` method of the
+/// [gRPC health checking protocol](https://github.com/grpc/grpc/blob/master/doc/health-checking.md).
+/// The check passes only when the backend responds with the `SERVING` status.
+pub struct GrpcHealthCheck {
+    /// Number of successful checks to flip from unhealthy to healthy.
+    pub consecutive_success: usize,
+    /// Number of failed checks to flip from healthy to unhealthy.
+    pub consecutive_failure: usize,
+    /// How to connect to the backend.
+    ///
+    /// This field defines settings like the connect timeout and src IP to bind.
+    /// The SocketAddr of `peer_template` is just a placeholder which will be replaced by the
+    /// actual address of the backend when the health check runs.
+    ///
+    /// The ALPN is set to h2 only. Without TLS, this means h2c with prior knowledge.
+    pub peer_template: HttpPeer,
+    /// The `:authority` of the health check request
+    pub host: String,
+    /// The name of the service to check. The empty string, the default, checks the health of the
+    /// server as a whole.
+    pub service: String,
+    /// Whether the underlying TCP/TLS connection can be reused across checks.
+    ///
+    /// See [HttpHealthCheck::reuse_connection].
+    pub reuse_connection: bool,
+    /// Sometimes the health check endpoint lives one a different port than the actual backend.
+    /// Setting this option allows the health check to perform on the given port of the backend IP.
+    pub port_override: Option<u16>,
+    connector: HttpConnector,
+}
+
+impl GrpcHealthCheck {
+    /// Create a new [GrpcHealthCheck] with the following default settings
+    /// * connect timeout: 1 second
+    /// * read timeout: 1 second
+    /// * service: the empty string, the server as a whole
+    /// * consecutive_success: 1
+    /// * consecutive_failure: 1
+    /// * reuse_connection: false
+    pub fn new(host: &str, tls: bool) -> Self {
+        let sni = if tls { host.into() } else { String::new() };
+        let mut peer_template = HttpPeer::new("0.0.0.0:1", tls, sni);
+        peer_template.options.alpn = ALPN::H2;
+        peer_template.options.connection_timeout = Some(Duration::from_secs(1));
+        peer_template.options.read_timeout = Some(Duration::from_secs(1));
+        GrpcHealthCheck {
+            consecutive_success: 1,
+            consecutive_failure: 1,
+            peer_template,
+            host: host.into(),
+            service: String::new(),
+            reuse_connection: false,
+            port_override: None,
+            connector: HttpConnector::new(None),
+        }
+    }
+
+    /// Replace the internal http connector with the given [HttpConnector]
+    pub fn set_connector(&mut self, connector: HttpConnector) {
+        self.connector = connector;
+    }
+}
+
+#[async_trait]
+impl HealthCheck for GrpcHealthCheck {
+    fn health_threshold(&self, success: bool) -> usize {
+        if success {
+            self.consecutive_success
+        } else {
+            self.consecutive_failure
+        }
+    }
+
+    async fn check(&self, target: &Backend) -> Result<()> {
+        let mut peer = self.peer_template.clone();
+        peer._address = target.addr.clone();
+        if let Some(port) = self.port_override {
+            peer._address.set_port(port);
+        }
+        let HttpSession::H2(mut session) = self.connector.get_http_session(&peer).await?.0 else {
+            return Error::e_explain(GRPC_HC_ERR, "backend doesn't support h2");
+        };
+
+        let mut req = RequestHeader::build("POST", b"/grpc.health.v1.Health/Check", None)?;
+        req.insert_header("Host", &self.host)?;
+        req.insert_header("Content-Type", "application/grpc")?;
+        req.insert_header("TE", "trailers")?;
+        session.write_request_header(Box::new(req), false)?;
+        session.write_request_body(encode_grpc_check_request(&self.service), true)?;
+        session.read_timeout = peer.options.read_timeout;
+
+        session.read_response_header().await?;
+        let resp = session.response_header().expect("just read");
+        if resp.status != 200 {
             return Error::e_explain(
                 CustomCode("non 200 code", resp.status.as_u16()),
-                "during http healthcheck",
+                "during grpc healthcheck",
+            );
+        }
+        // Trailers-Only responses carry the grpc-status in the headers
+        let mut grpc_status = resp.headers.get("grpc-status").cloned();
+
+        let mut body = Vec::new();
+        while let Some(chunk) = session.read_response_body().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() > GRPC_MAX_RESPONSE_SIZE {
+                return Error::e_explain(GRPC_HC_ERR, "response too large");
+            }
+        }
+        if grpc_status.is_none() {
+            grpc_status = session
+                .read_trailers()
+                .await?
+                .and_then(|t| t.get("grpc-status").cloned());
+        }
+        match grpc_status {
+            Some(s) if s == "0" => {}
+            s => {
+                return Error::e_explain(GRPC_HC_ERR, format!("grpc-status: {s:?}"));
+            }
+        }
+        let status = decode_grpc_check_response(&body)?;
+        if status != GRPC_SERVING {
+            return Error::e_explain(
+                GRPC_HC_ERR,
+                format!("service {:?} is not serving: {status}", self.service),
             );
-        };
-
-        while session.read_response_body().await?.is_some() {
-            // drain the body if any
         }
 
         if self.reuse_connection {
             let idle_timeout = peer.idle_timeout();
             self.connector
-                .release_http_session(session, &peer, idle_timeout)
+                .release_http_session(HttpSession::H2(session), &peer, idle_timeout)
                 .await;
         }
 
@@ -237,6 +460,125 @@ impl HealthCheck for HttpHealthCheck {
     }
 }
 
+// A length prefixed gRPC message of `grpc.health.v1.HealthCheckRequest { string service = 1; }`
+fn encode_grpc_check_request(service: &str) -> Bytes {
+    let mut msg = Vec::with_capacity(service.len() + 6);
+    if !service.is_empty() {
+        msg.push(0x0a); // field 1, length delimited
+        encode_varint(service.len() as u64, &mut msg);
+        msg.extend_from_slice(service.as_bytes());
+    }
+    let mut framed = Vec::with_capacity(msg.len() + 5);
+    framed.push(0); // not compressed
+    framed.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+    framed.extend_from_slice(&msg);
+    framed.into()
+}
+
+fn encode_varint(mut v: u64, buf: &mut Vec<u8>) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn decode_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut v = 0;
+    for shift in (0..64).step_by(7) {
+        let Some((&b, rest)) = buf.split_first() else {
+            break;
+        };
+        *buf = rest;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b < 0x80 {
+            return Ok(v);
+        }
+    }
+    Error::e_explain(GRPC_HC_ERR, "invalid varint in response")
+}
+
+// Return the `status` of a length prefixed gRPC message of
+// `grpc.health.v1.HealthCheckResponse { ServingStatus status = 1; }`
+fn decode_grpc_check_response(body: &[u8]) -> Result<u64> {
+    if body.len() < 5 {
+        return Error::e_explain(GRPC_HC_ERR, "response message too short");
+    }
+    if body[0] != 0 {
+        return Error::e_explain(GRPC_HC_ERR, "compressed response is not supported");
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    let Some(mut msg) = body[5..].get(..len) else {
+        return Error::e_explain(GRPC_HC_ERR, "truncated response message");
+    };
+
+    // UNKNOWN(0) is the default value, which is omitted on the wire
+    let mut status = 0;
+    while !msg.is_empty() {
+        let tag = decode_varint(&mut msg)?;
+        match tag & 0x7 {
+            0 => {
+                let v = decode_varint(&mut msg)?;
+                if tag >> 3 == 1 {
+                    status = v;
+                }
+            }
+            // skip unknown fields
+            1 | 5 => {
+                let size = if tag & 0x7 == 1 { 8 } else { 4 };
+                let Some(rest) = msg.get(size..) else {
+                    return Error::e_explain(GRPC_HC_ERR, "truncated response message");
+                };
+                msg = rest;
+            }
+            2 => {
+                let size = decode_varint(&mut msg)? as usize;
+                let Some(rest) = msg.get(size..) else {
+                    return Error::e_explain(GRPC_HC_ERR, "truncated response message");
+                };
+                msg = rest;
+            }
+            _ => return Error::e_explain(GRPC_HC_ERR, "invalid wire type in response"),
+        }
+    }
+    Ok(status)
+}
+
+/// The health of a backend as decided by the health check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthState {
+    /// The backend passes the health check
+    Healthy,
+    /// The backend fails the health check and takes no traffic
+    Unhealthy,
+}
+
+impl From<bool> for HealthState {
+    fn from(healthy: bool) -> Self {
+        if healthy {
+            HealthState::Healthy
+        } else {
+            HealthState::Unhealthy
+        }
+    }
+}
+
+/// A backend whose health flipped, see [Backends::set_health_callback()]
+///
+/// [Backends::set_health_callback()]: crate::Backends::set_health_callback
+#[derive(Debug)]
+pub struct HealthEvent {
+    /// The backend checked
+    pub backend: Backend,
+    /// The health before the check
+    pub from: HealthState,
+    /// The health after the check
+    pub to: HealthState,
+    /// The error of the failed check that made the backend unhealthy, `None` when it becomes
+    /// healthy
+    pub error: Option<Box<Error>>,
+}
+
 #[derive(Clone)]
 struct HealthInner {
     /// Whether the endpoint is healthy to serve traffic
@@ -247,6 +589,9 @@ struct HealthInner {
     /// When [healthy] is true, this counts the number of consecutive health check failures
     /// so that the caller can flip the healthy when a certain threshold is met, and vise versa.
     consecutive_counter: usize,
+    /// Since when the endpoint is warming up after it becomes healthy or is discovered.
+    /// `None` if the endpoint doesn't need to warm up.
+    warming_since: Option<Instant>,
 }
 
 /// Health of backends that can be updated atomically
@@ -258,6 +603,7 @@ impl Default for Health {
             healthy: true, // TODO: allow to start with unhealthy
             enabled: true,
             consecutive_counter: 0,
+            warming_since: None,
         })))
     }
 }
@@ -270,6 +616,16 @@ impl Clone for Health {
 }
 
 impl Health {
+    /// A new health for a backend that just joined and should warm up
+    pub fn new_warming() -> Self {
+        Health(ArcSwap::new(Arc::new(HealthInner {
+            healthy: true,
+            enabled: true,
+            consecutive_counter: 0,
+            warming_since: Some(Instant::now()),
+        })))
+    }
+
     pub fn ready(&self) -> bool {
         let h = self.0.load();
         h.healthy && h.enabled
@@ -297,6 +653,7 @@ impl Health {
             if new_health.consecutive_counter >= flip_threshold {
                 new_health.healthy = health;
                 new_health.consecutive_counter = 0;
+                new_health.warming_since = health.then(Instant::now);
                 flipped = true;
             }
             self.0.store(Arc::new(new_health));
@@ -309,6 +666,20 @@ impl Health {
         }
         flipped
     }
+
+    /// The fraction of the traffic this backend should receive while it warms up over the
+    /// `window`, ramping linearly from 0 to 1.
+    pub fn warm_up_ratio(&self, window: Duration) -> f64 {
+        let Some(since) = self.0.load().warming_since else {
+            return 1.0;
+        };
+        let elapsed = since.elapsed();
+        if elapsed >= window {
+            1.0
+        } else {
+            elapsed.as_secs_f64() / window.as_secs_f64()
+        }
+    }
 }
 
 #[cfg(test)]
@@ -323,6 +694,7 @@ mod test {
         let backend = Backend {
             addr: SocketAddr::Inet("1.1.1.1:80".parse().unwrap()),
             weight: 1,
+            metadata: Default::default(),
         };
 
         assert!(tcp_check.check(&backend).await.is_ok());
@@ -330,6 +702,7 @@ mod test {
         let backend = Backend {
             addr: SocketAddr::Inet("1.1.1.1:79".parse().unwrap()),
             weight: 1,
+            metadata: Default::default(),
         };
 
         assert!(tcp_check.check(&backend).await.is_err());
@@ -341,6 +714,7 @@ mod test {
         let backend = Backend {
             addr: SocketAddr::Inet("1.1.1.1:443".parse().unwrap()),
             weight: 1,
+            metadata: Default::default(),
         };
 
         assert!(tls_check.check(&backend).await.is_ok());
@@ -353,6 +727,7 @@ mod test {
         let backend = Backend {
             addr: SocketAddr::Inet("1.1.1.1:443".parse().unwrap()),
             weight: 1,
+            metadata: Default::default(),
         };
 
         assert!(https_check.check(&backend).await.is_ok());
@@ -375,10 +750,97 @@ mod test {
         let backend = Backend {
             addr: SocketAddr::Inet("1.1.1.1:80".parse().unwrap()),
             weight: 1,
+            metadata: Default::default(),
         };
 
         http_check.check(&backend).await.unwrap();
 
         assert!(http_check.check(&backend).await.is_ok());
     }
+
+    #[test]
+    fn test_body_matcher() {
+        let contains = BodyMatcher::Contains(b"\"ok\"".to_vec());
+        assert!(contains.is_match(br#"{"status": "ok"}"#));
+        assert!(!contains.is_match(br#"{"status": "degraded"}"#));
+        assert!(BodyMatcher::Contains(vec![]).is_match(b""));
+
+        let regex = BodyMatcher::regex(r#""status":\s*"ok""#).unwrap();
+        assert!(regex.is_match(br#"{"status":  "ok"}"#));
+        assert!(!regex.is_match(br#"{"status": "okay"}"#));
+        assert!(BodyMatcher::regex("(").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_accepted_status() {
+        let mut http_check = HttpHealthCheck::new("one.one.one.one", false);
+        let backend = Backend {
+            addr: SocketAddr::Inet("1.1.1.1:80".parse().unwrap()),
+            weight: 1,
+            metadata: Default::default(),
+        };
+        // the server redirects to https, which is not followed
+        assert!(http_check.check(&backend).await.is_err());
+
+        http_check.accepted_status = vec![200..=299, 301..=301];
+        assert!(http_check.check(&backend).await.is_ok());
+
+        http_check.body_matcher = Some(BodyMatcher::Contains(b"not in body".to_vec()));
+        assert!(http_check.check(&backend).await.is_err());
+    }
+
+    #[test]
+    fn test_grpc_check_request() {
+        assert_eq!(&encode_grpc_check_request("")[..], &[0, 0, 0, 0, 0]);
+        assert_eq!(
+            &encode_grpc_check_request("foo")[..],
+            &[0, 0, 0, 0, 5, 0x0a, 3, b'f', b'o', b'o']
+        );
+        let long = "a".repeat(200);
+        let encoded = encode_grpc_check_request(&long);
+        assert_eq!(&encoded[..8], &[0, 0, 0, 0, 203, 0x0a, 0xc8, 0x01]);
+    }
+
+    #[test]
+    fn test_grpc_check_response() {
+        // SERVING
+        assert_eq!(
+            decode_grpc_check_response(&[0, 0, 0, 0, 2, 0x08, 1]).unwrap(),
+            GRPC_SERVING
+        );
+        // NOT_SERVING
+        assert_eq!(
+            decode_grpc_check_response(&[0, 0, 0, 0, 2, 0x08, 2]).unwrap(),
+            2
+        );
+        // UNKNOWN is omitted
+        assert_eq!(decode_grpc_check_response(&[0, 0, 0, 0, 0]).unwrap(), 0);
+        // unknown length delimited field 2 is skipped
+        assert_eq!(
+            decode_grpc_check_response(&[0, 0, 0, 0, 5, 0x12, 1, b'x', 0x08, 1]).unwrap(),
+            GRPC_SERVING
+        );
+        // truncated
+        assert!(decode_grpc_check_response(&[0, 0, 0, 0, 2, 0x08]).is_err());
+        assert!(decode_grpc_check_response(&[0, 0, 0]).is_err());
+        // compressed
+        assert!(decode_grpc_check_response(&[1, 0, 0, 0, 2, 0x08, 1]).is_err());
+    }
+
+    #[test]
+    fn test_warm_up_ratio() {
+        let window = Duration::from_secs(100);
+        let health = Health::default();
+        assert_eq!(health.warm_up_ratio(window), 1.0);
+
+        let health = Health::new_warming();
+        assert!(health.warm_up_ratio(window) < 0.01);
+        assert_eq!(health.warm_up_ratio(Duration::ZERO), 1.0);
+
+        // warm up again after recovering from unhealthy
+        let health = Health::default();
+        assert!(health.observe_health(false, 1));
+        assert!(health.observe_health(true, 1));
+        assert!(health.warm_up_ratio(window) < 0.01);
+    }
 }
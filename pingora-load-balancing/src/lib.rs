@@ -16,27 +16,33 @@
 //! This crate provides common service discovery, health check and load balancing
 //! algorithms for proxies to use.
 
-use arc_swap::ArcSwap;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use futures::FutureExt;
 use pingora_core::protocols::l4::socket::SocketAddr;
-use pingora_error::{ErrorType, OrErr, Result};
+use pingora_error::{Error, ErrorType, OrErr, Result};
+use rand::Rng;
 use std::collections::hash_map::DefaultHasher;
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::hash::{Hash, Hasher};
 use std::io::Result as IoResult;
 use std::net::ToSocketAddrs;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 mod background;
 pub mod discovery;
 pub mod health_check;
+pub mod outlier;
 pub mod selection;
+pub mod sticky;
+pub mod subset;
 
 use discovery::ServiceDiscovery;
-use health_check::Health;
+use health_check::{Health, HealthEvent};
+use outlier::{Outcome, OutlierDetection};
 use selection::UniqueIterator;
-use selection::{BackendIter, BackendSelection};
+use selection::{BackendIter, BackendSelection, InFlightGuard, InFlightTracking};
+use subset::Subset;
 
 pub mod prelude {
     pub use crate::health_check::TcpHealthCheck;
@@ -52,6 +58,12 @@ pub struct Backend {
     /// The relative weight of the server. Load balancing algorithms will
     /// proportionally distributed traffic according to this value.
     pub weight: usize,
+    /// Arbitrary key/value metadata of the server, such as its availability zone.
+    ///
+    /// The metadata is part of the identity of the backend: the same address with different
+    /// metadata is a different backend. See [LoadBalancer::select_filtered()] for how to route
+    /// based on it.
+    pub metadata: BTreeMap<String, String>,
 }
 
 impl Backend {
@@ -64,10 +76,17 @@ impl Backend {
         Ok(Backend {
             addr: SocketAddr::Inet(addr),
             weight: 1,
+            metadata: BTreeMap::new(),
         })
         // TODO: UDS
     }
 
+    /// Attach the given metadata to this [Backend].
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
     pub(crate) fn hash_key(&self) -> u64 {
         let mut hasher = DefaultHasher::new();
         self.hash(&mut hasher);
@@ -97,6 +116,63 @@ impl std::net::ToSocketAddrs for Backend {
     }
 }
 
+/// What [Backends::update()] does when the service discovery returns no backend while some
+/// backends are known
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyDiscovery {
+    /// Treat it as a failed discovery: keep the last known backends and return an error. This
+    /// protects against routing to nothing because of a transient failure of the source, e.g., a
+    /// DNS server returning no record.
+    #[default]
+    Reject,
+    /// Remove all the backends
+    Accept,
+}
+
+/// The change of the set of backends made by a service discovery
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackendsChange {
+    /// The backends that are newly discovered
+    pub added: Vec<Backend>,
+    /// The backends that are no longer discovered
+    pub removed: Vec<Backend>,
+}
+
+type ChangeCallback = Box<dyn Fn(&BackendsChange) + Send + Sync>;
+type HealthCallback = Box<dyn Fn(&HealthEvent) + Send + Sync>;
+
+// Calls the health callback on a single task, so that the events are delivered in the order the
+// health checks flip the health and the callback doesn't hold up the health checks.
+struct HealthNotifier {
+    callback: Arc<HealthCallback>,
+    sender: std::sync::OnceLock<tokio::sync::mpsc::UnboundedSender<HealthEvent>>,
+}
+
+impl HealthNotifier {
+    fn new(callback: HealthCallback) -> Self {
+        HealthNotifier {
+            callback: Arc::new(callback),
+            sender: std::sync::OnceLock::new(),
+        }
+    }
+
+    fn notify(&self, event: HealthEvent) {
+        let sender = self.sender.get_or_init(|| {
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<HealthEvent>();
+            let callback = self.callback.clone();
+            // the task ends once the sender is dropped along with the Backends
+            pingora_runtime::current_handle().spawn(async move {
+                while let Some(event) = rx.recv().await {
+                    callback(&event);
+                }
+            });
+            tx
+        });
+        // only fails when the runtime of the task is gone, there is nobody to tell then
+        let _ = sender.send(event);
+    }
+}
+
 /// [Backends] is a collection of [Backend]s.
 ///
 /// It includes a service discovery method (static or dynamic) to discover all
@@ -105,6 +181,11 @@ impl std::net::ToSocketAddrs for Backend {
 pub struct Backends {
     discovery: Box<dyn ServiceDiscovery + Send + Sync + 'static>,
     health_check: Option<Arc<dyn health_check::HealthCheck + Send + Sync + 'static>>,
+    outlier_detection: Option<Box<OutlierDetection>>,
+    slow_start: Option<Duration>,
+    empty_discovery: EmptyDiscovery,
+    change_callback: Option<ChangeCallback>,
+    health_callback: Option<Arc<HealthNotifier>>,
     backends: ArcSwap<BTreeSet<Backend>>,
     health: ArcSwap<HashMap<u64, Health>>,
 }
@@ -117,6 +198,11 @@ impl Backends {
         Self {
             discovery,
             health_check: None,
+            outlier_detection: None,
+            slow_start: None,
+            empty_discovery: EmptyDiscovery::Reject,
+            change_callback: None,
+            health_callback: None,
             backends: Default::default(),
             health: Default::default(),
         }
@@ -130,15 +216,67 @@ impl Backends {
         self.health_check = Some(hc.into())
     }
 
+    /// Set the passive health check method. See [outlier] for more details.
+    pub fn set_outlier_detection(&mut self, od: Box<OutlierDetection>) {
+        self.outlier_detection = Some(od)
+    }
+
+    /// Set the slow start window. `None`, the default, disables slow start.
+    ///
+    /// During the window after a backend becomes healthy or is newly discovered, the share of
+    /// traffic it receives ramps up linearly from zero to its full weight. The backends from the
+    /// first discovery don't warm up.
+    ///
+    /// The weighted selections, see [BackendSelection::RAMPS_WEIGHTS], are rebuilt with the
+    /// ramped weights as the ramp moves on. The other selections skip the warming backends at
+    /// random instead, see [Self::warmed_up()].
+    pub fn set_slow_start(&mut self, window: Option<Duration>) {
+        self.slow_start = window;
+    }
+
+    /// Set what to do when the service discovery returns no backend. See [EmptyDiscovery] for the
+    /// default.
+    pub fn set_empty_discovery(&mut self, empty_discovery: EmptyDiscovery) {
+        self.empty_discovery = empty_discovery;
+    }
+
+    /// Set the function to call whenever the service discovery adds or removes backends.
+    ///
+    /// It is called during [Self::update()], after the new backends are in place.
+    pub fn set_change_callback(&mut self, callback: Box<dyn Fn(&BackendsChange) + Send + Sync>) {
+        self.change_callback = Some(callback);
+    }
+
+    /// Set the function to call whenever the health check flips the health of a backend.
+    ///
+    /// It is called on a single background task, so that it doesn't hold up the health checks,
+    /// with the events in the order the health checks flip the health.
+    pub fn set_health_callback(&mut self, callback: Box<dyn Fn(&HealthEvent) + Send + Sync>) {
+        self.health_callback = Some(Arc::new(HealthNotifier::new(callback)));
+    }
+
     /// Return true when the new is different from the current set of backends
     fn do_update(&self, new_backends: BTreeSet<Backend>, enablement: HashMap<u64, bool>) -> bool {
         if (**self.backends.load()) != new_backends {
+            let change = self.change_callback.as_ref().map(|_| {
+                let old_backends = self.backends.load();
+                BackendsChange {
+                    added: new_backends.difference(&old_backends).cloned().collect(),
+                    removed: old_backends.difference(&new_backends).cloned().collect(),
+                }
+            });
             let old_health = self.health.load();
+            // backends from the very first discovery don't need to warm up
+            let initial = self.backends.load().is_empty();
             let mut health = HashMap::with_capacity(new_backends.len());
             for backend in new_backends.iter() {
                 let hash_key = backend.hash_key();
                 // use the default health if the backend is new
-                let backend_health = old_health.get(&hash_key).cloned().unwrap_or_default();
+                let backend_health = match old_health.get(&hash_key) {
+                    Some(h) => h.clone(),
+                    None if !initial => Health::new_warming(),
+                    None => Health::default(),
+                };
 
                 // override enablement
                 if let Some(backend_enabled) = enablement.get(&hash_key) {
@@ -147,9 +285,16 @@ impl Backends {
                 health.insert(hash_key, backend_health);
             }
 
+            if let Some(od) = self.outlier_detection.as_ref() {
+                od.retain(|hash_key| health.contains_key(&hash_key));
+            }
+
             // TODO: put backend and health under 1 ArcSwap so that this update is atomic
             self.backends.store(Arc::new(new_backends));
             self.health.store(Arc::new(health));
+            if let (Some(callback), Some(change)) = (self.change_callback.as_ref(), change) {
+                callback(&change);
+            }
             true
         } else {
             // no backend change, just check enablement
@@ -170,13 +315,80 @@ impl Backends {
     /// This function returns true when the health check is unset but the backend is enabled.
     /// When the health check is set, this function will return false for the `backend` it
     /// doesn't know.
+    ///
+    /// When the outlier detection is set, this function returns false for ejected backends. The
+    /// slow start after their readmission is up to [Self::warmed_up()].
     pub fn ready(&self, backend: &Backend) -> bool {
-        self.health
+        let hash_key = backend.hash_key();
+        let healthy = self
+            .health
             .load()
-            .get(&backend.hash_key())
+            .get(&hash_key)
             // Racing: return `None` when this function is called between the
             // backend store and the health store
-            .map_or(self.health_check.is_none(), |h| h.ready())
+            .map_or(self.health_check.is_none(), |h| h.ready());
+        healthy
+            && !self
+                .outlier_detection
+                .as_ref()
+                .is_some_and(|od| od.is_ejected(hash_key))
+    }
+
+    /// Whether the given [Backend] should take a request according to its slow start ramp.
+    ///
+    /// This function randomly returns false for backends that are still warming up, with a
+    /// probability that decreases linearly over the slow start window. The backends readmitted by
+    /// the outlier detection ramp up the same way over its `slow_start`. Both ramps are decided by
+    /// a single draw, so call this once per selected backend.
+    pub fn warmed_up(&self, backend: &Backend) -> bool {
+        let ratio = self
+            .warm_up_ratio(backend)
+            .min(self.readmission_ratio(backend));
+        ratio >= 1.0 || (ratio > 0.0 && rand::thread_rng().gen_bool(ratio))
+    }
+
+    // Same as warmed_up() but only for the outlier detection, for the selections that apply the
+    // slow start to the weights instead
+    fn readmitted(&self, backend: &Backend) -> bool {
+        let ratio = self.readmission_ratio(backend);
+        ratio >= 1.0 || (ratio > 0.0 && rand::thread_rng().gen_bool(ratio))
+    }
+
+    // the share of the traffic of the backend according to its slow start ramp
+    fn warm_up_ratio(&self, backend: &Backend) -> f64 {
+        self.slow_start.map_or(1.0, |window| {
+            self.health
+                .load()
+                .get(&backend.hash_key())
+                .map_or(1.0, |h| h.warm_up_ratio(window))
+        })
+    }
+
+    // the share of the traffic of the backend according to its outlier detection readmission
+    fn readmission_ratio(&self, backend: &Backend) -> f64 {
+        self.outlier_detection
+            .as_ref()
+            .map_or(1.0, |od| od.ramp(backend.hash_key()))
+    }
+
+    /// Report the outcome of a request to the given [Backend] to the outlier detection.
+    ///
+    /// This method is noop when the outlier detection is not set.
+    pub fn report_outcome(&self, backend: &Backend, outcome: Outcome) {
+        let Some(od) = self.outlier_detection.as_ref() else {
+            return;
+        };
+        let total = self.backends.load().len();
+        if od.observe(backend.hash_key(), outcome, total) {
+            log::warn!("{backend:?} is ejected after {outcome:?}");
+        }
+    }
+
+    /// Whether the given [Backend] is currently ejected by the outlier detection
+    pub fn ejected(&self, backend: &Backend) -> bool {
+        self.outlier_detection
+            .as_ref()
+            .is_some_and(|od| od.is_ejected(backend.hash_key()))
     }
 
     /// Manually set if a [Backend] is ready to serve traffic.
@@ -202,8 +414,20 @@ impl Backends {
     /// Return `true` when the new collection is different from the current set of backends.
     /// This return value is useful to tell the caller when to rebuild things that are expensive to
     /// update, such as consistent hashing rings.
+    ///
+    /// The current backends are kept if the discovery fails, or if it returns no backend and the
+    /// empty discovery is rejected, see [Self::set_empty_discovery()].
     pub async fn update(&self) -> Result<bool> {
         let (new_backends, enablement) = self.discovery.discover().await?;
+        if new_backends.is_empty()
+            && self.empty_discovery == EmptyDiscovery::Reject
+            && !self.backends.load().is_empty()
+        {
+            return Error::e_explain(
+                ErrorType::InternalError,
+                "service discovery returned no backend, keeping the last known ones",
+            );
+        }
         Ok(self.do_update(new_backends, enablement))
     }
 
@@ -211,6 +435,13 @@ impl Backends {
     ///
     /// When `parallel: true`, all the backends are checked in parallel instead of sequentially
     pub async fn run_health_check(&self, parallel: bool) {
+        self.run_health_check_of(&self.backends.load(), parallel)
+            .await
+    }
+
+    /// Same as [Self::run_health_check()] but only on the given backends, e.g., the ones a
+    /// [LoadBalancer] with a [Subset] may select from. The others keep their last health.
+    pub async fn run_health_check_of(&self, backends: &BTreeSet<Backend>, parallel: bool) {
         use crate::health_check::HealthCheck;
         use log::{info, warn};
         use pingora_runtime::current_handle;
@@ -219,17 +450,26 @@ impl Backends {
             backend: &Backend,
             check: &Arc<dyn HealthCheck + Send + Sync>,
             health_table: &HashMap<u64, Health>,
+            notifier: Option<&HealthNotifier>,
         ) {
             let errored = check.check(backend).await.err();
             if let Some(h) = health_table.get(&backend.hash_key()) {
-                let flipped =
-                    h.observe_health(errored.is_none(), check.health_threshold(errored.is_none()));
+                let healthy = errored.is_none();
+                let flipped = h.observe_health(healthy, check.health_threshold(healthy));
                 if flipped {
-                    if let Some(e) = errored {
+                    if let Some(e) = errored.as_ref() {
                         warn!("{backend:?} becomes unhealthy, {e}");
                     } else {
                         info!("{backend:?} becomes healthy");
                     }
+                    if let Some(notifier) = notifier {
+                        notifier.notify(HealthEvent {
+                            backend: backend.clone(),
+                            from: (!healthy).into(),
+                            to: healthy.into(),
+                            error: errored,
+                        });
+                    }
                 }
             }
         }
@@ -238,7 +478,6 @@ impl Backends {
             return;
         };
 
-        let backends = self.backends.load();
         if parallel {
             let health_table = self.health.load_full();
             let runtime = current_handle();
@@ -246,15 +485,22 @@ impl Backends {
                 let backend = backend.clone();
                 let check = health_check.clone();
                 let ht = health_table.clone();
+                let notifier = self.health_callback.clone();
                 runtime.spawn(async move {
-                    check_and_report(&backend, &check, &ht).await;
+                    check_and_report(&backend, &check, &ht, notifier.as_deref()).await;
                 })
             });
 
             futures::future::join_all(jobs).await;
         } else {
             for backend in backends.iter() {
-                check_and_report(backend, health_check, &self.health.load()).await;
+                check_and_report(
+                    backend,
+                    health_check,
+                    &self.health.load(),
+                    self.health_callback.as_deref(),
+                )
+                .await;
             }
         }
     }
@@ -265,9 +511,14 @@ impl Backends {
 ///
 /// In order to run service discovery and health check at the designated frequencies, the [LoadBalancer]
 /// needs to be run as a [pingora_core::services::background::BackgroundService].
-pub struct LoadBalancer<S> {
+pub struct LoadBalancer<S: BackendSelection> {
     backends: Backends,
+    // of the subset of the backends when the subset is set, otherwise of all the backends
     selector: ArcSwap<S>,
+    // of all the backends, only when the subset is set
+    fallback: ArcSwapOption<S>,
+    subset: Option<Subset>,
+    selection_config: S::Config,
     /// How frequent the health check logic (if set) should run.
     ///
     /// If `None`, the health check logic will only run once at the beginning.
@@ -278,8 +529,21 @@ pub struct LoadBalancer<S> {
     pub update_frequency: Option<Duration>,
     /// Whether to run health check to all backends in parallel. Default is false.
     pub parallel_health_check: bool,
+    // when the selection was last rebuilt with the slow start ramp, while some backends warm up
+    ramp_rebuilt: ArcSwapOption<Instant>,
+    rebuild_lock: Mutex<()>,
+    // the backends of `selector`
+    selector_backends: ArcSwap<BTreeSet<Backend>>,
+    // the selections of the candidates of select_filtered(), by the hash of the candidates
+    filtered: Mutex<HashMap<u64, Arc<S>>>,
 }
 
+// How many selections of the filtered candidates to keep, see LoadBalancer::select_filtered()
+const MAX_FILTERED_SELECTIONS: usize = 16;
+
+// How often the selection is rebuilt as the slow start ramp moves on
+const RAMP_REBUILD_INTERVAL: Duration = Duration::from_secs(1);
+
 impl<'a, S: BackendSelection> LoadBalancer<S>
 where
     S: BackendSelection + 'static,
@@ -305,13 +569,21 @@ where
 
     /// Build a [LoadBalancer] with the given [Backends].
     pub fn from_backends(backends: Backends) -> Self {
-        let selector = ArcSwap::new(Arc::new(S::build(&backends.get_backend())));
+        let selector_backends = ArcSwap::new(backends.get_backend());
+        let selector = ArcSwap::new(Arc::new(S::build(&selector_backends.load())));
         LoadBalancer {
             backends,
             selector,
+            fallback: ArcSwapOption::empty(),
+            subset: None,
+            selection_config: S::Config::default(),
             health_check_frequency: None,
             update_frequency: None,
             parallel_health_check: false,
+            ramp_rebuilt: ArcSwapOption::empty(),
+            rebuild_lock: Mutex::new(()),
+            selector_backends,
+            filtered: Mutex::new(HashMap::new()),
         }
     }
 
@@ -321,12 +593,140 @@ where
     /// is running as a background service.
     pub async fn update(&self) -> Result<()> {
         if self.backends.update().await? {
-            self.selector
-                .store(Arc::new(S::build(&self.backends.get_backend())))
+            self.rebuild();
         }
         Ok(())
     }
 
+    // rebuild the selection algorithms from the current backends
+    fn rebuild(&self) {
+        let _lock = self.rebuild_lock.lock().unwrap();
+        self.rebuild_locked();
+    }
+
+    fn rebuild_locked(&self) {
+        let backends = self.backends.get_backend();
+        let ramping = S::RAMPS_WEIGHTS
+            && self.backends.slow_start.is_some()
+            && backends
+                .iter()
+                .any(|b| self.backends.warm_up_ratio(b) < 1.0);
+        let build = |backends: &BTreeSet<Backend>| self.build_selection(backends, ramping);
+        self.ramp_rebuilt
+            .store(ramping.then(|| Arc::new(Instant::now())));
+        match self.subset.as_ref() {
+            Some(subset) => {
+                let selected = subset.select(&backends);
+                self.selector.store(build(&selected));
+                self.selector_backends.store(Arc::new(selected));
+                let fallback = subset.select_fallback(&backends);
+                self.fallback
+                    .store((!fallback.is_empty()).then(|| build(&fallback)));
+            }
+            None => {
+                self.selector.store(build(&backends));
+                self.selector_backends.store(backends);
+                self.fallback.store(None);
+            }
+        }
+        self.filtered.lock().unwrap().clear();
+    }
+
+    fn build_selection(&self, backends: &BTreeSet<Backend>, ramping: bool) -> Arc<S> {
+        Arc::new(if ramping {
+            S::build_with_ramp(backends, &self.selection_config, &|b| {
+                self.backends.warm_up_ratio(b)
+            })
+        } else {
+            S::build_with_config(backends, &self.selection_config)
+        })
+    }
+
+    // the selection of the given candidates, built once and then reused until the next rebuild
+    fn filtered_selection(&self, candidates: &BTreeSet<Backend>) -> Arc<S> {
+        let mut hasher = DefaultHasher::new();
+        candidates.hash(&mut hasher);
+        let key = hasher.finish();
+
+        let mut filtered = self.filtered.lock().unwrap();
+        if let Some(selection) = filtered.get(&key) {
+            return selection.clone();
+        }
+        if filtered.len() >= MAX_FILTERED_SELECTIONS {
+            filtered.clear();
+        }
+        let ramping = self.ramp_rebuilt.load().is_some();
+        let selection = self.build_selection(candidates, ramping);
+        filtered.insert(key, selection.clone());
+        selection
+    }
+
+    /// Set the configuration of the selection algorithm, e.g., the
+    /// [selection::maglev::MaglevConfig] of [selection::Maglev]. The default is
+    /// `S::Config::default()`.
+    ///
+    /// The selection is rebuilt right away with the new configuration.
+    pub fn set_selection_config(&mut self, config: S::Config) {
+        self.selection_config = config;
+        self.rebuild();
+    }
+
+    /// Only select from a deterministic subset of the backends, see [Subset]. `None`, the
+    /// default, selects from all the backends.
+    ///
+    /// The subset is computed right away and then recomputed whenever the service discovery
+    /// changes the backends. When no backend of the subset is accepted, the selection falls back
+    /// to the fallback tier of the [Subset], and fails if none of it is accepted either. Note
+    /// that not only the unhealthy backends are not accepted: the ones rejected by the `accept`
+    /// function of [Self::select_with()] also trigger the fallback.
+    ///
+    /// The health check only runs on the subset and the fallback tier, since the other backends
+    /// are never selected.
+    pub fn set_subset(&mut self, subset: Option<Subset>) {
+        self.subset = subset;
+        self.rebuild();
+    }
+
+    /// The backends to run the health check on: the subset and the fallback tier if
+    /// [Self::set_subset()] is set, otherwise all of them.
+    pub fn health_check_backends(&self) -> BTreeSet<Backend> {
+        let backends = self.backends.get_backend();
+        match self.subset.as_ref() {
+            Some(subset) => subset.select_all_tiers(&backends),
+            None => backends.as_ref().clone(),
+        }
+    }
+
+    /// Run the health check, if it is set, on the [Self::health_check_backends()].
+    ///
+    /// This function will be called every `health_check_frequency` if this [LoadBalancer]
+    /// instance is running as a background service.
+    pub async fn run_health_check(&self) {
+        if self.subset.is_none() {
+            self.backends
+                .run_health_check(self.parallel_health_check)
+                .await;
+        } else {
+            self.backends
+                .run_health_check_of(&self.health_check_backends(), self.parallel_health_check)
+                .await;
+        }
+        if S::RAMPS_WEIGHTS && self.backends.slow_start.is_some() {
+            // the backends that just became healthy start to warm up
+            self.rebuild();
+        }
+    }
+
+    /// The backends this [LoadBalancer] selects from when they are healthy: the subset of the
+    /// backends if [Self::set_subset()] is set, otherwise all of them.
+    pub fn subset_backends(&self) -> BTreeSet<Backend> {
+        let backends = self.backends.get_backend();
+        match self.subset.as_ref() {
+            Some(subset) => subset.select(&backends),
+            None => backends.as_ref().clone(),
+        }
+    }
+
     /// Return the first healthy [Backend] according to the selection algorithm and the
     /// health check results.
     ///
@@ -348,18 +748,105 @@ where
     /// backend. The function can do things like ignoring the internal health checks or skipping this backend
     /// because it failed before. The `accept` function is called multiple times iterating over backends
     /// until it returns `true`.
+    ///
+    /// With a [Subset], the fallback tier is tried when no backend of the subset is accepted,
+    /// whether it is for their health or for the `accept` function, see [Self::set_subset()].
     pub fn select_with<F>(&self, key: &[u8], max_iterations: usize, accept: F) -> Option<Backend>
     where
         F: Fn(&Backend, bool) -> bool,
     {
-        let selection = self.selector.load();
+        self.advance_ramp();
+        self.select_from(&self.selector.load(), key, max_iterations, &accept)
+            .or_else(|| {
+                let fallback = self.fallback.load();
+                self.select_from(fallback.as_ref()?, key, max_iterations, &accept)
+            })
+    }
+
+    /// Similar to [Self::select], but prefer the [Backend]s that pass the given `filter`.
+    ///
+    /// The `filter` narrows down the candidates based on the context of the request, usually by
+    /// comparing the [Backend::metadata] to it. For example, to prefer the backends in the same
+    /// availability zone as the client:
+    ///
+    /// ```ignore
+    /// lb.select_filtered(key, 256, |b| b.metadata.get("zone") == Some(&client_zone))
+    /// ```
+    ///
+    /// The selection algorithm runs on the backends that pass the filter, so `max_iterations`
+    /// only counts them. The selection of each distinct set of them is built on first use and
+    /// kept until the backends change. With a [Subset], only the backends of the subset are
+    /// filtered.
+    ///
+    /// If no healthy backend passes the filter, the selection falls back to all the backends.
+    pub fn select_filtered<P>(
+        &self,
+        key: &[u8],
+        max_iterations: usize,
+        filter: P,
+    ) -> Option<Backend>
+    where
+        P: Fn(&Backend) -> bool,
+    {
+        self.advance_ramp();
+        let candidates: BTreeSet<Backend> = self
+            .selector_backends
+            .load()
+            .iter()
+            .filter(|b| filter(b))
+            .cloned()
+            .collect();
+        if !candidates.is_empty() {
+            let selection = self.filtered_selection(&candidates);
+            let selected = self.select_from(&selection, key, max_iterations, |_, health| health);
+            if selected.is_some() {
+                return selected;
+            }
+        }
+        self.select(key, max_iterations)
+    }
+
+    // rebuild the selection with the current slow start ramp if it is due
+    fn advance_ramp(&self) {
+        let due = self
+            .ramp_rebuilt
+            .load()
+            .as_ref()
+            .is_some_and(|t| t.elapsed() >= RAMP_REBUILD_INTERVAL);
+        // skip when another rebuild is already in progress
+        if let (true, Ok(_lock)) = (due, self.rebuild_lock.try_lock()) {
+            self.rebuild_locked();
+        }
+    }
+
+    fn select_from<F>(
+        &self,
+        selection: &Arc<S>,
+        key: &[u8],
+        max_iterations: usize,
+        accept: F,
+    ) -> Option<Backend>
+    where
+        F: Fn(&Backend, bool) -> bool,
+    {
         let mut iter = UniqueIterator::new(selection.iter(key), max_iterations);
+        // a backend that is warming up, used only when no warm backend is accepted
+        let mut warming = None;
         while let Some(b) = iter.get_next() {
             if accept(&b, self.backends.ready(&b)) {
-                return Some(b);
+                let warm = if S::RAMPS_WEIGHTS {
+                    // the slow start is already in the weights of the selection
+                    self.backends.readmitted(&b)
+                } else {
+                    self.backends.warmed_up(&b)
+                };
+                if warm {
+                    return Some(b);
+                }
+                warming.get_or_insert(b);
             }
         }
-        None
+        warming
     }
 
     /// Set the health check method. See [health_check].
@@ -370,10 +857,141 @@ where
         self.backends.set_health_check(hc);
     }
 
+    /// Set the passive health check method. See [outlier].
+    pub fn set_outlier_detection(&mut self, od: Box<OutlierDetection>) {
+        self.backends.set_outlier_detection(od);
+    }
+
+    /// Report the outcome of a request to the given [Backend], for the outlier detection.
+    ///
+    /// The proxy should call this once for each request sent to a backend selected by this
+    /// [LoadBalancer]. It is noop if the outlier detection is not set.
+    pub fn report_outcome(&self, backend: &Backend, outcome: Outcome) {
+        self.backends.report_outcome(backend, outcome);
+    }
+
+    /// Set the slow start window of the backends. See [Backends::set_slow_start].
+    pub fn set_slow_start(&mut self, window: Option<Duration>) {
+        self.backends.set_slow_start(window);
+        self.rebuild();
+    }
+
+    /// Set what to do when the service discovery returns no backend. See
+    /// [Backends::set_empty_discovery].
+    pub fn set_empty_discovery(&mut self, empty_discovery: EmptyDiscovery) {
+        self.backends.set_empty_discovery(empty_discovery);
+    }
+
+    /// Set the function to call when the health of a backend flips. See
+    /// [Backends::set_health_callback].
+    pub fn set_health_callback(&mut self, callback: Box<dyn Fn(&HealthEvent) + Send + Sync>) {
+        self.backends.set_health_callback(callback);
+    }
+
+    /// Set the function to call when the backends change. See [Backends::set_change_callback].
+    pub fn set_change_callback(&mut self, callback: Box<dyn Fn(&BackendsChange) + Send + Sync>) {
+        self.backends.set_change_callback(callback);
+    }
+
     /// Access the [Backends] of this [LoadBalancer]
     pub fn backends(&self) -> &Backends {
         &self.backends
     }
+
+    /// Warm up all the healthy backends to select from, see [Self::subset_backends()], e.g., by
+    /// pooling connections to them before the service takes traffic.
+    ///
+    /// `warm` is called for each backend concurrently. It usually builds the peer of the backend
+    /// the same way the proxy does and pools connections to it with `HttpProxy::warm_up()`:
+    ///
+    /// ```ignore
+    /// lb.warm_up(|b| {
+    ///     let peer = HttpPeer::new(b, false, String::new());
+    ///     let proxy = proxy.clone();
+    ///     async move { proxy.warm_up(&peer, 8).await }
+    /// })
+    /// .await;
+    /// ```
+    ///
+    /// Return the number of the backends warmed up without error, the errors are logged.
+    pub async fn warm_up<F, Fut>(&self, warm: F) -> usize
+    where
+        F: Fn(&Backend) -> Fut,
+        Fut: std::future::Future<Output = Result<usize>>,
+    {
+        let backends = self.subset_backends();
+        let jobs = backends
+            .iter()
+            .filter(|b| self.backends.ready(b))
+            .map(|b| warm(b).map(move |r| (b, r)));
+        let mut warmed = 0;
+        for (backend, result) in futures::future::join_all(jobs).await {
+            match result {
+                Ok(n) => {
+                    log::debug!("{n} connections pooled to {backend:?}");
+                    warmed += 1;
+                }
+                Err(e) => log::warn!("fail to warm up {backend:?}, {e}"),
+            }
+        }
+        warmed
+    }
+}
+
+impl<S> LoadBalancer<S>
+where
+    S: BackendSelection + InFlightTracking + 'static,
+    S::Iter: BackendIter,
+{
+    /// Similar to [Self::select], but also count the selected [Backend] as having one more
+    /// in-flight request.
+    ///
+    /// The request is counted until the returned [InFlightGuard] is dropped, so it should be held
+    /// for as long as the request to the backend is ongoing.
+    pub fn select_tracked(
+        &self,
+        key: &[u8],
+        max_iterations: usize,
+    ) -> Option<(Backend, InFlightGuard)> {
+        self.select_tracked_with(key, max_iterations, |_, health| health)
+    }
+
+    /// Similar to [Self::select_with], but also count the selected [Backend] as having one more
+    /// in-flight request. See [Self::select_tracked].
+    pub fn select_tracked_with<F>(
+        &self,
+        key: &[u8],
+        max_iterations: usize,
+        accept: F,
+    ) -> Option<(Backend, InFlightGuard)>
+    where
+        F: Fn(&Backend, bool) -> bool,
+    {
+        let mut selection = self.selector.load_full();
+        let backend = match self.select_from(&selection, key, max_iterations, &accept) {
+            Some(backend) => backend,
+            None => {
+                selection = self.fallback.load_full()?;
+                self.select_from(&selection, key, max_iterations, &accept)?
+            }
+        };
+        let guard = selection.in_flight().acquire(&backend)?;
+        Some((backend, guard))
+    }
+
+    /// The current number of in-flight requests to each [Backend]
+    pub fn in_flight(&self) -> Vec<(Backend, usize)> {
+        let selection = self.selector.load();
+        let fallback = self.fallback.load();
+        self.backends
+            .get_backend()
+            .iter()
+            .map(|b| {
+                let fallback = fallback.as_ref().map_or(0, |s| s.in_flight().get(b));
+                (b.clone(), selection.in_flight().get(b) + fallback)
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -484,4 +1102,358 @@ mod test {
         assert!(backends.ready(&good2));
         assert!(!backends.ready(&bad));
     }
+
+    #[tokio::test]
+    async fn test_health_callback() {
+        use health_check::{HealthCheck, HealthState};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        struct FlagCheck(Arc<AtomicBool>);
+        #[async_trait]
+        impl HealthCheck for FlagCheck {
+            async fn check(&self, _target: &Backend) -> Result<()> {
+                if self.0.load(Ordering::Relaxed) {
+                    Ok(())
+                } else {
+                    Error::e_explain(ErrorType::ConnectRefused, "test")
+                }
+            }
+            fn health_threshold(&self, _success: bool) -> usize {
+                1
+            }
+        }
+
+        let up = Arc::new(AtomicBool::new(true));
+        let mut lb: LoadBalancer<selection::RoundRobin> =
+            LoadBalancer::try_from_iter(["1.1.1.1:80"]).unwrap();
+        lb.set_health_check(Box::new(FlagCheck(up.clone())));
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        lb.set_health_callback(Box::new(move |event: &HealthEvent| {
+            let error = event.error.as_ref().map(|e| e.etype().clone());
+            tx.send((event.backend.clone(), event.from, event.to, error))
+                .unwrap();
+        }));
+        let backend = Backend::new("1.1.1.1:80").unwrap();
+
+        for parallel in [false, true] {
+            // no flip, no event
+            lb.backends().run_health_check(parallel).await;
+            up.store(false, Ordering::Relaxed);
+            lb.backends().run_health_check(parallel).await;
+            let event = rx.recv().await.unwrap();
+            assert_eq!(
+                event,
+                (
+                    backend.clone(),
+                    HealthState::Healthy,
+                    HealthState::Unhealthy,
+                    Some(ErrorType::ConnectRefused)
+                )
+            );
+            up.store(true, Ordering::Relaxed);
+            lb.backends().run_health_check(parallel).await;
+            let event = rx.recv().await.unwrap();
+            assert_eq!(event.2, HealthState::Healthy);
+            assert!(event.3.is_none());
+            assert!(rx.try_recv().is_err());
+        }
+
+        // the events of many flips in a row arrive in order
+        for i in 0..50 {
+            up.store(i % 2 == 1, Ordering::Relaxed);
+            lb.backends().run_health_check(true).await;
+        }
+        for i in 0..50 {
+            let event = rx.recv().await.unwrap();
+            let expected = if i % 2 == 1 {
+                HealthState::Healthy
+            } else {
+                HealthState::Unhealthy
+            };
+            assert_eq!(event.2, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outlier_detection() {
+        let mut lb: LoadBalancer<selection::RoundRobin> =
+            LoadBalancer::try_from_iter(["1.1.1.1:80", "1.0.0.1:80"]).unwrap();
+        let mut od = OutlierDetection::new();
+        od.min_requests = 2;
+        lb.set_outlier_detection(od);
+
+        let bad = Backend::new("1.1.1.1:80").unwrap();
+        let good = Backend::new("1.0.0.1:80").unwrap();
+        lb.report_outcome(&bad, Outcome::ConnectError);
+        lb.report_outcome(&good, Outcome::Success);
+        assert!(!lb.backends().ejected(&bad));
+        lb.report_outcome(&bad, Outcome::from_status(502));
+        assert!(lb.backends().ejected(&bad));
+        assert!(!lb.backends().ready(&bad));
+        assert!(lb.backends().ready(&good));
+
+        for _ in 0..10 {
+            assert_eq!(lb.select(b"", 10).unwrap(), good);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_start() {
+        let discovery = discovery::Static::default();
+        let old = Backend::new("1.1.1.1:80").unwrap();
+        discovery.add(old.clone());
+        let discovery = Arc::new(discovery);
+
+        struct SharedDiscovery(Arc<discovery::Static>);
+        #[async_trait]
+        impl ServiceDiscovery for SharedDiscovery {
+            async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+                self.0.discover().await
+            }
+        }
+
+        let mut backends = Backends::new(Box::new(SharedDiscovery(discovery.clone())));
+        backends.set_slow_start(Some(Duration::from_secs(100)));
+        let mut lb: LoadBalancer<selection::RoundRobin> = LoadBalancer::from_backends(backends);
+        lb.update().await.unwrap();
+        // the initial backends are warm
+        assert!(lb.backends().warmed_up(&old));
+
+        let new = Backend::new("1.0.0.1:80").unwrap();
+        discovery.add(new.clone());
+        lb.update().await.unwrap();
+        for _ in 0..20 {
+            assert_eq!(lb.select(b"", 10).unwrap(), old);
+        }
+        // still selected when it is the only choice
+        let only_new = lb.select_with(b"", 10, |b, _| *b == new);
+        assert_eq!(only_new.unwrap(), new);
+
+        lb.set_slow_start(None);
+        assert!(lb.backends().warmed_up(&new));
+        // the selection is rebuilt with the full weights
+        let selected: BTreeSet<_> = (0..4).map(|_| lb.select(b"", 10).unwrap()).collect();
+        assert!(selected.contains(&new));
+    }
+
+    #[tokio::test]
+    async fn test_discovery_change() {
+        struct SharedDiscovery(Arc<discovery::Static>);
+        #[async_trait]
+        impl ServiceDiscovery for SharedDiscovery {
+            async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+                self.0.discover().await
+            }
+        }
+
+        let discovery = Arc::new(discovery::Static::default());
+        let b1 = Backend::new("1.1.1.1:80").unwrap();
+        let b2 = Backend::new("1.0.0.1:80").unwrap();
+        discovery.add(b1.clone());
+        let changes = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut backends = Backends::new(Box::new(SharedDiscovery(discovery.clone())));
+        let recorder = changes.clone();
+        backends.set_change_callback(Box::new(move |change: &BackendsChange| {
+            recorder.lock().unwrap().push(change.clone())
+        }));
+
+        assert!(backends.update().await.unwrap());
+        // no change, no event
+        assert!(!backends.update().await.unwrap());
+        discovery.add(b2.clone());
+        discovery.remove(&b1);
+        assert!(backends.update().await.unwrap());
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![
+                BackendsChange {
+                    added: vec![b1.clone()],
+                    removed: vec![],
+                },
+                BackendsChange {
+                    added: vec![b2.clone()],
+                    removed: vec![b1.clone()],
+                },
+            ]
+        );
+
+        // the last known backends are kept
+        discovery.remove(&b2);
+        assert!(backends.update().await.is_err());
+        assert!(backends.get_backend().contains(&b2));
+        assert_eq!(changes.lock().unwrap().len(), 2);
+
+        backends.set_empty_discovery(EmptyDiscovery::Accept);
+        assert!(backends.update().await.unwrap());
+        assert!(backends.get_backend().is_empty());
+        assert_eq!(changes.lock().unwrap()[2].removed, vec![b2]);
+    }
+
+    #[tokio::test]
+    async fn test_select_filtered() {
+        let discovery = discovery::Static::default();
+        let a1 = Backend::new("1.1.1.1:80")
+            .unwrap()
+            .with_metadata("zone", "a");
+        let a2 = Backend::new("1.1.1.2:80")
+            .unwrap()
+            .with_metadata("zone", "a");
+        let b1 = Backend::new("1.0.0.1:80")
+            .unwrap()
+            .with_metadata("zone", "b");
+        discovery.add(a1.clone());
+        discovery.add(a2.clone());
+        discovery.add(b1.clone());
+        let lb: LoadBalancer<selection::RoundRobin> =
+            LoadBalancer::from_backends(Backends::new(Box::new(discovery)));
+        lb.update().await.unwrap();
+
+        let zone = |z: &'static str| {
+            move |b: &Backend| b.metadata.get("zone").map(|v| v.as_str()) == Some(z)
+        };
+        for _ in 0..10 {
+            assert_eq!(lb.select_filtered(b"", 10, zone("b")).unwrap(), b1);
+            assert_ne!(lb.select_filtered(b"", 10, zone("a")).unwrap(), b1);
+        }
+
+        // no healthy backend in the zone: fall back to the others
+        lb.backends().set_enable(&b1, false);
+        assert_ne!(lb.select_filtered(b"", 10, zone("b")).unwrap(), b1);
+        // no backend in the zone at all
+        assert!(lb.select_filtered(b"", 10, zone("c")).is_some());
+
+        // the preferred backends are found even when they are few among many
+        let discovery = discovery::Static::default();
+        for i in 1..=50 {
+            discovery.add(
+                Backend::new(&format!("10.0.0.{i}:80"))
+                    .unwrap()
+                    .with_metadata("zone", "a"),
+            );
+        }
+        discovery.add(b1.clone());
+        let lb: LoadBalancer<selection::Random> =
+            LoadBalancer::from_backends(Backends::new(Box::new(discovery)));
+        lb.update().await.unwrap();
+        for _ in 0..10 {
+            assert_eq!(lb.select_filtered(b"", 1, zone("b")).unwrap(), b1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subset() {
+        let addrs: Vec<_> = (1..=20).map(|i| format!("10.0.0.{i}:80")).collect();
+        let mut lb: LoadBalancer<selection::RoundRobin> =
+            LoadBalancer::try_from_iter(&addrs).unwrap();
+        lb.set_subset(Some(Subset::new("proxy-1", 3)));
+        let subset = lb.subset_backends();
+        assert_eq!(subset.len(), 3);
+
+        for _ in 0..30 {
+            assert!(subset.contains(&lb.select(b"", 10).unwrap()));
+        }
+
+        // the whole subset is down: fall back to the next tier
+        for b in subset.iter() {
+            lb.backends().set_enable(b, false);
+        }
+        let tiers = lb.health_check_backends();
+        assert_eq!(tiers.len(), 6);
+        assert!(tiers.is_superset(&subset));
+        for _ in 0..30 {
+            let selected = lb.select(b"", 10).unwrap();
+            assert!(!subset.contains(&selected));
+            assert!(tiers.contains(&selected));
+        }
+        // the rejections of the accept function fall back too
+        let selected = lb.select_with(b"", 10, |b, _| !subset.contains(b)).unwrap();
+        assert!(tiers.contains(&selected));
+
+        // the next tier is down too: the other backends are never selected
+        for b in tiers.iter() {
+            lb.backends().set_enable(b, false);
+        }
+        assert!(lb.select(b"", 10).is_none());
+
+        lb.set_subset(None);
+        assert_eq!(lb.subset_backends().len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_subset_health_check() {
+        use crate::health_check::HealthCheck;
+
+        struct RecordCheck(Arc<std::sync::Mutex<BTreeSet<Backend>>>);
+        #[async_trait]
+        impl HealthCheck for RecordCheck {
+            async fn check(&self, target: &Backend) -> Result<()> {
+                self.0.lock().unwrap().insert(target.clone());
+                Ok(())
+            }
+            fn health_threshold(&self, _success: bool) -> usize {
+                1
+            }
+        }
+
+        let addrs: Vec<_> = (1..=20).map(|i| format!("10.0.0.{i}:80")).collect();
+        let mut lb: LoadBalancer<selection::RoundRobin> =
+            LoadBalancer::try_from_iter(&addrs).unwrap();
+        lb.set_subset(Some(Subset::new("proxy-1", 3)));
+        let checked = Arc::new(std::sync::Mutex::new(BTreeSet::new()));
+        lb.set_health_check(Box::new(RecordCheck(checked.clone())));
+        lb.run_health_check().await;
+        assert_eq!(*checked.lock().unwrap(), lb.health_check_backends());
+        assert_eq!(checked.lock().unwrap().len(), 6);
+
+        lb.set_subset(None);
+        lb.run_health_check().await;
+        assert_eq!(checked.lock().unwrap().len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_warm_up() {
+        use pingora_error::Error;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let lb: LoadBalancer<selection::RoundRobin> =
+            LoadBalancer::try_from_iter(["1.1.1.1:80", "1.0.0.1:80", "1.0.0.2:80"]).unwrap();
+        let down = Backend::new("1.0.0.2:80").unwrap();
+        lb.backends().set_enable(&down, false);
+
+        let calls = AtomicUsize::new(0);
+        let warmed = lb
+            .warm_up(|b| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                let ok = b.addr.to_string() == "1.1.1.1:80";
+                async move {
+                    if ok {
+                        Ok(4)
+                    } else {
+                        Error::e_explain(ErrorType::ConnectRefused, "test")
+                    }
+                }
+            })
+            .await;
+        // the disabled backend is skipped
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert_eq!(warmed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_select_tracked() {
+        let lb: LoadBalancer<selection::LeastConnection> =
+            LoadBalancer::try_from_iter(["1.1.1.1:80", "1.0.0.1:80"]).unwrap();
+
+        let (first, guard) = lb.select_tracked(b"", 10).unwrap();
+        let (second, _guard) = lb.select_tracked(b"", 10).unwrap();
+        assert_ne!(first, second);
+        assert!(lb.in_flight().iter().all(|(_, count)| *count == 1));
+
+        drop(guard);
+        let counts = lb.in_flight();
+        let count = |b: &Backend| counts.iter().find(|(c, _)| c == b).unwrap().1;
+        assert_eq!(count(&first), 0);
+        assert_eq!(count(&second), 1);
+        assert_eq!(lb.select_tracked(b"", 10).unwrap().0, first);
+    }
 }
@@ -0,0 +1,455 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Passive health checks: eject backends based on the outcomes of real requests.
+
+use arc_swap::ArcSwap;
+use pingora_error::{Error, ErrorType};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// the sliding window is made of this many buckets
+const BUCKETS: usize = 10;
+
+/// The outcome of a request to a backend
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The backend responded with a non 5xx response
+    Success,
+    /// The backend responded with a 5xx response
+    ServerError,
+    /// The backend didn't respond in time
+    Timeout,
+    /// Failed to connect to the backend
+    ConnectError,
+}
+
+impl Outcome {
+    /// The outcome of a response with the given status code
+    pub fn from_status(status: u16) -> Self {
+        if (500..600).contains(&status) {
+            Outcome::ServerError
+        } else {
+            Outcome::Success
+        }
+    }
+
+    /// The outcome of a request that failed with the given error
+    pub fn from_error(e: &Error) -> Self {
+        use ErrorType::*;
+        match e.etype() {
+            ConnectTimedout | ConnectRefused | ConnectNoRoute | TLSHandshakeFailure
+            | TLSHandshakeTimedout | InvalidCert | HandshakeError | ConnectError
+            | ConnectProxyFailure => Outcome::ConnectError,
+            ReadTimedout | WriteTimedout => Outcome::Timeout,
+            HTTPStatus(code) => Self::from_status(*code),
+            _ => Outcome::ServerError,
+        }
+    }
+
+    /// Whether this outcome counts as a failure of the backend
+    pub fn is_failure(&self) -> bool {
+        *self != Outcome::Success
+    }
+}
+
+// a bucket of the sliding window packed in a u64 so that it is updated without locking: the
+// low 32 bits of its slot, then the number of requests and of failures, both saturating at
+// u16::MAX
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+struct Bucket {
+    slot: u32,
+    total: u16,
+    failures: u16,
+}
+
+impl Bucket {
+    fn pack(self) -> u64 {
+        ((self.slot as u64) << 32) | ((self.total as u64) << 16) | self.failures as u64
+    }
+
+    fn unpack(v: u64) -> Self {
+        Bucket {
+            slot: (v >> 32) as u32,
+            total: (v >> 16) as u16,
+            failures: v as u16,
+        }
+    }
+}
+
+// the state of a backend, all in atomics so that the selections and the reports of the requests
+// don't contend on a lock
+#[derive(Default)]
+struct BackendState {
+    buckets: [AtomicU64; BUCKETS],
+    // the nanoseconds since the epoch of the detection, 0 if not ejected
+    ejected_at: AtomicU64,
+    // the nanoseconds since the epoch of the detection, 0 if not in slow start
+    readmitted_at: AtomicU64,
+}
+
+impl BackendState {
+    fn record(&self, slot: u64, failure: bool) {
+        let slot = slot as u32;
+        let bucket = &self.buckets[slot as usize % BUCKETS];
+        let _ = bucket.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+            let mut b = Bucket::unpack(v);
+            if b.slot != slot {
+                b = Bucket {
+                    slot,
+                    ..Default::default()
+                };
+            }
+            if b.total == u16::MAX {
+                // keep the failure rate of what is counted
+                return None;
+            }
+            b.total += 1;
+            b.failures += failure as u16;
+            Some(b.pack())
+        });
+    }
+
+    // (total, failures) within the window that ends at `slot`
+    fn stats(&self, slot: u64) -> (usize, usize) {
+        let slot = slot as u32;
+        self.buckets
+            .iter()
+            .map(|b| Bucket::unpack(b.load(Ordering::Relaxed)))
+            .filter(|b| slot.wrapping_sub(b.slot) < BUCKETS as u32)
+            .fold((0, 0), |(t, f), b| {
+                (t + b.total as usize, f + b.failures as usize)
+            })
+    }
+
+    fn reset(&self) {
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Outlier detection: eject backends whose recent requests fail too often.
+///
+/// The outcome of each request is reported via [crate::LoadBalancer::report_outcome()]. When the
+/// failure rate of a backend over the last `window` exceeds `failure_rate`, the backend is
+/// ejected: it is not ready to serve traffic for `ejection_time`. After that, it is readmitted
+/// with traffic ramping up linearly over `slow_start`.
+///
+/// Ejection only affects the readiness of the backend, it is independent of the active
+/// [crate::health_check].
+///
+/// The state of each backend is kept in atomics, so the limits, e.g., `max_ejection_ratio`, are
+/// approximate when the outcomes are reported concurrently.
+pub struct OutlierDetection {
+    /// The duration of the sliding window in which the failure rate is calculated.
+    pub window: Duration,
+    /// The minimal number of requests in the window before a backend can be ejected.
+    pub min_requests: usize,
+    /// The failure rate, between 0 and 1, at which a backend is ejected.
+    pub failure_rate: f64,
+    /// How long an ejected backend is kept out.
+    pub ejection_time: Duration,
+    /// How long it takes for a readmitted backend to receive its full share of traffic.
+    pub slow_start: Duration,
+    /// The maximal fraction of the backends that can be ejected at the same time.
+    pub max_ejection_ratio: f64,
+    epoch: Instant,
+    backends: ArcSwap<HashMap<u64, Arc<BackendState>>>,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        OutlierDetection {
+            window: Duration::from_secs(10),
+            min_requests: 10,
+            failure_rate: 0.5,
+            ejection_time: Duration::from_secs(30),
+            slow_start: Duration::from_secs(30),
+            max_ejection_ratio: 0.5,
+            epoch: Instant::now(),
+            backends: Default::default(),
+        }
+    }
+}
+
+impl OutlierDetection {
+    /// Create a new [OutlierDetection] with the following default values
+    /// * window: 10 seconds
+    /// * min_requests: 10
+    /// * failure_rate: 0.5
+    /// * ejection_time: 30 seconds
+    /// * slow_start: 30 seconds
+    /// * max_ejection_ratio: 0.5
+    pub fn new() -> Box<Self> {
+        Box::<OutlierDetection>::default()
+    }
+
+    fn slot(&self, now: Instant) -> u64 {
+        let bucket_len = (self.window / BUCKETS as u32).max(Duration::from_millis(1));
+        (now.saturating_duration_since(self.epoch).as_nanos() / bucket_len.as_nanos()) as u64
+    }
+
+    // the time as stored in the state, never 0 which means unset
+    fn stamp(&self, now: Instant) -> u64 {
+        (now.saturating_duration_since(self.epoch).as_nanos() as u64).max(1)
+    }
+
+    fn state(&self, key: u64) -> Option<Arc<BackendState>> {
+        self.backends.load().get(&key).cloned()
+    }
+
+    // the state of a backend seen for the first time is added, which is rare
+    fn state_or_insert(&self, key: u64) -> Arc<BackendState> {
+        if let Some(state) = self.state(key) {
+            return state;
+        }
+        self.backends.rcu(|backends| {
+            let mut backends = HashMap::clone(backends);
+            backends.entry(key).or_default();
+            backends
+        });
+        self.state(key).expect("just inserted")
+    }
+
+    // whether the backend is ejected, readmit it with a clean record once its ejection is over
+    fn check_ejection(&self, state: &BackendState, now: u64) -> bool {
+        let ejected_at = state.ejected_at.load(Ordering::Acquire);
+        if ejected_at == 0 {
+            return false;
+        }
+        if now < ejected_at.saturating_add(self.ejection_time.as_nanos() as u64) {
+            return true;
+        }
+        if state
+            .ejected_at
+            .compare_exchange(ejected_at, 0, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            state.reset();
+            state.readmitted_at.store(now, Ordering::Release);
+        }
+        false
+    }
+
+    /// Record the outcome of a request to the backend with the given hash key.
+    ///
+    /// Return true when the backend is ejected because of this outcome.
+    pub(crate) fn observe(&self, key: u64, outcome: Outcome, total_backends: usize) -> bool {
+        let now = Instant::now();
+        let slot = self.slot(now);
+        let stamp = self.stamp(now);
+        let state = self.state_or_insert(key);
+        if self.check_ejection(&state, stamp) {
+            // outcome of requests sent before the ejection
+            return false;
+        }
+        state.record(slot, outcome.is_failure());
+        if !outcome.is_failure() {
+            return false;
+        }
+
+        let (total, failures) = state.stats(slot);
+        if total < self.min_requests || (failures as f64) < total as f64 * self.failure_rate {
+            return false;
+        }
+        let max_ejected = (total_backends as f64 * self.max_ejection_ratio) as usize;
+        let ejected = self
+            .backends
+            .load()
+            .values()
+            .filter(|s| self.check_ejection(s, stamp))
+            .count();
+        if ejected >= max_ejected {
+            return false;
+        }
+        let ejected = state
+            .ejected_at
+            .compare_exchange(0, stamp, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok();
+        if ejected {
+            state.readmitted_at.store(0, Ordering::Release);
+        }
+        ejected
+    }
+
+    /// The share of its traffic the backend with the given hash key should take, between 0 and 1
+    ///
+    /// It is 0 for the ejected backends. It ramps up linearly over `slow_start` after a backend
+    /// is readmitted, from at least 0.1 to let a trickle of traffic through right away, to 1.
+    pub(crate) fn ramp(&self, key: u64) -> f64 {
+        let Some(state) = self.state(key) else {
+            return 1.0;
+        };
+        let now = self.stamp(Instant::now());
+        if self.check_ejection(&state, now) {
+            return 0.0;
+        }
+        let readmitted_at = state.readmitted_at.load(Ordering::Acquire);
+        if readmitted_at == 0 {
+            return 1.0;
+        }
+        let elapsed = Duration::from_nanos(now.saturating_sub(readmitted_at));
+        if elapsed >= self.slow_start {
+            let _ = state.readmitted_at.compare_exchange(
+                readmitted_at,
+                0,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+            return 1.0;
+        }
+        (elapsed.as_secs_f64() / self.slow_start.as_secs_f64()).max(0.1)
+    }
+
+    /// Whether the backend with the given hash key is currently ejected
+    pub(crate) fn is_ejected(&self, key: u64) -> bool {
+        self.state(key)
+            .is_some_and(|s| self.check_ejection(&s, self.stamp(Instant::now())))
+    }
+
+    /// Forget the backends that are no longer known
+    pub(crate) fn retain(&self, keep: impl Fn(u64) -> bool) {
+        self.backends.rcu(|backends| {
+            let mut backends = HashMap::clone(backends);
+            backends.retain(|k, _| keep(*k));
+            backends
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn detector() -> OutlierDetection {
+        OutlierDetection {
+            min_requests: 4,
+            ejection_time: Duration::from_millis(50),
+            slow_start: Duration::ZERO,
+            max_ejection_ratio: 1.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_outcome() {
+        assert_eq!(Outcome::from_status(200), Outcome::Success);
+        assert_eq!(Outcome::from_status(404), Outcome::Success);
+        assert_eq!(Outcome::from_status(503), Outcome::ServerError);
+        let e = Error::new(ErrorType::ConnectRefused);
+        assert_eq!(Outcome::from_error(&e), Outcome::ConnectError);
+        let e = Error::new(ErrorType::ReadTimedout);
+        assert_eq!(Outcome::from_error(&e), Outcome::Timeout);
+        let e = Error::new(ErrorType::HTTPStatus(502));
+        assert_eq!(Outcome::from_error(&e), Outcome::ServerError);
+    }
+
+    #[test]
+    fn test_eject_and_readmit() {
+        let od = detector();
+        assert!(!od.observe(1, Outcome::ServerError, 2));
+        assert!(!od.observe(1, Outcome::Success, 2));
+        assert!(!od.observe(1, Outcome::Timeout, 2));
+        assert!(!od.is_ejected(1));
+        // 3 failures out of 4 requests
+        assert!(od.observe(1, Outcome::ConnectError, 2));
+        assert!(od.is_ejected(1));
+        assert_eq!(od.ramp(1), 0.0);
+        // unrelated backend
+        assert!(!od.is_ejected(2));
+        assert_eq!(od.ramp(2), 1.0);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!od.is_ejected(1));
+        assert_eq!(od.ramp(1), 1.0);
+        // the record is clean after readmission
+        assert!(!od.observe(1, Outcome::ServerError, 2));
+    }
+
+    #[test]
+    fn test_below_threshold() {
+        let od = detector();
+        for _ in 0..10 {
+            assert!(!od.observe(1, Outcome::Success, 1));
+        }
+        for _ in 0..9 {
+            assert!(!od.observe(1, Outcome::ServerError, 1));
+        }
+        assert!(!od.is_ejected(1));
+    }
+
+    #[test]
+    fn test_max_ejection_ratio() {
+        let od = OutlierDetection {
+            max_ejection_ratio: 0.5,
+            ..detector()
+        };
+        for _ in 0..3 {
+            od.observe(1, Outcome::ServerError, 2);
+        }
+        assert!(od.observe(1, Outcome::ServerError, 2));
+        // only 1 of 2 backends can be ejected
+        for _ in 0..10 {
+            assert!(!od.observe(2, Outcome::ServerError, 2));
+        }
+        assert!(!od.is_ejected(2));
+    }
+
+    #[test]
+    fn test_window_expires() {
+        let od = OutlierDetection {
+            window: Duration::from_millis(100),
+            ..detector()
+        };
+        for _ in 0..3 {
+            od.observe(1, Outcome::ServerError, 1);
+        }
+        std::thread::sleep(Duration::from_millis(120));
+        // the old failures are out of the window
+        assert!(!od.observe(1, Outcome::ServerError, 1));
+    }
+
+    #[test]
+    fn test_slow_start() {
+        let od = OutlierDetection {
+            slow_start: Duration::from_secs(60),
+            ..detector()
+        };
+        for _ in 0..4 {
+            od.observe(1, Outcome::ServerError, 2);
+        }
+        std::thread::sleep(Duration::from_millis(60));
+        // right after readmission only ~10% of the traffic goes through
+        let ramp = od.ramp(1);
+        assert!((0.1..0.2).contains(&ramp), "{ramp}");
+    }
+
+    #[test]
+    fn test_bucket_saturation() {
+        let state = BackendState::default();
+        for _ in 0..u16::MAX {
+            state.record(7, false);
+        }
+        state.record(7, true);
+        assert_eq!(state.stats(7), (u16::MAX as usize, 0));
+        // a new slot of the same bucket starts over
+        state.record(7 + BUCKETS as u64, true);
+        assert_eq!(state.stats(7 + BUCKETS as u64), (1, 1));
+        // the bucket of the slot before the window is not counted
+        assert_eq!(state.stats(7 + 2 * BUCKETS as u64), (0, 0));
+    }
+}
@@ -16,10 +16,55 @@
 
 use super::*;
 use pingora_core::protocols::l4::socket::SocketAddr;
-use pingora_ketama::{Bucket, Continuum};
+use pingora_error::{Error, ErrorType, Result};
+use pingora_ketama::{Bucket, Continuum, DEFAULT_POINTS_PER_WEIGHT};
 use std::collections::HashMap;
 
+/// The configuration of [KetamaHashing]
+///
+/// Each backend gets [Self::points_per_weight()] points (virtual nodes) on the ring per unit of
+/// its [weight](Backend::weight). More points spread the keys more evenly at the cost of a larger
+/// ring. The default [DEFAULT_POINTS_PER_WEIGHT] is the same as nginx.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KetamaConfig {
+    points_per_weight: u32,
+}
+
+impl KetamaConfig {
+    /// Create a [KetamaConfig] with the given number of points per weight unit.
+    ///
+    /// Return an error if the number is 0.
+    pub fn new(points_per_weight: u32) -> Result<Self> {
+        if points_per_weight == 0 {
+            return Error::e_explain(
+                ErrorType::InternalError,
+                "ketama points per weight must be positive",
+            );
+        }
+        Ok(KetamaConfig { points_per_weight })
+    }
+
+    /// The number of points per weight unit.
+    pub fn points_per_weight(&self) -> u32 {
+        self.points_per_weight
+    }
+}
+
+impl Default for KetamaConfig {
+    fn default() -> Self {
+        KetamaConfig {
+            points_per_weight: DEFAULT_POINTS_PER_WEIGHT,
+        }
+    }
+}
+
 /// Weighted Ketama consistent hashing
+///
+/// The share of the keys of a backend is proportional to its [weight](Backend::weight). The
+/// number of points per weight unit is set by [KetamaConfig].
+///
+/// When a backend is added or removed, only the keys mapped to its points move, about 1/N of
+/// them with N backends of the same weight.
 pub struct KetamaHashing {
     ring: Continuum,
     // TODO: update Ketama to just store this
@@ -28,8 +73,13 @@ pub struct KetamaHashing {
 
 impl BackendSelection for KetamaHashing {
     type Iter = OwnedNodeIterator;
+    type Config = KetamaConfig;
 
     fn build(backends: &BTreeSet<Backend>) -> Self {
+        Self::build_with_config(backends, &KetamaConfig::default())
+    }
+
+    fn build_with_config(backends: &BTreeSet<Backend>, config: &KetamaConfig) -> Self {
         let buckets: Vec<_> = backends
             .iter()
             .filter_map(|b| {
@@ -46,7 +96,7 @@ impl BackendSelection for KetamaHashing {
             .map(|b| (b.addr.clone(), b.clone()))
             .collect();
         KetamaHashing {
-            ring: Continuum::new(&buckets),
+            ring: Continuum::with_points_per_weight(&buckets, config.points_per_weight),
             backends: new_backends,
         }
     }
@@ -131,4 +181,84 @@ mod test {
         let mut iter = hash.iter(b"test9");
         assert_eq!(iter.next(), Some(&b2));
     }
+
+    // the share of the keys that map to a different backend once `added` is added
+    fn moved_keys(points: u32, backends: &BTreeSet<Backend>, added: &Backend) -> f64 {
+        let config = KetamaConfig::new(points).unwrap();
+        let before = Arc::new(KetamaHashing::build_with_config(backends, &config));
+        let mut backends = backends.clone();
+        backends.insert(added.clone());
+        let after = Arc::new(KetamaHashing::build_with_config(&backends, &config));
+
+        let keys = 10000;
+        let mut moved = 0;
+        for i in 0..keys {
+            let key = format!("key{i}");
+            let old = before.iter(key.as_bytes()).next().unwrap().clone();
+            let new = after.iter(key.as_bytes()).next().unwrap().clone();
+            if old != new {
+                // keys only move to the new backend
+                assert_eq!(&new, added);
+                moved += 1;
+            }
+        }
+        moved as f64 / keys as f64
+    }
+
+    #[test]
+    fn test_ketama_add_backend() {
+        let backends: BTreeSet<_> = (1..=10)
+            .map(|i| Backend::new(&format!("10.0.0.{i}:80")).unwrap())
+            .collect();
+        let added = Backend::new("10.0.0.11:80").unwrap();
+        // about 1/11 of the keys move
+        let moved = moved_keys(160, &backends, &added);
+        assert!((0.05..0.14).contains(&moved), "{moved}");
+        let moved = moved_keys(1000, &backends, &added);
+        assert!((0.07..0.11).contains(&moved), "{moved}");
+    }
+
+    #[test]
+    fn test_ketama_weight() {
+        let b1 = Backend::new("1.1.1.1:80").unwrap();
+        let mut b2 = Backend::new("1.0.0.1:80").unwrap();
+        b2.weight = 3;
+        let backends = BTreeSet::from_iter([b1.clone(), b2.clone()]);
+        let config = KetamaConfig::new(400).unwrap();
+        let hash = Arc::new(KetamaHashing::build_with_config(&backends, &config));
+
+        let keys = 10000;
+        let to_b2 = (0..keys)
+            .filter(|i| hash.iter(format!("key{i}").as_bytes()).next() == Some(&b2))
+            .count();
+        // about 3/4 of the keys
+        let share = to_b2 as f64 / keys as f64;
+        assert!((0.68..0.82).contains(&share), "{share}");
+    }
+
+    #[test]
+    fn test_ketama_config() {
+        assert_eq!(
+            KetamaConfig::default().points_per_weight(),
+            DEFAULT_POINTS_PER_WEIGHT
+        );
+        assert!(KetamaConfig::new(0).is_err());
+
+        // the default config is the same as build()
+        let backends: BTreeSet<_> = (1..=5)
+            .map(|i| Backend::new(&format!("10.0.0.{i}:80")).unwrap())
+            .collect();
+        let hash = Arc::new(KetamaHashing::build(&backends));
+        let configured = Arc::new(KetamaHashing::build_with_config(
+            &backends,
+            &KetamaConfig::default(),
+        ));
+        for i in 0..100 {
+            let key = format!("key{i}");
+            assert_eq!(
+                hash.iter(key.as_bytes()).next(),
+                configured.iter(key.as_bytes()).next()
+            );
+        }
+    }
 }
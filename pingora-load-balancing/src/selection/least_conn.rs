@@ -0,0 +1,211 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Least connections selection and in-flight request tracking
+
+use super::{Backend, BackendIter, BackendSelection};
+use rand::seq::SliceRandom;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The number of in-flight requests to each backend of a selection.
+///
+/// The counters are created when the selection is built. Rebuilding the selection (e.g. after
+/// the service discovery finds new backends) starts over from zero, requests that are still in
+/// flight are decremented on the counters of the old selection.
+pub struct InFlight {
+    counters: HashMap<u64, Arc<AtomicUsize>>,
+}
+
+impl InFlight {
+    /// Create the counters for the given backends
+    pub fn new<'a>(backends: impl IntoIterator<Item = &'a Backend>) -> Self {
+        InFlight {
+            counters: backends
+                .into_iter()
+                .map(|b| (b.hash_key(), Arc::new(AtomicUsize::new(0))))
+                .collect(),
+        }
+    }
+
+    /// The current number of in-flight requests to the given backend.
+    ///
+    /// Unknown backends have 0.
+    pub fn get(&self, backend: &Backend) -> usize {
+        self.counters
+            .get(&backend.hash_key())
+            .map_or(0, |c| c.load(Ordering::Relaxed))
+    }
+
+    /// Count a new in-flight request to the given backend.
+    ///
+    /// The count is decremented when the returned [InFlightGuard] is dropped. `None` if the
+    /// backend is not part of this selection.
+    pub fn acquire(&self, backend: &Backend) -> Option<InFlightGuard> {
+        let counter = self.counters.get(&backend.hash_key())?;
+        counter.fetch_add(1, Ordering::Relaxed);
+        Some(InFlightGuard(counter.clone()))
+    }
+}
+
+/// A request in flight to a backend.
+///
+/// Hold it for the lifetime of the request. It decrements the counter of the backend when
+/// dropped, including when the request fails or is abandoned halfway.
+#[derive(Debug)]
+pub struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// [BackendSelection]s that make decisions based on the in-flight requests to their backends.
+///
+/// See [crate::LoadBalancer::select_tracked()].
+pub trait InFlightTracking {
+    /// The in-flight request counters of this selection
+    fn in_flight(&self) -> &InFlight;
+}
+
+/// Select the backend with the fewest in-flight requests, ties are broken randomly.
+///
+/// The weights of the backends are ignored. The key is ignored.
+///
+/// Requests are only counted when selected via [crate::LoadBalancer::select_tracked()] or
+/// [crate::LoadBalancer::select_tracked_with()].
+pub struct LeastConnection {
+    backends: Box<[Backend]>,
+    in_flight: InFlight,
+}
+
+impl BackendSelection for LeastConnection {
+    type Iter = LeastConnectionIterator;
+    type Config = ();
+
+    fn build(backends: &BTreeSet<Backend>) -> Self {
+        let backends = Vec::from_iter(backends.iter().cloned()).into_boxed_slice();
+        LeastConnection {
+            in_flight: InFlight::new(backends.iter()),
+            backends,
+        }
+    }
+
+    fn iter(self: &Arc<Self>, _key: &[u8]) -> Self::Iter {
+        // shuffle first so that the stable sort below breaks ties randomly
+        let mut order: Vec<(usize, usize)> = self
+            .backends
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (self.in_flight.get(b), i))
+            .collect();
+        order.shuffle(&mut rand::thread_rng());
+        order.sort_by_key(|(count, _)| *count);
+        LeastConnectionIterator {
+            order: order.into_iter().map(|(_, i)| i).collect(),
+            next: 0,
+            selection: self.clone(),
+        }
+    }
+}
+
+impl InFlightTracking for LeastConnection {
+    fn in_flight(&self) -> &InFlight {
+        &self.in_flight
+    }
+}
+
+/// An iterator over the backends of a [LeastConnection] selection, from the least loaded one.
+pub struct LeastConnectionIterator {
+    order: Box<[usize]>,
+    next: usize,
+    selection: Arc<LeastConnection>,
+}
+
+impl BackendIter for LeastConnectionIterator {
+    fn next(&mut self) -> Option<&Backend> {
+        let index = *self.order.get(self.next)?;
+        self.next += 1;
+        Some(&self.selection.backends[index])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn backends() -> (Backend, Backend, Backend) {
+        (
+            Backend::new("1.0.0.1:80").unwrap(),
+            Backend::new("1.0.0.2:80").unwrap(),
+            Backend::new("1.0.0.3:80").unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_least_connection() {
+        let (a, b, c) = backends();
+        let set = BTreeSet::from_iter([a.clone(), b.clone(), c.clone()]);
+        let selection = Arc::new(LeastConnection::build(&set));
+
+        let _a1 = selection.in_flight().acquire(&a).unwrap();
+        let _a2 = selection.in_flight().acquire(&a).unwrap();
+        let c1 = selection.in_flight().acquire(&c).unwrap();
+
+        let mut iter = selection.iter(b"");
+        assert_eq!(iter.next(), Some(&b));
+        assert_eq!(iter.next(), Some(&c));
+        assert_eq!(iter.next(), Some(&a));
+        assert_eq!(iter.next(), None);
+
+        // b and c tie after c1 is done, a is never the first choice
+        drop(c1);
+        for _ in 0..10 {
+            assert_ne!(selection.iter(b"").next(), Some(&a));
+        }
+    }
+
+    #[test]
+    fn test_least_connection_random_ties() {
+        let (a, b, c) = backends();
+        let set = BTreeSet::from_iter([a.clone(), b.clone(), c.clone()]);
+        let selection = Arc::new(LeastConnection::build(&set));
+
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..100 {
+            seen.insert(selection.iter(b"").next().unwrap().clone());
+        }
+        assert_eq!(seen.len(), 3);
+    }
+
+    #[test]
+    fn test_in_flight_guard() {
+        let (a, b, _) = backends();
+        let in_flight = InFlight::new([&a]);
+        assert_eq!(in_flight.get(&a), 0);
+
+        let g1 = in_flight.acquire(&a).unwrap();
+        let g2 = in_flight.acquire(&a).unwrap();
+        assert_eq!(in_flight.get(&a), 2);
+        drop(g1);
+        assert_eq!(in_flight.get(&a), 1);
+        drop(g2);
+        assert_eq!(in_flight.get(&a), 0);
+
+        assert!(in_flight.acquire(&b).is_none());
+        assert_eq!(in_flight.get(&b), 0);
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Maglev consistent hashing
+
+use super::*;
+use fnv::FnvHasher;
+use pingora_error::{Error, ErrorType, Result};
+use std::hash::Hasher;
+
+/// The default size of the lookup table
+pub const DEFAULT_TABLE_SIZE: usize = 65537;
+
+/// The configuration of [Maglev]
+///
+/// The size of the lookup table has to be a prime, as the Maglev paper requires, and it stays the
+/// same across the backend sets so that the keys only move off the backends removed. The paper
+/// suggests a size of at least 100 times the number of backends to keep the imbalance between
+/// them within 1%: the default [DEFAULT_TABLE_SIZE] covers about 650 backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaglevConfig {
+    table_size: usize,
+}
+
+impl MaglevConfig {
+    /// Create a [MaglevConfig] with the given lookup table size.
+    ///
+    /// Return an error if the size is not a prime.
+    pub fn new(table_size: usize) -> Result<Self> {
+        if !is_prime(table_size) {
+            return Error::e_explain(
+                ErrorType::InternalError,
+                format!("maglev table size {table_size} is not a prime"),
+            );
+        }
+        Ok(MaglevConfig { table_size })
+    }
+
+    /// The size of the lookup table.
+    pub fn table_size(&self) -> usize {
+        self.table_size
+    }
+}
+
+impl Default for MaglevConfig {
+    fn default() -> Self {
+        MaglevConfig {
+            table_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+}
+
+/// Weighted [Maglev](https://research.google/pubs/pub44824/) consistent hashing
+///
+/// Compared to [super::Consistent] (Ketama), Maglev spreads the keys more evenly across the
+/// backends at the cost of slightly more disruption when the backends change.
+///
+/// The lookup table is built once per backend set, its size is set by [MaglevConfig]. Backends
+/// with weight 0 receive no traffic.
+pub struct Maglev {
+    backends: Box<[Backend]>,
+    // each item is an index to the `backends`, use u16 to save memory, support up to 2^16 backends
+    table: Box<[u16]>,
+}
+
+fn hash_with_seed(seed: u64, data: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::with_key(seed);
+    hasher.write(data);
+    hasher.finish()
+}
+
+fn is_prime(n: usize) -> bool {
+    n >= 2
+        && (2..)
+            .take_while(|i| i * i <= n)
+            .map(|i| n % i)
+            .all(|rem| rem > 0)
+}
+
+impl BackendSelection for Maglev {
+    type Iter = MaglevIterator;
+    type Config = MaglevConfig;
+
+    fn build(backends: &BTreeSet<Backend>) -> Self {
+        Self::build_with_config(backends, &MaglevConfig::default())
+    }
+
+    fn build_with_config(backends: &BTreeSet<Backend>, config: &MaglevConfig) -> Self {
+        assert!(
+            backends.len() <= u16::MAX as usize,
+            "support up to 2^16 backends"
+        );
+        let backends = Vec::from_iter(backends.iter().cloned()).into_boxed_slice();
+        if backends.iter().all(|b| b.weight == 0) {
+            return Maglev {
+                backends,
+                table: Box::new([]),
+            };
+        }
+
+        let size = config.table_size;
+        // the permutation of each backend: (offset, skip)
+        let permutations: Vec<(usize, usize)> = backends
+            .iter()
+            .map(|b| {
+                let name = b.addr.to_string();
+                let offset = hash_with_seed(0xcbf2_9ce4_8422_2325, name.as_bytes()) as usize;
+                let skip = hash_with_seed(0x8422_2325_cbf2_9ce4, name.as_bytes()) as usize;
+                (offset % size, skip % (size - 1) + 1)
+            })
+            .collect();
+
+        let mut table: Vec<Option<u16>> = vec![None; size];
+        let mut next = vec![0; backends.len()];
+        let mut filled = 0;
+        'fill: loop {
+            for (i, b) in backends.iter().enumerate() {
+                let (offset, skip) = permutations[i];
+                // each backend claims `weight` entries per round
+                for _ in 0..b.weight {
+                    loop {
+                        let candidate = (offset + next[i] * skip) % size;
+                        next[i] += 1;
+                        if table[candidate].is_none() {
+                            table[candidate] = Some(i as u16);
+                            filled += 1;
+                            break;
+                        }
+                    }
+                    if filled == size {
+                        break 'fill;
+                    }
+                }
+            }
+        }
+
+        Maglev {
+            backends,
+            table: table.into_iter().flatten().collect(),
+        }
+    }
+
+    fn iter(self: &Arc<Self>, key: &[u8]) -> Self::Iter {
+        let index = if self.table.is_empty() {
+            0
+        } else {
+            let mut hasher = FnvHasher::default();
+            hasher.write(key);
+            hasher.finish() as usize % self.table.len()
+        };
+        MaglevIterator {
+            index,
+            steps: 0,
+            maglev: self.clone(),
+        }
+    }
+}
+
+/// An iterator over the backends of a [Maglev] selection.
+///
+/// The first item is the backend the key maps to, the following items walk the lookup table
+/// from there, so they are also stable for the same key.
+pub struct MaglevIterator {
+    index: usize,
+    steps: usize,
+    maglev: Arc<Maglev>,
+}
+
+impl BackendIter for MaglevIterator {
+    fn next(&mut self) -> Option<&Backend> {
+        let table = &self.maglev.table;
+        if self.steps >= table.len() {
+            return None;
+        }
+        let backend = table[(self.index + self.steps) % table.len()];
+        self.steps += 1;
+        Some(&self.maglev.backends[backend as usize])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn first(maglev: &Arc<Maglev>, key: &str) -> Backend {
+        maglev.iter(key.as_bytes()).next().unwrap().clone()
+    }
+
+    #[test]
+    fn test_maglev_even_distribution() {
+        let b1 = Backend::new("1.1.1.1:80").unwrap();
+        let b2 = Backend::new("1.0.0.1:80").unwrap();
+        let b3 = Backend::new("1.0.0.255:80").unwrap();
+        let backends = BTreeSet::from_iter([b1.clone(), b2.clone(), b3.clone()]);
+        let maglev = Arc::new(Maglev::build(&backends));
+        assert_eq!(maglev.table.len(), DEFAULT_TABLE_SIZE);
+
+        for b in [&b1, &b2, &b3] {
+            let index = maglev.backends.iter().position(|x| x == b).unwrap() as u16;
+            let entries = maglev.table.iter().filter(|i| **i == index).count();
+            assert!((21845..=21846).contains(&entries), "{entries}");
+        }
+    }
+
+    #[test]
+    fn test_maglev_config() {
+        assert_eq!(MaglevConfig::new(101).unwrap().table_size(), 101);
+        assert_eq!(MaglevConfig::default().table_size(), DEFAULT_TABLE_SIZE);
+        for size in [0, 1, 100, 65536] {
+            assert!(MaglevConfig::new(size).is_err(), "{size}");
+        }
+
+        let b1 = Backend::new("1.1.1.1:80").unwrap();
+        let b2 = Backend::new("1.0.0.1:80").unwrap();
+        let backends = BTreeSet::from_iter([b1, b2]);
+        let config = MaglevConfig::new(307).unwrap();
+        let maglev = Maglev::build_with_config(&backends, &config);
+        assert_eq!(maglev.table.len(), 307);
+        for index in 0..2 {
+            let entries = maglev.table.iter().filter(|i| **i == index).count();
+            assert!((153..=154).contains(&entries), "{entries}");
+        }
+    }
+
+    #[test]
+    fn test_maglev_weighted() {
+        let mut b1 = Backend::new("1.1.1.1:80").unwrap();
+        b1.weight = 2;
+        let b2 = Backend::new("1.0.0.1:80").unwrap();
+        let mut b3 = Backend::new("1.0.0.255:80").unwrap();
+        b3.weight = 0;
+        let backends = BTreeSet::from_iter([b1.clone(), b2.clone(), b3.clone()]);
+        let maglev = Arc::new(Maglev::build(&backends));
+
+        let mut counts = HashMap::new();
+        for i in 0..3000 {
+            *counts
+                .entry(first(&maglev, &format!("test{i}")))
+                .or_insert(0) += 1;
+        }
+        assert!(!counts.contains_key(&b3));
+        let ratio = counts[&b1] as f64 / counts[&b2] as f64;
+        assert!((1.6..2.4).contains(&ratio), "{ratio}");
+    }
+
+    #[test]
+    fn test_maglev_minimal_disruption() {
+        let b1 = Backend::new("1.1.1.1:80").unwrap();
+        let b2 = Backend::new("1.0.0.1:80").unwrap();
+        let b3 = Backend::new("1.0.0.255:80").unwrap();
+        let b4 = Backend::new("1.0.0.2:80").unwrap();
+        let before = Arc::new(Maglev::build(&BTreeSet::from_iter([
+            b1.clone(),
+            b2.clone(),
+            b3.clone(),
+            b4.clone(),
+        ])));
+        let after = Arc::new(Maglev::build(&BTreeSet::from_iter([
+            b1.clone(),
+            b2.clone(),
+            b4.clone(),
+        ])));
+
+        let mut kept = 0;
+        let mut moved = 0;
+        for i in 0..1000 {
+            let key = format!("test{i}");
+            let old = first(&before, &key);
+            if old == b3 {
+                continue;
+            }
+            if first(&after, &key) == old {
+                kept += 1;
+            } else {
+                moved += 1;
+            }
+        }
+        assert!(moved * 10 < kept, "moved {moved} kept {kept}");
+    }
+
+    #[test]
+    fn test_maglev_iterator() {
+        let b1 = Backend::new("1.1.1.1:80").unwrap();
+        let b2 = Backend::new("1.0.0.1:80").unwrap();
+        let backends = BTreeSet::from_iter([b1.clone(), b2.clone()]);
+        let maglev = Arc::new(Maglev::build(&backends));
+
+        let mut iter = UniqueIterator::new(maglev.iter(b"test"), 1000);
+        let mut seen = vec![iter.get_next().unwrap(), iter.get_next().unwrap()];
+        seen.sort();
+        assert_eq!(seen, vec![b2, b1]);
+        assert_eq!(iter.get_next(), None);
+
+        let empty = Arc::new(Maglev::build(&BTreeSet::new()));
+        assert_eq!(empty.iter(b"test").next(), None);
+    }
+}
@@ -16,6 +16,10 @@
 
 pub mod algorithms;
 pub mod consistent;
+pub mod least_conn;
+pub mod maglev;
+pub mod p2c;
+pub mod smooth_weighted;
 pub mod weighted;
 
 use super::Backend;
@@ -23,12 +27,46 @@ use std::collections::{BTreeSet, HashSet};
 use std::sync::Arc;
 use weighted::Weighted;
 
+pub use least_conn::{InFlight, InFlightGuard, InFlightTracking};
+
 /// [BackendSelection] is the interface to implement backend selection mechanisms.
 pub trait BackendSelection {
     /// The [BackendIter] returned from iter() below.
     type Iter;
+    /// The configuration of the selection, `()` for the ones without any.
+    ///
+    /// See [crate::LoadBalancer::set_selection_config()].
+    type Config: Default + Send + Sync;
     /// The function to create a [BackendSelection] implementation.
     fn build(backends: &BTreeSet<Backend>) -> Self;
+    /// Similar to [Self::build()], with the given configuration.
+    ///
+    /// The default implementation ignores the configuration.
+    fn build_with_config(backends: &BTreeSet<Backend>, _config: &Self::Config) -> Self
+    where
+        Self: Sized,
+    {
+        Self::build(backends)
+    }
+    /// Whether [Self::build_with_ramp()] scales the weights of the backends by their ramp.
+    ///
+    /// Otherwise the [crate::LoadBalancer] ramps up the traffic of the backends that warm up by
+    /// skipping them at random.
+    const RAMPS_WEIGHTS: bool = false;
+    /// Similar to [Self::build_with_config()], but the weight of each backend is scaled by
+    /// `ramp(backend)`, a ratio between 0 and 1, e.g., while it warms up during the slow start.
+    ///
+    /// The default implementation ignores the ramp.
+    fn build_with_ramp(
+        backends: &BTreeSet<Backend>,
+        config: &Self::Config,
+        _ramp: &dyn Fn(&Backend) -> f64,
+    ) -> Self
+    where
+        Self: Sized,
+    {
+        Self::build_with_config(backends, config)
+    }
     /// Select backends for a given key.
     ///
     /// An [BackendIter] should be returned. The first item in the iter is the first
@@ -67,8 +105,23 @@ pub type Random = Weighted<algorithms::Random>;
 pub type RoundRobin = Weighted<algorithms::RoundRobin>;
 /// Consistent Ketama hashing on weighted backends
 pub type Consistent = consistent::KetamaHashing;
-
-// TODO: least conn
+/// Maglev consistent hashing on weighted backends
+pub type Maglev = maglev::Maglev;
+/// Smooth (nginx-style) weighted round robin selection
+pub type WeightedRoundRobin = smooth_weighted::SmoothWeighted;
+/// Least connections selection
+pub type LeastConnection = least_conn::LeastConnection;
+/// Power of two choices selection
+pub type P2C = p2c::P2C;
+
+// The weights are scaled up by this factor when some backends ramp up, for a finer ramp than
+// the integer weights allow.
+const RAMP_SCALE: f64 = 10.0;
+
+// The weight of a backend scaled by its ramp, see BackendSelection::build_with_ramp()
+fn ramped_weight(weight: usize, ramp: f64) -> usize {
+    (weight as f64 * RAMP_SCALE * ramp.clamp(0.0, 1.0)).round() as usize
+}
 
 /// An iterator which wraps another iterator and yields unique items. It optionally takes a max
 /// number of iterations if the wrapped iterator never returns.
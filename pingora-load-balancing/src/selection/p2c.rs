@@ -0,0 +1,157 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Power of two choices selection
+
+use super::{Backend, BackendIter, BackendSelection, InFlight, InFlightTracking};
+use rand::Rng;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+/// Power of two choices: pick two backends at random and select the one with fewer in-flight
+/// requests.
+///
+/// Unlike [super::LeastConnection], only two counters are read per selection. The weights of
+/// the backends are ignored. The key is ignored.
+///
+/// If the first choice cannot be used (e.g. it is unhealthy), the iterator falls back to the
+/// other one of the two, then to the rest of the backends starting from a random position, so
+/// the selection degrades to random when few backends are usable.
+///
+/// Requests are only counted when selected via [crate::LoadBalancer::select_tracked()] or
+/// [crate::LoadBalancer::select_tracked_with()].
+pub struct P2C {
+    backends: Box<[Backend]>,
+    in_flight: InFlight,
+}
+
+impl BackendSelection for P2C {
+    type Iter = P2CIterator;
+    type Config = ();
+
+    fn build(backends: &BTreeSet<Backend>) -> Self {
+        let backends = Vec::from_iter(backends.iter().cloned()).into_boxed_slice();
+        P2C {
+            in_flight: InFlight::new(backends.iter()),
+            backends,
+        }
+    }
+
+    fn iter(self: &Arc<Self>, _key: &[u8]) -> Self::Iter {
+        let len = self.backends.len();
+        let mut choices = [0, 0];
+        if len > 1 {
+            let mut rng = rand::thread_rng();
+            let a = rng.gen_range(0..len);
+            // pick a different second backend
+            let b = (a + rng.gen_range(1..len)) % len;
+            let count = |i: usize| self.in_flight.get(&self.backends[i]);
+            choices = if count(b) < count(a) { [b, a] } else { [a, b] };
+        }
+        P2CIterator {
+            choices,
+            steps: 0,
+            selection: self.clone(),
+        }
+    }
+}
+
+impl InFlightTracking for P2C {
+    fn in_flight(&self) -> &InFlight {
+        &self.in_flight
+    }
+}
+
+/// An iterator over the backends of a [P2C] selection.
+pub struct P2CIterator {
+    choices: [usize; 2],
+    steps: usize,
+    selection: Arc<P2C>,
+}
+
+impl BackendIter for P2CIterator {
+    fn next(&mut self) -> Option<&Backend> {
+        let len = self.selection.backends.len();
+        if self.steps >= len {
+            return None;
+        }
+        let index = match self.steps {
+            0 | 1 => self.choices[self.steps],
+            // the rest, skipping the two choices already returned
+            _ => {
+                let [first, second] = self.choices;
+                let skip = (first + len - second) % len;
+                let mut offset = self.steps - 1;
+                if offset >= skip {
+                    offset += 1;
+                }
+                (second + offset) % len
+            }
+        };
+        self.steps += 1;
+        Some(&self.selection.backends[index])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn build(n: u8) -> (Vec<Backend>, Arc<P2C>) {
+        let backends: Vec<_> = (1..=n)
+            .map(|i| Backend::new(&format!("1.0.0.{i}:80")).unwrap())
+            .collect();
+        let selection = Arc::new(P2C::build(&BTreeSet::from_iter(backends.iter().cloned())));
+        (backends, selection)
+    }
+
+    fn collect(mut iter: P2CIterator) -> Vec<Backend> {
+        let mut all = vec![];
+        while let Some(b) = iter.next() {
+            all.push(b.clone());
+        }
+        all
+    }
+
+    #[test]
+    fn test_p2c_prefers_less_loaded() {
+        let (backends, selection) = build(2);
+        let _guards: Vec<_> = (0..3)
+            .map(|_| selection.in_flight().acquire(&backends[0]).unwrap())
+            .collect();
+        for _ in 0..20 {
+            assert_eq!(selection.iter(b"").next(), Some(&backends[1]));
+        }
+    }
+
+    #[test]
+    fn test_p2c_iterates_all_backends() {
+        for n in 1..=5 {
+            let (backends, selection) = build(n);
+            for _ in 0..20 {
+                let all = collect(selection.iter(b""));
+                assert_eq!(all.len(), n as usize);
+                let unique: HashSet<_> = all.into_iter().collect();
+                assert_eq!(unique, HashSet::from_iter(backends.iter().cloned()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_p2c_empty() {
+        let (_, selection) = build(0);
+        assert_eq!(selection.iter(b"").next(), None);
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Smooth weighted round robin selection
+
+use super::{Backend, BackendIter, BackendSelection};
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+
+/// Smooth weighted round robin selection, the same algorithm as nginx's `upstream` module.
+///
+/// Each backend is selected proportionally to its weight, and the selections of different
+/// backends are interleaved instead of clustered, e.g. weights `{a: 5, b: 1, c: 1}` produce
+/// `a a b a c a a`. The key is ignored.
+///
+/// If all the backends have weight 0, they are treated as having equal weights.
+pub struct SmoothWeighted {
+    backends: Box<[Backend]>,
+    weights: Box<[i64]>,
+    total_weight: i64,
+    current_weights: Mutex<Box<[i64]>>,
+}
+
+impl SmoothWeighted {
+    fn build_weighted(backends: &BTreeSet<Backend>, weight: impl Fn(&Backend) -> usize) -> Self {
+        let backends = Vec::from_iter(backends.iter().cloned()).into_boxed_slice();
+        let no_weight = backends.iter().all(|b| weight(b) == 0);
+        let weights: Box<[i64]> = backends
+            .iter()
+            .map(|b| if no_weight { 1 } else { weight(b) as i64 })
+            .collect();
+        SmoothWeighted {
+            total_weight: weights.iter().sum(),
+            current_weights: Mutex::new(vec![0; backends.len()].into_boxed_slice()),
+            backends,
+            weights,
+        }
+    }
+
+    fn next_index(&self) -> Option<usize> {
+        if self.backends.is_empty() {
+            return None;
+        }
+        let mut current = self.current_weights.lock().unwrap();
+        let mut selected = 0;
+        for (i, weight) in self.weights.iter().enumerate() {
+            current[i] += weight;
+            if current[i] > current[selected] {
+                selected = i;
+            }
+        }
+        current[selected] -= self.total_weight;
+        Some(selected)
+    }
+}
+
+impl BackendSelection for SmoothWeighted {
+    type Iter = SmoothWeightedIterator;
+    type Config = ();
+
+    const RAMPS_WEIGHTS: bool = true;
+
+    fn build(backends: &BTreeSet<Backend>) -> Self {
+        Self::build_weighted(backends, |b| b.weight)
+    }
+
+    fn build_with_ramp(
+        backends: &BTreeSet<Backend>,
+        _config: &(),
+        ramp: &dyn Fn(&Backend) -> f64,
+    ) -> Self {
+        if backends
+            .iter()
+            .all(|b| super::ramped_weight(b.weight, ramp(b)) == 0)
+        {
+            // every backend ramps from 0, nothing to weigh them by yet
+            return Self::build(backends);
+        }
+        Self::build_weighted(backends, |b| super::ramped_weight(b.weight, ramp(b)))
+    }
+
+    fn iter(self: &Arc<Self>, _key: &[u8]) -> Self::Iter {
+        SmoothWeightedIterator {
+            first: self.next_index(),
+            steps: 0,
+            selection: self.clone(),
+        }
+    }
+}
+
+/// An iterator over the backends of a [SmoothWeighted] selection.
+///
+/// The first item is the weighted selection, the rest of the backends follow in order.
+pub struct SmoothWeightedIterator {
+    first: Option<usize>,
+    steps: usize,
+    selection: Arc<SmoothWeighted>,
+}
+
+impl BackendIter for SmoothWeightedIterator {
+    fn next(&mut self) -> Option<&Backend> {
+        let first = self.first?;
+        let len = self.selection.backends.len();
+        if self.steps >= len {
+            return None;
+        }
+        let index = (first + self.steps) % len;
+        self.steps += 1;
+        Some(&self.selection.backends[index])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn first_choices(selection: &Arc<SmoothWeighted>, n: usize) -> Vec<Backend> {
+        (0..n)
+            .map(|_| selection.iter(b"").next().unwrap().clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_smooth_weighted() {
+        let mut a = Backend::new("1.0.0.1:80").unwrap();
+        a.weight = 5;
+        let b = Backend::new("1.0.0.2:80").unwrap();
+        let c = Backend::new("1.0.0.3:80").unwrap();
+        let backends = BTreeSet::from_iter([a.clone(), b.clone(), c.clone()]);
+        let selection = Arc::new(SmoothWeighted::build(&backends));
+
+        let expected = [&a, &a, &b, &a, &c, &a, &a];
+        let choices = first_choices(&selection, 14);
+        for (choice, expected) in choices.iter().zip(expected.iter().cycle()) {
+            assert_eq!(choice, *expected);
+        }
+    }
+
+    #[test]
+    fn test_smooth_weighted_ramp() {
+        let a = Backend::new("1.0.0.1:80").unwrap();
+        let b = Backend::new("1.0.0.2:80").unwrap();
+        let backends = BTreeSet::from_iter([a.clone(), b.clone()]);
+        // b is a fifth of the way through its ramp
+        let ramp = |backend: &Backend| if *backend == b { 0.2 } else { 1.0 };
+        let selection = Arc::new(SmoothWeighted::build_with_ramp(&backends, &(), &ramp));
+
+        let choices = first_choices(&selection, 12);
+        assert_eq!(choices.iter().filter(|c| **c == b).count(), 2);
+    }
+
+    #[test]
+    fn test_smooth_weighted_fallback() {
+        let mut a = Backend::new("1.0.0.1:80").unwrap();
+        a.weight = 2;
+        let b = Backend::new("1.0.0.2:80").unwrap();
+        let c = Backend::new("1.0.0.3:80").unwrap();
+        let backends = BTreeSet::from_iter([a.clone(), b.clone(), c.clone()]);
+        let selection = Arc::new(SmoothWeighted::build(&backends));
+
+        let mut iter = selection.iter(b"");
+        assert_eq!(iter.next(), Some(&a));
+        assert_eq!(iter.next(), Some(&b));
+        assert_eq!(iter.next(), Some(&c));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_smooth_weighted_zero_weights() {
+        let mut a = Backend::new("1.0.0.1:80").unwrap();
+        a.weight = 0;
+        let mut b = Backend::new("1.0.0.2:80").unwrap();
+        b.weight = 0;
+        let backends = BTreeSet::from_iter([a.clone(), b.clone()]);
+        let selection = Arc::new(SmoothWeighted::build(&backends));
+        assert_eq!(
+            first_choices(&selection, 4),
+            vec![a.clone(), b.clone(), a, b]
+        );
+    }
+
+    #[test]
+    fn test_smooth_weighted_empty() {
+        let selection = Arc::new(SmoothWeighted::build(&BTreeSet::new()));
+        assert_eq!(selection.iter(b"").next(), None);
+    }
+}
@@ -29,10 +29,8 @@ pub struct Weighted<H = FnvHasher> {
     algorithm: H,
 }
 
-impl<H: SelectionAlgorithm> BackendSelection for Weighted<H> {
-    type Iter = WeightedIterator<H>;
-
-    fn build(backends: &BTreeSet<Backend>) -> Self {
+impl<H: SelectionAlgorithm> Weighted<H> {
+    fn build_weighted(backends: &BTreeSet<Backend>, weight: impl Fn(&Backend) -> usize) -> Self {
         assert!(
             backends.len() <= u16::MAX as usize,
             "support up to 2^16 backends"
@@ -40,7 +38,7 @@ impl<H: SelectionAlgorithm> BackendSelection for Weighted<H> {
         let backends = Vec::from_iter(backends.iter().cloned()).into_boxed_slice();
         let mut weighted = Vec::with_capacity(backends.len());
         for (index, b) in backends.iter().enumerate() {
-            for _ in 0..b.weight {
+            for _ in 0..weight(b) {
                 weighted.push(index as u16);
             }
         }
@@ -50,6 +48,30 @@ impl<H: SelectionAlgorithm> BackendSelection for Weighted<H> {
             algorithm: H::new(),
         }
     }
+}
+
+impl<H: SelectionAlgorithm> BackendSelection for Weighted<H> {
+    type Iter = WeightedIterator<H>;
+    type Config = ();
+
+    const RAMPS_WEIGHTS: bool = true;
+
+    fn build(backends: &BTreeSet<Backend>) -> Self {
+        Self::build_weighted(backends, |b| b.weight)
+    }
+
+    fn build_with_ramp(
+        backends: &BTreeSet<Backend>,
+        _config: &(),
+        ramp: &dyn Fn(&Backend) -> f64,
+    ) -> Self {
+        let weighted = Self::build_weighted(backends, |b| super::ramped_weight(b.weight, ramp(b)));
+        if weighted.weighted.is_empty() {
+            // every backend ramps from 0, nothing to weigh them by yet
+            return Self::build(backends);
+        }
+        weighted
+    }
 
     fn iter(self: &Arc<Self>, key: &[u8]) -> Self::Iter {
         WeightedIterator::new(key, self.clone())
@@ -148,6 +170,31 @@ mod test {
         assert_eq!(iter.next(), Some(&b2));
     }
 
+    #[test]
+    fn test_ramp() {
+        let b1 = Backend::new("1.1.1.1:80").unwrap();
+        let b2 = Backend::new("1.0.0.1:80").unwrap();
+        let backends = BTreeSet::from_iter([b1.clone(), b2.clone()]);
+        // b2 is halfway through its ramp
+        let ramp = |b: &Backend| if *b == b2 { 0.5 } else { 1.0 };
+        let hash: Arc<Weighted<RoundRobin>> =
+            Arc::new(Weighted::build_with_ramp(&backends, &(), &ramp));
+
+        let mut counter = HashMap::new();
+        for _ in 0..30 {
+            *counter
+                .entry(hash.iter(b"").next().unwrap().clone())
+                .or_insert(0) += 1;
+        }
+        assert_eq!(counter[&b1], 20);
+        assert_eq!(counter[&b2], 10);
+
+        // nothing to weigh by when every backend ramps from 0
+        let hash: Arc<Weighted<RoundRobin>> =
+            Arc::new(Weighted::build_with_ramp(&backends, &(), &|_| 0.0));
+        assert!(hash.iter(b"").next().is_some());
+    }
+
     #[test]
     fn test_round_robin() {
         let b1 = Backend::new("1.1.1.1:80").unwrap();
@@ -0,0 +1,251 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sticky sessions: route the requests of a client to the same backend via a cookie
+
+use super::{Backend, BackendIter, BackendSelection, LoadBalancer};
+use arc_swap::ArcSwap;
+use pingora_core::tls::hash::{hash, MessageDigest};
+use pingora_http::RequestHeader;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+// the number of bytes of the digest used as the backend id
+const ID_LEN: usize = 16;
+
+struct IdTable {
+    backends: Arc<BTreeSet<Backend>>,
+    ids: HashMap<String, Backend>,
+}
+
+/// The sticky session cookie settings
+///
+/// The cookie value is an opaque id of the backend: a truncated SHA-256 digest of a secret and
+/// the backend address, so the address of the backend isn't exposed to the clients. Use the
+/// same secret across the instances of the proxy so that they agree on the ids.
+pub struct StickyCookie {
+    /// The name of the cookie
+    pub name: String,
+    /// The `Path` attribute of the cookie, `/` by default
+    pub path: String,
+    /// The `Max-Age` attribute of the cookie. `None`, the default, makes it a session cookie.
+    pub max_age: Option<Duration>,
+    /// Whether to set the `Secure` attribute of the cookie, `false` by default
+    pub secure: bool,
+    secret: Vec<u8>,
+    table: ArcSwap<IdTable>,
+}
+
+/// The result of [LoadBalancer::select_sticky()]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StickySelection {
+    /// The selected backend
+    pub backend: Backend,
+    /// The `Set-Cookie` header value to send to the client, if the cookie needs to be (re)set
+    pub set_cookie: Option<String>,
+}
+
+impl StickyCookie {
+    /// Create a new [StickyCookie] with the given cookie name and secret
+    pub fn new(name: &str, secret: &[u8]) -> Self {
+        StickyCookie {
+            name: name.into(),
+            path: "/".into(),
+            max_age: None,
+            secure: false,
+            secret: secret.to_vec(),
+            table: ArcSwap::from_pointee(IdTable {
+                backends: Default::default(),
+                ids: HashMap::new(),
+            }),
+        }
+    }
+
+    /// The opaque id of the given backend
+    pub fn backend_id(&self, backend: &Backend) -> String {
+        let mut data = self.secret.clone();
+        data.extend_from_slice(backend.addr.to_string().as_bytes());
+        let digest = hash(MessageDigest::sha256(), &data).expect("sha256 should not fail");
+        digest[..ID_LEN]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// The value of this cookie in the given request, if any
+    pub fn find<'a>(&self, req: &'a RequestHeader) -> Option<&'a str> {
+        req.headers
+            .get_all("Cookie")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .find(|(name, _)| *name == self.name)
+            .map(|(_, value)| value)
+    }
+
+    /// The `Set-Cookie` header value that pins the client to the given backend
+    pub fn set_cookie(&self, backend: &Backend) -> String {
+        let mut cookie = format!(
+            "{}={}; Path={}; HttpOnly",
+            self.name,
+            self.backend_id(backend),
+            self.path
+        );
+        if let Some(max_age) = self.max_age {
+            cookie.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+
+    // look up the backend of the given id among the current backends
+    fn lookup(&self, id: &str, backends: Arc<BTreeSet<Backend>>) -> Option<Backend> {
+        let table = self.table.load();
+        if Arc::ptr_eq(&table.backends, &backends) {
+            return table.ids.get(id).cloned();
+        }
+        // the backends changed, rebuild the table
+        let ids: HashMap<_, _> = backends
+            .iter()
+            .map(|b| (self.backend_id(b), b.clone()))
+            .collect();
+        let found = ids.get(id).cloned();
+        self.table.store(Arc::new(IdTable { backends, ids }));
+        found
+    }
+}
+
+impl<S> LoadBalancer<S>
+where
+    S: BackendSelection + 'static,
+    S::Iter: BackendIter,
+{
+    /// Select a [Backend] with sticky sessions.
+    ///
+    /// If the request carries the `sticky` cookie of a backend that is still ready, that backend
+    /// is selected. Otherwise, a backend is selected via [Self::select()] and the returned
+    /// [StickySelection::set_cookie] should be sent to the client in a `Set-Cookie` header.
+    pub fn select_sticky(
+        &self,
+        sticky: &StickyCookie,
+        req: &RequestHeader,
+        key: &[u8],
+        max_iterations: usize,
+    ) -> Option<StickySelection> {
+        if let Some(id) = sticky.find(req) {
+            if let Some(backend) = sticky.lookup(id, self.backends.get_backend()) {
+                if self.backends.ready(&backend) {
+                    return Some(StickySelection {
+                        backend,
+                        set_cookie: None,
+                    });
+                }
+            }
+        }
+        let backend = self.select(key, max_iterations)?;
+        Some(StickySelection {
+            set_cookie: Some(sticky.set_cookie(&backend)),
+            backend,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::selection::RoundRobin;
+
+    fn request(cookie: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        if let Some(cookie) = cookie {
+            req.append_header("Cookie", cookie).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn test_backend_id() {
+        let sticky = StickyCookie::new("lb", b"secret");
+        let b1 = Backend::new("1.1.1.1:80").unwrap();
+        let b2 = Backend::new("1.0.0.1:80").unwrap();
+        let id = sticky.backend_id(&b1);
+        assert_eq!(id.len(), ID_LEN * 2);
+        assert!(!id.contains("1.1.1.1"));
+        assert_eq!(id, sticky.backend_id(&b1));
+        assert_ne!(id, sticky.backend_id(&b2));
+        // a different secret gives different ids
+        assert_ne!(id, StickyCookie::new("lb", b"other").backend_id(&b1));
+    }
+
+    #[test]
+    fn test_find_cookie() {
+        let sticky = StickyCookie::new("lb", b"secret");
+        assert_eq!(sticky.find(&request(None)), None);
+        assert_eq!(sticky.find(&request(Some("a=1; lb=abc; b=2"))), Some("abc"));
+        assert_eq!(sticky.find(&request(Some("lbx=abc"))), None);
+    }
+
+    #[test]
+    fn test_set_cookie() {
+        let mut sticky = StickyCookie::new("lb", b"secret");
+        let b1 = Backend::new("1.1.1.1:80").unwrap();
+        let id = sticky.backend_id(&b1);
+        assert_eq!(sticky.set_cookie(&b1), format!("lb={id}; Path=/; HttpOnly"));
+        sticky.max_age = Some(Duration::from_secs(3600));
+        sticky.secure = true;
+        assert_eq!(
+            sticky.set_cookie(&b1),
+            format!("lb={id}; Path=/; HttpOnly; Max-Age=3600; Secure")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_select_sticky() {
+        let lb: LoadBalancer<RoundRobin> =
+            LoadBalancer::try_from_iter(["1.1.1.1:80", "1.0.0.1:80"]).unwrap();
+        let sticky = StickyCookie::new("lb", b"secret");
+
+        // no cookie: select and set the cookie
+        let first = lb.select_sticky(&sticky, &request(None), b"", 10).unwrap();
+        let set_cookie = first.set_cookie.unwrap();
+        let cookie = set_cookie.split(';').next().unwrap();
+
+        // with the cookie: always the same backend, no need to set the cookie again
+        for _ in 0..5 {
+            let again = lb
+                .select_sticky(&sticky, &request(Some(cookie)), b"", 10)
+                .unwrap();
+            assert_eq!(again.backend, first.backend);
+            assert_eq!(again.set_cookie, None);
+        }
+
+        // the backend is no longer ready: re-select and reset the cookie
+        lb.backends().set_enable(&first.backend, false);
+        let other = lb
+            .select_sticky(&sticky, &request(Some(cookie)), b"", 10)
+            .unwrap();
+        assert_ne!(other.backend, first.backend);
+        assert_eq!(other.set_cookie, Some(sticky.set_cookie(&other.backend)));
+
+        // unknown id
+        let other = lb
+            .select_sticky(&sticky, &request(Some("lb=bogus")), b"", 10)
+            .unwrap();
+        assert!(other.set_cookie.is_some());
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Subsetting: limit each proxy instance to a deterministic subset of the backends
+
+use super::Backend;
+use std::collections::BTreeSet;
+use std::hash::Hasher;
+
+/// The subset of the backends a proxy instance selects from
+///
+/// With many backends and many proxy instances, every instance connecting to every backend
+/// wastes connections. With a [Subset], each instance only selects from `size` of the backends,
+/// so that the connections of an instance are bounded by `size` no matter how many backends
+/// there are.
+///
+/// The subset is chosen by rendezvous hashing of the instance id and the backend addresses:
+/// - the same instance id always gets the same subset of the same backends, across restarts
+/// - different instance ids get different subsets, so the instances together spread over all
+///   the backends
+/// - when a backend is added or removed, the subset of an instance changes by at most that one
+///   backend, so the connections to the other backends are kept
+///
+/// The next `size` backends in the same ranking form the fallback tier, which is selected from
+/// only when no backend of the subset is accepted. It widens the selection gradually so that the
+/// load of a failed subset doesn't spill over all the backends at once.
+///
+/// The weights of the backends are not considered when choosing the subset. They still apply to
+/// the selection within the subset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subset {
+    instance_id: String,
+    size: usize,
+}
+
+impl Subset {
+    /// Create a [Subset] of `size` backends for the proxy instance with the given stable id, such
+    /// as the hostname or the pod name.
+    ///
+    /// # Panics
+    /// if `size` is 0
+    pub fn new(instance_id: &str, size: usize) -> Self {
+        assert!(size > 0, "subset size must be positive");
+        Subset {
+            instance_id: instance_id.into(),
+            size,
+        }
+    }
+
+    /// The id of the proxy instance
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// The maximum number of the backends in the subset
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The subset of the given backends for this instance
+    ///
+    /// All the backends are returned if there are no more than `size` of them.
+    pub fn select(&self, backends: &BTreeSet<Backend>) -> BTreeSet<Backend> {
+        if backends.len() <= self.size {
+            return backends.clone();
+        }
+        self.ranked(backends, 0, self.size)
+    }
+
+    /// The fallback tier of the given backends for this instance: the `size` backends ranked
+    /// right after the subset
+    ///
+    /// It is empty if there are no more than `size` backends.
+    pub fn select_fallback(&self, backends: &BTreeSet<Backend>) -> BTreeSet<Backend> {
+        if backends.len() <= self.size {
+            return BTreeSet::new();
+        }
+        self.ranked(backends, self.size, self.size)
+    }
+
+    /// Both the subset and the fallback tier of the given backends, which are the only ones this
+    /// instance may select from
+    pub fn select_all_tiers(&self, backends: &BTreeSet<Backend>) -> BTreeSet<Backend> {
+        if backends.len() <= self.size {
+            return backends.clone();
+        }
+        self.ranked(backends, 0, self.size.saturating_mul(2))
+    }
+
+    // take `n` of the backends ranked by their scores, skipping the first `skip`
+    fn ranked(&self, backends: &BTreeSet<Backend>, skip: usize, n: usize) -> BTreeSet<Backend> {
+        let mut scored: Vec<_> = backends.iter().map(|b| (self.score(b), b)).collect();
+        // the highest scores, ties broken by the order of the backends to stay deterministic
+        scored.sort_unstable_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+        scored
+            .into_iter()
+            .skip(skip)
+            .take(n)
+            .map(|(_, b)| b.clone())
+            .collect()
+    }
+
+    // FNV is stable across builds and platforms unlike the std hasher, so that all the instances
+    // of different versions agree on the subsets
+    fn score(&self, backend: &Backend) -> u64 {
+        let mut hasher = fnv::FnvHasher::default();
+        hasher.write(self.instance_id.as_bytes());
+        hasher.write_u8(0);
+        hasher.write(backend.addr.to_string().as_bytes());
+        mix(hasher.finish())
+    }
+}
+
+// the splitmix64 finalizer, FNV alone spreads similar inputs poorly
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58476d1ce4e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backends(n: usize) -> BTreeSet<Backend> {
+        (0..n)
+            .map(|i| Backend::new(&format!("10.0.{}.{}:80", i / 256, i % 256)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_subset_size() {
+        let all = backends(100);
+        let subset = Subset::new("proxy-1", 10);
+        let selected = subset.select(&all);
+        assert_eq!(selected.len(), 10);
+        assert!(selected.is_subset(&all));
+
+        // deterministic
+        assert_eq!(Subset::new("proxy-1", 10).select(&all), selected);
+        // different instances get different subsets
+        assert_ne!(Subset::new("proxy-2", 10).select(&all), selected);
+
+        // fewer backends than the subset size
+        let few = backends(5);
+        assert_eq!(subset.select(&few), few);
+        assert!(subset.select_fallback(&few).is_empty());
+        assert_eq!(subset.select_all_tiers(&few), few);
+    }
+
+    #[test]
+    fn test_subset_fallback() {
+        let all = backends(100);
+        let subset = Subset::new("proxy-1", 10);
+        let selected = subset.select(&all);
+        let fallback = subset.select_fallback(&all);
+        assert_eq!(fallback.len(), 10);
+        assert!(fallback.is_disjoint(&selected));
+        let tiers = subset.select_all_tiers(&all);
+        assert_eq!(tiers.len(), 20);
+        assert!(tiers.is_superset(&selected) && tiers.is_superset(&fallback));
+
+        // the fallback tier is partial when there are not enough backends
+        let some = backends(15);
+        assert_eq!(subset.select_fallback(&some).len(), 5);
+        assert_eq!(subset.select_all_tiers(&some), some);
+    }
+
+    #[test]
+    fn test_subset_spread() {
+        let all = backends(100);
+        let mut used = BTreeSet::new();
+        for i in 0..50 {
+            used.extend(Subset::new(&format!("proxy-{i}"), 10).select(&all));
+        }
+        // 50 instances of 10 each cover most of the 100 backends
+        assert!(used.len() > 90, "{}", used.len());
+    }
+
+    #[test]
+    fn test_subset_stable_on_membership_change() {
+        let mut all = backends(100);
+        let subset = Subset::new("proxy-1", 10);
+        let before = subset.select(&all);
+
+        // removing a backend outside of the subset changes nothing
+        let outside = all.difference(&before).next().unwrap().clone();
+        all.remove(&outside);
+        assert_eq!(subset.select(&all), before);
+
+        // removing a backend in the subset replaces only that one
+        let inside = before.iter().next().unwrap().clone();
+        all.remove(&inside);
+        let after = subset.select(&all);
+        assert_eq!(after.len(), 10);
+        assert_eq!(before.intersection(&after).count(), 9);
+
+        // adding a backend replaces at most one
+        all.insert(Backend::new("10.1.0.1:80").unwrap());
+        let added = subset.select(&all);
+        assert!(after.intersection(&added).count() >= 9);
+    }
+}
@@ -16,7 +16,7 @@ use foreign_types::ForeignTypeRef;
 use libc::*;
 use openssl::error::ErrorStack;
 use openssl::pkey::{HasPrivate, PKeyRef};
-use openssl::ssl::{Ssl, SslAcceptor, SslRef};
+use openssl::ssl::{Ssl, SslAcceptor, SslContextBuilder, SslRef};
 use openssl::x509::store::X509StoreRef;
 use openssl::x509::verify::X509VerifyParamRef;
 use openssl::x509::X509Ref;
@@ -52,8 +52,12 @@ extern "C" {
         >,
         arg: *mut raw::c_void,
     );
+
+    pub fn SSL_get_early_data_status(ssl: *const SSL) -> c_int;
 }
 
+const SSL_EARLY_DATA_ACCEPTED: c_int = 2;
+
 /// Add name as an additional reference identifier that can match the peer's certificate
 ///
 /// See [X509_VERIFY_PARAM_set1_host](https://www.openssl.org/docs/man3.1/man3/X509_VERIFY_PARAM_set1_host.html).
@@ -154,6 +158,19 @@ pub fn ssl_set_groups_list(ssl: &mut SslRef, groups: &str) -> Result<(), ErrorSt
 /// This function is specific to BoringSSL. This function is noop for OpenSSL.
 pub fn ssl_use_second_key_share(_ssl: &mut SslRef, _enabled: bool) {}
 
+/// Accept TLS 1.3 early data (0-RTT) of up to `bytes` bytes per connection, 0 to reject it
+pub fn ssl_ctx_set_max_early_data(
+    ctx: &mut SslContextBuilder,
+    bytes: u32,
+) -> Result<(), ErrorStack> {
+    ctx.set_max_early_data(bytes)
+}
+
+/// Whether the early data the client sent on this connection was accepted
+pub fn ssl_early_data_accepted(ssl: &SslRef) -> bool {
+    unsafe { SSL_get_early_data_status(ssl.as_ptr()) == SSL_EARLY_DATA_ACCEPTED }
+}
+
 /// Clear the error stack
 ///
 /// SSL calls should check and clear the OpenSSL error stack. But some calls fail to do so.
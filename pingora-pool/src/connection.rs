@@ -19,12 +19,14 @@ use parking_lot::{Mutex, RwLock};
 use pingora_timeout::{sleep, timeout};
 use std::collections::HashMap;
 use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::sync::{oneshot, watch, Notify, OwnedMutexGuard};
 
 use super::lru::Lru;
+use super::stats::{OpenConnection, PoolCounters, PoolStats};
 
 type GroupKey = u64;
 type ID = i32;
@@ -48,6 +50,7 @@ impl ConnectionMeta {
 struct PoolConnection<S> {
     pub notify_use: oneshot::Sender<bool>,
     pub connection: S,
+    pub idle_since: Instant,
 }
 
 impl<S> PoolConnection<S> {
@@ -55,6 +58,7 @@ impl<S> PoolConnection<S> {
         PoolConnection {
             notify_use,
             connection,
+            idle_since: Instant::now(),
         }
     }
 
@@ -68,6 +72,29 @@ impl<S> PoolConnection<S> {
 
 use crossbeam_queue::ArrayQueue;
 
+/// The order in which the idle connections of the same group are checked out of the pool
+///
+/// Under a steady load the two orders behave the same. They differ once the load drops after a
+/// spike:
+/// - [CheckoutOrder::Fifo] rotates through all the idle connections so that all of them stay in
+///   use. None of them stays idle long enough to be closed by the idle timeout, so the pool keeps
+///   as many connections as the spike needed.
+/// - [CheckoutOrder::Lifo] keeps reusing the most recently released connections. The others stay
+///   idle until the idle timeout, see [ConnectionPool::with_idle_timeout()], or the peer closes
+///   them, so the pool shrinks back to what the load needs. The reused connections are also the
+///   ones least likely to have been closed by the peer.
+///
+/// Without an idle timeout, neither order lets the pool shrink on its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CheckoutOrder {
+    /// Check out the connection that has been idle for the longest first, roughly: the most
+    /// recently released ones are checked out in order, the overflow in no particular order.
+    #[default]
+    Fifo,
+    /// Check out the most recently released connection first
+    Lifo,
+}
+
 /// A pool of exchangeable items
 pub struct PoolNode<T> {
     connections: Mutex<HashMap<ID, T>>,
@@ -75,6 +102,9 @@ pub struct PoolNode<T> {
     hot_queue: ArrayQueue<(ID, T)>,
     // to avoid race between 2 evictions on the queue
     hot_queue_remove_lock: Mutex<()>,
+    // the items in the order they are inserted, used instead of the above for Lifo
+    stack: Mutex<Vec<(ID, T)>>,
+    order: CheckoutOrder,
     // TODO: store the GroupKey to avoid hash collision?
 }
 
@@ -84,15 +114,27 @@ const HOT_QUEUE_SIZE: usize = 16;
 impl<T> PoolNode<T> {
     /// Create a new [PoolNode]
     pub fn new() -> Self {
+        Self::with_checkout_order(CheckoutOrder::Fifo)
+    }
+
+    /// Create a new [PoolNode] that hands out the items in the given order
+    pub fn with_checkout_order(order: CheckoutOrder) -> Self {
         PoolNode {
             connections: Mutex::new(HashMap::new()),
             hot_queue: ArrayQueue::new(HOT_QUEUE_SIZE),
             hot_queue_remove_lock: Mutex::new(()),
+            stack: Mutex::new(vec![]),
+            order,
         }
     }
 
     /// Get any item from the pool
+    ///
+    /// Which item is returned depends on the [CheckoutOrder] of this pool.
     pub fn get_any(&self) -> Option<(ID, T)> {
+        if self.order == CheckoutOrder::Lifo {
+            return self.stack.lock().pop();
+        }
         let hot_conn = self.hot_queue.pop();
         if hot_conn.is_some() {
             return hot_conn;
@@ -112,8 +154,27 @@ impl<T> PoolNode<T> {
         // connections.lock released here
     }
 
+    /// The number of items in the pool
+    ///
+    /// The number is only a snapshot as the pool can be accessed concurrently.
+    pub fn len(&self) -> usize {
+        if self.order == CheckoutOrder::Lifo {
+            return self.stack.lock().len();
+        }
+        self.hot_queue.len() + self.connections.lock().len()
+    }
+
+    /// Whether the pool is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Insert an item with the given unique ID into the pool
     pub fn insert(&self, id: ID, conn: T) {
+        if self.order == CheckoutOrder::Lifo {
+            self.stack.lock().push((id, conn));
+            return;
+        }
         if let Err(node) = self.hot_queue.push((id, conn)) {
             // hot queue is full
             let mut connections = self.connections.lock();
@@ -126,6 +187,11 @@ impl<T> PoolNode<T> {
     /// Remove the item associated with the id from the pool. The item is returned
     /// if it is found and removed.
     pub fn remove(&self, id: ID) -> Option<T> {
+        if self.order == CheckoutOrder::Lifo {
+            let mut stack = self.stack.lock();
+            let pos = stack.iter().position(|(conn_id, _)| *conn_id == id)?;
+            return Some(stack.remove(pos).1);
+        }
         // check the table first as least recent used ones are likely there
         let removed = self.connections.lock().remove(&id);
         if removed.is_some() {
@@ -162,6 +228,13 @@ pub struct ConnectionPool<S> {
     // TODO: n-way pools to reduce lock contention
     pool: RwLock<HashMap<GroupKey, Arc<PoolNode<PoolConnection<S>>>>>,
     lru: Lru<ID, ConnectionMeta>,
+    // the total number of idle connections in the pool
+    idle: AtomicUsize,
+    max_idle: Option<usize>,
+    max_idle_per_host: Option<usize>,
+    idle_timeout: Option<Duration>,
+    checkout_order: CheckoutOrder,
+    counters: PoolCounters,
 }
 
 impl<S> ConnectionPool<S> {
@@ -172,9 +245,103 @@ impl<S> ConnectionPool<S> {
         ConnectionPool {
             pool: RwLock::new(HashMap::with_capacity(size)), // this is oversized since some connections will have the same key
             lru: Lru::new(size),
+            idle: AtomicUsize::new(0),
+            max_idle: None,
+            max_idle_per_host: None,
+            idle_timeout: None,
+            checkout_order: CheckoutOrder::Fifo,
+            counters: PoolCounters::default(),
         }
     }
 
+    /// Cap the number of idle connections under the same group key, e.g., to the same host.
+    ///
+    /// When the group is at capacity, the connection being released is closed instead of pooled.
+    pub fn with_max_idle_per_host(mut self, max: usize) -> Self {
+        self.max_idle_per_host = Some(max);
+        self
+    }
+
+    /// Cap the total number of idle connections in this pool.
+    ///
+    /// Unlike the size limit given to [Self::new()], which evicts the least recently used
+    /// connections, the connection being released is closed instead of pooled when the pool is
+    /// at capacity.
+    pub fn with_max_idle(mut self, max: usize) -> Self {
+        self.max_idle = Some(max);
+        self
+    }
+
+    /// Close the connections that stay idle in this pool for longer than the given duration.
+    ///
+    /// The timeout is enforced by [Self::idle_poll()] and [Self::idle_timeout()], together with
+    /// the timeout given to them, whichever is shorter. Connections that outlive it, e.g., because
+    /// their watcher is not polled in time, are also discarded by [Self::get()], so that checkout
+    /// only returns connections that are fresh enough.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the order in which the idle connections of the same group key are checked out, see
+    /// [CheckoutOrder]. The default is [CheckoutOrder::Fifo].
+    pub fn with_checkout_order(mut self, order: CheckoutOrder) -> Self {
+        self.checkout_order = order;
+        self
+    }
+
+    // the shorter one of the given timeout and the idle timeout of this pool
+    fn effective_timeout(&self, timeout: Option<Duration>) -> Option<Duration> {
+        match (timeout, self.idle_timeout) {
+            (Some(t), Some(idle)) => Some(t.min(idle)),
+            (t, idle) => t.or(idle),
+        }
+    }
+
+    /// The cap set by [Self::with_max_idle_per_host()], if any
+    pub fn max_idle_per_host(&self) -> Option<usize> {
+        self.max_idle_per_host
+    }
+
+    /// The number of idle connections under the given group key
+    pub fn idle_count(&self, key: &GroupKey) -> usize {
+        self.pool.read().get(key).map_or(0, |node| node.len())
+    }
+
+    /// The number of idle connections of each group key that has any
+    pub fn idle_counts(&self) -> Vec<(GroupKey, usize)> {
+        self.pool
+            .read()
+            .iter()
+            .map(|(key, node)| (*key, node.len()))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+
+    /// The total number of idle connections in this pool
+    pub fn total_idle(&self) -> usize {
+        self.idle.load(Ordering::Relaxed)
+    }
+
+    /// Count a newly established connection under the given group key as open
+    ///
+    /// The connection is counted until the returned [OpenConnection] is dropped, so it should
+    /// live as long as the connection does. Open connections that are not idle in this pool are
+    /// reported as active by [Self::stats()].
+    pub fn connection_created(&self, key: GroupKey) -> OpenConnection {
+        self.counters.open(key)
+    }
+
+    /// The statistics of this pool
+    pub fn stats(&self) -> PoolStats {
+        self.counters
+            .snapshot(self.total_idle(), self.idle_counts())
+    }
+
+    fn idle_removed(&self) {
+        self.idle.fetch_sub(1, Ordering::Relaxed);
+    }
+
     /* get or create and insert a pool node for the hash key */
     fn get_pool_node(&self, key: GroupKey) -> Arc<PoolNode<PoolConnection<S>>> {
         {
@@ -191,7 +358,7 @@ impl<S> ConnectionPool<S> {
             if let Some(v) = pool.get(&key) {
                 return (*v).clone();
             }
-            let node = Arc::new(PoolNode::new());
+            let node = Arc::new(PoolNode::with_checkout_order(self.checkout_order));
             let node_ret = node.clone();
             pool.insert(key, node); // TODO: check dup
             node_ret
@@ -211,7 +378,9 @@ impl<S> ConnectionPool<S> {
             }
         }; // read lock released here
 
-        pool_node.remove(meta.id);
+        if pool_node.remove(meta.id).is_some() {
+            self.idle_removed();
+        }
         debug!("evict fd: {} from key {}", meta.id, meta.key);
     }
 
@@ -231,38 +400,72 @@ impl<S> ConnectionPool<S> {
             }
         }; // read lock released here
 
-        if let Some((id, connection)) = pool_node.get_any() {
+        while let Some((id, connection)) = pool_node.get_any() {
+            self.idle_removed();
             self.lru.pop(&id); // the notified is not needed
-            Some(connection.release())
-        } else {
-            None
+            if self
+                .idle_timeout
+                .is_some_and(|t| connection.idle_since.elapsed() >= t)
+            {
+                // dropping it also lets its idle watcher exit
+                debug!("discard fd: {id} of key {key} as it is idle for too long");
+                PoolCounters::incr(&self.counters.evicted);
+                continue;
+            }
+            PoolCounters::incr(&self.counters.reused);
+            return Some(connection.release());
         }
+        None
     }
 
     /// Release a connection to this pool for reuse
     ///
     /// - The returned [`Arc<Notify>`] will notify any listen when the connection is evicted from the pool.
     /// - The returned [`oneshot::Receiver<bool>`] will notify when the connection is being picked up by [Self::get()].
+    ///
+    /// If the pool is at the capacity set by [Self::with_max_idle()] or
+    /// [Self::with_max_idle_per_host()], the connection is dropped and the returned [`Arc<Notify>`]
+    /// is notified right away, as if the connection were evicted.
     pub fn put(
         &self,
         meta: &ConnectionMeta,
         connection: S,
     ) -> (Arc<Notify>, oneshot::Receiver<bool>) {
+        let pool_full = self.max_idle.is_some_and(|max| self.total_idle() >= max);
+        if pool_full
+            || self
+                .max_idle_per_host
+                .is_some_and(|max| self.idle_count(&meta.key) >= max)
+        {
+            debug!("pool is full, close fd: {} of key {}", meta.id, meta.key);
+            PoolCounters::incr(&self.counters.closed_full);
+            let notify_close = Arc::new(Notify::new());
+            notify_close.notify_one();
+            // the connection is dropped here, the receiver resolves right away as its sender is gone
+            drop(connection);
+            let (_, watch_use) = oneshot::channel();
+            return (notify_close, watch_use);
+        }
+
         let (notify_close, replaced) = self.lru.add(meta.id, meta.clone());
         if let Some(meta) = replaced {
+            PoolCounters::incr(&self.counters.closed_full);
             self.pop_evicted(&meta);
         };
         let pool_node = self.get_pool_node(meta.key);
         let (notify_use, watch_use) = oneshot::channel();
         let connection = PoolConnection::new(notify_use, connection);
+        // count it before it can be taken out of the pool
+        self.idle.fetch_add(1, Ordering::Relaxed);
         pool_node.insert(meta.id, connection);
         (notify_close, watch_use)
     }
 
     /// Actively monitor the health of a connection that is already released to this pool
     ///
-    /// When the connection breaks, or the optional `timeout` is reached this function will
-    /// remove it from the pool and drop the connection.
+    /// When the connection breaks, or the optional `timeout` (capped by
+    /// [Self::with_idle_timeout()]) is reached this function will remove it from the pool and drop
+    /// the connection.
     ///
     /// If the connection is reused via [Self::get()] or being evicted, this function will just exit.
     pub async fn idle_poll<Stream>(
@@ -275,6 +478,7 @@ impl<S> ConnectionPool<S> {
     ) where
         Stream: AsyncRead + Unpin + Send,
     {
+        let timeout = self.effective_timeout(timeout);
         let read_result = tokio::select! {
             biased;
             _ = watch_use => {
@@ -294,10 +498,14 @@ impl<S> ConnectionPool<S> {
                 if n > 0 {
                     warn!("Data received on idle client connection, close it")
                 } else {
-                    debug!("Peer closed the idle connection or timeout")
+                    debug!("Peer closed the idle connection")
                 }
             }
 
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                debug!("Idle timeout reached, close the connection");
+                PoolCounters::incr(&self.counters.evicted);
+            }
             Err(e) => {
                 debug!("error with the idle connection, close it {:?}", e);
             }
@@ -318,6 +526,7 @@ impl<S> ConnectionPool<S> {
         mut notify_closed: watch::Receiver<bool>,
         watch_use: oneshot::Receiver<bool>,
     ) {
+        let timeout = self.effective_timeout(Some(timeout)).unwrap_or(timeout);
         tokio::select! {
             biased;
             _ = watch_use => {
@@ -334,6 +543,7 @@ impl<S> ConnectionPool<S> {
             }
             _ = sleep(timeout) => {
                 debug!("idle connection is being evicted");
+                PoolCounters::incr(&self.counters.evicted);
                 self.pop_closed(meta);
             }
         };
@@ -354,7 +564,7 @@ where
             Ok(res) => res,
             Err(e) => {
                 debug!("keepalive timeout {:?} reached, {:?}", d, e);
-                Ok(0)
+                Err(io::ErrorKind::TimedOut.into())
             }
         },
         _ => read_event.await,
@@ -364,6 +574,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::HostStats;
     use log::debug;
     use tokio::sync::Mutex as AsyncMutex;
     use tokio_test::io::{Builder, Mock};
@@ -439,6 +650,208 @@ mod tests {
         assert_eq!(cp.get(&meta1.key), None)
     }
 
+    #[tokio::test]
+    async fn test_max_idle_per_host() {
+        let meta1 = ConnectionMeta::new(101, 1);
+        let meta2 = ConnectionMeta::new(101, 2);
+        let meta3 = ConnectionMeta::new(102, 3);
+        let cp: ConnectionPool<String> = ConnectionPool::new(10).with_max_idle_per_host(1);
+        let (notify_close1, _) = cp.put(&meta1, "v1".to_string());
+        let (notify_close2, watch_use2) = cp.put(&meta2, "v2".to_string()); // over the cap
+        cp.put(&meta3, "v3".to_string());
+
+        // the rejected connection is closed right away
+        notify_close2.notified().await;
+        assert!(watch_use2.await.is_err());
+        assert_eq!(cp.idle_count(&101), 1);
+        assert_eq!(cp.idle_count(&102), 1);
+        assert_eq!(cp.idle_count(&103), 0);
+        assert_eq!(cp.total_idle(), 2);
+        let mut counts = cp.idle_counts();
+        counts.sort();
+        assert_eq!(counts, vec![(101, 1), (102, 1)]);
+
+        assert_eq!(cp.get(&101), Some("v1".to_string()));
+        assert_eq!(cp.idle_count(&101), 0);
+        assert_eq!(cp.total_idle(), 1);
+        assert_eq!(cp.idle_counts(), vec![(102, 1)]);
+        // not notified as it is reused
+        drop(notify_close1);
+
+        // room again
+        cp.put(&meta2, "v2".to_string());
+        assert_eq!(cp.get(&101), Some("v2".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_max_idle() {
+        let meta1 = ConnectionMeta::new(101, 1);
+        let meta2 = ConnectionMeta::new(102, 2);
+        let meta3 = ConnectionMeta::new(103, 3);
+        let cp: ConnectionPool<String> = ConnectionPool::new(10).with_max_idle(2);
+        cp.put(&meta1, "v1".to_string());
+        cp.put(&meta2, "v2".to_string());
+        let (notify_close3, _) = cp.put(&meta3, "v3".to_string()); // over the cap
+        notify_close3.notified().await;
+        assert_eq!(cp.total_idle(), 2);
+        assert!(cp.get(&103).is_none());
+
+        cp.pop_closed(&meta1);
+        assert_eq!(cp.total_idle(), 1);
+        cp.put(&meta3, "v3".to_string());
+        assert_eq!(cp.get(&103), Some("v3".to_string()));
+        assert_eq!(cp.total_idle(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_checkout_order() {
+        let put_all = |cp: &ConnectionPool<String>| {
+            for id in 1..=3 {
+                cp.put(&ConnectionMeta::new(101, id), format!("v{id}"));
+            }
+        };
+        let cp: ConnectionPool<String> = ConnectionPool::new(10);
+        put_all(&cp);
+        assert_eq!(cp.get(&101), Some("v1".to_string()));
+        assert_eq!(cp.get(&101), Some("v2".to_string()));
+
+        let cp: ConnectionPool<String> =
+            ConnectionPool::new(10).with_checkout_order(CheckoutOrder::Lifo);
+        put_all(&cp);
+        assert_eq!(cp.get(&101), Some("v3".to_string()));
+        // released again, so it is the next one
+        cp.put(&ConnectionMeta::new(101, 3), "v3".to_string());
+        assert_eq!(cp.get(&101), Some("v3".to_string()));
+        cp.pop_closed(&ConnectionMeta::new(101, 2));
+        assert_eq!(cp.get(&101), Some("v1".to_string()));
+        assert!(cp.get(&101).is_none());
+        assert_eq!(cp.total_idle(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_on_get() {
+        let meta1 = ConnectionMeta::new(101, 1);
+        let meta2 = ConnectionMeta::new(101, 2);
+        let cp: ConnectionPool<String> =
+            ConnectionPool::new(10).with_idle_timeout(Duration::from_millis(50));
+        cp.put(&meta1, "v1".to_string());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        cp.put(&meta2, "v2".to_string());
+
+        // v1 is too old to be reused
+        assert_eq!(cp.get(&101), Some("v2".to_string()));
+        assert!(cp.get(&101).is_none());
+        assert_eq!(cp.total_idle(), 0);
+
+        cp.put(&meta1, "v1".to_string());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(cp.get(&101).is_none());
+        assert_eq!(cp.total_idle(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_poll() {
+        let meta1 = ConnectionMeta::new(101, 1);
+        let mock_io1 = Arc::new(AsyncMutex::new(
+            Builder::new().wait(Duration::from_secs(99)).build(),
+        ));
+        let meta2 = ConnectionMeta::new(101, 2);
+        let mock_io2 = Arc::new(AsyncMutex::new(
+            Builder::new().wait(Duration::from_secs(99)).build(),
+        ));
+        let cp: ConnectionPool<Arc<AsyncMutex<Mock>>> =
+            ConnectionPool::new(3).with_idle_timeout(Duration::from_secs(1));
+        let (c1, u1) = cp.put(&meta1, mock_io1.clone());
+        let (c2, u2) = cp.put(&meta2, mock_io2.clone());
+
+        // the pool idle timeout is shorter than the one of the connection
+        let closed_item = tokio::select! {
+            _ = cp.idle_poll(mock_io1.try_lock_owned().unwrap(), &meta1, Some(Duration::from_secs(2)), c1, u1) => {debug!("notifier1"); 1},
+            _ = tokio::time::sleep(Duration::from_millis(1500)) => {debug!("timer"); 0},
+        };
+        assert_eq!(closed_item, 1);
+        assert_eq!(cp.idle_count(&101), 1);
+
+        // and it applies when the connection has no timeout of its own
+        let closed_item = tokio::select! {
+            _ = cp.idle_poll(mock_io2.try_lock_owned().unwrap(), &meta2, None, c2, u2) => {debug!("notifier2"); 2},
+            _ = tokio::time::sleep(Duration::from_millis(1500)) => {debug!("timer"); 0},
+        };
+        assert_eq!(closed_item, 2);
+        assert_eq!(cp.total_idle(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let meta1 = ConnectionMeta::new(101, 1);
+        let meta2 = ConnectionMeta::new(101, 2);
+        let meta3 = ConnectionMeta::new(102, 3);
+        let cp: ConnectionPool<String> = ConnectionPool::new(2).with_max_idle_per_host(1);
+        let open1 = cp.connection_created(101);
+        let open2 = cp.connection_created(101);
+        let _open3 = cp.connection_created(102);
+        let stats = cp.stats();
+        assert_eq!(stats.created, 3);
+        assert_eq!(stats.active, 3);
+        assert_eq!(stats.idle, 0);
+
+        cp.put(&meta1, "v1".to_string());
+        cp.put(&meta2, "v2".to_string()); // over the per host cap
+        drop(open2);
+        cp.put(&meta3, "v3".to_string());
+        let stats = cp.stats();
+        assert_eq!(stats.closed_full, 1);
+        assert_eq!(stats.idle, 2);
+        assert_eq!(stats.active, 0);
+        assert_eq!(
+            stats.hosts,
+            vec![
+                HostStats {
+                    key: 101,
+                    idle: 1,
+                    active: 0
+                },
+                HostStats {
+                    key: 102,
+                    idle: 1,
+                    active: 0
+                }
+            ]
+        );
+
+        assert_eq!(cp.get(&101), Some("v1".to_string()));
+        let stats = cp.stats();
+        assert_eq!(stats.reused, 1);
+        assert_eq!(stats.active, 1);
+        assert_eq!(stats.hosts[0].active, 1);
+        assert_eq!(stats.reuse_ratio(), 0.25);
+
+        drop(open1);
+        assert_eq!(cp.stats().active, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stats_evicted() {
+        let meta1 = ConnectionMeta::new(101, 1);
+        let mock_io1 = Arc::new(AsyncMutex::new(
+            Builder::new().wait(Duration::from_secs(99)).build(),
+        ));
+        let cp: ConnectionPool<Arc<AsyncMutex<Mock>>> = ConnectionPool::new(3);
+        let (c1, u1) = cp.put(&meta1, mock_io1.clone());
+        cp.idle_poll(
+            mock_io1.try_lock_owned().unwrap(),
+            &meta1,
+            Some(Duration::from_millis(10)),
+            c1,
+            u1,
+        )
+        .await;
+        let stats = cp.stats();
+        assert_eq!(stats.evicted, 1);
+        assert_eq!(stats.idle, 0);
+        assert!(stats.hosts.is_empty());
+    }
+
     #[tokio::test]
     #[should_panic(expected = "There is still data left to read.")]
     async fn test_read_close() {
@@ -24,5 +24,7 @@
 
 mod connection;
 mod lru;
+mod stats;
 
-pub use connection::{ConnectionMeta, ConnectionPool, PoolNode};
+pub use connection::{CheckoutOrder, ConnectionMeta, ConnectionPool, PoolNode};
+pub use stats::{HostStats, OpenConnection, PoolStats};
@@ -0,0 +1,159 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connection pool statistics
+
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The numbers of a connection group, e.g., a host, in [PoolStats]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostStats {
+    /// The group key
+    pub key: u64,
+    /// The number of connections idle in the pool
+    pub idle: usize,
+    /// The number of open connections that are not in the pool, i.e., in use
+    pub active: usize,
+}
+
+/// A snapshot of the statistics of a [crate::ConnectionPool]
+///
+/// The counters are accumulated since the pool is created. The gauges, `idle` and `active`, are
+/// the current numbers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Connections created, as reported via [crate::ConnectionPool::connection_created()]
+    pub created: u64,
+    /// Connections taken out of the pool for reuse
+    pub reused: u64,
+    /// Connections closed after staying idle in the pool for too long
+    pub evicted: u64,
+    /// Connections closed because the pool was full when they were released or after
+    pub closed_full: u64,
+    /// The number of idle connections in the pool
+    pub idle: usize,
+    /// The number of open connections that are not in the pool
+    pub active: usize,
+    /// The numbers of each connection group that has any connection
+    pub hosts: Vec<HostStats>,
+}
+
+impl PoolStats {
+    /// The ratio of the reused connections among all the connections handed out, new or reused.
+    /// Return 0 when no connection is handed out.
+    pub fn reuse_ratio(&self) -> f64 {
+        let total = self.created + self.reused;
+        if total == 0 {
+            0.0
+        } else {
+            self.reused as f64 / total as f64
+        }
+    }
+}
+
+/// The handle that keeps a connection counted as open by its pool until it is dropped
+///
+/// See [crate::ConnectionPool::connection_created()].
+#[derive(Debug)]
+pub struct OpenConnection {
+    count: Arc<AtomicUsize>,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct PoolCounters {
+    pub created: AtomicU64,
+    pub reused: AtomicU64,
+    pub evicted: AtomicU64,
+    pub closed_full: AtomicU64,
+    open: RwLock<HashMap<u64, Arc<AtomicUsize>>>,
+}
+
+impl PoolCounters {
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn open(&self, key: u64) -> OpenConnection {
+        Self::incr(&self.created);
+        let count = {
+            let open = self.open.read();
+            open.get(&key).cloned()
+        }; // read lock released here
+        let count = count.unwrap_or_else(|| {
+            let mut open = self.open.write();
+            // forget the groups without any open connection, only when a new group shows up
+            // (each open connection holds a reference of its count)
+            open.retain(|_, c| Arc::strong_count(c) > 1);
+            open.entry(key).or_default().clone()
+        });
+        count.fetch_add(1, Ordering::Relaxed);
+        OpenConnection { count }
+    }
+
+    // the number of open connections of each group
+    pub fn open_counts(&self) -> Vec<(u64, usize)> {
+        self.open
+            .read()
+            .iter()
+            .map(|(key, count)| (*key, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    pub fn snapshot(&self, idle: usize, idle_counts: Vec<(u64, usize)>) -> PoolStats {
+        let mut hosts: HashMap<u64, HostStats> = idle_counts
+            .into_iter()
+            .map(|(key, idle)| {
+                let host = HostStats {
+                    key,
+                    idle,
+                    active: 0,
+                };
+                (key, host)
+            })
+            .collect();
+        for (key, open) in self.open_counts() {
+            let host = hosts.entry(key).or_insert(HostStats {
+                key,
+                idle: 0,
+                active: 0,
+            });
+            // connections that are not created via this pool are never active
+            host.active = open.saturating_sub(host.idle);
+        }
+        let mut hosts: Vec<_> = hosts
+            .into_values()
+            .filter(|h| h.idle > 0 || h.active > 0)
+            .collect();
+        hosts.sort_by_key(|h| h.key);
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        PoolStats {
+            created: get(&self.created),
+            reused: get(&self.reused),
+            evicted: get(&self.evicted),
+            closed_full: get(&self.closed_full),
+            idle,
+            active: hosts.iter().map(|h| h.active).sum(),
+            hosts,
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use async_trait::async_trait;
+use http::header::HeaderName;
+use log::info;
+use structopt::StructOpt;
+
+use pingora_core::server::configuration::Opt;
+use pingora_core::server::Server;
+use pingora_core::upstreams::peer::HttpPeer;
+use pingora_core::Result;
+use pingora_load_balancing::{selection::RoundRobin, LoadBalancer};
+use pingora_proxy::{OverrideTrust, ProxyHttp, Session, UpstreamOverride};
+
+pub struct Canary {
+    upstreams: LoadBalancer<RoundRobin>,
+    upstream_override: UpstreamOverride,
+}
+
+#[async_trait]
+impl ProxyHttp for Canary {
+    type CTX = ();
+    fn new_ctx(&self) -> Self::CTX {}
+
+    async fn upstream_peer(&self, session: &mut Session, _ctx: &mut ()) -> Result<Box<HttpPeer>> {
+        // trusted requests can pick the upstream themselves
+        if let Some(peer) = self.upstream_override.peer(session)? {
+            info!("upstream overridden to {peer}");
+            return Ok(peer);
+        }
+        let upstream = self.upstreams.select(b"", 256).unwrap();
+        Ok(Box::new(HttpPeer::new(upstream, false, "".to_string())))
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut pingora_http::RequestHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        // don't leak the override and its secret to the upstream
+        self.upstream_override.remove_headers(upstream_request);
+        Ok(())
+    }
+}
+
+// RUST_LOG=INFO cargo run --example upstream_override
+// curl 127.0.0.1:6190 -H "X-Debug-Upstream: 127.0.0.1:8001" -H "X-Debug-Token: changeme"
+fn main() {
+    env_logger::init();
+
+    let opt = Opt::from_args();
+    let mut my_server = Server::new(Some(opt)).unwrap();
+    my_server.bootstrap();
+
+    let upstreams = LoadBalancer::try_from_iter(["127.0.0.1:8000"]).unwrap();
+    // only the requests with the shared secret are trusted, and they can only pick the canaries
+    let secret = std::env::var("DEBUG_TOKEN").unwrap_or_else(|_| "changeme".to_string());
+    let trust = OverrideTrust::Secret(HeaderName::from_static("x-debug-token"), secret);
+    let upstream_override =
+        UpstreamOverride::new(HeaderName::from_static("x-debug-upstream"), trust)
+            .with_allowed_upstreams(["127.0.0.1:8001", "127.0.0.1:8002"]);
+
+    let mut proxy = pingora_proxy::http_proxy_service(
+        &my_server.configuration,
+        Canary {
+            upstreams,
+            upstream_override,
+        },
+    );
+    proxy.add_tcp("0.0.0.0:6190");
+
+    my_server.add_service(proxy);
+    my_server.run_forever();
+}
@@ -0,0 +1,478 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Access logs of the finished requests
+//!
+//! An [AccessLogger] is called once per request right after [ProxyHttp::logging()] with the
+//! [AccessLogEntry] of the request. [AccessLog] is the built-in logger that writes each entry as a
+//! line in one of the [AccessLogFormat]s to a sink, e.g., a file.
+
+use super::*;
+use chrono::{DateTime, SecondsFormat, Utc};
+use pingora_core::protocols::l4::socket::SocketAddr;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, LineWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The fields of a finished request for access logging
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry<'a> {
+    /// When the request header is read
+    pub time: SystemTime,
+    /// The ID of the request, see [ProxyHttp::request_id()]
+    pub request_id: Option<&'a str>,
+    /// The request method
+    pub method: &'a str,
+    /// The request path and query
+    pub path: &'a str,
+    /// The HTTP version of the request
+    pub version: Version,
+    /// The response status code, 0 if no response is sent
+    pub status: u16,
+    /// The request body bytes read from the client
+    pub bytes_in: usize,
+    /// The response body bytes sent to the client
+    pub bytes_out: usize,
+    /// The address of the client
+    pub client_addr: Option<&'a SocketAddr>,
+    /// The address of the upstream of the last attempt, `None` if not proxied
+    pub upstream_addr: Option<&'a SocketAddr>,
+    /// The timestamps of the phases of the request
+    pub timings: &'a Timings,
+    /// The cache phase of the request, e.g., `hit`, `miss` or `disabled`
+    pub cache_status: &'static str,
+    /// The `Referer` header of the request
+    pub referer: Option<&'a str>,
+    /// The `User-Agent` header of the request
+    pub user_agent: Option<&'a str>,
+    /// The error that terminated the request, if any
+    pub error: Option<&'a Error>,
+}
+
+impl<'a> AccessLogEntry<'a> {
+    /// Collect the fields of the request in the given session
+    pub fn new(session: &'a Session, error: Option<&'a Error>) -> Self {
+        let req = session.req_header();
+        let header_str = |name| req.headers.get(name).and_then(|v| v.to_str().ok());
+        AccessLogEntry {
+            time: SystemTime::now() - session.timings().total(),
+            request_id: session.request_id(),
+            method: req.method.as_str(),
+            path: req.uri.path_and_query().map_or("/", |p| p.as_str()),
+            version: req.version,
+            status: session.response_written().map_or(0, |r| r.status.as_u16()),
+            bytes_in: session.body_bytes_read(),
+            bytes_out: session.body_bytes_sent(),
+            client_addr: session.client_addr(),
+            upstream_addr: session.upstream_addr(),
+            timings: session.timings(),
+            cache_status: session.cache.phase().as_str(),
+            referer: header_str(header::REFERER),
+            user_agent: header_str(header::USER_AGENT),
+            error,
+        }
+    }
+}
+
+/// The interface to log the finished requests, see [ProxyHttp::access_logger()]
+pub trait AccessLogger: Send + Sync {
+    /// Log the given request. This is called on the request path so it should not block.
+    fn log(&self, entry: &AccessLogEntry);
+
+    /// Write out the buffered entries, if any. This is called when the service shuts down.
+    fn flush(&self) {}
+}
+
+/// The built-in formats of the access log lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// A JSON object per line with all the fields of [AccessLogEntry]
+    Json,
+    /// The Apache/NCSA combined log format
+    Combined,
+}
+
+impl AccessLogFormat {
+    /// Format the entry as a line without the line break
+    pub fn format(&self, entry: &AccessLogEntry) -> String {
+        match self {
+            Self::Json => format_json(entry),
+            Self::Combined => format_combined(entry),
+        }
+    }
+}
+
+// how often the sink is flushed when there are lines written
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// the lines waiting for the writer thread, beyond which the new lines are dropped
+const QUEUE_SIZE: usize = 65536;
+
+enum Message {
+    Line(String),
+    Flush(SyncSender<()>),
+}
+
+/// An [AccessLogger] that writes the entries in the given format to a sink line by line
+///
+/// The lines are written by a dedicated thread so that a slow sink never blocks the requests. The
+/// sink is flushed every second, when [AccessLogger::flush()] is called and when the logger is
+/// dropped. When the sink falls behind by too many lines, the new ones are dropped with a warning.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sender: Option<SyncSender<Message>>,
+    writer: Option<JoinHandle<()>>,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl AccessLog {
+    /// Create a new [AccessLog] that writes to the given sink
+    pub fn new(format: AccessLogFormat, sink: Box<dyn Write + Send>) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let writer_dropped = dropped.clone();
+        let writer = std::thread::Builder::new()
+            .name("access log".into())
+            .spawn(move || write_lines(sink, receiver, &writer_dropped))
+            .expect("Failed to spawn the access log thread");
+        AccessLog {
+            format,
+            sender: Some(sender),
+            writer: Some(writer),
+            dropped,
+        }
+    }
+
+    /// Create a new [AccessLog] that appends to the file at the given path
+    ///
+    /// The lines are buffered, see [AccessLog] for when they are flushed.
+    pub fn to_file(format: AccessLogFormat, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .or_err_with(FileOpenError, || format!("access log {}", path.display()))?;
+        Ok(Self::new(format, Box::new(BufWriter::new(file))))
+    }
+
+    /// Create a new [AccessLog] that writes to stdout
+    pub fn to_stdout(format: AccessLogFormat) -> Self {
+        Self::new(format, Box::new(LineWriter::new(std::io::stdout())))
+    }
+}
+
+impl AccessLogger for AccessLog {
+    fn log(&self, entry: &AccessLogEntry) {
+        let mut line = self.format.format(entry);
+        line.push('\n');
+        let Some(sender) = self.sender.as_ref() else {
+            return;
+        };
+        if let Err(TrySendError::Full(_)) = sender.try_send(Message::Line(line)) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn flush(&self) {
+        let Some(sender) = self.sender.as_ref() else {
+            return;
+        };
+        let (done, wait) = mpsc::sync_channel(1);
+        if sender.send(Message::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // the writer flushes and exits once the sender is gone
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+// the writer thread of AccessLog
+fn write_lines(
+    mut sink: Box<dyn Write + Send>,
+    receiver: mpsc::Receiver<Message>,
+    dropped: &AtomicUsize,
+) {
+    let mut last_flush = Instant::now();
+    let mut unflushed = false;
+    loop {
+        let message = receiver.recv_timeout(FLUSH_INTERVAL.saturating_sub(last_flush.elapsed()));
+        let mut done = None;
+        match message {
+            Ok(Message::Line(line)) => {
+                if let Err(e) = sink.write_all(line.as_bytes()) {
+                    warn!("Fail to write access log: {e}");
+                }
+                unflushed = true;
+            }
+            Ok(Message::Flush(sender)) => done = Some(sender),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if done.is_some() || (unflushed && last_flush.elapsed() >= FLUSH_INTERVAL) {
+            if let Err(e) = sink.flush() {
+                warn!("Fail to flush access log: {e}");
+            }
+            unflushed = false;
+            last_flush = Instant::now();
+            let dropped = dropped.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                warn!("Access log is falling behind, {dropped} lines dropped");
+            }
+        } else if !unflushed {
+            // nothing to flush, wait for the next line for up to the interval
+            last_flush = Instant::now();
+        }
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
+    }
+    if let Err(e) = sink.flush() {
+        warn!("Fail to flush access log: {e}");
+    }
+}
+
+fn format_ms(d: Option<Duration>) -> String {
+    d.map_or_else(
+        || "null".to_string(),
+        |d| format!("{:.3}", d.as_secs_f64() * 1000.0),
+    )
+}
+
+// escape the string for both JSON and the quoted fields of the combined log
+fn escape(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+fn json_str(s: Option<&str>, out: &mut String) {
+    match s {
+        Some(s) => {
+            out.push('"');
+            escape(s, out);
+            out.push('"');
+        }
+        None => out.push_str("null"),
+    }
+}
+
+fn format_json(entry: &AccessLogEntry) -> String {
+    let time: DateTime<Utc> = entry.time.into();
+    let client_addr = entry.client_addr.map(|a| a.to_string());
+    let upstream_addr = entry.upstream_addr.map(|a| a.to_string());
+    let error = entry.error.map(|e| e.to_string());
+    let timings = entry.timings;
+
+    let mut out = String::with_capacity(512);
+    out.push_str("{\"time\":");
+    json_str(
+        Some(&time.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        &mut out,
+    );
+    out.push_str(",\"client_addr\":");
+    json_str(client_addr.as_deref(), &mut out);
+    out.push_str(",\"request_id\":");
+    json_str(entry.request_id, &mut out);
+    out.push_str(",\"method\":");
+    json_str(Some(entry.method), &mut out);
+    out.push_str(",\"path\":");
+    json_str(Some(entry.path), &mut out);
+    out.push_str(",\"version\":");
+    json_str(Some(&format!("{:?}", entry.version)), &mut out);
+    let _ = write!(
+        out,
+        ",\"status\":{},\"bytes_in\":{},\"bytes_out\":{}",
+        entry.status, entry.bytes_in, entry.bytes_out
+    );
+    out.push_str(",\"upstream_addr\":");
+    json_str(upstream_addr.as_deref(), &mut out);
+    out.push_str(",\"cache_status\":");
+    json_str(Some(entry.cache_status), &mut out);
+    let _ = write!(
+        out,
+        ",\"upstream_connect_ms\":{},\"ttfb_ms\":{},\"upstream_response_ms\":{},\"body_transfer_ms\":{},\"total_ms\":{}",
+        format_ms(timings.upstream_connect_time()),
+        format_ms(timings.time_to_first_byte()),
+        format_ms(timings.upstream_response_time()),
+        format_ms(timings.body_transfer_time()),
+        format_ms(Some(timings.total())),
+    );
+    out.push_str(",\"referer\":");
+    json_str(entry.referer, &mut out);
+    out.push_str(",\"user_agent\":");
+    json_str(entry.user_agent, &mut out);
+    out.push_str(",\"error\":");
+    json_str(error.as_deref(), &mut out);
+    out.push('}');
+    out
+}
+
+fn format_combined(entry: &AccessLogEntry) -> String {
+    let time: DateTime<Utc> = entry.time.into();
+    // only the IP, as %h of Apache
+    let client = match entry.client_addr {
+        Some(SocketAddr::Inet(addr)) => addr.ip().to_string(),
+        _ => "-".to_string(),
+    };
+    let mut out = String::with_capacity(256);
+    let _ = write!(
+        out,
+        "{client} - - [{}] \"",
+        time.format("%d/%b/%Y:%H:%M:%S %z")
+    );
+    escape(entry.method, &mut out);
+    out.push(' ');
+    escape(entry.path, &mut out);
+    let _ = write!(out, " {:?}\" {} ", entry.version, entry.status);
+    if entry.bytes_out == 0 {
+        out.push('-');
+    } else {
+        let _ = write!(out, "{}", entry.bytes_out);
+    }
+    for field in [entry.referer, entry.user_agent] {
+        out.push_str(" \"");
+        escape(field.unwrap_or("-"), &mut out);
+        out.push('"');
+    }
+    out
+}
+
+impl<SV> HttpProxy<SV> {
+    // run the logging() phase and then the access logger, if any, of the finished request
+    pub(crate) async fn log_request(
+        &self,
+        session: &mut Session,
+        error: Option<&Error>,
+        ctx: &mut SV::CTX,
+    ) where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        session.timings.response_finished();
+        self.inner.logging(session, error, ctx).await;
+        #[cfg(feature = "opentelemetry")]
+        session.end_trace(error);
+        if let Some(logger) = self.inner.access_logger() {
+            logger.log(&AccessLogEntry::new(session, error));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn entry<'a>(timings: &'a Timings, client: &'a SocketAddr) -> AccessLogEntry<'a> {
+        AccessLogEntry {
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(971186136),
+            request_id: Some("abc"),
+            method: "GET",
+            path: "/a\"b?c=1",
+            version: Version::HTTP_11,
+            status: 200,
+            bytes_in: 0,
+            bytes_out: 2326,
+            client_addr: Some(client),
+            upstream_addr: None,
+            timings,
+            cache_status: "disabled",
+            referer: None,
+            user_agent: Some("curl/8.0"),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_format_combined() {
+        let timings = Timings::new();
+        let client = SocketAddr::Inet("127.0.0.1:1234".parse().unwrap());
+        let line = AccessLogFormat::Combined.format(&entry(&timings, &client));
+        assert_eq!(
+            line,
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /a\"b?c=1 HTTP/1.1" 200 2326 "-" "curl/8.0""#
+        );
+    }
+
+    #[test]
+    fn test_format_json() {
+        let timings = Timings::new();
+        let client = SocketAddr::Inet("127.0.0.1:1234".parse().unwrap());
+        let line = AccessLogFormat::Json.format(&entry(&timings, &client));
+        assert!(line.starts_with(
+            r#"{"time":"2000-10-10T13:55:36.000Z","client_addr":"127.0.0.1:1234","request_id":"abc","method":"GET","path":"/a\"b?c=1","version":"HTTP/1.1","status":200,"bytes_in":0,"bytes_out":2326,"upstream_addr":null,"cache_status":"disabled","upstream_connect_ms":null,"#
+        ));
+        assert!(line.ends_with(r#","referer":null,"user_agent":"curl/8.0","error":null}"#));
+        let mut escaped = String::new();
+        escape("a\u{1}\n", &mut escaped);
+        assert_eq!(escaped, "a\\u0001\\n");
+    }
+
+    #[test]
+    fn test_access_log_sink() {
+        #[derive(Clone, Default)]
+        struct Sink(Arc<Mutex<Vec<u8>>>);
+        impl Write for Sink {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let sink = Sink::default();
+        let log = AccessLog::new(AccessLogFormat::Combined, Box::new(sink.clone()));
+        let timings = Timings::new();
+        let client = SocketAddr::Inet("127.0.0.1:1234".parse().unwrap());
+        log.log(&entry(&timings, &client));
+        log.log(&entry(&timings, &client));
+        log.flush();
+        let written = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(written.lines().count(), 2);
+        assert!(written.ends_with("\"curl/8.0\"\n"));
+
+        // the buffered lines are written out when the logger is dropped
+        let path = std::env::temp_dir().join(format!("pingora-access-log-{}", std::process::id()));
+        let log = AccessLog::to_file(AccessLogFormat::Combined, &path).unwrap();
+        log.log(&entry(&timings, &client));
+        drop(log);
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written.lines().count(), 1);
+    }
+}
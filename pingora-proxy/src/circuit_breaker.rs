@@ -0,0 +1,387 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stop sending requests to the failing upstreams for a while
+//!
+//! Each upstream has a circuit which is closed (requests go through) until it fails too much. Then
+//! it opens (requests fail right away) for `open_duration`, after which it becomes half-open: a
+//! few probe requests go through. The circuit closes again once the probes succeed, or reopens
+//! once any of them fails. The probes that are not reported within another `open_duration` are
+//! given up and new ones are let through.
+
+use super::*;
+use pingora_core::protocols::l4::socket::SocketAddr;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The state of the circuit of an upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests go through
+    Closed,
+    /// Requests fail right away
+    Open,
+    /// A limited number of probe requests go through to test the recovery
+    HalfOpen,
+}
+
+/// The thresholds of the [CircuitBreaker]
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Open the circuit after this many failures in a row
+    pub consecutive_failures: usize,
+    /// Open the circuit when the ratio of the failures in a `window` reaches this, if set
+    pub error_rate: Option<f64>,
+    /// The minimum number of requests in a `window` to consider the `error_rate`
+    pub min_requests: usize,
+    /// The period to calculate the `error_rate` over
+    pub window: Duration,
+    /// How long the circuit stays open before probing the upstream
+    pub open_duration: Duration,
+    /// The number of the probe requests that have to succeed to close the circuit again
+    pub half_open_probes: usize,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            consecutive_failures: 5,
+            error_rate: None,
+            min_requests: 20,
+            window: Duration::from_secs(10),
+            open_duration: Duration::from_secs(30),
+            half_open_probes: 1,
+        }
+    }
+}
+
+struct Circuit {
+    state: CircuitState,
+    consecutive_failures: usize,
+    window_start: Instant,
+    window_requests: usize,
+    window_failures: usize,
+    // when the circuit last became open or half-open
+    changed_at: Instant,
+    // the probes sent and succeeded during half-open
+    probes: usize,
+    probe_successes: usize,
+}
+
+impl Circuit {
+    fn new(now: Instant) -> Self {
+        Circuit {
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            window_start: now,
+            window_requests: 0,
+            window_failures: 0,
+            changed_at: now,
+            probes: 0,
+            probe_successes: 0,
+        }
+    }
+
+    fn open(&mut self, now: Instant) {
+        self.state = CircuitState::Open;
+        self.changed_at = now;
+    }
+
+    fn half_open(&mut self, now: Instant) {
+        self.state = CircuitState::HalfOpen;
+        self.changed_at = now;
+        self.probes = 0;
+        self.probe_successes = 0;
+    }
+
+    fn close(&mut self, now: Instant) {
+        *self = Circuit::new(now);
+    }
+
+    // move from open to half-open once it has been open long enough, and start over the probes
+    // that are never reported, e.g., because their requests were dropped
+    fn update(&mut self, config: &CircuitBreakerConfig, now: Instant) {
+        if self.state != CircuitState::Closed && now >= self.changed_at + config.open_duration {
+            self.half_open(now);
+        }
+    }
+}
+
+/// A circuit breaker per upstream, see [ProxyHttp::circuit_breaker()]
+///
+/// The upstreams are told apart by their addresses. Only the errors from the upstreams, e.g.,
+/// failing to connect or timing out, count as failures, except when the request runs out of the
+/// time budget given by its client, see [ProxyHttp::request_deadline()]. The requests to an upstream whose circuit
+/// is open fail with the retriable `CircuitOpen` error, which is answered with `503`.
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    circuits: Mutex<HashMap<SocketAddr, Circuit>>,
+}
+
+impl CircuitBreaker {
+    /// Create a new [CircuitBreaker] with the given thresholds
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            config,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether a request can be sent to the given upstream now
+    ///
+    /// Each allowed request should be followed by a [Self::report()] of its outcome.
+    pub fn allow(&self, addr: &SocketAddr) -> bool {
+        self.allow_at(addr, Instant::now())
+    }
+
+    fn allow_at(&self, addr: &SocketAddr, now: Instant) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(addr) else {
+            return true; // never failed
+        };
+        circuit.update(&self.config, now);
+        match circuit.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                if circuit.probes < self.config.half_open_probes.max(1) {
+                    circuit.probes += 1;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// Report the outcome of a request to the given upstream
+    pub fn report(&self, addr: &SocketAddr, success: bool) {
+        self.report_at(addr, success, Instant::now())
+    }
+
+    fn report_at(&self, addr: &SocketAddr, success: bool, now: Instant) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = match circuits.get_mut(addr) {
+            Some(c) => c,
+            // nothing to track until the first failure
+            None if success => return,
+            None => circuits.entry(addr.clone()).or_insert(Circuit::new(now)),
+        };
+        circuit.update(&self.config, now);
+        match circuit.state {
+            CircuitState::Closed => {}
+            // a request allowed before the circuit opened
+            CircuitState::Open => return,
+            CircuitState::HalfOpen => {
+                if !success {
+                    circuit.open(now);
+                } else {
+                    circuit.probe_successes += 1;
+                    if circuit.probe_successes >= self.config.half_open_probes.max(1) {
+                        circuit.close(now);
+                    }
+                }
+                return;
+            }
+        }
+
+        if now >= circuit.window_start + self.config.window {
+            circuit.window_start = now;
+            circuit.window_requests = 0;
+            circuit.window_failures = 0;
+        }
+        circuit.window_requests += 1;
+        if success {
+            circuit.consecutive_failures = 0;
+            return;
+        }
+        circuit.consecutive_failures += 1;
+        circuit.window_failures += 1;
+        let rate_exceeded = self.config.error_rate.is_some_and(|rate| {
+            circuit.window_requests >= self.config.min_requests
+                && circuit.window_failures as f64 >= rate * circuit.window_requests as f64
+        });
+        if circuit.consecutive_failures >= self.config.consecutive_failures || rate_exceeded {
+            warn!("Circuit breaker opens for {addr}");
+            circuit.open(now);
+        }
+    }
+
+    // give up a request allowed to the given upstream without an outcome to report
+    fn release(&self, addr: &SocketAddr) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(addr) {
+            if circuit.state == CircuitState::HalfOpen {
+                circuit.probes = circuit.probes.saturating_sub(1);
+            }
+        }
+    }
+
+    /// The current state of the circuit of the given upstream
+    pub fn state(&self, addr: &SocketAddr) -> CircuitState {
+        let mut circuits = self.circuits.lock().unwrap();
+        circuits.get_mut(addr).map_or(CircuitState::Closed, |c| {
+            c.update(&self.config, Instant::now());
+            c.state
+        })
+    }
+
+    /// The current states of the circuits of the upstreams that have failed before
+    pub fn states(&self) -> Vec<(SocketAddr, CircuitState)> {
+        let now = Instant::now();
+        let mut circuits = self.circuits.lock().unwrap();
+        circuits
+            .iter_mut()
+            .map(|(addr, c)| {
+                c.update(&self.config, now);
+                (addr.clone(), c.state)
+            })
+            .collect()
+    }
+}
+
+impl Session {
+    // consult the circuit breaker before sending this request to the given upstream
+    pub(crate) fn check_circuit(
+        &mut self,
+        breaker: Option<Arc<CircuitBreaker>>,
+        peer: &HttpPeer,
+    ) -> Result<()> {
+        let Some(breaker) = breaker else {
+            return Ok(());
+        };
+        let addr = peer.address();
+        if !breaker.allow(addr) {
+            return Error::e_explain(CircuitOpen, format!("circuit breaker open for {addr}"))
+                .map_err(|e| e.into_up());
+        }
+        self.circuit = Some((breaker, addr.clone()));
+        Ok(())
+    }
+
+    // report the outcome of the upstream attempt to the circuit breaker
+    pub(crate) fn report_circuit(&mut self, error: Option<&Error>) {
+        let Some((breaker, addr)) = self.circuit.take() else {
+            return;
+        };
+        let failed = error.is_some_and(|e| e.esource() == &ErrorSource::Upstream);
+        // running out of the time budget of the client is not a failure of the upstream
+        if failed && self.deadline_expired() {
+            breaker.release(&addr);
+            return;
+        }
+        breaker.report(&addr, !failed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:80".parse().unwrap()
+    }
+
+    #[test]
+    fn test_consecutive_failures() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            consecutive_failures: 3,
+            open_duration: Duration::from_secs(10),
+            half_open_probes: 2,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let addr = addr();
+        breaker.report_at(&addr, false, now);
+        breaker.report_at(&addr, false, now);
+        breaker.report_at(&addr, true, now);
+        breaker.report_at(&addr, false, now);
+        breaker.report_at(&addr, false, now);
+        assert_eq!(breaker.state(&addr), CircuitState::Closed);
+        breaker.report_at(&addr, false, now);
+        assert_eq!(breaker.state(&addr), CircuitState::Open);
+        assert!(!breaker.allow_at(&addr, now + Duration::from_secs(5)));
+
+        // half-open: 2 probes
+        let later = now + Duration::from_secs(10);
+        assert!(breaker.allow_at(&addr, later));
+        assert!(breaker.allow_at(&addr, later));
+        assert!(!breaker.allow_at(&addr, later));
+        breaker.report_at(&addr, true, later);
+        breaker.report_at(&addr, false, later);
+        // reopened
+        assert!(!breaker.allow_at(&addr, later));
+
+        let later = later + Duration::from_secs(10);
+        assert!(breaker.allow_at(&addr, later));
+        assert!(breaker.allow_at(&addr, later));
+        breaker.report_at(&addr, true, later);
+        breaker.report_at(&addr, true, later);
+        assert!(breaker.allow_at(&addr, later));
+        assert!(breaker.allow_at(&addr, later));
+        assert!(breaker.allow_at(&addr, later));
+    }
+
+    #[test]
+    fn test_unreported_probes() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            consecutive_failures: 1,
+            open_duration: Duration::from_secs(10),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let addr = addr();
+        breaker.report_at(&addr, false, now);
+        let later = now + Duration::from_secs(10);
+        assert!(breaker.allow_at(&addr, later));
+        assert!(!breaker.allow_at(&addr, later));
+        // a probe given up without an outcome lets another one through
+        breaker.release(&addr);
+        assert!(breaker.allow_at(&addr, later));
+        assert!(!breaker.allow_at(&addr, later + Duration::from_secs(5)));
+        // the probe is never reported, so a new one goes through after a while
+        let later = later + Duration::from_secs(10);
+        assert!(breaker.allow_at(&addr, later));
+        assert!(!breaker.allow_at(&addr, later));
+        breaker.report_at(&addr, true, later);
+        assert_eq!(breaker.state(&addr), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_error_rate() {
+        let breaker = CircuitBreaker::new(CircuitBreakerConfig {
+            consecutive_failures: usize::MAX,
+            error_rate: Some(0.5),
+            min_requests: 4,
+            window: Duration::from_secs(10),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let addr = addr();
+        breaker.report_at(&addr, false, now);
+        breaker.report_at(&addr, true, now);
+        breaker.report_at(&addr, false, now);
+        assert!(breaker.allow_at(&addr, now));
+        // a new window
+        let later = now + Duration::from_secs(10);
+        breaker.report_at(&addr, true, later);
+        breaker.report_at(&addr, true, later);
+        breaker.report_at(&addr, false, later);
+        assert!(breaker.allow_at(&addr, later));
+        breaker.report_at(&addr, false, later);
+        assert!(!breaker.allow_at(&addr, later));
+        assert_eq!(breaker.states(), vec![(addr, CircuitState::Open)]);
+    }
+}
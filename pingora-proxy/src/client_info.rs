@@ -0,0 +1,97 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Information about the downstream connection of a request
+
+use pingora_core::protocols::l4::socket::SocketAddr;
+use pingora_core::protocols::ssl::digest::SslDigest;
+use pingora_core::protocols::Digest;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The socket and TLS information of the client connection, see [crate::Session::client_info()]
+///
+/// The addresses are the ones recorded in the socket digest of the connection. They are the
+/// addresses the client claims, e.g., via the PROXY protocol, when the listener records those
+/// instead of the ones of the accepted socket.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    /// The client (peer) address
+    pub client_addr: Option<SocketAddr>,
+    /// The server (local) address the client connected to
+    pub server_addr: Option<SocketAddr>,
+    /// The TLS information, `None` if the connection is plaintext
+    pub ssl: Option<Arc<SslDigest>>,
+    /// The smoothed round trip time measured by the kernel when this info is created, `None` if
+    /// the connection is not TCP or the platform doesn't provide it
+    pub rtt: Option<Duration>,
+}
+
+impl ClientInfo {
+    pub(crate) fn from_digest(digest: &Digest) -> Self {
+        let socket = digest.socket_digest.as_ref();
+        ClientInfo {
+            client_addr: socket.and_then(|s| s.peer_addr()).cloned(),
+            server_addr: socket.and_then(|s| s.local_addr()).cloned(),
+            ssl: digest.ssl_digest.clone(),
+            rtt: socket.and_then(|s| s.rtt()),
+        }
+    }
+
+    /// Whether the connection is over TLS
+    pub fn is_tls(&self) -> bool {
+        self.ssl.is_some()
+    }
+
+    /// The negotiated TLS version, e.g., `TLSv1.3`
+    pub fn tls_version(&self) -> Option<&str> {
+        self.ssl.as_ref().map(|s| s.version)
+    }
+
+    /// The negotiated TLS cipher
+    pub fn tls_cipher(&self) -> Option<&str> {
+        self.ssl.as_ref().map(|s| s.cipher)
+    }
+
+    /// The SNI the client sent
+    pub fn sni(&self) -> Option<&str> {
+        self.ssl.as_ref().and_then(|s| s.sni.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_core::protocols::SocketDigest;
+    use std::os::unix::io::AsRawFd;
+
+    #[tokio::test]
+    async fn test_from_digest() {
+        assert!(ClientInfo::from_digest(&Digest::default())
+            .client_addr
+            .is_none());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let digest = Digest {
+            socket_digest: Some(Arc::new(SocketDigest::from_raw_fd(client.as_raw_fd()))),
+            ..Default::default()
+        };
+        let info = ClientInfo::from_digest(&digest);
+        assert_eq!(info.client_addr.as_ref().unwrap().as_inet(), Some(&addr));
+        assert!(!info.is_tls());
+        assert!(info.sni().is_none());
+    }
+}
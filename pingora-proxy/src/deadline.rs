@@ -0,0 +1,251 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The time budget of a request given by the client
+
+use super::*;
+use http::header::HeaderName;
+use pingora_timeout::Deadline;
+use std::time::Duration;
+
+/// The format of the value of the deadline header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadlineFormat {
+    /// The time budget in milliseconds, e.g., `1500`
+    Milliseconds,
+    /// The `grpc-timeout` format: at most 8 digits followed by the unit `H`, `M`, `S`, `m`, `u` or
+    /// `n`, e.g., `1500m`
+    GrpcTimeout,
+}
+
+impl DeadlineFormat {
+    /// Parse the header value into a time budget
+    pub fn parse(&self, value: &str) -> Option<Duration> {
+        match self {
+            Self::Milliseconds => value.trim().parse().ok().map(Duration::from_millis),
+            Self::GrpcTimeout => parse_grpc_timeout(value.trim()),
+        }
+    }
+
+    /// Format the time budget as a header value, rounding down to the precision of the format
+    pub fn format(&self, budget: Duration) -> String {
+        match self {
+            Self::Milliseconds => budget.as_millis().to_string(),
+            Self::GrpcTimeout => format_grpc_timeout(budget),
+        }
+    }
+}
+
+// https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-HTTP2.md
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let n: u64 = digits.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(n * 3600),
+        "M" => Duration::from_secs(n * 60),
+        "S" => Duration::from_secs(n),
+        "m" => Duration::from_millis(n),
+        "u" => Duration::from_micros(n),
+        "n" => Duration::from_nanos(n),
+        _ => return None,
+    })
+}
+
+fn format_grpc_timeout(budget: Duration) -> String {
+    const MAX: u128 = 99_999_999;
+    // the finest unit that fits in 8 digits
+    let nanos = budget.as_nanos();
+    for (unit, nanos_per_unit) in [
+        ('n', 1),
+        ('u', 1_000),
+        ('m', 1_000_000),
+        ('S', 1_000_000_000),
+        ('M', 60_000_000_000),
+        ('H', 3_600_000_000_000),
+    ] {
+        let n = nanos / nanos_per_unit;
+        if n <= MAX {
+            return format!("{n}{unit}");
+        }
+    }
+    format!("{MAX}H")
+}
+
+/// The config to derive the deadline of each request from its header, see
+/// [ProxyHttp::request_deadline()]
+///
+/// The deadline limits the total time of proxying to the upstream, including the connection,
+/// all the retries and the transfer of the response. Once it passes, the request fails with
+/// `504`. The remaining budget is forwarded to the upstream in the same header so that it can give
+/// up in time as well.
+#[derive(Debug, Clone)]
+pub struct DeadlineConfig {
+    /// The header that carries the time budget, `X-Request-Deadline` by default
+    pub header: HeaderName,
+    /// The format of the header value
+    pub format: DeadlineFormat,
+    /// The budget when the request doesn't have a valid header. `None` to only rely on the
+    /// configured timeouts for such requests.
+    pub default_budget: Option<Duration>,
+    /// The cap of the budget the client asks for
+    pub max_budget: Option<Duration>,
+    /// Whether to forward the remaining budget to the upstream
+    pub forward: bool,
+}
+
+impl Default for DeadlineConfig {
+    fn default() -> Self {
+        DeadlineConfig {
+            header: HeaderName::from_static("x-request-deadline"),
+            format: DeadlineFormat::Milliseconds,
+            default_budget: None,
+            max_budget: None,
+            forward: true,
+        }
+    }
+}
+
+impl DeadlineConfig {
+    /// Create a new [DeadlineConfig] with the given header and format
+    pub fn new(header: HeaderName, format: DeadlineFormat) -> Self {
+        DeadlineConfig {
+            header,
+            format,
+            ..Default::default()
+        }
+    }
+
+    /// Create a new [DeadlineConfig] that follows the `grpc-timeout` header
+    pub fn grpc() -> Self {
+        Self::new(
+            HeaderName::from_static("grpc-timeout"),
+            DeadlineFormat::GrpcTimeout,
+        )
+    }
+
+    // the budget of the request
+    fn budget(&self, req: &RequestHeader) -> Option<Duration> {
+        let requested = req.headers.get(&self.header).and_then(|v| {
+            let budget = v.to_str().ok().and_then(|v| self.format.parse(v));
+            if budget.is_none() {
+                debug!("invalid deadline header {:?}", v);
+            }
+            budget
+        });
+        match (requested, self.max_budget) {
+            (Some(budget), Some(max)) => Some(budget.min(max)),
+            (Some(budget), None) => Some(budget),
+            (None, _) => self.default_budget,
+        }
+    }
+}
+
+// the error when the deadline of the request passes
+pub(crate) fn deadline_exceeded() -> Box<Error> {
+    Error::explain(ReadTimedout, "request deadline exceeded").into_up()
+}
+
+impl Session {
+    // derive the deadline of this request, counting from when its header was read
+    pub(crate) fn init_deadline(&mut self, config: Arc<DeadlineConfig>) {
+        let Some(budget) = config.budget(self.req_header()) else {
+            return;
+        };
+        let budget = budget.saturating_sub(self.timings.request_start.elapsed());
+        self.deadline = Some((Deadline::new(budget), config));
+    }
+
+    // set the remaining budget on the upstream request
+    pub(crate) fn forward_deadline(&mut self) {
+        let Some((deadline, config)) = self.deadline.as_ref() else {
+            return;
+        };
+        let Some(remaining) = deadline.remaining().filter(|_| config.forward) else {
+            return;
+        };
+        let value = config.format.format(remaining);
+        let header = config.header.clone();
+        // always a valid header value
+        let _ = self.req_header_mut().insert_header(header, value);
+    }
+
+    // whether the deadline of this request has passed
+    pub(crate) fn deadline_expired(&self) -> bool {
+        self.deadline.as_ref().is_some_and(|(d, _)| d.is_expired())
+    }
+
+    /// The deadline of this request, if [ProxyHttp::request_deadline()] is configured and the
+    /// request has a budget
+    pub fn deadline(&self) -> Option<&Deadline> {
+        self.deadline.as_ref().map(|(d, _)| d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grpc_timeout() {
+        let f = DeadlineFormat::GrpcTimeout;
+        assert_eq!(f.parse("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(f.parse("2M"), Some(Duration::from_secs(120)));
+        assert_eq!(f.parse("3S"), Some(Duration::from_secs(3)));
+        assert_eq!(f.parse("1500m"), Some(Duration::from_millis(1500)));
+        assert_eq!(f.parse("10u"), Some(Duration::from_micros(10)));
+        assert_eq!(f.parse("99999999n"), Some(Duration::from_nanos(99999999)));
+        assert_eq!(f.parse("123456789m"), None); // more than 8 digits
+        assert_eq!(f.parse("m"), None);
+        assert_eq!(f.parse("10"), None);
+        assert_eq!(f.parse("10x"), None);
+        assert_eq!(f.parse("-1m"), None);
+
+        assert_eq!(f.format(Duration::from_nanos(10)), "10n");
+        assert_eq!(f.format(Duration::from_millis(1500)), "1500000u");
+        assert_eq!(f.format(Duration::from_secs(1000)), "1000000m");
+        assert_eq!(f.format(Duration::from_secs(3600 * 100_000)), "6000000M");
+        for d in [
+            Duration::from_millis(1234),
+            Duration::from_secs(7200),
+            Duration::from_nanos(1),
+        ] {
+            let parsed = f.parse(&f.format(d)).unwrap();
+            assert!(parsed <= d);
+        }
+    }
+
+    #[test]
+    fn test_budget() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        let config = DeadlineConfig::default();
+        assert_eq!(config.budget(&req), None);
+        req.insert_header("X-Request-Deadline", "250").unwrap();
+        assert_eq!(config.budget(&req), Some(Duration::from_millis(250)));
+
+        let config = DeadlineConfig {
+            default_budget: Some(Duration::from_secs(5)),
+            max_budget: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        assert_eq!(config.budget(&req), Some(Duration::from_millis(100)));
+        req.insert_header("X-Request-Deadline", "soon").unwrap();
+        assert_eq!(config.budget(&req), Some(Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Handle `Expect: 100-continue` of the requests
+
+use super::*;
+
+/// How to answer a request with `Expect: 100-continue`, see [ProxyHttp::expect_continue_filter()]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpectContinue {
+    /// Forward the expectation to the upstream and relay its interim `100 Continue`, if any, to
+    /// the client
+    Forward,
+    /// Send `100 Continue` to the client right away and remove the expectation from the request
+    /// to the upstream
+    Accept,
+    /// Reject the request with the given status code, e.g., 417 or 413, without reading its body.
+    /// The downstream connection is not reused because the client might send the body anyway.
+    Reject(u16),
+}
+
+// https://www.rfc-editor.org/rfc/rfc9110#name-expect
+pub(crate) fn expects_continue(req: &RequestHeader) -> bool {
+    // a server must ignore the expectation of HTTP/1.0 requests
+    req.version != Version::HTTP_10
+        && req.version != Version::HTTP_09
+        && req
+            .headers
+            .get(header::EXPECT)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
+
+impl<SV> HttpProxy<SV> {
+    // answer `Expect: 100-continue` if the request has it, return whether the request is rejected
+    pub(crate) async fn handle_expect_continue(
+        &self,
+        session: &mut Session,
+        ctx: &mut SV::CTX,
+    ) -> Result<bool>
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        if !expects_continue(session.req_header()) {
+            return Ok(false);
+        }
+        match self.inner.expect_continue_filter(session, ctx).await? {
+            ExpectContinue::Forward => Ok(false),
+            ExpectContinue::Accept => {
+                session.req_header_mut().remove_header(&header::EXPECT);
+                // the client may have already started sending the body without waiting, which
+                // is fine because the 100 is just an interim response
+                session.write_continue_response().await?;
+                Ok(false)
+            }
+            ExpectContinue::Reject(status) => {
+                // this also stops the reuse of the downstream connection
+                session.respond_error(status).await;
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expects_continue() {
+        let mut req = RequestHeader::build("POST", b"/", None).unwrap();
+        assert!(!expects_continue(&req));
+        req.insert_header("Expect", "100-Continue").unwrap();
+        assert!(expects_continue(&req));
+        req.set_version(Version::HTTP_10);
+        assert!(!expects_continue(&req));
+        req.set_version(Version::HTTP_2);
+        assert!(expects_continue(&req));
+        req.insert_header("Expect", "something-else").unwrap();
+        assert!(!expects_continue(&req));
+    }
+}
@@ -47,42 +47,75 @@ use once_cell::sync::Lazy;
 use pingora_http::{RequestHeader, ResponseHeader};
 use std::fmt::Debug;
 use std::str;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use tokio::sync::{mpsc, Notify};
 use tokio::time;
 
 use pingora_cache::NoCacheReason;
 use pingora_core::apps::HttpServerApp;
-use pingora_core::connectors::{http::Connector, ConnectorOptions};
+use pingora_core::connectors::{http::Connector, ConnectorOptions, TransportConnector};
 use pingora_core::protocols::http::client::HttpSession as ClientSession;
 use pingora_core::protocols::http::v1::client::HttpSession as HttpSessionV1;
+use pingora_core::protocols::http::v1::common::header_value_content_length;
+use pingora_core::protocols::http::v2::server::H2Options;
+use pingora_core::protocols::http::v2::settings::H2Settings;
 use pingora_core::protocols::http::HttpTask;
 use pingora_core::protocols::http::ServerSession as HttpSession;
 use pingora_core::protocols::http::SERVER_NAME;
+use pingora_core::protocols::l4::socket::SocketAddr;
 use pingora_core::protocols::Stream;
-use pingora_core::protocols::{Digest, UniqueID};
-use pingora_core::server::configuration::ServerConf;
+use pingora_core::protocols::{Digest, UniqueID, ALPN};
+use pingora_core::server::configuration::{OversizedRequestBody, ServerConf};
 use pingora_core::server::ShutdownWatch;
 use pingora_core::upstreams::peer::{HttpPeer, Peer};
-use pingora_error::{Error, ErrorSource, ErrorType::*, OrErr, Result};
+use pingora_error::{Error, ErrorSource, ErrorType::*, OrErr, RequestProgress, Result};
 
 const MAX_RETRIES: usize = 16;
 const TASK_BUFFER_SIZE: usize = 4;
 
+mod access_log;
+mod circuit_breaker;
+mod client_info;
+mod deadline;
+mod expect_continue;
+mod mirror;
+#[cfg(feature = "opentelemetry")]
+mod otel;
 mod proxy_cache;
 mod proxy_common;
 mod proxy_h1;
 mod proxy_h2;
 mod proxy_purge;
 mod proxy_trait;
+mod rate_limit;
+mod request_id;
+mod retry;
 mod subrequest;
+mod timing;
+mod trailers;
+mod tunnel;
+mod upstream_override;
 
 use subrequest::Ctx as SubReqCtx;
 
+pub use access_log::{AccessLog, AccessLogEntry, AccessLogFormat, AccessLogger};
+pub use circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitState};
+pub use client_info::ClientInfo;
+pub use deadline::{DeadlineConfig, DeadlineFormat};
+pub use expect_continue::ExpectContinue;
+pub use mirror::Mirror;
+#[cfg(feature = "opentelemetry")]
+pub use otel::Tracing;
 pub use proxy_trait::ProxyHttp;
+pub use rate_limit::{KeyFn, RateLimit, RateLimitKey};
+pub use request_id::{RequestIdConfig, RequestIdGenerator};
+pub use retry::{RetryBudget, RetryPolicy};
+pub use timing::Timings;
+pub use upstream_override::{parse_upstream, OverrideTrust, UpstreamOverride};
 
 pub mod prelude {
-    pub use crate::{http_proxy_service, ProxyHttp, Session};
+    pub use crate::{http_proxy_service, ProxyHttp, RetryPolicy, Session};
 }
 
 /// The concrete type that holds the user defined HTTP proxy.
@@ -91,7 +124,13 @@ pub mod prelude {
 pub struct HttpProxy<SV> {
     inner: SV, // TODO: name it better than inner
     client_upstream: Connector,
+    client_tunnel: TransportConnector,
     shutdown: Notify,
+    max_requests_per_connection: Option<usize>,
+    h2_settings: Option<H2Settings>,
+    max_buffered_request_body: Option<usize>,
+    oversized_request_body: OversizedRequestBody,
+    mirror_inflight: AtomicUsize,
 }
 
 impl<SV> HttpProxy<SV> {
@@ -99,10 +138,26 @@ impl<SV> HttpProxy<SV> {
         Arc::new(HttpProxy {
             inner,
             client_upstream: Connector::new(Some(ConnectorOptions::from_server_conf(&conf))),
+            client_tunnel: TransportConnector::new(Some(ConnectorOptions::from_server_conf(&conf))),
             shutdown: Notify::new(),
+            max_requests_per_connection: conf.max_requests_per_connection,
+            h2_settings: conf.h2_settings.clone(),
+            max_buffered_request_body: conf.max_buffered_request_body,
+            oversized_request_body: conf.oversized_request_body,
+            mirror_inflight: AtomicUsize::new(0),
         })
     }
 
+    /// Pool connections to the given upstream ahead of time so that the first requests to it
+    /// don't pay for the connection setup, e.g., from a background service at startup.
+    ///
+    /// The `peer` should be the same as what [ProxyHttp::upstream_peer()] returns for the
+    /// upstream, otherwise the pooled connections won't be reused. See
+    /// [Connector::warm_up()] for the details.
+    pub async fn warm_up(&self, peer: &HttpPeer, count: usize) -> Result<usize> {
+        self.client_upstream.warm_up(peer, count).await
+    }
+
     async fn handle_new_request(
         &self,
         mut downstream_session: Box<HttpSession>,
@@ -143,9 +198,48 @@ impl<SV> HttpProxy<SV> {
             "Request header: {:?}",
             downstream_session.req_header().as_ref()
         );
+        if let Some(limit) = self.max_buffered_request_body {
+            downstream_session.set_retry_buffer_limit(limit);
+        }
         Some(downstream_session)
     }
 
+    // respond 413 right away to the request whose Content-Length is already larger than the
+    // buffer if it is configured to be rejected, return whether it is rejected
+    async fn reject_request_content_length(&self, session: &mut Session) -> bool {
+        if self.oversized_request_body != OversizedRequestBody::Reject {
+            return false;
+        }
+        let limit = session.downstream_session.retry_buffer_limit();
+        match request_content_length(session) {
+            Some(length) if length > limit => {
+                debug!("Rejecting the request body of {length} bytes, larger than {limit}");
+                // this also stops the reuse of the downstream connection
+                session.respond_error(413).await;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // fail the request whose body outgrows the buffer if it is configured to be rejected
+    //
+    // The ones with a Content-Length are already rejected up front, so this only catches the
+    // bodies of unknown length, e.g., chunked ones, as they are proxied.
+    fn check_request_body_size(&self, session: &Session) -> Result<()> {
+        if self.oversized_request_body == OversizedRequestBody::Reject
+            && session.is_request_body_streamed()
+            && request_content_length(session).is_none()
+        {
+            return Error::e_explain(
+                HTTPStatus(413),
+                "request body larger than max_buffered_request_body",
+            )
+            .map_err(|e| e.into_down());
+        }
+        Ok(())
+    }
+
     // return bool: server_session can be reused, and error if any
     async fn proxy_to_upstream(
         &self,
@@ -156,14 +250,27 @@ impl<SV> HttpProxy<SV> {
         SV: ProxyHttp + Send + Sync,
         SV::CTX: Send + Sync,
     {
-        let peer = match self.inner.upstream_peer(session, ctx).await {
+        let mut peer = match self.inner.upstream_peer(session, ctx).await {
             Ok(p) => p,
             Err(e) => return (false, Some(e)),
         };
+        session.timings.upstream_started();
+        session.upstream_addr = Some(peer.address().clone());
+        if let Err(e) = session.check_circuit(self.inner.circuit_breaker(), &peer) {
+            return (false, Some(e));
+        }
+        session.forward_deadline();
+        #[cfg(feature = "opentelemetry")]
+        session.start_upstream_trace(&peer);
+        // h2 has no Upgrade, so upgrade requests, e.g. WebSocket, have to go through h1
+        if session.is_upgrade_req() && matches!(peer.options.alpn, ALPN::H2H1) {
+            peer.options.alpn = ALPN::H1;
+        }
 
         let client_session = self.client_upstream.get_http_session(&*peer).await;
         match client_session {
             Ok((client_session, client_reused)) => {
+                session.timings.upstream_connected();
                 let (server_reused, error) = match client_session {
                     ClientSession::H1(mut h1) => {
                         let (server_reused, client_reuse, error) = self
@@ -199,7 +306,7 @@ impl<SV> HttpProxy<SV> {
                                     self.client_upstream.prefer_h1(&*peer);
                                 } else {
                                     // the peer doesn't allow downgrading to h1 (e.g. gRPC)
-                                    e.retry = false.into();
+                                    e.set_retry(false);
                                 }
                             }
                         }
@@ -222,6 +329,74 @@ impl<SV> HttpProxy<SV> {
         }
     }
 
+    // proxy_to_upstream() within the time limit of each attempt of the retry policy, if any
+    async fn proxy_to_upstream_attempt(
+        &self,
+        session: &mut Session,
+        ctx: &mut SV::CTX,
+    ) -> (bool, Option<Box<Error>>)
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        let limit = session
+            .retry_policy
+            .as_ref()
+            .and_then(|p| p.attempt_timeout);
+        let attempt_timed_out = |limit: Option<std::time::Duration>| {
+            let e = Error::explain(
+                ReadTimedout,
+                format!("upstream attempt timed out after {limit:?}"),
+            );
+            (false, Some(e.into_up()))
+        };
+        match (session.deadline().cloned(), limit) {
+            (None, None) => self.proxy_to_upstream(session, ctx).await,
+            (None, Some(limit)) => {
+                match pingora_timeout::timeout(limit, self.proxy_to_upstream(session, ctx))
+                    .label("upstream_attempt")
+                    .await
+                {
+                    Ok(r) => r,
+                    Err(_) => attempt_timed_out(Some(limit)),
+                }
+            }
+            (Some(deadline), limit) => {
+                match deadline
+                    .timeout_with(limit, self.proxy_to_upstream(session, ctx))
+                    .await
+                {
+                    Ok(r) => r,
+                    Err(_) if deadline.is_expired() => (false, Some(deadline::deadline_exceeded())),
+                    Err(_) => attempt_timed_out(limit),
+                }
+            }
+        }
+    }
+
+    // early_request_filter(), the rate limit, request_filter(), the request body size then
+    // answering `Expect: 100-continue`, return true if a response is already sent
+    async fn request_filters(&self, session: &mut Session, ctx: &mut SV::CTX) -> Result<bool>
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        if self.inner.early_request_filter(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_rate_limit(session).await? {
+            return Ok(true);
+        }
+        if self.inner.request_filter(session, ctx).await? {
+            return Ok(true);
+        }
+        // before the client is told to send the body, and before connecting to the upstream
+        if self.reject_request_content_length(session).await {
+            return Ok(true);
+        }
+        self.handle_expect_continue(session, ctx).await
+    }
+
     fn upstream_filter(&self, session: &mut Session, task: &mut HttpTask, ctx: &mut SV::CTX)
     where
         SV: ProxyHttp,
@@ -250,7 +425,7 @@ impl<SV> HttpProxy<SV> {
         SV: ProxyHttp + Send + Sync,
         SV::CTX: Send + Sync,
     {
-        self.inner.logging(&mut session, error, ctx).await;
+        self.log_request(&mut session, error, ctx).await;
 
         if reuse {
             // TODO: log error
@@ -262,7 +437,7 @@ impl<SV> HttpProxy<SV> {
 }
 
 use pingora_cache::HttpCache;
-use pingora_core::protocols::http::compression::ResponseCompressionCtx;
+use pingora_core::protocols::http::compression::{CompressionConfig, ResponseCompressionCtx};
 
 /// The established HTTP session
 ///
@@ -281,6 +456,27 @@ pub struct Session {
     pub ignore_downstream_range: bool,
     // the context from parent request
     subrequest_ctx: Option<Box<SubReqCtx>>,
+    // the retry policy of this request, if any
+    retry_policy: Option<Arc<RetryPolicy>>,
+    // the number of attempts to proxy to upstream so far
+    upstream_attempts: usize,
+    // the shadow upstream to send a copy of this request to after it finishes
+    mirror: Option<Box<mirror::PendingMirror>>,
+    // the timestamps of the phases of this request
+    timings: Timings,
+    // the address of the upstream of the last attempt
+    upstream_addr: Option<SocketAddr>,
+    // the ID of this request and its config
+    request_id: Option<(String, Arc<RequestIdConfig>)>,
+    // the deadline of this request and its config
+    deadline: Option<(pingora_timeout::Deadline, Arc<DeadlineConfig>)>,
+    // the circuit breaker to report the outcome of the ongoing upstream attempt to
+    circuit: Option<(Arc<CircuitBreaker>, SocketAddr)>,
+    // the trailers to add to the response
+    response_trailers: Option<Box<http::HeaderMap>>,
+    // the OpenTelemetry spans of this request
+    #[cfg(feature = "opentelemetry")]
+    trace: Option<Box<otel::RequestTrace>>,
 }
 
 impl Session {
@@ -292,6 +488,17 @@ impl Session {
             downstream_compression: ResponseCompressionCtx::new(0, false), // disable both
             ignore_downstream_range: false,
             subrequest_ctx: None,
+            retry_policy: None,
+            upstream_attempts: 0,
+            mirror: None,
+            timings: Timings::new(),
+            upstream_addr: None,
+            request_id: None,
+            deadline: None,
+            circuit: None,
+            response_trailers: None,
+            #[cfg(feature = "opentelemetry")]
+            trace: None,
         }
     }
 
@@ -309,6 +516,101 @@ impl Session {
     pub fn as_downstream(&self) -> &HttpSession {
         &self.downstream_session
     }
+
+    /// Return the socket and TLS information of the client connection in one call
+    ///
+    /// The RTT is read from the kernel on each call.
+    pub fn client_info(&self) -> ClientInfo {
+        self.downstream_session
+            .digest()
+            .map(ClientInfo::from_digest)
+            .unwrap_or_default()
+    }
+
+    /// Return the timestamps of the phases of this request so far
+    ///
+    /// In [ProxyHttp::logging()], the response is already finished.
+    pub fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    /// Return the address of the upstream that the last attempt of this request goes to, if any
+    pub fn upstream_addr(&self) -> Option<&SocketAddr> {
+        self.upstream_addr.as_ref()
+    }
+
+    /// Whether this request is upgraded, e.g., to WebSocket, i.e., `101 Switching Protocols` is
+    /// sent to downstream. The bytes of both sides are relayed as is afterwards.
+    pub fn is_upgraded(&self) -> bool {
+        self.is_upgrade_req() && self.response_written().is_some_and(|r| r.status == 101)
+    }
+
+    // whether the final response header is sent to downstream. Interim 1xx responses such as
+    // `103 Early Hints` don't count as they don't stop another response from being sent, unlike
+    // `101 Switching Protocols`.
+    pub(crate) fn response_started(&self) -> bool {
+        self.response_written()
+            .is_some_and(|r| r.status == 101 || !r.status.is_informational())
+    }
+
+    /// The number of attempts made to proxy this request to the upstream so far, including the
+    /// ongoing one
+    pub fn upstream_attempts(&self) -> usize {
+        self.upstream_attempts
+    }
+
+    /// Whether the request body outgrew `max_buffered_request_body` of the server conf so that it
+    /// is streamed to the upstream without a copy kept. Such a request can no longer be retried
+    /// or mirrored.
+    ///
+    /// This is only decided as the body is being proxied, so it is `false` before that.
+    pub fn is_request_body_streamed(&self) -> bool {
+        self.downstream_session.retry_buffer_truncated()
+    }
+
+    // whether the request can be sent to upstream again: either it has no body or its body is
+    // fully kept in the retry buffer
+    fn request_replayable(&mut self) -> bool {
+        self.downstream_session.is_body_empty()
+            || self.downstream_session.get_retry_buffer().is_some()
+    }
+
+    // whether the retry policy of this request retries this error, on top of error.retry()
+    fn retry_on_error(&mut self, e: &Error) -> bool {
+        let Some(policy) = self.retry_policy.clone() else {
+            return false;
+        };
+        let progress = RequestProgress {
+            response_started: self.response_started(),
+            idempotent: retry::is_idempotent(&self.req_header().method),
+        };
+        policy.should_retry(e, progress) && self.request_replayable()
+    }
+
+    // fail the upstream response whose status is retried by the retry policy of this request
+    // so that it is neither cached nor sent to downstream
+    fn check_retry_status(&mut self, task: &HttpTask) -> Result<()> {
+        let HttpTask::Header(header, _) = task else {
+            return Ok(());
+        };
+        let status = header.status.as_u16();
+        let retry = self
+            .retry_policy
+            .as_ref()
+            .is_some_and(|p| self.upstream_attempts < p.attempts() && p.retry_on_status(status));
+        if !retry || !self.request_replayable() {
+            return Ok(());
+        }
+        // the response is passed on as is once the budget is exhausted
+        let budget = self.retry_policy.as_ref().and_then(|p| p.budget.as_ref());
+        if budget.is_some_and(|b| !b.try_retry()) {
+            warn!("Retry budget exhausted, not retrying upstream response status {status}");
+            return Ok(());
+        }
+        let mut e = Error::explain(HTTPStatus(status), "retry on upstream response status");
+        e.set_retry(true);
+        Err(e.into_up())
+    }
 }
 
 impl Session {
@@ -319,7 +621,20 @@ impl Session {
         tasks
             .iter_mut()
             .for_each(|t| self.downstream_compression.response_filter(t));
-        self.downstream_session.response_duplex_vec(tasks).await
+        for task in tasks.iter_mut() {
+            if let HttpTask::Header(header, _) = task {
+                self.echo_request_id(header);
+            }
+        }
+        let started = tasks.iter().any(timing::is_final_header);
+        let done = self.downstream_session.response_duplex_vec(tasks).await?;
+        if started {
+            self.timings.response_started();
+        }
+        if done {
+            self.timings.response_finished();
+        }
+        Ok(done)
     }
 }
 
@@ -351,6 +666,11 @@ impl DerefMut for Session {
     }
 }
 
+// the Content-Length of the request, if any
+fn request_content_length(session: &Session) -> Option<usize> {
+    header_value_content_length(session.req_header().headers.get(header::CONTENT_LENGTH))
+}
+
 // generic HTTP 502 response sent when proxy_upstream_filter refuses to connect to upstream
 static BAD_GATEWAY: Lazy<ResponseHeader> = Lazy::new(|| {
     let mut resp = ResponseHeader::build(http::StatusCode::BAD_GATEWAY, Some(3)).unwrap();
@@ -373,11 +693,25 @@ impl<SV> HttpProxy<SV> {
         SV: ProxyHttp + Send + Sync + 'static,
         <SV as ProxyHttp>::CTX: Send + Sync,
     {
-        match self.inner.request_filter(&mut session, &mut ctx).await {
+        if let Some(config) = self.inner.response_compression() {
+            session.downstream_compression = ResponseCompressionCtx::with_config(config);
+        }
+        if let Some(config) = self.inner.request_id() {
+            session.init_request_id(config);
+        }
+        if let Some(config) = self.inner.request_deadline() {
+            session.init_deadline(config);
+        }
+        #[cfg(feature = "opentelemetry")]
+        if let Some(tracing) = self.inner.tracing() {
+            session.start_trace(tracing);
+        }
+
+        match self.request_filters(&mut session, &mut ctx).await {
             Ok(response_sent) => {
                 if response_sent {
                     // TODO: log error
-                    self.inner.logging(&mut session, None, &mut ctx).await;
+                    self.log_request(&mut session, None, &mut ctx).await;
                     return session.downstream_session.finish().await.ok().flatten();
                 }
                 /* else continue */
@@ -391,7 +725,28 @@ impl<SV> HttpProxy<SV> {
                     );
                 }
                 self.inner.fail_to_proxy(&mut session, &e, &mut ctx).await;
-                self.inner.logging(&mut session, Some(&e), &mut ctx).await;
+                self.log_request(&mut session, Some(&e), &mut ctx).await;
+                return None;
+            }
+        }
+
+        match self.proxy_connect(&mut session, &mut ctx).await {
+            Ok(true) => {
+                // the connection is taken over by the tunnel, it can't be reused
+                self.log_request(&mut session, None, &mut ctx).await;
+                return None;
+            }
+            Ok(false) => {} // not tunneled, continue
+            Err(e) => {
+                if !self.inner.suppress_error_log(&session, &ctx, &e) {
+                    error!(
+                        "Fail to tunnel request: {}, {}",
+                        e,
+                        self.inner.request_summary(&session, &ctx)
+                    );
+                }
+                self.inner.fail_to_proxy(&mut session, &e, &mut ctx).await;
+                self.log_request(&mut session, Some(&e), &mut ctx).await;
                 return None;
             }
         }
@@ -430,7 +785,7 @@ impl<SV> HttpProxy<SV> {
                                     );
                                 }
                                 self.inner.fail_to_proxy(&mut session, &e, &mut ctx).await;
-                                self.inner.logging(&mut session, Some(&e), &mut ctx).await;
+                                self.log_request(&mut session, Some(&e), &mut ctx).await;
                                 return None;
                             }
                         }
@@ -449,7 +804,7 @@ impl<SV> HttpProxy<SV> {
                     );
                 }
                 self.inner.fail_to_proxy(&mut session, &e, &mut ctx).await;
-                self.inner.logging(&mut session, Some(&e), &mut ctx).await;
+                self.log_request(&mut session, Some(&e), &mut ctx).await;
                 return None;
             }
         }
@@ -459,15 +814,75 @@ impl<SV> HttpProxy<SV> {
         let mut server_reuse = false;
         let mut proxy_error: Option<Box<Error>> = None;
 
-        while retries < MAX_RETRIES {
+        self.start_mirror(&mut session, &ctx);
+
+        if let Some(hints) = self.inner.early_hints(&session, &ctx) {
+            if hints.status == 103 {
+                // best effort: a broken downstream fails the request later anyway
+                if let Err(e) = session.write_response_header(hints).await {
+                    debug!("Fail to send early hints: {e}");
+                }
+            } else {
+                warn!("Early hints with status {} are not sent", hints.status);
+            }
+        }
+
+        session.retry_policy = self.inner.retry_policy(&session, &ctx);
+        let max_attempts = session
+            .retry_policy
+            .as_ref()
+            .map_or(MAX_RETRIES, |p| p.attempts());
+        let retry_budget = session.retry_policy.as_ref().and_then(|p| p.budget.clone());
+        if let Some(budget) = retry_budget.as_ref() {
+            budget.record_request();
+        }
+
+        while retries < max_attempts {
+            if let Some(policy) = session.retry_policy.as_ref() {
+                let backoff = policy.backoff(retries);
+                if !backoff.is_zero() {
+                    match session.deadline() {
+                        // no need to wait beyond the deadline
+                        Some(deadline) => {
+                            let _ = deadline.timeout(time::sleep(backoff)).await;
+                        }
+                        None => time::sleep(backoff).await,
+                    }
+                }
+            }
+            if session.deadline_expired() {
+                // give up instead of trying (again)
+                proxy_error = Some(deadline::deadline_exceeded());
+                break;
+            }
             retries += 1;
+            session.upstream_attempts = retries;
 
-            let (reuse, e) = self.proxy_to_upstream(&mut session, &mut ctx).await;
+            let (reuse, e) = self.proxy_to_upstream_attempt(&mut session, &mut ctx).await;
             server_reuse = reuse;
+            session.report_circuit(e.as_deref());
+            #[cfg(feature = "opentelemetry")]
+            session.end_upstream_trace(e.as_deref());
 
             match e {
                 Some(error) => {
-                    let retry = error.retry();
+                    // only the retries of the retry policy take from its budget, the ones
+                    // decided by the error itself, e.g., on a stale reused connection, are free
+                    let mut retry = error.retry();
+                    if !retry && session.retry_on_error(&error) {
+                        retry = match retry_budget.as_ref() {
+                            Some(budget) => retries >= max_attempts || budget.try_retry(),
+                            None => true,
+                        };
+                        if !retry {
+                            warn!(
+                                "Retry budget exhausted, not retrying: {}, tries: {}, {}",
+                                error,
+                                retries,
+                                self.inner.request_summary(&session, &ctx)
+                            );
+                        }
+                    }
                     proxy_error = Some(error);
                     if !retry {
                         break;
@@ -521,6 +936,8 @@ impl<SV> HttpProxy<SV> {
             }
         }
 
+        self.send_mirror(&mut session);
+
         // logging() will be called in finish()
         self.finish(session, &mut ctx, server_reuse, final_error.as_deref())
             .await
@@ -593,9 +1010,18 @@ where
             None => return None, // bad request
         };
 
+        let exhausted = self.max_requests_per_connection.is_some_and(|max| {
+            session
+                .digest()
+                .and_then(|d| d.socket_digest.as_ref())
+                .is_some_and(|d| d.requests() >= max)
+        });
         if *shutdown.borrow() {
             // stop downstream from reusing if this service is shutting down soon
             session.set_keepalive(None);
+        } else if exhausted {
+            // the connection served enough requests, let the client reconnect
+            session.set_keepalive(None);
         } else {
             // default 60s
             session.set_keepalive(Some(60));
@@ -608,11 +1034,20 @@ where
     fn http_cleanup(&self) {
         // Notify all keepalived requests blocking on read_request() to abort
         self.shutdown.notify_waiters();
+        if let Some(logger) = self.inner.access_logger() {
+            logger.flush();
+        }
 
         // TODO: impl shutting down flag so that we don't need to read stack.is_shutting_down()
     }
 
-    // TODO implement h2_options
+    fn max_requests_per_connection(&self) -> Option<usize> {
+        self.max_requests_per_connection
+    }
+
+    fn h2_options(&self) -> Option<H2Options> {
+        self.h2_settings.as_ref().map(|s| s.server_options())
+    }
 }
 
 use pingora_core::services::listening::Service;
@@ -0,0 +1,226 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Send a copy of the requests to a shadow upstream
+//!
+//! The copy is sent in the background after the request is proxied so that the shadow upstream
+//! never blocks or affects the real response. Its response is read and discarded, and its
+//! errors are only logged. A slow or unresponsive shadow upstream is bounded by the timeout and
+//! the number of mirrored requests in flight.
+
+use super::*;
+use rand::Rng;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// The shadow upstream to send a copy of a request to, see [ProxyHttp::mirror_request()]
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    /// The shadow upstream
+    pub peer: Box<HttpPeer>,
+    /// The fraction of the requests to mirror, between 0.0 and 1.0
+    pub sample: f64,
+    /// The maximum size of the request body to mirror. The requests with larger bodies are not
+    /// mirrored.
+    ///
+    /// The body is buffered while it is proxied, so it is also capped by
+    /// `max_buffered_request_body` of the server conf, which is 64KiB by default.
+    pub max_body: usize,
+    /// The maximum time to send a mirrored request and read its response, 10 seconds by default
+    pub timeout: Duration,
+    /// The maximum number of the mirrored requests in flight, 100 by default. The requests
+    /// beyond it are not mirrored.
+    ///
+    /// The count covers all the mirrored requests of the proxy service, regardless of their
+    /// [Mirror]s.
+    pub max_inflight: usize,
+}
+
+impl Mirror {
+    /// Mirror all the requests to the given upstream
+    pub fn new(peer: Box<HttpPeer>) -> Self {
+        Mirror {
+            peer,
+            sample: 1.0,
+            max_body: usize::MAX,
+            timeout: Duration::from_secs(10),
+            max_inflight: 100,
+        }
+    }
+
+    /// Only mirror the given fraction of the requests
+    pub fn with_sample(mut self, sample: f64) -> Self {
+        self.sample = sample;
+        self
+    }
+
+    /// Only mirror the requests whose bodies are no larger than the given size
+    pub fn with_max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+
+    /// Give up on the mirrored requests that take longer than the given time
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Only mirror up to the given number of requests at the same time
+    pub fn with_max_inflight(mut self, max_inflight: usize) -> Self {
+        self.max_inflight = max_inflight;
+        self
+    }
+
+    fn sampled(&self) -> bool {
+        if self.sample >= 1.0 {
+            true
+        } else if self.sample > 0.0 {
+            rand::thread_rng().gen_bool(self.sample)
+        } else {
+            false // also when NaN
+        }
+    }
+}
+
+// the mirror selected for a request, waiting for the request to finish
+pub(crate) struct PendingMirror {
+    peer: Box<HttpPeer>,
+    max_body: usize,
+    timeout: Duration,
+    max_inflight: usize,
+    req: RequestHeader,
+}
+
+impl<SV> HttpProxy<SV> {
+    // decide whether to mirror this request and start buffering its body if so
+    pub(crate) fn start_mirror(&self, session: &mut Session, ctx: &SV::CTX)
+    where
+        SV: ProxyHttp,
+    {
+        let Some(mirror) = self.inner.mirror_request(session, ctx) else {
+            return;
+        };
+        if !mirror.sampled() {
+            return;
+        }
+        if !session.as_mut().is_body_empty() {
+            if mirror.max_body == 0 {
+                return;
+            }
+            session.as_mut().enable_retry_buffering();
+        }
+        session.mirror = Some(Box::new(PendingMirror {
+            peer: mirror.peer,
+            max_body: mirror.max_body,
+            timeout: mirror.timeout,
+            max_inflight: mirror.max_inflight,
+            req: session.req_header().clone(),
+        }));
+    }
+
+    // send the copy of the finished request to the mirror in the background, if any
+    pub(crate) fn send_mirror(self: &Arc<Self>, session: &mut Session)
+    where
+        SV: ProxyHttp + Send + Sync + 'static,
+    {
+        let Some(mirror) = session.mirror.take() else {
+            return;
+        };
+        let downstream = session.as_mut();
+        let body = if downstream.is_body_empty() {
+            None
+        } else {
+            // the body is incomplete or too large to be buffered
+            if !downstream.is_body_done() || downstream.retry_buffer_truncated() {
+                debug!("skip mirroring request with unbuffered body");
+                return;
+            }
+            let body = downstream.get_retry_buffer();
+            if body.as_ref().map_or(0, |b| b.len()) > mirror.max_body {
+                debug!("skip mirroring request with body larger than the limit");
+                return;
+            }
+            body
+        };
+        if self.mirror_inflight.fetch_add(1, Ordering::Relaxed) >= mirror.max_inflight {
+            self.mirror_inflight.fetch_sub(1, Ordering::Relaxed);
+            debug!("skip mirroring request with too many mirrored requests in flight");
+            return;
+        }
+        let app = self.clone();
+        tokio::spawn(async move {
+            let peer = mirror.peer.to_string();
+            let timeout = mirror.timeout;
+            match pingora_timeout::timeout(timeout, app.mirror_to(*mirror, body)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("Fail to mirror request to {peer}: {e}"),
+                Err(_) => debug!("Mirrored request to {peer} timed out after {timeout:?}"),
+            }
+            app.mirror_inflight.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    async fn mirror_to(&self, mirror: PendingMirror, body: Option<Bytes>) -> Result<()> {
+        let PendingMirror { peer, req, .. } = mirror;
+        let (mut client, _reused) = self.client_upstream.get_http_session(&*peer).await?;
+        let req = match client {
+            ClientSession::H1(_) => Box::new(req),
+            ClientSession::H2(_) => proxy_h2::to_h2_request(req)?,
+        };
+        client.write_request_header(req).await?;
+        if let Some(body) = body {
+            client.write_request_body(body, true).await?;
+        }
+        client.finish_request_body().await?;
+        client.read_response_header().await?;
+        // discard the response
+        while client.read_response_body().await?.is_some() {}
+        self.client_upstream
+            .release_http_session(client, &*peer, peer.idle_timeout())
+            .await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampled() {
+        let peer = Box::new(HttpPeer::new("127.0.0.1:80", false, "".into()));
+        let mirror = Mirror::new(peer);
+        assert!(mirror.sampled());
+        assert!(!mirror.clone().with_sample(0.0).sampled());
+        assert!(!mirror.clone().with_sample(f64::NAN).sampled());
+        assert!(mirror.clone().with_sample(2.0).sampled());
+        let half = mirror.with_sample(0.5);
+        let hits = (0..1000).filter(|_| half.sampled()).count();
+        assert!(hits > 300 && hits < 700);
+    }
+
+    #[test]
+    fn test_limits() {
+        let peer = Box::new(HttpPeer::new("127.0.0.1:80", false, "".into()));
+        let mirror = Mirror::new(peer);
+        assert_eq!(mirror.timeout, Duration::from_secs(10));
+        assert_eq!(mirror.max_inflight, 100);
+        let mirror = mirror
+            .with_timeout(Duration::from_millis(500))
+            .with_max_inflight(0);
+        assert_eq!(mirror.timeout, Duration::from_millis(500));
+        assert_eq!(mirror.max_inflight, 0);
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! OpenTelemetry tracing of the requests
+//!
+//! Each request gets a server span, whose parent is taken from the W3C `traceparent` and
+//! `tracestate` headers of the request if any. Each attempt to proxy to the upstream gets a client
+//! span under it, with a child span for establishing the connection. The context of the client
+//! span is injected into the upstream request so that the upstream joins the same trace.
+
+use super::*;
+use opentelemetry::global::{self, BoxedTracer};
+use opentelemetry::trace::{
+    Span, SpanBuilder, SpanContext, SpanId, SpanKind, Status, TraceContextExt, TraceFlags, TraceId,
+    TraceState,
+};
+use opentelemetry::{Context, KeyValue};
+use std::time::{Instant, SystemTime};
+
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// The tracer to create the spans of the requests with, see [ProxyHttp::tracing()]
+///
+/// The exporter is whatever the given tracer is set up with.
+pub struct Tracing {
+    tracer: BoxedTracer,
+}
+
+impl Tracing {
+    /// Create a new [Tracing] with the given tracer
+    pub fn new(tracer: BoxedTracer) -> Self {
+        Tracing { tracer }
+    }
+
+    /// Create a new [Tracing] with the tracer of the given name from the global tracer provider
+    pub fn global(name: &'static str) -> Self {
+        Self::new(global::tracer(name))
+    }
+}
+
+// the spans of a request in flight
+pub(crate) struct RequestTrace {
+    tracing: Arc<Tracing>,
+    // holds the server span
+    request: Context,
+    // holds the client span of the ongoing upstream attempt
+    upstream: Option<Context>,
+}
+
+// parse the W3C trace context headers, https://www.w3.org/TR/trace-context/
+fn extract(req: &RequestHeader) -> Option<SpanContext> {
+    let traceparent = req.headers.get(TRACEPARENT)?.to_str().ok()?;
+    let mut parts = traceparent.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let span_id = parts.next()?;
+    let flags = parts.next()?;
+    // future versions may append more fields, but version 00 must have exactly 4
+    if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    u8::from_str_radix(version, 16).ok()?;
+    if trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+        return None;
+    }
+    let trace_id = TraceId::from_hex(trace_id).ok()?;
+    let span_id = SpanId::from_hex(span_id).ok()?;
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    if trace_id == TraceId::INVALID || span_id == SpanId::INVALID {
+        return None;
+    }
+    let state = req
+        .headers
+        .get(TRACESTATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(TraceState::default);
+    Some(SpanContext::new(
+        trace_id,
+        span_id,
+        TraceFlags::new(flags & TraceFlags::SAMPLED.to_u8()),
+        true,
+        state,
+    ))
+}
+
+// set the W3C trace context headers to the given span
+fn inject(req: &mut RequestHeader, span: &SpanContext) {
+    if !span.is_valid() {
+        req.remove_header(TRACEPARENT);
+        req.remove_header(TRACESTATE);
+        return;
+    }
+    let traceparent = format!(
+        "00-{:032x}-{:016x}-{:02x}",
+        span.trace_id(),
+        span.span_id(),
+        span.trace_flags().to_u8()
+    );
+    // always valid header values
+    let _ = req.insert_header(TRACEPARENT, traceparent);
+    let state = span.trace_state().header();
+    if state.is_empty() {
+        req.remove_header(TRACESTATE);
+    } else {
+        let _ = req.insert_header(TRACESTATE, state);
+    }
+}
+
+fn system_time(t: Instant) -> SystemTime {
+    SystemTime::now() - t.elapsed()
+}
+
+impl Session {
+    // start the server span of this request
+    pub(crate) fn start_trace(&mut self, tracing: Arc<Tracing>) {
+        let req = self.req_header();
+        let parent = match extract(req) {
+            Some(span) => Context::new().with_remote_span_context(span),
+            None => Context::new(),
+        };
+        let mut attributes = vec![
+            KeyValue::new("http.request.method", req.method.to_string()),
+            KeyValue::new("url.path", req.uri.path().to_string()),
+        ];
+        if let Some(id) = self.request_id() {
+            attributes.push(KeyValue::new("pingora.request_id", id.to_string()));
+        }
+        let span = SpanBuilder::from_name(req.method.to_string())
+            .with_kind(SpanKind::Server)
+            .with_start_time(system_time(self.timings.request_start))
+            .with_attributes(attributes)
+            .start_with_context(&tracing.tracer, &parent);
+        self.trace = Some(Box::new(RequestTrace {
+            tracing,
+            request: parent.with_span(span),
+            upstream: None,
+        }));
+    }
+
+    // start the client span of the upstream attempt and propagate it to the upstream request
+    pub(crate) fn start_upstream_trace(&mut self, peer: &HttpPeer) {
+        let Some(trace) = self.trace.as_mut() else {
+            return;
+        };
+        let span = SpanBuilder::from_name("upstream")
+            .with_kind(SpanKind::Client)
+            .with_attributes(vec![
+                KeyValue::new("server.address", peer.address().to_string()),
+                KeyValue::new("pingora.upstream.attempt", self.upstream_attempts as i64),
+            ])
+            .start_with_context(&trace.tracing.tracer, &trace.request);
+        let upstream = trace.request.with_span(span);
+        inject(
+            self.downstream_session.req_header_mut(),
+            upstream.span().span_context(),
+        );
+        trace.upstream = Some(upstream);
+    }
+
+    // end the client span of the upstream attempt
+    pub(crate) fn end_upstream_trace(&mut self, error: Option<&Error>) {
+        let Some(trace) = self.trace.as_mut() else {
+            return;
+        };
+        let Some(upstream) = trace.upstream.take() else {
+            return;
+        };
+        if let (Some(start), Some(connected)) =
+            (self.timings.upstream_start, self.timings.upstream_connected)
+        {
+            let mut connect = SpanBuilder::from_name("connect")
+                .with_kind(SpanKind::Client)
+                .with_start_time(system_time(start))
+                .start_with_context(&trace.tracing.tracer, &upstream);
+            connect.end_with_timestamp(system_time(connected));
+        }
+        let span = upstream.span();
+        if let Some(e) = error {
+            span.set_status(Status::error(e.to_string()));
+        }
+        span.end();
+    }
+
+    // end the server span of this request
+    pub(crate) fn end_trace(&mut self, error: Option<&Error>) {
+        self.end_upstream_trace(error);
+        let Some(trace) = self.trace.take() else {
+            return;
+        };
+        let span = trace.request.span();
+        if let Some(resp) = self.response_written() {
+            let status = resp.status.as_u16();
+            span.set_attribute(KeyValue::new("http.response.status_code", status as i64));
+            if status >= 500 {
+                span.set_status(Status::error(""));
+            }
+        }
+        if let Some(e) = error {
+            span.set_status(Status::error(e.to_string()));
+        }
+        span.end();
+    }
+
+    /// The OpenTelemetry context that holds the span of this request, if [ProxyHttp::tracing()]
+    /// is configured
+    ///
+    /// Use it to, e.g., set the `http.route` attribute or to create child spans.
+    pub fn trace_context(&self) -> Option<&Context> {
+        self.trace.as_ref().map(|t| &t.request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_inject() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        assert!(extract(&req).is_none());
+
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        req.insert_header(TRACEPARENT, traceparent).unwrap();
+        req.insert_header(TRACESTATE, "congo=t61rcWkgMzE").unwrap();
+        let span = extract(&req).unwrap();
+        assert!(span.is_remote());
+        assert!(span.is_sampled());
+        assert_eq!(span.trace_state().header(), "congo=t61rcWkgMzE");
+
+        let mut upstream = RequestHeader::build("GET", b"/", None).unwrap();
+        inject(&mut upstream, &span);
+        assert_eq!(upstream.headers.get(TRACEPARENT).unwrap(), traceparent);
+        assert_eq!(
+            upstream.headers.get(TRACESTATE).unwrap(),
+            "congo=t61rcWkgMzE"
+        );
+
+        for invalid in [
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b716920333-01",
+            "garbage",
+        ] {
+            req.insert_header(TRACEPARENT, invalid).unwrap();
+            assert!(extract(&req).is_none(), "{invalid}");
+        }
+        // a future version may have more fields
+        req.insert_header(
+            TRACEPARENT,
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+        )
+        .unwrap();
+        assert!(extract(&req).is_some());
+    }
+}
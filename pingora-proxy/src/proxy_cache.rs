@@ -14,13 +14,18 @@
 
 use super::*;
 use http::StatusCode;
+use pingora_cache::cache_control::CacheControl;
+use pingora_cache::filters::{apply_ttl_override, resp_cacheable};
 use pingora_cache::key::CacheHashKey;
 use pingora_cache::lock::LockStatus;
 use pingora_cache::max_file_size::ERR_RESPONSE_TOO_LARGE;
-use pingora_cache::{HitStatus, RespCacheable::*};
+use pingora_cache::{CacheMetaDefaults, HitStatus, RespCacheable, RespCacheable::*};
 use pingora_core::protocols::http::v1::common::header_value_content_length;
 use pingora_core::ErrorType;
 
+// No TTL without caching headers and no serving stale, so that only the headers decide
+const HEADER_ONLY_DEFAULTS: CacheMetaDefaults = CacheMetaDefaults::new(|_| None, 0, 0);
+
 impl<SV> HttpProxy<SV> {
     // return bool: server_session can be reused, and error if any
     pub(crate) async fn proxy_cache(
@@ -163,8 +168,11 @@ impl<SV> HttpProxy<SV> {
                                     // and then let it go to upstream
                                     break None;
                                 }
-                                let will_serve_stale = session.cache.can_serve_stale_updating()
-                                    && self.inner.should_serve_stale(session, ctx, None);
+                                // force expired assets are not served stale
+                                let will_serve_stale = (matches!(hit_status, HitStatus::Expired)
+                                    && session.cache.can_serve_stale_while_locked())
+                                    || (session.cache.can_serve_stale_updating()
+                                        && self.inner.should_serve_stale(session, ctx, None));
                                 if !will_serve_stale {
                                     let lock_status = session.cache.cache_lock_wait().await;
                                     if self.handle_lock_status(session, ctx, lock_status) {
@@ -272,6 +280,7 @@ impl<SV> HttpProxy<SV> {
         // TODO: use ProxyUseCache to replace the logic below
         match self.inner.response_filter(session, &mut header, ctx).await {
             Ok(_) => {
+                session.echo_request_id(&mut header);
                 if let Err(e) = session
                     .as_mut()
                     .write_response_header(header)
@@ -281,6 +290,7 @@ impl<SV> HttpProxy<SV> {
                     // downstream connection is bad already
                     return (false, Some(e));
                 }
+                session.timings.response_started();
             }
             Err(e) => {
                 // TODO: more logging and error handling
@@ -292,7 +302,7 @@ impl<SV> HttpProxy<SV> {
         debug!("finished sending cached header to downstream");
 
         if !header_only {
-            if let RangeType::Single(r) = range_type {
+            if let RangeType::Single(r) = &range_type {
                 if let Err(e) = session.cache.hit_handler().seek(r.start, Some(r.end)) {
                     return (false, Some(e));
                 }
@@ -301,6 +311,7 @@ impl<SV> HttpProxy<SV> {
                 match session.cache.hit_handler().read_body().await {
                     Ok(body) => {
                         if let Some(b) = body {
+                            session.cache.record_cache_bytes(b.len());
                             // write to downstream
                             if let Err(e) = session
                                 .as_mut()
@@ -317,6 +328,21 @@ impl<SV> HttpProxy<SV> {
                     Err(e) => return (false, Some(e)),
                 }
             }
+            // a partial response does not carry the trailers of the whole body
+            if matches!(range_type, RangeType::None) {
+                let trailers = session.cache.cache_meta().trailers().map(Box::new);
+                // the added trailers go through the filter too
+                let task = self.response_trailers_task(session, trailers, ctx).await;
+                if !matches!(task, HttpTask::Done) {
+                    if let Err(e) = session
+                        .write_response_tasks(vec![task])
+                        .await
+                        .map_err(|e| e.into_down())
+                    {
+                        return (false, Some(e));
+                    }
+                }
+            }
         }
 
         if let Err(e) = session.cache.finish_hit_handler().await {
@@ -359,7 +385,7 @@ impl<SV> HttpProxy<SV> {
                 {
                     return Ok(());
                 }
-                match self.inner.response_cache_filter(session, header, ctx)? {
+                match self.response_cacheable(session, header, ctx)? {
                     Cacheable(meta) => {
                         let mut fill_cache = true;
                         if session.cache.bypassing() {
@@ -421,6 +447,10 @@ impl<SV> HttpProxy<SV> {
                             let variance = self.inner.cache_vary_filter(&meta, ctx, req_header);
                             session.cache.set_cache_meta(meta);
                             session.cache.update_variance(variance);
+                            // too many variants of this asset may disable the cache
+                            fill_cache = session.cache.enabled();
+                        }
+                        if fill_cache {
                             // this sends the meta and header
                             session.cache.set_miss_handler().await?;
                             if session.cache.miss_body_reader().is_some() {
@@ -449,6 +479,7 @@ impl<SV> HttpProxy<SV> {
             HttpTask::Body(data, end_stream) => match data {
                 Some(d) => {
                     if session.cache.enabled() {
+                        session.cache.record_origin_bytes(d.len());
                         // this will panic if more data is sent after we see end_stream
                         // but should be impossible in real world
                         let miss_handler = session.cache.miss_handler().unwrap();
@@ -475,7 +506,15 @@ impl<SV> HttpProxy<SV> {
                     }
                 }
             },
-            HttpTask::Trailer(_) => {} // h1 trailer is not supported yet
+            HttpTask::Trailer(trailers) => {
+                if session.cache.enabled() {
+                    // the trailers are kept in the meta so that cache hits can serve them
+                    if let Some(t) = trailers {
+                        session.cache.set_miss_trailers(t).await?;
+                    }
+                    session.cache.finish_miss_handler().await?;
+                }
+            }
             HttpTask::Done => {
                 if session.cache.enabled() {
                     session.cache.finish_miss_handler().await?;
@@ -515,10 +554,7 @@ impl<SV> HttpProxy<SV> {
                         // 304 doesn't contain all the headers, merge 304 into cached 200 header
                         // in order for response_cache_filter to run correctly
                         let merged_header = session.cache.revalidate_merge_header(resp);
-                        match self
-                            .inner
-                            .response_cache_filter(session, &merged_header, ctx)
-                        {
+                        match self.response_cacheable(session, &merged_header, ctx) {
                             Ok(Cacheable(mut meta)) => {
                                 // For simplicity, ignore changes to variance over 304 for now.
                                 // Note this means upstream can only update variance via 2xx
@@ -579,10 +615,8 @@ impl<SV> HttpProxy<SV> {
                 } else if resp.status.is_server_error() {
                     // stale if error logic, 5xx only for now
 
-                    // this is response header filter, response_written should always be None?
-                    if !session.cache.can_serve_stale_error()
-                        || session.response_written().is_some()
-                    {
+                    // this is response header filter, at most 1xx responses are written
+                    if !session.cache.can_serve_stale_error() || session.response_started() {
                         return false;
                     }
 
@@ -622,7 +656,7 @@ impl<SV> HttpProxy<SV> {
 
         // the error happen halfway through a regular response to downstream
         // can't resend the response
-        if session.response_written().is_some() {
+        if session.response_started() {
             return None;
         }
 
@@ -641,6 +675,44 @@ impl<SV> HttpProxy<SV> {
         Some(self.proxy_cache_hit(session, ctx).await)
     }
 
+    // run response_cache_filter() then apply cache_ttl_override() to its decision
+    fn response_cacheable(
+        &self,
+        session: &Session,
+        resp: &ResponseHeader,
+        ctx: &mut SV::CTX,
+    ) -> Result<RespCacheable>
+    where
+        SV: ProxyHttp,
+    {
+        let mut cacheable = self.inner.response_cache_filter(session, resp, ctx)?;
+        let Some(ttl_override) = self.inner.cache_ttl_override(session, resp, ctx) else {
+            return Ok(cacheable);
+        };
+        let cache_control = CacheControl::from_resp_headers(resp);
+        let authorization_present = session
+            .req_header()
+            .headers
+            .contains_key(header::AUTHORIZATION);
+        if matches!(cacheable, Uncacheable(NoCacheReason::Custom("default"))) {
+            // The default filter caches nothing. Decide from the headers instead so that the
+            // override has something to apply to.
+            cacheable = resp_cacheable(
+                cache_control.as_ref(),
+                resp,
+                authorization_present,
+                &HEADER_ONLY_DEFAULTS,
+            );
+        }
+        Ok(apply_ttl_override(
+            cacheable,
+            ttl_override,
+            cache_control.as_ref(),
+            resp,
+            authorization_present,
+        ))
+    }
+
     // helper function to check when to continue to retry lock (true) or give up (false)
     fn handle_lock_status(
         &self,
@@ -1171,10 +1243,11 @@ impl ServeFromCache {
             }
             Self::CacheBody => {
                 if let Some(b) = cache.hit_handler().read_body().await? {
+                    cache.record_cache_bytes(b.len());
                     Ok(HttpTask::Body(Some(b), false)) // false for now
                 } else {
                     *self = Self::Done;
-                    Ok(HttpTask::Done)
+                    Ok(cache_end_task(cache))
                 }
             }
             Self::CacheBodyMiss => {
@@ -1183,7 +1256,7 @@ impl ServeFromCache {
                     Ok(HttpTask::Body(Some(b), false)) // false for now
                 } else {
                     *self = Self::Done;
-                    Ok(HttpTask::Done)
+                    Ok(cache_end_task(cache))
                 }
             }
             Self::Done => Ok(HttpTask::Done),
@@ -1191,6 +1264,14 @@ impl ServeFromCache {
     }
 }
 
+// the task that ends a response served from cache: the cached trailers if any
+fn cache_end_task(cache: &HttpCache) -> HttpTask {
+    match cache.maybe_cache_meta().and_then(|m| m.trailers()) {
+        Some(trailers) => HttpTask::Trailer(Some(Box::new(trailers))),
+        None => HttpTask::Done,
+    }
+}
+
 /* Downstream revalidation, only needed when cache is on because otherwise origin
  * will handle it */
 pub(crate) fn downstream_response_conditional_filter(
@@ -28,9 +28,6 @@ impl<SV> HttpProxy<SV> {
         SV: ProxyHttp + Send + Sync,
         SV::CTX: Send + Sync,
     {
-        client_session.read_timeout = peer.options.read_timeout;
-        client_session.write_timeout = peer.options.write_timeout;
-
         // phase 2 send to upstream
 
         let mut req = session.req_header().clone();
@@ -93,7 +90,13 @@ impl<SV> HttpProxy<SV> {
 
         // start bi-directional streaming
         let ret = tokio::try_join!(
-            self.proxy_handle_downstream(session, tx_downstream, rx_upstream, ctx),
+            self.proxy_handle_downstream(
+                session,
+                tx_downstream,
+                rx_upstream,
+                peer.options.upgrade_idle_timeout,
+                ctx,
+            ),
             self.proxy_handle_upstream(client_session, tx_upstream, rx_downstream),
         );
 
@@ -207,6 +210,7 @@ impl<SV> HttpProxy<SV> {
         session: &mut Session,
         tx: mpsc::Sender<HttpTask>,
         mut rx: mpsc::Receiver<HttpTask>,
+        upgrade_idle_timeout: Option<std::time::Duration>,
         ctx: &mut SV::CTX,
     ) -> Result<()>
     where
@@ -257,6 +261,9 @@ impl<SV> HttpProxy<SV> {
                 .try_reserve()
                 .or_err(InternalError, "try_reserve() body pipe for upstream");
 
+            // the idle timer restarts on every event of either side
+            let idle_timeout = upgrade_idle_timeout.filter(|_| session.is_upgraded());
+
             tokio::select! {
                 // only try to send to pipe if there is capacity to avoid deadlock
                 // Otherwise deadlock could happen if both upstream and downstream are blocked
@@ -282,18 +289,41 @@ impl<SV> HttpProxy<SV> {
                            }
                         }
                     };
+                    self.check_request_body_size(session)?;
                     // If the request is websocket, `None` body means the request is closed.
                     // Set the response to be done as well so that the request completes normally.
                     if body.is_none() && session.is_upgrade_req() {
                         response_state.maybe_set_upstream_done(true);
                     }
+                    let trailers = if downstream_state.is_reading()
+                        && !session.is_upgrade_req()
+                        && (body.is_none() || session.is_body_done())
+                    {
+                        self.request_trailers(session, ctx).await?
+                    } else {
+                        None
+                    };
                     // TODO: consider just drain this if serve_from_cache is set
-                    let request_done = send_body_to_pipe(
-                        body,
-                        session.is_body_done(),
-                        send_permit.unwrap(), // safe because we checked is_ok()
-                    )
-                    .await;
+                    let request_done = match trailers {
+                        Some(trailers) => {
+                            send_body_and_trailers_to_pipe(
+                                body,
+                                trailers,
+                                send_permit.unwrap(), // safe because we checked is_ok()
+                                &tx,
+                            )
+                            .await?;
+                            true
+                        }
+                        None => {
+                            send_body_to_pipe(
+                                body,
+                                session.is_body_done(),
+                                send_permit.unwrap(), // safe because we checked is_ok()
+                            )
+                            .await
+                        }
+                    };
                     downstream_state.maybe_finished(request_done);
                 },
 
@@ -357,6 +387,7 @@ impl<SV> HttpProxy<SV> {
                         }
 
                         // set to downstream
+                        self.add_response_trailers(session, &mut filtered_tasks, ctx).await;
                         let response_done = session.write_response_tasks(filtered_tasks).await?;
                         response_state.maybe_set_upstream_done(response_done);
                         // unsuccessful upgrade response may force the request done
@@ -375,7 +406,9 @@ impl<SV> HttpProxy<SV> {
                         &mut range_body_filter, true).await?;
                     debug!("serve_from_cache task {task:?}");
 
-                    match session.write_response_tasks(vec![task]).await {
+                    let mut tasks = vec![task];
+                    self.add_response_trailers(session, &mut tasks, ctx).await;
+                    match session.write_response_tasks(tasks).await {
                         Ok(b) => response_state.maybe_set_cache_done(b),
                         Err(e) => if serve_from_cache.is_miss() {
                             // give up writing to downstream but wait for upstream cache write to finish
@@ -398,6 +431,11 @@ impl<SV> HttpProxy<SV> {
                     }
                 }
 
+                _ = time::sleep(idle_timeout.unwrap_or_default()), if idle_timeout.is_some() => {
+                    debug!("upgraded connection idle for {:?}, closing", idle_timeout);
+                    break;
+                }
+
                 else => {
                     break;
                 }
@@ -431,6 +469,8 @@ impl<SV> HttpProxy<SV> {
     {
         // skip caching if already served from cache
         if !from_cache {
+            session.timings.upstream_task(&task);
+            session.check_retry_status(&task)?;
             self.upstream_filter(session, &mut task, ctx);
 
             // cache the original response before any downstream transformation
@@ -468,7 +508,8 @@ impl<SV> HttpProxy<SV> {
                 /* Downstream revalidation/range, only needed when cache is on because otherwise origin
                  * will handle it */
                 // TODO: if cache is disabled during response phase, we should still do the filter
-                if session.cache.enabled() {
+                // 1xx are interim responses, the final response header will follow
+                if session.cache.enabled() && !header.status.is_informational() {
                     proxy_cache::downstream_response_conditional_filter(
                         serve_from_cache,
                         req,
@@ -481,10 +522,17 @@ impl<SV> HttpProxy<SV> {
                     }
                 }
 
+                self.inner
+                    .response_filter(session, &mut header, ctx)
+                    .await?;
+                if !end {
+                    session.announce_response_trailers(&mut header);
+                }
+
                 /* Convert HTTP 1.0 style response to chunked encoding so that we don't
                  * have to close the downstream connection */
                 // these status codes / method cannot have body, so no need to add chunked encoding
-                let no_body = req.method == http::method::Method::HEAD
+                let no_body = session.req_header().method == http::method::Method::HEAD
                     || matches!(header.status.as_u16(), 204 | 304);
                 if !no_body
                     && !header.status.is_informational()
@@ -498,10 +546,7 @@ impl<SV> HttpProxy<SV> {
                     header.insert_header(http::header::TRANSFER_ENCODING, "chunked")?;
                 }
 
-                match self.inner.response_filter(session, &mut header, ctx).await {
-                    Ok(_) => Ok(HttpTask::Header(header, end)),
-                    Err(e) => Err(e),
-                }
+                Ok(HttpTask::Header(header, end))
             }
             HttpTask::Body(data, end) => {
                 let mut data = range_body_filter.filter_body(data);
@@ -514,7 +559,9 @@ impl<SV> HttpProxy<SV> {
                 }
                 Ok(HttpTask::Body(data, end))
             }
-            HttpTask::Trailer(h) => Ok(HttpTask::Trailer(h)), // no h1 trailer filter yet
+            HttpTask::Trailer(trailers) => {
+                Ok(self.response_trailers_task(session, trailers, ctx).await)
+            }
             HttpTask::Done => Ok(task),
             HttpTask::Failed(_) => Ok(task), // Do nothing just pass the error down
         }
@@ -546,6 +593,32 @@ pub(crate) async fn send_body_to_pipe(
     }
 }
 
+// send the last piece of the request body followed by the trailers, which end the request
+pub(crate) async fn send_body_and_trailers_to_pipe(
+    data: Option<Bytes>,
+    trailers: Box<http::HeaderMap>,
+    permit: mpsc::Permit<'_, HttpTask>,
+    tx: &mpsc::Sender<HttpTask>,
+) -> Result<()> {
+    match data {
+        Some(data) if !data.is_empty() => {
+            debug!(
+                "Read {} bytes body and trailers from downstream",
+                data.len()
+            );
+            permit.send(HttpTask::Body(Some(data), false));
+            tx.send(HttpTask::Trailer(Some(trailers)))
+                .await
+                .or_err(InternalError, "sending trailers to pipe")
+        }
+        _ => {
+            debug!("Read trailers from downstream");
+            permit.send(HttpTask::Trailer(Some(trailers)));
+            Ok(())
+        }
+    }
+}
+
 pub(crate) async fn send_body_to1(
     client_session: &mut HttpSessionV1,
     recv_task: Option<HttpTask>,
@@ -573,6 +646,19 @@ pub(crate) async fn send_body_to1(
                     }
                 }
             }
+            HttpTask::Trailer(trailers) => {
+                let res = match trailers {
+                    Some(trailers) => client_session.write_trailers(&trailers).await,
+                    None => client_session.finish_body().await,
+                };
+                return match res {
+                    Ok(_) => {
+                        debug!("finish sending body and trailers to upstream");
+                        Ok(true)
+                    }
+                    Err(e) => e.into_up().into_err(),
+                };
+            }
             _ => {
                 // should never happen, sender only sends body
                 warn!("Unexpected task sent to upstream");
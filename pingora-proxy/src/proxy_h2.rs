@@ -60,6 +60,30 @@ fn update_h2_scheme_authority(header: &mut http::request::Parts, raw_host: &[u8]
     }
 }
 
+// remove the H1 specific headers and turn the request into h2
+fn remove_h1_headers(req: &mut RequestHeader) {
+    if req.version != Version::HTTP_2 {
+        // https://github.com/hyperium/h2/blob/d3b9f1e36aadc1a7a6804e2f8e86d3fe4a244b4f/src/proto/streams/send.rs#L72
+        req.remove_header(&http::header::TRANSFER_ENCODING);
+        req.remove_header(&http::header::CONNECTION);
+        req.remove_header(&http::header::UPGRADE);
+        req.remove_header("keep-alive");
+        req.remove_header("proxy-connection");
+    }
+    req.set_version(Version::HTTP_2);
+}
+
+// turn the request into h2 without going through the filters of proxy_1to2()
+pub(crate) fn to_h2_request(mut req: RequestHeader) -> Result<Box<RequestHeader>> {
+    remove_h1_headers(&mut req);
+    let host = req.remove_header(&http::header::HOST);
+    let mut req: http::request::Parts = req.into();
+    if let Some(host) = host {
+        update_h2_scheme_authority(&mut req, host.as_bytes())?;
+    }
+    Ok(Box::new(RequestHeader::from(req)))
+}
+
 impl<SV> HttpProxy<SV> {
     pub(crate) async fn proxy_1to2(
         &self,
@@ -74,19 +98,7 @@ impl<SV> HttpProxy<SV> {
         SV::CTX: Send + Sync,
     {
         let mut req = session.req_header().clone();
-
-        if req.version != Version::HTTP_2 {
-            /* remove H1 specific headers */
-            // https://github.com/hyperium/h2/blob/d3b9f1e36aadc1a7a6804e2f8e86d3fe4a244b4f/src/proto/streams/send.rs#L72
-            req.remove_header(&http::header::TRANSFER_ENCODING);
-            req.remove_header(&http::header::CONNECTION);
-            req.remove_header(&http::header::UPGRADE);
-            req.remove_header("keep-alive");
-            req.remove_header("proxy-connection");
-        }
-
-        /* turn it into h2 */
-        req.set_version(Version::HTTP_2);
+        remove_h1_headers(&mut req);
 
         if session.cache.enabled() {
             if let Err(e) = pingora_cache::filters::upstream::request_filter(
@@ -151,8 +163,6 @@ impl<SV> HttpProxy<SV> {
             };
         }
 
-        client_session.read_timeout = peer.options.read_timeout;
-
         // take the body writer out of the client for easy duplex
         let mut client_body = client_session
             .take_request_body_writer()
@@ -260,7 +270,22 @@ impl<SV> HttpProxy<SV> {
                            }
                         }
                     };
-                    let request_done = send_body_to2(Ok(body), session.is_body_done(), client_body)?;
+                    self.check_request_body_size(session)?;
+                    let trailers = if downstream_state.is_reading() && (body.is_none() || session.is_body_done()) {
+                        self.request_trailers(session, ctx).await?
+                    } else {
+                        None
+                    };
+                    let request_done = match trailers {
+                        Some(trailers) => {
+                            if body.is_some() {
+                                send_body_to2(Ok(body), false, client_body)?;
+                            }
+                            send_trailers_to2(*trailers, client_body)?;
+                            true
+                        }
+                        None => send_body_to2(Ok(body), session.is_body_done(), client_body)?,
+                    };
                     downstream_state.maybe_finished(request_done);
                 },
 
@@ -313,6 +338,7 @@ impl<SV> HttpProxy<SV> {
                             continue;
                         }
 
+                        self.add_response_trailers(session, &mut filtered_tasks, ctx).await;
                         let response_done = session.write_response_tasks(filtered_tasks).await?;
                         response_state.maybe_set_upstream_done(response_done);
                     } else {
@@ -326,7 +352,9 @@ impl<SV> HttpProxy<SV> {
                     let task = self.h2_response_filter(session, task?, ctx,
                         &mut serve_from_cache,
                         &mut range_body_filter, true).await?;
-                    match session.write_response_tasks(vec![task]).await {
+                    let mut tasks = vec![task];
+                    self.add_response_trailers(session, &mut tasks, ctx).await;
+                    match session.write_response_tasks(tasks).await {
                         Ok(b) => response_state.maybe_set_cache_done(b),
                         Err(e) => if serve_from_cache.is_miss() {
                             // give up writing to downstream but wait for upstream cache write to finish
@@ -381,6 +409,8 @@ impl<SV> HttpProxy<SV> {
         SV::CTX: Send + Sync,
     {
         if !from_cache {
+            session.timings.upstream_task(&task);
+            session.check_retry_status(&task)?;
             self.upstream_filter(session, &mut task, ctx);
 
             // cache the original response before any downstream transformation
@@ -417,7 +447,8 @@ impl<SV> HttpProxy<SV> {
                 /* Downstream revalidation, only needed when cache is on because otherwise origin
                  * will handle it */
                 // TODO: if cache is disabled during response phase, we should still do the filter
-                if session.cache.enabled() {
+                // 1xx are interim responses, the final response header will follow
+                if session.cache.enabled() && !header.status.is_informational() {
                     proxy_cache::downstream_response_conditional_filter(
                         serve_from_cache,
                         req,
@@ -433,6 +464,9 @@ impl<SV> HttpProxy<SV> {
                 self.inner
                     .response_filter(session, &mut header, ctx)
                     .await?;
+                if !eos {
+                    session.announce_response_trailers(&mut header);
+                }
                 /* Downgrade the version so that write_response_header won't panic */
                 header.set_version(Version::HTTP_11);
 
@@ -461,36 +495,8 @@ impl<SV> HttpProxy<SV> {
                 }
                 Ok(HttpTask::Body(data, eos))
             }
-            HttpTask::Trailer(header_map) => {
-                let trailer_buffer = match header_map {
-                    Some(mut trailer_map) => {
-                        debug!("Parsing response trailers..");
-                        match self
-                            .inner
-                            .response_trailer_filter(session, &mut trailer_map, ctx)
-                            .await
-                        {
-                            Ok(buf) => buf,
-                            Err(e) => {
-                                error!(
-                                    "Encountered error while filtering upstream trailers {:?}",
-                                    e
-                                );
-                                None
-                            }
-                        }
-                    }
-                    _ => None,
-                };
-                // if we have a trailer buffer write it to the downstream response body
-                if let Some(buffer) = trailer_buffer {
-                    // write_body will not write additional bytes after reaching the content-length
-                    // for gRPC H2 -> H1 this is not a problem but may be a problem for non gRPC code
-                    // https://http2.github.io/http2-spec/#malformed
-                    Ok(HttpTask::Body(Some(buffer), true))
-                } else {
-                    Ok(HttpTask::Done)
-                }
+            HttpTask::Trailer(trailers) => {
+                Ok(self.response_trailers_task(session, trailers, ctx).await)
             }
             HttpTask::Done => Ok(task),
             HttpTask::Failed(_) => Ok(task), // Do nothing just pass the error down
@@ -531,6 +537,18 @@ pub(crate) fn send_body_to2(
     }
 }
 
+pub(crate) fn send_trailers_to2(
+    trailers: http::HeaderMap,
+    client_body: &mut h2::SendStream<bytes::Bytes>,
+) -> Result<()> {
+    client_body
+        .send_trailers(trailers)
+        .or_err(WriteError, "while writing h2 request trailers")
+        .map_err(|e| e.into_up())?;
+    debug!("Write trailers to h2 upstream");
+    Ok(())
+}
+
 /* Read response header, body and trailer from h2 upstream and send them to tx */
 pub(crate) async fn pipe_2to1_response(
     client: &mut Http2Session,
@@ -13,7 +13,9 @@
 // limitations under the License.
 
 use super::*;
-use pingora_cache::{key::HashBinary, CacheKey, CacheMeta, RespCacheable, RespCacheable::*};
+use pingora_cache::{
+    filters::TtlOverride, key::HashBinary, CacheKey, CacheMeta, RespCacheable, RespCacheable::*,
+};
 
 /// The interface to control the HTTP proxy
 ///
@@ -39,11 +41,42 @@ pub trait ProxyHttp {
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>>;
 
+    /// Handle the incoming request before any other phase.
+    ///
+    /// This filter is called right after the request header is read from downstream and before
+    /// [Self::request_filter()], i.e., before any built-in processing such as the cache lookup and
+    /// [Self::upstream_peer()]. It fits the logic that every other phase should see the result
+    /// of, e.g., authentication, rejecting bad requests quickly and normalizing the request.
+    ///
+    /// The phases of a request run in this order:
+    /// 1. `early_request_filter()`
+    /// 2. the [Self::rate_limit()] check, if configured
+    /// 3. [Self::request_filter()]
+    /// 4. [Self::request_cache_filter()] and the other cache phases, if caching is enabled
+    /// 5. [Self::proxy_upstream_filter()] and then [Self::upstream_peer()]
+    ///
+    /// Same as [Self::request_filter()], `Ok(true)` should be returned if a response is already
+    /// sent so that the proxy would exit, and an error fails the request.
+    ///
+    /// By default this filter does nothing and returns `Ok(false)`.
+    async fn early_request_filter(
+        &self,
+        _session: &mut Session,
+        _ctx: &mut Self::CTX,
+    ) -> Result<bool>
+    where
+        Self::CTX: Send + Sync,
+    {
+        Ok(false)
+    }
+
     /// Handle the incoming request.
     ///
     /// In this phase, users can parse, validate, rate limit, perform access control and/or
     /// return a response for this request.
     ///
+    /// This filter runs after [Self::early_request_filter()].
+    ///
     /// If the user already sent a response to this request, an `Ok(true)` should be returned so that
     /// the proxy would exit. The proxy continues to the next phases when `Ok(false)` is returned.
     ///
@@ -55,6 +88,49 @@ pub trait ProxyHttp {
         Ok(false)
     }
 
+    /// Decide where to tunnel a `CONNECT` request to.
+    ///
+    /// This filter is called after [Self::request_filter()], only for the HTTP/1.x `CONNECT`
+    /// requests, with their `host:port` `target`. The `target` is not validated yet: when a peer
+    /// is returned for a target without a port, the request is answered with `400` instead.
+    ///
+    /// When a peer is returned, the proxy connects to it (over TLS if the peer uses TLS), answers
+    /// the client with `200` and then relays the bytes between the two connections until both
+    /// sides close, either fails or the tunnel is idle for longer than the `upgrade_idle_timeout`
+    /// of the peer. Return an error to deny the target, e.g., `HTTPStatus(403)`.
+    ///
+    /// By default `Ok(None)` is returned so that `CONNECT` requests are proxied like the other
+    /// requests.
+    async fn connect_tunnel_peer(
+        &self,
+        _session: &mut Session,
+        _target: &str,
+        _ctx: &mut Self::CTX,
+    ) -> Result<Option<Box<HttpPeer>>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        Ok(None)
+    }
+
+    /// Decide how to answer a request with `Expect: 100-continue`.
+    ///
+    /// This filter is called after [Self::request_filter()], only for the requests that expect
+    /// `100 Continue` before sending their bodies. The request can be rejected by its header
+    /// before the body is sent, e.g., with 413 when its `Content-Length` is too large.
+    ///
+    /// By default, [ExpectContinue::Forward] is returned so that the upstream decides.
+    async fn expect_continue_filter(
+        &self,
+        _session: &mut Session,
+        _ctx: &mut Self::CTX,
+    ) -> Result<ExpectContinue>
+    where
+        Self::CTX: Send + Sync,
+    {
+        Ok(ExpectContinue::Forward)
+    }
+
     /// This filter decides if the request is cacheable and what cache backend to use
     ///
     /// The caller can interact with `Session.cache` to enable caching.
@@ -125,17 +201,40 @@ pub trait ProxyHttp {
         Ok(Uncacheable(NoCacheReason::Custom("default")))
     }
 
+    /// Override the TTL of the response, taking precedence over the TTL derived from its headers
+    ///
+    /// This callback is called after [Self::response_cache_filter()] and is applied to its
+    /// decision, see [pingora_cache::filters::apply_ttl_override()] for the rules. In particular, `Cache-Control:
+    /// no-store` and `private` from the origin are always respected, and a response that
+    /// [Self::response_cache_filter()] deems uncacheable for reasons other than the lack of
+    /// caching headers stays uncacheable.
+    ///
+    /// When [Self::response_cache_filter()] is not implemented, the override applies to the
+    /// cacheability decided by the `Cache-Control` and `Expires` headers of the response alone.
+    ///
+    /// By default, `None` is returned: no override.
+    fn cache_ttl_override(
+        &self,
+        _session: &Session,
+        _resp: &ResponseHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Option<TtlOverride> {
+        None
+    }
+
     /// Decide how to generate cache vary key from both request and response
     ///
     /// None means no variance is needed.
+    ///
+    /// By default, the variance follows the `Vary` header of the response, see
+    /// [pingora_cache::vary_variance()].
     fn cache_vary_filter(
         &self,
-        _meta: &CacheMeta,
+        meta: &CacheMeta,
         _ctx: &mut Self::CTX,
-        _req: &RequestHeader,
+        req: &RequestHeader,
     ) -> Option<HashBinary> {
-        // default to None for now to disable vary feature
-        None
+        pingora_cache::vary_variance(meta, req)
     }
 
     /// Modify the request before it is sent to the upstream
@@ -173,6 +272,10 @@ pub trait ProxyHttp {
     ///
     /// The modification is after caching. This filter is called for all responses including
     /// responses served from cache.
+    ///
+    /// If [Self::response_body_filter()] changes the length of the body, the `Content-Length`
+    /// header should be removed here. The proxy then sends the body with chunked encoding to
+    /// HTTP/1.1 clients.
     async fn response_filter(
         &self,
         _session: &mut Session,
@@ -199,6 +302,20 @@ pub trait ProxyHttp {
     }
 
     /// Similar to [Self::response_filter()] but for response body chunks
+    ///
+    /// This filter is called on every chunk of the response body as it streams to the downstream,
+    /// so the body can be transformed without buffering all of it. The chunk can be modified,
+    /// replaced, or dropped by setting it to `None`. Bytes can be appended to the end of the body
+    /// when `end_of_stream` is true, in which case `body` may be `None` before the change.
+    ///
+    /// - When the length of the body changes, `Content-Length` should be removed in
+    ///   [Self::response_filter()] so that chunked encoding is used instead.
+    /// - The chunks are already decompressed here if the decompression of
+    ///   [Session::upstream_compression] is enabled, and are compressed after this filter if
+    ///   [Session::downstream_compression] is enabled.
+    /// - The chunks are cached before this filter, so the changes are not cached.
+    ///
+    /// The returned duration, if any, delays sending this chunk, e.g., to throttle the response.
     fn response_body_filter(
         &self,
         _session: &mut Session,
@@ -212,7 +329,18 @@ pub trait ProxyHttp {
         Ok(None)
     }
 
-    /// When a trailer is received.
+    /// Modify the response trailers from the upstream, e.g., the gRPC status.
+    ///
+    /// The trailers added via [Session::response_trailers_mut()] are already merged in. This filter
+    /// is called whenever trailers are about to be sent, i.e., if either the upstream response has
+    /// trailers or some are added.
+    ///
+    /// The trailers are relayed to the downstream after this filter. They are dropped if the
+    /// downstream response can't carry them, i.e., an HTTP/1.1 response that doesn't use chunked
+    /// encoding or whose request doesn't have `TE: trailers`.
+    ///
+    /// If `Some` bytes are returned, they are sent as the last piece of the response body instead
+    /// of the trailers, e.g., to convert them into the body of gRPC-Web.
     async fn response_trailer_filter(
         &self,
         _session: &mut Session,
@@ -225,6 +353,22 @@ pub trait ProxyHttp {
         Ok(None)
     }
 
+    /// Modify the request trailers from the downstream before they are sent to the upstream.
+    ///
+    /// This filter is called after the request body is read entirely, only if the request has
+    /// trailers.
+    async fn request_trailer_filter(
+        &self,
+        _session: &mut Session,
+        _trailers: &mut header::HeaderMap,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        Ok(())
+    }
+
     /// This filter is called when the entire response is sent to the downstream successfully or
     /// there is a fatal error that terminate the request.
     ///
@@ -236,6 +380,16 @@ pub trait ProxyHttp {
     {
     }
 
+    /// The [AccessLogger] to log every finished request with, right after [Self::logging()]
+    ///
+    /// [AccessLog] is the built-in logger that writes JSON or Apache combined log lines to a
+    /// file or another sink. Create it once and return the same instance for every request.
+    ///
+    /// By default, `None` is returned: no access log.
+    fn access_logger(&self) -> Option<Arc<dyn AccessLogger>> {
+        None
+    }
+
     /// A value of true means that the log message will be suppressed. The default value is false.
     fn suppress_error_log(&self, _session: &Session, _ctx: &Self::CTX, _error: &Error) -> bool {
         false
@@ -266,6 +420,10 @@ pub trait ProxyHttp {
     /// If the error can be retried, [Self::upstream_peer()] will be called again so that the user
     /// can decide whether to send the request to the same upstream or another upstream that is possibly
     /// available.
+    ///
+    /// For example, connecting to an HTTP/2 only peer fails with `H2Downgrade` when the server
+    /// doesn't negotiate h2. The error can be marked retry-able so that the next attempt can go to
+    /// the same upstream over HTTP/1.1 instead, if the request allows that.
     fn fail_to_connect(
         &self,
         _session: &mut Session,
@@ -276,6 +434,121 @@ pub trait ProxyHttp {
         e
     }
 
+    /// Decide how to retry the failed attempts to proxy this request to the upstream.
+    ///
+    /// This filter is called once per request, before the first attempt. The number of attempts
+    /// made is available via [Session::upstream_attempts()], e.g., for [Self::logging()].
+    ///
+    /// By default, `None` is returned: only the errors marked as retry-able, e.g., in
+    /// [Self::fail_to_connect()], are retried, for up to 16 attempts.
+    fn retry_policy(&self, _session: &Session, _ctx: &Self::CTX) -> Option<Arc<RetryPolicy>> {
+        None
+    }
+
+    /// Decide whether to send a copy of this request to a shadow upstream, e.g., to validate a
+    /// new version of the backend with live traffic.
+    ///
+    /// This filter is called once per request, before it is proxied to the upstream. The copy is
+    /// the request header at this point plus the request body, and it is sent in the background
+    /// after the request finishes. The response and the errors from the shadow upstream are
+    /// discarded.
+    fn mirror_request(&self, _session: &Session, _ctx: &Self::CTX) -> Option<Mirror> {
+        None
+    }
+
+    /// The `103 Early Hints` to send to the client before the request is proxied to the upstream,
+    /// e.g., with `Link: </style.css>; rel=preload` headers so that the client can start fetching
+    /// the resources while waiting for the final response.
+    ///
+    /// This filter is called once per request, right before the first attempt to the upstream.
+    /// The 1xx responses from the upstream, including `103 Early Hints`, are relayed to the
+    /// client as they are received, before the final response. Neither is sent to HTTP/1.0
+    /// clients, which don't understand interim responses, nor to HTTP/2 clients, which the
+    /// underlying h2 library can't send interim responses to.
+    ///
+    /// By default, `None` is returned: no early hints.
+    fn early_hints(&self, _session: &Session, _ctx: &Self::CTX) -> Option<Box<ResponseHeader>> {
+        None
+    }
+
+    /// The compression of the responses to the downstream of this proxy.
+    ///
+    /// When configured, the eligible responses, by their `Content-Type` and size, are compressed
+    /// with the encoding that the client accepts, unless they are already encoded. The body is
+    /// compressed as it streams and is sent with chunked encoding instead of `Content-Length`.
+    ///
+    /// The config is applied to [Session::downstream_compression] of every request before
+    /// [Self::early_request_filter()], so it can still be adjusted per request in the filters.
+    ///
+    /// By default, `None` is returned: no compression.
+    fn response_compression(&self) -> Option<Arc<CompressionConfig>> {
+        None
+    }
+
+    /// The tracer to create the OpenTelemetry spans of every request with, see [Tracing]
+    ///
+    /// The span of each request is available via [Session::trace_context()] from the start of
+    /// [Self::early_request_filter()] until the end of [Self::logging()].
+    ///
+    /// By default, `None` is returned: no tracing.
+    #[cfg(feature = "opentelemetry")]
+    fn tracing(&self) -> Option<Arc<Tracing>> {
+        None
+    }
+
+    /// The rate limit of the requests per client, see [RateLimit]
+    ///
+    /// It is checked right after [Self::early_request_filter()]. The limited requests are answered
+    /// with `429` and skip the rest of the phases except [Self::logging()].
+    ///
+    /// By default, `None` is returned: no rate limit.
+    fn rate_limit(&self) -> Option<Arc<RateLimit>> {
+        None
+    }
+
+    /// The config to derive the deadline of every request from its header, see [DeadlineConfig]
+    ///
+    /// The deadline is derived before [Self::early_request_filter()] and is available via
+    /// [Session::deadline()]. It is enforced on top of the other timeouts: the request fails with
+    /// `504` once it passes. The requests without a budget only follow the other timeouts.
+    ///
+    /// By default, `None` is returned: no deadline.
+    fn request_deadline(&self) -> Option<Arc<DeadlineConfig>> {
+        None
+    }
+
+    /// The circuit breaker of the upstreams, see [CircuitBreaker]
+    ///
+    /// It is consulted after [Self::upstream_peer()] returns the peer of each attempt. When the
+    /// circuit of the peer is open, the attempt fails with the retriable `CircuitOpen` error, which
+    /// is `503` by default. Otherwise the outcome of the attempt is reported to it.
+    ///
+    /// By default, `None` is returned: no circuit breaker.
+    fn circuit_breaker(&self) -> Option<Arc<CircuitBreaker>> {
+        None
+    }
+
+    /// The config to tag every request with an ID, see [RequestIdConfig]
+    ///
+    /// When configured, the ID is taken from or added to the request header before
+    /// [Self::early_request_filter()], so it is forwarded to the upstream, and it is echoed on the
+    /// response header. It is available via [Session::request_id()], e.g., to the access logs.
+    ///
+    /// By default, `None` is returned: no request ID.
+    fn request_id(&self) -> Option<Arc<RequestIdConfig>> {
+        None
+    }
+
+    /// The HTTP status code to respond to the client with for the given error.
+    ///
+    /// 0 means that the client connection is already broken so no response should be sent.
+    ///
+    /// By default, [pingora_error::ErrorType::default_status()] is used. Override this to
+    /// customize the mapping in one place, e.g., via a [pingora_error::StatusMap].
+    fn error_status(&self, e: &Error) -> u16 {
+        e.etype().default_status(e.esource())
+    }
+
     /// This filter is called when the request encounters a fatal error.
     ///
     /// Users may write an error response to the downstream if the downstream is still writable.
@@ -285,25 +558,8 @@ pub trait ProxyHttp {
     where
         Self::CTX: Send + Sync,
     {
+        let code = self.error_status(e);
         let server_session = session.as_mut();
-        let code = match e.etype() {
-            HTTPStatus(code) => *code,
-            _ => {
-                match e.esource() {
-                    ErrorSource::Upstream => 502,
-                    ErrorSource::Downstream => {
-                        match e.etype() {
-                            WriteError | ReadError | ConnectionClosed => {
-                                /* conn already dead */
-                                0
-                            }
-                            _ => 400,
-                        }
-                    }
-                    ErrorSource::Internal | ErrorSource::Unset => 500,
-                }
-            }
-        };
         if code > 0 {
             server_session.respond_error(code).await
         }
@@ -0,0 +1,207 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Limit the request rate of each client
+
+use super::*;
+use http::header::HeaderName;
+use pingora_core::protocols::http::error_resp::gen_error_response;
+use pingora_core::services::background::BackgroundService;
+use pingora_limits::token_bucket::TokenBucket;
+use std::time::Duration;
+
+// how often to drop the clients idle long enough to be at their full burst again
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A function that returns the rate limit key of the request
+pub type KeyFn = Box<dyn Fn(&Session) -> Option<String> + Send + Sync>;
+
+/// How to tell the clients apart for rate limiting
+pub enum RateLimitKey {
+    /// The IP of the client, see [Session::client_addr()]
+    ClientIp,
+    /// The value of the given request header
+    Header(HeaderName),
+    /// The given function
+    Custom(KeyFn),
+}
+
+impl RateLimitKey {
+    fn key(&self, session: &Session) -> Option<String> {
+        match self {
+            Self::ClientIp => {
+                let addr = session.client_addr()?;
+                Some(match addr.as_inet() {
+                    Some(inet) => inet.ip().to_string(),
+                    None => addr.to_string(), // UDS
+                })
+            }
+            Self::Header(name) => session
+                .req_header()
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+            Self::Custom(f) => f(session),
+        }
+    }
+}
+
+/// A token bucket rate limit per client, see [ProxyHttp::rate_limit()]
+///
+/// The limited requests are answered with `429` and a `Retry-After` header. The requests without
+/// a key, e.g., without the configured header, are not limited.
+///
+/// The state is shared by all the requests that use the same [RateLimit], so create it once and
+/// share it across the threads and services. To bound the memory used by the idle clients, run it
+/// as a [BackgroundService], which drops them every 10 seconds:
+///
+/// ```no_run
+/// # use pingora_core::services::background::background_service;
+/// # use pingora_proxy::{RateLimit, RateLimitKey};
+/// let cleanup = background_service("rate limit", RateLimit::new(RateLimitKey::ClientIp, 10.0, 20));
+/// // return this from ProxyHttp::rate_limit() and add `cleanup` to the server
+/// let rate_limit = cleanup.task();
+/// ```
+pub struct RateLimit {
+    key: RateLimitKey,
+    limiter: TokenBucket<String>,
+}
+
+impl RateLimit {
+    /// Create a new [RateLimit] that allows `rate` requests per second on average and up to
+    /// `burst` requests at once for each client
+    pub fn new(key: RateLimitKey, rate: f64, burst: usize) -> Self {
+        RateLimit {
+            key,
+            limiter: TokenBucket::new(rate, burst),
+        }
+    }
+
+    /// Check the request, return the time to wait before retrying if it is limited
+    pub fn check(&self, session: &Session) -> Option<Duration> {
+        let key = self.key.key(session)?;
+        self.limiter.acquire(key).err()
+    }
+
+    /// The total number of the requests allowed so far
+    pub fn allowed(&self) -> u64 {
+        self.limiter.allowed()
+    }
+
+    /// The total number of the requests limited so far
+    pub fn limited(&self) -> u64 {
+        self.limiter.limited()
+    }
+
+    /// The number of the clients being tracked
+    pub fn clients(&self) -> usize {
+        self.limiter.len()
+    }
+}
+
+#[async_trait]
+impl BackgroundService for RateLimit {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = time::sleep(CLEANUP_INTERVAL) => {}
+            }
+            let removed = self.limiter.cleanup();
+            debug!("Rate limit dropped {removed} idle clients");
+        }
+    }
+}
+
+impl<SV> HttpProxy<SV> {
+    // respond with 429 if the request exceeds the rate limit, return whether it is limited
+    pub(crate) async fn check_rate_limit(&self, session: &mut Session) -> Result<bool>
+    where
+        SV: ProxyHttp,
+    {
+        let Some(limit) = self.inner.rate_limit() else {
+            return Ok(false);
+        };
+        let Some(retry_after) = limit.check(session) else {
+            return Ok(false);
+        };
+        let mut resp = gen_error_response(429);
+        // round up to whole seconds
+        let secs = retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+        resp.insert_header(header::RETRY_AFTER, secs.max(1))?;
+        if !session.is_body_empty() {
+            // don't bother reading the body
+            session.set_keepalive(None);
+        }
+        session
+            .write_response_tasks(vec![HttpTask::Header(Box::new(resp), true)])
+            .await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_test::io::Builder;
+
+    async fn session(headers: &[u8]) -> Session {
+        let input = [b"GET / HTTP/1.1\r\n".as_slice(), headers, b"\r\n"].concat();
+        let mock_io = Builder::new().read(&input).build();
+        let mut session = Session::new_h1(Box::new(mock_io));
+        session.read_request().await.unwrap();
+        session
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let limit = RateLimit::new(
+            RateLimitKey::Header(HeaderName::from_static("x-api-key")),
+            1.0,
+            2,
+        );
+        let keyed = session(b"X-Api-Key: a\r\n").await;
+        assert!(limit.check(&keyed).is_none());
+        assert!(limit.check(&keyed).is_none());
+        let retry_after = limit.check(&keyed).unwrap();
+        assert!(retry_after <= Duration::from_secs(1));
+
+        // no key, no limit
+        let unkeyed = session(b"").await;
+        for _ in 0..5 {
+            assert!(limit.check(&unkeyed).is_none());
+        }
+        assert_eq!(limit.allowed(), 2);
+        assert_eq!(limit.limited(), 1);
+        assert_eq!(limit.clients(), 1);
+
+        let custom = RateLimit::new(
+            RateLimitKey::Custom(Box::new(|s| Some(s.req_header().uri.path().to_string()))),
+            1.0,
+            1,
+        );
+        assert!(custom.check(&unkeyed).is_none());
+        assert!(custom.check(&keyed).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_cleanup_service() {
+        let limit = RateLimit::new(RateLimitKey::ClientIp, 1.0, 1);
+        let (tx, shutdown) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn(async move { limit.start(shutdown).await });
+        tx.send(true).unwrap();
+        task.await.unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tag every request with an ID for correlation across services
+
+use super::*;
+use http::header::HeaderName;
+use rand::RngCore;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The longest incoming request ID to accept
+const MAX_ID_LEN: usize = 200;
+
+/// How to generate a new request ID
+#[derive(Debug, Clone, Copy)]
+pub enum RequestIdGenerator {
+    /// A random UUID (version 4), e.g., `3f2504e0-4f89-41d3-9a0c-0305e82c3301`
+    Uuid,
+    /// A ULID, which is sortable by its creation time, e.g., `01ARZ3NDEKTSV4RRFFQ69G5FAV`
+    Ulid,
+    /// The given function
+    Custom(fn() -> String),
+}
+
+impl RequestIdGenerator {
+    /// Generate a new ID
+    pub fn generate(&self) -> String {
+        match self {
+            Self::Uuid => uuid_v4(),
+            Self::Ulid => ulid(),
+            Self::Custom(f) => f(),
+        }
+    }
+}
+
+/// The config of request IDs, see [ProxyHttp::request_id()]
+///
+/// The ID of each request is taken from the request header, or generated and added to the
+/// request header when the header is absent, so that the upstream sees the same ID. The ID is
+/// then echoed on the response header.
+#[derive(Debug, Clone)]
+pub struct RequestIdConfig {
+    /// The header that carries the ID, `X-Request-Id` by default
+    pub header: HeaderName,
+    /// How to generate the ID when the request doesn't have one
+    pub generator: RequestIdGenerator,
+    /// Whether to use the ID of the incoming request. When false, a new ID always replaces it.
+    pub trust_incoming: bool,
+    /// Whether to add the ID to the response header
+    pub echo: bool,
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> Self {
+        RequestIdConfig {
+            header: HeaderName::from_static("x-request-id"),
+            generator: RequestIdGenerator::Uuid,
+            trust_incoming: true,
+            echo: true,
+        }
+    }
+}
+
+impl RequestIdConfig {
+    /// Create a new [RequestIdConfig] with the given header and generator
+    pub fn new(header: HeaderName, generator: RequestIdGenerator) -> Self {
+        RequestIdConfig {
+            header,
+            generator,
+            ..Default::default()
+        }
+    }
+
+    // the acceptable ID in the request, if any
+    fn incoming<'a>(&self, req: &'a RequestHeader) -> Option<&'a str> {
+        if !self.trust_incoming {
+            return None;
+        }
+        req.headers
+            .get(&self.header)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= MAX_ID_LEN)
+    }
+}
+
+fn uuid_v4() -> String {
+    let mut b = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut b);
+    b[6] = (b[6] & 0x0f) | 0x40; // version 4
+    b[8] = (b[8] & 0x3f) | 0x80; // RFC 4122 variant
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+        u16::from_be_bytes([b[4], b[5]]),
+        u16::from_be_bytes([b[6], b[7]]),
+        u16::from_be_bytes([b[8], b[9]]),
+        u64::from_be_bytes([0, 0, b[10], b[11], b[12], b[13], b[14], b[15]]),
+    )
+}
+
+// https://github.com/ulid/spec: 48 bits of milliseconds and 80 random bits in Crockford's base32
+fn ulid() -> String {
+    const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+    let ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+        & ((1 << 48) - 1);
+    let mut rand = [0u8; 10];
+    rand::thread_rng().fill_bytes(&mut rand);
+    let mut value = (ms as u128) << 80;
+    for (i, b) in rand.iter().enumerate() {
+        value |= (*b as u128) << (8 * (9 - i));
+    }
+    // 26 characters of 5 bits, the first one only has 3 bits
+    (0..26)
+        .rev()
+        .map(|i| ALPHABET[((value >> (5 * i)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+impl Session {
+    // take the ID of the request or generate one for it
+    pub(crate) fn init_request_id(&mut self, config: Arc<RequestIdConfig>) {
+        let id = match config.incoming(self.req_header()) {
+            Some(id) => id.to_string(),
+            None => {
+                let id = config.generator.generate();
+                // the generated ID should always be a valid header value
+                if let Err(e) = self
+                    .req_header_mut()
+                    .insert_header(config.header.clone(), &id)
+                {
+                    warn!("Fail to add request ID {id}: {e}");
+                }
+                id
+            }
+        };
+        self.request_id = Some((id, config));
+    }
+
+    // add the request ID, if any and configured to, to the response header
+    pub(crate) fn echo_request_id(&self, resp: &mut ResponseHeader) {
+        let Some((id, config)) = self.request_id.as_ref() else {
+            return;
+        };
+        if !config.echo || resp.status.is_informational() {
+            return;
+        }
+        if let Err(e) = resp.insert_header(config.header.clone(), id) {
+            warn!("Fail to echo request ID {id}: {e}");
+        }
+    }
+
+    /// The ID of this request, if [ProxyHttp::request_id()] is configured
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_ref().map(|(id, _)| id.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let id = RequestIdGenerator::Uuid.generate();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert!("89ab".contains(&id[19..20]));
+        assert_ne!(id, RequestIdGenerator::Uuid.generate());
+
+        let id = RequestIdGenerator::Ulid.generate();
+        assert_eq!(id.len(), 26);
+        assert!(id.bytes().all(|c| c.is_ascii_alphanumeric()));
+        // sortable by time, the timestamp part doesn't go backwards
+        assert!(RequestIdGenerator::Ulid.generate()[..10] >= id[..10]);
+
+        let custom = RequestIdGenerator::Custom(|| "my-id".into());
+        assert_eq!(custom.generate(), "my-id");
+    }
+
+    #[test]
+    fn test_incoming() {
+        let config = RequestIdConfig::default();
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        assert!(config.incoming(&req).is_none());
+        req.insert_header("X-Request-Id", "abc").unwrap();
+        assert_eq!(config.incoming(&req), Some("abc"));
+        req.insert_header("X-Request-Id", "a".repeat(MAX_ID_LEN + 1))
+            .unwrap();
+        assert!(config.incoming(&req).is_none());
+
+        let config = RequestIdConfig {
+            trust_incoming: false,
+            ..Default::default()
+        };
+        req.insert_header("X-Request-Id", "abc").unwrap();
+        assert!(config.incoming(&req).is_none());
+    }
+}
@@ -0,0 +1,392 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The policy to retry failed upstream requests
+
+use pingora_error::{Error, RequestProgress};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::MAX_RETRIES;
+
+/// The policy to retry the failed attempts to proxy a request to the upstream
+///
+/// See [crate::ProxyHttp::retry_policy()]. Each attempt calls [crate::ProxyHttp::upstream_peer()]
+/// again so the retry can go to a different upstream.
+///
+/// Besides the errors decided to be retried by the existing filters, e.g.,
+/// [crate::ProxyHttp::fail_to_connect()], this policy retries:
+/// - the errors that happen before the request reaches the upstream
+/// - with `retry_idempotent`, the errors that happen before the response starts when the request
+///   method is idempotent
+/// - the responses with one of the `retry_statuses`, unless it is the last attempt
+///
+/// A request is never retried once the response has started to be sent to the client, or if its
+/// body is already sent to the upstream but not kept in the retry buffer, i.e., it is larger
+/// than `max_buffered_request_body` of the server conf.
+///
+/// With a `budget`, a retry of this policy is only made when the [RetryBudget] allows it,
+/// otherwise the request fails with the error of its last attempt, or the response with a
+/// retried status is sent as is. The retries decided by the existing filters are not limited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one. It is capped at 16.
+    pub max_attempts: usize,
+    /// The time limit of each attempt, from selecting the upstream till the end of the response
+    pub attempt_timeout: Option<Duration>,
+    /// The delay before the first retry, which doubles on each following retry
+    pub backoff: Duration,
+    /// The maximum delay before a retry
+    pub max_backoff: Duration,
+    /// The upstream response status codes to retry on
+    pub retry_statuses: Vec<u16>,
+    /// Whether to retry idempotent requests that fail after being sent to the upstream
+    pub retry_idempotent: bool,
+    /// The [RetryBudget] limiting the retries, usually shared by the policies of all the requests
+    pub budget: Option<Arc<RetryBudget>>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: MAX_RETRIES,
+            attempt_timeout: None,
+            backoff: Duration::ZERO,
+            max_backoff: Duration::from_secs(1),
+            retry_statuses: vec![],
+            retry_idempotent: false,
+            budget: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Create a new [RetryPolicy] that makes at most the given number of attempts
+    pub fn new(max_attempts: usize) -> Self {
+        RetryPolicy {
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    /// The number of attempts to make, between 1 and 16
+    pub fn attempts(&self) -> usize {
+        self.max_attempts.clamp(1, MAX_RETRIES)
+    }
+
+    /// The delay before the given retry, starting from 1 for the second attempt
+    pub fn backoff(&self, retry: usize) -> Duration {
+        if retry == 0 || self.backoff.is_zero() {
+            return Duration::ZERO;
+        }
+        // cap the shift so that it never overflows
+        let factor = 1u32 << (retry - 1).min(16);
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+
+    /// Whether the upstream response with this status code should be retried
+    pub fn retry_on_status(&self, status: u16) -> bool {
+        self.retry_statuses.contains(&status)
+    }
+
+    /// Whether the request that failed with this error should be retried given its progress
+    ///
+    /// The number of attempts left and the request body are not considered here.
+    pub fn should_retry(&self, e: &Error, progress: RequestProgress) -> bool {
+        let progress = RequestProgress {
+            idempotent: self.retry_idempotent && progress.idempotent,
+            ..progress
+        };
+        e.is_retriable_at(progress)
+    }
+}
+
+/// A limit of the retries relative to the requests, to avoid retry storms
+///
+/// When the upstreams fail, retrying every failed request multiplies the load on the upstreams
+/// that are already struggling. A [RetryBudget] allows retries only up to a `ratio` of the
+/// requests over the last one to two `window`s, e.g., 10%, plus `min_retries` per window so that
+/// a proxy with little traffic can still retry. The retries beyond the budget are not made and
+/// counted as [Self::exhausted()].
+///
+/// Share one budget, e.g., in a static or in the [crate::ProxyHttp] implementation, among the
+/// [RetryPolicy] of the requests it should limit, usually all the requests of the process. The
+/// counters are updated without locking, so the budget is approximate under concurrency.
+#[derive(Debug)]
+pub struct RetryBudget {
+    ratio: f64,
+    min_retries: u64,
+    window: Duration,
+    start: Instant,
+    requests: WindowCounter,
+    window_retries: WindowCounter,
+    // all time counters
+    retries: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+impl RetryBudget {
+    /// Create a new [RetryBudget] that allows `ratio` retries per request, e.g., `0.1` for 10%, with
+    /// 10 retries per 10 second window at least.
+    pub fn new(ratio: f64) -> Self {
+        RetryBudget {
+            ratio: ratio.max(0.0),
+            min_retries: 10,
+            window: Duration::from_secs(10),
+            start: Instant::now(),
+            requests: Default::default(),
+            window_retries: Default::default(),
+            retries: AtomicU64::new(0),
+            exhausted: AtomicU64::new(0),
+        }
+    }
+
+    /// Allow this number of retries per window regardless of the ratio
+    pub fn with_min_retries(mut self, min_retries: u64) -> Self {
+        self.min_retries = min_retries;
+        self
+    }
+
+    /// Set the window over which the retries are compared to the requests
+    ///
+    /// # Panics
+    /// if `window` is zero
+    pub fn with_window(mut self, window: Duration) -> Self {
+        assert!(!window.is_zero(), "retry budget window must be positive");
+        self.window = window;
+        self
+    }
+
+    // the index of the current window since `start`
+    fn epoch(&self) -> u64 {
+        (self.start.elapsed().as_nanos() / self.window.as_nanos()) as u64
+    }
+
+    /// Count a new request, which adds `ratio` to the retries allowed
+    ///
+    /// The proxy calls this once per request whose [RetryPolicy] has this budget.
+    pub fn record_request(&self) {
+        self.requests.add(self.epoch());
+    }
+
+    /// Take a retry from the budget. Return false if the budget is exhausted, in which case the
+    /// retry should not be made.
+    pub fn try_retry(&self) -> bool {
+        let epoch = self.epoch();
+        let requests = self.requests.sum(epoch);
+        let allowed = ((requests as f64 * self.ratio) as u64).max(self.min_retries);
+        if self.window_retries.sum(epoch) >= allowed {
+            self.exhausted.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        self.window_retries.add(epoch);
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// The number of the retries allowed by this budget so far
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// The number of the retries denied by this budget so far
+    pub fn exhausted(&self) -> u64 {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+}
+
+// The counts of the current and the previous window, in the slot of the parity of their window.
+// Each count is tagged with the lower 32 bits of its window index in its upper 32 bits, so a
+// count of a window that is over is replaced atomically by the first update of a new window,
+// instead of being reset separately which would lose the updates made in between.
+#[derive(Debug, Default)]
+struct WindowCounter([AtomicU64; 2]);
+
+impl WindowCounter {
+    const COUNT_MASK: u64 = u32::MAX as u64;
+
+    fn tag(epoch: u64) -> u64 {
+        epoch << 32
+    }
+
+    fn add(&self, epoch: u64) {
+        let tag = Self::tag(epoch);
+        let _ =
+            self.0[(epoch % 2) as usize].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                if v & !Self::COUNT_MASK != tag {
+                    Some(tag | 1)
+                } else if v & Self::COUNT_MASK == Self::COUNT_MASK {
+                    None // saturated
+                } else {
+                    Some(v + 1)
+                }
+            });
+    }
+
+    fn sum(&self, epoch: u64) -> u64 {
+        let current = Self::tag(epoch);
+        let previous = Self::tag(epoch.wrapping_sub(1));
+        self.0
+            .iter()
+            .map(|c| c.load(Ordering::Relaxed))
+            .filter(|v| v & !Self::COUNT_MASK == current || v & !Self::COUNT_MASK == previous)
+            .map(|v| v & Self::COUNT_MASK)
+            .sum()
+    }
+}
+
+// budgets are shared, so two budgets are equal only if they are the same one
+impl PartialEq for RetryBudget {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for RetryBudget {}
+
+/// Whether the request method is idempotent per RFC 9110
+pub(crate) fn is_idempotent(method: &http::Method) -> bool {
+    use http::Method;
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_error::ErrorType::*;
+
+    #[test]
+    fn test_attempts() {
+        assert_eq!(RetryPolicy::default().attempts(), 16);
+        assert_eq!(RetryPolicy::new(3).attempts(), 3);
+        assert_eq!(RetryPolicy::new(0).attempts(), 1);
+        assert_eq!(RetryPolicy::new(100).attempts(), 16);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+            ..RetryPolicy::new(10)
+        };
+        assert_eq!(policy.backoff(0), Duration::ZERO);
+        assert_eq!(policy.backoff(1), Duration::from_millis(10));
+        assert_eq!(policy.backoff(2), Duration::from_millis(20));
+        assert_eq!(policy.backoff(3), Duration::from_millis(40));
+        assert_eq!(policy.backoff(4), Duration::from_millis(50));
+        assert_eq!(policy.backoff(100), Duration::from_millis(50));
+        assert_eq!(RetryPolicy::new(3).backoff(2), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_should_retry() {
+        let idempotent = RequestProgress {
+            idempotent: true,
+            ..Default::default()
+        };
+        let started = RequestProgress {
+            response_started: true,
+            idempotent: true,
+        };
+
+        let policy = RetryPolicy::new(3);
+        assert!(policy.should_retry(&Error::new_up(ConnectRefused), idempotent));
+        assert!(!policy.should_retry(&Error::new_up(ConnectRefused), started));
+        // not enabled
+        assert!(!policy.should_retry(&Error::new_up(ReadTimedout), idempotent));
+
+        let policy = RetryPolicy {
+            retry_idempotent: true,
+            retry_statuses: vec![502, 503],
+            ..policy
+        };
+        assert!(policy.should_retry(&Error::new_up(ReadTimedout), idempotent));
+        assert!(!policy.should_retry(&Error::new_up(ReadTimedout), Default::default()));
+        assert!(!policy.should_retry(&Error::new_up(ReadTimedout), started));
+        assert!(policy.retry_on_status(503));
+        assert!(!policy.retry_on_status(500));
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let budget = RetryBudget::new(0.1).with_min_retries(2);
+        // the minimum when there is no request yet
+        assert!(budget.try_retry());
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+
+        for _ in 0..100 {
+            budget.record_request();
+        }
+        // 10% of 100, 2 of them are already made
+        for _ in 0..8 {
+            assert!(budget.try_retry());
+        }
+        assert!(!budget.try_retry());
+        assert_eq!(budget.retries(), 10);
+        assert_eq!(budget.exhausted(), 2);
+    }
+
+    #[test]
+    fn test_retry_budget_window() {
+        let budget = RetryBudget::new(0.5)
+            .with_min_retries(0)
+            .with_window(Duration::from_millis(20));
+        for _ in 0..2 {
+            budget.record_request();
+        }
+        assert!(budget.try_retry());
+        assert!(!budget.try_retry());
+        // the requests and retries of the windows that are over are forgotten
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!budget.try_retry());
+        budget.record_request();
+        budget.record_request();
+        assert!(budget.try_retry());
+    }
+
+    #[test]
+    fn test_window_counter() {
+        let counter = WindowCounter::default();
+        counter.add(0);
+        counter.add(1);
+        counter.add(1);
+        assert_eq!(counter.sum(1), 3);
+        // the count of window 0 is replaced, not added to
+        counter.add(2);
+        assert_eq!(counter.sum(2), 3);
+        // only the current and the previous windows count
+        assert_eq!(counter.sum(3), 1);
+        assert_eq!(counter.sum(4), 0);
+        // the window index wraps around 32 bits
+        let counter = WindowCounter::default();
+        let epoch = u32::MAX as u64;
+        counter.add(epoch);
+        counter.add(epoch + 1);
+        assert_eq!(counter.sum(epoch + 1), 2);
+    }
+
+    #[test]
+    fn test_idempotent() {
+        assert!(is_idempotent(&http::Method::GET));
+        assert!(is_idempotent(&http::Method::PUT));
+        assert!(!is_idempotent(&http::Method::POST));
+        assert!(!is_idempotent(&http::Method::PATCH));
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The timestamps of the phases of a request
+
+use pingora_core::protocols::http::HttpTask;
+use std::time::{Duration, Instant};
+
+/// The time when each phase of a request starts, see [crate::Session::timings()]
+///
+/// The upstream timestamps are of the last attempt when the request is retried. A phase that is
+/// not reached, e.g., the upstream phases of a cache hit, is `None`.
+#[derive(Debug, Clone, Copy)]
+pub struct Timings {
+    /// When the request header is read from downstream
+    pub request_start: Instant,
+    /// When the upstream peer is selected
+    pub upstream_start: Option<Instant>,
+    /// When the connection to the upstream is established or reused
+    pub upstream_connected: Option<Instant>,
+    /// When the (final) response header is received from the upstream
+    pub upstream_header: Option<Instant>,
+    /// When the upstream response is fully received
+    pub upstream_end: Option<Instant>,
+    /// When the (final) response header is sent to downstream
+    pub response_start: Option<Instant>,
+    /// When the response is fully sent to downstream or the request fails
+    pub response_end: Option<Instant>,
+}
+
+fn between(start: Option<Instant>, end: Option<Instant>) -> Option<Duration> {
+    Some(end?.saturating_duration_since(start?))
+}
+
+impl Timings {
+    pub(crate) fn new() -> Self {
+        Timings {
+            request_start: Instant::now(),
+            upstream_start: None,
+            upstream_connected: None,
+            upstream_header: None,
+            upstream_end: None,
+            response_start: None,
+            response_end: None,
+        }
+    }
+
+    // a new attempt to proxy to upstream starts, drop the timestamps of the previous attempt
+    pub(crate) fn upstream_started(&mut self) {
+        self.upstream_start = Some(Instant::now());
+        self.upstream_connected = None;
+        self.upstream_header = None;
+        self.upstream_end = None;
+    }
+
+    pub(crate) fn upstream_connected(&mut self) {
+        self.upstream_connected = Some(Instant::now());
+    }
+
+    // record the upstream response task as it arrives
+    pub(crate) fn upstream_task(&mut self, task: &HttpTask) {
+        if self.upstream_header.is_none() && is_final_header(task) {
+            self.upstream_header = Some(Instant::now());
+        }
+        if task.is_end() && !matches!(task, HttpTask::Failed(_)) {
+            self.upstream_end = Some(Instant::now());
+        }
+    }
+
+    pub(crate) fn response_started(&mut self) {
+        self.response_start.get_or_insert_with(Instant::now);
+    }
+
+    pub(crate) fn response_finished(&mut self) {
+        self.response_end.get_or_insert_with(Instant::now);
+    }
+
+    /// The time spent to connect to the upstream, including the connection reuse
+    pub fn upstream_connect_time(&self) -> Option<Duration> {
+        between(self.upstream_start, self.upstream_connected)
+    }
+
+    /// The time from selecting the upstream till its response header is received
+    pub fn time_to_first_byte(&self) -> Option<Duration> {
+        between(self.upstream_start, self.upstream_header)
+    }
+
+    /// The time from selecting the upstream till its response is fully received
+    pub fn upstream_response_time(&self) -> Option<Duration> {
+        between(self.upstream_start, self.upstream_end)
+    }
+
+    /// The time from sending the response header to downstream till the response is finished
+    pub fn body_transfer_time(&self) -> Option<Duration> {
+        between(self.response_start, self.response_end)
+    }
+
+    /// The time from reading the request header till the response is finished, or till now if
+    /// the response is not finished yet
+    pub fn total(&self) -> Duration {
+        self.response_end
+            .unwrap_or_else(Instant::now)
+            .saturating_duration_since(self.request_start)
+    }
+}
+
+// whether the task is the response header that is not 1xx
+pub(crate) fn is_final_header(task: &HttpTask) -> bool {
+    matches!(task, HttpTask::Header(h, _) if !h.status.is_informational())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_http::ResponseHeader;
+
+    #[test]
+    fn test_timings() {
+        let mut timings = Timings::new();
+        assert!(timings.time_to_first_byte().is_none());
+
+        timings.upstream_started();
+        timings.upstream_connected();
+        let interim = ResponseHeader::build(100, None).unwrap();
+        timings.upstream_task(&HttpTask::Header(Box::new(interim), false));
+        assert!(timings.upstream_header.is_none());
+        let header = ResponseHeader::build(200, None).unwrap();
+        timings.upstream_task(&HttpTask::Header(Box::new(header), false));
+        assert!(timings.time_to_first_byte().is_some());
+        assert!(timings.upstream_response_time().is_none());
+        timings.upstream_task(&HttpTask::Done);
+        assert!(timings.upstream_response_time() >= timings.time_to_first_byte());
+
+        timings.response_started();
+        assert!(timings.body_transfer_time().is_none());
+        timings.response_finished();
+        let total = timings.total();
+        assert!(timings.body_transfer_time().unwrap() <= total);
+        // stays the same once finished
+        assert_eq!(timings.total(), total);
+
+        // a retry drops the previous upstream timestamps
+        timings.upstream_started();
+        assert!(timings.upstream_connect_time().is_none());
+        assert!(timings.time_to_first_byte().is_none());
+    }
+}
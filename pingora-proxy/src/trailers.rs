@@ -0,0 +1,327 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Relaying the trailers of the requests and the responses, e.g., the gRPC status
+//!
+//! The trailers are relayed between any combination of HTTP/1.1 and HTTP/2 on either side. Over
+//! HTTP/1.1 they are only carried by chunked bodies: they are dropped when the other side uses
+//! `Content-Length`, or when the downstream client doesn't ask for them with `TE: trailers`.
+//!
+//! The trailers added to the [Session] are sent at the end of the response too, merged into the
+//! ones from the upstream if any.
+
+use super::*;
+use http::header::{AsHeaderName, IntoHeaderName};
+use http::{HeaderMap, HeaderValue};
+
+impl Session {
+    /// The trailers added to the response so far, see [Self::response_trailers_mut()]
+    pub fn response_trailers(&self) -> Option<&HeaderMap> {
+        self.response_trailers.as_deref()
+    }
+
+    /// The trailers to add to the response, e.g., a checksum of the body computed in
+    /// [ProxyHttp::response_body_filter()].
+    ///
+    /// They are merged into the trailers from the upstream, replacing the ones of the same names.
+    /// If the upstream response has no trailers, they are sent after the last piece of the body.
+    /// Either way they go through [ProxyHttp::response_trailer_filter()]. The trailers added
+    /// before the response header is sent are announced in its `Trailer` header.
+    pub fn response_trailers_mut(&mut self) -> &mut HeaderMap {
+        self.response_trailers.get_or_insert_with(Default::default)
+    }
+
+    /// Add a trailer to the response, replacing the existing one of the same name
+    pub fn insert_response_trailer(
+        &mut self,
+        name: impl IntoHeaderName,
+        value: impl TryInto<HeaderValue>,
+    ) -> Result<()> {
+        let value = value
+            .try_into()
+            .explain_err(InvalidHTTPHeader, |_| "invalid trailer value")?;
+        self.response_trailers_mut().insert(name, value);
+        Ok(())
+    }
+
+    /// Remove a trailer added to the response. The trailers from the upstream should be removed
+    /// in [ProxyHttp::response_trailer_filter()].
+    pub fn remove_response_trailer(&mut self, name: impl AsHeaderName) -> Option<HeaderValue> {
+        self.response_trailers.as_mut()?.remove(name)
+    }
+
+    // list the trailers added so far in the `Trailer` header of the response
+    pub(crate) fn announce_response_trailers(&self, header: &mut ResponseHeader) {
+        let Some(trailers) = self.response_trailers.as_ref() else {
+            return;
+        };
+        if trailers.is_empty() || !self.downstream_session.accepts_trailers() {
+            return;
+        }
+        let names: Vec<&str> = trailers.keys().map(|name| name.as_str()).collect();
+        // the names are valid header values already
+        let _ = header.append_header(header::TRAILER, names.join(", "));
+    }
+}
+
+impl<SV> HttpProxy<SV> {
+    // read the request trailers once the request body is done and run them through the filter
+    pub(crate) async fn request_trailers(
+        &self,
+        session: &mut Session,
+        ctx: &mut SV::CTX,
+    ) -> Result<Option<Box<HeaderMap>>>
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        let Some(mut trailers) = session
+            .downstream_session
+            .read_request_trailers()
+            .await
+            .map_err(|e| e.into_down())?
+        else {
+            return Ok(None);
+        };
+        debug!("Request trailers: {:?}", trailers);
+        self.inner
+            .request_trailer_filter(session, &mut trailers, ctx)
+            .await?;
+        Ok(Some(trailers))
+    }
+
+    // run the response trailers, along with the added ones, through the filter, return the task
+    // to send to downstream
+    pub(crate) async fn response_trailers_task(
+        &self,
+        session: &mut Session,
+        trailers: Option<Box<HeaderMap>>,
+        ctx: &mut SV::CTX,
+    ) -> HttpTask
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        let added = session.response_trailers.take();
+        let mut trailers = match (trailers, added) {
+            (None, None) => return HttpTask::Done,
+            (None, Some(added)) => added,
+            (Some(mut trailers), added) => {
+                if let Some(added) = added {
+                    trailers.extend(*added);
+                }
+                trailers
+            }
+        };
+        debug!("Response trailers: {:?}", trailers);
+        match self
+            .inner
+            .response_trailer_filter(session, &mut trailers, ctx)
+            .await
+        {
+            Ok(None) => HttpTask::Trailer(Some(trailers)),
+            // if we have a trailer buffer write it to the downstream response body
+            Ok(Some(buffer)) => {
+                // write_body will not write additional bytes after reaching the content-length
+                // for gRPC H2 -> H1 this is not a problem but may be a problem for non gRPC code
+                // https://http2.github.io/http2-spec/#malformed
+                HttpTask::Body(Some(buffer), true)
+            }
+            Err(e) => {
+                error!(
+                    "Encountered error while filtering upstream trailers {:?}",
+                    e
+                );
+                HttpTask::Done
+            }
+        }
+    }
+
+    // send the added trailers at the end of the response if the upstream doesn't send any
+    pub(crate) async fn add_response_trailers(
+        &self,
+        session: &mut Session,
+        tasks: &mut Vec<HttpTask>,
+        ctx: &mut SV::CTX,
+    ) where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        if session.response_trailers.is_none() {
+            return;
+        }
+        // the trailers from the upstream already went through response_trailers_task()
+        let Some(index) = tasks
+            .iter()
+            .position(|t| matches!(t, HttpTask::Body(_, true) | HttpTask::Done))
+        else {
+            return;
+        };
+        let task = self.response_trailers_task(session, None, ctx).await;
+        match &mut tasks[index] {
+            HttpTask::Body(_, end) => {
+                *end = false;
+                tasks.insert(index + 1, task);
+            }
+            end => *end = task,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_core::protocols::http::v2::server::{handshake, HttpSession as SessionV2};
+    use std::sync::Arc;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    // marks the trailers it sees
+    struct TrailerProxy;
+
+    #[async_trait]
+    impl ProxyHttp for TrailerProxy {
+        type CTX = ();
+        fn new_ctx(&self) -> Self::CTX {}
+
+        async fn upstream_peer(
+            &self,
+            _session: &mut Session,
+            _ctx: &mut Self::CTX,
+        ) -> Result<Box<HttpPeer>> {
+            unreachable!("no upstream in these tests")
+        }
+
+        async fn response_trailer_filter(
+            &self,
+            _session: &mut Session,
+            trailers: &mut HeaderMap,
+            _ctx: &mut Self::CTX,
+        ) -> Result<Option<Bytes>> {
+            trailers.insert("x-filtered", HeaderValue::from_static("1"));
+            Ok(None)
+        }
+    }
+
+    fn trailer_proxy() -> Arc<HttpProxy<TrailerProxy>> {
+        HttpProxy::new(TrailerProxy, Arc::new(ServerConf::default()))
+    }
+
+    #[tokio::test]
+    async fn test_h1_response_trailers() {
+        let proxy = trailer_proxy();
+        for (te, trailers) in [("TE: trailers\r\n", true), ("", false)] {
+            let (mut client, server) = duplex(65536);
+            let req = format!("GET / HTTP/1.1\r\nHost: pingora.org\r\n{te}\r\n");
+            client.write_all(req.as_bytes()).await.unwrap();
+
+            let mut session = Session::new_h1(Box::new(server));
+            session.read_request().await.unwrap();
+            session
+                .insert_response_trailer("x-checksum", "abc")
+                .unwrap();
+            let mut resp = ResponseHeader::build(200, None).unwrap();
+            resp.insert_header("transfer-encoding", "chunked").unwrap();
+            session.announce_response_trailers(&mut resp);
+            let mut tasks = vec![
+                HttpTask::Header(Box::new(resp), false),
+                HttpTask::Body(Some("hello".into()), true),
+            ];
+            // the upstream has no trailers, the added ones still go through the filter
+            proxy
+                .add_response_trailers(&mut session, &mut tasks, &mut ())
+                .await;
+            assert!(session.response_trailers().is_none());
+            assert!(session.write_response_tasks(tasks).await.unwrap());
+            drop(session);
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            let response = response.to_lowercase();
+            if trailers {
+                assert!(response.contains("trailer: x-checksum\r\n"), "{response}");
+                let (_, trailers) = response.split_once("5\r\nhello\r\n0\r\n").unwrap();
+                assert!(trailers.contains("x-checksum: abc\r\n"), "{trailers}");
+                assert!(trailers.contains("x-filtered: 1\r\n"), "{trailers}");
+                assert!(trailers.ends_with("\r\n\r\n"));
+            } else {
+                assert!(!response.contains("trailer:"), "{response}");
+                assert!(response.ends_with("5\r\nhello\r\n0\r\n\r\n"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_h2_response_trailers() {
+        let (client, server) = duplex(65536);
+
+        tokio::spawn(async move {
+            let proxy = trailer_proxy();
+            let mut connection = handshake(Box::new(server), None).await.unwrap();
+            let digest = Arc::new(Digest::default());
+            while let Some(http) = SessionV2::from_h2_conn(&mut connection, digest.clone())
+                .await
+                .unwrap()
+            {
+                let proxy = proxy.clone();
+                tokio::spawn(async move {
+                    let mut session = Session::new(HttpSession::new_http2(http));
+                    session
+                        .insert_response_trailer("x-checksum", "abc")
+                        .unwrap();
+                    session.insert_response_trailer("grpc-status", "0").unwrap();
+                    session.remove_response_trailer("grpc-status");
+                    let mut resp = ResponseHeader::build(200, None).unwrap();
+                    session.announce_response_trailers(&mut resp);
+                    let mut upstream_trailers = HeaderMap::new();
+                    upstream_trailers.insert("grpc-status", "1".parse().unwrap());
+                    let trailers = proxy
+                        .response_trailers_task(
+                            &mut session,
+                            Some(Box::new(upstream_trailers)),
+                            &mut (),
+                        )
+                        .await;
+                    let tasks = vec![
+                        HttpTask::Header(Box::new(resp), false),
+                        HttpTask::Body(Some("hello".into()), false),
+                        trailers,
+                    ];
+                    assert!(session.write_response_tasks(tasks).await.unwrap());
+                });
+            }
+        });
+
+        let (h2, connection) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(async move {
+            connection.await.unwrap();
+        });
+        let mut h2 = h2.ready().await.unwrap();
+        let request = http::Request::builder()
+            .uri("https://www.example.com/")
+            .body(())
+            .unwrap();
+        let (response, _) = h2.send_request(request, true).unwrap();
+
+        let (head, mut body) = response.await.unwrap().into_parts();
+        assert_eq!(head.status, 200);
+        assert_eq!(head.headers.get("trailer").unwrap(), "x-checksum");
+        assert_eq!(body.data().await.unwrap().unwrap(), "hello");
+        assert!(body.data().await.is_none());
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers.get("x-checksum").unwrap(), "abc");
+        // the upstream trailers are relayed along with the added ones
+        assert_eq!(trailers.get("grpc-status").unwrap(), "1");
+        assert_eq!(trailers.get("x-filtered").unwrap(), "1");
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tunnel `CONNECT` requests
+//!
+//! Once [ProxyHttp::connect_tunnel_peer()] picks a peer for a `CONNECT` request, the proxy
+//! connects to it, answers `200` and then relays the raw bytes between the client and the peer
+//! until both sides close, either of them fails or the tunnel is idle for too long.
+
+use super::*;
+use http::Method;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const TUNNEL_BUF_SIZE: usize = 16 * 1024;
+
+/// The target of the `CONNECT` request, `None` if it has none
+pub(crate) fn connect_target(req: &RequestHeader) -> Option<&http::uri::Authority> {
+    if req.method != Method::CONNECT {
+        return None;
+    }
+    req.uri.authority()
+}
+
+impl<SV> HttpProxy<SV> {
+    // tunnel the CONNECT request if the peer to tunnel to is given. Return whether the request
+    // is handled; errors before the tunnel is established should be answered by fail_to_proxy()
+    pub(crate) async fn proxy_connect(
+        &self,
+        session: &mut Session,
+        ctx: &mut SV::CTX,
+    ) -> Result<bool>
+    where
+        SV: ProxyHttp + Send + Sync,
+        SV::CTX: Send + Sync,
+    {
+        // h2 CONNECT streams can't be taken over
+        if session.is_http2() || session.req_header().method != Method::CONNECT {
+            return Ok(false);
+        }
+        let Some(target) = connect_target(session.req_header()).cloned() else {
+            return Ok(false);
+        };
+        let Some(peer) = self
+            .inner
+            .connect_tunnel_peer(session, target.as_str(), ctx)
+            .await?
+        else {
+            return Ok(false);
+        };
+        // the port is required to tunnel, RFC 9110 9.3.6
+        if target.port_u16().is_none() {
+            return Error::e_explain(HTTPStatus(400), "invalid CONNECT target");
+        }
+        debug!("tunnel CONNECT {target} to {peer}");
+
+        let mut upstream = self
+            .client_tunnel
+            .new_stream(&*peer)
+            .await
+            .map_err(|e| e.into_up())?;
+        session.upstream_addr = Some(peer.address().clone());
+
+        let resp = ResponseHeader::build(200, Some(0))?;
+        session.write_response_header(Box::new(resp)).await?;
+        let Some((downstream, preread)) = session.downstream_session.take_over() else {
+            // checked above
+            return Error::e_explain(InternalError, "no stream to tunnel");
+        };
+        match splice(
+            downstream,
+            &mut upstream,
+            preread,
+            peer.options.upgrade_idle_timeout,
+        )
+        .await
+        {
+            Ok((sent, received)) => {
+                debug!("tunnel to {target} closed, sent {sent} bytes, received {received} bytes")
+            }
+            // the response is already sent, nothing else to do
+            Err(e) => warn!("tunnel to {target} failed: {e}"),
+        }
+        let _ = upstream.shutdown().await;
+        Ok(true)
+    }
+}
+
+// which side of the tunnel an event comes from
+enum Side {
+    Downstream,
+    Upstream,
+}
+
+// Relay the bytes between the two streams until both of them close or either fails. The EOF of
+// one side is relayed as a write shutdown of the other. Return the bytes sent to the upstream and
+// to the downstream.
+async fn splice(
+    downstream: &mut Stream,
+    upstream: &mut Stream,
+    preread: Bytes,
+    idle_timeout: Option<Duration>,
+) -> Result<(usize, usize)> {
+    let mut sent = preread.len();
+    let mut received = 0;
+    if !preread.is_empty() {
+        upstream
+            .write_all(&preread)
+            .await
+            .or_err(WriteError, "writing to the tunnel upstream")?;
+        upstream
+            .flush()
+            .await
+            .or_err(WriteError, "flushing the tunnel upstream")?;
+    }
+
+    let mut down_buf = vec![0; TUNNEL_BUF_SIZE];
+    let mut up_buf = vec![0; TUNNEL_BUF_SIZE];
+    let mut downstream_done = false;
+    let mut upstream_done = false;
+    while !downstream_done || !upstream_done {
+        let read = async {
+            tokio::select! {
+                n = downstream.read(&mut down_buf), if !downstream_done => (Side::Downstream, n),
+                n = upstream.read(&mut up_buf), if !upstream_done => (Side::Upstream, n),
+            }
+        };
+        // the idle timer restarts on every read of either side
+        let (side, n) = match idle_timeout {
+            Some(t) => match pingora_timeout::timeout(t, read).await {
+                Ok(r) => r,
+                Err(_) => {
+                    return Error::e_explain(ReadTimedout, format!("tunnel idle for {t:?}"));
+                }
+            },
+            None => read.await,
+        };
+        match side {
+            Side::Downstream => {
+                let n = n.or_err(ReadError, "reading from the tunnel downstream")?;
+                if n == 0 {
+                    downstream_done = true;
+                    let _ = upstream.shutdown().await;
+                    continue;
+                }
+                upstream
+                    .write_all(&down_buf[..n])
+                    .await
+                    .or_err(WriteError, "writing to the tunnel upstream")?;
+                upstream
+                    .flush()
+                    .await
+                    .or_err(WriteError, "flushing the tunnel upstream")?;
+                sent += n;
+            }
+            Side::Upstream => {
+                let n = n.or_err(ReadError, "reading from the tunnel upstream")?;
+                if n == 0 {
+                    upstream_done = true;
+                    let _ = downstream.shutdown().await;
+                    continue;
+                }
+                downstream
+                    .write_all(&up_buf[..n])
+                    .await
+                    .or_err(WriteError, "writing to the tunnel downstream")?;
+                downstream
+                    .flush()
+                    .await
+                    .or_err(WriteError, "flushing the tunnel downstream")?;
+                received += n;
+            }
+        }
+    }
+    Ok((sent, received))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_test::io::Builder;
+
+    #[test]
+    fn test_connect_target() {
+        let req = RequestHeader::build("CONNECT", b"pingora.org:443", None).unwrap();
+        assert_eq!(connect_target(&req).unwrap(), "pingora.org:443");
+        let req = RequestHeader::build("CONNECT", b"pingora.org", None).unwrap();
+        assert_eq!(connect_target(&req).unwrap(), "pingora.org");
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        assert!(connect_target(&req).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_splice() {
+        let mut downstream: Stream = Box::new(
+            Builder::new()
+                .read(b"world")
+                .write(b"pong")
+                .wait(Duration::from_millis(10))
+                .build(),
+        );
+        let mut upstream: Stream = Box::new(
+            Builder::new()
+                .write(b"hello ")
+                .write(b"world")
+                .read(b"pong")
+                .build(),
+        );
+        let (sent, received) = splice(
+            &mut downstream,
+            &mut upstream,
+            Bytes::from_static(b"hello "),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(sent, 11);
+        assert_eq!(received, 4);
+    }
+
+    #[tokio::test]
+    async fn test_splice_idle_timeout() {
+        let mut downstream: Stream = Box::new(Builder::new().wait(Duration::from_secs(10)).build());
+        let mut upstream: Stream = Box::new(Builder::new().wait(Duration::from_secs(10)).build());
+        let e = splice(
+            &mut downstream,
+            &mut upstream,
+            Bytes::new(),
+            Some(Duration::from_millis(10)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(e.etype(), &ReadTimedout);
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Route trusted requests to the upstream named in a request header, e.g., for canary testing
+
+use super::*;
+use http::header::HeaderName;
+use http::uri::Authority;
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+/// A function that decides whether the request is trusted to choose its upstream
+pub type TrustFn = Box<dyn Fn(&Session) -> bool + Send + Sync>;
+
+/// Which requests are trusted to choose their upstreams
+pub enum OverrideTrust {
+    /// The requests from the given client IPs, see [Session::client_addr()]
+    ClientIps(HashSet<IpAddr>),
+    /// The requests that carry the given secret in the given header
+    Secret(HeaderName, String),
+    /// The given function, e.g., to verify a signature of the request
+    Custom(TrustFn),
+}
+
+impl OverrideTrust {
+    fn trusted(&self, session: &Session) -> bool {
+        match self {
+            Self::ClientIps(ips) => session
+                .client_addr()
+                .and_then(|addr| addr.as_inet())
+                .is_some_and(|addr| ips.contains(&addr.ip())),
+            Self::Secret(header, secret) => session
+                .req_header()
+                .headers
+                .get(header)
+                .is_some_and(|v| constant_time_eq(v.as_bytes(), secret.as_bytes())),
+            Self::Custom(f) => f(session),
+        }
+    }
+}
+
+/// Force the upstream of a trusted request via a request header like
+/// `X-Debug-Upstream: 10.0.0.1:8080`, bypassing the load balancer
+///
+/// The header is ignored on the requests that are not trusted, see [OverrideTrust]. Call
+/// [Self::peer()] in [ProxyHttp::upstream_peer()] before selecting the upstream as usual, and
+/// [Self::remove_headers()] in [ProxyHttp::upstream_request_filter()] so that the upstream
+/// doesn't see the headers. See [parse_upstream()] for the format of the header.
+pub struct UpstreamOverride {
+    header: HeaderName,
+    trust: OverrideTrust,
+    allowed: Option<HashSet<String>>,
+}
+
+impl UpstreamOverride {
+    /// Create a new [UpstreamOverride] that reads the upstream from the given header of the
+    /// requests trusted by `trust`
+    pub fn new(header: HeaderName, trust: OverrideTrust) -> Self {
+        UpstreamOverride {
+            header,
+            trust,
+            allowed: None,
+        }
+    }
+
+    /// Only allow the given upstreams, written in the same format as the header, e.g.,
+    /// `10.0.0.1:8080`. Any upstream is allowed by default.
+    pub fn with_allowed_upstreams<I, S>(mut self, upstreams: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let allowed = upstreams
+            .into_iter()
+            .map(|u| u.as_ref().to_ascii_lowercase())
+            .collect();
+        self.allowed = Some(allowed);
+        self
+    }
+
+    /// The upstream the request asks for, if the request is trusted and has the header
+    ///
+    /// An error is returned if the header of a trusted request is invalid or names an upstream
+    /// that is not allowed.
+    pub fn peer(&self, session: &Session) -> Result<Option<Box<HttpPeer>>> {
+        let Some(value) = session.req_header().headers.get(&self.header) else {
+            return Ok(None);
+        };
+        if !self.trust.trusted(session) {
+            debug!("ignoring {} of untrusted request", self.header);
+            return Ok(None);
+        }
+        let value = value
+            .to_str()
+            .or_err(InvalidHTTPHeader, "invalid upstream override")?;
+        if let Some(allowed) = self.allowed.as_ref() {
+            if !allowed.contains(&value.to_ascii_lowercase()) {
+                return Error::e_explain(
+                    InvalidHTTPHeader,
+                    format!("upstream override {value} is not allowed"),
+                );
+            }
+        }
+        let peer = parse_upstream(value)?;
+        debug!("upstream overridden by {}: {peer}", self.header);
+        Ok(Some(Box::new(peer)))
+    }
+
+    /// Remove the override header, and the secret header of [OverrideTrust::Secret], from the
+    /// request to the upstream
+    pub fn remove_headers(&self, req: &mut RequestHeader) {
+        req.remove_header(&self.header);
+        if let OverrideTrust::Secret(header, _) = &self.trust {
+            req.remove_header(header);
+        }
+    }
+}
+
+/// Parse an upstream like `10.0.0.1:8080`, `[::1]:8080` or `https://backend.internal:443` into
+/// an [HttpPeer]
+///
+/// The port is required. The upstream uses TLS only with the `https://` prefix, with the host as
+/// the SNI. A hostname is resolved by the connector when connecting.
+pub fn parse_upstream(value: &str) -> Result<HttpPeer> {
+    let (tls, authority) = if let Some(rest) = value.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = value.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        (false, value)
+    };
+    let invalid = || format!("invalid upstream {value:?}");
+    // only the host and port, no userinfo or path
+    if authority.contains(['@', '/']) {
+        return Error::e_explain(InvalidHTTPHeader, invalid());
+    }
+    let authority: Authority = authority
+        .parse()
+        .explain_err(InvalidHTTPHeader, |_| invalid())?;
+    let port = match authority.port_u16() {
+        Some(port) if port > 0 => port,
+        _ => return Error::e_explain(InvalidHTTPHeader, invalid()),
+    };
+    let host = authority.host();
+    if host.is_empty() {
+        return Error::e_explain(InvalidHTTPHeader, invalid());
+    }
+    let sni = if tls { host.to_string() } else { String::new() };
+    // IPv6 addresses are in brackets
+    let ip = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host)
+        .parse::<IpAddr>();
+    Ok(match ip {
+        Ok(ip) => HttpPeer::new((ip, port), tls, sni),
+        Err(_) => HttpPeer::new_hostname(host, port, tls, sni),
+    })
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora_core::upstreams::peer::Peer;
+    use tokio_test::io::Builder;
+
+    #[test]
+    fn test_parse_upstream() {
+        let peer = parse_upstream("10.0.0.1:8080").unwrap();
+        assert_eq!(peer.address().to_string(), "10.0.0.1:8080");
+        assert!(!peer.is_tls());
+
+        let peer = parse_upstream("[::1]:8443").unwrap();
+        assert_eq!(peer.address().to_string(), "[::1]:8443");
+
+        let peer = parse_upstream("https://backend.internal:443").unwrap();
+        assert!(peer.is_tls());
+        assert_eq!(peer.sni, "backend.internal");
+        assert_eq!(peer.hostname(), Some("backend.internal"));
+
+        for invalid in [
+            "",
+            "10.0.0.1",
+            "10.0.0.1:0",
+            "10.0.0.1:99999",
+            "user@10.0.0.1:80",
+            "10.0.0.1:80/path",
+            "ftp://10.0.0.1:80",
+            ":80",
+        ] {
+            assert!(parse_upstream(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    async fn session(headers: &[u8]) -> Session {
+        let input = [b"GET / HTTP/1.1\r\n".as_slice(), headers, b"\r\n"].concat();
+        let mock_io = Builder::new().read(&input).build();
+        let mut session = Session::new_h1(Box::new(mock_io));
+        session.read_request().await.unwrap();
+        session
+    }
+
+    #[tokio::test]
+    async fn test_upstream_override() {
+        let trust =
+            OverrideTrust::Secret(HeaderName::from_static("x-debug-token"), "s3cret".into());
+        let upstream_override =
+            UpstreamOverride::new(HeaderName::from_static("x-debug-upstream"), trust)
+                .with_allowed_upstreams(["10.0.0.1:8080"]);
+
+        let trusted =
+            session(b"X-Debug-Upstream: 10.0.0.1:8080\r\nX-Debug-Token: s3cret\r\n").await;
+        let peer = upstream_override.peer(&trusted).unwrap().unwrap();
+        assert_eq!(peer.address().to_string(), "10.0.0.1:8080");
+
+        let mut req = trusted.req_header().clone();
+        upstream_override.remove_headers(&mut req);
+        assert!(req.headers.get("x-debug-upstream").is_none());
+        assert!(req.headers.get("x-debug-token").is_none());
+
+        // not allowed
+        let trusted =
+            session(b"X-Debug-Upstream: 10.0.0.2:8080\r\nX-Debug-Token: s3cret\r\n").await;
+        assert!(upstream_override.peer(&trusted).is_err());
+
+        // not trusted
+        let untrusted =
+            session(b"X-Debug-Upstream: 10.0.0.1:8080\r\nX-Debug-Token: s3crex\r\n").await;
+        assert!(upstream_override.peer(&untrusted).unwrap().is_none());
+        let untrusted = session(b"X-Debug-Upstream: 10.0.0.1:8080\r\n").await;
+        assert!(upstream_override.peer(&untrusted).unwrap().is_none());
+
+        // no override
+        let plain = session(b"X-Debug-Token: s3cret\r\n").await;
+        assert!(upstream_override.peer(&plain).unwrap().is_none());
+    }
+}
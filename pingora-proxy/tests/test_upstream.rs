@@ -23,6 +23,36 @@ use reqwest::StatusCode;
 use std::time::Duration;
 use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
 
+// an origin that answers every request with `response`, return its port
+async fn mock_origin(response: &'static str) -> u16 {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut req = Vec::new();
+                let mut buf = [0; 1024];
+                while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => req.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+    port
+}
+
+// a port that refuses connections
+fn closed_port() -> u16 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
 #[tokio::test]
 async fn test_ip_binding() {
     init();
@@ -80,6 +110,233 @@ async fn test_upload() {
     assert_eq!(body.len(), 64 * 5);
 }
 
+#[tokio::test]
+async fn test_response_filter_sees_upstream_framing() {
+    init();
+    let client = reqwest::Client::new();
+
+    // a response delimited by closing the connection
+    let port = mock_origin("HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nhello").await;
+    let res = client
+        .get("http://127.0.0.1:6147/")
+        .header("x-port", port.to_string())
+        .header("x-report-framing", "1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let headers = res.headers();
+    // response_filter() runs before the conversion to chunked encoding
+    assert_eq!(headers["x-seen-content-length"], "none");
+    assert_eq!(headers["x-seen-transfer-encoding"], "none");
+    assert_eq!(headers["transfer-encoding"], "chunked");
+    assert_eq!(res.text().await.unwrap(), "hello");
+
+    // removing Content-Length in response_filter() switches to chunked encoding
+    let port = mock_origin("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+    let res = client
+        .get("http://127.0.0.1:6147/")
+        .header("x-port", port.to_string())
+        .header("x-report-framing", "1")
+        .header("x-strip-content-length", "1")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let headers = res.headers();
+    assert_eq!(headers["x-seen-content-length"], "5");
+    assert_eq!(headers["x-seen-transfer-encoding"], "none");
+    assert!(headers.get("content-length").is_none());
+    assert_eq!(headers["transfer-encoding"], "chunked");
+    assert_eq!(res.text().await.unwrap(), "hello");
+}
+
+#[tokio::test]
+async fn test_grpc_trailers() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    init();
+    // a gRPC-style exchange, reqwest doesn't expose the trailers
+    let mut stream = tokio::net::TcpStream::connect("127.0.0.1:6147")
+        .await
+        .unwrap();
+    let req = "POST /grpc_trailers HTTP/1.1\r\n\
+               Host: 127.0.0.1\r\n\
+               Content-Type: application/grpc\r\n\
+               TE: trailers\r\n\
+               Transfer-Encoding: chunked\r\n\
+               Connection: close\r\n\r\n\
+               5\r\nhello\r\n0\r\nx-request-trailer: 1\r\n\r\n";
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).await.unwrap();
+
+    assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+    let (head, body) = resp.split_once("\r\n\r\n").unwrap();
+    assert!(head.to_lowercase().contains("transfer-encoding: chunked"));
+    assert!(body.contains("hello"));
+    // the trailers follow the last chunk
+    let (_, trailers) = body.split_once("0\r\n").unwrap();
+    assert!(trailers.contains("grpc-status: 0\r\n"), "{trailers}");
+    assert!(trailers.contains("grpc-message: ok\r\n"), "{trailers}");
+}
+
+#[tokio::test]
+async fn test_connect_tunnel() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    init();
+    let mut stream = tokio::net::TcpStream::connect("127.0.0.1:6147")
+        .await
+        .unwrap();
+    let req = "CONNECT 127.0.0.1:8000 HTTP/1.1\r\nHost: 127.0.0.1:8000\r\n\r\n";
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut buf = vec![0; 1024];
+    let n = stream.read(&mut buf).await.unwrap();
+    let resp = std::str::from_utf8(&buf[..n]).unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+    assert!(resp.ends_with("\r\n\r\n"), "{resp}");
+
+    // a plain HTTP request to the origin through the tunnel
+    let req = "GET / HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n";
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).await.unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200"), "{resp}");
+    assert!(resp.ends_with("Hello World!\n"), "{resp}");
+}
+
+#[tokio::test]
+async fn test_connect_tunnel_denied() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    init();
+    let mut stream = tokio::net::TcpStream::connect("127.0.0.1:6147")
+        .await
+        .unwrap();
+    let req = "CONNECT 127.0.0.1:22 HTTP/1.1\r\nHost: 127.0.0.1:22\r\n\r\n";
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).await.unwrap();
+    assert!(resp.starts_with("HTTP/1.1 403"), "{resp}");
+}
+
+#[tokio::test]
+async fn test_connect_tunnel_without_port() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    init();
+    let mut stream = tokio::net::TcpStream::connect("127.0.0.1:6147")
+        .await
+        .unwrap();
+    // the filter decides before the missing port is rejected
+    let req = "CONNECT 127.0.0.1 HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+    stream.write_all(req.as_bytes()).await.unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).await.unwrap();
+    assert!(resp.starts_with("HTTP/1.1 403"), "{resp}");
+}
+
+#[tokio::test]
+async fn test_retry_after_early_hints() {
+    init();
+    let port = mock_origin("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+    let res = reqwest::Client::new()
+        .get("http://127.0.0.1:6153/")
+        .header("x-early-hints", "1")
+        .header("x-first-port", closed_port().to_string())
+        .header("x-port", port.to_string())
+        .send()
+        .await
+        .unwrap();
+    // the 103 sent before the first attempt doesn't stop the retry
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.text().await.unwrap(), "hello");
+}
+
+#[tokio::test]
+async fn test_retry_budget_exhausted() {
+    init();
+    let port = mock_origin("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+    let closed = closed_port();
+    let client = reqwest::Client::new();
+    // the connection failure is retried by the policy
+    let res = client
+        .get("http://127.0.0.1:6153/")
+        .header("x-first-port", closed.to_string())
+        .header("x-port", port.to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // unless its budget, which allows no retry, is exhausted
+    let res = client
+        .get("http://127.0.0.1:6153/")
+        .header("x-retry-budget", "1")
+        .header("x-first-port", closed.to_string())
+        .header("x-port", port.to_string())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn test_retry_streamed_request_body() {
+    init();
+    let unavailable =
+        mock_origin("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n").await;
+    let port = mock_origin("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+    let client = reqwest::Client::new();
+    // the body fits in the buffer, so the 503 is retried
+    let res = client
+        .post("http://127.0.0.1:6154/")
+        .header("x-first-port", unavailable.to_string())
+        .header("x-port", port.to_string())
+        .body("a".repeat(16))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // a larger body is streamed without a copy, so it can't be sent again
+    let res = client
+        .post("http://127.0.0.1:6154/")
+        .header("x-first-port", unavailable.to_string())
+        .header("x-port", port.to_string())
+        .body("a".repeat(1024))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn test_reject_oversized_request_body() {
+    init();
+    let port = mock_origin("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+    let client = reqwest::Client::new();
+    let res = client
+        .post("http://127.0.0.1:6155/")
+        .header("x-port", port.to_string())
+        .body("a".repeat(16))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // rejected by its Content-Length before connecting to the upstream, which would fail
+    let res = client
+        .post("http://127.0.0.1:6155/")
+        .header("x-port", closed_port().to_string())
+        .body("a".repeat(17))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
 #[tokio::test]
 async fn test_ws_server_ends_conn() {
     init();
@@ -133,10 +390,96 @@ async fn test_ws_server_ends_conn() {
     assert!(ws_stream.next().await.is_none());
 }
 
+#[tokio::test]
+async fn test_ws_upgrade_idle_timeout() {
+    init();
+    let _ = *WS_ECHO;
+
+    let mut req = "ws://127.0.0.1:6147".into_client_request().unwrap();
+    req.headers_mut()
+        .insert("x-port", HeaderValue::from_static("9283"));
+    req.headers_mut()
+        .insert("x-upgrade-idle-timeout-ms", HeaderValue::from_static("200"));
+
+    let (mut ws_stream, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+    // the traffic of either side restarts the idle timer
+    for _ in 0..3 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        ws_stream.send("test".into()).await.unwrap();
+        let msg = ws_stream.next().await.unwrap().unwrap();
+        assert_eq!("test", msg.into_text().unwrap());
+    }
+    // the proxy closes the connection once it is idle for long enough
+    let closed = tokio::time::timeout(Duration::from_secs(2), ws_stream.next())
+        .await
+        .expect("the idle upgraded connection should be closed");
+    assert!(!matches!(closed, Some(Ok(Message::Text(_)))));
+}
+
+#[tokio::test]
+async fn test_ws_upgrade_forces_h1() {
+    init();
+    let _ = *WS_ECHO;
+
+    // the peer prefers h2, but there is no upgrade over h2
+    let mut req = "ws://127.0.0.1:6147".into_client_request().unwrap();
+    req.headers_mut()
+        .insert("x-port", HeaderValue::from_static("9283"));
+    req.headers_mut()
+        .insert("x-h2h1", HeaderValue::from_static("1"));
+
+    let (mut ws_stream, resp) = tokio_tungstenite::connect_async(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    assert_eq!(resp.headers()["x-upstream-alpn"], "H1");
+    ws_stream.send("test".into()).await.unwrap();
+    let msg = ws_stream.next().await.unwrap().unwrap();
+    assert_eq!("test", msg.into_text().unwrap());
+}
+
 mod test_cache {
     use super::*;
     use tokio::time::sleep;
 
+    #[tokio::test]
+    async fn test_ttl_override_with_default_cache_filter() {
+        init();
+        let port = mock_origin(
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nCache-Control: max-age=86400\r\n\r\nhello",
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let get = |path: &'static str, ttl_override: bool| {
+            let mut req = client
+                .get(format!("http://127.0.0.1:6148/unique/{path}"))
+                .header("x-port", port.to_string())
+                .header("x-default-cache-filter", "1");
+            if ttl_override {
+                req = req.header("x-ttl-override-secs", "1");
+            }
+            req.send()
+        };
+
+        // the default filter caches nothing
+        for _ in 0..2 {
+            let res = get("test_ttl_override_default/none", false).await.unwrap();
+            assert_eq!(res.headers()["x-cache-status"], "no-cache");
+        }
+
+        // the override applies to the TTL of the headers
+        let path = "test_ttl_override_default/override";
+        let res = get(path, true).await.unwrap();
+        assert_eq!(res.headers()["x-cache-status"], "miss");
+        assert_eq!(res.text().await.unwrap(), "hello");
+        let res = get(path, true).await.unwrap();
+        assert_eq!(res.headers()["x-cache-status"], "hit");
+        assert_eq!(res.text().await.unwrap(), "hello");
+
+        // capped at 1 second instead of the max-age of a day
+        sleep(Duration::from_millis(1100)).await;
+        let res = get(path, true).await.unwrap();
+        assert_eq!(res.headers()["x-cache-status"], "expired");
+    }
+
     #[tokio::test]
     async fn test_basic_caching() {
         init();
@@ -14,25 +14,30 @@
 
 use super::cert;
 use async_trait::async_trait;
+use http::header;
 use once_cell::sync::Lazy;
 use pingora_cache::cache_control::CacheControl;
 use pingora_cache::key::HashBinary;
 use pingora_cache::VarianceBuilder;
 use pingora_cache::{
-    eviction::simple_lru::Manager, filters::resp_cacheable, lock::CacheLock, predictor::Predictor,
+    eviction::simple_lru::Manager,
+    filters::{resp_cacheable, TtlOverride},
+    lock::CacheLock,
+    predictor::Predictor,
     set_compression_dict_path, CacheMeta, CacheMetaDefaults, CachePhase, MemCache, NoCacheReason,
     RespCacheable,
 };
-use pingora_core::protocols::{l4::socket::SocketAddr, Digest};
-use pingora_core::server::configuration::Opt;
+use pingora_core::protocols::{l4::socket::SocketAddr, Digest, ALPN};
+use pingora_core::server::configuration::{Opt, OversizedRequestBody, ServerConf};
 use pingora_core::services::Service;
 use pingora_core::upstreams::peer::HttpPeer;
 use pingora_core::utils::CertKey;
-use pingora_error::{Error, ErrorSource, Result};
+use pingora_error::{Error, ErrorSource, ErrorType::HTTPStatus, Result};
 use pingora_http::{RequestHeader, ResponseHeader};
-use pingora_proxy::{ProxyHttp, Session};
+use pingora_proxy::{ProxyHttp, RetryBudget, RetryPolicy, Session};
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
 
 pub struct ExampleProxyHttps {}
@@ -43,6 +48,7 @@ pub struct CTX {
     conn_reused: bool,
     upstream_client_addr: Option<SocketAddr>,
     upstream_server_addr: Option<SocketAddr>,
+    upstream_alpn: Option<String>,
 }
 
 // Common logic for both ProxyHttp(s) types
@@ -95,6 +101,9 @@ fn response_filter_common(
             .as_ref()
             .map_or_else(|| "unset".into(), |a| a.to_string()),
     )?;
+    if let Some(alpn) = ctx.upstream_alpn.as_ref() {
+        response.insert_header("x-upstream-alpn", alpn)?;
+    }
 
     Ok(())
 }
@@ -222,6 +231,23 @@ impl ProxyHttp for ExampleProxyHttp {
         upstream_response: &mut ResponseHeader,
         ctx: &mut Self::CTX,
     ) -> Result<()> {
+        let req = session.req_header();
+        if req.headers.contains_key("x-report-framing") {
+            // the framing headers as this filter sees them
+            for (name, report) in [
+                (header::CONTENT_LENGTH, "x-seen-content-length"),
+                (header::TRANSFER_ENCODING, "x-seen-transfer-encoding"),
+            ] {
+                let seen = upstream_response
+                    .headers
+                    .get(&name)
+                    .map_or("none".to_string(), |v| v.to_str().unwrap().to_string());
+                upstream_response.insert_header(report, seen)?;
+            }
+        }
+        if req.headers.contains_key("x-strip-content-length") {
+            upstream_response.remove_header(&header::CONTENT_LENGTH);
+        }
         response_filter_common(session, upstream_response, ctx)
     }
 
@@ -242,33 +268,114 @@ impl ProxyHttp for ExampleProxyHttp {
             .headers
             .get("x-port")
             .map_or("8000", |v| v.to_str().unwrap());
-        let peer = Box::new(HttpPeer::new(
+        let mut peer = Box::new(HttpPeer::new(
             format!("127.0.0.1:{port}"),
             false,
             "".to_string(),
         ));
+        if req.headers.contains_key("x-h2h1") {
+            peer.options.alpn = ALPN::H2H1;
+        }
+        if let Some(ms) = req.headers.get("x-upgrade-idle-timeout-ms") {
+            let ms = ms.to_str().unwrap().parse().unwrap();
+            peer.options.upgrade_idle_timeout = Some(Duration::from_millis(ms));
+        }
         Ok(peer)
     }
 
+    async fn connect_tunnel_peer(
+        &self,
+        _session: &mut Session,
+        target: &str,
+        _ctx: &mut Self::CTX,
+    ) -> Result<Option<Box<HttpPeer>>> {
+        // only the test origin is allowed
+        if target != "127.0.0.1:8000" {
+            return Error::e_explain(HTTPStatus(403), "CONNECT target not allowed");
+        }
+        Ok(Some(Box::new(HttpPeer::new(target, false, "".to_string()))))
+    }
+
     async fn connected_to_upstream(
         &self,
         _http_session: &mut Session,
         reused: bool,
-        _peer: &HttpPeer,
+        peer: &HttpPeer,
         _fd: std::os::unix::io::RawFd,
         digest: Option<&Digest>,
         ctx: &mut CTX,
     ) -> Result<()> {
+        // the ALPN the request is actually sent with
+        ctx.upstream_alpn = Some(peer.options.alpn.to_string());
         connected_to_upstream_common(reused, digest, ctx)
     }
 }
 
+pub struct ExampleProxyRetry {
+    // a budget that allows no retry at all
+    budget: Arc<RetryBudget>,
+}
+
+#[async_trait]
+impl ProxyHttp for ExampleProxyRetry {
+    type CTX = ();
+    fn new_ctx(&self) -> Self::CTX {}
+
+    async fn upstream_peer(
+        &self,
+        session: &mut Session,
+        _ctx: &mut Self::CTX,
+    ) -> Result<Box<HttpPeer>> {
+        let req = session.req_header();
+        // the first attempt goes to x-first-port, if any, so that it can fail
+        let port_header = if session.upstream_attempts() == 1 {
+            "x-first-port"
+        } else {
+            "x-port"
+        };
+        let port = req
+            .headers
+            .get(port_header)
+            .or_else(|| req.headers.get("x-port"))
+            .map_or("8000", |v| v.to_str().unwrap());
+        let peer = Box::new(HttpPeer::new(
+            format!("127.0.0.1:{port}"),
+            false,
+            "".to_string(),
+        ));
+        Ok(peer)
+    }
+
+    fn retry_policy(&self, session: &Session, _ctx: &Self::CTX) -> Option<Arc<RetryPolicy>> {
+        let budget = session
+            .req_header()
+            .headers
+            .contains_key("x-retry-budget")
+            .then(|| self.budget.clone());
+        Some(Arc::new(RetryPolicy {
+            budget,
+            retry_statuses: vec![503],
+            ..RetryPolicy::new(2)
+        }))
+    }
+
+    fn early_hints(&self, session: &Session, _ctx: &Self::CTX) -> Option<Box<ResponseHeader>> {
+        if !session.req_header().headers.contains_key("x-early-hints") {
+            return None;
+        }
+        let mut hints = ResponseHeader::build(103, None).unwrap();
+        hints
+            .insert_header("link", "</style.css>; rel=preload")
+            .unwrap();
+        Some(Box::new(hints))
+    }
+}
+
 static CACHE_BACKEND: Lazy<MemCache> = Lazy::new(MemCache::new);
 const CACHE_DEFAULT: CacheMetaDefaults = CacheMetaDefaults::new(|_| Some(1), 1, 1);
 static CACHE_PREDICTOR: Lazy<Predictor<32>> = Lazy::new(|| Predictor::new(5, None));
 static EVICTION_MANAGER: Lazy<Manager> = Lazy::new(|| Manager::new(8192)); // 8192 bytes
-static CACHE_LOCK: Lazy<CacheLock> =
-    Lazy::new(|| CacheLock::new(std::time::Duration::from_secs(2)));
+static CACHE_LOCK: Lazy<CacheLock> = Lazy::new(|| CacheLock::new(Duration::from_secs(2)));
 
 // #[allow(clippy::upper_case_acronyms)]
 pub struct CacheCTX {
@@ -356,14 +463,29 @@ impl ProxyHttp for ExampleProxyCache {
 
     fn response_cache_filter(
         &self,
-        _session: &Session,
+        session: &Session,
         resp: &ResponseHeader,
         _ctx: &mut Self::CTX,
     ) -> Result<RespCacheable> {
+        if session.get_header("x-default-cache-filter").is_some() {
+            // what the default response_cache_filter() returns
+            return Ok(RespCacheable::Uncacheable(NoCacheReason::Custom("default")));
+        }
         let cc = CacheControl::from_resp_headers(resp);
         Ok(resp_cacheable(cc.as_ref(), resp, false, &CACHE_DEFAULT))
     }
 
+    fn cache_ttl_override(
+        &self,
+        session: &Session,
+        _resp: &ResponseHeader,
+        _ctx: &mut Self::CTX,
+    ) -> Option<TtlOverride> {
+        let secs = session.get_header("x-ttl-override-secs")?;
+        let secs = secs.to_str().unwrap().parse().unwrap();
+        Some(TtlOverride::Ttl(Duration::from_secs(secs)))
+    }
+
     fn upstream_response_filter(
         &self,
         _session: &mut Session,
@@ -459,10 +581,45 @@ fn test_main() {
         pingora_proxy::http_proxy_service(&my_server.configuration, ExampleProxyCache {});
     proxy_service_cache.add_tcp("0.0.0.0:6148");
 
+    let retry_budget = RetryBudget::new(0.0).with_min_retries(0);
+    let mut proxy_service_retry = pingora_proxy::http_proxy_service(
+        &my_server.configuration,
+        ExampleProxyRetry {
+            budget: Arc::new(retry_budget),
+        },
+    );
+    proxy_service_retry.add_tcp("0.0.0.0:6153");
+
+    // the same but buffering at most 16 bytes of the request bodies to retry them
+    let small_buffer_conf = |oversized_request_body| {
+        Arc::new(ServerConf {
+            max_buffered_request_body: Some(16),
+            oversized_request_body,
+            ..Default::default()
+        })
+    };
+    let mut proxy_service_streamed_body = pingora_proxy::http_proxy_service(
+        &small_buffer_conf(OversizedRequestBody::Stream),
+        ExampleProxyRetry {
+            budget: Arc::new(RetryBudget::new(0.0).with_min_retries(0)),
+        },
+    );
+    proxy_service_streamed_body.add_tcp("0.0.0.0:6154");
+    let mut proxy_service_rejected_body = pingora_proxy::http_proxy_service(
+        &small_buffer_conf(OversizedRequestBody::Reject),
+        ExampleProxyRetry {
+            budget: Arc::new(RetryBudget::new(0.0).with_min_retries(0)),
+        },
+    );
+    proxy_service_rejected_body.add_tcp("0.0.0.0:6155");
+
     let services: Vec<Box<dyn Service>> = vec![
         Box::new(proxy_service_http),
         Box::new(proxy_service_https),
         Box::new(proxy_service_cache),
+        Box::new(proxy_service_retry),
+        Box::new(proxy_service_streamed_body),
+        Box::new(proxy_service_rejected_body),
     ];
 
     set_compression_dict_path("tests/headers.dict");
@@ -45,14 +45,25 @@ pub enum Runtime {
 impl Runtime {
     /// Create a `Steal` flavor runtime. This just a regular tokio runtime
     pub fn new_steal(threads: usize, name: &str) -> Self {
-        Self::Steal(
-            Builder::new_multi_thread()
-                .enable_all()
-                .worker_threads(threads)
-                .thread_name(name)
-                .build()
-                .unwrap(),
-        )
+        Self::new_steal_with_max_blocking(threads, name, None)
+    }
+
+    /// Similar to [Self::new_steal()], with the maximum number of threads for blocking
+    /// operations. `None` to use tokio's default. Panic if it is 0
+    pub fn new_steal_with_max_blocking(
+        threads: usize,
+        name: &str,
+        max_blocking_threads: Option<usize>,
+    ) -> Self {
+        let mut builder = Builder::new_multi_thread();
+        builder
+            .enable_all()
+            .worker_threads(threads)
+            .thread_name(name);
+        if let Some(max) = max_blocking_threads {
+            builder.max_blocking_threads(max);
+        }
+        Self::Steal(builder.build().unwrap())
     }
 
     /// Create a `NoSteal` flavor runtime. This is backed by multiple tokio current-thread runtime
@@ -60,6 +71,20 @@ impl Runtime {
         Self::NoSteal(NoStealRuntime::new(threads, name))
     }
 
+    /// Similar to [Self::new_no_steal()], with the maximum number of threads for blocking
+    /// operations of **each** of its threads. `None` to use tokio's default. Panic if it is 0
+    pub fn new_no_steal_with_max_blocking(
+        threads: usize,
+        name: &str,
+        max_blocking_threads: Option<usize>,
+    ) -> Self {
+        let mut runtime = NoStealRuntime::new(threads, name);
+        if let Some(max) = max_blocking_threads {
+            runtime = runtime.with_max_blocking_threads(max);
+        }
+        Self::NoSteal(runtime)
+    }
+
     /// Return the &[Handle] of the [Runtime].
     /// For `Steal` flavor, it will just return the &[Handle].
     /// For `NoSteal` flavor, it will return the &[Handle] of a random thread in its pool.
@@ -109,6 +134,7 @@ type Pools = Arc<OnceCell<Box<[Handle]>>>;
 pub struct NoStealRuntime {
     threads: usize,
     name: String,
+    max_blocking_threads: Option<usize>,
     // Lazily init the runtimes so that they are created after pingora
     // daemonize itself. Otherwise the runtime threads are lost.
     pools: Arc<OnceCell<Box<[Handle]>>>,
@@ -122,16 +148,30 @@ impl NoStealRuntime {
         NoStealRuntime {
             threads,
             name: name.to_string(),
+            max_blocking_threads: None,
             pools: Arc::new(OnceCell::new()),
             controls: OnceCell::new(),
         }
     }
 
+    /// Set the maximum number of threads for blocking operations of each of the threads of this
+    /// runtime. Panic if `max` is 0
+    pub fn with_max_blocking_threads(mut self, max: usize) -> Self {
+        assert!(max != 0);
+        self.max_blocking_threads = Some(max);
+        self
+    }
+
     fn init_pools(&self) -> (Box<[Handle]>, Vec<Control>) {
         let mut pools = Vec::with_capacity(self.threads);
         let mut controls = Vec::with_capacity(self.threads);
         for _ in 0..self.threads {
-            let rt = Builder::new_current_thread().enable_all().build().unwrap();
+            let mut builder = Builder::new_current_thread();
+            builder.enable_all();
+            if let Some(max) = self.max_blocking_threads {
+                builder.max_blocking_threads(max);
+            }
+            let rt = builder.build().unwrap();
             let handler = rt.handle().clone();
             let (tx, rx) = channel::<Duration>();
             let pools_ref = self.pools.clone();
@@ -263,3 +303,22 @@ fn test_no_steal_shutdown() {
 
     rt.shutdown_timeout(Duration::from_secs(1));
 }
+
+#[test]
+fn test_max_blocking_threads() {
+    for rt in [
+        Runtime::new_steal_with_max_blocking(1, "test", Some(1)),
+        Runtime::new_no_steal_with_max_blocking(1, "test", Some(1)),
+    ] {
+        let handle = rt.get_handle();
+        let ret = handle.block_on(async {
+            let handle = current_handle();
+            // only one blocking thread, so the tasks run one after another
+            let first = handle.spawn_blocking(|| std::thread::current().id());
+            let second = handle.spawn_blocking(|| std::thread::current().id());
+            first.await.unwrap() == second.await.unwrap()
+        });
+        assert!(ret);
+        rt.shutdown_timeout(Duration::from_secs(1));
+    }
+}
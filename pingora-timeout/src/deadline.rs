@@ -0,0 +1,282 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Deadlines shared by the nested operations of a task
+//!
+//! A multi-stage task, e.g., connect, write then read, often has an overall time budget on top of
+//! the limit of each stage. A [Deadline] tracks the budget: each operation run via
+//! [Deadline::timeout()] gets the remaining time of the budget, or its own limit if that is
+//! shorter. [Deadline::child()] derives a deadline for a sub-task which never outlives its parent.
+//!
+//! A deadline can also be cancelled, which fails all the pending and future operations under it
+//! and its children right away.
+
+use super::fast_timeout::fast_timeout;
+use super::Elapsed;
+use parking_lot::Mutex;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+struct Inner {
+    expires_at: Option<Instant>,
+    cancelled: AtomicBool,
+    notify: Notify,
+    children: Mutex<Vec<Weak<Inner>>>,
+}
+
+impl Inner {
+    fn new(expires_at: Option<Instant>) -> Self {
+        Inner {
+            expires_at,
+            cancelled: AtomicBool::new(false),
+            notify: Notify::new(),
+            children: Mutex::new(vec![]),
+        }
+    }
+
+    fn cancel(&self) {
+        if self.cancelled.swap(true, Ordering::AcqRel) {
+            return; // already cancelled
+        }
+        self.notify.notify_waiters();
+        let children = std::mem::take(&mut *self.children.lock());
+        for child in children.iter().filter_map(Weak::upgrade) {
+            child.cancel();
+        }
+    }
+}
+
+/// The overall time budget of a task, shared by its operations
+///
+/// Cloning a [Deadline] is cheap and the clones share the same budget and cancellation.
+#[derive(Clone)]
+pub struct Deadline {
+    inner: Arc<Inner>,
+}
+
+impl std::fmt::Debug for Deadline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Deadline")
+            .field("remaining", &self.remaining())
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl Deadline {
+    /// Create a new [Deadline] that expires after the given duration from now
+    pub fn new(budget: Duration) -> Self {
+        Self::with_expiry(Some(Instant::now() + budget))
+    }
+
+    /// Create a new [Deadline] that never expires. It can still be cancelled.
+    pub fn unbounded() -> Self {
+        Self::with_expiry(None)
+    }
+
+    fn with_expiry(expires_at: Option<Instant>) -> Self {
+        Deadline {
+            inner: Arc::new(Inner::new(expires_at)),
+        }
+    }
+
+    /// Derive the deadline of a sub-task
+    ///
+    /// The child expires after the given `limit` or when this deadline expires, whichever comes
+    /// first. Cancelling this deadline also cancels the child, but not the other way around.
+    pub fn child(&self, limit: Option<Duration>) -> Self {
+        let expires_at = match (self.inner.expires_at, limit) {
+            (Some(at), Some(limit)) => Some(at.min(Instant::now() + limit)),
+            (at, limit) => at.or_else(|| limit.map(|l| Instant::now() + l)),
+        };
+        let child = Self::with_expiry(expires_at);
+        let mut children = self.inner.children.lock();
+        if self.is_cancelled() {
+            child.inner.cancel();
+        } else {
+            // clean up the children that are gone
+            children.retain(|c| c.strong_count() > 0);
+            children.push(Arc::downgrade(&child.inner));
+        }
+        child
+    }
+
+    /// The time left before this deadline expires, `None` if it never expires
+    ///
+    /// Return zero once the deadline has passed or is cancelled.
+    pub fn remaining(&self) -> Option<Duration> {
+        if self.is_cancelled() {
+            return Some(Duration::ZERO);
+        }
+        self.inner
+            .expires_at
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Whether this deadline has passed or is cancelled
+    pub fn is_expired(&self) -> bool {
+        self.remaining().is_some_and(|r| r.is_zero())
+    }
+
+    /// Cancel this deadline and all its children
+    pub fn cancel(&self) {
+        self.inner.cancel()
+    }
+
+    /// Whether this deadline, or any of its parents, is cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Wait until this deadline is cancelled
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.inner.notify.notified();
+            tokio::pin!(notified);
+            // register the waiter before checking the flag to not miss the notification
+            notified.as_mut().enable();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Run the given future within the remaining time of this deadline
+    ///
+    /// [Elapsed] is returned if the deadline expires or is cancelled before the future finishes.
+    pub async fn timeout<T: Future>(&self, future: T) -> Result<T::Output, Elapsed> {
+        self.timeout_with(None, future).await
+    }
+
+    /// Run the given future within the remaining time of this deadline or the given `limit`,
+    /// whichever is shorter
+    ///
+    /// [Elapsed] is returned if either expires or this deadline is cancelled before the future
+    /// finishes.
+    pub async fn timeout_with<T: Future>(
+        &self,
+        limit: Option<Duration>,
+        future: T,
+    ) -> Result<T::Output, Elapsed> {
+        let remaining = match (self.remaining(), limit) {
+            (Some(r), Some(limit)) => Some(r.min(limit)),
+            (r, limit) => r.or(limit),
+        };
+        if self.is_expired() {
+            return Err(Elapsed);
+        }
+        let run = async {
+            tokio::select! {
+                biased;
+                v = future => Ok(v),
+                _ = self.cancelled() => Err(Elapsed),
+            }
+        };
+        match remaining {
+            Some(d) => fast_timeout(d, run).await.and_then(|r| r),
+            None => run.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn test_remaining() {
+        let deadline = Deadline::new(Duration::from_secs(10));
+        let remaining = deadline.remaining().unwrap();
+        assert!(remaining <= Duration::from_secs(10) && remaining > Duration::from_secs(9));
+        assert!(!deadline.is_expired());
+        assert_eq!(Deadline::unbounded().remaining(), None);
+        assert!(!Deadline::unbounded().is_expired());
+
+        let deadline = Deadline::new(Duration::from_millis(10));
+        sleep(Duration::from_millis(20)).await;
+        assert_eq!(deadline.remaining(), Some(Duration::ZERO));
+        assert!(deadline.is_expired());
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let deadline = Deadline::new(Duration::from_millis(100));
+        assert_eq!(deadline.timeout(async { 1 }).await.unwrap(), 1);
+        // the per call limit is shorter
+        let r = deadline
+            .timeout_with(
+                Some(Duration::from_millis(10)),
+                sleep(Duration::from_secs(1)),
+            )
+            .await;
+        assert!(r.is_err());
+        assert!(!deadline.is_expired());
+        // the deadline is shorter
+        let r = deadline
+            .timeout_with(Some(Duration::from_secs(10)), sleep(Duration::from_secs(1)))
+            .await;
+        assert!(r.is_err());
+        assert!(deadline.is_expired());
+        // short-circuit once expired
+        assert!(deadline.timeout(async { 1 }).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_child() {
+        let parent = Deadline::new(Duration::from_millis(50));
+        let longer = parent.child(Some(Duration::from_secs(10)));
+        assert!(longer.remaining().unwrap() <= Duration::from_millis(50));
+        let shorter = parent.child(Some(Duration::from_millis(10)));
+        assert!(shorter.remaining().unwrap() <= Duration::from_millis(10));
+        let unlimited = parent.child(None);
+        assert!(unlimited.remaining().unwrap() <= Duration::from_millis(50));
+
+        let child = Deadline::unbounded().child(Some(Duration::from_millis(10)));
+        assert!(child.remaining().unwrap() <= Duration::from_millis(10));
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let parent = Deadline::unbounded();
+        let child = parent.child(None);
+        let grandchild = child.child(Some(Duration::from_secs(10)));
+
+        let pending = {
+            let grandchild = grandchild.clone();
+            tokio::spawn(async move { grandchild.timeout(sleep(Duration::from_secs(10))).await })
+        };
+        sleep(Duration::from_millis(10)).await;
+
+        // cancelling a child doesn't affect the parent
+        let sibling = parent.child(None);
+        sibling.cancel();
+        assert!(sibling.is_cancelled());
+        assert!(!parent.is_cancelled());
+
+        parent.cancel();
+        assert!(pending.await.unwrap().is_err());
+        assert!(child.is_cancelled());
+        assert!(grandchild.is_cancelled());
+        assert!(grandchild.is_expired());
+        assert!(child.timeout(async { 1 }).await.is_err());
+
+        // children created after the cancellation are cancelled too
+        assert!(parent.child(None).is_cancelled());
+    }
+}
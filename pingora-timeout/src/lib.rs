@@ -33,9 +33,12 @@
 //! 10.716192ms total, 107ns avg per iteration
 //!
 
+pub mod deadline;
 pub mod fast_timeout;
+pub mod stats;
 pub mod timer;
 
+pub use deadline::Deadline;
 pub use fast_timeout::fast_sleep as sleep;
 pub use fast_timeout::fast_timeout as timeout;
 
@@ -102,6 +105,7 @@ pin_project! {
         #[pin]
         delay: Option<BoxFuture<'static, ()>>,
         callback: F, // callback to create the timer
+        label: Option<&'static str>,
     }
 }
 
@@ -114,8 +118,16 @@ where
             value,
             delay: None,
             callback: F::create(d),
+            label: None,
         }
     }
+
+    /// Label this timeout so that it is counted by [stats] when it elapses, e.g., by the phase
+    /// of the request it guards: `timeout(d, fut).label("connect")`.
+    pub fn label(mut self, label: &'static str) -> Self {
+        self.label = Some(label);
+        self
+    }
 }
 
 impl<T, F> Future for Timeout<T, F>
@@ -139,7 +151,12 @@ where
 
         match delay.as_mut().poll(cx) {
             Poll::Pending => Poll::Pending,
-            Poll::Ready(()) => Poll::Ready(Err(Elapsed {})),
+            Poll::Ready(()) => {
+                if let Some(label) = me.label {
+                    stats::record(label);
+                }
+                Poll::Ready(Err(Elapsed {}))
+            }
         }
     }
 }
@@ -0,0 +1,120 @@
+// Copyright 2024 Cloudflare, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The counts of the elapsed timeouts by label
+//!
+//! A timeout is labeled via [crate::Timeout::label()], e.g., by the phase of the request it
+//! guards. Each time a labeled timeout elapses, the counter of its label is incremented and the
+//! hook set via [set_expiry_hook()], if any, is called with the label.
+
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static COUNTERS: Lazy<RwLock<HashMap<&'static str, AtomicU64>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+type Hook = Box<dyn Fn(&'static str) + Send + Sync>;
+static HOOK: OnceCell<Hook> = OnceCell::new();
+
+/// Set the function to call with the label of each labeled timeout that elapses
+///
+/// The hook can only be set once. Return false if it is already set. The hook is called on the
+/// task that polls the timeout so it should be cheap.
+pub fn set_expiry_hook(hook: impl Fn(&'static str) + Send + Sync + 'static) -> bool {
+    HOOK.set(Box::new(hook)).is_ok()
+}
+
+pub(crate) fn record(label: &'static str) {
+    // the read guard has to be dropped before taking the write lock below
+    let counted = match COUNTERS.read().get(label) {
+        Some(counter) => {
+            counter.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    };
+    if !counted {
+        COUNTERS
+            .write()
+            .entry(label)
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+    if let Some(hook) = HOOK.get() {
+        hook(label);
+    }
+}
+
+/// The number of elapsed timeouts of each label since the start of the process
+pub fn expired_counts() -> Vec<(&'static str, u64)> {
+    let mut counts: Vec<_> = COUNTERS
+        .read()
+        .iter()
+        .map(|(label, count)| (*label, count.load(Ordering::Relaxed)))
+        .collect();
+    counts.sort_unstable();
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{timeout, tokio_timeout};
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    fn count(label: &str) -> u64 {
+        expired_counts()
+            .into_iter()
+            .find(|(l, _)| *l == label)
+            .map_or(0, |(_, c)| c)
+    }
+
+    #[tokio::test]
+    async fn test_expired_counts() {
+        static HOOK_CALLS: AtomicUsize = AtomicUsize::new(0);
+        assert!(set_expiry_hook(|label| {
+            if label == "test_read" {
+                HOOK_CALLS.fetch_add(1, Ordering::Relaxed);
+            }
+        }));
+        assert!(!set_expiry_hook(|_| {}));
+
+        let fut = sleep(Duration::from_secs(1000));
+        assert!(timeout(Duration::from_millis(10), fut)
+            .label("test_read")
+            .await
+            .is_err());
+        let fut = sleep(Duration::from_secs(1000));
+        assert!(tokio_timeout(Duration::from_millis(10), fut)
+            .label("test_read")
+            .await
+            .is_err());
+        // not elapsed
+        assert!(timeout(Duration::from_secs(1), async { 1 })
+            .label("test_write")
+            .await
+            .is_ok());
+        // not labeled
+        let fut = sleep(Duration::from_secs(1000));
+        assert!(timeout(Duration::from_millis(10), fut).await.is_err());
+
+        assert_eq!(count("test_read"), 2);
+        assert_eq!(count("test_write"), 0);
+        assert_eq!(HOOK_CALLS.load(Ordering::Relaxed), 2);
+    }
+}
@@ -39,9 +39,14 @@
 //! # features
 //! * `openssl`: Using OpenSSL as the internal TLS backend. This feature is default on.
 //! * `boringssl`: Switch the internal TLS library from OpenSSL to BoringSSL. This feature will disable `openssl`.
+//! * `brotli`: Brotli (de)compression of HTTP responses. This feature is default on.
+//! * `prometheus`: The HTTP service that reports the Prometheus metrics, see `prometheus_service()`
+//!   of `pingora_core::services::listening`. This feature is default on.
 //! * `proxy`: This feature will include and export `pingora_proxy::prelude::*`.
 //! * `lb`: This feature will include and export `pingora_load_balancing::prelude::*`.
 //! * `cache`: This feature will include and export `pingora_cache::prelude::*`.
+//! * `opentelemetry`: OpenTelemetry tracing of the proxied requests, see `ProxyHttp::tracing()`. This
+//!   feature enables `proxy`.
 
 pub use pingora_core::*;
 